@@ -0,0 +1,212 @@
+//! Benchmarks of the storage and network pipelines.
+//!
+//! Run with `cargo bench --features bench --bench pipeline`. Besides the criterion reports,
+//! the mean and standard deviation of every benchmark are written as json to
+//! `target/criterion/pipeline.json`, or to the path in `IPFS_EMBED_BENCH_JSON`.
+use async_std::task::block_on;
+use criterion::{BatchSize, Criterion, Throughput};
+use ipfs_embed::{Config, Ipfs, NetworkConfig, StorageConfig};
+use libipld::cbor::DagCborCodec;
+use libipld::multihash::Code;
+use libipld::raw::RawCodec;
+use libipld::store::DefaultParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const BLOCK_SIZE: usize = 1024;
+const BLOCKS: usize = 256;
+
+static NONCE: AtomicU64 = AtomicU64::new(0);
+
+/// Creates blocks with unique content, so that every iteration writes new blocks.
+fn create_blocks(n: usize) -> Vec<Block<DefaultParams>> {
+    let nonce = NONCE.fetch_add(1, Ordering::Relaxed);
+    (0..n)
+        .map(|i| {
+            let mut data = vec![0; BLOCK_SIZE];
+            data[..8].copy_from_slice(&nonce.to_be_bytes());
+            data[8..16].copy_from_slice(&(i as u64).to_be_bytes());
+            Block::encode(RawCodec, Code::Blake3_256, &data[..]).unwrap()
+        })
+        .collect()
+}
+
+/// Creates a dag of a root linking to `n` leaves.
+fn create_dag(n: usize) -> (Block<DefaultParams>, Vec<Block<DefaultParams>>) {
+    let leaves = create_blocks(n);
+    let links = leaves.iter().map(|b| Ipld::Link(*b.cid())).collect();
+    let root = Block::encode(DagCborCodec, Code::Blake3_256, &Ipld::List(links)).unwrap();
+    (root, leaves)
+}
+
+async fn create_node(cache_size: u64) -> Result<Ipfs<DefaultParams>> {
+    let storage = StorageConfig::new(None, cache_size, Duration::from_secs(60 * 60));
+    let mut network = NetworkConfig::new();
+    network.enable_mdns = false;
+    network.enable_kad = false;
+    let ipfs = Ipfs::new(Config { storage, network }).await?;
+    ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+    Ok(ipfs)
+}
+
+fn insert(c: &mut Criterion) {
+    let node = block_on(create_node(u64::MAX)).unwrap();
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Bytes((BLOCKS * BLOCK_SIZE) as u64));
+    group.bench_function("blocks", |b| {
+        b.iter_batched(
+            || create_blocks(BLOCKS),
+            |blocks| {
+                block_on(async {
+                    for block in &blocks {
+                        node.insert(block).unwrap().await.unwrap();
+                    }
+                })
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("import", |b| {
+        b.iter_batched(
+            || create_dag(BLOCKS),
+            |(root, leaves)| {
+                let cid = *root.cid();
+                let blocks = leaves.into_iter().chain(std::iter::once(root));
+                node.import_blocks(blocks, vec![cid]).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn sync(c: &mut Criterion) {
+    let (a, b) = block_on(async {
+        let a = create_node(u64::MAX).await.unwrap();
+        let b = create_node(u64::MAX).await.unwrap();
+        b.dial_address(&a.local_peer_id(), a.listeners()[0].clone())
+            .unwrap();
+        (a, b)
+    });
+    let mut group = c.benchmark_group("sync");
+    group.throughput(Throughput::Bytes((BLOCKS * BLOCK_SIZE) as u64));
+    group.bench_function("dag", |bench| {
+        bench.iter_custom(|iters| {
+            let mut elapsed = Duration::default();
+            for _ in 0..iters {
+                let (root, leaves) = create_dag(BLOCKS);
+                let cid = *root.cid();
+                let blocks = leaves.into_iter().chain(std::iter::once(root));
+                a.import_blocks(blocks, vec![cid]).unwrap();
+                let start = Instant::now();
+                block_on(async {
+                    b.alias("bench", Some(&cid)).unwrap();
+                    b.sync(&cid).await.unwrap();
+                });
+                elapsed += start.elapsed();
+            }
+            elapsed
+        })
+    });
+    group.finish();
+}
+
+fn alias_churn(c: &mut Criterion) {
+    let node = block_on(create_node(u64::MAX)).unwrap();
+    let roots: Vec<Cid> = (0..16)
+        .map(|_| {
+            let (root, leaves) = create_dag(16);
+            let cid = *root.cid();
+            let blocks = leaves.into_iter().chain(std::iter::once(root));
+            node.import_blocks(blocks, vec![cid]).unwrap();
+            cid
+        })
+        .collect();
+    let mut group = c.benchmark_group("alias");
+    group.throughput(Throughput::Elements(roots.len() as u64));
+    group.bench_function("churn", |b| {
+        b.iter(|| {
+            for root in &roots {
+                node.alias("churn", Some(root)).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn gc(c: &mut Criterion) {
+    let node = block_on(create_node(0)).unwrap();
+    let mut group = c.benchmark_group("gc");
+    group.throughput(Throughput::Elements(BLOCKS as u64));
+    group.bench_function("evict", |b| {
+        b.iter_batched(
+            || {
+                let blocks = create_blocks(BLOCKS);
+                node.import_blocks(blocks, vec![]).unwrap();
+            },
+            |()| block_on(node.evict()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+/// Collects the estimates of all benchmarks of the criterion output directory.
+fn collect(dir: &Path, name: &str, results: &mut serde_json::Map<String, serde_json::Value>) {
+    let estimates = dir.join("new").join("estimates.json");
+    if let Ok(json) = std::fs::read(&estimates) {
+        if let Ok(estimates) = serde_json::from_slice::<serde_json::Value>(&json) {
+            results.insert(
+                name.to_string(),
+                serde_json::json!({
+                    "mean_ns": estimates["mean"]["point_estimate"],
+                    "std_dev_ns": estimates["std_dev"]["point_estimate"],
+                }),
+            );
+        }
+        return;
+    }
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name == "report" || !entry.path().is_dir() {
+                continue;
+            }
+            let name = if name.is_empty() {
+                file_name.to_string()
+            } else {
+                format!("{}/{}", name, file_name)
+            };
+            collect(&entry.path(), &name, results);
+        }
+    }
+}
+
+fn write_json(dir: &Path) {
+    let mut results = serde_json::Map::new();
+    for group in &["insert", "sync", "alias", "gc"] {
+        collect(&dir.join(group), group, &mut results);
+    }
+    let path = std::env::var_os("IPFS_EMBED_BENCH_JSON")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| dir.join("pipeline.json"));
+    let json = serde_json::to_vec_pretty(&serde_json::Value::Object(results)).unwrap();
+    std::fs::write(&path, json).unwrap();
+    println!("wrote results to {}", path.display());
+}
+
+fn main() {
+    let mut criterion = Criterion::default().sample_size(10).configure_from_args();
+    insert(&mut criterion);
+    sync(&mut criterion);
+    alias_churn(&mut criterion);
+    gc(&mut criterion);
+    criterion.final_summary();
+    let target = std::env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("target"));
+    write_json(&target.join("criterion"));
+}
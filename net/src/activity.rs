@@ -0,0 +1,111 @@
+use futures::channel::oneshot;
+use futures::future::{self, Either, Future};
+use ipfs_embed_rt::{Instant, Timer};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Intervals of background tasks are stretched by this factor in the background.
+const BACKGROUND_FACTOR: u32 = 4;
+
+/// How active the host application is. Used to shed periodic work when a desktop or
+/// mobile application isn't in use, to save power.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ActivityLevel {
+    /// The application is in use and periodic tasks run at their configured intervals.
+    Active,
+    /// The application is in the background and periodic tasks run four times less
+    /// often.
+    Background,
+    /// The application is suspended and periodic tasks are paused until it is activated.
+    Suspended,
+}
+
+impl Default for ActivityLevel {
+    fn default() -> Self {
+        Self::Active
+    }
+}
+
+impl ActivityLevel {
+    /// Returns the factor intervals are stretched by, or `None` if periodic tasks are
+    /// paused.
+    pub fn factor(self) -> Option<u32> {
+        match self {
+            Self::Active => Some(1),
+            Self::Background => Some(BACKGROUND_FACTOR),
+            Self::Suspended => None,
+        }
+    }
+
+    /// Returns `interval` stretched by the factor, or `None` if periodic tasks are
+    /// paused. Saturates instead of overflowing.
+    pub fn stretch(self, interval: Duration) -> Option<Duration> {
+        let factor = self.factor()?;
+        Some(
+            interval
+                .checked_mul(factor)
+                .unwrap_or_else(|| Duration::from_secs(u64::MAX)),
+        )
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    level: ActivityLevel,
+    changed: Vec<oneshot::Sender<()>>,
+}
+
+/// Shared activity level of a node, which periodic tasks sleep on.
+#[derive(Clone, Default)]
+pub struct Activity(Arc<Mutex<Inner>>);
+
+impl Activity {
+    pub fn level(&self) -> ActivityLevel {
+        self.0.lock().level
+    }
+
+    /// Changes the activity level, waking the sleeping tasks so that they adjust their
+    /// timers right away.
+    pub fn set_level(&self, level: ActivityLevel) {
+        let mut inner = self.0.lock();
+        if inner.level != level {
+            tracing::debug!("activity level changed to {:?}", level);
+            inner.level = level;
+            for tx in inner.changed.drain(..) {
+                tx.send(()).ok();
+            }
+        }
+    }
+
+    /// Returns the activity level and a receiver that completes when it changes.
+    pub(crate) fn watch(&self) -> (ActivityLevel, oneshot::Receiver<()>) {
+        let mut inner = self.0.lock();
+        inner.changed.retain(|tx| !tx.is_canceled());
+        let (tx, rx) = oneshot::channel();
+        inner.changed.push(tx);
+        (inner.level, rx)
+    }
+
+    /// Completes once `interval` stretched by the activity level elapsed. While the node
+    /// is suspended it doesn't complete.
+    pub fn sleep(&self, interval: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let activity = self.clone();
+        let start = Instant::now();
+        async move {
+            loop {
+                let (level, changed) = activity.watch();
+                let deadline = level
+                    .stretch(interval)
+                    .and_then(|interval| start.checked_add(interval));
+                let timer = match deadline {
+                    Some(deadline) => Either::Left(Timer::at(deadline)),
+                    None => Either::Right(future::pending()),
+                };
+                if let Either::Left(_) = future::select(timer, changed).await {
+                    return;
+                }
+            }
+        }
+    }
+}
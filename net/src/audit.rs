@@ -0,0 +1,177 @@
+use libipld::Result;
+use libp2p::PeerId;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of lines queued for the writer before lines are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// Kind of an outbound request recorded in the audit log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuditKind {
+    DhtBootstrap,
+    DhtGetRecord,
+    DhtPutRecord,
+    DhtProvide,
+    DhtGetProviders,
+    BitswapWant,
+    GossipPublish,
+}
+
+impl AuditKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::DhtBootstrap => "dht_bootstrap",
+            Self::DhtGetRecord => "dht_get_record",
+            Self::DhtPutRecord => "dht_put_record",
+            Self::DhtProvide => "dht_provide",
+            Self::DhtGetProviders => "dht_get_providers",
+            Self::BitswapWant => "bitswap_want",
+            Self::GossipPublish => "gossip_publish",
+        }
+    }
+}
+
+impl std::fmt::Display for AuditKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Audit log configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditConfig {
+    /// Path of the log file. Rotated files get the suffix `.1`, `.2` and so on, with `.1`
+    /// being the most recent.
+    pub path: PathBuf,
+    /// Size in bytes after which the log file is rotated.
+    pub max_file_size: u64,
+    /// Number of rotated files that are kept.
+    pub max_files: usize,
+}
+
+impl AuditConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_file_size: 1024 * 1024 * 16,
+            max_files: 8,
+        }
+    }
+}
+
+/// Formats bytes as lower case hex.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the path of the `n`th rotated file of `path`.
+pub(crate) fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", n));
+    path.into()
+}
+
+/// Writes the lines of the audit log, rotating the log file.
+struct AuditWriter {
+    config: AuditConfig,
+    file: File,
+    size: u64,
+}
+
+impl AuditWriter {
+    fn rotate(&mut self) -> Result<()> {
+        let path = &self.config.path;
+        if self.config.max_files == 0 {
+            self.file = File::create(path)?;
+        } else {
+            for n in (1..self.config.max_files).rev() {
+                let from = rotated(path, n);
+                if from.exists() {
+                    std::fs::rename(from, rotated(path, n + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated(path, 1))?;
+            self.file = File::create(path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_file_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Log of the outbound requests of the node.
+///
+/// Every request is recorded on a line with the unix timestamp in milliseconds, the kind
+/// of request, its target and the comma separated peers it was sent to, separated by
+/// tabs. The peers of dht queries are selected while the query runs and are not
+/// recorded.
+///
+/// The lines are written by a blocking task, so recording a request never blocks the
+/// swarm. When the writer falls behind by more than `QUEUE_SIZE` lines, lines are
+/// dropped.
+pub struct AuditLog {
+    tx: SyncSender<String>,
+    dropped: u64,
+}
+
+impl AuditLog {
+    pub fn open(config: AuditConfig) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+        let mut writer = AuditWriter { config, file, size };
+        let (tx, rx) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        ipfs_embed_rt::spawn(ipfs_embed_rt::spawn_blocking(move || {
+            for line in rx {
+                if let Err(err) = writer.write(&line) {
+                    tracing::warn!("failed to write audit log: {}", err);
+                }
+            }
+        }))
+        .detach();
+        Ok(Self { tx, dropped: 0 })
+    }
+
+    pub fn record<'a>(
+        &mut self,
+        kind: AuditKind,
+        target: &str,
+        peers: impl IntoIterator<Item = &'a PeerId>,
+    ) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let peers = peers
+            .into_iter()
+            .map(|peer| peer.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let line = format!("{}\t{}\t{}\t{}\n", time, kind, target, peers);
+        match self.tx.try_send(line) {
+            Ok(()) if self.dropped > 0 => {
+                tracing::warn!("audit log dropped {} lines", self.dropped);
+                self.dropped = 0;
+            }
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::warn!("failed to write audit log: the writer stopped");
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use async_trait::async_trait;
+use fnv::FnvHashMap;
+use futures::future::{self, Either};
+use ipfs_embed_rt::Timer;
+use libipld::Result;
+use libp2p::core::PublicKey;
+use libp2p::{Multiaddr, PeerId};
+use parking_lot::Mutex;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Identity and metadata a peer sent with the identify protocol.
+#[derive(Clone, Debug)]
+pub struct PeerIdentity {
+    /// The peer.
+    pub peer_id: PeerId,
+    /// Public key the peer id is derived from.
+    pub public_key: PublicKey,
+    /// Protocol family of the peer, e.g. `ipfs/1.0.0`.
+    pub protocol_version: String,
+    /// Name and version of the peer's implementation.
+    pub agent_version: String,
+    /// Addresses the peer is listening on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Protocols supported by the peer.
+    pub protocols: Vec<String>,
+}
+
+/// Authenticates peers after they identified themselves, for example by checking that
+/// their public key belongs to a signed-in user.
+///
+/// A peer is authenticated once per connection. Its blocks, pushes and rendezvous
+/// requests aren't served until the authenticator accepted it. Peers the authenticator
+/// rejects or that aren't accepted within `NetworkConfig::auth_timeout` are disconnected
+/// and banned for `NetworkConfig::auth_backoff`, and their `PeerStats::auth_failures` is
+/// incremented.
+#[async_trait]
+pub trait Authenticator: Send + Sync + 'static {
+    /// Returns an error if the peer isn't allowed to stay connected.
+    async fn authenticate(&self, identity: &PeerIdentity) -> Result<()>;
+}
+
+/// Error returned when the authenticator didn't accept a peer in time.
+#[derive(Debug, Error)]
+#[error("authentication timed out after {0:?}")]
+pub struct AuthTimeout(pub Duration);
+
+/// Authenticates the peer, failing with `AuthTimeout` if the authenticator doesn't
+/// complete within `timeout`.
+pub(crate) async fn authenticate(
+    authenticator: &dyn Authenticator,
+    identity: &PeerIdentity,
+    timeout: Duration,
+) -> Result<()> {
+    let auth = authenticator.authenticate(identity);
+    match future::select(auth, Timer::after(timeout)).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(AuthTimeout(timeout).into()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AuthState {
+    Pending,
+    Accepted,
+}
+
+#[derive(Default)]
+struct Gate {
+    enabled: bool,
+    peers: FnvHashMap<PeerId, AuthState>,
+}
+
+/// Tracks which connected peers the authenticator accepted. All peers are accepted while
+/// no authenticator is installed.
+#[derive(Clone, Default)]
+pub(crate) struct AuthGate(Arc<Mutex<Gate>>);
+
+impl AuthGate {
+    /// Enables or disables authentication, forgetting the peers accepted so far.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut gate = self.0.lock();
+        gate.enabled = enabled;
+        gate.peers.clear();
+    }
+
+    /// Marks the authentication of `peer` as pending. Returns `false` if authentication
+    /// is disabled or the peer is already authenticated.
+    pub fn begin(&self, peer: PeerId) -> bool {
+        let mut gate = self.0.lock();
+        if !gate.enabled {
+            return false;
+        }
+        match gate.peers.entry(peer) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(AuthState::Pending);
+                true
+            }
+        }
+    }
+
+    /// Accepts `peer` if its authentication is pending.
+    pub fn accept(&self, peer: &PeerId) {
+        if let Some(state) = self.0.lock().peers.get_mut(peer) {
+            *state = AuthState::Accepted;
+        }
+    }
+
+    pub fn is_accepted(&self, peer: &PeerId) -> bool {
+        let gate = self.0.lock();
+        !gate.enabled || gate.peers.get(peer) == Some(&AuthState::Accepted)
+    }
+
+    /// Forgets `peer` once it disconnected, so that it is authenticated again when it
+    /// reconnects.
+    pub fn remove(&self, peer: &PeerId) {
+        self.0.lock().peers.remove(peer);
+    }
+}
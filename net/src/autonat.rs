@@ -0,0 +1,559 @@
+use async_trait::async_trait;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::io::{AsyncRead, AsyncWrite};
+use ipfs_embed_rt::{Instant, Timer};
+use libp2p::core::connection::{ConnectedPoint, ConnectionId, ListenerId};
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::upgrade::{read_one, read_varint, write_varint, write_with_len_prefix};
+use libp2p::core::ProtocolName;
+use libp2p::request_response::{
+    ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+};
+use libp2p::swarm::protocols_handler::{IntoProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Maximum number of addresses a peer asks to be dialed at.
+const MAX_ADDRS: usize = 16;
+/// Maximum size of an encoded address.
+const MAX_ADDR_SIZE: usize = 1024;
+/// Maximum number of addresses dialed for a single request.
+const MAX_DIALS_PER_REQUEST: usize = 4;
+/// Maximum number of requests served at the same time.
+const MAX_SERVING: usize = 16;
+/// Number of failed dial backs after which the node is considered private.
+const CONFIDENCE: usize = 3;
+/// Interval at which the reachability is probed again once it is known.
+const PROBE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Interval at which a probe is retried when no peer could be asked.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Timeout of a probe, covering the dial back by the peer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct AutoNatProtocol;
+
+impl ProtocolName for AutoNatProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/ipfs-embed/autonat/1.0.0"
+    }
+}
+
+/// Addresses the requesting peer asks to be dialed at.
+pub type DialRequest = Vec<Multiaddr>;
+
+/// Outcome of a dial back.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DialResponse {
+    /// The peer was reached at the address.
+    Ok(Multiaddr),
+    /// None of the addresses could be dialed.
+    DialError,
+    /// The request was refused, for example because none of the addresses matched the
+    /// ip the peer connected from.
+    Refused,
+}
+
+/// Reachability of the node from the internet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NatStatus {
+    /// Not enough peers dialed the node back yet.
+    Unknown,
+    /// A peer dialed the node back at one of its addresses.
+    Public,
+    /// Several peers failed to dial the node back, the node is behind a nat or firewall.
+    Private,
+}
+
+impl Default for NatStatus {
+    fn default() -> Self {
+        Self::Unknown
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AutoNatCodec;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_addr(bytes: Vec<u8>) -> io::Result<Multiaddr> {
+    Multiaddr::try_from(bytes).map_err(|_| invalid_data("invalid multiaddr"))
+}
+
+#[async_trait]
+impl RequestResponseCodec for AutoNatCodec {
+    type Protocol = AutoNatProtocol;
+    type Request = DialRequest;
+    type Response = DialResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let len = read_varint(io).await?;
+        if len > MAX_ADDRS {
+            return Err(invalid_data("too many addresses"));
+        }
+        let mut addrs = Vec::with_capacity(len);
+        for _ in 0..len {
+            let addr = read_one(io, MAX_ADDR_SIZE)
+                .await
+                .map_err(|_| invalid_data("invalid address"))?;
+            addrs.push(read_addr(addr)?);
+        }
+        Ok(addrs)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut res = read_one(io, MAX_ADDR_SIZE + 1)
+            .await
+            .map_err(|_| invalid_data("invalid response"))?;
+        if res.is_empty() {
+            return Err(invalid_data("empty response"));
+        }
+        let addr = res.split_off(1);
+        match res[0] {
+            0 => Ok(DialResponse::Ok(read_addr(addr)?)),
+            1 => Ok(DialResponse::DialError),
+            2 => Ok(DialResponse::Refused),
+            _ => Err(invalid_data("invalid response status")),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        addrs: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_varint(io, addrs.len()).await?;
+        for addr in addrs {
+            write_with_len_prefix(io, addr.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = match res {
+            DialResponse::Ok(addr) => {
+                let mut bytes = vec![0];
+                bytes.extend_from_slice(addr.as_ref());
+                bytes
+            }
+            DialResponse::DialError => vec![1],
+            DialResponse::Refused => vec![2],
+        };
+        write_with_len_prefix(io, bytes).await
+    }
+}
+
+/// Returns the ip of an address.
+pub(crate) fn ip(addr: &Multiaddr) -> Option<IpAddr> {
+    match addr.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+/// A request of a peer that is being served.
+struct Serving {
+    addrs: FnvHashSet<Multiaddr>,
+    channel: ResponseChannel<DialResponse>,
+}
+
+/// Determines whether the node is reachable by asking peers to dial it back, and dials
+/// back peers asking for it.
+///
+/// Peers only dial back addresses on the ip the requesting peer connected from, so the
+/// protocol can't be used to make a node dial arbitrary hosts.
+pub struct AutoNat {
+    inner: RequestResponse<AutoNatCodec>,
+    probe: bool,
+    status: NatStatus,
+    failures: usize,
+    connected: FnvHashMap<PeerId, IpAddr>,
+    probed: FnvHashMap<PeerId, Instant>,
+    in_flight: Option<RequestId>,
+    addrs: Vec<Multiaddr>,
+    next_probe: Option<Timer>,
+    serving: FnvHashMap<PeerId, Serving>,
+    dialing: FnvHashMap<Multiaddr, PeerId>,
+    actions: VecDeque<NetworkBehaviourAction<HandlerIn, NatStatus>>,
+}
+
+impl AutoNat {
+    /// Creates a new `AutoNat` behaviour. Requests of peers are always served, the node
+    /// only probes its own reachability if `probe` is set.
+    pub fn new(probe: bool) -> Self {
+        let mut config = RequestResponseConfig::default();
+        config.set_request_timeout(PROBE_TIMEOUT);
+        let inner = RequestResponse::new(
+            AutoNatCodec,
+            std::iter::once((AutoNatProtocol, ProtocolSupport::Full)),
+            config,
+        );
+        Self {
+            inner,
+            probe,
+            status: NatStatus::Unknown,
+            failures: 0,
+            connected: Default::default(),
+            probed: Default::default(),
+            in_flight: None,
+            addrs: Default::default(),
+            next_probe: None,
+            serving: Default::default(),
+            dialing: Default::default(),
+            actions: Default::default(),
+        }
+    }
+
+    /// Returns the reachability of the node.
+    pub fn status(&self) -> NatStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: NatStatus) {
+        if self.status != status {
+            tracing::info!("nat status changed to {:?}", status);
+            self.status = status;
+            self.actions
+                .push_back(NetworkBehaviourAction::GenerateEvent(status));
+        }
+    }
+
+    fn schedule_probe(&mut self, after: Duration) {
+        self.next_probe = Some(Timer::after(after));
+    }
+
+    /// Asks a connected peer that wasn't asked recently to dial the node back.
+    fn probe(&mut self) {
+        if self.in_flight.is_some() || self.addrs.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let probed = &self.probed;
+        let peer = self.connected.keys().find(|peer| {
+            probed
+                .get(peer)
+                .map(|at| now.duration_since(*at) >= PROBE_INTERVAL)
+                .unwrap_or(true)
+        });
+        if let Some(peer) = peer.copied() {
+            tracing::debug!("asking {} to dial back {:?}", peer, self.addrs);
+            self.probed.insert(peer, now);
+            self.in_flight = Some(self.inner.send_request(&peer, self.addrs.clone()));
+        }
+    }
+
+    fn probe_result(&mut self, res: DialResponse) {
+        self.in_flight = None;
+        match res {
+            DialResponse::Ok(addr) => {
+                tracing::debug!("dialed back at {}", addr);
+                self.failures = 0;
+                self.set_status(NatStatus::Public);
+                self.schedule_probe(PROBE_INTERVAL);
+            }
+            DialResponse::DialError => {
+                self.failures += 1;
+                if self.failures >= CONFIDENCE {
+                    self.set_status(NatStatus::Private);
+                    self.schedule_probe(PROBE_INTERVAL);
+                } else {
+                    self.schedule_probe(Duration::default());
+                }
+            }
+            DialResponse::Refused => self.schedule_probe(Duration::default()),
+        }
+    }
+
+    fn serve(&mut self, peer: PeerId, addrs: DialRequest, channel: ResponseChannel<DialResponse>) {
+        let observed = self.connected.get(&peer).copied();
+        let addrs = addrs
+            .into_iter()
+            .filter(|addr| ip(addr).is_some() && ip(addr) == observed)
+            .filter(|addr| !self.dialing.contains_key(addr))
+            .take(MAX_DIALS_PER_REQUEST)
+            .collect::<FnvHashSet<_>>();
+        if addrs.is_empty() || self.serving.len() >= MAX_SERVING || self.serving.contains_key(&peer)
+        {
+            self.inner
+                .send_response(channel, DialResponse::Refused)
+                .ok();
+            return;
+        }
+        for addr in &addrs {
+            tracing::trace!("dialing back {} at {}", peer, addr);
+            self.dialing.insert(addr.clone(), peer);
+            self.actions.push_back(NetworkBehaviourAction::DialAddress {
+                address: addr.clone(),
+            });
+        }
+        self.serving.insert(peer, Serving { addrs, channel });
+    }
+
+    /// Answers the request of `peer` once one of its addresses was reached or all failed.
+    fn dialed(&mut self, peer: PeerId, addr: &Multiaddr, reached: bool) {
+        let serving = match self.serving.get_mut(&peer) {
+            Some(serving) => serving,
+            None => return,
+        };
+        serving.addrs.remove(addr);
+        let res = if reached {
+            DialResponse::Ok(addr.clone())
+        } else if serving.addrs.is_empty() {
+            DialResponse::DialError
+        } else {
+            return;
+        };
+        let serving = self.serving.remove(&peer).unwrap();
+        for addr in &serving.addrs {
+            self.dialing.remove(addr);
+        }
+        self.inner.send_response(serving.channel, res).ok();
+    }
+
+    fn inject_autonat_event(&mut self, event: RequestResponseEvent<DialRequest, DialResponse>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => self.serve(peer, request, channel),
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                if self.in_flight == Some(request_id) {
+                    self.probe_result(response);
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if self.in_flight == Some(request_id) {
+                    tracing::debug!("failed to ask {} to dial back: {:?}", peer, error);
+                    self.in_flight = None;
+                    self.schedule_probe(Duration::default());
+                }
+            }
+            RequestResponseEvent::InboundFailure { .. } => {}
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Collects the addresses peers are asked to dial. Returns `true` if they changed.
+    fn update_addrs(&mut self, params: &mut impl PollParameters) -> bool {
+        let mut addrs = params
+            .external_addresses()
+            .map(|record| record.addr)
+            .chain(params.listened_addresses())
+            .filter(|addr| ip(addr).is_some())
+            .collect::<Vec<_>>();
+        addrs.sort();
+        addrs.dedup();
+        addrs.truncate(MAX_ADDRS);
+        if addrs == self.addrs {
+            return false;
+        }
+        self.addrs = addrs;
+        true
+    }
+}
+
+type Handler = <RequestResponse<AutoNatCodec> as NetworkBehaviour>::ProtocolsHandler;
+type HandlerEvent = <<Handler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent;
+type HandlerIn = <<Handler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent;
+
+impl NetworkBehaviour for AutoNat {
+    type ProtocolsHandler = Handler;
+    type OutEvent = NatStatus;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.connected.remove(peer_id);
+        if let Some(serving) = self.serving.remove(peer_id) {
+            for addr in &serving.addrs {
+                self.dialing.remove(addr);
+            }
+        }
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let remote = match endpoint {
+            ConnectedPoint::Dialer { address } => {
+                if let Some(peer) = self.dialing.remove(address) {
+                    // another peer answering at the address doesn't prove reachability.
+                    self.dialed(peer, address, peer == *peer_id);
+                }
+                address
+            }
+            ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+        };
+        if let Some(ip) = ip(remote) {
+            self.connected.insert(*peer_id, ip);
+            if self.probe && self.status == NatStatus::Unknown && self.next_probe.is_none() {
+                self.schedule_probe(Duration::default());
+            }
+        }
+        self.inner
+            .inject_connection_established(peer_id, id, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner.inject_connection_closed(peer_id, id, endpoint)
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inner.inject_address_change(peer_id, id, old, new)
+    }
+
+    fn inject_event(&mut self, peer_id: PeerId, connection: ConnectionId, event: HandlerEvent) {
+        self.inner.inject_event(peer_id, connection, event)
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn Error,
+    ) {
+        if let Some(peer) = self.dialing.remove(addr) {
+            self.dialed(peer, addr, false);
+        }
+        self.inner.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.inner.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_listen_addr(addr)
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_expired_listen_addr(addr)
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_external_addr(addr)
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn Error + 'static)) {
+        self.inner.inject_listener_error(id, err)
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        self.inner.inject_listener_closed(id, reason)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<HandlerIn, NatStatus>> {
+        if self.probe {
+            // the swarm doesn't notify behaviours of external addresses added by the
+            // user, so the addresses are compared on every poll.
+            if self.update_addrs(params) {
+                // peers are asked again about the new addresses.
+                self.failures = 0;
+                self.probed.clear();
+                self.schedule_probe(Duration::default());
+            }
+            if let Some(timer) = self.next_probe.as_mut() {
+                if Pin::new(timer).poll(cx).is_ready() {
+                    self.next_probe = None;
+                    self.probe();
+                    // while the status is unknown, the next peer that connects is asked.
+                    if self.in_flight.is_none() && self.status != NatStatus::Unknown {
+                        self.schedule_probe(RETRY_INTERVAL);
+                    }
+                }
+            }
+        }
+        loop {
+            if let Some(action) = self.actions.pop_front() {
+                return Poll::Ready(action);
+            }
+            match self.inner.poll(cx, params) {
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
+                    self.inject_autonat_event(event)
+                }
+                Poll::Ready(action) => {
+                    return Poll::Ready(action.map_out(|_| unreachable!("handled above")))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use fnv::FnvHashMap;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use ipfs_embed_rt::{Instant, Timer};
+use libp2p::core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::PeerId;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Upload and download limits in bytes per second. `None` means unlimited and `Some(0)`
+/// pauses the transfers in that direction until the limit is raised.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BandwidthLimits {
+    /// Upload limit in bytes per second.
+    pub upload: Option<u64>,
+    /// Download limit in bytes per second.
+    pub download: Option<u64>,
+}
+
+impl BandwidthLimits {
+    /// No limits.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+/// Token bucket allowing bursts of up to one second worth of traffic.
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        self.rate = rate;
+        if let Some(rate) = rate {
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    /// Returns the number of bytes that can be transferred or `None` if unlimited.
+    fn available(&mut self, now: Instant) -> Option<f64> {
+        let rate = self.rate? as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last = now;
+        Some(self.tokens)
+    }
+
+    /// Returns the time until a single byte can be transferred. A paused bucket is checked
+    /// again after a second.
+    fn delay(&self) -> Duration {
+        match self.rate {
+            Some(rate) if rate > 0 => {
+                Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / rate as f64)
+            }
+            Some(_) => Duration::from_secs(1),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        if self.rate.is_some() {
+            self.tokens -= n as f64;
+        }
+    }
+}
+
+struct Buckets {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl Buckets {
+    fn new(limits: BandwidthLimits) -> Self {
+        Self {
+            upload: Bucket::new(limits.upload),
+            download: Bucket::new(limits.download),
+        }
+    }
+
+    fn set_limits(&mut self, limits: BandwidthLimits) {
+        self.upload.set_rate(limits.upload);
+        self.download.set_rate(limits.download);
+    }
+
+    fn bucket(&mut self, dir: Direction) -> &mut Bucket {
+        match dir {
+            Direction::Upload => &mut self.upload,
+            Direction::Download => &mut self.download,
+        }
+    }
+}
+
+struct Limiter {
+    global: Buckets,
+    peers: FnvHashMap<PeerId, Buckets>,
+    /// Bytes sent to and received from each peer.
+    traffic: FnvHashMap<PeerId, (u64, u64)>,
+}
+
+/// Limits the bandwidth of all connections and of connections to individual peers.
+#[derive(Clone)]
+pub struct BandwidthLimiter(Arc<Mutex<Limiter>>);
+
+impl BandwidthLimiter {
+    /// Creates a new limiter with global `limits`.
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Self(Arc::new(Mutex::new(Limiter {
+            global: Buckets::new(limits),
+            peers: Default::default(),
+            traffic: Default::default(),
+        })))
+    }
+
+    /// Sets the limits of all connections.
+    pub fn set_limits(&self, limits: BandwidthLimits) {
+        self.0.lock().global.set_limits(limits);
+    }
+
+    /// Sets the limits of connections to `peer`. `None` removes the limits.
+    pub fn set_peer_limits(&self, peer: PeerId, limits: Option<BandwidthLimits>) {
+        let mut limiter = self.0.lock();
+        if let Some(limits) = limits {
+            limiter
+                .peers
+                .entry(peer)
+                .or_insert_with(|| Buckets::new(limits))
+                .set_limits(limits);
+        } else {
+            limiter.peers.remove(&peer);
+        }
+    }
+
+    /// Returns the bytes sent to and received from each peer.
+    pub fn traffic(&self) -> Vec<(PeerId, u64, u64)> {
+        self.0
+            .lock()
+            .traffic
+            .iter()
+            .map(|(peer, (sent, received))| (*peer, *sent, *received))
+            .collect()
+    }
+
+    /// Sets the bytes sent to and received from `peer`.
+    pub fn set_traffic(&self, peer: PeerId, sent: u64, received: u64) {
+        self.0.lock().traffic.insert(peer, (sent, received));
+    }
+
+    /// Returns how many of the `wanted` bytes can be transferred or how long to wait.
+    fn acquire(&self, peer: &PeerId, dir: Direction, wanted: usize) -> Result<usize, Duration> {
+        let mut limiter = self.0.lock();
+        let limiter = &mut *limiter;
+        let now = Instant::now();
+        let mut buckets = vec![limiter.global.bucket(dir)];
+        if let Some(peer) = limiter.peers.get_mut(peer) {
+            buckets.push(peer.bucket(dir));
+        }
+        let mut allowed = wanted;
+        let mut delay = Duration::from_secs(0);
+        for bucket in buckets {
+            if let Some(available) = bucket.available(now) {
+                if available < 1.0 {
+                    delay = delay.max(bucket.delay());
+                } else {
+                    allowed = allowed.min(available as usize);
+                }
+            }
+        }
+        if delay > Duration::from_secs(0) {
+            Err(delay)
+        } else {
+            Ok(allowed)
+        }
+    }
+
+    fn consume(&self, peer: &PeerId, dir: Direction, n: usize) {
+        let mut limiter = self.0.lock();
+        limiter.global.bucket(dir).consume(n);
+        if let Some(peer) = limiter.peers.get_mut(peer) {
+            peer.bucket(dir).consume(n);
+        }
+        let traffic = limiter.traffic.entry(*peer).or_default();
+        match dir {
+            Direction::Upload => traffic.0 += n as u64,
+            Direction::Download => traffic.1 += n as u64,
+        }
+    }
+}
+
+/// Connection that is throttled by a `BandwidthLimiter`.
+pub struct Throttled<S> {
+    inner: S,
+    peer: PeerId,
+    limiter: BandwidthLimiter,
+    read_delay: Option<Timer>,
+    write_delay: Option<Timer>,
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, peer: PeerId, limiter: BandwidthLimiter) -> Self {
+        Self {
+            inner,
+            peer,
+            limiter,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// Waits until `wanted` bytes can be transferred, returning the number of bytes allowed.
+fn poll_acquire(
+    limiter: &BandwidthLimiter,
+    peer: &PeerId,
+    dir: Direction,
+    wanted: usize,
+    delay: &mut Option<Timer>,
+    cx: &mut Context,
+) -> Poll<usize> {
+    loop {
+        if let Some(timer) = delay.as_mut() {
+            ready!(Pin::new(timer).poll(cx));
+            *delay = None;
+        }
+        match limiter.acquire(peer, dir, wanted) {
+            Ok(allowed) => return Poll::Ready(allowed),
+            Err(duration) => *delay = Some(Timer::after(duration)),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowed = ready!(poll_acquire(
+            &this.limiter,
+            &this.peer,
+            Direction::Download,
+            buf.len(),
+            &mut this.read_delay,
+            cx,
+        ));
+        let res = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..allowed]));
+        if let Ok(n) = &res {
+            this.limiter.consume(&this.peer, Direction::Download, *n);
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowed = ready!(poll_acquire(
+            &this.limiter,
+            &this.peer,
+            Direction::Upload,
+            buf.len(),
+            &mut this.write_delay,
+            cx,
+        ));
+        let res = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]));
+        if let Ok(n) = &res {
+            this.limiter.consume(&this.peer, Direction::Upload, *n);
+        }
+        Poll::Ready(res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Upgrade that throttles the connection before applying the `inner` upgrade.
+#[derive(Clone)]
+pub(crate) struct ThrottledUpgrade<U> {
+    inner: U,
+    peer: PeerId,
+    limiter: BandwidthLimiter,
+}
+
+impl<U> ThrottledUpgrade<U> {
+    pub fn new(inner: U, peer: PeerId, limiter: BandwidthLimiter) -> Self {
+        Self {
+            inner,
+            peer,
+            limiter,
+        }
+    }
+}
+
+impl<U: UpgradeInfo> UpgradeInfo for ThrottledUpgrade<U> {
+    type Info = U::Info;
+    type InfoIter = U::InfoIter;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.inner.protocol_info()
+    }
+}
+
+impl<C, U: InboundUpgrade<Throttled<C>>> InboundUpgrade<C> for ThrottledUpgrade<U> {
+    type Output = U::Output;
+    type Error = U::Error;
+    type Future = U::Future;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let socket = Throttled::new(socket, self.peer, self.limiter);
+        self.inner.upgrade_inbound(socket, info)
+    }
+}
+
+impl<C, U: OutboundUpgrade<Throttled<C>>> OutboundUpgrade<C> for ThrottledUpgrade<U> {
+    type Output = U::Output;
+    type Error = U::Error;
+    type Future = U::Future;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        let socket = Throttled::new(socket, self.peer, self.limiter);
+        self.inner.upgrade_outbound(socket, info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{self, Ready};
+    use futures::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+
+    #[test]
+    fn test_bucket_refill() {
+        let mut bucket = Bucket::new(Some(100));
+        let now = bucket.last;
+        // starts with a burst of one second worth of traffic.
+        assert_eq!(bucket.available(now), Some(100.0));
+        bucket.consume(100);
+        assert_eq!(bucket.available(now), Some(0.0));
+        assert_eq!(bucket.delay(), Duration::from_millis(10));
+        assert_eq!(
+            bucket.available(now + Duration::from_millis(500)),
+            Some(50.0)
+        );
+        // the burst is capped at one second.
+        assert_eq!(bucket.available(now + Duration::from_secs(10)), Some(100.0));
+    }
+
+    #[test]
+    fn test_bucket_unlimited_and_paused() {
+        let mut unlimited = Bucket::new(None);
+        assert_eq!(unlimited.available(Instant::now()), None);
+        assert_eq!(unlimited.delay(), Duration::from_secs(0));
+
+        let mut paused = Bucket::new(Some(0));
+        let now = paused.last;
+        assert_eq!(paused.available(now + Duration::from_secs(10)), Some(0.0));
+        assert_eq!(paused.delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_bucket_set_rate() {
+        let mut bucket = Bucket::new(Some(100));
+        let now = bucket.last;
+        bucket.set_rate(Some(10));
+        assert_eq!(bucket.available(now), Some(10.0));
+        bucket.set_rate(None);
+        assert_eq!(bucket.available(now), None);
+    }
+
+    #[test]
+    fn test_peer_limits() {
+        let limiter = BandwidthLimiter::new(BandwidthLimits {
+            upload: Some(1000),
+            download: None,
+        });
+        let (limited, other) = (PeerId::random(), PeerId::random());
+        limiter.set_peer_limits(
+            limited,
+            Some(BandwidthLimits {
+                upload: Some(10),
+                download: None,
+            }),
+        );
+        assert_eq!(limiter.acquire(&limited, Direction::Upload, 500), Ok(10));
+        assert_eq!(limiter.acquire(&other, Direction::Upload, 500), Ok(500));
+        assert_eq!(limiter.acquire(&limited, Direction::Download, 500), Ok(500));
+
+        limiter.consume(&limited, Direction::Upload, 10);
+        assert!(limiter.acquire(&limited, Direction::Upload, 500).is_err());
+        // the global bucket still has tokens left.
+        assert!(limiter.acquire(&other, Direction::Upload, 500).is_ok());
+        assert_eq!(limiter.traffic(), vec![(limited, 10, 0)]);
+
+        limiter.set_peer_limits(limited, None);
+        assert!(limiter.acquire(&limited, Direction::Upload, 500).is_ok());
+    }
+
+    #[test]
+    fn test_set_limits_at_runtime() {
+        let limiter = BandwidthLimiter::new(BandwidthLimits::unlimited());
+        let peer = PeerId::random();
+        assert_eq!(limiter.acquire(&peer, Direction::Download, 500), Ok(500));
+        limiter.set_limits(BandwidthLimits {
+            upload: None,
+            download: Some(0),
+        });
+        assert_eq!(
+            limiter.acquire(&peer, Direction::Download, 500),
+            Err(Duration::from_secs(1))
+        );
+        assert_eq!(limiter.acquire(&peer, Direction::Upload, 500), Ok(500));
+        limiter.set_limits(BandwidthLimits::unlimited());
+        assert_eq!(limiter.acquire(&peer, Direction::Download, 500), Ok(500));
+    }
+
+    /// Upgrade returning the throttled socket.
+    struct Socket;
+
+    impl UpgradeInfo for Socket {
+        type Info = &'static [u8];
+        type InfoIter = std::iter::Once<Self::Info>;
+
+        fn protocol_info(&self) -> Self::InfoIter {
+            std::iter::once(&b"/socket"[..])
+        }
+    }
+
+    impl<C> InboundUpgrade<Throttled<C>> for Socket {
+        type Output = Throttled<C>;
+        type Error = io::Error;
+        type Future = Ready<io::Result<Throttled<C>>>;
+
+        fn upgrade_inbound(self, socket: Throttled<C>, _: Self::Info) -> Self::Future {
+            future::ready(Ok(socket))
+        }
+    }
+
+    #[test]
+    fn test_throttled_upgrade() {
+        futures::executor::block_on(async {
+            let limiter = BandwidthLimiter::new(BandwidthLimits {
+                upload: Some(1000),
+                download: Some(4),
+            });
+            let peer = PeerId::random();
+            let upgrade = ThrottledUpgrade::new(Socket, peer, limiter.clone());
+            let socket = Cursor::new(b"hello world".to_vec());
+            let mut socket = upgrade
+                .upgrade_inbound(socket, &b"/socket"[..])
+                .await
+                .unwrap();
+
+            // a read only receives the bytes the download bucket allows.
+            let mut buf = [0; 16];
+            assert_eq!(socket.read(&mut buf).await.unwrap(), 4);
+            assert_eq!(&buf[..4], b"hell");
+            socket.write_all(b"!").await.unwrap();
+            assert_eq!(limiter.traffic(), vec![(peer, 1, 4)]);
+        });
+    }
+}
@@ -0,0 +1,241 @@
+use crate::peers::AddressSource;
+use crate::NetworkService;
+use futures::future::{self, Either, FutureExt};
+use ipfs_embed_rt::UdpSocket;
+use libipld::store::StoreParams;
+use libp2p::core::identity::{Keypair, PublicKey};
+use libp2p::{Multiaddr, PeerId};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::convert::TryFrom;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8] = b"/ipfs-embed/beacon/1.0.0";
+const MAX_BEACON_SIZE: usize = 8192;
+
+/// Configuration of the lan discovery using udp broadcast beacons. It can be used
+/// instead of mdns on networks that block multicast.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BeaconConfig {
+    /// Udp port the beacons are sent to and received on.
+    pub port: u16,
+    /// Address the beacons are sent to.
+    pub broadcast: Ipv4Addr,
+    /// Interval between beacons.
+    pub interval: Duration,
+    /// Maximum clock difference to the sender of a beacon. Older beacons are ignored, so
+    /// that recorded beacons can't be replayed.
+    pub max_age: Duration,
+}
+
+impl Default for BeaconConfig {
+    fn default() -> Self {
+        Self {
+            port: 4002,
+            broadcast: Ipv4Addr::BROADCAST,
+            interval: Duration::from_secs(30),
+            max_age: Duration::from_secs(60 * 5),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if buf.len() < 2 + len {
+        return None;
+    }
+    let bytes = &buf[2..2 + len];
+    *buf = &buf[2 + len..];
+    Some(bytes)
+}
+
+/// Encodes a beacon announcing the `addrs` of the node, signed with the node key.
+fn encode(keypair: &Keypair, addrs: &[Multiaddr], time: u64) -> io::Result<Vec<u8>> {
+    let addrs = &addrs[..std::cmp::min(addrs.len(), u8::MAX as usize)];
+    let mut buf = MAGIC.to_vec();
+    put_bytes(&mut buf, &keypair.public().into_protobuf_encoding());
+    buf.extend_from_slice(&time.to_be_bytes());
+    buf.push(addrs.len() as u8);
+    for addr in addrs {
+        put_bytes(&mut buf, addr.as_ref());
+    }
+    let signature = keypair
+        .sign(&buf)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    put_bytes(&mut buf, &signature);
+    Ok(buf)
+}
+
+/// Decodes a beacon, returning the sender, its addresses and the time it was sent.
+/// Returns `None` if the beacon is malformed or the signature is invalid.
+fn decode(beacon: &[u8]) -> Option<(PeerId, Vec<Multiaddr>, u64)> {
+    if !beacon.starts_with(MAGIC) {
+        return None;
+    }
+    let mut buf = &beacon[MAGIC.len()..];
+    let public_key = PublicKey::from_protobuf_encoding(get_bytes(&mut buf)?).ok()?;
+    if buf.len() < 9 {
+        return None;
+    }
+    let mut time = [0; 8];
+    time.copy_from_slice(&buf[..8]);
+    let n = buf[8];
+    buf = &buf[9..];
+    let mut addrs = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        addrs.push(Multiaddr::try_from(get_bytes(&mut buf)?.to_vec()).ok()?);
+    }
+    let signed = &beacon[..beacon.len() - buf.len()];
+    let signature = get_bytes(&mut buf)?;
+    if !buf.is_empty() || !public_key.verify(signed, signature) {
+        return None;
+    }
+    Some((public_key.into_peer_id(), addrs, u64::from_be_bytes(time)))
+}
+
+fn bind(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_broadcast(true)?;
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    socket.bind(&SockAddr::from(addr))?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+/// Sends a beacon every `interval` and adds the addresses announced in the beacons of
+/// other nodes to the address book.
+pub(crate) async fn run<P: StoreParams>(service: NetworkService<P>, config: BeaconConfig) {
+    let socket = match bind(config.port) {
+        Ok(socket) => socket,
+        Err(err) => {
+            tracing::warn!("failed to bind beacon socket: {}", err);
+            return;
+        }
+    };
+    let activity = service.activity();
+    let target = SocketAddr::from((config.broadcast, config.port));
+    let mut buf = vec![0; MAX_BEACON_SIZE];
+    loop {
+        // read on every beacon, since the key may be rotated.
+        let keypair = service.node_key();
+        let local = keypair.public().into_peer_id();
+        let addrs = service.listeners();
+        if !addrs.is_empty() {
+            match encode(&keypair, &addrs, now()) {
+                Ok(beacon) => {
+                    if let Err(err) = socket.send_to(&beacon, target).await {
+                        tracing::debug!("failed to send beacon: {}", err);
+                    }
+                }
+                Err(err) => tracing::debug!("failed to sign beacon: {}", err),
+            }
+        }
+        let mut timer = activity.sleep(config.interval).boxed();
+        loop {
+            let res = {
+                let recv = socket.recv_from(&mut buf);
+                futures::pin_mut!(recv);
+                match future::select(recv, &mut timer).await {
+                    Either::Left((res, _)) => res,
+                    Either::Right(_) => break,
+                }
+            };
+            let len = match res {
+                Ok((len, _)) => len,
+                Err(err) => {
+                    tracing::debug!("failed to receive beacon: {}", err);
+                    continue;
+                }
+            };
+            let (peer, addrs, time) = match decode(&buf[..len]) {
+                Some(beacon) => beacon,
+                None => continue,
+            };
+            let received = now();
+            let age = std::cmp::max(received, time) - std::cmp::min(received, time);
+            if peer == local || Duration::from_secs(age) > config.max_age {
+                continue;
+            }
+            tracing::trace!("received beacon of {}", peer);
+            for addr in addrs {
+                service.add_discovered_address(&peer, addr, AddressSource::Beacon);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs() -> Vec<Multiaddr> {
+        vec![
+            "/ip4/192.168.1.2/tcp/4001".parse().unwrap(),
+            "/ip4/192.168.1.2/udp/4001/quic".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let keypair = Keypair::generate_ed25519();
+        let beacon = encode(&keypair, &addrs(), 42).unwrap();
+        let (peer, decoded, time) = decode(&beacon).unwrap();
+        assert_eq!(peer, keypair.public().into_peer_id());
+        assert_eq!(decoded, addrs());
+        assert_eq!(time, 42);
+    }
+
+    #[test]
+    fn test_decode_rejects_tampered() {
+        let keypair = Keypair::generate_ed25519();
+        let beacon = encode(&keypair, &addrs(), 42).unwrap();
+        // the time follows the magic and the length prefixed public key.
+        let key_len = u16::from_be_bytes([beacon[MAGIC.len()], beacon[MAGIC.len() + 1]]);
+        let time = MAGIC.len() + 2 + key_len as usize;
+        let mut tampered = beacon.clone();
+        tampered[time + 7] ^= 1;
+        assert!(decode(&tampered).is_none());
+        let mut tampered = beacon.clone();
+        tampered[0] ^= 1;
+        assert!(decode(&tampered).is_none());
+        let mut trailing = beacon.clone();
+        trailing.push(0);
+        assert!(decode(&trailing).is_none());
+    }
+
+    #[test]
+    fn test_decode_rejects_truncated() {
+        let keypair = Keypair::generate_ed25519();
+        let beacon = encode(&keypair, &addrs(), 42).unwrap();
+        for len in 0..beacon.len() {
+            assert!(decode(&beacon[..len]).is_none());
+        }
+    }
+
+    #[test]
+    fn test_encode_caps_addrs() {
+        let keypair = Keypair::generate_ed25519();
+        let addrs = vec![addrs()[0].clone(); 300];
+        let beacon = encode(&keypair, &addrs, 42).unwrap();
+        let (_, decoded, _) = decode(&beacon).unwrap();
+        assert_eq!(decoded.len(), u8::MAX as usize);
+    }
+}
@@ -1,76 +1,300 @@
-use crate::config::NetworkConfig;
-use crate::peers::{AddressBook, AddressSource, PeerInfo};
-use fnv::FnvHashMap;
+use crate::activity::Activity;
+#[cfg(feature = "dht")]
+use crate::audit;
+use crate::audit::{AuditKind, AuditLog};
+use crate::auth::{AuthGate, Authenticator, PeerIdentity};
+use crate::autonat::{AutoNat, NatStatus};
+use crate::config::{DhtMode, NetworkConfig};
+#[cfg(feature = "dht")]
+use crate::dht::Dht;
+use crate::dialback::{DialBack, DialBackEvent};
+#[cfg(any(not(feature = "mdns"), not(feature = "dht"), not(feature = "gossip")))]
+use crate::disabled::Disabled;
+#[cfg(not(feature = "dht"))]
+use crate::disabled::{Key, PeerRecord, Quorum, Record};
+use crate::exchange::ReceiverStore;
+use crate::health::Health;
+use crate::limits::{TraversalLimits, TraversalOrder, UnsupportedOrder};
+#[cfg(any(feature = "dht", feature = "gossip"))]
+use crate::pace::Paced;
+use crate::peers::{AddressBook, AddressSource, Event, PeerInfo, PeerStats};
+use crate::policy::{self, BlockPolicy, PolicyStore};
+use crate::push::{PushCodec, PushFailure, PushProtocol, PushRejected, PushRequest, PushResponse};
+use crate::rendezvous::{
+    Registrations, RendezvousCodec, RendezvousFailure, RendezvousProtocol, RendezvousRequest,
+    RendezvousResponse, RendezvousStatus,
+};
+use crate::retry::{ErrorClass, RetryPolicy};
+use crate::serve::{PeerScope, ServingStore};
+use crate::streams::{AppProtocol, AppStream, AppStreams, StreamChannel};
+use crate::votes::{AddressChange, AddressVotes, ObservedAddress};
+use crate::wants::{Priority, WantTable};
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::channel::{mpsc, oneshot};
-use futures::stream::Stream;
+use futures::stream::{Stream, StreamExt};
 use ip_network::IpNetwork;
+use ipfs_embed_rt::Instant;
+use libipld::error::BlockNotFound;
+use libipld::multihash::Multihash;
 use libipld::store::StoreParams;
-use libipld::{Cid, Result};
+use libipld::{Block, Cid, Result};
+use libp2p::core::connection::ListenerId;
+#[cfg(feature = "gossip")]
 use libp2p::gossipsub::{
     Gossipsub, GossipsubConfig, GossipsubEvent, GossipsubMessage, IdentTopic, MessageAuthenticity,
+    TopicHash,
 };
 use libp2p::identify::{Identify, IdentifyEvent};
+#[cfg(feature = "dht")]
 use libp2p::kad::record::store::MemoryStore;
+#[cfg(feature = "dht")]
 use libp2p::kad::record::{Key, Record};
+#[cfg(feature = "dht")]
 use libp2p::kad::{
-    AddProviderOk, BootstrapOk, GetProvidersOk, GetRecordOk, Kademlia, KademliaEvent, PeerRecord,
-    PutRecordOk, QueryResult, Quorum,
+    AddProviderOk, BootstrapOk, GetProvidersOk, GetRecordOk, Kademlia, KademliaConfig,
+    KademliaEvent, PeerRecord, PutRecordOk, QueryResult, Quorum,
 };
+#[cfg(feature = "mdns")]
 use libp2p::mdns::{Mdns, MdnsEvent};
 use libp2p::multiaddr::Protocol;
 use libp2p::ping::{Ping, PingEvent, PingFailure, PingSuccess};
+use libp2p::request_response::{
+    ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+    RequestResponseMessage,
+};
 use libp2p::swarm::toggle::Toggle;
 use libp2p::swarm::NetworkBehaviourEventProcess;
 use libp2p::NetworkBehaviour;
 use libp2p::{Multiaddr, PeerId};
 use libp2p_bitswap::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore};
-use prometheus::Registry;
+use prometheus::{IntCounter, IntCounterVec, Opts, Registry};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
+const RAW: u64 = 0x55;
+const SHA2_256: u64 = 0x12;
+/// Number of blocks a depth first sync requests at a time.
+const ORDERED_SYNC_WANTS: usize = 8;
+/// Interval at which kademlia checks its jobs and query timeouts in the background,
+/// before it is stretched by the activity level.
+#[cfg(feature = "dht")]
+const KAD_TICK: Duration = Duration::from_secs(1);
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct QueryId(InnerQueryId);
 
+impl QueryId {
+    /// Returns the id of the `n`th get or sync query.
+    pub(crate) fn want(n: u64) -> Self {
+        Self(InnerQueryId::Want(n))
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 enum InnerQueryId {
-    Bitswap(libp2p_bitswap::QueryId),
+    Want(u64),
+    #[cfg(feature = "dht")]
     Kad(libp2p::kad::QueryId),
+    Push(RequestId),
+    Rendezvous(RequestId),
 }
 
-impl From<libp2p_bitswap::QueryId> for QueryId {
-    fn from(id: libp2p_bitswap::QueryId) -> Self {
-        Self(InnerQueryId::Bitswap(id))
-    }
-}
-
+#[cfg(feature = "dht")]
 impl From<libp2p::kad::QueryId> for QueryId {
     fn from(id: libp2p::kad::QueryId) -> Self {
         Self(InnerQueryId::Kad(id))
     }
 }
 
+impl From<RequestId> for QueryId {
+    fn from(id: RequestId) -> Self {
+        Self(InnerQueryId::Push(id))
+    }
+}
+
 /// An event of a sync query.
+#[non_exhaustive]
 pub enum SyncEvent {
-    /// Signals that the sync query made progress and counts the amount of subtrees to
-    /// sync. If it is syncing a linked list, it will always be 1.
+    /// Signals that the sync query made progress and counts the amount of blocks that
+    /// are currently requested. If it is syncing a linked list, it will always be 1.
     Progress(usize),
+    /// Reports the bytes downloaded since the sync query started. Follows every
+    /// `Progress` event.
+    Bandwidth(SyncStats),
+    /// A block failed to download and is requested again. Contains the number of the
+    /// attempt.
+    Retry(Cid, u32),
+    /// A block failed to download while no peer is connected. It is requested again
+    /// once a peer connects.
+    Stalled(Cid),
+    /// A peer connected and the stalled blocks are requested again.
+    Resumed(PeerId),
     /// Signals completion of the sync query and if it was completed successfully.
     Complete(Result<()>),
 }
 
+/// Bandwidth used by a sync query.
+///
+/// Bytes are counted per connection, so traffic of other queries running at the same
+/// time with the same peers is included.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SyncStats {
+    /// Time since the sync query started.
+    pub elapsed: Duration,
+    /// Bytes downloaded from each peer.
+    pub peers: Vec<(PeerId, u64)>,
+}
+
+impl SyncStats {
+    /// Returns the total number of bytes downloaded.
+    pub fn bytes(&self) -> u64 {
+        self.peers.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    /// Returns the average download rate in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes() as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
 pub type GetChannel = oneshot::Receiver<Result<()>>;
 pub type SyncChannel = mpsc::UnboundedReceiver<SyncEvent>;
 pub type BootstrapChannel = oneshot::Receiver<Result<()>>;
 pub type StartProvidingChannel = oneshot::Receiver<Result<()>>;
 pub type GetRecordChannel = oneshot::Receiver<Result<Vec<PeerRecord>>>;
 pub type PutRecordChannel = oneshot::Receiver<Result<()>>;
+pub type PushChannel = oneshot::Receiver<Result<()>>;
+pub type RendezvousChannel = oneshot::Receiver<Result<RendezvousResponse>>;
+
+/// Traversal state of a sync query with limits.
+struct SyncState {
+    limits: TraversalLimits,
+    order: TraversalOrder,
+    depth: FnvHashMap<Cid, usize>,
+    blocks: usize,
+    bytes: u64,
+    /// Attempts made to download the blocks that failed at least once.
+    attempts: FnvHashMap<Cid, u32>,
+    /// Blocks waiting for a peer to connect.
+    stalled: FnvHashSet<Cid>,
+    /// Time since blocks are waiting for a peer to connect.
+    stalled_since: Option<Instant>,
+    /// Failed blocks waiting for the retry backoff to expire.
+    backoff: FnvHashSet<Cid>,
+    /// Received blocks whose missing links are being looked up.
+    resolving: usize,
+    /// Blocks of a depth first sync that weren't wanted yet, in traversal order.
+    frontier: VecDeque<Cid>,
+}
+
+impl SyncState {
+    fn new(limits: TraversalLimits, order: TraversalOrder) -> Self {
+        Self {
+            limits,
+            order,
+            depth: Default::default(),
+            blocks: 0,
+            bytes: 0,
+            attempts: Default::default(),
+            stalled: Default::default(),
+            stalled_since: None,
+            backoff: Default::default(),
+            resolving: 0,
+            frontier: Default::default(),
+        }
+    }
+}
+
+/// A delayed action of a sync query, passed to `sync_timer_expired` after `delay`.
+#[derive(Debug)]
+pub struct SyncTimer {
+    pub id: QueryId,
+    /// The block requested again after its retry backoff, or `None` to check if the
+    /// sync stalled for longer than the stall timeout.
+    pub cid: Option<Cid>,
+    pub delay: Duration,
+}
+
+/// Error returned when the blocks of a sync query waited for a peer to connect for
+/// longer than the stall timeout.
+#[derive(Debug, Error)]
+#[error("sync stalled without connected peers for {0:?}")]
+pub struct SyncStalled(pub Duration);
+
+/// A block received by a sync query whose missing links need to be looked up in the
+/// store. The size of the block is only needed if the sync limits the bytes.
+#[derive(Debug)]
+pub struct ResolveRequest {
+    pub id: QueryId,
+    pub cid: Cid,
+    pub size: bool,
+}
+
+/// The missing links and the size of a block received by a sync query.
+pub type Resolved = (QueryId, Cid, Result<(Vec<Cid>, usize)>);
+
+/// Blocks pushed by a peer.
+pub type Pushed<P> = (PeerId, Vec<Block<P>>);
+
+/// A message received on a gossipsub topic.
+#[derive(Clone, Debug)]
+pub struct GossipMessage {
+    /// The gossipsub message id.
+    pub id: Vec<u8>,
+    /// The peer that published the message. Messages are signed by their source, so the
+    /// source is authenticated.
+    pub source: Option<PeerId>,
+    pub data: Vec<u8>,
+}
+
+/// Counters of the behaviour. They are created once per node and shared by the
+/// behaviours of the swarms rebuilt by identity rotations, so that the registered metrics
+/// keep counting.
+#[derive(Clone)]
+pub(crate) struct BehaviourMetrics {
+    blocks_rejected: IntCounterVec,
+    wants_dropped: IntCounter,
+}
+
+impl BehaviourMetrics {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            blocks_rejected: IntCounterVec::new(
+                Opts::new(
+                    "bitswap_blocks_rejected_total",
+                    "Number of blocks rejected by the block policy labelled by reason.",
+                ),
+                &["reason"],
+            )?,
+            wants_dropped: IntCounter::new(
+                "bitswap_wants_dropped_total",
+                "Number of wants of peers dropped because of the serving limits.",
+            )?,
+        })
+    }
+}
 
 enum QueryChannel {
     Get(oneshot::Sender<Result<()>>),
     Sync(mpsc::UnboundedSender<SyncEvent>),
+    #[cfg(feature = "dht")]
     Bootstrap(oneshot::Sender<Result<()>>),
+    #[cfg(feature = "dht")]
     StartProviding(oneshot::Sender<Result<()>>),
+    #[cfg(feature = "dht")]
     GetRecord(oneshot::Sender<Result<Vec<PeerRecord>>>),
+    #[cfg(feature = "dht")]
     PutRecord(oneshot::Sender<Result<()>>),
+    Push(oneshot::Sender<Result<()>>),
+    Rendezvous(oneshot::Sender<Result<RendezvousResponse>>),
 }
 
 /// Behaviour type.
@@ -82,21 +306,111 @@ pub struct NetworkBackendBehaviour<P: StoreParams> {
     bootstrap_complete: bool,
 
     peers: AddressBook,
-    kad: Toggle<Kademlia<MemoryStore>>,
-    mdns: Toggle<Mdns>,
+    kad: Toggle<DhtBehaviour>,
+    autonat: Toggle<AutoNat>,
+    mdns: Toggle<MdnsBehaviour>,
     ping: Ping,
     identify: Identify,
-    bitswap: Bitswap<P>,
-    gossipsub: Gossipsub,
+    bitswap: PeerScope<Bitswap<P>>,
+    gossipsub: GossipBehaviour,
+    push: RequestResponse<PushCodec>,
+    rendezvous: RequestResponse<RendezvousCodec>,
+    streams: AppStreams,
+    dial_back: DialBack,
 
+    #[behaviour(ignore)]
+    resolver: Option<mpsc::UnboundedSender<ResolveRequest>>,
+    #[behaviour(ignore)]
+    sync_timers: Option<mpsc::UnboundedSender<SyncTimer>>,
+    #[behaviour(ignore)]
+    block_policy: BlockPolicy,
+    #[behaviour(ignore)]
+    blocks_rejected: IntCounterVec,
+    #[behaviour(ignore)]
+    wants_dropped: IntCounter,
+    #[cfg(feature = "dht")]
     #[behaviour(ignore)]
     provider_queries: FnvHashMap<libp2p::kad::QueryId, libp2p_bitswap::QueryId>,
+    /// Bitswap queries that only ask the connected peers, without looking up providers.
+    #[behaviour(ignore)]
+    connected_wants: FnvHashSet<libp2p_bitswap::QueryId>,
     #[behaviour(ignore)]
     queries: FnvHashMap<QueryId, QueryChannel>,
     #[behaviour(ignore)]
-    subscriptions: FnvHashMap<String, Vec<mpsc::UnboundedSender<Vec<u8>>>>,
+    next_query_id: u64,
+    #[behaviour(ignore)]
+    wants: WantTable,
+    #[behaviour(ignore)]
+    pending: FnvHashMap<QueryId, FnvHashSet<Cid>>,
+    #[behaviour(ignore)]
+    priorities: FnvHashMap<QueryId, Priority>,
+    #[behaviour(ignore)]
+    background_wants: usize,
+    #[behaviour(ignore)]
+    syncs: FnvHashMap<QueryId, SyncState>,
+    #[behaviour(ignore)]
+    sync_limits: TraversalLimits,
+    #[behaviour(ignore)]
+    sync_retry: RetryPolicy,
+    #[behaviour(ignore)]
+    sync_stall_timeout: Option<Duration>,
+    #[behaviour(ignore)]
+    subscriptions: FnvHashMap<String, Vec<mpsc::UnboundedSender<GossipMessage>>>,
+    #[cfg(feature = "gossip")]
+    #[behaviour(ignore)]
+    peer_topic: TopicHash,
+    #[behaviour(ignore)]
+    direct_subscribers: Vec<mpsc::UnboundedSender<(PeerId, Vec<u8>)>>,
+    #[behaviour(ignore)]
+    authenticator: Option<Arc<dyn Authenticator>>,
+    #[behaviour(ignore)]
+    auth: AuthGate,
+    /// Latest identity of the connected peers, authenticated when an authenticator is
+    /// installed.
+    #[behaviour(ignore)]
+    identities: FnvHashMap<PeerId, PeerIdentity>,
+    #[behaviour(ignore)]
+    identified: Vec<mpsc::UnboundedSender<PeerIdentity>>,
+    /// Peers banned with `NetworkService::ban`.
+    #[behaviour(ignore)]
+    banned: FnvHashSet<PeerId>,
+    #[behaviour(ignore)]
+    enable_push: bool,
+    #[behaviour(ignore)]
+    push_subscribers: Vec<mpsc::UnboundedSender<Pushed<P>>>,
+    #[behaviour(ignore)]
+    registrations: Option<Registrations>,
+    #[behaviour(ignore)]
+    audit: Option<AuditLog>,
+    #[behaviour(ignore)]
+    address_votes: AddressVotes,
+    #[behaviour(ignore)]
+    address_changes: Option<mpsc::UnboundedSender<AddressChange>>,
+}
+
+#[cfg(feature = "mdns")]
+type MdnsBehaviour = Mdns;
+#[cfg(not(feature = "mdns"))]
+type MdnsBehaviour = Disabled;
+
+#[cfg(feature = "mdns")]
+async fn new_mdns(enable: bool) -> Result<Option<Mdns>> {
+    Ok(if enable {
+        Some(Mdns::new().await?)
+    } else {
+        None
+    })
+}
+
+#[cfg(not(feature = "mdns"))]
+async fn new_mdns(enable: bool) -> Result<Option<Disabled>> {
+    if enable {
+        tracing::warn!("mdns is enabled but the mdns feature is disabled");
+    }
+    Ok(None)
 }
 
+#[cfg(feature = "mdns")]
 impl<P: StoreParams> NetworkBehaviourEventProcess<MdnsEvent> for NetworkBackendBehaviour<P> {
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
@@ -112,39 +426,119 @@ impl<P: StoreParams> NetworkBehaviourEventProcess<MdnsEvent> for NetworkBackendB
     }
 }
 
+#[cfg(feature = "dht")]
+type DhtBehaviour = Paced<Dht>;
+#[cfg(not(feature = "dht"))]
+type DhtBehaviour = Disabled;
+
+#[cfg(feature = "dht")]
+fn new_dht(config: &NetworkConfig, activity: &Activity) -> Option<DhtBehaviour> {
+    if !config.enable_kad {
+        return None;
+    }
+    let peer_id = config.peer_id();
+    let kad_store = MemoryStore::new(peer_id);
+    // records published by the node are republished by the persistent scheduler,
+    // so kademlia only needs to replicate the records it stores for others.
+    let mut kad_config = KademliaConfig::default();
+    kad_config.set_publication_interval(None);
+    kad_config.set_provider_publication_interval(None);
+    let dht = Dht::new(
+        Kademlia::with_config(peer_id, kad_store, kad_config),
+        config.dht_mode,
+    );
+    Some(Paced::new(dht, activity.clone(), KAD_TICK))
+}
+
+#[cfg(not(feature = "dht"))]
+fn new_dht(config: &NetworkConfig, _activity: &Activity) -> Option<DhtBehaviour> {
+    if config.enable_kad {
+        tracing::warn!("kad is enabled but the dht feature isn't");
+    }
+    None
+}
+
+#[cfg(feature = "gossip")]
+type GossipBehaviour = Paced<Gossipsub>;
+#[cfg(not(feature = "gossip"))]
+type GossipBehaviour = Disabled;
+
+/// Creates the gossipsub behaviour subscribed to the topic of the local peer.
+#[cfg(feature = "gossip")]
+fn new_gossipsub(config: &NetworkConfig, activity: &Activity) -> Result<GossipBehaviour> {
+    let gossipsub_config = GossipsubConfig::default();
+    let heartbeat_interval = gossipsub_config.heartbeat_interval();
+    let gossipsub = Gossipsub::new(
+        MessageAuthenticity::Signed(config.node_key.clone()),
+        gossipsub_config,
+    )
+    .map_err(|err| anyhow::anyhow!("{}", err))?;
+    let mut gossipsub = Paced::new(gossipsub, activity.clone(), heartbeat_interval);
+    gossipsub
+        .subscribe(&IdentTopic::new(peer_topic(&config.peer_id())))
+        .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+    Ok(gossipsub)
+}
+
+#[cfg(not(feature = "gossip"))]
+fn new_gossipsub(_config: &NetworkConfig, _activity: &Activity) -> Result<GossipBehaviour> {
+    Ok(Disabled)
+}
+
+/// Error returned when using the dht in a build without the `dht` feature.
+#[derive(Debug, Error)]
+#[error("the dht isn't compiled in, enable the dht feature")]
+pub struct DhtDisabled;
+
+/// Error returned when using gossipsub in a build without the `gossip` feature.
+#[derive(Debug, Error)]
+#[error("gossipsub isn't compiled in, enable the gossip feature")]
+pub struct GossipDisabled;
+
+#[cfg(feature = "dht")]
 #[derive(Debug, Error)]
 #[error("Trying to use kad before bootstrap completed successfully.")]
 pub struct NotBootstrapped;
 
+#[cfg(feature = "dht")]
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadStoreError(pub libp2p::kad::record::store::Error);
 
+#[cfg(feature = "dht")]
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadAddProviderError(pub libp2p::kad::AddProviderError);
 
+#[cfg(feature = "dht")]
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadGetRecordError(pub libp2p::kad::GetRecordError);
 
+#[cfg(feature = "dht")]
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadPutRecordError(pub libp2p::kad::PutRecordError);
 
+#[cfg(feature = "dht")]
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadBootstrapError(pub libp2p::kad::BootstrapError);
 
+#[cfg(feature = "dht")]
 impl<P: StoreParams> NetworkBehaviourEventProcess<KademliaEvent> for NetworkBackendBehaviour<P> {
     fn inject_event(&mut self, event: KademliaEvent) {
         tracing::trace!("kademlia event {:?}", event);
         if let KademliaEvent::QueryResult { id, result, .. } = event {
             match result {
-                QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. })) => {
+                QueryResult::GetProviders(Ok(GetProvidersOk { key, providers, .. })) => {
                     if let Some(id) = self.provider_queries.remove(&id) {
-                        self.bitswap
-                            .inject_providers(id, providers.into_iter().collect());
+                        let mut providers = providers.into_iter().collect::<Vec<_>>();
+                        self.peers.rank(&mut providers);
+                        if let Ok(cid) = Cid::try_from(key.to_vec()) {
+                            self.audit(AuditKind::BitswapWant, &cid.to_string(), &providers);
+                        }
+                        self.bitswap.inject_providers(id, providers);
                     }
                 }
                 QueryResult::GetProviders(Err(err)) => {
@@ -223,34 +617,148 @@ impl<P: StoreParams> NetworkBehaviourEventProcess<KademliaEvent> for NetworkBack
 impl<P: StoreParams> NetworkBehaviourEventProcess<BitswapEvent> for NetworkBackendBehaviour<P> {
     fn inject_event(&mut self, event: BitswapEvent) {
         match event {
+            BitswapEvent::Providers(id, _) if self.connected_wants.contains(&id) => {
+                self.bitswap.inject_providers(id, vec![]);
+            }
             BitswapEvent::Providers(id, cid) => {
                 if self.bootstrap_complete {
-                    let key = Key::new(&cid.to_bytes());
-                    let kad_id = self.kad.as_mut().unwrap().get_providers(key);
-                    self.provider_queries.insert(kad_id, id);
+                    self.audit(AuditKind::DhtGetProviders, &cid.to_string(), &[]);
+                    self.get_providers(id, cid);
                 } else {
-                    let providers = self.peers().copied().collect();
+                    let mut providers = self.peers().copied().collect::<Vec<_>>();
+                    self.peers.rank(&mut providers);
+                    self.audit(AuditKind::BitswapWant, &cid.to_string(), &providers);
                     self.bitswap.inject_providers(id, providers);
                 }
             }
-            BitswapEvent::Progress(id, missing) => {
-                if let Some(QueryChannel::Sync(ch)) = self.queries.get(&id.into()) {
-                    ch.unbounded_send(SyncEvent::Progress(missing)).ok();
+            BitswapEvent::Progress(_, _) => {}
+            BitswapEvent::Complete(id, result) => {
+                self.connected_wants.remove(&id);
+                if let Some((cid, waiters)) = self.wants.complete(&id) {
+                    for waiter in waiters {
+                        let result = match &result {
+                            Ok(()) => Ok(()),
+                            Err(err) => Err(clone_error(err)),
+                        };
+                        self.complete_want(waiter, cid, result);
+                    }
                 }
+                self.schedule();
             }
-            BitswapEvent::Complete(id, result) => match self.queries.remove(&id.into()) {
-                Some(QueryChannel::Get(ch)) => {
-                    ch.send(result).ok();
+        }
+    }
+}
+
+impl<P: StoreParams>
+    NetworkBehaviourEventProcess<RequestResponseEvent<RendezvousRequest, RendezvousResponse>>
+    for NetworkBackendBehaviour<P>
+{
+    fn inject_event(&mut self, event: RequestResponseEvent<RendezvousRequest, RendezvousResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = if !self.auth.is_accepted(&peer) {
+                        let status = RendezvousStatus::NotAuthorized;
+                        RendezvousResponse::reject(&request, status, "not authenticated")
+                    } else if let Some(registrations) = self.registrations.as_mut() {
+                        registrations.handle(peer, request)
+                    } else {
+                        let status = RendezvousStatus::Unavailable;
+                        RendezvousResponse::reject(&request, status, "not a rendezvous point")
+                    };
+                    self.rendezvous.send_response(channel, response).ok();
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let RendezvousResponse::Discovered { registrations, .. } = &response {
+                        for registration in registrations {
+                            let peer_id = *registration.record.peer_id();
+                            for addr in registration.record.addresses() {
+                                self.add_address(&peer_id, addr.clone(), AddressSource::Rendezvous);
+                            }
+                        }
+                    }
+                    let id = QueryId(InnerQueryId::Rendezvous(request_id));
+                    if let Some(QueryChannel::Rendezvous(ch)) = self.queries.remove(&id) {
+                        ch.send(Ok(response)).ok();
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                request_id, error, ..
+            } => {
+                let id = QueryId(InnerQueryId::Rendezvous(request_id));
+                if let Some(QueryChannel::Rendezvous(ch)) = self.queries.remove(&id) {
+                    ch.send(Err(RendezvousFailure(error).into())).ok();
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                tracing::debug!(
+                    "inbound rendezvous request from {} failed: {:?}",
+                    peer,
+                    error
+                );
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl<P: StoreParams> NetworkBehaviourEventProcess<RequestResponseEvent<PushRequest, PushResponse>>
+    for NetworkBackendBehaviour<P>
+{
+    fn inject_event(&mut self, event: RequestResponseEvent<PushRequest, PushResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let accepted = self.accept_push(peer, request);
+                    self.push.send_response(channel, accepted).ok();
                 }
-                Some(QueryChannel::Sync(ch)) => {
-                    ch.unbounded_send(SyncEvent::Complete(result)).ok();
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(QueryChannel::Push(ch)) = self.queries.remove(&request_id.into()) {
+                        let result = if response {
+                            Ok(())
+                        } else {
+                            Err(PushRejected.into())
+                        };
+                        ch.send(result).ok();
+                    }
                 }
-                _ => {}
             },
+            RequestResponseEvent::OutboundFailure {
+                request_id, error, ..
+            } => {
+                if let Some(QueryChannel::Push(ch)) = self.queries.remove(&request_id.into()) {
+                    ch.send(Err(PushFailure(error).into())).ok();
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                tracing::debug!("inbound push from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
         }
     }
 }
 
+/// Errors aren't `Clone`, so every waiter of a want gets a copy that preserves
+/// `BlockNotFound` errors and the message of other errors.
+fn clone_error(err: &anyhow::Error) -> anyhow::Error {
+    if let Some(BlockNotFound(cid)) = err.downcast_ref::<BlockNotFound>() {
+        BlockNotFound(*cid).into()
+    } else {
+        anyhow::anyhow!("{}", err)
+    }
+}
+
 impl<P: StoreParams> NetworkBehaviourEventProcess<PingEvent> for NetworkBackendBehaviour<P> {
     fn inject_event(&mut self, event: PingEvent) {
         // Don't really need to do anything here as ping handles disconnecting automatically.
@@ -296,8 +804,26 @@ impl<P: StoreParams> NetworkBehaviourEventProcess<IdentifyEvent> for NetworkBack
             observed_addr,
         } = event
         {
+            let identity = PeerIdentity {
+                peer_id,
+                public_key: info.public_key.clone(),
+                protocol_version: info.protocol_version.clone(),
+                agent_version: info.agent_version.clone(),
+                listen_addrs: info.listen_addrs.clone(),
+                protocols: info.protocols.clone(),
+            };
+            self.identify(identity);
+            self.dial_back.verify(peer_id, info.listen_addrs.clone());
             self.peers.set_info(&peer_id, info);
             tracing::debug!("has external address {}", observed_addr);
+            if let Some(votes) = self.address_votes.vote(peer_id, observed_addr.clone()) {
+                tracing::info!(
+                    "external address {} confirmed by {} peers",
+                    observed_addr,
+                    votes
+                );
+            }
+            self.send_address_changes();
             let local_peer_id = *self.peers.local_peer_id();
             // source doesn't matter as it won't be added to address book.
             self.add_address(&local_peer_id, observed_addr, AddressSource::User);
@@ -305,27 +831,75 @@ impl<P: StoreParams> NetworkBehaviourEventProcess<IdentifyEvent> for NetworkBack
     }
 }
 
+impl<P: StoreParams> NetworkBehaviourEventProcess<NatStatus> for NetworkBackendBehaviour<P> {
+    fn inject_event(&mut self, status: NatStatus) {
+        self.set_dht_nat_status(status);
+    }
+}
+
+impl<P: StoreParams> NetworkBehaviourEventProcess<DialBackEvent> for NetworkBackendBehaviour<P> {
+    fn inject_event(&mut self, event: DialBackEvent) {
+        match event {
+            DialBackEvent::Verified(peer_id, addr) => {
+                tracing::debug!("verified address {} of {}", addr, peer_id);
+                self.add_address(&peer_id, addr, AddressSource::DialBack);
+            }
+            DialBackEvent::Failed(peer_id, addr) => {
+                tracing::debug!("failed to dial back {} at {}", peer_id, addr);
+            }
+        }
+    }
+}
+
+/// Returns the topic of `peer`, which every node subscribes to for receiving direct
+/// messages.
+pub fn peer_topic(peer: &PeerId) -> String {
+    format!("/peer/{}", peer)
+}
+
+#[cfg(feature = "gossip")]
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct GossipsubPublishError(pub libp2p::gossipsub::error::PublishError);
 
+#[cfg(feature = "gossip")]
 impl<P: StoreParams> NetworkBehaviourEventProcess<GossipsubEvent> for NetworkBackendBehaviour<P> {
     fn inject_event(&mut self, event: GossipsubEvent) {
         match event {
             GossipsubEvent::Message {
-                message: GossipsubMessage { data, topic, .. },
+                message_id,
+                message:
+                    GossipsubMessage {
+                        data,
+                        topic,
+                        source,
+                        ..
+                    },
                 ..
             } => {
+                if topic == self.peer_topic {
+                    if let Some(source) = source {
+                        self.direct_subscribers.retain(|subscriber| {
+                            subscriber.unbounded_send((source, data.clone())).is_ok()
+                        });
+                    }
+                }
                 if let Some(subscribers) = self.subscriptions.get_mut(topic.as_str()) {
-                    subscribers
-                        .retain(|subscriber| subscriber.unbounded_send(data.clone()).is_ok());
-                    if subscribers.is_empty() {
+                    let msg = GossipMessage {
+                        id: message_id.0,
+                        source,
+                        data,
+                    };
+                    subscribers.retain(|subscriber| subscriber.unbounded_send(msg.clone()).is_ok());
+                    if subscribers.is_empty() && topic != self.peer_topic {
                         self.unsubscribe(topic.as_str());
                         self.subscriptions.remove(topic.as_str());
                     }
                 }
             }
-            GossipsubEvent::Subscribed { .. } => {}
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                self.notify(Event::Subscribed(peer_id, topic.into_string()));
+            }
             GossipsubEvent::Unsubscribed { .. } => {}
         }
     }
@@ -337,17 +911,19 @@ impl<P: StoreParams> NetworkBehaviourEventProcess<void::Void> for NetworkBackend
 
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
     /// Create a Kademlia behaviour with the IPFS bootstrap nodes.
-    pub async fn new<S: BitswapStore<Params = P>>(config: NetworkConfig, store: S) -> Result<Self> {
+    pub async fn new<S: BitswapStore<Params = P> + Clone>(
+        config: NetworkConfig,
+        store: S,
+        health: &Health,
+        activity: &Activity,
+        metrics: &BehaviourMetrics,
+    ) -> Result<Self> {
         let peer_id = config.peer_id();
-        let mdns = if config.enable_mdns {
-            Some(Mdns::new().await?)
-        } else {
-            None
-        }
-        .into();
-        let kad = if config.enable_kad {
-            let kad_store = MemoryStore::new(peer_id);
-            Some(Kademlia::new(peer_id, kad_store))
+        let mdns = new_mdns(config.enable_mdns).await?.into();
+        let kad = new_dht(&config, activity);
+        // peers are asked to dial the node back to decide whether it can serve the dht.
+        let autonat = if kad.is_some() {
+            Some(AutoNat::new(config.dht_mode == DhtMode::Auto))
         } else {
             None
         }
@@ -360,32 +936,105 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         bitswap_config.request_timeout = config.bitswap_request_timeout;
         bitswap_config.connection_keep_alive = config.bitswap_connection_keepalive;
         bitswap_config.receive_limit = config.bitswap_receive_limit;
-        let bitswap = Bitswap::new(bitswap_config, store);
+        let blocks_rejected = metrics.blocks_rejected.clone();
+        let policy_store = PolicyStore::new(
+            ReceiverStore::new(store.clone(), config.block_receiver.clone()),
+            config.block_policy.clone(),
+            blocks_rejected.clone(),
+        );
+        let wants_dropped = metrics.wants_dropped.clone();
+        let auth = AuthGate::default();
+        let serving_store = ServingStore::new(
+            policy_store,
+            config.bitswap_serve_reads,
+            config.bitswap_serve_peer_rate,
+            wants_dropped.clone(),
+            health.queue("bitswap_serve_reads"),
+            auth.clone(),
+        );
+        let bitswap = serving_store.scope(Bitswap::new(bitswap_config, serving_store.clone()));
 
-        let gossipsub = Gossipsub::new(
-            MessageAuthenticity::Signed(config.node_key.clone()),
-            GossipsubConfig::default(),
-        )
-        .map_err(|err| anyhow::anyhow!("{}", err))?;
+        let mut push_config = RequestResponseConfig::default();
+        push_config.set_request_timeout(config.bitswap_request_timeout);
+        let push = RequestResponse::new(
+            PushCodec::new(config.push_max_blocks, P::MAX_BLOCK_SIZE),
+            std::iter::once((PushProtocol, ProtocolSupport::Full)),
+            push_config,
+        );
+
+        let mut rendezvous_config = RequestResponseConfig::default();
+        rendezvous_config.set_request_timeout(config.bitswap_request_timeout);
+        let rendezvous = RequestResponse::new(
+            RendezvousCodec,
+            std::iter::once((RendezvousProtocol, ProtocolSupport::Full)),
+            rendezvous_config,
+        );
+        let registrations = if config.enable_rendezvous_server {
+            Some(Registrations::default())
+        } else {
+            None
+        };
+        let audit = if let Some(config) = config.audit_log.clone() {
+            Some(AuditLog::open(config)?)
+        } else {
+            None
+        };
+
+        let gossipsub = new_gossipsub(&config, activity)?;
 
         Ok(Self {
             allow_non_globals_in_dht: config.allow_non_globals_in_dht,
             bootstrap_complete: false,
             peers: AddressBook::new(peer_id),
             mdns,
-            kad,
+            kad: kad.into(),
+            autonat,
             ping,
             identify,
             bitswap,
             gossipsub,
+            push,
+            rendezvous,
+            streams: Default::default(),
+            dial_back: DialBack::new(config.dial_back),
+            resolver: None,
+            sync_timers: None,
+            block_policy: config.block_policy,
+            blocks_rejected,
+            wants_dropped,
+            #[cfg(feature = "dht")]
             provider_queries: Default::default(),
+            connected_wants: Default::default(),
             queries: Default::default(),
+            next_query_id: 0,
+            wants: Default::default(),
+            pending: Default::default(),
+            priorities: Default::default(),
+            background_wants: config.bitswap_background_wants,
+            syncs: Default::default(),
+            sync_limits: config.sync_limits,
+            sync_retry: config.retry_policy.clone(),
+            sync_stall_timeout: config.sync_stall_timeout,
             subscriptions: Default::default(),
+            #[cfg(feature = "gossip")]
+            peer_topic: IdentTopic::new(peer_topic(&peer_id)).hash(),
+            direct_subscribers: Default::default(),
+            authenticator: None,
+            auth,
+            identities: Default::default(),
+            identified: Default::default(),
+            banned: Default::default(),
+            enable_push: config.enable_push,
+            push_subscribers: Default::default(),
+            registrations,
+            audit,
+            address_votes: AddressVotes::new(config.external_address_confidence),
+            address_changes: None,
         })
     }
 
     pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr, source: AddressSource) {
-        if let Some(kad) = self.kad.as_mut() {
+        if self.kad.is_enabled() {
             let is_global = match addr.iter().next() {
                 Some(Protocol::Ip4(ip)) => IpNetwork::from(ip).is_global(),
                 Some(Protocol::Ip6(ip)) => IpNetwork::from(ip).is_global(),
@@ -395,7 +1044,7 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                 _ => false,
             };
             if self.allow_non_globals_in_dht || is_global {
-                kad.add_address(&peer_id, addr.clone());
+                self.add_dht_address(peer_id, addr.clone());
             } else {
                 tracing::trace!("not adding local address {}", addr);
             }
@@ -405,9 +1054,7 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
 
     pub fn remove_address(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
         self.peers.remove_address(peer_id, addr);
-        if let Some(kad) = self.kad.as_mut() {
-            kad.remove_address(peer_id, addr);
-        }
+        self.remove_dht_address(peer_id, addr);
     }
 
     pub fn peers(&self) -> impl Iterator<Item = &PeerId> + '_ {
@@ -418,12 +1065,791 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         self.peers.info(peer_id)
     }
 
+    pub fn rtt(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.peers.rtt(peer_id)
+    }
+
+    pub fn peer_stats(&self) -> impl Iterator<Item = (&PeerId, &PeerStats)> + '_ {
+        self.peers.stats()
+    }
+
+    pub fn restore_peer_stats(&mut self, peer_id: &PeerId, stats: PeerStats) {
+        self.peers.restore_stats(peer_id, stats);
+    }
+
     pub fn connections(&self) -> impl Iterator<Item = (&PeerId, &Multiaddr)> + '_ {
         self.peers.connections()
     }
 
+    pub fn add_listener(&mut self, id: ListenerId, addr: Multiaddr) {
+        self.peers.add_listener(id, addr);
+    }
+
+    pub fn remove_listener(&mut self, id: &ListenerId) -> Option<Multiaddr> {
+        self.peers.remove_listener(id)
+    }
+
+    pub fn swarm_events(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        self.peers.swarm_events()
+    }
+
+    /// Returns the events for the tasks driving the swarm. Unlike the `swarm_events`
+    /// subscribers, they don't carry over to a swarm rebuilt by an identity rotation.
+    pub fn internal_events(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        self.peers.internal_events()
+    }
+
+    /// Returns the addresses the listeners were started with.
+    pub fn listener_addrs(&self) -> Vec<Multiaddr> {
+        self.peers.listener_addrs().cloned().collect()
+    }
+
+    /// Takes over the state of the behaviour `old` of a swarm replaced by an identity
+    /// rotation: the known addresses and statistics of peers, the swarm event, gossipsub,
+    /// direct message and push subscribers, the authenticator, the bans, the rendezvous
+    /// registrations and the audit log. Queries and syncs in flight are dropped, so they
+    /// fail.
+    pub fn take_over(&mut self, old: &mut Self) {
+        let addresses = old
+            .peers
+            .addresses()
+            .map(|(peer, addr, source)| (*peer, addr.clone(), source))
+            .collect::<Vec<_>>();
+        for (peer, addr, source) in addresses {
+            self.add_address(&peer, addr, source);
+        }
+        self.peers.take_over(&mut old.peers);
+        for (topic, subscribers) in old.subscriptions.drain() {
+            if let Err(err) = self.gossip_subscribe(&topic) {
+                tracing::warn!("failed to subscribe to topic {}: {}", topic, err);
+            }
+            self.subscriptions.insert(topic, subscribers);
+        }
+        self.direct_subscribers = std::mem::take(&mut old.direct_subscribers);
+        self.push_subscribers = std::mem::take(&mut old.push_subscribers);
+        self.set_authenticator(old.authenticator.take());
+        self.banned = std::mem::take(&mut old.banned);
+        if old.registrations.is_some() {
+            self.registrations = old.registrations.take();
+        }
+        if old.audit.is_some() {
+            self.audit = old.audit.take();
+        }
+    }
+
+    pub fn notify(&mut self, event: Event) {
+        self.peers.notify(event)
+    }
+
+    fn audit(&mut self, kind: AuditKind, target: &str, peers: &[PeerId]) {
+        if let Some(audit) = self.audit.as_mut() {
+            audit.record(kind, target, peers);
+        }
+    }
+
+    pub fn observed_addresses(&mut self) -> Vec<ObservedAddress> {
+        let addrs = self.address_votes.addresses();
+        self.send_address_changes();
+        addrs
+    }
+
+    /// Drops the expired votes for observed addresses.
+    pub fn expire_address_votes(&mut self) {
+        self.address_votes.expire();
+        self.send_address_changes();
+    }
+
+    /// Returns the channel receiving the confirmed and expired observed addresses, which
+    /// are added to and removed from the external addresses of the swarm.
+    pub(crate) fn address_changes(&mut self) -> mpsc::UnboundedReceiver<AddressChange> {
+        let (tx, rx) = mpsc::unbounded();
+        self.address_changes = Some(tx);
+        rx
+    }
+
+    fn send_address_changes(&mut self) {
+        let changes = self.address_votes.take_changes();
+        if let Some(tx) = self.address_changes.as_ref() {
+            for change in changes {
+                tx.unbounded_send(change).ok();
+            }
+        }
+    }
+
+    /// Returns the reachability of the node, `None` if kad is disabled.
+    pub fn nat_status(&self) -> Option<NatStatus> {
+        self.autonat.as_ref().map(|autonat| autonat.status())
+    }
+
+    /// Returns `true` once the dht was bootstrapped.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.bootstrap_complete
+    }
+
+    pub fn subscribe(&mut self, topic: &str) -> Result<impl Stream<Item = Vec<u8>>> {
+        Ok(self.subscribe_messages(topic)?.map(|msg| msg.data))
+    }
+
+    /// Like `subscribe`, but returns the gossipsub message id with every message.
+    pub fn subscribe_with_ids(
+        &mut self,
+        topic: &str,
+    ) -> Result<impl Stream<Item = (Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .subscribe_messages(topic)?
+            .map(|msg| (msg.id, msg.data)))
+    }
+
+    /// Like `subscribe`, but returns the id and the source of every message.
+    pub fn subscribe_messages(&mut self, topic: &str) -> Result<impl Stream<Item = GossipMessage>> {
+        let (tx, rx) = mpsc::unbounded();
+        if let Some(subscribers) = self.subscriptions.get_mut(topic) {
+            subscribers.push(tx);
+        } else {
+            self.gossip_subscribe(topic)?;
+            self.subscriptions.insert(topic.to_string(), vec![tx]);
+        }
+        Ok(rx)
+    }
+
+    /// Returns a stream of the messages published to the topic of the local peer and
+    /// their senders.
+    pub fn direct_messages(&mut self) -> impl Stream<Item = (PeerId, Vec<u8>)> {
+        let (tx, rx) = mpsc::unbounded();
+        self.direct_subscribers.push(tx);
+        rx
+    }
+
+    /// Returns a stream of the identities peers sent with the identify protocol.
+    pub fn identified(&mut self) -> mpsc::UnboundedReceiver<PeerIdentity> {
+        let (tx, rx) = mpsc::unbounded();
+        self.identified.push(tx);
+        rx
+    }
+
+    pub fn authenticator(&self) -> Option<Arc<dyn Authenticator>> {
+        self.authenticator.clone()
+    }
+
+    /// Replaces the authenticator. The connected peers are authenticated again by the new
+    /// authenticator.
+    pub fn set_authenticator(&mut self, authenticator: Option<Arc<dyn Authenticator>>) {
+        self.auth.set_enabled(authenticator.is_some());
+        self.authenticator = authenticator;
+        let identities = self.identities.values().cloned().collect::<Vec<_>>();
+        for identity in identities {
+            self.identify(identity);
+        }
+    }
+
+    /// Hands the identity of a peer to the authenticator, unless the peer was already
+    /// authenticated on its current connection.
+    fn identify(&mut self, identity: PeerIdentity) {
+        let peer = identity.peer_id;
+        let connected = self
+            .peers
+            .connections()
+            .map(|(peer, _)| *peer)
+            .collect::<FnvHashSet<_>>();
+        self.identities.retain(|peer, _| connected.contains(peer));
+        self.identities.insert(peer, identity.clone());
+        if self.auth.begin(peer) {
+            self.identified
+                .retain(|tx| tx.unbounded_send(identity.clone()).is_ok());
+        }
+    }
+
+    /// Serves a peer the authenticator accepted.
+    pub fn accept_auth(&mut self, peer: &PeerId) {
+        self.auth.accept(peer);
+    }
+
+    /// Returns `true` if the authenticator accepted `peer` or no authenticator is set.
+    pub fn is_authenticated(&self, peer: &PeerId) -> bool {
+        self.auth.is_accepted(peer)
+    }
+
+    /// Scores a peer that failed to authenticate. Returns the number of times the peer
+    /// failed.
+    pub fn record_auth_failure(&mut self, peer: &PeerId) -> u64 {
+        self.peers.record_auth_failure(peer)
+    }
+
+    /// Remembers a peer banned by the user, so that lifting the temporary ban of a peer
+    /// that failed to authenticate doesn't lift the user's ban.
+    pub fn set_banned(&mut self, peer: PeerId, banned: bool) {
+        if banned {
+            self.banned.insert(peer);
+        } else {
+            self.banned.remove(&peer);
+        }
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains(peer)
+    }
+
+    pub fn banned_peers(&self) -> Vec<PeerId> {
+        self.banned.iter().copied().collect()
+    }
+
+    /// Publishes a message to the topic of `peer`.
+    pub fn send_direct(&mut self, peer: &PeerId, msg: Vec<u8>) -> Result<()> {
+        self.publish(&peer_topic(peer), msg)
+    }
+
+    pub fn push(&mut self, peer: &PeerId, blocks: Vec<Block<P>>) -> PushChannel {
+        let (tx, rx) = oneshot::channel();
+        let request = blocks
+            .iter()
+            .map(|block| (*block.cid(), block.data().to_vec()))
+            .collect();
+        let id = self.push.send_request(peer, request);
+        self.queries.insert(id.into(), QueryChannel::Push(tx));
+        rx
+    }
+
+    pub fn rendezvous(&mut self, peer: &PeerId, request: RendezvousRequest) -> RendezvousChannel {
+        let (tx, rx) = oneshot::channel();
+        let id = self.rendezvous.send_request(peer, request);
+        self.queries.insert(
+            QueryId(InnerQueryId::Rendezvous(id)),
+            QueryChannel::Rendezvous(tx),
+        );
+        rx
+    }
+
+    pub fn open_stream(&mut self, peer: PeerId, protocol: AppProtocol) -> StreamChannel {
+        self.streams.open_stream(peer, protocol)
+    }
+
+    pub fn listen_streams(
+        &mut self,
+        protocol: AppProtocol,
+    ) -> mpsc::UnboundedReceiver<(PeerId, AppStream)> {
+        self.streams.listen(protocol)
+    }
+
+    pub fn pushed(&mut self) -> mpsc::UnboundedReceiver<Pushed<P>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.push_subscribers.push(tx);
+        rx
+    }
+
+    /// Verifies the pushed blocks and hands them to the subscribers. Returns `false` if
+    /// the blocks were rejected.
+    fn accept_push(&mut self, peer: PeerId, request: PushRequest) -> bool {
+        if !self.enable_push || !self.auth.is_accepted(&peer) {
+            return false;
+        }
+        let mut blocks = Vec::with_capacity(request.len());
+        for (cid, data) in request {
+            if policy::check(&self.block_policy, &self.blocks_rejected, &cid).is_err() {
+                return false;
+            }
+            match Block::new(cid, data) {
+                Ok(block) => blocks.push(block),
+                Err(err) => {
+                    tracing::debug!("invalid block pushed by {}: {}", peer, err);
+                    return false;
+                }
+            }
+        }
+        self.push_subscribers
+            .retain(|tx| tx.unbounded_send((peer, blocks.clone())).is_ok());
+        !self.push_subscribers.is_empty()
+    }
+
+    fn next_query_id(&mut self) -> QueryId {
+        let id = QueryId::want(self.next_query_id);
+        self.next_query_id += 1;
+        id
+    }
+
+    /// Adds `cid` to the pending blocks of a query, requesting it unless it is already
+    /// wanted by another query. Fails if the block is rejected by the block policy.
+    fn want(&mut self, id: QueryId, cid: Cid) -> Result<()> {
+        policy::check(&self.block_policy, &self.blocks_rejected, &cid)?;
+        if !self.pending.entry(id).or_default().insert(cid) {
+            return Ok(());
+        }
+        let priority = self.priorities.get(&id).copied().unwrap_or_default();
+        if self.wants.add_waiter(&cid, id, priority) {
+            if priority == Priority::Interactive && self.wants.is_queued(&cid) {
+                let bitswap_id = self.bitswap.get(cid, std::iter::empty());
+                self.wants.start(&cid, bitswap_id);
+            }
+        } else if self.can_start(priority) {
+            let bitswap_id = self.bitswap.get(cid, std::iter::empty());
+            self.wants.insert(cid, bitswap_id, id, priority);
+        } else {
+            self.wants.enqueue(cid, id, priority);
+        }
+        Ok(())
+    }
+
+    /// Interactive blocks are always requested. Background blocks are requested up to the
+    /// background limit while no interactive blocks are in flight. A single background
+    /// block is requested while interactive blocks are in flight, so that a steady stream
+    /// of interactive fetches doesn't starve background syncs.
+    fn can_start(&self, priority: Priority) -> bool {
+        match priority {
+            Priority::Interactive => true,
+            Priority::Background => {
+                let limit = if self.wants.in_flight(Priority::Interactive) == 0 {
+                    self.background_wants
+                } else {
+                    std::cmp::min(self.background_wants, 1)
+                };
+                self.wants.in_flight(Priority::Background) < limit
+            }
+        }
+    }
+
+    /// Requests queued background blocks.
+    fn schedule(&mut self) {
+        while self.can_start(Priority::Background) {
+            if let Some((cid, _)) = self.wants.next_queued() {
+                let bitswap_id = self.bitswap.get(cid, std::iter::empty());
+                self.wants.start(&cid, bitswap_id);
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Wants all `missing` blocks of a sync query. A depth first sync adds them to the
+    /// front of its frontier, so that the links of a block are requested before its
+    /// siblings, and only wants `ORDERED_SYNC_WANTS` blocks at a time.
+    fn want_all(&mut self, id: QueryId, missing: impl IntoIterator<Item = Cid>) -> Result<()> {
+        let state = match self.syncs.get_mut(&id) {
+            Some(state) if state.order == TraversalOrder::DepthFirst => state,
+            _ => {
+                for cid in missing {
+                    self.want(id, cid)?;
+                }
+                return Ok(());
+            }
+        };
+        let missing = missing.into_iter().collect::<Vec<_>>();
+        for cid in missing.into_iter().rev() {
+            state.frontier.push_front(cid);
+        }
+        while self.pending.get(&id).map(|p| p.len()).unwrap_or_default() < ORDERED_SYNC_WANTS {
+            match self
+                .syncs
+                .get_mut(&id)
+                .and_then(|state| state.frontier.pop_front())
+            {
+                Some(cid) => self.want(id, cid)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn complete_want(&mut self, id: QueryId, cid: Cid, result: Result<()>) {
+        if let Some(pending) = self.pending.get_mut(&id) {
+            pending.remove(&cid);
+        }
+        match self.queries.get(&id) {
+            Some(QueryChannel::Get(_)) => {
+                self.pending.remove(&id);
+                self.priorities.remove(&id);
+                if let Some(QueryChannel::Get(ch)) = self.queries.remove(&id) {
+                    ch.send(result).ok();
+                }
+            }
+            Some(QueryChannel::Sync(_)) => match result {
+                Ok(()) => self.resolve(id, cid),
+                Err(err) => self.retry_sync_want(id, cid, err),
+            },
+            _ => {}
+        }
+    }
+
+    /// Returns the channel receiving the blocks of sync queries whose missing links need
+    /// to be looked up. The lookups are done outside of the swarm, so that reading the
+    /// store doesn't stall the network, and are returned with `resolved`.
+    pub fn resolve_requests(&mut self) -> mpsc::UnboundedReceiver<ResolveRequest> {
+        let (tx, rx) = mpsc::unbounded();
+        self.resolver = Some(tx);
+        rx
+    }
+
+    fn resolve(&mut self, id: QueryId, cid: Cid) {
+        let state = match self.syncs.get_mut(&id) {
+            Some(state) => state,
+            None => return,
+        };
+        let size = state.limits.max_bytes.is_some();
+        let sent = self
+            .resolver
+            .as_ref()
+            .map(|tx| tx.unbounded_send(ResolveRequest { id, cid, size }).is_ok())
+            .unwrap_or_default();
+        if sent {
+            state.resolving += 1;
+        } else {
+            let err = anyhow::anyhow!("no resolver for the missing blocks of a sync");
+            self.complete_sync(id, Err(err));
+        }
+    }
+
+    /// Wants the missing links of received blocks. The lookups of a sync are batched, so
+    /// that its wants are added at once.
+    pub fn resolved(&mut self, resolved: Vec<Resolved>) {
+        let mut batches: FnvHashMap<QueryId, Vec<Cid>> = FnvHashMap::default();
+        for (id, cid, result) in resolved {
+            if let Some(state) = self.syncs.get_mut(&id) {
+                state.resolving -= 1;
+            } else {
+                continue;
+            }
+            let result = result.and_then(|(missing, size)| {
+                self.sync_progress(id, &cid, &missing, size)
+                    .map(|_| missing)
+            });
+            match result {
+                Ok(missing) => batches.entry(id).or_default().extend(missing),
+                Err(err) => {
+                    batches.remove(&id);
+                    self.complete_sync(id, Err(err));
+                }
+            }
+        }
+        for (id, missing) in batches {
+            if let Err(err) = self.want_all(id, missing) {
+                self.complete_sync(id, Err(err));
+                continue;
+            }
+            let remaining = self
+                .syncs
+                .get(&id)
+                .map(|state| {
+                    state.stalled.len()
+                        + state.backoff.len()
+                        + state.resolving
+                        + state.frontier.len()
+                })
+                .unwrap_or_default()
+                + self.pending.get(&id).map(|p| p.len()).unwrap_or_default();
+            if let Some(QueryChannel::Sync(ch)) = self.queries.get(&id) {
+                ch.unbounded_send(SyncEvent::Progress(remaining)).ok();
+            }
+            if remaining == 0 {
+                self.complete_sync(id, Ok(()));
+            }
+        }
+        self.schedule();
+    }
+
+    /// Checks the traversal limits of a sync query after it received a block of `size`
+    /// bytes with `missing` links.
+    fn sync_progress(
+        &mut self,
+        id: QueryId,
+        cid: &Cid,
+        missing: &[Cid],
+        size: usize,
+    ) -> Result<()> {
+        if let Some(state) = self.syncs.get_mut(&id) {
+            let limits = state.limits;
+            let depth = state.depth.remove(cid).unwrap_or(1);
+            state.blocks += 1;
+            limits.check_blocks(state.blocks)?;
+            state.bytes += size as u64;
+            limits.check_bytes(state.bytes)?;
+            if !missing.is_empty() && limits.max_depth.is_some() {
+                limits.check_depth(depth + 1)?;
+                for cid in missing {
+                    state.depth.entry(*cid).or_insert(depth + 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the channel receiving the timers of sync queries. The timers run outside
+    /// of the swarm and are returned with `sync_timer_expired`.
+    pub fn sync_timers(&mut self) -> mpsc::UnboundedReceiver<SyncTimer> {
+        let (tx, rx) = mpsc::unbounded();
+        self.sync_timers = Some(tx);
+        rx
+    }
+
+    fn start_sync_timer(&self, timer: SyncTimer) -> bool {
+        self.sync_timers
+            .as_ref()
+            .map(|tx| tx.unbounded_send(timer).is_ok())
+            .unwrap_or_default()
+    }
+
+    /// Requests a block of a sync query again after it failed, if the retry policy allows
+    /// it, waiting for the backoff of the policy first. Blocks that fail while no peer is
+    /// connected are requested again once a peer connects, so that a sync survives losing
+    /// its connections.
+    fn retry_sync_want(&mut self, id: QueryId, cid: Cid, err: anyhow::Error) {
+        let retry = self.sync_retry.retry_on.contains(&ErrorClass::of(&err));
+        let attempt = match self.syncs.get(&id) {
+            Some(state) if retry => state.attempts.get(&cid).copied().unwrap_or(1),
+            _ => return self.complete_sync(id, Err(err)),
+        };
+        if attempt >= self.sync_retry.max_attempts {
+            return self.complete_sync(id, Err(err));
+        }
+        tracing::debug!("sync {:?}: fetching {} failed: {}", id, cid, err);
+        if self.peers.connections().next().is_none() {
+            return self.stall_sync_want(id, cid);
+        }
+        let delay = self.sync_retry.backoff.delay(attempt);
+        let timer = SyncTimer {
+            id,
+            cid: Some(cid),
+            delay,
+        };
+        if delay > Duration::from_secs(0) && self.start_sync_timer(timer) {
+            if let Some(state) = self.syncs.get_mut(&id) {
+                state.backoff.insert(cid);
+            }
+        } else if let Err(err) = self.resend_sync_want(id, cid) {
+            self.complete_sync(id, Err(err));
+        }
+    }
+
+    /// Waits for a peer to connect before requesting a block of a sync query again. The
+    /// sync fails once it stalled for longer than the stall timeout.
+    fn stall_sync_want(&mut self, id: QueryId, cid: Cid) {
+        let state = match self.syncs.get_mut(&id) {
+            Some(state) => state,
+            None => return,
+        };
+        state.stalled.insert(cid);
+        let start_timer = state.stalled_since.is_none();
+        if start_timer {
+            state.stalled_since = Some(Instant::now());
+        }
+        if let Some(QueryChannel::Sync(ch)) = self.queries.get(&id) {
+            ch.unbounded_send(SyncEvent::Stalled(cid)).ok();
+        }
+        if let (true, Some(delay)) = (start_timer, self.sync_stall_timeout) {
+            self.start_sync_timer(SyncTimer {
+                id,
+                cid: None,
+                delay,
+            });
+        }
+    }
+
+    /// Runs the action of an expired sync timer.
+    pub fn sync_timer_expired(&mut self, timer: SyncTimer) {
+        let state = match self.syncs.get_mut(&timer.id) {
+            Some(state) => state,
+            None => return,
+        };
+        match timer.cid {
+            Some(cid) => {
+                if !state.backoff.remove(&cid) {
+                    return;
+                }
+                if self.peers.connections().next().is_none() {
+                    return self.stall_sync_want(timer.id, cid);
+                }
+                if let Err(err) = self.resend_sync_want(timer.id, cid) {
+                    self.complete_sync(timer.id, Err(err));
+                }
+            }
+            None => {
+                // the timer of an earlier stall may expire after the sync resumed and
+                // stalled again.
+                let stalled = state
+                    .stalled_since
+                    .map(|since| since.elapsed() >= timer.delay)
+                    .unwrap_or_default();
+                if stalled {
+                    self.complete_sync(timer.id, Err(SyncStalled(timer.delay).into()));
+                }
+            }
+        }
+    }
+
+    fn resend_sync_want(&mut self, id: QueryId, cid: Cid) -> Result<()> {
+        if let Some(state) = self.syncs.get_mut(&id) {
+            let attempt = state.attempts.entry(cid).or_insert(1);
+            *attempt += 1;
+            if let Some(QueryChannel::Sync(ch)) = self.queries.get(&id) {
+                ch.unbounded_send(SyncEvent::Retry(cid, *attempt)).ok();
+            }
+        }
+        self.want(id, cid)
+    }
+
+    /// Requests the blocks of the sync queries that stalled because no peer was
+    /// connected.
+    pub fn resume_syncs(&mut self, peer: PeerId) {
+        let stalled = self
+            .syncs
+            .iter_mut()
+            .filter(|(_, state)| !state.stalled.is_empty())
+            .map(|(id, state)| {
+                state.stalled_since = None;
+                (*id, std::mem::take(&mut state.stalled))
+            })
+            .collect::<Vec<_>>();
+        for (id, cids) in stalled {
+            if let Some(QueryChannel::Sync(ch)) = self.queries.get(&id) {
+                ch.unbounded_send(SyncEvent::Resumed(peer)).ok();
+            }
+            for cid in cids {
+                if let Err(err) = self.resend_sync_want(id, cid) {
+                    self.complete_sync(id, Err(err));
+                    break;
+                }
+            }
+        }
+    }
+
+    fn complete_sync(&mut self, id: QueryId, result: Result<()>) {
+        self.unwant(&id);
+        self.priorities.remove(&id);
+        self.syncs.remove(&id);
+        if let Some(QueryChannel::Sync(ch)) = self.queries.remove(&id) {
+            ch.unbounded_send(SyncEvent::Complete(result)).ok();
+        }
+    }
+
+    pub fn get(&mut self, cid: Cid, priority: Priority) -> (GetChannel, QueryId) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_query_id();
+        self.queries.insert(id, QueryChannel::Get(tx));
+        self.priorities.insert(id, priority);
+        if let Err(err) = self.want(id, cid) {
+            self.complete_want(id, cid, Err(err));
+        }
+        (rx, id)
+    }
+
+    /// Requests a block from the connected peers only, without looking up providers in
+    /// the dht. Joins the request of a query already wanting the block.
+    pub fn get_connected(&mut self, cid: Cid) -> (GetChannel, QueryId) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_query_id();
+        self.queries.insert(id, QueryChannel::Get(tx));
+        if let Err(err) = policy::check(&self.block_policy, &self.blocks_rejected, &cid) {
+            self.complete_want(id, cid, Err(err));
+            return (rx, id);
+        }
+        self.pending.entry(id).or_default().insert(cid);
+        if !self.wants.add_waiter(&cid, id, Priority::Interactive) {
+            let mut peers = self
+                .connections()
+                .map(|(peer, _)| *peer)
+                .collect::<FnvHashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>();
+            self.peers.rank(&mut peers);
+            self.audit(AuditKind::BitswapWant, &cid.to_string(), &peers);
+            let bitswap_id = self.bitswap.get(cid, peers.into_iter());
+            self.connected_wants.insert(bitswap_id);
+            self.wants
+                .insert(cid, bitswap_id, id, Priority::Interactive);
+        }
+        (rx, id)
+    }
+
+    /// Requests a block that doesn't exist from `peer`. The query completes with a
+    /// `BlockNotFound` error once the peer responded.
+    pub fn echo(&mut self, peer: PeerId) -> (GetChannel, QueryId) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_query_id();
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut digest = [0u8; 32];
+        digest[..16].copy_from_slice(&nanos.to_be_bytes());
+        digest[16..24].copy_from_slice(&self.next_query_id.to_be_bytes());
+        let mh = Multihash::wrap(SHA2_256, &digest).expect("digest fits");
+        let cid = Cid::new_v1(RAW, mh);
+        self.queries.insert(id, QueryChannel::Get(tx));
+        self.pending.entry(id).or_default().insert(cid);
+        self.audit(AuditKind::BitswapWant, &cid.to_string(), &[peer]);
+        let bitswap_id = self.bitswap.get(cid, std::iter::once(peer));
+        self.wants
+            .insert(cid, bitswap_id, id, Priority::Interactive);
+        (rx, id)
+    }
+
+    pub fn sync(
+        &mut self,
+        missing: impl Iterator<Item = Cid>,
+        priority: Priority,
+        limits: Option<TraversalLimits>,
+        order: TraversalOrder,
+    ) -> (SyncChannel, QueryId) {
+        let (tx, rx) = mpsc::unbounded();
+        let id = self.next_query_id();
+        self.queries.insert(id, QueryChannel::Sync(tx));
+        self.priorities.insert(id, priority);
+        let limits = limits.unwrap_or(self.sync_limits);
+        self.syncs.insert(id, SyncState::new(limits, order));
+        let res = if order == TraversalOrder::LargestFirst {
+            Err(UnsupportedOrder(order).into())
+        } else {
+            self.want_all(id, missing)
+        };
+        if let Err(err) = res {
+            self.complete_sync(id, Err(err));
+        } else if !self.pending.contains_key(&id) {
+            self.complete_sync(id, Ok(()));
+        }
+        (rx, id)
+    }
+
+    /// Removes the query from all wants, cancelling the bitswap queries that no other
+    /// query is waiting for.
+    fn unwant(&mut self, id: &QueryId) {
+        if let Some(pending) = self.pending.remove(id) {
+            for cid in pending {
+                if let Some(bitswap_id) = self.wants.remove_waiter(&cid, id) {
+                    self.connected_wants.remove(&bitswap_id);
+                    self.bitswap.cancel(bitswap_id);
+                }
+            }
+        }
+        self.schedule();
+    }
+
+    pub fn cancel(&mut self, id: QueryId) {
+        self.unwant(&id);
+        self.queries.remove(&id);
+        self.priorities.remove(&id);
+        self.syncs.remove(&id);
+    }
+
+    /// Returns the number of requested and queued wants.
+    pub fn want_counts(&self) -> (usize, usize) {
+        (self.wants.requested(), self.wants.queued())
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
+        self.bitswap.register_metrics(registry)?;
+        registry.register(Box::new(self.blocks_rejected.clone()))?;
+        registry.register(Box::new(self.wants_dropped.clone()))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dht")]
+impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    /// Returns the mode the dht is operating in, `None` if kad is disabled.
+    pub fn dht_mode(&self) -> Option<DhtMode> {
+        self.kad.as_ref().map(|kad| kad.mode())
+    }
+
     pub fn bootstrap(&mut self) -> BootstrapChannel {
         let (tx, rx) = oneshot::channel();
+        self.audit(AuditKind::DhtBootstrap, "", &[]);
         if let Some(kad) = self.kad.as_mut() {
             match kad.bootstrap() {
                 Ok(id) => {
@@ -442,6 +1868,7 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub fn provide(&mut self, cid: Cid) -> StartProvidingChannel {
         let (tx, rx) = oneshot::channel();
         if self.bootstrap_complete {
+            self.audit(AuditKind::DhtProvide, &cid.to_string(), &[]);
             if let Some(kad) = self.kad.as_mut() {
                 let key = Key::new(&cid.to_bytes());
                 match kad.start_providing(key) {
@@ -470,6 +1897,7 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub fn get_record(&mut self, key: &Key, quorum: Quorum) -> GetRecordChannel {
         let (tx, rx) = oneshot::channel();
         if self.bootstrap_complete {
+            self.audit(AuditKind::DhtGetRecord, &audit::hex(key.as_ref()), &[]);
             if let Some(kad) = self.kad.as_mut() {
                 let id = kad.get_record(key, quorum);
                 self.queries.insert(id.into(), QueryChannel::GetRecord(tx));
@@ -483,6 +1911,11 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub fn put_record(&mut self, record: Record, quorum: Quorum) -> PutRecordChannel {
         let (tx, rx) = oneshot::channel();
         if self.bootstrap_complete {
+            self.audit(
+                AuditKind::DhtPutRecord,
+                &audit::hex(record.key.as_ref()),
+                &[],
+            );
             if let Some(kad) = self.kad.as_mut() {
                 match kad.put_record(record, quorum) {
                     Ok(id) => {
@@ -505,18 +1938,85 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         }
     }
 
-    pub fn subscribe(&mut self, topic: &str) -> Result<impl Stream<Item = Vec<u8>>> {
-        let (tx, rx) = mpsc::unbounded();
-        if let Some(subscribers) = self.subscriptions.get_mut(topic) {
-            subscribers.push(tx);
-        } else {
-            self.subscriptions.insert(topic.to_string(), vec![tx]);
-            let topic = IdentTopic::new(topic);
-            self.gossipsub
-                .subscribe(&topic)
-                .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+    fn add_dht_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
+        if let Some(kad) = self.kad.as_mut() {
+            kad.add_address(peer_id, addr);
         }
-        Ok(rx)
+    }
+
+    fn remove_dht_address(&mut self, peer_id: &PeerId, addr: &Multiaddr) {
+        if let Some(kad) = self.kad.as_mut() {
+            kad.remove_address(peer_id, addr);
+        }
+    }
+
+    fn get_providers(&mut self, id: libp2p_bitswap::QueryId, cid: Cid) {
+        let key = Key::new(&cid.to_bytes());
+        let kad_id = self.kad.as_mut().unwrap().get_providers(key);
+        self.provider_queries.insert(kad_id, id);
+    }
+
+    fn set_dht_nat_status(&mut self, status: NatStatus) {
+        if let Some(kad) = self.kad.as_mut() {
+            kad.set_nat_status(status);
+        }
+    }
+}
+
+/// Returns a channel that fails with `DhtDisabled`.
+#[cfg(not(feature = "dht"))]
+fn dht_disabled<T>() -> oneshot::Receiver<Result<T>> {
+    let (tx, rx) = oneshot::channel();
+    tx.send(Err(DhtDisabled.into())).ok();
+    rx
+}
+
+#[cfg(not(feature = "dht"))]
+impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    /// Returns the mode the dht is operating in, `None` if kad is disabled.
+    pub fn dht_mode(&self) -> Option<DhtMode> {
+        None
+    }
+
+    pub fn bootstrap(&mut self) -> BootstrapChannel {
+        dht_disabled()
+    }
+
+    pub fn provide(&mut self, _cid: Cid) -> StartProvidingChannel {
+        dht_disabled()
+    }
+
+    pub fn unprovide(&mut self, _cid: Cid) {}
+
+    pub fn get_record(&mut self, _key: &Key, _quorum: Quorum) -> GetRecordChannel {
+        dht_disabled()
+    }
+
+    pub fn put_record(&mut self, _record: Record, _quorum: Quorum) -> PutRecordChannel {
+        dht_disabled()
+    }
+
+    pub fn remove_record(&mut self, _key: &Key) {}
+
+    fn add_dht_address(&mut self, _peer_id: &PeerId, _addr: Multiaddr) {}
+
+    fn remove_dht_address(&mut self, _peer_id: &PeerId, _addr: &Multiaddr) {}
+
+    /// The dht is never bootstrapped, so providers are never looked up.
+    fn get_providers(&mut self, id: libp2p_bitswap::QueryId, _cid: Cid) {
+        self.bitswap.inject_providers(id, vec![]);
+    }
+
+    fn set_dht_nat_status(&mut self, _status: NatStatus) {}
+}
+
+#[cfg(feature = "gossip")]
+impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    fn gossip_subscribe(&mut self, topic: &str) -> Result<()> {
+        self.gossipsub
+            .subscribe(&IdentTopic::new(topic))
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+        Ok(())
     }
 
     fn unsubscribe(&mut self, topic: &str) {
@@ -528,35 +2028,30 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
 
     pub fn publish(&mut self, topic: &str, msg: Vec<u8>) -> Result<()> {
         let topic = IdentTopic::new(topic);
+        if self.audit.is_some() {
+            let hash = topic.hash();
+            let peers = self
+                .gossipsub
+                .all_peers()
+                .filter(|(_, topics)| topics.contains(&&hash))
+                .map(|(peer, _)| *peer)
+                .collect::<Vec<_>>();
+            self.audit(AuditKind::GossipPublish, hash.as_str(), &peers);
+        }
         self.gossipsub
             .publish(topic, msg)
             .map_err(GossipsubPublishError)?;
         Ok(())
     }
+}
 
-    pub fn get(&mut self, cid: Cid) -> (GetChannel, QueryId) {
-        let (tx, rx) = oneshot::channel();
-        let id = self.bitswap.get(cid, std::iter::empty());
-        self.queries.insert(id.into(), QueryChannel::Get(tx));
-        (rx, id.into())
-    }
-
-    pub fn sync(&mut self, cid: Cid, missing: impl Iterator<Item = Cid>) -> (SyncChannel, QueryId) {
-        let (tx, rx) = mpsc::unbounded();
-        let id = self.bitswap.sync(cid, missing);
-        self.queries.insert(id.into(), QueryChannel::Sync(tx));
-        (rx, id.into())
-    }
-
-    pub fn cancel(&mut self, id: QueryId) {
-        self.queries.remove(&id);
-        if let QueryId(InnerQueryId::Bitswap(id)) = id {
-            self.bitswap.cancel(id);
-        }
+#[cfg(not(feature = "gossip"))]
+impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    fn gossip_subscribe(&mut self, _topic: &str) -> Result<()> {
+        Err(GossipDisabled.into())
     }
 
-    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
-        self.bitswap.register_metrics(registry)?;
-        Ok(())
+    pub fn publish(&mut self, _topic: &str, _msg: Vec<u8>) -> Result<()> {
+        Err(GossipDisabled.into())
     }
 }
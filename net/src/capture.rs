@@ -0,0 +1,599 @@
+//! Capture of the protocol messages of all connections for debugging interop issues.
+//!
+//! A capture file starts with the 8 byte magic `IECAP002`, followed by a record for every
+//! message read from or written to a substream. All integers are big endian:
+//!
+//! | field  | type           | description                                              |
+//! |--------|----------------|----------------------------------------------------------|
+//! | time   | u64            | microseconds since the unix epoch                        |
+//! | stream | u64            | id of the substream, unique within the capture           |
+//! | flags  | u8             | bit 0: the data was sent, bit 1: the node opened the stream, bit 2: the data is a message |
+//! | peer   | u16 + bytes    | length and bytes of the remote peer id                   |
+//! | len    | u32            | length of the message or chunk                           |
+//! | data   | u32 + bytes    | length and bytes of the data, truncated to `max_frame_size` |
+//!
+//! The multistream-select negotiation at the start of every substream, bitswap, kademlia,
+//! gossipsub and identify prefix their messages with their length as an unsigned varint.
+//! Their records hold one message each without the length prefix. The data of other
+//! protocols, or of substreams whose framing is invalid, is recorded in chunks as it is
+//! transferred. `CaptureReader` determines the protocol of a substream from its
+//! negotiation.
+//!
+//! The records are written by a blocking task, so capturing never blocks a connection.
+//! When the writer falls behind by more than `QUEUE_SIZE` records, records are dropped.
+//! The capture file is rotated after `max_file_size` bytes, every file starting with the
+//! magic.
+use crate::audit::rotated;
+use fnv::FnvHashMap;
+use futures::ready;
+use libipld::Result;
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerEvent};
+use libp2p::PeerId;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"IECAP002";
+const SENT: u8 = 1;
+const OUTBOUND: u8 = 2;
+const MESSAGE: u8 = 4;
+const MULTISTREAM: &[u8] = b"/multistream/1.0.0";
+/// Number of records queued for the writer before records are dropped.
+const QUEUE_SIZE: usize = 4096;
+/// Length prefixes above this are taken as invalid framing.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 64;
+/// Prefixes of the protocols whose messages are prefixed with their length.
+const LENGTH_PREFIXED: &[&str] = &["/ipfs/bitswap", "/ipfs/kad/", "/meshsub/", "/ipfs/id/"];
+
+/// Capture configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaptureConfig {
+    /// Path of the capture file. An existing file is overwritten. Rotated files get the
+    /// suffix `.1`, `.2` and so on, with `.1` being the most recent.
+    pub path: PathBuf,
+    /// Data of a record is truncated to `max_frame_size` bytes, the `len` of the record
+    /// is the length of the message or chunk.
+    pub max_frame_size: usize,
+    /// Size in bytes after which the capture file is rotated.
+    pub max_file_size: u64,
+    /// Number of rotated files that are kept.
+    pub max_files: usize,
+}
+
+impl CaptureConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_frame_size: 1024 * 1024 * 4,
+            max_file_size: 1024 * 1024 * 64,
+            max_files: 4,
+        }
+    }
+}
+
+/// Error returned when reading a file that isn't a capture.
+#[derive(Debug, Error)]
+#[error("invalid capture file")]
+pub struct InvalidCapture;
+
+/// Message or chunk of data read from or written to a substream.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CapturedFrame {
+    /// When the data was transferred.
+    pub time: SystemTime,
+    /// Id of the substream.
+    pub stream: u64,
+    /// Remote peer of the substream.
+    pub peer: PeerId,
+    /// If the data was sent, or received otherwise.
+    pub sent: bool,
+    /// If the substream was opened by the node, or by the peer otherwise.
+    pub outbound: bool,
+    /// If the data is a whole message without its length prefix, or a chunk of the
+    /// substream otherwise.
+    pub message: bool,
+    /// Protocol of the substream, `None` while it is being negotiated.
+    pub protocol: Option<String>,
+    /// Length of the message or chunk.
+    pub len: usize,
+    /// The data, truncated to the `max_frame_size` of the capture.
+    pub data: Vec<u8>,
+}
+
+/// Writes the records of a capture, rotating the capture file.
+struct CaptureWriter {
+    config: CaptureConfig,
+    file: File,
+    size: u64,
+}
+
+impl CaptureWriter {
+    fn create(config: CaptureConfig) -> Result<Self> {
+        let mut file = File::create(&config.path)?;
+        file.write_all(MAGIC)?;
+        Ok(Self {
+            config,
+            file,
+            size: MAGIC.len() as u64,
+        })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let path = &self.config.path;
+        if self.config.max_files > 0 {
+            for n in (1..self.config.max_files).rev() {
+                let from = rotated(path, n);
+                if from.exists() {
+                    std::fs::rename(from, rotated(path, n + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated(path, 1))?;
+        }
+        self.file = File::create(path)?;
+        self.file.write_all(MAGIC)?;
+        self.size = MAGIC.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &[u8]) -> Result<()> {
+        if self.size > MAGIC.len() as u64
+            && self.size + record.len() as u64 > self.config.max_file_size
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+}
+
+struct CaptureQueue {
+    tx: SyncSender<Vec<u8>>,
+    max_frame_size: usize,
+    next_stream: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Writes the captured substreams to a file.
+#[derive(Clone)]
+pub(crate) struct Capture(Arc<CaptureQueue>);
+
+impl Capture {
+    pub fn create(config: &CaptureConfig) -> Result<Self> {
+        let mut writer = CaptureWriter::create(config.clone())?;
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(QUEUE_SIZE);
+        ipfs_embed_rt::spawn(ipfs_embed_rt::spawn_blocking(move || {
+            for record in rx {
+                if let Err(err) = writer.write(&record) {
+                    tracing::warn!("failed to write capture: {}", err);
+                }
+            }
+        }))
+        .detach();
+        Ok(Self(Arc::new(CaptureQueue {
+            tx,
+            max_frame_size: config.max_frame_size,
+            next_stream: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })))
+    }
+
+    fn next_stream(&self) -> u64 {
+        self.0.next_stream.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn record(&self, stream: u64, flags: u8, peer: &[u8], len: usize, data: &[u8]) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let truncated = &data[..std::cmp::min(data.len(), self.0.max_frame_size)];
+        let mut record = Vec::with_capacity(31 + peer.len() + truncated.len());
+        record.extend_from_slice(&time.to_be_bytes());
+        record.extend_from_slice(&stream.to_be_bytes());
+        record.push(flags);
+        record.extend_from_slice(&(peer.len() as u16).to_be_bytes());
+        record.extend_from_slice(peer);
+        record.extend_from_slice(&(len as u32).to_be_bytes());
+        record.extend_from_slice(&(truncated.len() as u32).to_be_bytes());
+        record.extend_from_slice(truncated);
+        match self.0.tx.try_send(record) {
+            Ok(()) => {
+                if self.0.dropped.load(Ordering::Relaxed) > 0 {
+                    let dropped = self.0.dropped.swap(0, Ordering::Relaxed);
+                    tracing::warn!("capture dropped {} records", dropped);
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                self.0.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::warn!("failed to write capture: the writer stopped");
+            }
+        }
+    }
+}
+
+/// Returns the protocol accepted by a message of the listener of a multistream-select
+/// negotiation. The listener echoes the protocol it accepts and answers `na` otherwise.
+fn accepted_protocol(msg: &[u8]) -> Option<String> {
+    let msg = msg.strip_suffix(b"\n").unwrap_or(msg);
+    if msg == MULTISTREAM || msg == b"na" {
+        return None;
+    }
+    std::str::from_utf8(msg)
+        .ok()
+        .map(|protocol| protocol.to_string())
+}
+
+fn is_length_prefixed(protocol: &str) -> bool {
+    LENGTH_PREFIXED
+        .iter()
+        .any(|prefix| protocol.starts_with(prefix))
+}
+
+/// Splits the data of one direction of a substream into length prefixed messages.
+#[derive(Default)]
+struct Framer {
+    /// Bytes of the length prefix read so far.
+    header: Vec<u8>,
+    /// Length, truncated data and number of bytes still to read of the current message.
+    message: Option<(usize, Vec<u8>, usize)>,
+}
+
+impl Framer {
+    /// Returns `true` between messages.
+    fn is_idle(&self) -> bool {
+        self.header.is_empty() && self.message.is_none()
+    }
+
+    /// Consumes `data` until a message is complete, returning its length and its data
+    /// truncated to `max` bytes. Fails with the bytes of the length prefix if the prefix
+    /// is invalid or exceeds `MAX_MESSAGE_SIZE`.
+    fn next(
+        &mut self,
+        data: &mut &[u8],
+        max: usize,
+    ) -> std::result::Result<Option<(usize, Vec<u8>)>, Vec<u8>> {
+        loop {
+            if let Some((_, buf, remaining)) = self.message.as_mut() {
+                let n = std::cmp::min(*remaining, data.len());
+                let keep = std::cmp::min(n, max.saturating_sub(buf.len()));
+                buf.extend_from_slice(&data[..keep]);
+                *remaining -= n;
+                *data = &data[n..];
+                if *remaining > 0 {
+                    return Ok(None);
+                }
+                let (len, buf, _) = self.message.take().unwrap();
+                return Ok(Some((len, buf)));
+            }
+            let (byte, rest) = match data.split_first() {
+                Some(split) => split,
+                None => return Ok(None),
+            };
+            *data = rest;
+            self.header.push(*byte);
+            match read_uvarint(&self.header) {
+                Some(Ok((len, _))) if len <= MAX_MESSAGE_SIZE => {
+                    self.header.clear();
+                    let buf = Vec::with_capacity(std::cmp::min(len, max));
+                    self.message = Some((len, buf, len));
+                }
+                Some(_) => return Err(std::mem::take(&mut self.header)),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Sent or received data of a substream.
+#[derive(Default)]
+struct Direction {
+    framer: Framer,
+    /// The negotiation ended in this direction.
+    negotiated: bool,
+    /// The data is recorded in chunks.
+    chunked: bool,
+}
+
+/// Substream recorded by a `CaptureMuxer`.
+pub(crate) struct CaptureSubstream<S> {
+    inner: S,
+    id: u64,
+    outbound: bool,
+    /// Protocol accepted by the listener.
+    protocol: Option<String>,
+    /// State of the received and the sent data.
+    directions: [Direction; 2],
+}
+
+/// Muxer recording the data of its substreams.
+pub(crate) struct CaptureMuxer<M> {
+    inner: M,
+    peer: Vec<u8>,
+    capture: Capture,
+}
+
+impl<M> CaptureMuxer<M> {
+    pub fn new(inner: M, peer: &PeerId, capture: Capture) -> Self {
+        Self {
+            inner,
+            peer: peer.to_bytes(),
+            capture,
+        }
+    }
+
+    fn substream<S>(&self, inner: S, outbound: bool) -> CaptureSubstream<S> {
+        CaptureSubstream {
+            inner,
+            id: self.capture.next_stream(),
+            outbound,
+            protocol: None,
+            directions: Default::default(),
+        }
+    }
+
+    fn record<S>(&self, substream: &mut CaptureSubstream<S>, sent: bool, mut data: &[u8]) {
+        let CaptureSubstream {
+            id,
+            outbound,
+            protocol,
+            directions,
+            ..
+        } = substream;
+        let mut flags = 0;
+        if sent {
+            flags |= SENT;
+        }
+        if *outbound {
+            flags |= OUTBOUND;
+        }
+        // the node is the listener of inbound substreams.
+        let listener = sent != *outbound;
+        let direction = &mut directions[sent as usize];
+        let max = self.capture.0.max_frame_size;
+        while !data.is_empty() {
+            // the listener switches to the protocol after echoing it, the dialer waits for
+            // the echo before sending messages of the protocol.
+            if !direction.negotiated && direction.framer.is_idle() {
+                if let Some(protocol) = protocol.as_deref() {
+                    direction.negotiated = true;
+                    direction.chunked = !is_length_prefixed(protocol);
+                }
+            }
+            if direction.chunked {
+                self.capture
+                    .record(*id, flags, &self.peer, data.len(), data);
+                return;
+            }
+            match direction.framer.next(&mut data, max) {
+                Ok(Some((len, msg))) => {
+                    if listener && protocol.is_none() {
+                        *protocol = accepted_protocol(&msg);
+                    }
+                    self.capture
+                        .record(*id, flags | MESSAGE, &self.peer, len, &msg);
+                }
+                Ok(None) => {}
+                Err(mut chunk) => {
+                    direction.chunked = true;
+                    chunk.extend_from_slice(data);
+                    self.capture
+                        .record(*id, flags, &self.peer, chunk.len(), &chunk);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl<M: StreamMuxer> StreamMuxer for CaptureMuxer<M> {
+    type Substream = CaptureSubstream<M::Substream>;
+    type OutboundSubstream = M::OutboundSubstream;
+    type Error = M::Error;
+
+    fn poll_event(
+        &self,
+        cx: &mut Context,
+    ) -> Poll<Result<StreamMuxerEvent<Self::Substream>, Self::Error>> {
+        let event = match ready!(self.inner.poll_event(cx))? {
+            StreamMuxerEvent::InboundSubstream(inner) => {
+                StreamMuxerEvent::InboundSubstream(self.substream(inner, false))
+            }
+            StreamMuxerEvent::AddressChange(addr) => StreamMuxerEvent::AddressChange(addr),
+        };
+        Poll::Ready(Ok(event))
+    }
+
+    fn open_outbound(&self) -> Self::OutboundSubstream {
+        self.inner.open_outbound()
+    }
+
+    fn poll_outbound(
+        &self,
+        cx: &mut Context,
+        substream: &mut Self::OutboundSubstream,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(self.inner.poll_outbound(cx, substream))?;
+        Poll::Ready(Ok(self.substream(inner, true)))
+    }
+
+    fn destroy_outbound(&self, substream: Self::OutboundSubstream) {
+        self.inner.destroy_outbound(substream)
+    }
+
+    fn read_substream(
+        &self,
+        cx: &mut Context,
+        substream: &mut Self::Substream,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let n = ready!(self.inner.read_substream(cx, &mut substream.inner, buf))?;
+        self.record(substream, false, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn write_substream(
+        &self,
+        cx: &mut Context,
+        substream: &mut Self::Substream,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        let n = ready!(self.inner.write_substream(cx, &mut substream.inner, buf))?;
+        self.record(substream, true, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn flush_substream(
+        &self,
+        cx: &mut Context,
+        substream: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.flush_substream(cx, &mut substream.inner)
+    }
+
+    fn shutdown_substream(
+        &self,
+        cx: &mut Context,
+        substream: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.shutdown_substream(cx, &mut substream.inner)
+    }
+
+    fn destroy_substream(&self, substream: Self::Substream) {
+        self.inner.destroy_substream(substream.inner)
+    }
+
+    fn close(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.close(cx)
+    }
+
+    fn flush_all(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.flush_all(cx)
+    }
+}
+
+/// Reads an unsigned varint, returning the value and the number of bytes read. Returns
+/// `None` if more bytes are needed.
+fn read_uvarint(buf: &[u8]) -> Option<std::result::Result<(usize, usize), ()>> {
+    let mut value = 0usize;
+    for (i, byte) in buf.iter().enumerate() {
+        if i >= 9 {
+            return Some(Err(()));
+        }
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(Ok((value, i + 1)));
+        }
+    }
+    None
+}
+
+/// Reads the frames of a capture file in the order they were recorded.
+pub struct CaptureReader<R = BufReader<File>> {
+    reader: R,
+    /// Protocols accepted by the listeners of the substreams.
+    streams: FnvHashMap<u64, Option<String>>,
+}
+
+impl CaptureReader {
+    /// Opens the capture file at `path`. Rotated files are read separately.
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Reads a capture from `reader`, failing with `InvalidCapture` if it doesn't start
+    /// with the capture magic.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).map_err(|_| InvalidCapture)?;
+        if &magic != MAGIC {
+            return Err(InvalidCapture.into());
+        }
+        Ok(Self {
+            reader,
+            streams: Default::default(),
+        })
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Reads `len` bytes, allocating as the bytes are read, so that a corrupt length
+    /// doesn't allocate more than the capture contains.
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            return Err(InvalidCapture.into());
+        }
+        Ok(buf)
+    }
+
+    /// Returns the next frame or `None` at the end of the capture.
+    pub fn read_frame(&mut self) -> Result<Option<CapturedFrame>> {
+        let mut time = [0; 8];
+        if self.reader.read(&mut time[..1])? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut time[1..])?;
+        let time = UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(time));
+        let stream = self.read_u64()?;
+        let flags = self.read_bytes(1)?[0];
+        let mut peer_len = [0; 2];
+        self.reader.read_exact(&mut peer_len)?;
+        let peer = self.read_bytes(u16::from_be_bytes(peer_len) as usize)?;
+        let peer = PeerId::from_bytes(&peer).map_err(|_| InvalidCapture)?;
+        let len = self.read_u32()? as usize;
+        let data_len = self.read_u32()? as usize;
+        if data_len > len {
+            return Err(InvalidCapture.into());
+        }
+        let data = self.read_bytes(data_len)?;
+        let sent = flags & SENT != 0;
+        let outbound = flags & OUTBOUND != 0;
+        let message = flags & MESSAGE != 0;
+        let protocol = self.streams.entry(stream).or_default();
+        // the node is the listener of inbound substreams.
+        if message && sent != outbound && protocol.is_none() {
+            *protocol = accepted_protocol(&data);
+        }
+        Ok(Some(CapturedFrame {
+            time,
+            stream,
+            peer,
+            sent,
+            outbound,
+            message,
+            protocol: protocol.clone(),
+            len,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
@@ -1,10 +1,42 @@
-use libp2p::core::PeerId;
+use crate::audit::AuditConfig;
+use crate::bandwidth::BandwidthLimits;
+use crate::beacon::BeaconConfig;
+use crate::capture::CaptureConfig;
+use crate::exchange::BlockReceiver;
+use crate::limits::TraversalLimits;
+use crate::policy::BlockPolicy;
+use crate::portmap::PortMapConfig;
+use crate::resolver::DnsResolver;
+use crate::retry::{Backoff, RetryPolicy};
+use crate::socks::Socks5Config;
+use crate::translate::AddressTranslation;
+use libp2p::core::{Multiaddr, PeerId};
 use libp2p::identity::{Keypair, PublicKey};
 use libp2p::ping::PingConfig;
 use libp2p::pnet::PreSharedKey;
 use std::num::NonZeroU16;
+use std::sync::Arc;
 use std::time::Duration;
 
+/// Mode of the kademlia dht.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DhtMode {
+    /// Answers dht requests from peers, which adds the node to their routing tables.
+    Server,
+    /// Only makes dht requests. The kademlia protocol isn't advertised, so peers don't
+    /// add the node to their routing tables.
+    Client,
+    /// Acts as a server unless peers fail to dial the node back, in which case it is
+    /// demoted to a client.
+    Auto,
+}
+
+impl Default for DhtMode {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
 /// Network configuration.
 #[derive(Clone)]
 pub struct NetworkConfig {
@@ -12,31 +44,121 @@ pub struct NetworkConfig {
     pub node_key: Keypair,
     /// Name of the node. Sent over the wire for debugging purposes.
     pub node_name: String,
-    /// Enable mdns.
+    /// Enable mdns. Requires the `mdns` feature.
     pub enable_mdns: bool,
-    /// Enable kad.
+    /// Enable kad. Requires the `dht` feature.
     pub enable_kad: bool,
     /// Should we insert non-global addresses into the DHT?
     pub allow_non_globals_in_dht: bool,
+    /// Verify the listen addresses of peers that connected to the node by dialing them
+    /// back, before adding the addresses to the address book and the dht. Listen
+    /// addresses of inbound peers are ignored otherwise.
+    pub dial_back: bool,
+    /// Dht mode. Nodes behind a nat are demoted to clients by default.
+    pub dht_mode: DhtMode,
     /// Bitswap request timeout.
     pub bitswap_request_timeout: Duration,
     /// Bitswap connection keep alive.
     pub bitswap_connection_keepalive: Duration,
     /// Bitswap inbound requests per peer limit.
     pub bitswap_receive_limit: NonZeroU16,
+    /// Maximum number of background blocks requested concurrently. Background blocks are
+    /// only requested while no interactive blocks are in flight.
+    pub bitswap_background_wants: usize,
+    /// Maximum number of blocks read concurrently from the store to answer wants of
+    /// peers.
+    pub bitswap_serve_reads: usize,
+    /// Maximum number of wants of a single peer answered per second. Further wants are
+    /// answered as if the block wasn't stored.
+    pub bitswap_serve_peer_rate: u32,
+    /// Maximum number of established inbound connections. Further peers dialing the node
+    /// are refused. `None` doesn't limit them.
+    pub max_incoming_connections: Option<u32>,
+    /// Default limits of sync queries.
+    pub sync_limits: TraversalLimits,
+    /// Retry policy of bitswap fetches, the blocks of sync queries and dht record
+    /// lookups. Defaults to a single attempt.
+    ///
+    /// Blocks of sync queries that fail with a retried error while no peer is connected
+    /// are requested again once a peer connects.
+    pub retry_policy: RetryPolicy,
+    /// Time after which a sync query fails when its blocks keep waiting for a peer to
+    /// connect. `None` waits forever.
+    pub sync_stall_timeout: Option<Duration>,
+    /// Time the `Authenticator` has to accept a peer before the peer is disconnected.
+    pub auth_timeout: Duration,
+    /// Time a peer that failed to authenticate stays banned, by its number of failures.
+    pub auth_backoff: Backoff,
+    /// Accept blocks pushed by peers.
+    pub enable_push: bool,
+    /// Maximum number of blocks in a single push.
+    pub push_max_blocks: usize,
+    /// Accept rendezvous registrations of peers.
+    pub enable_rendezvous_server: bool,
+    /// Rendezvous points the node registers at and discovers peers from.
+    pub rendezvous_points: Vec<(PeerId, Multiaddr)>,
+    /// Namespace registered at the rendezvous points. No registrations are made if `None`.
+    pub rendezvous_namespace: Option<String>,
+    /// Time to live of rendezvous registrations.
+    pub rendezvous_ttl: Duration,
+    /// Interval at which registrations are renewed and peers are discovered at the
+    /// rendezvous points. Should be shorter than the `rendezvous_ttl`.
+    pub rendezvous_discovery_interval: Duration,
+    /// Hashes and codecs of blocks accepted from the network.
+    pub block_policy: BlockPolicy,
+    /// Upload and download limits of all connections.
+    pub bandwidth_limits: BandwidthLimits,
     /// Pre shared key for pnet.
     pub psk: Option<PreSharedKey>,
+    /// Dials all peers through a socks5 proxy when set, for example a tor daemon. Peers
+    /// can still connect to the listeners of the node directly, and mdns leaks the local
+    /// addresses, so it should be disabled when privacy matters.
+    pub socks5: Option<Socks5Config>,
+    /// Resolves the domain names of dns addresses and dnslinks when set, instead of the
+    /// system resolver. Not used for dialing when the socks5 proxy resolves domain names.
+    pub dns_resolver: Option<Arc<dyn DnsResolver>>,
+    /// Notified of every block received via bitswap, from a background task.
+    pub block_receiver: Option<Arc<dyn BlockReceiver>>,
+    /// Maps the ports of the listeners on the gateway with nat-pmp or upnp when set, and
+    /// announces the external addresses to peers.
+    pub port_mapping: Option<PortMapConfig>,
+    /// Translations of listen addresses to the addresses they are reachable at from
+    /// outside. The external addresses are announced to peers and in the dht.
+    pub address_translations: Vec<AddressTranslation>,
+    /// Discovers peers on the local network with udp broadcast beacons signed with the
+    /// node key. Can be used instead of mdns on networks blocking multicast.
+    pub beacon: Option<BeaconConfig>,
     /// Ping config.
     pub ping: PingConfig,
+    /// Initial delay before rebinding a listener that closed with an error. The delay is
+    /// doubled after every failed attempt.
+    pub listener_rebind_backoff: Duration,
+    /// Maximum delay between attempts to rebind a listener.
+    pub listener_rebind_max_backoff: Duration,
+    /// Number of distinct peers that need to observe an address of the node before it is
+    /// announced as an external address.
+    pub external_address_confidence: usize,
+    /// Interval at which published provider and dht records are republished.
+    pub republish_interval: Duration,
+    /// Maximum delay added to the republish interval. The delay is derived from the record
+    /// key, spreading the republishing of records evenly over time.
+    pub republish_jitter: Duration,
+    /// Records outbound dht queries, bitswap wants and gossip publishes when set.
+    pub audit_log: Option<AuditConfig>,
+    /// Records the data of all substreams to a file when set, for debugging interop
+    /// issues. Captures contain the unencrypted traffic of the node.
+    pub capture: Option<CaptureConfig>,
 }
 
 impl NetworkConfig {
     /// Creates a new network configuration.
     pub fn new() -> Self {
         Self {
-            enable_mdns: true,
-            enable_kad: true,
+            enable_mdns: cfg!(feature = "mdns"),
+            enable_kad: cfg!(feature = "dht"),
             allow_non_globals_in_dht: false,
+            dial_back: false,
+            dht_mode: DhtMode::Auto,
             node_key: Keypair::generate_ed25519(),
             node_name: names::Generator::with_naming(names::Name::Numbered)
                 .next()
@@ -44,8 +166,42 @@ impl NetworkConfig {
             bitswap_request_timeout: Duration::from_secs(10),
             bitswap_connection_keepalive: Duration::from_secs(10),
             bitswap_receive_limit: NonZeroU16::new(20).expect("20 > 0"),
+            bitswap_background_wants: 32,
+            bitswap_serve_reads: 8,
+            bitswap_serve_peer_rate: 64,
+            max_incoming_connections: None,
+            sync_limits: TraversalLimits::unlimited(),
+            retry_policy: RetryPolicy::none(),
+            sync_stall_timeout: Some(Duration::from_secs(300)),
+            auth_timeout: Duration::from_secs(10),
+            auth_backoff: Backoff::Exponential {
+                initial: Duration::from_secs(10),
+                max: Duration::from_secs(3600),
+            },
+            enable_push: false,
+            push_max_blocks: 64,
+            enable_rendezvous_server: false,
+            rendezvous_points: vec![],
+            rendezvous_namespace: None,
+            rendezvous_ttl: Duration::from_secs(60 * 60 * 2),
+            rendezvous_discovery_interval: Duration::from_secs(60 * 5),
+            block_policy: BlockPolicy::allow_all(),
+            bandwidth_limits: BandwidthLimits::unlimited(),
             psk: None,
+            socks5: None,
+            dns_resolver: None,
+            block_receiver: None,
+            port_mapping: None,
+            address_translations: vec![],
+            beacon: None,
             ping: PingConfig::new().with_keep_alive(true),
+            listener_rebind_backoff: Duration::from_secs(1),
+            listener_rebind_max_backoff: Duration::from_secs(60),
+            external_address_confidence: 3,
+            republish_interval: Duration::from_secs(60 * 60 * 12),
+            republish_jitter: Duration::from_secs(60 * 10),
+            audit_log: None,
+            capture: None,
         }
     }
 
@@ -74,13 +230,55 @@ impl std::fmt::Debug for NetworkConfig {
             .field("enable_mdns", &self.enable_mdns)
             .field("enable_kad", &self.enable_kad)
             .field("allow_non_globals_in_dht", &self.allow_non_globals_in_dht)
+            .field("dial_back", &self.dial_back)
+            .field("dht_mode", &self.dht_mode)
             .field("bitswap_request_timeout", &self.bitswap_request_timeout)
             .field(
                 "bitswap_connection_keepalive",
                 &self.bitswap_connection_keepalive,
             )
             .field("bitswap_receive_limit", &self.bitswap_receive_limit)
+            .field("bitswap_background_wants", &self.bitswap_background_wants)
+            .field("bitswap_serve_reads", &self.bitswap_serve_reads)
+            .field("bitswap_serve_peer_rate", &self.bitswap_serve_peer_rate)
+            .field("max_incoming_connections", &self.max_incoming_connections)
+            .field("sync_limits", &self.sync_limits)
+            .field("retry_policy", &self.retry_policy)
+            .field("sync_stall_timeout", &self.sync_stall_timeout)
+            .field("auth_timeout", &self.auth_timeout)
+            .field("auth_backoff", &self.auth_backoff)
+            .field("enable_push", &self.enable_push)
+            .field("push_max_blocks", &self.push_max_blocks)
+            .field("enable_rendezvous_server", &self.enable_rendezvous_server)
+            .field("rendezvous_points", &self.rendezvous_points)
+            .field("rendezvous_namespace", &self.rendezvous_namespace)
+            .field("rendezvous_ttl", &self.rendezvous_ttl)
+            .field(
+                "rendezvous_discovery_interval",
+                &self.rendezvous_discovery_interval,
+            )
+            .field("block_policy", &self.block_policy)
+            .field("bandwidth_limits", &self.bandwidth_limits)
             .field("psk", &self.psk.is_some())
+            .field("socks5", &self.socks5.as_ref().map(|socks5| socks5.proxy))
+            .field("dns_resolver", &self.dns_resolver.is_some())
+            .field("block_receiver", &self.block_receiver.is_some())
+            .field("port_mapping", &self.port_mapping)
+            .field("address_translations", &self.address_translations)
+            .field("beacon", &self.beacon)
+            .field("listener_rebind_backoff", &self.listener_rebind_backoff)
+            .field(
+                "listener_rebind_max_backoff",
+                &self.listener_rebind_max_backoff,
+            )
+            .field(
+                "external_address_confidence",
+                &self.external_address_confidence,
+            )
+            .field("republish_interval", &self.republish_interval)
+            .field("republish_jitter", &self.republish_jitter)
+            .field("audit_log", &self.audit_log)
+            .field("capture", &self.capture)
             .finish()
     }
 }
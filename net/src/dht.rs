@@ -0,0 +1,290 @@
+use crate::autonat::NatStatus;
+use crate::config::DhtMode;
+use libp2p::core::connection::{ConnectedPoint, ConnectionId, ListenerId};
+use libp2p::core::upgrade::{DeniedUpgrade, EitherUpgrade};
+use libp2p::kad::handler::{KademliaHandler, KademliaHandlerProto};
+use libp2p::kad::record::store::MemoryStore;
+use libp2p::kad::{Kademlia, KademliaEvent, QueryId};
+use libp2p::swarm::protocols_handler::{
+    InboundUpgradeSend, IntoProtocolsHandler, KeepAlive, OutboundUpgradeSend, ProtocolsHandler,
+    ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr, SubstreamProtocol,
+};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use std::error::Error;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Kademlia behaviour that stops accepting inbound dht requests in client mode.
+pub struct Dht {
+    kad: Kademlia<MemoryStore>,
+    mode: DhtMode,
+    server: Arc<AtomicBool>,
+}
+
+impl Dht {
+    pub fn new(kad: Kademlia<MemoryStore>, mode: DhtMode) -> Self {
+        Self {
+            kad,
+            mode,
+            server: Arc::new(AtomicBool::new(mode != DhtMode::Client)),
+        }
+    }
+
+    /// Returns the mode the node is currently operating in, either `Server` or `Client`.
+    pub fn mode(&self) -> DhtMode {
+        if self.server.load(Ordering::Relaxed) {
+            DhtMode::Server
+        } else {
+            DhtMode::Client
+        }
+    }
+
+    /// Promotes or demotes the node in `DhtMode::Auto` based on its reachability. The
+    /// node stays a server while the reachability is unknown.
+    pub fn set_nat_status(&mut self, status: NatStatus) {
+        if self.mode != DhtMode::Auto {
+            return;
+        }
+        let server = status != NatStatus::Private;
+        if self.server.swap(server, Ordering::Relaxed) != server {
+            tracing::info!("dht mode changed to {:?}", self.mode());
+        }
+    }
+}
+
+impl Deref for Dht {
+    type Target = Kademlia<MemoryStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.kad
+    }
+}
+
+impl DerefMut for Dht {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.kad
+    }
+}
+
+impl NetworkBehaviour for Dht {
+    type ProtocolsHandler = DhtHandlerProto;
+    type OutEvent = KademliaEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        DhtHandlerProto {
+            inner: self.kad.new_handler(),
+            server: self.server.clone(),
+        }
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.kad.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.kad.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.kad.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.kad
+            .inject_connection_established(peer_id, id, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.kad.inject_connection_closed(peer_id, id, endpoint)
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.kad.inject_address_change(peer_id, id, old, new)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: <KademliaHandler<QueryId> as ProtocolsHandler>::OutEvent,
+    ) {
+        self.kad.inject_event(peer_id, connection, event)
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn Error,
+    ) {
+        self.kad.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.kad.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.kad.inject_new_listen_addr(addr)
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.kad.inject_expired_listen_addr(addr)
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.kad.inject_new_external_addr(addr)
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn Error + 'static)) {
+        self.kad.inject_listener_error(id, err)
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        self.kad.inject_listener_closed(id, reason)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<
+            <KademliaHandler<QueryId> as ProtocolsHandler>::InEvent,
+            KademliaEvent,
+        >,
+    > {
+        self.kad.poll(cx, params)
+    }
+}
+
+pub struct DhtHandlerProto {
+    inner: KademliaHandlerProto<QueryId>,
+    server: Arc<AtomicBool>,
+}
+
+impl IntoProtocolsHandler for DhtHandlerProto {
+    type Handler = DhtHandler;
+
+    fn into_handler(self, peer_id: &PeerId, endpoint: &ConnectedPoint) -> Self::Handler {
+        DhtHandler {
+            inner: self.inner.into_handler(peer_id, endpoint),
+            server: self.server,
+        }
+    }
+
+    fn inbound_protocol(&self) -> <Self::Handler as ProtocolsHandler>::InboundProtocol {
+        if self.server.load(Ordering::Relaxed) {
+            self.inner.inbound_protocol()
+        } else {
+            EitherUpgrade::B(DeniedUpgrade)
+        }
+    }
+}
+
+/// Kademlia handler that denies inbound substreams while the node is a client. The
+/// listen protocol is requested for every inbound substream, so a mode change applies to
+/// existing connections too.
+pub struct DhtHandler {
+    inner: KademliaHandler<QueryId>,
+    server: Arc<AtomicBool>,
+}
+
+type Inner = KademliaHandler<QueryId>;
+
+impl ProtocolsHandler for DhtHandler {
+    type InEvent = <Inner as ProtocolsHandler>::InEvent;
+    type OutEvent = <Inner as ProtocolsHandler>::OutEvent;
+    type Error = <Inner as ProtocolsHandler>::Error;
+    type InboundProtocol = <Inner as ProtocolsHandler>::InboundProtocol;
+    type OutboundProtocol = <Inner as ProtocolsHandler>::OutboundProtocol;
+    type InboundOpenInfo = <Inner as ProtocolsHandler>::InboundOpenInfo;
+    type OutboundOpenInfo = <Inner as ProtocolsHandler>::OutboundOpenInfo;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        if self.server.load(Ordering::Relaxed) {
+            self.inner.listen_protocol()
+        } else {
+            SubstreamProtocol::new(EitherUpgrade::B(DeniedUpgrade), ())
+        }
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        protocol: <Self::InboundProtocol as InboundUpgradeSend>::Output,
+        info: Self::InboundOpenInfo,
+    ) {
+        self.inner.inject_fully_negotiated_inbound(protocol, info)
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        protocol: <Self::OutboundProtocol as OutboundUpgradeSend>::Output,
+        info: Self::OutboundOpenInfo,
+    ) {
+        self.inner.inject_fully_negotiated_outbound(protocol, info)
+    }
+
+    fn inject_event(&mut self, event: Self::InEvent) {
+        self.inner.inject_event(event)
+    }
+
+    fn inject_address_change(&mut self, addr: &Multiaddr) {
+        self.inner.inject_address_change(addr)
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Self::OutboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr<<Self::OutboundProtocol as OutboundUpgradeSend>::Error>,
+    ) {
+        self.inner.inject_dial_upgrade_error(info, error)
+    }
+
+    fn inject_listen_upgrade_error(
+        &mut self,
+        info: Self::InboundOpenInfo,
+        error: ProtocolsHandlerUpgrErr<<Self::InboundProtocol as InboundUpgradeSend>::Error>,
+    ) {
+        self.inner.inject_listen_upgrade_error(info, error)
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+    ) -> Poll<
+        ProtocolsHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        self.inner.poll(cx)
+    }
+}
@@ -0,0 +1,202 @@
+use crate::autonat::ip;
+use fnv::{FnvHashMap, FnvHashSet};
+use libp2p::core::connection::{ConnectedPoint, ConnectionId};
+use libp2p::swarm::protocols_handler::DummyProtocolsHandler;
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::task::{Context, Poll};
+
+/// Maximum number of addresses of a peer that are dialed back.
+const MAX_ADDRS_PER_PEER: usize = 8;
+/// Maximum number of dial backs in flight.
+const MAX_PENDING: usize = 64;
+
+/// Outcome of a dial back.
+#[derive(Debug)]
+pub enum DialBackEvent {
+    /// The peer was reached at a listen address it claimed.
+    Verified(PeerId, Multiaddr),
+    /// The listen address claimed by the peer couldn't be dialed, or another peer
+    /// answered.
+    Failed(PeerId, Multiaddr),
+}
+
+/// Verifies the listen addresses of peers that connected to the node by dialing them,
+/// so that peers behind a nat don't pollute the address book and the dht with addresses
+/// nobody can reach.
+///
+/// Like autonat, only addresses on the ip the peer connected from are dialed, so a peer
+/// can't make the node dial arbitrary hosts, and each address is dialed at most once per
+/// connected peer.
+#[derive(Default)]
+pub struct DialBack {
+    enabled: bool,
+    /// The ip each peer with only inbound connections connected from.
+    inbound: FnvHashMap<PeerId, IpAddr>,
+    /// The addresses of each peer that were verified or failed to verify.
+    checked: FnvHashMap<PeerId, FnvHashSet<Multiaddr>>,
+    pending: FnvHashMap<Multiaddr, PeerId>,
+    actions: VecDeque<NetworkBehaviourAction<void::Void, DialBackEvent>>,
+}
+
+impl DialBack {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    /// Dials the listen `addrs` of `peer` on the ip it connected from if the node only
+    /// has inbound connections to it.
+    pub fn verify(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) {
+        if !self.enabled {
+            return;
+        }
+        let observed = match self.inbound.get(&peer) {
+            Some(observed) => *observed,
+            None => return,
+        };
+        let checked = self.checked.get(&peer);
+        let pending = &self.pending;
+        let addrs = addrs
+            .into_iter()
+            .filter(|addr| ip(addr) == Some(observed))
+            .filter(|addr| !pending.contains_key(addr))
+            .filter(|addr| checked.map_or(true, |checked| !checked.contains(addr)))
+            .take(MAX_ADDRS_PER_PEER)
+            .collect::<Vec<_>>();
+        for addr in addrs {
+            if self.pending.len() >= MAX_PENDING {
+                tracing::debug!("too many dial backs, not verifying {} of {}", addr, peer);
+                break;
+            }
+            tracing::trace!("dialing back {} at {}", peer, addr);
+            self.pending.insert(addr.clone(), peer);
+            self.actions
+                .push_back(NetworkBehaviourAction::DialAddress { address: addr });
+        }
+    }
+}
+
+impl NetworkBehaviour for DialBack {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = DialBackEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        vec![]
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inbound.remove(peer_id);
+        self.checked.remove(peer_id);
+        self.pending.retain(|_, peer| peer != peer_id);
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _: &ConnectionId,
+        conn: &ConnectedPoint,
+    ) {
+        match conn {
+            ConnectedPoint::Listener { send_back_addr, .. } => {
+                if let Some(ip) = ip(send_back_addr) {
+                    self.inbound.insert(*peer_id, ip);
+                }
+            }
+            ConnectedPoint::Dialer { address } => {
+                self.inbound.remove(peer_id);
+                if let Some(peer) = self.pending.remove(address) {
+                    self.checked
+                        .entry(peer)
+                        .or_default()
+                        .insert(address.clone());
+                    let event = if peer == *peer_id {
+                        DialBackEvent::Verified(peer, address.clone())
+                    } else {
+                        DialBackEvent::Failed(peer, address.clone())
+                    };
+                    self.actions
+                        .push_back(NetworkBehaviourAction::GenerateEvent(event));
+                }
+            }
+        }
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        _peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        _error: &dyn std::error::Error,
+    ) {
+        if let Some(peer) = self.pending.remove(addr) {
+            self.checked.entry(peer).or_default().insert(addr.clone());
+            self.actions
+                .push_back(NetworkBehaviourAction::GenerateEvent(
+                    DialBackEvent::Failed(peer, addr.clone()),
+                ));
+        }
+    }
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, _event: void::Void) {}
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<void::Void, DialBackEvent>> {
+        if let Some(action) = self.actions.pop_front() {
+            Poll::Ready(action)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dials(dial_back: &mut DialBack) -> Vec<Multiaddr> {
+        dial_back
+            .actions
+            .drain(..)
+            .filter_map(|action| match action {
+                NetworkBehaviourAction::DialAddress { address } => Some(address),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut dial_back = DialBack::new(true);
+        let peer = PeerId::random();
+        let endpoint = ConnectedPoint::Listener {
+            local_addr: "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+            send_back_addr: "/ip4/1.2.3.4/tcp/50000".parse().unwrap(),
+        };
+        dial_back.inject_connection_established(&peer, &ConnectionId::new(0), &endpoint);
+        let own: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        let other: Multiaddr = "/ip4/5.6.7.8/tcp/4001".parse().unwrap();
+        dial_back.verify(peer, vec![own.clone(), other.clone()]);
+        assert_eq!(dials(&mut dial_back), vec![own.clone()]);
+
+        // identify reruns while the dial back is pending or after it failed.
+        dial_back.verify(peer, vec![own.clone(), other.clone()]);
+        assert!(dials(&mut dial_back).is_empty());
+        let error = std::io::Error::new(std::io::ErrorKind::Other, "unreachable");
+        dial_back.inject_addr_reach_failure(None, &own, &error);
+        dial_back.verify(peer, vec![own, other]);
+        assert!(dials(&mut dial_back).is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+use libp2p::core::connection::ConnectionId;
+use libp2p::swarm::protocols_handler::DummyProtocolsHandler;
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use std::task::{Context, Poll};
+
+/// Behaviour standing in for a protocol whose feature is disabled.
+#[derive(Default)]
+pub struct Disabled;
+
+impl NetworkBehaviour for Disabled {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = void::Void;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        vec![]
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, _event: void::Void) {}
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<void::Void, void::Void>> {
+        Poll::Pending
+    }
+}
+
+/// Key of a dht record, standing in for the kademlia key without the `dht` feature.
+#[cfg(not(feature = "dht"))]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Key(Vec<u8>);
+
+#[cfg(not(feature = "dht"))]
+impl Key {
+    /// Creates a key from the bytes of `key`.
+    pub fn new<K: AsRef<[u8]>>(key: &K) -> Self {
+        Self(key.as_ref().to_vec())
+    }
+
+    /// Returns the bytes of the key.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+#[cfg(not(feature = "dht"))]
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(not(feature = "dht"))]
+impl From<Vec<u8>> for Key {
+    fn from(key: Vec<u8>) -> Self {
+        Self(key)
+    }
+}
+
+/// A dht record, standing in for the kademlia record without the `dht` feature.
+#[cfg(not(feature = "dht"))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    /// Key of the record.
+    pub key: Key,
+    /// Value of the record.
+    pub value: Vec<u8>,
+    /// Peer that published the record.
+    pub publisher: Option<PeerId>,
+    /// When the record expires.
+    pub expires: Option<std::time::Instant>,
+}
+
+#[cfg(not(feature = "dht"))]
+impl Record {
+    /// Creates a record that doesn't expire.
+    pub fn new<K: Into<Key>>(key: K, value: Vec<u8>) -> Self {
+        Self {
+            key: key.into(),
+            value,
+            publisher: None,
+            expires: None,
+        }
+    }
+}
+
+/// A record returned by a dht query and the peer it was received from.
+#[cfg(not(feature = "dht"))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerRecord {
+    /// Peer the record was received from, `None` if it was stored locally.
+    pub peer: Option<PeerId>,
+    /// The record.
+    pub record: Record,
+}
+
+/// Number of peers a dht query needs to succeed with.
+#[cfg(not(feature = "dht"))]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Quorum {
+    /// One peer.
+    One,
+    /// A majority of the closest peers.
+    Majority,
+    /// All of the closest peers.
+    All,
+    /// `n` peers.
+    N(std::num::NonZeroUsize),
+}
@@ -0,0 +1,247 @@
+use crate::resolver::DnsResolver;
+use async_trait::async_trait;
+use futures::future::{self, Either};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use ipfs_embed_rt::{TcpStream, Timer};
+use libipld::Result;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, RecordType};
+
+/// Maximum size of a response including the http headers.
+const MAX_RESPONSE_SIZE: u64 = 65_536 + 8192;
+
+fn error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("dns-over-https: {}", msg))
+}
+
+/// Resolves names with dns-over-https queries, so that they can't be observed or
+/// tampered with on the network.
+///
+/// The address of the server is configured instead of resolved, since resolving it
+/// would leak the queries the resolver is meant to protect.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DohResolver {
+    server: SocketAddr,
+    host: String,
+    path: String,
+    timeout: Duration,
+}
+
+impl DohResolver {
+    /// Creates a resolver sending queries to `https://<host><path>` at `server`.
+    pub fn new(server: SocketAddr, host: &str, path: &str) -> Self {
+        Self {
+            server,
+            host: host.to_string(),
+            path: path.to_string(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Creates a resolver using the cloudflare dns-over-https server.
+    pub fn cloudflare() -> Self {
+        Self::new(
+            ([1, 1, 1, 1], 443).into(),
+            "cloudflare-dns.com",
+            "/dns-query",
+        )
+    }
+
+    /// Creates a resolver using the google dns-over-https server.
+    pub fn google() -> Self {
+        Self::new(([8, 8, 8, 8], 443).into(), "dns.google", "/dns-query")
+    }
+
+    /// Sets the timeout of a single query.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn post(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let stream = TcpStream::connect(self.server).await?;
+        let mut stream = async_native_tls::connect(&self.host, stream)
+            .await
+            .map_err(|err| error(err.to_string()))?;
+        let header = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\n\
+             Accept: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+        let mut response = vec![];
+        stream
+            .take(MAX_RESPONSE_SIZE)
+            .read_to_end(&mut response)
+            .await?;
+        parse_response(&response)
+    }
+
+    async fn query(&self, name: &str, record_type: RecordType) -> Result<Vec<RData>> {
+        let mut msg = Message::new();
+        msg.set_id(0)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name)?, record_type));
+        let body = msg.to_vec()?;
+        let post = self.post(&body);
+        futures::pin_mut!(post);
+        let response = match future::select(post, Timer::after(self.timeout)).await {
+            Either::Left((res, _)) => res?,
+            Either::Right(_) => return Err(error(format!("query for {} timed out", name)).into()),
+        };
+        let response = Message::from_vec(&response)?;
+        match response.response_code() {
+            ResponseCode::NoError => {}
+            ResponseCode::NXDomain => return Ok(vec![]),
+            code => return Err(error(format!("query for {} failed: {}", name, code)).into()),
+        }
+        Ok(response
+            .answers()
+            .iter()
+            .filter(|record| record.record_type() == record_type)
+            .map(|record| record.rdata().clone())
+            .collect())
+    }
+}
+
+/// Returns the body of an http response, failing unless the status is 200.
+fn parse_response(response: &[u8]) -> io::Result<Vec<u8>> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| error("truncated response".into()))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(error(format!("unexpected status {}", status)));
+    }
+    let chunked = lines.any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    if chunked {
+        decode_chunked(body)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let truncated = || error("truncated chunk".into());
+    let mut data = vec![];
+    loop {
+        let end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(truncated)?;
+        let size = String::from_utf8_lossy(&body[..end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| error("invalid chunk".into()))?;
+        body = &body[end + 2..];
+        if size == 0 {
+            return Ok(data);
+        }
+        // the size is sent by the server, so it may be anything up to `usize::MAX`.
+        match size.checked_add(2) {
+            Some(len) if len <= body.len() => {}
+            _ => return Err(truncated()),
+        }
+        data.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[async_trait]
+impl DnsResolver for DohResolver {
+    /// Fails only if both the A and the AAAA query fail, since many networks or servers
+    /// only handle one of them.
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let (v4, v6) = future::join(
+            self.query(name, RecordType::A),
+            self.query(name, RecordType::AAAA),
+        )
+        .await;
+        let rdatas = match (v4, v6) {
+            (Err(err), Err(_)) => return Err(err),
+            (v4, v6) => v4.into_iter().chain(v6).flatten(),
+        };
+        Ok(rdatas
+            .filter_map(|rdata| match rdata {
+                RData::A(ip) => Some(ip.into()),
+                RData::AAAA(ip) => Some(ip.into()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        let mut txts = vec![];
+        for rdata in self.query(name, RecordType::TXT).await? {
+            if let RData::TXT(txt) = rdata {
+                txts.push(
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect(),
+                );
+            }
+        }
+        Ok(txts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(parse_response(response).unwrap(), b"hello");
+        let response = b"HTTP/1.1 404 Not Found\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(parse_response(response).is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n").is_err());
+        assert!(parse_response(b"").is_err());
+    }
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: Chunked\r\n\r\n5\r\nhello\r\n1;ext=1\r\n!\r\n0\r\n\r\n";
+        assert_eq!(parse_response(response).unwrap(), b"hello!");
+    }
+
+    #[test]
+    fn test_decode_chunked() {
+        assert_eq!(decode_chunked(b"0\r\n\r\n").unwrap(), b"");
+        assert_eq!(
+            decode_chunked(b"3\r\nabc\r\na\r\n0123456789\r\n0\r\n\r\n").unwrap(),
+            b"abc0123456789"
+        );
+    }
+
+    #[test]
+    fn test_decode_chunked_rejects_invalid() {
+        // truncated chunks, a missing final chunk and invalid sizes.
+        assert!(decode_chunked(b"5\r\nhel").is_err());
+        assert!(decode_chunked(b"5\r\nhello").is_err());
+        assert!(decode_chunked(b"5\r\nhello\r\n").is_err());
+        assert!(decode_chunked(b"x\r\nhello\r\n0\r\n\r\n").is_err());
+        assert!(decode_chunked(b"").is_err());
+        // sizes that overflow when adding the length of the chunk terminator.
+        assert!(decode_chunked(b"ffffffffffffffff\r\nhello\r\n0\r\n\r\n").is_err());
+        assert!(decode_chunked(b"fffffffffffffffe\r\nhello\r\n0\r\n\r\n").is_err());
+        assert!(decode_chunked(b"fffffffffffffffff\r\nhello\r\n0\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{GetQuery, NetworkService, Priority, SyncEvent, SyncQuery};
+use futures::channel::mpsc;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{Stream, StreamExt};
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Result};
+use libp2p_bitswap::BitswapStore;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Callback invoked for every block received via bitswap, once it passed the
+/// `BlockPolicy` and was inserted in to the store.
+pub trait BlockReceiver: Send + Sync + 'static {
+    /// Called with the cid and the size of the received block. Receipts are delivered in
+    /// order by a background task, not by the swarm, so a slow receiver only delays the
+    /// receipts after it.
+    fn received(&self, cid: &Cid, size: usize);
+}
+
+/// The block exchange of a node. The client side requests blocks from peers and inserts
+/// them in to a `BitswapStore`, the server side answers the wants of peers from the same
+/// store and announces the blocks it provides.
+///
+/// `NetworkService` implements it over bitswap for any `BitswapStore`, so it can be used
+/// with custom storage, and `Ipfs` requests and provides blocks through it. Code written
+/// against this trait instead of `NetworkService` can in turn be used with custom
+/// networking.
+pub trait BlockExchange<P: StoreParams>: Clone + Send + Sync + 'static {
+    /// Future completing once a wanted block was inserted in to the store.
+    type Want: Future<Output = Result<()>> + Send + Unpin;
+    /// Stream of the progress of a sync, ending with `SyncEvent::Complete`.
+    type Sync: Stream<Item = SyncEvent> + Future<Output = Result<()>> + Send + Unpin;
+    /// Future completing once a block was announced to peers.
+    type Provide: Future<Output = Result<()>> + Send + Unpin;
+
+    /// Wants the block `cid`. Wants of the same block are shared, so it is requested only
+    /// once.
+    ///
+    /// Dropping the returned future cancels the want. The request to peers is cancelled
+    /// once no other want or sync is waiting for the block.
+    fn want(&self, cid: Cid, priority: Priority) -> Self::Want;
+
+    /// Wants the `missing` blocks and the blocks they link to which are missing from the
+    /// store. Dropping the returned sync cancels all of its wants.
+    fn sync(&self, missing: Vec<Cid>, priority: Priority) -> Self::Sync;
+
+    /// Returns the number of wants requested from peers and the number of queued
+    /// background wants.
+    fn wants(&self) -> (usize, usize);
+
+    /// Announces that the store provides the block `cid`, so that peers looking for it
+    /// want it from the local node.
+    fn provide(&self, cid: Cid) -> Self::Provide;
+
+    /// Stops announcing the block `cid`. Wants of peers are still answered while the
+    /// block is in the store.
+    fn unprovide(&self, cid: Cid);
+}
+
+impl<P: StoreParams> BlockExchange<P> for NetworkService<P> {
+    type Want = GetQuery<P>;
+    type Sync = SyncQuery<P>;
+    type Provide = BoxFuture<'static, Result<()>>;
+
+    fn want(&self, cid: Cid, priority: Priority) -> Self::Want {
+        self.get_with_priority(cid, priority)
+    }
+
+    fn sync(&self, missing: Vec<Cid>, priority: Priority) -> Self::Sync {
+        self.sync_missing(missing.into_iter(), priority, None)
+    }
+
+    fn wants(&self) -> (usize, usize) {
+        self.want_counts()
+    }
+
+    fn provide(&self, cid: Cid) -> Self::Provide {
+        let network = self.clone();
+        async move { network.provide(cid).await }.boxed()
+    }
+
+    fn unprovide(&self, cid: Cid) {
+        NetworkService::unprovide(self, cid)
+    }
+}
+
+/// Store notifying the `BlockReceiver` of inserted blocks. The store is called by the
+/// swarm, so the receipts are queued and delivered by a task, which ends once the store
+/// is dropped.
+#[derive(Clone)]
+pub(crate) struct ReceiverStore<S> {
+    store: S,
+    receipts: Option<mpsc::UnboundedSender<(Cid, usize)>>,
+}
+
+impl<S> ReceiverStore<S> {
+    pub fn new(store: S, receiver: Option<Arc<dyn BlockReceiver>>) -> Self {
+        let receipts = receiver.map(|receiver| {
+            let (tx, mut rx) = mpsc::unbounded::<(Cid, usize)>();
+            ipfs_embed_rt::spawn(async move {
+                while let Some((cid, size)) = rx.next().await {
+                    receiver.received(&cid, size);
+                }
+            })
+            .detach();
+            tx
+        });
+        Self { store, receipts }
+    }
+}
+
+impl<P: StoreParams, S: BitswapStore<Params = P>> BitswapStore for ReceiverStore<S> {
+    type Params = P;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.store.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.store.get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<P>) -> Result<()> {
+        self.store.insert(block)?;
+        if let Some(receipts) = self.receipts.as_ref() {
+            receipts
+                .unbounded_send((*block.cid(), block.data().len()))
+                .ok();
+        }
+        Ok(())
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.store.missing_blocks(cid)
+    }
+}
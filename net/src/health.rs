@@ -0,0 +1,183 @@
+use crate::activity::Activity;
+use libipld::Result;
+use parking_lot::Mutex;
+use prometheus::{IntGauge, IntGaugeVec, Opts, Registry};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Heartbeats missed before a task counts as stale.
+const MISSED_BEATS: u32 = 2;
+/// Allowance for the work a task does between two heartbeats.
+const STALE_SLACK: Duration = Duration::from_secs(30);
+
+/// Heartbeat of a background task.
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    last: IntGauge,
+    interval: IntGauge,
+}
+
+impl Heartbeat {
+    /// Records that the task is making progress.
+    pub fn beat(&self) {
+        self.last.set(now());
+    }
+
+    /// Sets the interval at which the task beats, for tasks whose interval changes.
+    pub fn set_interval(&self, interval: Duration) {
+        self.interval.set(interval.as_secs() as i64);
+    }
+}
+
+/// Heartbeats of the background tasks and lengths of the internal queues.
+///
+/// A task that stops beating or a queue that keeps growing indicates that the node is
+/// stuck or can't keep up, before it stops responding altogether.
+#[derive(Clone)]
+pub struct Health {
+    heartbeat_gauges: IntGaugeVec,
+    interval_gauges: IntGaugeVec,
+    queue_gauges: IntGaugeVec,
+    heartbeats: Arc<Mutex<BTreeMap<String, Heartbeat>>>,
+    /// Tasks whose interval is stretched by the activity level.
+    paced: Arc<Mutex<BTreeSet<String>>>,
+    queues: Arc<Mutex<BTreeMap<String, IntGauge>>>,
+    activity: Activity,
+}
+
+impl Health {
+    pub(crate) fn new(activity: Activity) -> Result<Self> {
+        let heartbeat_gauges = IntGaugeVec::new(
+            Opts::new(
+                "task_heartbeat_timestamp_seconds",
+                "Unix time of the last heartbeat of a background task.",
+            ),
+            &["task"],
+        )?;
+        let interval_gauges = IntGaugeVec::new(
+            Opts::new(
+                "task_heartbeat_interval_seconds",
+                "Interval at which a background task beats.",
+            ),
+            &["task"],
+        )?;
+        let queue_gauges = IntGaugeVec::new(
+            Opts::new(
+                "queue_length",
+                "Number of items waiting in an internal queue.",
+            ),
+            &["queue"],
+        )?;
+        Ok(Self {
+            heartbeat_gauges,
+            interval_gauges,
+            queue_gauges,
+            heartbeats: Default::default(),
+            paced: Default::default(),
+            queues: Default::default(),
+            activity,
+        })
+    }
+
+    /// Returns the heartbeat of `task`, which beats every `interval`. The task counts as
+    /// alive from now on.
+    pub fn heartbeat(&self, task: &str, interval: Duration) -> Heartbeat {
+        let heartbeat = self
+            .heartbeats
+            .lock()
+            .entry(task.to_string())
+            .or_insert_with(|| Heartbeat {
+                last: self.heartbeat_gauges.with_label_values(&[task]),
+                interval: self.interval_gauges.with_label_values(&[task]),
+            })
+            .clone();
+        heartbeat.set_interval(interval);
+        heartbeat.beat();
+        heartbeat
+    }
+
+    /// Like `heartbeat`, but for a task that sleeps on the `Activity` of the node. Its
+    /// interval is stretched by the activity level, and it isn't reported stale while
+    /// the node is suspended.
+    pub fn paced_heartbeat(&self, task: &str, interval: Duration) -> Heartbeat {
+        self.paced.lock().insert(task.to_string());
+        self.heartbeat(task, interval)
+    }
+
+    /// Returns the gauge tracking the length of `queue`.
+    pub fn queue(&self, queue: &str) -> IntGauge {
+        self.queues
+            .lock()
+            .entry(queue.to_string())
+            .or_insert_with(|| self.queue_gauges.with_label_values(&[queue]))
+            .clone()
+    }
+
+    /// Returns the time since the last heartbeat of each task.
+    pub fn tasks(&self) -> Vec<(String, Duration)> {
+        let now = now();
+        self.heartbeats
+            .lock()
+            .iter()
+            .map(|(task, heartbeat)| {
+                let elapsed = std::cmp::max(now - heartbeat.last.get(), 0) as u64;
+                (task.clone(), Duration::from_secs(elapsed))
+            })
+            .collect()
+    }
+
+    /// Returns the tasks that missed their heartbeats. A task is stale once it didn't
+    /// beat for two of its intervals, so tasks running once a day aren't reported
+    /// between their runs.
+    pub fn stale_tasks(&self) -> Vec<String> {
+        let now = now();
+        let level = self.activity.level();
+        let paced = self.paced.lock();
+        self.heartbeats
+            .lock()
+            .iter()
+            .filter(|(task, heartbeat)| {
+                let elapsed =
+                    Duration::from_secs(std::cmp::max(now - heartbeat.last.get(), 0) as u64);
+                let mut interval =
+                    Duration::from_secs(std::cmp::max(heartbeat.interval.get(), 0) as u64);
+                if paced.contains(*task) {
+                    interval = match level.stretch(interval) {
+                        Some(interval) => interval,
+                        None => return false,
+                    };
+                }
+                let allowed = interval
+                    .checked_mul(MISSED_BEATS)
+                    .and_then(|allowed| allowed.checked_add(STALE_SLACK))
+                    .unwrap_or_else(|| Duration::from_secs(u64::MAX));
+                elapsed > allowed
+            })
+            .map(|(task, _)| task.clone())
+            .collect()
+    }
+
+    /// Returns the current length of each queue.
+    pub fn queues(&self) -> Vec<(String, usize)> {
+        self.queues
+            .lock()
+            .iter()
+            .map(|(queue, gauge)| (queue.clone(), std::cmp::max(gauge.get(), 0) as usize))
+            .collect()
+    }
+
+    pub(crate) fn register_metrics(&self, registry: &Registry) -> Result<()> {
+        registry.register(Box::new(self.heartbeat_gauges.clone()))?;
+        registry.register(Box::new(self.interval_gauges.clone()))?;
+        registry.register(Box::new(self.queue_gauges.clone()))?;
+        Ok(())
+    }
+}
@@ -1,17 +1,40 @@
-use crate::behaviour::{GetChannel, NetworkBackendBehaviour, SyncChannel};
-use futures::stream::Stream;
+use crate::bandwidth::{BandwidthLimiter, ThrottledUpgrade};
+use crate::behaviour::{BehaviourMetrics, GetChannel, NetworkBackendBehaviour, ResolveRequest};
+use crate::capture::{Capture, CaptureMuxer};
+use crate::health::Health;
+use crate::portmap::PortMapper;
+use crate::rendezvous::{
+    RendezvousRejected, RendezvousRequest, RendezvousResponse, SignedPeerRecord,
+    UnexpectedResponse, MAX_DISCOVER_PAGES,
+};
+use crate::resolver::{DnsResolver, ResolverTransport, SystemResolver};
+use crate::sizes::SizeRecorder;
+use crate::socks::Socks5Transport;
+use crate::streams::AppProtocol;
+use crate::votes::{AddressChange, VOTE_EXPIRY_INTERVAL};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{BoxStream, Stream, StreamExt};
 use futures::{future, pin_mut};
+use ipfs_embed_rt::{Instant, Task, Timer};
+use libipld::error::BlockNotFound;
 use libipld::store::StoreParams;
-use libipld::{Cid, Result};
+use libipld::{Block, Cid, Result};
+use libp2p::core::connection::ConnectionLimits;
 use libp2p::core::either::EitherTransport;
+use libp2p::core::transport::dummy::DummyTransport;
 use libp2p::core::transport::Transport;
 use libp2p::core::upgrade::{SelectUpgrade, Version};
 use libp2p::dns::DnsConfig;
 use libp2p::mplex::MplexConfig;
-use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
+use libp2p::multiaddr::Protocol;
+use libp2p::noise::{self, NoiseConfig, X25519Spec};
 use libp2p::pnet::PnetConfig;
 use libp2p::swarm::{AddressScore, Swarm, SwarmBuilder, SwarmEvent};
+#[cfg(not(feature = "tokio"))]
 use libp2p::tcp::TcpConfig;
+#[cfg(feature = "tokio")]
+use libp2p::tcp::TokioTcpConfig as TcpConfig;
 use libp2p::yamux::YamuxConfig;
 use parking_lot::Mutex;
 use prometheus::Registry;
@@ -20,77 +43,298 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
+use thiserror::Error;
 
+#[cfg(not(any(feature = "async-global", feature = "tokio")))]
+compile_error!("one of the features `async-global` or `tokio` needs to be enabled");
+
+mod activity;
+mod audit;
+mod auth;
+mod autonat;
+mod bandwidth;
+mod beacon;
 mod behaviour;
+mod capture;
 mod config;
+#[cfg(feature = "dht")]
+mod dht;
+mod dialback;
+#[cfg(any(not(feature = "mdns"), not(feature = "dht"), not(feature = "gossip")))]
+mod disabled;
+#[cfg(feature = "dns-over-https")]
+mod doh;
+mod exchange;
+mod health;
+mod limits;
+#[cfg(any(feature = "dht", feature = "gossip"))]
+mod pace;
 mod peers;
+mod policy;
+mod portmap;
+mod push;
+mod rendezvous;
+mod resolver;
+mod retry;
+mod serve;
+mod sizes;
+mod socks;
+mod streams;
+mod translate;
+mod votes;
+mod wants;
 
-pub use crate::behaviour::{QueryId, SyncEvent};
-pub use crate::config::NetworkConfig;
-pub use crate::peers::{AddressSource, PeerInfo};
+pub use crate::activity::{Activity, ActivityLevel};
+pub use crate::audit::{AuditConfig, AuditKind};
+pub use crate::auth::{AuthTimeout, Authenticator, PeerIdentity};
+pub use crate::autonat::NatStatus;
+pub use crate::bandwidth::BandwidthLimits;
+pub use crate::beacon::BeaconConfig;
+pub use crate::behaviour::{
+    peer_topic, DhtDisabled, GossipDisabled, GossipMessage, Pushed, QueryId, SyncEvent,
+    SyncStalled, SyncStats,
+};
+pub use crate::capture::{CaptureConfig, CaptureReader, CapturedFrame, InvalidCapture};
+pub use crate::config::{DhtMode, NetworkConfig};
+#[cfg(not(feature = "dht"))]
+pub use crate::disabled::{Key, PeerRecord, Quorum, Record};
+#[cfg(feature = "dns-over-https")]
+pub use crate::doh::DohResolver;
+pub use crate::exchange::{BlockExchange, BlockReceiver};
+pub use crate::health::{Health, Heartbeat};
+pub use crate::limits::{LimitExceeded, TraversalLimits, TraversalOrder, UnsupportedOrder};
+pub use crate::peers::{AddressSource, Event, PeerInfo, PeerStats};
+pub use crate::policy::{BlockPolicy, BlockRejected};
+pub use crate::portmap::PortMapConfig;
+pub use crate::push::{PushFailure, PushRejected, TooManyBlocks};
+pub use crate::rendezvous::{
+    RendezvousFailure, RendezvousRejected, RendezvousStatus, UnexpectedResponse,
+};
+#[cfg(feature = "trust-dns")]
+pub use crate::resolver::TrustDnsResolver;
+pub use crate::resolver::{CachingResolver, DnsResolver, SystemResolver};
+pub use crate::retry::{Backoff, ErrorClass, RetryPolicy};
+pub use crate::serve::bitswap_sender;
+pub use crate::socks::Socks5Config;
+pub use crate::streams::{AppStream, InvalidProtocolName, StreamFailure};
+pub use crate::translate::AddressTranslation;
+pub use crate::votes::ObservedAddress;
+pub use crate::wants::Priority;
+#[cfg(feature = "trust-dns")]
+pub use async_std_resolver::config::{ResolverConfig, ResolverOpts};
+pub use libp2p::core::connection::ListenerId;
+pub use libp2p::core::muxing::StreamMuxerBox;
+pub use libp2p::core::transport::Boxed;
+#[cfg(feature = "gossip")]
 pub use libp2p::gossipsub::{GossipsubEvent, GossipsubMessage, MessageId, Topic, TopicHash};
+pub use libp2p::identity::{Keypair, PublicKey};
+#[cfg(feature = "dht")]
 pub use libp2p::kad::record::{Key, Record};
+#[cfg(feature = "dht")]
 pub use libp2p::kad::{PeerRecord, Quorum};
 pub use libp2p::swarm::AddressRecord;
 pub use libp2p::{Multiaddr, PeerId};
 pub use libp2p_bitswap::BitswapStore;
 
+#[derive(Debug, Error)]
+#[error("the swarm stopped")]
+pub struct SwarmStopped;
+
+/// Error returned when rotating the key of a `NetworkService` created with
+/// `with_transport`, whose transport authenticates with its own key.
+#[derive(Debug, Error)]
+#[error("the key of a custom transport can't be rotated")]
+pub struct RotationUnsupported;
+
+/// Rebuilds the swarm with a new key, replaces the running swarm with it and returns the
+/// replaced swarm.
+type Rotate<P> = Arc<
+    dyn Fn(Keypair) -> BoxFuture<'static, Result<Swarm<NetworkBackendBehaviour<P>>>> + Send + Sync,
+>;
+
 #[derive(Clone)]
 pub struct NetworkService<P: StoreParams> {
     swarm: Arc<Mutex<Swarm<NetworkBackendBehaviour<P>>>>,
+    limiter: BandwidthLimiter,
+    retry: RetryPolicy,
+    health: Health,
+    activity: Activity,
+    resolver: Arc<dyn DnsResolver>,
+    node_key: Arc<Mutex<Keypair>>,
+    port_mapper: Option<PortMapper>,
+    rotate: Option<Rotate<P>>,
 }
 
 impl<P: StoreParams> NetworkService<P> {
-    pub async fn new<S: BitswapStore<Params = P>>(config: NetworkConfig, store: S) -> Result<Self> {
-        let transport = DnsConfig::new(TcpConfig::new().nodelay(true))?;
-        let transport = if let Some(psk) = config.psk {
-            EitherTransport::Left(
-                transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
-            )
-        } else {
-            EitherTransport::Right(transport)
+    pub async fn new<S: BitswapStore<Params = P> + Clone>(
+        config: NetworkConfig,
+        store: S,
+    ) -> Result<Self> {
+        let limiter = BandwidthLimiter::new(config.bandwidth_limits);
+        let transport = new_transport(&config, limiter.clone())?;
+        Self::build(config, transport, limiter, store, true).await
+    }
+
+    /// Creates a new `NetworkService` running the swarm over `transport`. The transport
+    /// needs to authenticate and multiplex connections itself, and the `psk` and
+    /// `bandwidth_limits` of the config are not applied to it.
+    pub async fn with_transport<S: BitswapStore<Params = P> + Clone>(
+        config: NetworkConfig,
+        transport: Boxed<(PeerId, StreamMuxerBox)>,
+        store: S,
+    ) -> Result<Self> {
+        let limiter = BandwidthLimiter::new(config.bandwidth_limits);
+        Self::build(config, transport, limiter, store, false).await
+    }
+
+    /// Creates a new `NetworkService` whose swarm can't listen or dial, for nodes that only
+    /// use their block store. Unless `new` is used too, the tcp, dns and noise transports
+    /// aren't linked.
+    pub async fn offline<S: BitswapStore<Params = P> + Clone>(
+        config: NetworkConfig,
+        store: S,
+    ) -> Result<Self> {
+        let limiter = BandwidthLimiter::new(config.bandwidth_limits);
+        let transport = DummyTransport::new().boxed();
+        Self::build(config, transport, limiter, store, false).await
+    }
+
+    async fn build<S: BitswapStore<Params = P> + Clone>(
+        config: NetworkConfig,
+        transport: Boxed<(PeerId, StreamMuxerBox)>,
+        limiter: BandwidthLimiter,
+        store: S,
+        rotatable: bool,
+    ) -> Result<Self> {
+        let activity = Activity::default();
+        let health = Health::new(activity.clone())?;
+        let metrics = BehaviourMetrics::new()?;
+        let capture = match config.capture.as_ref() {
+            Some(capture) => Some(Capture::create(capture)?),
+            None => None,
         };
-        let dh_key = Keypair::<X25519Spec>::new()
-            .into_authentic(&config.node_key)
-            .unwrap();
-        let transport = transport
-            .upgrade(Version::V1)
-            .authenticate(NoiseConfig::xx(dh_key).into_authenticated())
-            .multiplex(SelectUpgrade::new(
-                YamuxConfig::default(),
-                MplexConfig::new(),
-            ))
-            .timeout(Duration::from_secs(5))
-            .boxed();
-
-        let peer_id = config.peer_id();
-        let behaviour = NetworkBackendBehaviour::<P>::new(config.clone(), store).await?;
-        let swarm = SwarmBuilder::new(transport.boxed(), behaviour, peer_id)
-            .executor(Box::new(|fut| {
-                async_global_executor::spawn(fut).detach();
-            }))
-            .build();
+        let store = SizeRecorder::new(store);
+        let swarm = new_swarm::<P, _>(
+            config.clone(),
+            transport,
+            capture.clone(),
+            store.clone(),
+            &health,
+            &activity,
+            &metrics,
+        )
+        .await?;
 
         let swarm = Arc::new(Mutex::new(swarm));
         let swarm2 = swarm.clone();
-        async_global_executor::spawn::<_, ()>(async move {
+        spawn_swarm_tasks(&swarm, &mut swarm.lock(), &config, store.clone());
+        let swarm8 = swarm.clone();
+        ipfs_embed_rt::spawn(async move {
+            loop {
+                Timer::after(VOTE_EXPIRY_INTERVAL).await;
+                swarm8.lock().expire_address_votes();
+            }
+        })
+        .detach();
+        // the swarm beats on every poll.
+        let heartbeat = health.heartbeat("swarm", Duration::from_secs(1));
+        let wants_requested = health.queue("bitswap_wants_requested");
+        let wants_queued = health.queue("bitswap_wants_queued");
+        let task: Task<()> = ipfs_embed_rt::spawn(async move {
             loop {
                 future::poll_fn(|cx| {
                     tracing::trace!("poll swarm");
+                    heartbeat.beat();
                     let mut guard = swarm.lock();
                     while {
                         let swarm = &mut *guard;
                         pin_mut!(swarm);
                         swarm.poll_next(cx).is_ready()
                     } {}
+                    let (requested, queued) = guard.want_counts();
+                    wants_requested.set(requested as i64);
+                    wants_queued.set(queued as i64);
                     Poll::Ready(())
                 })
                 .await
             }
-        })
-        .detach();
+        });
+        task.detach();
 
-        Ok(Self { swarm: swarm2 })
+        let rotate = if rotatable {
+            let shared = swarm2.clone();
+            let config = config.clone();
+            let (limiter, health, activity) = (limiter.clone(), health.clone(), activity.clone());
+            let rotate: Rotate<P> = Arc::new(move |key: Keypair| {
+                let mut config = config.clone();
+                config.node_key = key;
+                let (shared, limiter, capture) = (shared.clone(), limiter.clone(), capture.clone());
+                let (store, health, activity) = (store.clone(), health.clone(), activity.clone());
+                let metrics = metrics.clone();
+                async move {
+                    let transport = new_transport(&config, limiter)?;
+                    let mut swarm = new_swarm::<P, _>(
+                        config.clone(),
+                        transport,
+                        capture,
+                        store.clone(),
+                        &health,
+                        &activity,
+                        &metrics,
+                    )
+                    .await?;
+                    spawn_swarm_tasks(&shared, &mut swarm, &config, store);
+                    let mut guard = shared.lock();
+                    swarm.take_over(&mut guard);
+                    std::mem::swap(&mut *guard, &mut swarm);
+                    Ok(swarm)
+                }
+                .boxed()
+            });
+            Some(rotate)
+        } else {
+            None
+        };
+
+        let service = Self {
+            swarm: swarm2,
+            limiter,
+            retry: config.retry_policy.clone(),
+            health,
+            activity,
+            resolver: config
+                .dns_resolver
+                .clone()
+                .unwrap_or_else(|| Arc::new(SystemResolver::default())),
+            node_key: Arc::new(Mutex::new(config.node_key.clone())),
+            port_mapper: config.port_mapping.clone().map(PortMapper::new),
+            rotate,
+        };
+        if let Some(beacon) = config.beacon.clone() {
+            ipfs_embed_rt::spawn(beacon::run(service.clone(), beacon)).detach();
+        }
+        if !config.address_translations.is_empty() {
+            let translations = config.address_translations.clone();
+            ipfs_embed_rt::spawn(translate::run(service.clone(), translations)).detach();
+        }
+        if let Some(mapper) = service.port_mapper.clone() {
+            ipfs_embed_rt::spawn(portmap::run(service.clone(), mapper)).detach();
+        }
+        if let Some(namespace) = config.rendezvous_namespace.clone() {
+            if !config.rendezvous_points.is_empty() {
+                let rendezvous = rendezvous(service.clone(), namespace, config);
+                ipfs_embed_rt::spawn(rendezvous).detach();
+            }
+        }
+        Ok(service)
+    }
+
+    pub fn set_bandwidth_limits(&self, limits: BandwidthLimits) {
+        self.limiter.set_limits(limits);
+    }
+
+    pub fn set_peer_bandwidth_limits(&self, peer: PeerId, limits: Option<BandwidthLimits>) {
+        self.limiter.set_peer_limits(peer, limits);
     }
 
     pub fn local_peer_id(&self) -> PeerId {
@@ -98,10 +342,72 @@ impl<P: StoreParams> NetworkService<P> {
         *Swarm::local_peer_id(&swarm)
     }
 
+    /// Returns the key the node authenticates with.
+    pub fn node_key(&self) -> Keypair {
+        self.node_key.lock().clone()
+    }
+
+    /// Replaces the node key while the node is running.
+    ///
+    /// The swarm is rebuilt with `key`, which closes all connections and updates the
+    /// identify info. The listeners are bound again on the same ports, the external
+    /// addresses and the known addresses of peers are kept, the peers the node was
+    /// connected to are dialed under the new `PeerId` and the dht is bootstrapped again
+    /// if it was bootstrapped. Gossipsub subscriptions, swarm event, direct message and
+    /// push subscribers, the authenticator and the bans carry over, while queries and
+    /// syncs in flight fail. Fails with `RotationUnsupported` if the service was created
+    /// `with_transport`.
+    pub async fn rotate_key(&self, key: Keypair) -> Result<()> {
+        let rotate = self.rotate.clone().ok_or(RotationUnsupported)?;
+        let old = rotate(key.clone()).await?;
+        *self.node_key.lock() = key;
+        let listeners = old.listener_addrs();
+        let bound = Swarm::listeners(&old).cloned().collect::<Vec<_>>();
+        let external = Swarm::external_addresses(&old).cloned().collect::<Vec<_>>();
+        let connected = old
+            .connections()
+            .map(|(peer, _)| *peer)
+            .collect::<FnvHashSet<_>>();
+        let bootstrapped = old.is_bootstrapped();
+        // closes the listeners of the old swarm before their ports are bound again.
+        drop(old);
+        {
+            let mut swarm = self.swarm.lock();
+            for peer in swarm.banned_peers() {
+                Swarm::ban_peer_id(&mut swarm, peer);
+            }
+            for record in external {
+                Swarm::add_external_address(&mut swarm, record.addr, record.score);
+            }
+        }
+        for addr in listeners {
+            let bound = bound_port(&addr, &bound);
+            if let Err(err) = self.add_listener(bound.clone()) {
+                tracing::warn!("failed to listen on {} again: {}", bound, err);
+                self.add_listener(addr)?;
+            }
+        }
+        for peer in connected {
+            if let Err(err) = self.dial(&peer) {
+                tracing::debug!("failed to dial {}: {}", peer, err);
+            }
+        }
+        if bootstrapped {
+            if let Err(err) = self.bootstrap(&[]).await {
+                tracing::warn!(
+                    "failed to bootstrap the dht after rotating the key: {}",
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
+
     #[allow(clippy::await_holding_lock)]
     pub async fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr> {
         let mut swarm = self.swarm.lock();
-        Swarm::listen_on(&mut swarm, addr)?;
+        let id = Swarm::listen_on(&mut swarm, addr.clone())?;
+        swarm.add_listener(id, addr);
         loop {
             match swarm.next_event().await {
                 SwarmEvent::NewListenAddr(addr) => {
@@ -116,6 +422,60 @@ impl<P: StoreParams> NetworkService<P> {
         }
     }
 
+    /// Waits for a listener to start listening on a new address. Addresses bound before
+    /// this is called are not returned.
+    pub fn next_listen_addr(&self) -> impl Future<Output = Result<Multiaddr>> {
+        let mut events = self.swarm_events();
+        async move {
+            while let Some(event) = events.next().await {
+                if let Event::NewListenAddr(addr) = event {
+                    return Ok(addr);
+                }
+            }
+            Err(SwarmStopped.into())
+        }
+    }
+
+    /// Waits until the swarm listens on at least `n` addresses and returns them.
+    pub fn wait_for_listeners(&self, n: usize) -> impl Future<Output = Result<Vec<Multiaddr>>> {
+        // subscribe before checking, so that no address is missed.
+        let mut events = self.swarm_events();
+        let service = self.clone();
+        async move {
+            loop {
+                let listeners = service.listeners();
+                if listeners.len() >= n {
+                    return Ok(listeners);
+                }
+                loop {
+                    match events.next().await {
+                        Some(Event::NewListenAddr(_)) => break,
+                        Some(_) => continue,
+                        None => return Err(SwarmStopped.into()),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn add_listener(&self, addr: Multiaddr) -> Result<ListenerId> {
+        let mut swarm = self.swarm.lock();
+        let id = Swarm::listen_on(&mut swarm, addr.clone())?;
+        swarm.add_listener(id, addr);
+        Ok(id)
+    }
+
+    pub fn remove_listener(&self, id: ListenerId) -> bool {
+        let mut swarm = self.swarm.lock();
+        swarm.remove_listener(&id);
+        Swarm::remove_listener(&mut swarm, id).is_ok()
+    }
+
+    pub fn swarm_events(&self) -> impl Stream<Item = Event> {
+        let mut swarm = self.swarm.lock();
+        swarm.swarm_events()
+    }
+
     pub fn listeners(&self) -> Vec<Multiaddr> {
         let swarm = self.swarm.lock();
         Swarm::listeners(&swarm).cloned().collect()
@@ -126,14 +486,73 @@ impl<P: StoreParams> NetworkService<P> {
         Swarm::add_external_address(&mut swarm, addr, AddressScore::Infinite);
     }
 
+    /// Announces the external address a listen address was mapped to on the gateway.
+    fn add_port_mapping(&self, listen: Multiaddr, external: Multiaddr) {
+        let mut swarm = self.swarm.lock();
+        Swarm::add_external_address(&mut swarm, external.clone(), AddressScore::Infinite);
+        swarm.notify(Event::PortMapped(listen, external));
+    }
+
+    fn remove_port_mapping(&self, external: &Multiaddr) {
+        let mut swarm = self.swarm.lock();
+        Swarm::remove_external_address(&mut swarm, external);
+    }
+
+    /// Removes the port mappings from the gateway and stops renewing them. Call this
+    /// before stopping the node, so that the gateway doesn't forward the ports until the
+    /// mappings expire.
+    pub async fn remove_port_mappings(&self) {
+        if let Some(mapper) = self.port_mapper.as_ref() {
+            mapper.close(self).await;
+        }
+    }
+
+    /// Announces the external address of a listen address given by an address
+    /// translation.
+    fn add_translated_address(&self, listen: Multiaddr, external: Multiaddr) {
+        tracing::debug!("translated {} to {}", listen, external);
+        let mut swarm = self.swarm.lock();
+        Swarm::add_external_address(&mut swarm, external, AddressScore::Infinite);
+    }
+
+    fn remove_translated_address(&self, external: &Multiaddr) {
+        let mut swarm = self.swarm.lock();
+        Swarm::remove_external_address(&mut swarm, external);
+    }
+
+    fn notify(&self, event: Event) {
+        let mut swarm = self.swarm.lock();
+        swarm.notify(event);
+    }
+
     pub fn external_addresses(&self) -> Vec<AddressRecord> {
         let swarm = self.swarm.lock();
         Swarm::external_addresses(&swarm).cloned().collect()
     }
 
+    /// Returns the addresses peers observed the node at.
+    pub fn observed_addresses(&self) -> Vec<ObservedAddress> {
+        let mut swarm = self.swarm.lock();
+        swarm.observed_addresses()
+    }
+
+    pub fn dht_mode(&self) -> Option<DhtMode> {
+        let swarm = self.swarm.lock();
+        swarm.dht_mode()
+    }
+
+    pub fn nat_status(&self) -> Option<NatStatus> {
+        let swarm = self.swarm.lock();
+        swarm.nat_status()
+    }
+
     pub fn add_address(&self, peer: &PeerId, addr: Multiaddr) {
+        self.add_discovered_address(peer, addr, AddressSource::User);
+    }
+
+    fn add_discovered_address(&self, peer: &PeerId, addr: Multiaddr, source: AddressSource) {
         let mut swarm = self.swarm.lock();
-        swarm.add_address(peer, addr, AddressSource::User);
+        swarm.add_address(peer, addr, source);
     }
 
     pub fn remove_address(&self, peer: &PeerId, addr: &Multiaddr) {
@@ -146,16 +565,30 @@ impl<P: StoreParams> NetworkService<P> {
         Ok(Swarm::dial(&mut swarm, peer)?)
     }
 
+    /// Returns `true` if the authenticator accepted `peer` or no authenticator is set.
+    pub fn is_authenticated(&self, peer: &PeerId) -> bool {
+        self.swarm.lock().is_authenticated(peer)
+    }
+
     pub fn ban(&self, peer: PeerId) {
         let mut swarm = self.swarm.lock();
+        swarm.set_banned(peer, true);
         Swarm::ban_peer_id(&mut swarm, peer)
     }
 
     pub fn unban(&self, peer: PeerId) {
         let mut swarm = self.swarm.lock();
+        swarm.set_banned(peer, false);
         Swarm::unban_peer_id(&mut swarm, peer)
     }
 
+    /// Installs an authenticator that is asked to accept every peer once it identified
+    /// itself. Replaces the previously installed authenticator, `None` accepts all peers.
+    pub fn set_authenticator(&self, authenticator: Option<Arc<dyn Authenticator>>) {
+        let mut swarm = self.swarm.lock();
+        swarm.set_authenticator(authenticator)
+    }
+
     pub fn peers(&self) -> Vec<PeerId> {
         let swarm = self.swarm.lock();
         swarm.peers().copied().collect()
@@ -174,36 +607,206 @@ impl<P: StoreParams> NetworkService<P> {
         swarm.info(peer).cloned()
     }
 
+    pub fn peer_rtt(&self, peer: &PeerId) -> Option<Duration> {
+        let swarm = self.swarm.lock();
+        swarm.rtt(peer)
+    }
+
+    /// Returns the statistics of all peers seen since the node started or restored with
+    /// `restore_peer_stats`. Traffic is only counted when the bandwidth limits apply to
+    /// the transport.
+    pub fn peer_stats(&self) -> Vec<(PeerId, PeerStats)> {
+        let mut stats = {
+            let swarm = self.swarm.lock();
+            swarm
+                .peer_stats()
+                .map(|(peer, stats)| (*peer, *stats))
+                .collect::<FnvHashMap<_, _>>()
+        };
+        for (peer, sent, received) in self.limiter.traffic() {
+            let stats = stats.entry(peer).or_default();
+            stats.bytes_sent = sent;
+            stats.bytes_received = received;
+        }
+        stats.into_iter().collect()
+    }
+
+    /// Seeds the statistics of `peer`, so that rtt estimates and success rates start
+    /// from the values learned before a restart.
+    pub fn restore_peer_stats(&self, peer: &PeerId, stats: PeerStats) {
+        self.limiter
+            .set_traffic(*peer, stats.bytes_sent, stats.bytes_received);
+        let mut swarm = self.swarm.lock();
+        swarm.restore_peer_stats(peer, stats);
+    }
+
     pub async fn bootstrap(&self, peers: &[(PeerId, Multiaddr)]) -> Result<()> {
         for (peer, addr) in peers {
             self.add_address(peer, addr.clone());
             self.dial(peer)?;
         }
+        self.retry
+            .run(|| {
+                let rx = {
+                    let mut swarm = self.swarm.lock();
+                    swarm.bootstrap()
+                };
+                tracing::trace!("started bootstrap");
+                async move { Ok(rx.await??) }
+            })
+            .await?;
+        tracing::trace!("boostrap complete");
+        Ok(())
+    }
+
+    /// Looks up a record in the dht, retrying according to the `RetryPolicy`.
+    pub async fn get_record(&self, key: &Key, quorum: Quorum) -> Result<Vec<PeerRecord>> {
+        self.retry
+            .run(|| {
+                let rx = {
+                    let mut swarm = self.swarm.lock();
+                    swarm.get_record(key, quorum)
+                };
+                async move { Ok(rx.await??) }
+            })
+            .await
+    }
+
+    /// Stores a record in the dht, retrying according to the `RetryPolicy`.
+    pub async fn put_record(&self, record: Record, quorum: Quorum) -> Result<()> {
+        self.retry
+            .run(|| {
+                let rx = {
+                    let mut swarm = self.swarm.lock();
+                    swarm.put_record(record.clone(), quorum)
+                };
+                async move { Ok(rx.await??) }
+            })
+            .await
+    }
+
+    pub async fn push(&self, peer: &PeerId, blocks: Vec<Block<P>>) -> Result<()> {
         let rx = {
             let mut swarm = self.swarm.lock();
-            swarm.bootstrap()
+            swarm.push(peer, blocks)
         };
-        tracing::trace!("started bootstrap");
         rx.await??;
-        tracing::trace!("boostrap complete");
         Ok(())
     }
 
-    pub async fn get_record(&self, key: &Key, quorum: Quorum) -> Result<Vec<PeerRecord>> {
+    pub fn pushed(&self) -> impl Stream<Item = Pushed<P>> {
+        let mut swarm = self.swarm.lock();
+        swarm.pushed()
+    }
+
+    async fn rendezvous(
+        &self,
+        point: &PeerId,
+        request: RendezvousRequest,
+    ) -> Result<RendezvousResponse> {
         let rx = {
             let mut swarm = self.swarm.lock();
-            swarm.get_record(key, quorum)
+            swarm.rendezvous(point, request)
         };
-        Ok(rx.await??)
+        match rx.await?? {
+            RendezvousResponse::Rejected(_, reason)
+            | RendezvousResponse::DiscoverRejected(_, reason) => {
+                Err(RendezvousRejected(reason).into())
+            }
+            response => Ok(response),
+        }
     }
 
-    pub async fn put_record(&self, record: Record, quorum: Quorum) -> Result<()> {
+    /// Registers the listen and external addresses of the node under `namespace` at the
+    /// rendezvous `point`. Returns the ttl granted by the rendezvous point.
+    pub async fn rendezvous_register(
+        &self,
+        point: &PeerId,
+        namespace: &str,
+        ttl: Duration,
+    ) -> Result<Duration> {
+        let mut addresses = self.listeners();
+        for record in self.external_addresses() {
+            if !addresses.contains(&record.addr) {
+                addresses.push(record.addr);
+            }
+        }
+        let request = RendezvousRequest::Register {
+            namespace: namespace.into(),
+            record: SignedPeerRecord::new(&self.node_key(), addresses)?,
+            ttl: Some(ttl),
+        };
+        match self.rendezvous(point, request).await? {
+            RendezvousResponse::Registered { ttl } => Ok(ttl),
+            _ => Err(UnexpectedResponse.into()),
+        }
+    }
+
+    /// Removes the registration under `namespace` at the rendezvous `point`.
+    pub async fn rendezvous_unregister(&self, point: &PeerId, namespace: &str) -> Result<()> {
+        let request = RendezvousRequest::Unregister {
+            namespace: namespace.into(),
+        };
+        match self.rendezvous(point, request).await? {
+            RendezvousResponse::Unregistered => Ok(()),
+            _ => Err(UnexpectedResponse.into()),
+        }
+    }
+
+    /// Discovers the peers registered under `namespace` at the rendezvous `point`. The
+    /// addresses of the discovered peers are added to the address book.
+    ///
+    /// The registrations are requested in pages, until the rendezvous point returns no
+    /// more registrations.
+    pub async fn rendezvous_discover(
+        &self,
+        point: &PeerId,
+        namespace: &str,
+    ) -> Result<Vec<(PeerId, Vec<Multiaddr>)>> {
+        let mut peers = FnvHashMap::<PeerId, Vec<Multiaddr>>::default();
+        let mut cookie = None;
+        for _ in 0..MAX_DISCOVER_PAGES {
+            let request = RendezvousRequest::Discover {
+                namespace: namespace.into(),
+                limit: None,
+                cookie: cookie.take(),
+            };
+            match self.rendezvous(point, request).await? {
+                RendezvousResponse::Discovered {
+                    registrations,
+                    cookie: next,
+                } => {
+                    if registrations.is_empty() {
+                        break;
+                    }
+                    for registration in registrations {
+                        let peer = *registration.record.peer_id();
+                        peers.insert(peer, registration.record.addresses().to_vec());
+                    }
+                    cookie = Some(next);
+                }
+                _ => return Err(UnexpectedResponse.into()),
+            }
+        }
+        Ok(peers.into_iter().collect())
+    }
+
+    pub async fn open_stream(&self, peer: &PeerId, protocol: &str) -> Result<AppStream> {
+        let protocol = AppProtocol::new(protocol)?;
         let rx = {
             let mut swarm = self.swarm.lock();
-            swarm.put_record(record, quorum)
+            swarm.open_stream(*peer, protocol)
         };
-        rx.await??;
-        Ok(())
+        rx.await?
+    }
+
+    pub fn listen_streams(
+        &self,
+        protocol: &str,
+    ) -> Result<impl Stream<Item = (PeerId, AppStream)>> {
+        let protocol = AppProtocol::new(protocol)?;
+        let mut swarm = self.swarm.lock();
+        Ok(swarm.listen_streams(protocol))
     }
 
     pub fn subscribe(&self, topic: &str) -> Result<impl Stream<Item = Vec<u8>>> {
@@ -211,19 +814,47 @@ impl<P: StoreParams> NetworkService<P> {
         swarm.subscribe(topic)
     }
 
+    pub fn subscribe_with_ids(
+        &self,
+        topic: &str,
+    ) -> Result<impl Stream<Item = (Vec<u8>, Vec<u8>)>> {
+        let mut swarm = self.swarm.lock();
+        swarm.subscribe_with_ids(topic)
+    }
+
+    /// Like `subscribe`, but returns the id and the source of every message.
+    pub fn subscribe_messages(&self, topic: &str) -> Result<impl Stream<Item = GossipMessage>> {
+        let mut swarm = self.swarm.lock();
+        swarm.subscribe_messages(topic)
+    }
+
     pub fn publish(&self, topic: &str, msg: Vec<u8>) -> Result<()> {
         let mut swarm = self.swarm.lock();
         swarm.publish(topic, msg)
     }
 
+    pub fn direct_messages(&self) -> impl Stream<Item = (PeerId, Vec<u8>)> {
+        let mut swarm = self.swarm.lock();
+        swarm.direct_messages()
+    }
+
+    pub fn send_direct(&self, peer: &PeerId, msg: Vec<u8>) -> Result<()> {
+        let mut swarm = self.swarm.lock();
+        swarm.send_direct(peer, msg)
+    }
+
     pub fn remove_record(&self, key: &Key) {
         let mut swarm = self.swarm.lock();
         swarm.remove_record(key)
     }
 
     pub fn get(&self, cid: Cid) -> GetQuery<P> {
+        self.get_with_priority(cid, Priority::Interactive)
+    }
+
+    pub fn get_with_priority(&self, cid: Cid, priority: Priority) -> GetQuery<P> {
         let mut swarm = self.swarm.lock();
-        let (rx, id) = swarm.get(cid);
+        let (rx, id) = swarm.get(cid, priority);
         GetQuery {
             swarm: Some(self.swarm.clone()),
             id,
@@ -231,23 +862,157 @@ impl<P: StoreParams> NetworkService<P> {
         }
     }
 
-    pub fn sync(&self, cid: Cid, missing: impl Iterator<Item = Cid>) -> SyncQuery<P> {
+    /// Requests a block from the connected peers only, without looking up providers.
+    pub fn get_connected(&self, cid: Cid) -> GetQuery<P> {
         let mut swarm = self.swarm.lock();
-        let (rx, id) = swarm.sync(cid, missing);
-        SyncQuery {
+        let (rx, id) = swarm.get_connected(cid);
+        GetQuery {
             swarm: Some(self.swarm.clone()),
             id,
             rx,
         }
     }
 
-    pub async fn provide(&self, cid: Cid) -> Result<()> {
-        let rx = {
+    /// Fetches a block, retrying according to the `RetryPolicy`.
+    pub async fn fetch(&self, cid: Cid, priority: Priority) -> Result<()> {
+        self.retry
+            .run(|| self.get_with_priority(cid, priority))
+            .await
+    }
+
+    pub fn sync(&self, cid: Cid, missing: impl Iterator<Item = Cid>) -> SyncQuery<P> {
+        self.sync_with_priority(cid, missing, Priority::Interactive)
+    }
+
+    pub fn sync_with_priority(
+        &self,
+        cid: Cid,
+        missing: impl Iterator<Item = Cid>,
+        priority: Priority,
+    ) -> SyncQuery<P> {
+        self.sync_with_limits(cid, missing, priority, None)
+    }
+
+    /// Syncs a dag with `limits`, using the configured limits if `None`.
+    pub fn sync_with_limits(
+        &self,
+        cid: Cid,
+        missing: impl Iterator<Item = Cid>,
+        priority: Priority,
+        limits: Option<TraversalLimits>,
+    ) -> SyncQuery<P> {
+        tracing::trace!("syncing {}", cid);
+        self.sync_missing(missing, priority, limits)
+    }
+
+    /// Fetches the `missing` blocks and the blocks they link to in a single query. Used
+    /// to sync several dags at once, requesting the blocks they share only once.
+    pub fn sync_missing(
+        &self,
+        missing: impl Iterator<Item = Cid>,
+        priority: Priority,
+        limits: Option<TraversalLimits>,
+    ) -> SyncQuery<P> {
+        self.sync_missing_with_order(missing, priority, limits, TraversalOrder::BreadthFirst)
+    }
+
+    /// Like `sync_missing`, but requests queued blocks in traversal `order`.
+    pub fn sync_missing_with_order(
+        &self,
+        missing: impl Iterator<Item = Cid>,
+        priority: Priority,
+        limits: Option<TraversalLimits>,
+        order: TraversalOrder,
+    ) -> SyncQuery<P> {
+        let mut swarm = self.swarm.lock();
+        let (rx, id) = swarm.sync(missing, priority, limits, order);
+        let traffic = self
+            .limiter
+            .traffic()
+            .into_iter()
+            .map(|(peer, _, received)| (peer, received))
+            .collect();
+        SyncQuery {
+            swarm: Some(self.swarm.clone()),
+            id: Some(id),
+            rx: rx.boxed(),
+            guards: vec![],
+            limiter: self.limiter.clone(),
+            start: Instant::now(),
+            traffic,
+            report: false,
+        }
+    }
+
+    /// Returns a sync query that fails with `err`, for syncs that can't be started.
+    pub fn sync_failed(&self, err: anyhow::Error) -> SyncQuery<P> {
+        self.sync_with(futures::stream::once(async move {
+            SyncEvent::Complete(Err(err))
+        }))
+    }
+
+    /// Wraps the events of a sync run by another `BlockExchange` in a sync query, so
+    /// that it can hold guards like the syncs of the network. Dropping the query drops
+    /// `events`. The bandwidth stats only count the traffic of the swarm.
+    pub fn sync_with(
+        &self,
+        events: impl Stream<Item = SyncEvent> + Send + 'static,
+    ) -> SyncQuery<P> {
+        let traffic = self
+            .limiter
+            .traffic()
+            .into_iter()
+            .map(|(peer, _, received)| (peer, received))
+            .collect();
+        SyncQuery {
+            swarm: None,
+            id: None,
+            rx: events.boxed(),
+            guards: vec![],
+            limiter: self.limiter.clone(),
+            start: Instant::now(),
+            traffic,
+            report: false,
+        }
+    }
+
+    /// Returns the number of wants requested from peers and the number of queued
+    /// background wants.
+    pub fn want_counts(&self) -> (usize, usize) {
+        self.swarm.lock().want_counts()
+    }
+
+    /// Measures the time it takes `peer` to respond to a bitswap request.
+    pub async fn bitswap_echo(&self, peer: &PeerId) -> Result<Duration> {
+        let start = Instant::now();
+        let query = {
             let mut swarm = self.swarm.lock();
-            swarm.provide(cid)
+            let (rx, id) = swarm.echo(*peer);
+            GetQuery {
+                swarm: Some(self.swarm.clone()),
+                id,
+                rx,
+            }
         };
-        rx.await??;
-        Ok(())
+        match query.await {
+            Ok(()) => Ok(start.elapsed()),
+            Err(err) if err.downcast_ref::<BlockNotFound>().is_some() => Ok(start.elapsed()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Announces that the node provides a block, retrying according to the
+    /// `RetryPolicy`.
+    pub async fn provide(&self, cid: Cid) -> Result<()> {
+        self.retry
+            .run(|| {
+                let rx = {
+                    let mut swarm = self.swarm.lock();
+                    swarm.provide(cid)
+                };
+                async move { Ok(rx.await??) }
+            })
+            .await
     }
 
     pub fn unprovide(&self, cid: Cid) {
@@ -255,12 +1020,334 @@ impl<P: StoreParams> NetworkService<P> {
         swarm.unprovide(cid)
     }
 
+    /// Returns the heartbeats of the background tasks and the lengths of the internal
+    /// queues.
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+
+    /// Returns the resolver configured with `NetworkConfig::dns_resolver`, or the
+    /// `SystemResolver`.
+    pub fn dns_resolver(&self) -> Arc<dyn DnsResolver> {
+        self.resolver.clone()
+    }
+
+    /// Returns the activity level the periodic tasks of the node sleep on.
+    pub fn activity(&self) -> Activity {
+        self.activity.clone()
+    }
+
+    /// Changes the activity level, stretching or pausing the periodic beacons, rendezvous
+    /// registrations, gossipsub heartbeats and kademlia jobs.
+    pub fn set_activity_level(&self, level: ActivityLevel) {
+        self.activity.set_level(level)
+    }
+
+    /// Registers the prometheus metrics of the network. Nothing is registered without
+    /// the `metrics` feature.
     pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
+        if cfg!(not(feature = "metrics")) {
+            return Ok(());
+        }
+        self.health.register_metrics(registry)?;
         let swarm = self.swarm.lock();
         swarm.register_metrics(registry)
     }
 }
 
+/// Returns the missing links of a block received by a sync and its size if `size` is
+/// set.
+fn resolve<S: BitswapStore>(
+    store: &mut SizeRecorder<S>,
+    cid: &Cid,
+    size: bool,
+) -> Result<(Vec<Cid>, usize)> {
+    let missing = store.missing_blocks(cid)?;
+    let size = if size { store.size(cid)? } else { 0 };
+    Ok((missing, size))
+}
+
+/// Creates the transport of `NetworkService::new`, authenticating with the `node_key` of
+/// the config.
+fn new_transport(
+    config: &NetworkConfig,
+    limiter: BandwidthLimiter,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let tcp = Socks5Transport::new(TcpConfig::new().nodelay(true), config.socks5.clone());
+    let transport = match (config.socks5.as_ref(), config.dns_resolver.clone()) {
+        (Some(socks5), _) if socks5.remote_dns => EitherTransport::Left(tcp),
+        (_, Some(resolver)) => {
+            EitherTransport::Right(EitherTransport::Left(ResolverTransport::new(tcp, resolver)))
+        }
+        (_, None) => EitherTransport::Right(EitherTransport::Right(DnsConfig::new(tcp)?)),
+    };
+    let transport = if let Some(psk) = config.psk {
+        EitherTransport::Left(
+            transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
+        )
+    } else {
+        EitherTransport::Right(transport)
+    };
+    let dh_key = noise::Keypair::<X25519Spec>::new()
+        .into_authentic(&config.node_key)
+        .unwrap();
+    Ok(transport
+        .upgrade(Version::V1)
+        .authenticate(NoiseConfig::xx(dh_key).into_authenticated())
+        .multiplex_ext(move |peer, _| {
+            let upgrade = SelectUpgrade::new(YamuxConfig::default(), MplexConfig::new());
+            ThrottledUpgrade::new(upgrade, *peer, limiter)
+        })
+        .timeout(Duration::from_secs(5))
+        .boxed())
+}
+
+/// Builds the swarm of the node with the `node_key` of the config.
+async fn new_swarm<P: StoreParams, S: BitswapStore<Params = P> + Clone>(
+    config: NetworkConfig,
+    transport: Boxed<(PeerId, StreamMuxerBox)>,
+    capture: Option<Capture>,
+    store: SizeRecorder<S>,
+    health: &Health,
+    activity: &Activity,
+    metrics: &BehaviourMetrics,
+) -> Result<Swarm<NetworkBackendBehaviour<P>>> {
+    let peer_id = config.peer_id();
+    let limits =
+        ConnectionLimits::default().with_max_established_incoming(config.max_incoming_connections);
+    let transport = if let Some(capture) = capture {
+        transport
+            .map(move |(peer, muxer), _| {
+                let muxer = CaptureMuxer::new(muxer, &peer, capture.clone());
+                (peer, StreamMuxerBox::new(muxer))
+            })
+            .boxed()
+    } else {
+        transport
+    };
+    let behaviour =
+        NetworkBackendBehaviour::<P>::new(config, store, health, activity, metrics).await?;
+    Ok(SwarmBuilder::new(transport, behaviour, peer_id)
+        .connection_limits(limits)
+        .executor(Box::new(|fut| {
+            ipfs_embed_rt::spawn(fut).detach();
+        }))
+        .build())
+}
+
+/// Spawns the tasks serving the channels of the behaviour of `swarm`. The tasks operate
+/// on the `shared` swarm, which `swarm` is or is about to replace, and end when its
+/// behaviour is dropped.
+fn spawn_swarm_tasks<P: StoreParams, S: BitswapStore<Params = P> + Clone>(
+    shared: &Arc<Mutex<Swarm<NetworkBackendBehaviour<P>>>>,
+    swarm: &mut Swarm<NetworkBackendBehaviour<P>>,
+    config: &NetworkConfig,
+    store: SizeRecorder<S>,
+) {
+    let swarm3 = shared.clone();
+    let backoff = config.listener_rebind_backoff;
+    let max_backoff = config.listener_rebind_max_backoff;
+    let mut events = swarm.internal_events();
+    ipfs_embed_rt::spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                Event::ListenerClosed(_, addr, Some(_)) => {
+                    let rebind = rebind_listener(swarm3.clone(), addr, backoff, max_backoff);
+                    ipfs_embed_rt::spawn(rebind).detach();
+                }
+                Event::Connected(peer) => swarm3.lock().resume_syncs(peer),
+                _ => {}
+            }
+        }
+    })
+    .detach();
+    let swarm7 = shared.clone();
+    let mut address_changes = swarm.address_changes();
+    ipfs_embed_rt::spawn(async move {
+        while let Some(change) = address_changes.next().await {
+            let mut swarm = swarm7.lock();
+            match change {
+                AddressChange::Confirmed(addr, votes) => {
+                    let score = AddressScore::Finite(votes as u32);
+                    Swarm::add_external_address(&mut swarm, addr, score);
+                }
+                AddressChange::Expired(addr) => {
+                    tracing::info!("external address {} expired", addr);
+                    Swarm::remove_external_address(&mut swarm, &addr);
+                }
+            }
+        }
+    })
+    .detach();
+    let swarm4 = shared.clone();
+    let mut identified = swarm.identified();
+    let auth_timeout = config.auth_timeout;
+    let auth_backoff = config.auth_backoff;
+    ipfs_embed_rt::spawn(async move {
+        while let Some(identity) = identified.next().await {
+            let authenticator = match swarm4.lock().authenticator() {
+                Some(authenticator) => authenticator,
+                None => continue,
+            };
+            let swarm = swarm4.clone();
+            ipfs_embed_rt::spawn(async move {
+                let peer = identity.peer_id;
+                let res = auth::authenticate(&*authenticator, &identity, auth_timeout).await;
+                let err = match res {
+                    Ok(()) => {
+                        swarm.lock().accept_auth(&peer);
+                        return;
+                    }
+                    Err(err) => err,
+                };
+                tracing::info!("disconnecting {}: authentication failed: {}", peer, err);
+                let failures = {
+                    let mut swarm = swarm.lock();
+                    // banning closes the connections.
+                    Swarm::ban_peer_id(&mut swarm, peer);
+                    swarm.record_auth_failure(&peer)
+                };
+                let attempt = std::cmp::min(failures, u32::MAX as u64) as u32;
+                Timer::after(auth_backoff.delay(attempt)).await;
+                let mut swarm = swarm.lock();
+                if !swarm.is_banned(&peer) {
+                    Swarm::unban_peer_id(&mut swarm, peer);
+                }
+            })
+            .detach();
+        }
+    })
+    .detach();
+    let swarm5 = shared.clone();
+    let mut requests = swarm.resolve_requests();
+    ipfs_embed_rt::spawn(async move {
+        while let Some(request) = requests.next().await {
+            // requests queued while the previous batch was resolved are resolved
+            // together, so that the wants of a sync are added at once.
+            let mut batch = vec![request];
+            while let Ok(Some(request)) = requests.try_next() {
+                batch.push(request);
+            }
+            let mut store = store.clone();
+            let resolved = ipfs_embed_rt::spawn_blocking(move || {
+                batch
+                    .into_iter()
+                    .map(|ResolveRequest { id, cid, size }| {
+                        let result = resolve(&mut store, &cid, size);
+                        (id, cid, result)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            swarm5.lock().resolved(resolved);
+        }
+    })
+    .detach();
+    let swarm6 = shared.clone();
+    let mut timers = swarm.sync_timers();
+    ipfs_embed_rt::spawn(async move {
+        while let Some(timer) = timers.next().await {
+            let swarm = swarm6.clone();
+            ipfs_embed_rt::spawn(async move {
+                Timer::after(timer.delay).await;
+                swarm.lock().sync_timer_expired(timer);
+            })
+            .detach();
+        }
+    })
+    .detach();
+}
+
+/// Returns `addr` with a zero tcp port replaced by the port its listener was bound to,
+/// so that the listener keeps its port when the swarm is rebuilt.
+fn bound_port(addr: &Multiaddr, bound: &[Multiaddr]) -> Multiaddr {
+    let ip = addr.iter().next();
+    if !addr.iter().any(|proto| proto == Protocol::Tcp(0)) {
+        return addr.clone();
+    }
+    let port = bound.iter().find_map(|bound| {
+        let mut protos = bound.iter();
+        let same_ip = match (ip.as_ref(), protos.next()) {
+            (Some(Protocol::Ip4(ip)), Some(Protocol::Ip4(other))) => {
+                ip.is_unspecified() || *ip == other
+            }
+            (Some(Protocol::Ip6(ip)), Some(Protocol::Ip6(other))) => {
+                ip.is_unspecified() || *ip == other
+            }
+            _ => false,
+        };
+        match protos.next() {
+            Some(Protocol::Tcp(port)) if same_ip => Some(port),
+            _ => None,
+        }
+    });
+    match port {
+        Some(port) => addr
+            .iter()
+            .map(|proto| match proto {
+                Protocol::Tcp(0) => Protocol::Tcp(port),
+                proto => proto,
+            })
+            .collect(),
+        None => addr.clone(),
+    }
+}
+
+async fn rebind_listener<P: StoreParams>(
+    swarm: Arc<Mutex<Swarm<NetworkBackendBehaviour<P>>>>,
+    addr: Multiaddr,
+    mut backoff: Duration,
+    max_backoff: Duration,
+) {
+    loop {
+        tracing::debug!("rebinding listener {} in {:?}", addr, backoff);
+        Timer::after(backoff).await;
+        let mut guard = swarm.lock();
+        match Swarm::listen_on(&mut guard, addr.clone()) {
+            Ok(id) => {
+                guard.add_listener(id, addr);
+                return;
+            }
+            Err(err) => {
+                tracing::warn!("rebinding listener {} failed: {}", addr, err);
+                guard.notify(Event::RebindFailed(addr.clone(), err.to_string()));
+            }
+        }
+        backoff = std::cmp::min(backoff * 2, max_backoff);
+    }
+}
+
+/// Periodically registers at and discovers peers from the configured rendezvous points.
+async fn rendezvous<P: StoreParams>(
+    service: NetworkService<P>,
+    namespace: String,
+    config: NetworkConfig,
+) {
+    for (peer, addr) in &config.rendezvous_points {
+        service.add_address(peer, addr.clone());
+    }
+    let activity = service.activity();
+    // registering without addresses is pointless, wait for the first listener.
+    while service.listeners().is_empty() {
+        Timer::after(Duration::from_secs(1)).await;
+    }
+    loop {
+        for (point, _) in &config.rendezvous_points {
+            if let Err(err) = service
+                .rendezvous_register(point, &namespace, config.rendezvous_ttl)
+                .await
+            {
+                tracing::warn!("rendezvous registration at {} failed: {}", point, err);
+            }
+            match service.rendezvous_discover(point, &namespace).await {
+                Ok(peers) => tracing::debug!("discovered {} peers at {}", peers.len(), point),
+                Err(err) => tracing::warn!("rendezvous discovery at {} failed: {}", point, err),
+            }
+        }
+        activity.sleep(config.rendezvous_discovery_interval).await;
+    }
+}
+
 pub struct GetQuery<P: StoreParams> {
     swarm: Option<Arc<Mutex<Swarm<NetworkBackendBehaviour<P>>>>>,
     id: QueryId,
@@ -290,8 +1377,48 @@ impl<P: StoreParams> Drop for GetQuery<P> {
 /// A `bitswap` sync query.
 pub struct SyncQuery<P: StoreParams> {
     swarm: Option<Arc<Mutex<Swarm<NetworkBackendBehaviour<P>>>>>,
-    id: QueryId,
-    rx: SyncChannel,
+    /// `None` for queries that failed before they were started or that are run by
+    /// another exchange.
+    id: Option<QueryId>,
+    rx: BoxStream<'static, SyncEvent>,
+    guards: Vec<Box<dyn Send>>,
+    limiter: BandwidthLimiter,
+    start: Instant,
+    /// Bytes received from each peer when the query started.
+    traffic: FnvHashMap<PeerId, u64>,
+    /// Set when a `Bandwidth` event is due.
+    report: bool,
+}
+
+impl<P: StoreParams> SyncQuery<P> {
+    /// Keeps `guard` alive until the query is dropped.
+    pub fn hold<T: Send + 'static>(mut self, guard: T) -> Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+
+    /// Returns the bytes downloaded since the query started.
+    pub fn stats(&self) -> SyncStats {
+        let mut peers = self
+            .limiter
+            .traffic()
+            .into_iter()
+            .filter_map(|(peer, _, received)| {
+                let start = self.traffic.get(&peer).copied().unwrap_or_default();
+                let bytes = received.saturating_sub(start);
+                if bytes > 0 {
+                    Some((peer, bytes))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| b.1.cmp(&a.1));
+        SyncStats {
+            elapsed: self.start.elapsed(),
+            peers,
+        }
+    }
 }
 
 impl<P: StoreParams> Future for SyncQuery<P> {
@@ -312,14 +1439,22 @@ impl<P: StoreParams> Stream for SyncQuery<P> {
     type Item = SyncEvent;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.rx).poll_next(cx)
+        if self.report {
+            self.report = false;
+            return Poll::Ready(Some(SyncEvent::Bandwidth(self.stats())));
+        }
+        let event = Pin::new(&mut self.rx).poll_next(cx);
+        if let Poll::Ready(Some(SyncEvent::Progress(_))) = &event {
+            self.report = true;
+        }
+        event
     }
 }
 
 impl<P: StoreParams> Drop for SyncQuery<P> {
     fn drop(&mut self) {
-        let swarm = self.swarm.take().unwrap();
-        let mut swarm = swarm.lock();
-        swarm.cancel(self.id);
+        if let (Some(swarm), Some(id)) = (self.swarm.take(), self.id) {
+            swarm.lock().cancel(id);
+        }
     }
 }
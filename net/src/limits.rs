@@ -0,0 +1,74 @@
+use libipld::Result;
+use thiserror::Error;
+
+/// Error returned when a traversal exceeds its limits.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum LimitExceeded {
+    #[error("dag depth exceeds the limit of {0}")]
+    Depth(usize),
+    #[error("number of blocks exceeds the limit of {0}")]
+    Blocks(usize),
+    #[error("number of bytes exceeds the limit of {0}")]
+    Bytes(u64),
+}
+
+/// Error returned when a sync can't request blocks in the traversal order.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+#[error("syncs can't request blocks in {0:?} order")]
+pub struct UnsupportedOrder(pub TraversalOrder);
+
+/// Limits of a dag traversal, guarding against malicious dags with extreme depth or
+/// branching.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TraversalLimits {
+    /// Maximum depth of the dag. Syncs count the depth in blocks, path resolution counts
+    /// the path segments.
+    pub max_depth: Option<usize>,
+    /// Maximum number of blocks traversed.
+    pub max_blocks: Option<usize>,
+    /// Maximum number of bytes traversed.
+    pub max_bytes: Option<u64>,
+}
+
+impl TraversalLimits {
+    /// No limits.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn check_depth(&self, depth: usize) -> Result<()> {
+        match self.max_depth {
+            Some(max) if depth > max => Err(LimitExceeded::Depth(max).into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_blocks(&self, blocks: usize) -> Result<()> {
+        match self.max_blocks {
+            Some(max) if blocks > max => Err(LimitExceeded::Blocks(max).into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_bytes(&self, bytes: u64) -> Result<()> {
+        match self.max_bytes {
+            Some(max) if bytes > max => Err(LimitExceeded::Bytes(max).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Order in which the blocks of a dag are visited.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraversalOrder {
+    /// Visits a block before its links, following the links left to right. Blocks are
+    /// visited in the order of the bytes of a file, which suits readers consuming a dag
+    /// sequentially, like a video player.
+    DepthFirst,
+    /// Visits the blocks level by level.
+    BreadthFirst,
+    /// Visits the largest known blocks first, so that most of the bytes of a dag are
+    /// reached early. Syncs don't know the size of a block before receiving it, so they
+    /// fail with `UnsupportedOrder`.
+    LargestFirst,
+}
@@ -0,0 +1,205 @@
+use crate::activity::{Activity, ActivityLevel};
+use crate::serve::{HandlerIn, HandlerOut};
+use futures::channel::oneshot;
+use futures::future::FutureExt;
+use ipfs_embed_rt::Timer;
+use libp2p::core::connection::{ConnectedPoint, ConnectionId, ListenerId};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use std::error::Error;
+use std::ops::{Deref, DerefMut};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Paces the timers of a behaviour, like the gossipsub heartbeat or the kademlia jobs, by
+/// the activity level.
+///
+/// Outside of `ActivityLevel::Active` the behaviour is polled after it received an event
+/// or was called, so that messages and requests are still handled right away. Its timers
+/// only fire when it is polled, which in the background happens at least every `tick`
+/// stretched by the activity level, and while suspended not at all.
+pub(crate) struct Paced<B> {
+    inner: B,
+    activity: Activity,
+    tick: Duration,
+    level: ActivityLevel,
+    changed: Option<oneshot::Receiver<()>>,
+    timer: Option<Timer>,
+    /// The behaviour received an event or was called since it was last polled.
+    dirty: bool,
+}
+
+impl<B> Paced<B> {
+    pub fn new(inner: B, activity: Activity, tick: Duration) -> Self {
+        Self {
+            inner,
+            activity,
+            tick,
+            level: ActivityLevel::Active,
+            changed: None,
+            timer: None,
+            dirty: true,
+        }
+    }
+
+    /// Returns the activity level, registering the task to be woken when it changes.
+    fn level(&mut self, cx: &mut Context) -> ActivityLevel {
+        loop {
+            if let Some(changed) = self.changed.as_mut() {
+                if changed.poll_unpin(cx).is_pending() {
+                    return self.level;
+                }
+            }
+            let (level, changed) = self.activity.watch();
+            self.level = level;
+            self.changed = Some(changed);
+            self.timer = None;
+            self.dirty = true;
+        }
+    }
+
+    /// Returns `true` once the stretched tick elapsed.
+    fn tick(&mut self, cx: &mut Context, level: ActivityLevel) -> bool {
+        let tick = match level.stretch(self.tick) {
+            Some(tick) => tick,
+            None => return false,
+        };
+        let timer = self.timer.get_or_insert_with(|| Timer::after(tick));
+        if timer.poll_unpin(cx).is_ready() {
+            self.timer = None;
+            return true;
+        }
+        false
+    }
+}
+
+impl<B> Deref for Paced<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B> DerefMut for Paced<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        self.dirty = true;
+        &mut self.inner
+    }
+}
+
+impl<B: NetworkBehaviour> NetworkBehaviour for Paced<B> {
+    type ProtocolsHandler = B::ProtocolsHandler;
+    type OutEvent = B::OutEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.dirty = true;
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.dirty = true;
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.dirty = true;
+        self.inner
+            .inject_connection_established(peer_id, id, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.dirty = true;
+        self.inner.inject_connection_closed(peer_id, id, endpoint)
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.dirty = true;
+        self.inner.inject_address_change(peer_id, id, old, new)
+    }
+
+    fn inject_event(&mut self, peer_id: PeerId, connection: ConnectionId, event: HandlerOut<B>) {
+        self.dirty = true;
+        self.inner.inject_event(peer_id, connection, event)
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn Error,
+    ) {
+        self.dirty = true;
+        self.inner.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.dirty = true;
+        self.inner.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.dirty = true;
+        self.inner.inject_new_listen_addr(addr)
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.dirty = true;
+        self.inner.inject_expired_listen_addr(addr)
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.dirty = true;
+        self.inner.inject_new_external_addr(addr)
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn Error + 'static)) {
+        self.dirty = true;
+        self.inner.inject_listener_error(id, err)
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        self.dirty = true;
+        self.inner.inject_listener_closed(id, reason)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<HandlerIn<B>, B::OutEvent>> {
+        let level = self.level(cx);
+        let due = level == ActivityLevel::Active || self.tick(cx, level);
+        if !due && !self.dirty {
+            return Poll::Pending;
+        }
+        let poll = self.inner.poll(cx, params);
+        // a behaviour that returned an event may have more queued.
+        self.dirty = poll.is_ready();
+        poll
+    }
+}
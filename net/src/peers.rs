@@ -1,12 +1,19 @@
 use fnv::{FnvHashMap, FnvHashSet};
-use libp2p::core::connection::{ConnectedPoint, ConnectionId};
+use futures::channel::mpsc;
+use libp2p::core::connection::{ConnectedPoint, ConnectionId, ListenerId};
 use libp2p::identify::IdentifyInfo;
 use libp2p::swarm::protocols_handler::DummyProtocolsHandler;
 use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
 use libp2p::{Multiaddr, PeerId};
+use std::cmp::Reverse;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
+/// Weight of a new rtt measurement in the moving average.
+const RTT_EWMA_ALPHA: f64 = 0.2;
+/// Maximum number of peers statistics are kept for.
+const MAX_PEER_STATS: usize = 4096;
+
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct PeerInfo {
     protocol_version: Option<String>,
@@ -14,6 +21,7 @@ pub struct PeerInfo {
     protocols: Vec<String>,
     addresses: FnvHashMap<Multiaddr, AddressSource>,
     rtt: Option<Duration>,
+    rtt_ewma: Option<Duration>,
 }
 
 impl PeerInfo {
@@ -36,20 +44,107 @@ impl PeerInfo {
     pub fn rtt(&self) -> Option<Duration> {
         self.rtt
     }
+
+    /// Exponentially weighted moving average of the rtt measurements.
+    pub fn rtt_ewma(&self) -> Option<Duration> {
+        self.rtt_ewma
+    }
+}
+
+/// Rolling statistics of a peer. They are worth keeping across restarts so that
+/// the node doesn't need to relearn which peers are fast and reliable.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PeerStats {
+    /// Moving average of the rtt measurements.
+    pub rtt_ewma: Option<Duration>,
+    /// Number of dials that established a connection.
+    pub dial_successes: u64,
+    /// Number of dials that failed to reach any address of the peer.
+    pub dial_failures: u64,
+    /// Number of bytes sent to the peer.
+    pub bytes_sent: u64,
+    /// Number of bytes received from the peer.
+    pub bytes_received: u64,
+    /// Number of times the peer was rejected by the `Authenticator`. Every failure bans
+    /// the peer for longer, see `NetworkConfig::auth_backoff`.
+    pub auth_failures: u64,
+}
+
+impl PeerStats {
+    /// Returns the fraction of dials that succeeded or `None` if the peer was never dialed.
+    pub fn success_rate(&self) -> Option<f64> {
+        let dials = self.dial_successes + self.dial_failures;
+        if dials == 0 {
+            None
+        } else {
+            Some(self.dial_successes as f64 / dials as f64)
+        }
+    }
+
+    /// Key ordering peers by how well they are expected to serve requests. Peers that are
+    /// reliably reached come first, ties are broken by the rtt. Peers that were never
+    /// dialed are ranked like peers reached half of the time.
+    fn rank(&self) -> (Reverse<u64>, Duration) {
+        let success_rate = self.success_rate().unwrap_or(0.5);
+        let rtt = self
+            .rtt_ewma
+            .unwrap_or_else(|| Duration::from_secs(u64::MAX));
+        (Reverse((success_rate * 100.0) as u64), rtt)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AddressSource {
     Mdns,
     Kad,
+    Rendezvous,
+    Beacon,
+    DialBack,
     User,
 }
 
+/// An event emitted by the swarm.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// A new listener was created. When a listener is rebound after a failure it gets a
+    /// new `ListenerId`.
+    NewListener(ListenerId, Multiaddr),
+    /// A listener started listening on an address.
+    NewListenAddr(Multiaddr),
+    /// A listener stopped listening on an address.
+    ExpiredListenAddr(Multiaddr),
+    /// A listener reported a non-fatal error.
+    ListenerError(ListenerId, String),
+    /// A listener was closed. If it was closed due to an error, it will be rebound.
+    ListenerClosed(ListenerId, Multiaddr, Option<String>),
+    /// An attempt to rebind a failed listener failed.
+    RebindFailed(Multiaddr, String),
+    /// The port of a listen address was mapped to an external address on the gateway.
+    PortMapped(Multiaddr, Multiaddr),
+    /// Mapping the port of a listen address failed.
+    PortMappingFailed(Multiaddr, String),
+    /// The first connection to a peer was established.
+    Connected(PeerId),
+    /// The last connection to a peer was closed.
+    Disconnected(PeerId),
+    /// A connected peer subscribed to a gossipsub topic. Peers send their subscriptions
+    /// when they connect, so this is emitted again after a reconnect.
+    Subscribed(PeerId, String),
+    /// A peer was pinged. Contains the moving average of the rtt.
+    Rtt(PeerId, Duration),
+}
+
 #[derive(Debug)]
 pub struct AddressBook {
     local_peer_id: PeerId,
     peers: FnvHashMap<PeerId, PeerInfo>,
+    stats: FnvHashMap<PeerId, PeerStats>,
     connections: FnvHashSet<(PeerId, Multiaddr)>,
+    listeners: FnvHashMap<ListenerId, Multiaddr>,
+    event_stream: Vec<mpsc::UnboundedSender<Event>>,
+    /// Events for the tasks driving the swarm, which are spawned again when the swarm is
+    /// rebuilt by an identity rotation.
+    internal_events: Option<mpsc::UnboundedSender<Event>>,
 }
 
 impl AddressBook {
@@ -57,7 +152,11 @@ impl AddressBook {
         Self {
             local_peer_id,
             peers: Default::default(),
+            stats: Default::default(),
             connections: Default::default(),
+            listeners: Default::default(),
+            event_stream: Default::default(),
+            internal_events: None,
         }
     }
 
@@ -99,9 +198,122 @@ impl AddressBook {
     }
 
     pub fn set_rtt(&mut self, peer_id: &PeerId, rtt: Option<Duration>) {
+        let info = self.peers.entry(*peer_id).or_default();
+        info.rtt = rtt;
+        if let Some(rtt) = rtt {
+            let stats = self.stats_mut(peer_id);
+            let ewma = if let Some(ewma) = stats.rtt_ewma {
+                ewma.mul_f64(1.0 - RTT_EWMA_ALPHA) + rtt.mul_f64(RTT_EWMA_ALPHA)
+            } else {
+                rtt
+            };
+            stats.rtt_ewma = Some(ewma);
+            info.rtt_ewma = Some(ewma);
+            self.notify(Event::Rtt(*peer_id, ewma));
+        }
+    }
+
+    pub fn rtt(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.stats.get(peer_id)?.rtt_ewma
+    }
+
+    pub fn stats(&self) -> impl Iterator<Item = (&PeerId, &PeerStats)> + '_ {
+        self.stats.iter()
+    }
+
+    /// Orders `peers` by their statistics, so that requests go to the fastest and most
+    /// reliable peers first.
+    pub fn rank(&self, peers: &mut [PeerId]) {
+        let unknown = PeerStats::default();
+        peers.sort_by_key(|peer| self.stats.get(peer).unwrap_or(&unknown).rank());
+    }
+
+    fn stats_mut(&mut self, peer_id: &PeerId) -> &mut PeerStats {
+        if self.stats.len() >= MAX_PEER_STATS && !self.stats.contains_key(peer_id) {
+            self.prune_stats();
+        }
+        self.stats.entry(*peer_id).or_default()
+    }
+
+    /// Drops the statistics of the peers that left the address book. If that isn't
+    /// enough, the statistics of the lowest ranked peers are dropped.
+    fn prune_stats(&mut self) {
+        let peers = &self.peers;
+        self.stats.retain(|peer, _| peers.contains_key(peer));
+        if self.stats.len() >= MAX_PEER_STATS {
+            let mut ranked = self.stats.keys().copied().collect::<Vec<_>>();
+            self.rank(&mut ranked);
+            for peer in &ranked[MAX_PEER_STATS / 2..] {
+                self.stats.remove(peer);
+            }
+        }
+    }
+
+    /// Seeds the statistics of a peer, for example with the statistics persisted before
+    /// a restart. The rtt moving average continues from the seeded value.
+    pub fn restore_stats(&mut self, peer_id: &PeerId, stats: PeerStats) {
         if let Some(info) = self.peers.get_mut(peer_id) {
-            info.rtt = rtt;
+            info.rtt_ewma = stats.rtt_ewma;
+        }
+        *self.stats_mut(peer_id) = stats;
+    }
+
+    /// Counts a failed authentication, returning the number of failures of the peer.
+    pub fn record_auth_failure(&mut self, peer_id: &PeerId) -> u64 {
+        let stats = self.stats_mut(peer_id);
+        stats.auth_failures += 1;
+        stats.auth_failures
+    }
+
+    /// Returns the known addresses of all peers.
+    pub fn addresses(&self) -> impl Iterator<Item = (&PeerId, &Multiaddr, AddressSource)> + '_ {
+        self.peers.iter().flat_map(|(peer, info)| {
+            info.addresses()
+                .map(move |(addr, source)| (peer, addr, source))
+        })
+    }
+
+    /// Takes over the statistics and the swarm event subscribers of the address book of
+    /// a swarm replaced by an identity rotation.
+    pub fn take_over(&mut self, old: &mut Self) {
+        self.stats = std::mem::take(&mut old.stats);
+        self.event_stream = std::mem::take(&mut old.event_stream);
+    }
+
+    /// Returns the addresses the listeners were started with.
+    pub fn listener_addrs(&self) -> impl Iterator<Item = &Multiaddr> + '_ {
+        self.listeners.values()
+    }
+
+    pub fn add_listener(&mut self, id: ListenerId, addr: Multiaddr) {
+        self.listeners.insert(id, addr.clone());
+        self.notify(Event::NewListener(id, addr));
+    }
+
+    pub fn remove_listener(&mut self, id: &ListenerId) -> Option<Multiaddr> {
+        self.listeners.remove(id)
+    }
+
+    pub fn swarm_events(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded();
+        self.event_stream.push(tx);
+        rx
+    }
+
+    /// Returns the events for the tasks driving the swarm.
+    pub fn internal_events(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded();
+        self.internal_events = Some(tx);
+        rx
+    }
+
+    pub fn notify(&mut self, event: Event) {
+        tracing::trace!("{:?}", event);
+        if let Some(tx) = self.internal_events.as_ref() {
+            tx.unbounded_send(event.clone()).ok();
         }
+        self.event_stream
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
     }
 
     pub fn set_info(&mut self, peer_id: &PeerId, identify: IdentifyInfo) {
@@ -129,9 +341,13 @@ impl NetworkBehaviour for AddressBook {
         }
     }
 
-    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.notify(Event::Connected(*peer_id));
+    }
 
-    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.notify(Event::Disconnected(*peer_id));
+    }
 
     fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, _event: void::Void) {}
 
@@ -149,6 +365,9 @@ impl NetworkBehaviour for AddressBook {
         _: &ConnectionId,
         conn: &ConnectedPoint,
     ) {
+        if conn.is_dialer() {
+            self.stats_mut(peer_id).dial_successes += 1;
+        }
         let conn = (*peer_id, conn.get_remote_address().clone());
         self.connections.insert(conn);
     }
@@ -188,6 +407,26 @@ impl NetworkBehaviour for AddressBook {
     }
 
     fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.stats_mut(peer_id).dial_failures += 1;
         self.peers.remove(peer_id);
     }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.notify(Event::NewListenAddr(addr.clone()));
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.notify(Event::ExpiredListenAddr(addr.clone()));
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn std::error::Error + 'static)) {
+        self.notify(Event::ListenerError(id, err.to_string()));
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        if let Some(addr) = self.listeners.remove(&id) {
+            let err = reason.err().map(|err| err.to_string());
+            self.notify(Event::ListenerClosed(id, addr, err));
+        }
+    }
 }
@@ -0,0 +1,126 @@
+use fnv::FnvHashSet;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Result};
+use libp2p_bitswap::BitswapStore;
+use prometheus::IntCounterVec;
+use thiserror::Error;
+
+/// Restricts the hashes and codecs of blocks accepted from the network.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BlockPolicy {
+    hashes: Option<FnvHashSet<u64>>,
+    codecs: Option<FnvHashSet<u64>>,
+}
+
+impl BlockPolicy {
+    /// Creates a policy that accepts all blocks.
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Only accepts blocks hashed with one of the multihash codes in `hashes`.
+    pub fn with_hashes<I, T>(mut self, hashes: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<u64>,
+    {
+        self.hashes = Some(hashes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only accepts blocks encoded with one of the `codecs`.
+    pub fn with_codecs<I, T>(mut self, codecs: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<u64>,
+    {
+        self.codecs = Some(codecs.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Checks if a block is accepted by the policy.
+    pub fn check(&self, cid: &Cid) -> Result<(), BlockRejected> {
+        let hash = cid.hash().code();
+        if let Some(hashes) = self.hashes.as_ref() {
+            if !hashes.contains(&hash) {
+                return Err(BlockRejected::Hash(*cid, hash));
+            }
+        }
+        if let Some(codecs) = self.codecs.as_ref() {
+            if !codecs.contains(&cid.codec()) {
+                return Err(BlockRejected::Codec(*cid, cid.codec()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BlockRejected {
+    #[error("block {0} uses the disallowed hash 0x{1:x}")]
+    Hash(Cid, u64),
+    #[error("block {0} uses the disallowed codec 0x{1:x}")]
+    Codec(Cid, u64),
+}
+
+impl BlockRejected {
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::Hash(_, _) => "hash",
+            Self::Codec(_, _) => "codec",
+        }
+    }
+}
+
+/// Checks a block against the policy, counting rejected blocks.
+pub(crate) fn check(
+    policy: &BlockPolicy,
+    rejected: &IntCounterVec,
+    cid: &Cid,
+) -> Result<(), BlockRejected> {
+    let res = policy.check(cid);
+    if let Err(err) = &res {
+        tracing::debug!("{}", err);
+        rejected.with_label_values(&[err.reason()]).inc();
+    }
+    res
+}
+
+/// Bitswap store that refuses to insert blocks rejected by the policy.
+#[derive(Clone)]
+pub(crate) struct PolicyStore<S> {
+    store: S,
+    policy: BlockPolicy,
+    rejected: IntCounterVec,
+}
+
+impl<S> PolicyStore<S> {
+    pub fn new(store: S, policy: BlockPolicy, rejected: IntCounterVec) -> Self {
+        Self {
+            store,
+            policy,
+            rejected,
+        }
+    }
+}
+
+impl<P: StoreParams, S: BitswapStore<Params = P>> BitswapStore for PolicyStore<S> {
+    type Params = P;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.store.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.store.get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<P>) -> Result<()> {
+        check(&self.policy, &self.rejected, block.cid())?;
+        self.store.insert(block)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.store.missing_blocks(cid)
+    }
+}
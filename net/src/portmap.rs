@@ -0,0 +1,564 @@
+use crate::peers::Event;
+use crate::NetworkService;
+use futures::future::{self, Either};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::stream::StreamExt;
+use futures::Future;
+use ipfs_embed_rt::{TcpStream, Timer, UdpSocket};
+use libipld::store::StoreParams;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use parking_lot::Mutex;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+const NAT_PMP_PORT: u16 = 5351;
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const GATEWAY_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Port mapping configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PortMapConfig {
+    /// Requests mappings with nat-pmp.
+    pub nat_pmp: bool,
+    /// Requests mappings with upnp when nat-pmp is disabled or fails.
+    pub upnp: bool,
+    /// Address of the nat-pmp gateway. Read from the routing table when `None`, which is
+    /// only supported on linux.
+    pub gateway: Option<Ipv4Addr>,
+    /// Lifetime of the mappings. Mappings are renewed after half their lifetime.
+    pub lease: Duration,
+}
+
+impl Default for PortMapConfig {
+    fn default() -> Self {
+        Self {
+            nat_pmp: true,
+            upnp: true,
+            gateway: None,
+            lease: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("port mapping: {}", msg))
+}
+
+async fn timeout<T, F: Future<Output = io::Result<T>>>(
+    duration: Duration,
+    fut: F,
+) -> io::Result<T> {
+    futures::pin_mut!(fut);
+    match future::select(fut, Timer::after(duration)).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// Returns the private ip4 address and port of a tcp listener.
+fn mappable(addr: &Multiaddr) -> Option<(Ipv4Addr, u16)> {
+    let mut iter = addr.iter();
+    let ip = match iter.next()? {
+        Protocol::Ip4(ip) if ip.is_private() => ip,
+        _ => return None,
+    };
+    match (iter.next()?, iter.next()) {
+        (Protocol::Tcp(port), None) => Some((ip, port)),
+        _ => None,
+    }
+}
+
+/// Reads the default gateway from the routing table.
+fn default_gateway() -> Option<Ipv4Addr> {
+    parse_routes(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+/// Returns the gateway of the default route of a linux routing table.
+fn parse_routes(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        if fields.next()? != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.next()?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// Sends a nat-pmp request, retrying with the backoff recommended by rfc 6886.
+async fn nat_pmp_request(gateway: Ipv4Addr, request: &[u8], response: &mut [u8]) -> io::Result<()> {
+    let socket = UdpSocket::bind(([0, 0, 0, 0], 0))?;
+    let gateway = SocketAddr::from((gateway, NAT_PMP_PORT));
+    let mut delay = Duration::from_millis(250);
+    for _ in 0..4 {
+        socket.send_to(request, gateway).await?;
+        match timeout(delay, socket.recv_from(response)).await {
+            Ok((len, from)) if from == gateway && len == response.len() => {
+                if response[1] != request[1] | 0x80 {
+                    return Err(error("unexpected nat-pmp response"));
+                }
+                let code = u16::from_be_bytes([response[2], response[3]]);
+                if code != 0 {
+                    return Err(error(&format!("nat-pmp request failed with code {}", code)));
+                }
+                return Ok(());
+            }
+            Ok(_) => continue,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => delay *= 2,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(error("nat-pmp gateway not responding"))
+}
+
+/// Encodes a nat-pmp request mapping the tcp `port` for `lease`. A lease of zero removes
+/// the mapping.
+fn nat_pmp_map_request(port: u16, lease: Duration) -> Vec<u8> {
+    // the suggested external port must be zero when removing a mapping.
+    let external = if lease == Duration::default() {
+        0
+    } else {
+        port
+    };
+    let lease = std::cmp::min(lease.as_secs(), u32::MAX as u64) as u32;
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&external.to_be_bytes());
+    request.extend_from_slice(&lease.to_be_bytes());
+    request
+}
+
+/// Maps `port` with nat-pmp, returning the external address.
+async fn nat_pmp(gateway: Ipv4Addr, port: u16, lease: Duration) -> io::Result<Multiaddr> {
+    let mut response = [0; 12];
+    nat_pmp_request(gateway, &[0, 0], &mut response).await?;
+    let ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+    let mut response = [0; 16];
+    nat_pmp_request(gateway, &nat_pmp_map_request(port, lease), &mut response).await?;
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    Ok(Multiaddr::empty()
+        .with(Protocol::Ip4(ip))
+        .with(Protocol::Tcp(external_port)))
+}
+
+/// Returns the value of the first `tag` element in `xml`, starting the search at `from`.
+fn element<'a>(xml: &'a str, tag: &str, from: usize) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml[from..].find(&open)? + from + open.len();
+    let end = xml[start..].find(&close)? + start;
+    Some(xml[start..end].trim())
+}
+
+/// Splits `http://host:port/path` into the socket address and the path.
+fn parse_url(url: &str) -> Option<(SocketAddr, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.parse().ok()?
+    } else {
+        SocketAddr::new(host.parse().ok()?, 80)
+    };
+    Some((addr, path))
+}
+
+/// Sends a http request and returns the body of a successful response.
+async fn http(addr: SocketAddr, request: String) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(error(&format!(
+            "http request failed with status {}",
+            status
+        )));
+    }
+    let body = response.find("\r\n\r\n").map(|i| i + 4).unwrap_or(0);
+    Ok(response[body..].to_string())
+}
+
+struct Gateway {
+    addr: SocketAddr,
+    control: String,
+    service: &'static str,
+}
+
+impl Gateway {
+    /// Discovers a upnp internet gateway with ssdp.
+    async fn discover() -> io::Result<Self> {
+        let socket = UdpSocket::bind(([0, 0, 0, 0], 0))?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+            SSDP_ADDR, GATEWAY_TYPE
+        );
+        let ssdp: SocketAddr = SSDP_ADDR.parse().unwrap();
+        socket.send_to(search.as_bytes(), ssdp).await?;
+        let mut buf = [0; 1500];
+        let (len, _) = timeout(Duration::from_secs(3), socket.recv_from(&mut buf)).await?;
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = response
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_at(line.find(':')?);
+                if name.eq_ignore_ascii_case("location") {
+                    Some(value[1..].trim().to_string())
+                } else {
+                    None
+                }
+            })
+            .ok_or_else(|| error("ssdp response without location"))?;
+        let (addr, path) = parse_url(&location).ok_or_else(|| error("invalid location"))?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        );
+        let description = http(addr, request).await?;
+        for service in WAN_SERVICES.iter() {
+            if let Some(i) = description.find(service) {
+                let control = element(&description, "controlURL", i)
+                    .ok_or_else(|| error("service without control url"))?;
+                let control = match parse_url(control) {
+                    Some((_, path)) => path.to_string(),
+                    None => control.to_string(),
+                };
+                return Ok(Self {
+                    addr,
+                    control,
+                    service,
+                });
+            }
+        }
+        Err(error("gateway without wan connection service"))
+    }
+
+    /// Invokes `action` and returns the response.
+    async fn soap(&self, action: &str, args: &str) -> io::Result<String> {
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>",
+            action = action,
+            service = self.service,
+            args = args,
+        );
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+             Content-Type: text/xml; charset=\"utf-8\"\r\nContent-Length: {}\r\n\
+             SOAPAction: \"{}#{}\"\r\n\r\n{}",
+            self.control,
+            self.addr,
+            body.len(),
+            self.service,
+            action,
+            body
+        );
+        http(self.addr, request).await
+    }
+}
+
+/// Maps `port` of `ip` with upnp, returning the external address.
+async fn upnp(
+    gateway: &Gateway,
+    ip: Ipv4Addr,
+    port: u16,
+    lease: Duration,
+) -> io::Result<Multiaddr> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort>\
+         <NewProtocol>TCP</NewProtocol><NewInternalPort>{port}</NewInternalPort>\
+         <NewInternalClient>{ip}</NewInternalClient><NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>ipfs-embed</NewPortMappingDescription>\
+         <NewLeaseDuration>{lease}</NewLeaseDuration>",
+        port = port,
+        ip = ip,
+        lease = lease.as_secs(),
+    );
+    gateway.soap("AddPortMapping", &args).await?;
+    let response = gateway.soap("GetExternalIPAddress", "").await?;
+    let external: Ipv4Addr = element(&response, "NewExternalIPAddress", 0)
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| error("invalid external ip address"))?;
+    Ok(Multiaddr::empty()
+        .with(Protocol::Ip4(external))
+        .with(Protocol::Tcp(port)))
+}
+
+/// Removes the upnp mapping of `port`.
+async fn upnp_unmap(gateway: &Gateway, port: u16) -> io::Result<()> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+         <NewProtocol>TCP</NewProtocol>",
+        port
+    );
+    gateway.soap("DeletePortMapping", &args).await?;
+    Ok(())
+}
+
+/// Protocol a port was mapped with.
+#[derive(Clone, Copy, Debug)]
+enum Method {
+    NatPmp(Ipv4Addr),
+    Upnp,
+}
+
+struct Mapping {
+    listener: Multiaddr,
+    external: Multiaddr,
+    port: u16,
+    method: Method,
+}
+
+#[derive(Default)]
+struct State {
+    /// The upnp gateway, discovered once and kept until it fails.
+    gateway: Option<Arc<Gateway>>,
+    mappings: Vec<Mapping>,
+    closed: bool,
+}
+
+/// Maps the ports of the listeners on the gateway.
+#[derive(Clone)]
+pub(crate) struct PortMapper {
+    config: PortMapConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl PortMapper {
+    pub fn new(config: PortMapConfig) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns the upnp gateway. The gateway is discovered with ssdp at most once per
+    /// round of mappings, which `discovered` records.
+    async fn gateway(&self, discovered: &mut bool) -> io::Result<Arc<Gateway>> {
+        if let Some(gateway) = self.state.lock().gateway.clone() {
+            return Ok(gateway);
+        }
+        if *discovered {
+            return Err(error("no upnp gateway"));
+        }
+        *discovered = true;
+        let gateway = Arc::new(Gateway::discover().await?);
+        self.state.lock().gateway = Some(gateway.clone());
+        Ok(gateway)
+    }
+
+    /// Maps a port with the first protocol that succeeds.
+    async fn map(
+        &self,
+        ip: Ipv4Addr,
+        port: u16,
+        discovered: &mut bool,
+    ) -> io::Result<(Multiaddr, Method)> {
+        let mut res = Err(error("no protocol enabled"));
+        if self.config.nat_pmp {
+            if let Some(gateway) = self.config.gateway.or_else(default_gateway) {
+                match nat_pmp(gateway, port, self.config.lease).await {
+                    Ok(external) => return Ok((external, Method::NatPmp(gateway))),
+                    Err(err) => res = Err(err),
+                }
+            }
+        }
+        if self.config.upnp {
+            let gateway = self.gateway(discovered).await?;
+            match upnp(&gateway, ip, port, self.config.lease).await {
+                Ok(external) => return Ok((external, Method::Upnp)),
+                Err(err) => {
+                    // the gateway is rediscovered in the next round.
+                    self.state.lock().gateway = None;
+                    res = Err(err);
+                }
+            }
+        }
+        res
+    }
+
+    async fn unmap(&self, mapping: &Mapping) -> io::Result<()> {
+        match mapping.method {
+            Method::NatPmp(gateway) => {
+                nat_pmp(gateway, mapping.port, Duration::default()).await?;
+            }
+            Method::Upnp => {
+                let gateway = self.state.lock().gateway.clone();
+                let gateway = gateway.ok_or_else(|| error("no upnp gateway"))?;
+                upnp_unmap(&gateway, mapping.port).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps or renews the ports of the listeners on private addresses.
+    async fn map_listeners<P: StoreParams>(&self, service: &NetworkService<P>) {
+        let mut discovered = false;
+        for listener in service.listeners() {
+            let (ip, port) = match mappable(&listener) {
+                Some(mappable) => mappable,
+                None => continue,
+            };
+            match self.map(ip, port, &mut discovered).await {
+                Ok((external, method)) => {
+                    tracing::debug!("mapped {} to {}", listener, external);
+                    let mapping = Mapping {
+                        listener: listener.clone(),
+                        external: external.clone(),
+                        port,
+                        method,
+                    };
+                    let prev = {
+                        let mut state = self.state.lock();
+                        if state.closed {
+                            Err(mapping)
+                        } else {
+                            let i = state.mappings.iter().position(|m| m.listener == listener);
+                            match i {
+                                Some(i) => {
+                                    Ok(Some(std::mem::replace(&mut state.mappings[i], mapping)))
+                                }
+                                None => {
+                                    state.mappings.push(mapping);
+                                    Ok(None)
+                                }
+                            }
+                        }
+                    };
+                    let prev = match prev {
+                        Ok(prev) => prev,
+                        Err(mapping) => {
+                            // the mappings were removed while the port was mapped.
+                            self.unmap(&mapping).await.ok();
+                            return;
+                        }
+                    };
+                    if let Some(prev) = prev.filter(|prev| prev.external != external) {
+                        service.remove_port_mapping(&prev.external);
+                    }
+                    service.add_port_mapping(listener, external);
+                }
+                Err(err) => {
+                    tracing::debug!("mapping {} failed: {}", listener, err);
+                    service.notify(Event::PortMappingFailed(listener, err.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Removes all mappings from the gateway and stops renewing them.
+    pub async fn close<P: StoreParams>(&self, service: &NetworkService<P>) {
+        let mappings = {
+            let mut state = self.state.lock();
+            state.closed = true;
+            std::mem::take(&mut state.mappings)
+        };
+        for mapping in mappings {
+            service.remove_port_mapping(&mapping.external);
+            match self.unmap(&mapping).await {
+                Ok(()) => tracing::debug!("unmapped {}", mapping.listener),
+                Err(err) => tracing::debug!("unmapping {} failed: {}", mapping.listener, err),
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+/// Maps the ports of the listeners on private addresses and renews the mappings after
+/// half their lifetime. Listeners started later are mapped when they start listening.
+pub(crate) async fn run<P: StoreParams>(service: NetworkService<P>, mapper: PortMapper) {
+    let mut events = service.swarm_events();
+    let lease = mapper.config.lease;
+    while !mapper.is_closed() {
+        mapper.map_listeners(&service).await;
+        let renew = Timer::after(lease / 2);
+        let new_listener = async {
+            while let Some(event) = events.next().await {
+                if let Event::NewListenAddr(_) = event {
+                    return true;
+                }
+            }
+            false
+        };
+        futures::pin_mut!(new_listener);
+        if let Either::Left((false, _)) = future::select(new_listener, renew).await {
+            break;
+        }
+    }
+    mapper.close(&service).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mappable() {
+        let addr: Multiaddr = "/ip4/192.168.1.2/tcp/4001".parse().unwrap();
+        assert_eq!(mappable(&addr), Some((Ipv4Addr::new(192, 168, 1, 2), 4001)));
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        assert_eq!(mappable(&addr), None);
+        let addr: Multiaddr = "/ip4/192.168.1.2/udp/4001/quic".parse().unwrap();
+        assert_eq!(mappable(&addr), None);
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = "Iface\tDestination\tGateway \tFlags\n\
+                      eth0\t0001A8C0\t00000000\t0001\n\
+                      eth0\t00000000\t0101A8C0\t0003\n";
+        assert_eq!(parse_routes(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(parse_routes("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn test_nat_pmp_map_request() {
+        assert_eq!(
+            nat_pmp_map_request(4001, Duration::from_secs(3600)),
+            vec![0, 2, 0, 0, 0x0f, 0xa1, 0x0f, 0xa1, 0, 0, 0x0e, 0x10]
+        );
+        assert_eq!(
+            nat_pmp_map_request(4001, Duration::default()),
+            vec![0, 2, 0, 0, 0x0f, 0xa1, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let (addr, path) = parse_url("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(addr, "192.168.1.1:5000".parse().unwrap());
+        assert_eq!(path, "/rootDesc.xml");
+        let (addr, path) = parse_url("http://192.168.1.1").unwrap();
+        assert_eq!(addr, "192.168.1.1:80".parse().unwrap());
+        assert_eq!(path, "/");
+        assert!(parse_url("https://192.168.1.1/").is_none());
+    }
+
+    #[test]
+    fn test_element() {
+        let xml = "<service><serviceType>a</serviceType><controlURL>/a</controlURL></service>\
+                   <service><serviceType>b</serviceType><controlURL> /b </controlURL></service>";
+        assert_eq!(element(xml, "controlURL", 0), Some("/a"));
+        assert_eq!(
+            element(xml, "controlURL", xml.find('b').unwrap()),
+            Some("/b")
+        );
+        assert_eq!(element(xml, "eventSubURL", 0), None);
+    }
+}
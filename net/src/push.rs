@@ -0,0 +1,126 @@
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use libipld::Cid;
+use libp2p::core::upgrade::{read_one, read_varint, write_varint, write_with_len_prefix};
+use libp2p::core::ProtocolName;
+use libp2p::request_response::{OutboundFailure, RequestResponseCodec};
+use std::convert::TryFrom;
+use std::io;
+use thiserror::Error;
+
+/// Maximum size of an encoded cid.
+const MAX_CID_SIZE: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct PushProtocol;
+
+impl ProtocolName for PushProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/ipfs-embed/push/1.0.0"
+    }
+}
+
+/// Blocks pushed to a peer.
+pub type PushRequest = Vec<(Cid, Vec<u8>)>;
+
+/// Whether the peer accepted the pushed blocks.
+pub type PushResponse = bool;
+
+#[derive(Clone)]
+pub struct PushCodec {
+    max_blocks: usize,
+    max_block_size: usize,
+}
+
+impl PushCodec {
+    pub fn new(max_blocks: usize, max_block_size: usize) -> Self {
+        Self {
+            max_blocks,
+            max_block_size,
+        }
+    }
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[async_trait]
+impl RequestResponseCodec for PushCodec {
+    type Protocol = PushProtocol;
+    type Request = PushRequest;
+    type Response = PushResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let len = read_varint(io).await?;
+        if len > self.max_blocks {
+            return Err(invalid_data(TooManyBlocks(len)));
+        }
+        let mut blocks = Vec::with_capacity(len);
+        for _ in 0..len {
+            let cid = read_one(io, MAX_CID_SIZE).await.map_err(invalid_data)?;
+            let cid = Cid::try_from(cid).map_err(invalid_data)?;
+            let data = read_one(io, self.max_block_size)
+                .await
+                .map_err(invalid_data)?;
+            blocks.push((cid, data));
+        }
+        Ok(blocks)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let res = read_one(io, 1).await.map_err(invalid_data)?;
+        Ok(res == [1])
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        blocks: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_varint(io, blocks.len()).await?;
+        for (cid, data) in blocks {
+            write_with_len_prefix(io, cid.to_bytes()).await?;
+            write_with_len_prefix(io, data).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        accepted: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_with_len_prefix(io, [accepted as u8]).await
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("push of {0} blocks exceeds the limit")]
+pub struct TooManyBlocks(pub usize);
+
+#[derive(Debug, Error)]
+#[error("peer rejected the pushed blocks")]
+pub struct PushRejected;
+
+#[derive(Debug, Error)]
+#[error("{0:?}")]
+pub struct PushFailure(pub OutboundFailure);
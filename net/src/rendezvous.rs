@@ -0,0 +1,838 @@
+use async_trait::async_trait;
+use fnv::FnvHashMap;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use ipfs_embed_rt::Instant;
+use libp2p::core::identity::{Keypair, PublicKey};
+use libp2p::core::upgrade::{read_one, write_with_len_prefix};
+use libp2p::core::ProtocolName;
+use libp2p::request_response::{OutboundFailure, RequestResponseCodec};
+use libp2p::{Multiaddr, PeerId};
+use std::convert::TryFrom;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Maximum size of an encoded rendezvous message.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Maximum size of a signed peer record. Discover responses are paginated, so that they
+/// never exceed the `MAX_MESSAGE_SIZE`.
+const MAX_RECORD_SIZE: usize = 8 * 1024;
+/// Maximum number of registrations returned by a discover request.
+const MAX_DISCOVER_LIMIT: usize = 100;
+/// Maximum number of peers registered in a namespace.
+const MAX_REGISTRATIONS: usize = 1000;
+/// Maximum number of pages requested by a discovery.
+pub(crate) const MAX_DISCOVER_PAGES: usize = 100;
+/// Maximum number of addresses of a registered peer.
+const MAX_ADDRESSES: usize = 32;
+/// Maximum length of a namespace.
+const MAX_NAMESPACE_LEN: usize = 255;
+/// Ttl of a registration that doesn't ask for one.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60 * 2);
+/// Minimum ttl of a registration.
+const MIN_TTL: Duration = Duration::from_secs(120);
+/// Maximum ttl of a registration.
+const MAX_TTL: Duration = Duration::from_secs(60 * 60 * 72);
+
+/// Domain of the signature of a peer record envelope.
+const PEER_RECORD_DOMAIN: &[u8] = b"libp2p-routing-state";
+/// Multicodec `libp2p-peer-record` as an unsigned varint.
+const PEER_RECORD_PAYLOAD_TYPE: &[u8] = &[0x03, 0x01];
+
+const REGISTER: u64 = 0;
+const REGISTER_RESPONSE: u64 = 1;
+const UNREGISTER: u64 = 2;
+const DISCOVER: u64 = 3;
+const DISCOVER_RESPONSE: u64 = 4;
+
+/// Rendezvous protocol as specified in
+/// <https://github.com/libp2p/specs/blob/master/rendezvous/README.md>.
+#[derive(Clone, Debug)]
+pub struct RendezvousProtocol;
+
+impl ProtocolName for RendezvousProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/rendezvous/1.0.0"
+    }
+}
+
+/// Status of a rejected rendezvous request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RendezvousStatus {
+    InvalidNamespace,
+    InvalidSignedPeerRecord,
+    InvalidTtl,
+    InvalidCookie,
+    NotAuthorized,
+    InternalError,
+    Unavailable,
+}
+
+impl RendezvousStatus {
+    fn code(self) -> u64 {
+        match self {
+            Self::InvalidNamespace => 100,
+            Self::InvalidSignedPeerRecord => 101,
+            Self::InvalidTtl => 102,
+            Self::InvalidCookie => 103,
+            Self::NotAuthorized => 200,
+            Self::InternalError => 300,
+            Self::Unavailable => 400,
+        }
+    }
+
+    fn from_code(code: u64) -> Option<Self> {
+        Some(match code {
+            100 => Self::InvalidNamespace,
+            101 => Self::InvalidSignedPeerRecord,
+            102 => Self::InvalidTtl,
+            103 => Self::InvalidCookie,
+            200 => Self::NotAuthorized,
+            300 => Self::InternalError,
+            400 => Self::Unavailable,
+            _ => return None,
+        })
+    }
+}
+
+/// Addresses of a peer signed by the peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SignedPeerRecord {
+    peer: PeerId,
+    addresses: Vec<Multiaddr>,
+    envelope: Vec<u8>,
+}
+
+impl SignedPeerRecord {
+    /// Signs the `addresses` of the peer of `key`.
+    pub fn new(key: &Keypair, addresses: Vec<Multiaddr>) -> libipld::Result<Self> {
+        let peer = key.public().into_peer_id();
+        // the sequence number orders the records of a peer.
+        let seq = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut payload = Writer::default();
+        payload.bytes(1, &peer.to_bytes());
+        payload.varint(2, seq);
+        for addr in &addresses {
+            let mut info = Writer::default();
+            info.bytes(1, addr.as_ref());
+            payload.bytes(3, &info.0);
+        }
+        let signature = key.sign(&signing_buffer(&payload.0))?;
+        let mut envelope = Writer::default();
+        envelope.bytes(1, &key.public().into_protobuf_encoding());
+        envelope.bytes(2, PEER_RECORD_PAYLOAD_TYPE);
+        envelope.bytes(3, &payload.0);
+        envelope.bytes(5, &signature);
+        Ok(Self {
+            peer,
+            addresses,
+            envelope: envelope.0,
+        })
+    }
+
+    /// Decodes an envelope, verifying its signature.
+    fn from_envelope(envelope: &[u8]) -> io::Result<Self> {
+        if envelope.len() > MAX_RECORD_SIZE {
+            return Err(invalid_data("peer record too large"));
+        }
+        let (mut public_key, mut payload_type, mut payload, mut signature) =
+            (None, None, None, None);
+        for field in Reader(envelope) {
+            match field? {
+                (1, Value::Bytes(bytes)) => public_key = Some(bytes),
+                (2, Value::Bytes(bytes)) => payload_type = Some(bytes),
+                (3, Value::Bytes(bytes)) => payload = Some(bytes),
+                (5, Value::Bytes(bytes)) => signature = Some(bytes),
+                _ => {}
+            }
+        }
+        let public_key = PublicKey::from_protobuf_encoding(public_key.unwrap_or_default())
+            .map_err(invalid_data)?;
+        let payload = payload.unwrap_or_default();
+        if payload_type != Some(PEER_RECORD_PAYLOAD_TYPE) {
+            return Err(invalid_data("not a peer record"));
+        }
+        if !public_key.verify(&signing_buffer(payload), signature.unwrap_or_default()) {
+            return Err(invalid_data("invalid peer record signature"));
+        }
+        let mut peer = None;
+        let mut addresses = vec![];
+        for field in Reader(payload) {
+            match field? {
+                (1, Value::Bytes(bytes)) => {
+                    peer = Some(PeerId::from_bytes(bytes).map_err(invalid_data)?);
+                }
+                (3, Value::Bytes(info)) => {
+                    for field in Reader(info) {
+                        if let (1, Value::Bytes(addr)) = field? {
+                            addresses
+                                .push(Multiaddr::try_from(addr.to_vec()).map_err(invalid_data)?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let peer = peer
+            .filter(|peer| *peer == public_key.into_peer_id())
+            .ok_or_else(|| invalid_data("peer record of another peer"))?;
+        Ok(Self {
+            peer,
+            addresses,
+            envelope: envelope.to_vec(),
+        })
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer
+    }
+
+    pub fn addresses(&self) -> &[Multiaddr] {
+        &self.addresses
+    }
+}
+
+fn signing_buffer(payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    for part in &[PEER_RECORD_DOMAIN, PEER_RECORD_PAYLOAD_TYPE, payload] {
+        write_varint(&mut buf, part.len() as u64);
+        buf.extend_from_slice(part);
+    }
+    buf
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RendezvousRequest {
+    Register {
+        namespace: String,
+        record: SignedPeerRecord,
+        ttl: Option<Duration>,
+    },
+    Unregister {
+        namespace: String,
+    },
+    /// Discovers the registrations of `namespace`, or of all namespaces if it is empty.
+    /// The `cookie` of the previous response continues the discovery after the
+    /// registrations that were returned.
+    Discover {
+        namespace: String,
+        limit: Option<u64>,
+        cookie: Option<Vec<u8>>,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RendezvousResponse {
+    Registered {
+        ttl: Duration,
+    },
+    /// Unregister requests have no response, the stream is closed instead.
+    Unregistered,
+    Discovered {
+        registrations: Vec<Registration>,
+        cookie: Vec<u8>,
+    },
+    Rejected(RendezvousStatus, String),
+    DiscoverRejected(RendezvousStatus, String),
+}
+
+impl RendezvousResponse {
+    /// Rejects `request`.
+    pub fn reject(request: &RendezvousRequest, status: RendezvousStatus, reason: &str) -> Self {
+        match request {
+            RendezvousRequest::Register { .. } => Self::Rejected(status, reason.into()),
+            RendezvousRequest::Unregister { .. } => Self::Unregistered,
+            RendezvousRequest::Discover { .. } => Self::DiscoverRejected(status, reason.into()),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("rendezvous point rejected the request: {0}")]
+pub struct RendezvousRejected(pub String);
+
+#[derive(Debug, Error)]
+#[error("{0:?}")]
+pub struct RendezvousFailure(pub OutboundFailure);
+
+#[derive(Debug, Error)]
+#[error("unexpected rendezvous response")]
+pub struct UnexpectedResponse;
+
+/// A peer registered under a namespace.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Registration {
+    pub namespace: String,
+    pub record: SignedPeerRecord,
+    pub ttl: Duration,
+}
+
+struct Entry {
+    record: SignedPeerRecord,
+    expires: Instant,
+    seq: u64,
+}
+
+/// Peers registered at a rendezvous point.
+#[derive(Default)]
+pub struct Registrations {
+    namespaces: FnvHashMap<String, FnvHashMap<PeerId, Entry>>,
+    /// Orders the registrations, so that discover requests can continue after the last
+    /// registration they received.
+    seq: u64,
+}
+
+impl Registrations {
+    pub fn handle(&mut self, peer: PeerId, request: RendezvousRequest) -> RendezvousResponse {
+        use RendezvousStatus::*;
+        self.expire();
+        let reject = |status, reason| RendezvousResponse::reject(&request, status, reason);
+        match &request {
+            RendezvousRequest::Register { namespace, .. }
+            | RendezvousRequest::Unregister { namespace }
+                if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN =>
+            {
+                return reject(InvalidNamespace, "invalid namespace");
+            }
+            RendezvousRequest::Discover { namespace, .. }
+                if namespace.len() > MAX_NAMESPACE_LEN =>
+            {
+                return reject(InvalidNamespace, "invalid namespace");
+            }
+            _ => {}
+        }
+        match request {
+            RendezvousRequest::Register {
+                namespace,
+                record,
+                ttl,
+            } => {
+                let ttl = ttl.unwrap_or(DEFAULT_TTL);
+                if ttl < MIN_TTL || ttl > MAX_TTL {
+                    return RendezvousResponse::Rejected(InvalidTtl, "invalid ttl".into());
+                }
+                if *record.peer_id() != peer {
+                    return RendezvousResponse::Rejected(
+                        InvalidSignedPeerRecord,
+                        "peer record of another peer".into(),
+                    );
+                }
+                if record.addresses().len() > MAX_ADDRESSES {
+                    return RendezvousResponse::Rejected(
+                        InvalidSignedPeerRecord,
+                        "too many addresses".into(),
+                    );
+                }
+                let peers = self.namespaces.entry(namespace).or_default();
+                if !peers.contains_key(&peer) && peers.len() >= MAX_REGISTRATIONS {
+                    return RendezvousResponse::Rejected(Unavailable, "namespace is full".into());
+                }
+                self.seq += 1;
+                let entry = Entry {
+                    record,
+                    expires: Instant::now() + ttl,
+                    seq: self.seq,
+                };
+                peers.insert(peer, entry);
+                RendezvousResponse::Registered { ttl }
+            }
+            RendezvousRequest::Unregister { namespace } => {
+                if let Some(peers) = self.namespaces.get_mut(&namespace) {
+                    peers.remove(&peer);
+                }
+                RendezvousResponse::Unregistered
+            }
+            RendezvousRequest::Discover {
+                namespace,
+                limit,
+                cookie,
+            } => {
+                let after = match cookie
+                    .as_deref()
+                    .map(|cookie| decode_cookie(cookie, &namespace))
+                {
+                    Some(Some(seq)) => seq,
+                    Some(None) => {
+                        return RendezvousResponse::DiscoverRejected(
+                            InvalidCookie,
+                            "invalid cookie".into(),
+                        )
+                    }
+                    None => 0,
+                };
+                let mut found = self
+                    .namespaces
+                    .iter()
+                    .filter(|(ns, _)| namespace.is_empty() || **ns == namespace)
+                    .flat_map(|(ns, peers)| peers.values().map(move |entry| (ns, entry)))
+                    .filter(|(_, entry)| entry.seq > after)
+                    .collect::<Vec<_>>();
+                found.sort_by_key(|(_, entry)| entry.seq);
+                let limit = limit
+                    .map(|limit| limit as usize)
+                    .unwrap_or(MAX_DISCOVER_LIMIT)
+                    .min(MAX_DISCOVER_LIMIT);
+                let now = Instant::now();
+                let mut last = after;
+                let mut registrations = vec![];
+                // registrations are at most `MAX_RECORD_SIZE` large, so the response stays
+                // well below the `MAX_MESSAGE_SIZE`.
+                for (ns, entry) in found {
+                    if registrations.len() >= limit {
+                        break;
+                    }
+                    last = entry.seq;
+                    // the peer's own registration is skipped, but the cookie moves past it.
+                    if *entry.record.peer_id() == peer {
+                        continue;
+                    }
+                    registrations.push(Registration {
+                        namespace: ns.clone(),
+                        record: entry.record.clone(),
+                        ttl: entry.expires.saturating_duration_since(now),
+                    });
+                }
+                RendezvousResponse::Discovered {
+                    registrations,
+                    cookie: encode_cookie(last, &namespace),
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        for peers in self.namespaces.values_mut() {
+            peers.retain(|_, registration| registration.expires > now);
+        }
+        self.namespaces.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+fn encode_cookie(seq: u64, namespace: &str) -> Vec<u8> {
+    let mut cookie = seq.to_be_bytes().to_vec();
+    cookie.extend_from_slice(namespace.as_bytes());
+    cookie
+}
+
+fn decode_cookie(cookie: &[u8], namespace: &str) -> Option<u64> {
+    if cookie.len() < 8 || &cookie[8..] != namespace.as_bytes() {
+        return None;
+    }
+    let mut seq = [0; 8];
+    seq.copy_from_slice(&cookie[..8]);
+    Some(u64::from_be_bytes(seq))
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Writes protobuf fields.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn varint(&mut self, field: u64, n: u64) {
+        write_varint(&mut self.0, field << 3);
+        write_varint(&mut self.0, n);
+    }
+
+    fn bytes(&mut self, field: u64, bytes: &[u8]) {
+        write_varint(&mut self.0, field << 3 | 2);
+        write_varint(&mut self.0, bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn message(&mut self, field: u64, f: impl FnOnce(&mut Writer)) {
+        let mut msg = Writer::default();
+        f(&mut msg);
+        self.bytes(field, &msg.0);
+    }
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Reads protobuf fields. Fixed size fields are skipped.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid_data("unexpected end of message"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.take(1)?[0];
+            n |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid_data("invalid varint"))
+    }
+
+    fn field(&mut self) -> io::Result<Option<(u64, Value<'a>)>> {
+        loop {
+            if self.0.is_empty() {
+                return Ok(None);
+            }
+            let key = self.varint()?;
+            let value = match key & 7 {
+                0 => Value::Varint(self.varint()?),
+                1 => {
+                    self.take(8)?;
+                    continue;
+                }
+                2 => {
+                    let len = self.varint()?;
+                    if len > self.0.len() as u64 {
+                        return Err(invalid_data("invalid length"));
+                    }
+                    Value::Bytes(self.take(len as usize)?)
+                }
+                5 => {
+                    self.take(4)?;
+                    continue;
+                }
+                _ => return Err(invalid_data("invalid wire type")),
+            };
+            return Ok(Some((key >> 3, value)));
+        }
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = io::Result<(u64, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.field().transpose();
+        if let Some(Err(_)) = next {
+            self.0 = &[];
+        }
+        next
+    }
+}
+
+fn string(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(invalid_data)
+}
+
+fn encode_register(
+    w: &mut Writer,
+    namespace: &str,
+    record: &SignedPeerRecord,
+    ttl: Option<Duration>,
+) {
+    w.bytes(1, namespace.as_bytes());
+    w.bytes(2, &record.envelope);
+    if let Some(ttl) = ttl {
+        w.varint(3, ttl.as_secs());
+    }
+}
+
+fn decode_register(bytes: &[u8]) -> io::Result<(String, SignedPeerRecord, Option<Duration>)> {
+    let (mut namespace, mut record, mut ttl) = (String::new(), None, None);
+    for field in Reader(bytes) {
+        match field? {
+            (1, Value::Bytes(ns)) => namespace = string(ns)?,
+            (2, Value::Bytes(envelope)) => {
+                record = Some(SignedPeerRecord::from_envelope(envelope)?)
+            }
+            (3, Value::Varint(secs)) => ttl = Some(Duration::from_secs(secs)),
+            _ => {}
+        }
+    }
+    let record = record.ok_or_else(|| invalid_data("missing peer record"))?;
+    Ok((namespace, record, ttl))
+}
+
+fn encode_request(request: &RendezvousRequest) -> Vec<u8> {
+    let mut w = Writer::default();
+    match request {
+        RendezvousRequest::Register {
+            namespace,
+            record,
+            ttl,
+        } => {
+            w.varint(1, REGISTER);
+            w.message(2, |w| encode_register(w, namespace, record, *ttl));
+        }
+        RendezvousRequest::Unregister { namespace } => {
+            w.varint(1, UNREGISTER);
+            w.message(4, |w| w.bytes(1, namespace.as_bytes()));
+        }
+        RendezvousRequest::Discover {
+            namespace,
+            limit,
+            cookie,
+        } => {
+            w.varint(1, DISCOVER);
+            w.message(5, |w| {
+                if !namespace.is_empty() {
+                    w.bytes(1, namespace.as_bytes());
+                }
+                if let Some(limit) = limit {
+                    w.varint(2, *limit);
+                }
+                if let Some(cookie) = cookie {
+                    w.bytes(3, cookie);
+                }
+            });
+        }
+    }
+    w.0
+}
+
+/// Returns the type and the body of a message.
+fn decode_message(bytes: &[u8]) -> io::Result<(u64, &[u8])> {
+    let (mut ty, mut body) = (None, None);
+    for field in Reader(bytes) {
+        match field? {
+            (1, Value::Varint(t)) => ty = Some(t),
+            (2..=6, Value::Bytes(b)) => body = Some(b),
+            _ => {}
+        }
+    }
+    match (ty, body) {
+        (Some(ty), Some(body)) => Ok((ty, body)),
+        _ => Err(invalid_data("invalid rendezvous message")),
+    }
+}
+
+fn decode_request(bytes: &[u8]) -> io::Result<RendezvousRequest> {
+    let (ty, body) = decode_message(bytes)?;
+    Ok(match ty {
+        REGISTER => {
+            let (namespace, record, ttl) = decode_register(body)?;
+            RendezvousRequest::Register {
+                namespace,
+                record,
+                ttl,
+            }
+        }
+        UNREGISTER => {
+            let mut namespace = String::new();
+            for field in Reader(body) {
+                if let (1, Value::Bytes(ns)) = field? {
+                    namespace = string(ns)?;
+                }
+            }
+            RendezvousRequest::Unregister { namespace }
+        }
+        DISCOVER => {
+            let (mut namespace, mut limit, mut cookie) = (String::new(), None, None);
+            for field in Reader(body) {
+                match field? {
+                    (1, Value::Bytes(ns)) => namespace = string(ns)?,
+                    (2, Value::Varint(n)) => limit = Some(n),
+                    (3, Value::Bytes(c)) => cookie = Some(c.to_vec()),
+                    _ => {}
+                }
+            }
+            RendezvousRequest::Discover {
+                namespace,
+                limit,
+                cookie,
+            }
+        }
+        _ => return Err(invalid_data("unknown request")),
+    })
+}
+
+fn encode_response(response: &RendezvousResponse) -> Vec<u8> {
+    let mut w = Writer::default();
+    match response {
+        RendezvousResponse::Registered { ttl } => {
+            w.varint(1, REGISTER_RESPONSE);
+            w.message(3, |w| {
+                w.varint(1, 0);
+                w.varint(3, ttl.as_secs());
+            });
+        }
+        RendezvousResponse::Unregistered => {}
+        RendezvousResponse::Discovered {
+            registrations,
+            cookie,
+        } => {
+            w.varint(1, DISCOVER_RESPONSE);
+            w.message(6, |w| {
+                for registration in registrations {
+                    w.message(1, |w| {
+                        let ttl = Some(registration.ttl);
+                        encode_register(w, &registration.namespace, &registration.record, ttl)
+                    });
+                }
+                w.bytes(2, cookie);
+                w.varint(3, 0);
+            });
+        }
+        RendezvousResponse::Rejected(status, reason) => {
+            w.varint(1, REGISTER_RESPONSE);
+            w.message(3, |w| {
+                w.varint(1, status.code());
+                w.bytes(2, reason.as_bytes());
+            });
+        }
+        RendezvousResponse::DiscoverRejected(status, reason) => {
+            w.varint(1, DISCOVER_RESPONSE);
+            w.message(6, |w| {
+                w.varint(3, status.code());
+                w.bytes(4, reason.as_bytes());
+            });
+        }
+    }
+    w.0
+}
+
+/// Returns the status of a response, `None` if it is `OK`.
+fn decode_status(code: u64, text: String) -> io::Result<Option<(RendezvousStatus, String)>> {
+    if code == 0 {
+        return Ok(None);
+    }
+    let status = RendezvousStatus::from_code(code).ok_or_else(|| invalid_data("unknown status"))?;
+    Ok(Some((status, text)))
+}
+
+fn decode_response(bytes: &[u8]) -> io::Result<RendezvousResponse> {
+    if bytes.is_empty() {
+        return Ok(RendezvousResponse::Unregistered);
+    }
+    let (ty, body) = decode_message(bytes)?;
+    let (mut status, mut text) = (0, String::new());
+    Ok(match ty {
+        REGISTER_RESPONSE => {
+            let mut ttl = Duration::default();
+            for field in Reader(body) {
+                match field? {
+                    (1, Value::Varint(code)) => status = code,
+                    (2, Value::Bytes(t)) => text = string(t)?,
+                    (3, Value::Varint(secs)) => ttl = Duration::from_secs(secs),
+                    _ => {}
+                }
+            }
+            match decode_status(status, text)? {
+                Some((status, text)) => RendezvousResponse::Rejected(status, text),
+                None => RendezvousResponse::Registered { ttl },
+            }
+        }
+        DISCOVER_RESPONSE => {
+            let mut registrations = vec![];
+            let mut cookie = vec![];
+            for field in Reader(body) {
+                match field? {
+                    (1, Value::Bytes(registration)) => match decode_register(registration) {
+                        Ok((namespace, record, ttl)) => registrations.push(Registration {
+                            namespace,
+                            record,
+                            ttl: ttl.unwrap_or_default(),
+                        }),
+                        Err(err) => tracing::debug!("invalid rendezvous registration: {}", err),
+                    },
+                    (2, Value::Bytes(c)) => cookie = c.to_vec(),
+                    (3, Value::Varint(code)) => status = code,
+                    (4, Value::Bytes(t)) => text = string(t)?,
+                    _ => {}
+                }
+            }
+            match decode_status(status, text)? {
+                Some((status, text)) => RendezvousResponse::DiscoverRejected(status, text),
+                None => RendezvousResponse::Discovered {
+                    registrations,
+                    cookie,
+                },
+            }
+        }
+        _ => return Err(invalid_data("unknown response")),
+    })
+}
+
+#[derive(Clone, Default)]
+pub struct RendezvousCodec;
+
+#[async_trait]
+impl RequestResponseCodec for RendezvousCodec {
+    type Protocol = RendezvousProtocol;
+    type Request = RendezvousRequest;
+    type Response = RendezvousResponse;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_one(io, MAX_MESSAGE_SIZE).await.map_err(invalid_data)?;
+        decode_request(&bytes)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        // unregister requests are answered by closing the stream.
+        let mut buf = vec![];
+        io.take(MAX_MESSAGE_SIZE as u64 + 10)
+            .read_to_end(&mut buf)
+            .await?;
+        if buf.is_empty() {
+            return Ok(RendezvousResponse::Unregistered);
+        }
+        let mut r = Reader(&buf);
+        let len = r.varint()?;
+        if len > MAX_MESSAGE_SIZE as u64 || len != r.0.len() as u64 {
+            return Err(invalid_data("invalid length"));
+        }
+        decode_response(r.0)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        request: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_with_len_prefix(io, encode_request(&request)).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        response: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = encode_response(&response);
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        write_with_len_prefix(io, bytes).await
+    }
+}
@@ -0,0 +1,315 @@
+use crate::socks::Socks5Transport;
+#[cfg(feature = "trust-dns")]
+use async_std_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+#[cfg(feature = "trust-dns")]
+use async_std_resolver::AsyncStdResolver;
+use async_trait::async_trait;
+use fnv::FnvHashMap;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use ipfs_embed_rt::Instant;
+use libipld::Result;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::transport::{Transport, TransportError};
+use libp2p::Multiaddr;
+use parking_lot::Mutex;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Maximum number of names a `CachingResolver` keeps of each record type.
+const MAX_CACHED_NAMES: usize = 1024;
+
+/// Resolves the domain names of `/dns`, `/dns4` and `/dns6` addresses and the TXT
+/// records of dnslinks.
+#[async_trait]
+pub trait DnsResolver: Send + Sync + 'static {
+    /// Returns the ip addresses of `name`.
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>>;
+
+    /// Returns the TXT records of `name`. The strings of long records are joined.
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>>;
+}
+
+/// Resolves ip addresses with the resolver of the operating system, and TXT records with
+/// the name servers in the system configuration. The system configuration is read on the
+/// first TXT lookup and kept by all clones of the resolver.
+///
+/// Without the `trust-dns` feature TXT lookups fail.
+#[derive(Clone, Debug, Default)]
+pub struct SystemResolver {
+    #[cfg(feature = "trust-dns")]
+    txt: Arc<Mutex<Option<TrustDnsResolver>>>,
+}
+
+impl SystemResolver {
+    #[cfg(feature = "trust-dns")]
+    async fn txt_resolver(&self) -> Result<TrustDnsResolver> {
+        if let Some(resolver) = self.txt.lock().clone() {
+            return Ok(resolver);
+        }
+        // concurrent first lookups may both read the system configuration, the last one
+        // is kept.
+        let resolver = TrustDnsResolver::from_system_conf().await?;
+        *self.txt.lock() = Some(resolver.clone());
+        Ok(resolver)
+    }
+}
+
+#[async_trait]
+impl DnsResolver for SystemResolver {
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let name = name.to_string();
+        let addrs =
+            ipfs_embed_rt::spawn_blocking(move || (name.as_str(), 0).to_socket_addrs()).await?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+
+    #[cfg(feature = "trust-dns")]
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        self.txt_resolver().await?.lookup_txt(name).await
+    }
+
+    #[cfg(not(feature = "trust-dns"))]
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        Err(error(format!(
+            "can't look up the TXT records of {} without the trust-dns feature",
+            name
+        ))
+        .into())
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+async fn txt_lookup(resolver: &AsyncStdResolver, name: &str) -> Result<Vec<String>> {
+    let records = resolver.txt_lookup(name).await?;
+    Ok(records
+        .iter()
+        .map(|record| {
+            record
+                .txt_data()
+                .iter()
+                .map(|data| String::from_utf8_lossy(data))
+                .collect()
+        })
+        .collect())
+}
+
+/// Resolves names by querying name servers directly instead of relying on the resolver
+/// of the operating system, which is often broken on embedded devices. Answers are
+/// cached for their ttl.
+#[cfg(feature = "trust-dns")]
+#[derive(Clone)]
+pub struct TrustDnsResolver {
+    resolver: AsyncStdResolver,
+}
+
+#[cfg(feature = "trust-dns")]
+impl TrustDnsResolver {
+    /// Creates a resolver querying the name servers at `ips` on port 53.
+    pub async fn new(ips: &[IpAddr]) -> Result<Self> {
+        let servers = NameServerConfigGroup::from_ips_clear(ips, 53, true);
+        let config = ResolverConfig::from_parts(None, vec![], servers);
+        Self::with_config(config, ResolverOpts::default()).await
+    }
+
+    /// Creates a resolver querying the cloudflare name servers.
+    pub async fn cloudflare() -> Result<Self> {
+        Self::with_config(ResolverConfig::cloudflare(), ResolverOpts::default()).await
+    }
+
+    /// Creates a resolver querying the google name servers.
+    pub async fn google() -> Result<Self> {
+        Self::with_config(ResolverConfig::google(), ResolverOpts::default()).await
+    }
+
+    /// Creates a resolver from a trust-dns `config` and `opts`.
+    pub async fn with_config(config: ResolverConfig, opts: ResolverOpts) -> Result<Self> {
+        let resolver = async_std_resolver::resolver(config, opts).await?;
+        Ok(Self { resolver })
+    }
+
+    /// Creates a resolver querying the name servers of the system configuration.
+    pub async fn from_system_conf() -> Result<Self> {
+        let resolver = async_std_resolver::resolver_from_system_conf().await?;
+        Ok(Self { resolver })
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+#[async_trait]
+impl DnsResolver for TrustDnsResolver {
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        Ok(self.resolver.lookup_ip(name).await?.iter().collect())
+    }
+
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        txt_lookup(&self.resolver, name).await
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+impl std::fmt::Debug for TrustDnsResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TrustDnsResolver").finish()
+    }
+}
+
+struct Cache<T> {
+    entries: FnvHashMap<String, (Instant, Vec<T>)>,
+}
+
+impl<T: Clone> Cache<T> {
+    fn get(&self, name: &str, ttl: Duration) -> Option<Vec<T>> {
+        match self.entries.get(name) {
+            Some((cached, values)) if cached.elapsed() < ttl => Some(values.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, name: &str, values: Vec<T>, ttl: Duration) {
+        if self.entries.len() >= MAX_CACHED_NAMES {
+            self.entries.retain(|_, (cached, _)| cached.elapsed() < ttl);
+            if self.entries.len() >= MAX_CACHED_NAMES {
+                self.entries.clear();
+            }
+        }
+        self.entries
+            .insert(name.to_string(), (Instant::now(), values));
+    }
+}
+
+impl<T> Default for Cache<T> {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+        }
+    }
+}
+
+/// Caches the answers of a resolver that doesn't cache itself, like the `SystemResolver`,
+/// for a fixed `ttl`. Failed lookups aren't cached.
+pub struct CachingResolver<R> {
+    resolver: R,
+    ttl: Duration,
+    ips: Mutex<Cache<IpAddr>>,
+    txts: Mutex<Cache<String>>,
+}
+
+impl<R: DnsResolver> CachingResolver<R> {
+    pub fn new(resolver: R, ttl: Duration) -> Self {
+        Self {
+            resolver,
+            ttl,
+            ips: Default::default(),
+            txts: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<R: DnsResolver> DnsResolver for CachingResolver<R> {
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.ips.lock().get(name, self.ttl) {
+            return Ok(ips);
+        }
+        let ips = self.resolver.lookup_ip(name).await?;
+        self.ips.lock().insert(name, ips.clone(), self.ttl);
+        Ok(ips)
+    }
+
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        if let Some(txts) = self.txts.lock().get(name, self.ttl) {
+            return Ok(txts);
+        }
+        let txts = self.resolver.lookup_txt(name).await?;
+        self.txts.lock().insert(name, txts.clone(), self.ttl);
+        Ok(txts)
+    }
+}
+
+impl<R> std::fmt::Debug for CachingResolver<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CachingResolver")
+            .field("ttl", &self.ttl)
+            .field("ips", &self.ips.lock().entries.len())
+            .field("txts", &self.txts.lock().entries.len())
+            .finish()
+    }
+}
+
+fn error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+/// Tcp transport resolving the domain names of dns addresses with a `DnsResolver` before
+/// dialing. The resolved addresses are dialed one after the other until a dial succeeds.
+#[derive(Clone)]
+pub struct ResolverTransport {
+    inner: Socks5Transport,
+    resolver: Arc<dyn DnsResolver>,
+}
+
+impl ResolverTransport {
+    pub fn new(inner: Socks5Transport, resolver: Arc<dyn DnsResolver>) -> Self {
+        Self { inner, resolver }
+    }
+}
+
+impl Transport for ResolverTransport {
+    type Output = <Socks5Transport as Transport>::Output;
+    type Error = io::Error;
+    type Listener = <Socks5Transport as Transport>::Listener;
+    type ListenerUpgrade = <Socks5Transport as Transport>::ListenerUpgrade;
+    type Dial = BoxFuture<'static, io::Result<Self::Output>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        self.inner.listen_on(addr)
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let (name, v4, v6) = match addr.iter().next() {
+            Some(Protocol::Dns(name)) => (name.into_owned(), true, true),
+            Some(Protocol::Dns4(name)) => (name.into_owned(), true, false),
+            Some(Protocol::Dns6(name)) => (name.into_owned(), false, true),
+            _ => return self.inner.dial(addr),
+        };
+        Ok(async move {
+            let ips = self
+                .resolver
+                .lookup_ip(&name)
+                .await
+                .map_err(|err| error(format!("failed to resolve {}: {}", name, err)))?;
+            let mut last_err = error(format!("no addresses found for {}", name));
+            for ip in ips {
+                if (ip.is_ipv4() && !v4) || (ip.is_ipv6() && !v6) {
+                    continue;
+                }
+                let mut resolved = Multiaddr::empty();
+                resolved.push(Protocol::from(ip));
+                for protocol in addr.iter().skip(1) {
+                    resolved.push(protocol);
+                }
+                tracing::trace!("dialing {} resolved to {}", addr, resolved);
+                let res = match self.inner.clone().dial(resolved) {
+                    Ok(dial) => dial.await,
+                    Err(TransportError::MultiaddrNotSupported(addr)) => {
+                        Err(error(format!("unsupported address {}", addr)))
+                    }
+                    Err(TransportError::Other(err)) => Err(err),
+                };
+                match res {
+                    Ok(output) => return Ok(output),
+                    Err(err) => last_err = err,
+                }
+            }
+            Err(last_err)
+        }
+        .boxed())
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
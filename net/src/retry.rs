@@ -0,0 +1,159 @@
+#[cfg(feature = "dht")]
+use crate::behaviour::{
+    KadAddProviderError, KadBootstrapError, KadGetRecordError, KadPutRecordError,
+};
+use ipfs_embed_rt::Timer;
+use libipld::error::BlockNotFound;
+use libipld::Result;
+#[cfg(feature = "dht")]
+use libp2p::kad::{AddProviderError, BootstrapError, GetRecordError, PutRecordError};
+use std::future::Future;
+use std::time::Duration;
+
+/// Class of an error, used to decide if a failed request is retried.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ErrorClass {
+    /// No peer had the block or record, or too few peers stored a record.
+    NotFound,
+    /// The request timed out.
+    Timeout,
+    /// Any other error.
+    Other,
+}
+
+impl ErrorClass {
+    /// Classifies an error returned by a fetch or dht lookup.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<BlockNotFound>().is_some() {
+            return Self::NotFound;
+        }
+        #[cfg(feature = "dht")]
+        if let Some(class) = Self::of_kad(err) {
+            return class;
+        }
+        Self::Other
+    }
+
+    /// Classifies an error returned by a dht query.
+    #[cfg(feature = "dht")]
+    fn of_kad(err: &anyhow::Error) -> Option<Self> {
+        if let Some(KadGetRecordError(err)) = err.downcast_ref() {
+            return Some(match err {
+                GetRecordError::Timeout { .. } => Self::Timeout,
+                GetRecordError::NotFound { .. } | GetRecordError::QuorumFailed { .. } => {
+                    Self::NotFound
+                }
+            });
+        }
+        if let Some(KadPutRecordError(err)) = err.downcast_ref() {
+            return Some(match err {
+                PutRecordError::Timeout { .. } => Self::Timeout,
+                PutRecordError::QuorumFailed { .. } => Self::NotFound,
+            });
+        }
+        if let Some(KadAddProviderError(AddProviderError::Timeout { .. })) = err.downcast_ref() {
+            return Some(Self::Timeout);
+        }
+        if let Some(KadBootstrapError(BootstrapError::Timeout { .. })) = err.downcast_ref() {
+            return Some(Self::Timeout);
+        }
+        None
+    }
+}
+
+/// Delay between two attempts of a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backoff {
+    /// Waits the same duration after every attempt.
+    Constant(Duration),
+    /// Waits `n` times the duration after the `n`th attempt.
+    Linear(Duration),
+    /// Waits `initial` after the first attempt, doubling the delay after every further
+    /// attempt up to `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl Backoff {
+    /// Returns the delay after the failed attempt `attempt`, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Self::Constant(delay) => delay,
+            Self::Linear(delay) => delay * attempt,
+            Self::Exponential { initial, max } => {
+                let factor = 1u32
+                    .checked_shl(attempt.saturating_sub(1))
+                    .unwrap_or(u32::MAX);
+                std::cmp::min(initial.checked_mul(factor).unwrap_or(max), max)
+            }
+        }
+    }
+}
+
+/// Retry policy of bitswap fetches, dht lookups, dht writes and the dht bootstrap.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay between attempts.
+    pub backoff: Backoff,
+    /// Classes of errors that are retried. Other errors are returned immediately.
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl RetryPolicy {
+    /// Makes a single attempt.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Backoff::Constant(Duration::from_secs(0)),
+            retry_on: vec![],
+        }
+    }
+
+    /// Makes up to `max_attempts` attempts with exponential backoff, retrying when the
+    /// block or record wasn't found or the request timed out.
+    pub fn exponential(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            backoff: Backoff::Exponential {
+                initial: Duration::from_millis(500),
+                max: Duration::from_secs(30),
+            },
+            retry_on: vec![ErrorClass::NotFound, ErrorClass::Timeout],
+        }
+    }
+
+    /// Runs the request returned by `f` until it succeeds, fails with an error that isn't
+    /// retried or the attempts are exhausted. Returns the last error.
+    pub async fn run<T, F, Fut>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let err = match f().await {
+                Ok(res) => return Ok(res),
+                Err(err) => err,
+            };
+            if attempt >= self.max_attempts || !self.retry_on.contains(&ErrorClass::of(&err)) {
+                return Err(err);
+            }
+            let delay = self.backoff.delay(attempt);
+            tracing::debug!(
+                "attempt {} failed: {}, retrying in {:?}",
+                attempt,
+                err,
+                delay
+            );
+            Timer::after(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
@@ -0,0 +1,467 @@
+use crate::auth::AuthGate;
+use fnv::FnvHashMap;
+use ipfs_embed_rt::Instant;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Result};
+use libp2p::core::connection::{ConnectedPoint, ConnectionId, ListenerId};
+use libp2p::swarm::protocols_handler::{IntoProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::{Multiaddr, PeerId};
+use libp2p_bitswap::BitswapStore;
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntGauge};
+use std::cell::Cell;
+use std::error::Error;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Window in which the wants of a peer are counted.
+const PEER_WINDOW: Duration = Duration::from_secs(1);
+
+thread_local! {
+    static SENDER: Cell<Option<PeerId>> = Cell::new(None);
+}
+
+/// Returns the peer whose bitswap message is handled, if called by a `BitswapStore` while
+/// bitswap handles the message on the calling thread. Store calls bitswap makes from
+/// other threads aren't attributed to a peer.
+pub fn bitswap_sender() -> Option<PeerId> {
+    SENDER.with(|sender| sender.get())
+}
+
+/// A counting semaphore that never blocks. Reads are performed by the thread answering
+/// the wants, so a read that can't get a permit is dropped instead of waiting.
+struct Semaphore {
+    permits: AtomicUsize,
+    max: usize,
+}
+
+impl Semaphore {
+    fn new(max: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut active = self.permits.load(Ordering::Acquire);
+        loop {
+            if active >= self.max {
+                return None;
+            }
+            match self.permits.compare_exchange_weak(
+                active,
+                active + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(Permit(self)),
+                Err(current) => active = current,
+            }
+        }
+    }
+
+    fn active(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+}
+
+struct Permit<'a>(&'a Semaphore);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.permits.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Number of wants of a peer answered in the current window.
+struct PeerWants {
+    window: Instant,
+    wants: u32,
+}
+
+struct ReadLimiter {
+    reads: Semaphore,
+    peer_rate: u32,
+    peers: Mutex<FnvHashMap<PeerId, PeerWants>>,
+    dropped: IntCounter,
+    in_flight: IntGauge,
+    auth: AuthGate,
+}
+
+impl ReadLimiter {
+    /// Returns `false` if the sending peer wasn't accepted by the authenticator yet.
+    fn admit_auth(&self) -> bool {
+        match bitswap_sender() {
+            Some(peer) => self.auth.is_accepted(&peer),
+            None => true,
+        }
+    }
+
+    /// Counts a want of the sending peer, returning `false` if the peer exceeded its
+    /// rate.
+    fn admit_peer(&self) -> bool {
+        let peer = match bitswap_sender() {
+            Some(peer) => peer,
+            None => return true,
+        };
+        let now = Instant::now();
+        let mut peers = self.peers.lock();
+        let wants = peers.entry(peer).or_insert(PeerWants {
+            window: now,
+            wants: 0,
+        });
+        if now.duration_since(wants.window) >= PEER_WINDOW {
+            wants.window = now;
+            wants.wants = 0;
+        }
+        if wants.wants >= self.peer_rate {
+            return false;
+        }
+        wants.wants += 1;
+        true
+    }
+}
+
+/// Bitswap store that bounds the store reads used to answer wants. At most `max_reads`
+/// blocks are read concurrently, and each peer gets at most `peer_rate` wants answered
+/// per second. Wants exceeding the limits are answered as if the block wasn't stored, so
+/// that the peer asks someone else instead of delaying local reads. Peers that aren't
+/// authenticated yet are answered as if no block was stored.
+#[derive(Clone)]
+pub(crate) struct ServingStore<S> {
+    store: S,
+    limiter: Arc<ReadLimiter>,
+}
+
+impl<S> ServingStore<S> {
+    pub fn new(
+        store: S,
+        max_reads: usize,
+        peer_rate: u32,
+        dropped: IntCounter,
+        in_flight: IntGauge,
+        auth: AuthGate,
+    ) -> Self {
+        Self {
+            store,
+            limiter: Arc::new(ReadLimiter {
+                reads: Semaphore::new(std::cmp::max(max_reads, 1)),
+                peer_rate: std::cmp::max(peer_rate, 1),
+                peers: Default::default(),
+                dropped,
+                in_flight,
+                auth,
+            }),
+        }
+    }
+
+    /// Wraps the bitswap `behaviour`, so that the reads are attributed to peers.
+    pub fn scope<B>(&self, behaviour: B) -> PeerScope<B> {
+        PeerScope {
+            inner: behaviour,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+impl<P: StoreParams, S: BitswapStore<Params = P>> BitswapStore for ServingStore<S> {
+    type Params = P;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        if !self.limiter.admit_auth() {
+            return Ok(false);
+        }
+        self.store.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let limiter = &*self.limiter;
+        if !limiter.admit_auth() {
+            tracing::debug!("dropping want for {}: peer isn't authenticated", cid);
+            return Ok(None);
+        }
+        if !limiter.admit_peer() {
+            limiter.dropped.inc();
+            tracing::debug!("dropping want for {}: peer exceeded its rate", cid);
+            return Ok(None);
+        }
+        let _permit = match limiter.reads.try_acquire() {
+            Some(permit) => permit,
+            None => {
+                limiter.dropped.inc();
+                tracing::debug!("dropping want for {}: too many reads", cid);
+                return Ok(None);
+            }
+        };
+        limiter.in_flight.set(limiter.reads.active() as i64);
+        let res = self.store.get(cid);
+        limiter.in_flight.set(limiter.reads.active() as i64 - 1);
+        res
+    }
+
+    fn insert(&mut self, block: &Block<P>) -> Result<()> {
+        self.store.insert(block)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.store.missing_blocks(cid)
+    }
+}
+
+pub(crate) type HandlerIn<B> =
+    <<<B as NetworkBehaviour>::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent;
+pub(crate) type HandlerOut<B> =
+    <<<B as NetworkBehaviour>::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent;
+
+/// Attributes the store calls of the bitswap behaviour to the peer whose message it
+/// handles, see `bitswap_sender`. Reads that aren't attributed to a peer only count
+/// against the global limit.
+pub(crate) struct PeerScope<B> {
+    inner: B,
+    limiter: Arc<ReadLimiter>,
+}
+
+impl<B> Deref for PeerScope<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B> DerefMut for PeerScope<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+}
+
+impl<B: NetworkBehaviour> NetworkBehaviour for PeerScope<B> {
+    type ProtocolsHandler = B::ProtocolsHandler;
+    type OutEvent = B::OutEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.limiter.peers.lock().remove(peer_id);
+        self.limiter.auth.remove(peer_id);
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_established(peer_id, id, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner.inject_connection_closed(peer_id, id, endpoint)
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inner.inject_address_change(peer_id, id, old, new)
+    }
+
+    fn inject_event(&mut self, peer_id: PeerId, connection: ConnectionId, event: HandlerOut<B>) {
+        let previous = SENDER.with(|sender| sender.replace(Some(peer_id)));
+        self.inner.inject_event(peer_id, connection, event);
+        SENDER.with(|sender| sender.set(previous));
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn Error,
+    ) {
+        self.inner.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.inner.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_listen_addr(addr)
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_expired_listen_addr(addr)
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_external_addr(addr)
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn Error + 'static)) {
+        self.inner.inject_listener_error(id, err)
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        self.inner.inject_listener_closed(id, reason)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<HandlerIn<B>, B::OutEvent>> {
+        self.inner.poll(cx, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::multihash::Multihash;
+    use libipld::store::DefaultParams;
+
+    /// Store that has every block.
+    #[derive(Clone)]
+    struct Blocks;
+
+    impl BitswapStore for Blocks {
+        type Params = DefaultParams;
+
+        fn contains(&mut self, _: &Cid) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn get(&mut self, _: &Cid) -> Result<Option<Vec<u8>>> {
+            Ok(Some(b"block".to_vec()))
+        }
+
+        fn insert(&mut self, _: &Block<DefaultParams>) -> Result<()> {
+            Ok(())
+        }
+
+        fn missing_blocks(&mut self, _: &Cid) -> Result<Vec<Cid>> {
+            Ok(vec![])
+        }
+    }
+
+    fn cid(n: u8) -> Cid {
+        Cid::new_v1(0x55, Multihash::wrap(0x12, &[n; 32]).unwrap())
+    }
+
+    fn store(max_reads: usize, peer_rate: u32) -> ServingStore<Blocks> {
+        ServingStore::new(
+            Blocks,
+            max_reads,
+            peer_rate,
+            IntCounter::new("dropped", "dropped").unwrap(),
+            IntGauge::new("in_flight", "in_flight").unwrap(),
+            AuthGate::default(),
+        )
+    }
+
+    /// Calls `f` as if bitswap handled a message of `peer`.
+    fn from<T>(peer: Option<PeerId>, f: impl FnOnce() -> T) -> T {
+        SENDER.with(|sender| sender.set(peer));
+        let res = f();
+        SENDER.with(|sender| sender.set(None));
+        res
+    }
+
+    #[test]
+    fn test_semaphore() {
+        let semaphore = Semaphore::new(2);
+        let first = semaphore.try_acquire().unwrap();
+        let second = semaphore.try_acquire().unwrap();
+        assert_eq!(semaphore.active(), 2);
+        assert!(semaphore.try_acquire().is_none());
+        drop(first);
+        assert_eq!(semaphore.active(), 1);
+        let third = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        drop(second);
+        drop(third);
+        assert_eq!(semaphore.active(), 0);
+    }
+
+    #[test]
+    fn test_reads_exhausted() {
+        let mut store = store(1, 100);
+        let permit = store.limiter.reads.try_acquire().unwrap();
+        assert_eq!(store.get(&cid(0)).unwrap(), None);
+        assert_eq!(store.limiter.dropped.get(), 1);
+        drop(permit);
+        assert!(store.get(&cid(0)).unwrap().is_some());
+        assert_eq!(store.limiter.reads.active(), 0);
+        assert_eq!(store.limiter.in_flight.get(), 0);
+    }
+
+    #[test]
+    fn test_peer_rate() {
+        let mut store = store(10, 2);
+        let (limited, other) = (PeerId::random(), PeerId::random());
+        from(Some(limited), || {
+            assert!(store.get(&cid(0)).unwrap().is_some());
+            assert!(store.get(&cid(1)).unwrap().is_some());
+            assert_eq!(store.get(&cid(2)).unwrap(), None);
+        });
+        assert_eq!(store.limiter.dropped.get(), 1);
+
+        // other peers and unattributed reads aren't affected by the rate of the peer.
+        from(Some(other), || {
+            assert!(store.get(&cid(2)).unwrap().is_some())
+        });
+        for n in 0..10 {
+            assert!(store.get(&cid(n)).unwrap().is_some());
+        }
+
+        // the wants are counted again in the next window.
+        store.limiter.peers.lock().get_mut(&limited).unwrap().window -= PEER_WINDOW;
+        from(Some(limited), || {
+            assert!(store.get(&cid(2)).unwrap().is_some())
+        });
+        assert_eq!(store.limiter.dropped.get(), 1);
+    }
+
+    #[test]
+    fn test_unauthenticated_peer() {
+        let mut store = store(10, 100);
+        let peer = PeerId::random();
+        store.limiter.auth.set_enabled(true);
+        from(Some(peer), || {
+            assert!(!store.contains(&cid(0)).unwrap());
+            assert_eq!(store.get(&cid(0)).unwrap(), None);
+        });
+        assert!(store.get(&cid(0)).unwrap().is_some());
+        assert!(store.limiter.auth.begin(peer));
+        store.limiter.auth.accept(&peer);
+        from(Some(peer), || {
+            assert!(store.contains(&cid(0)).unwrap());
+            assert!(store.get(&cid(0)).unwrap().is_some());
+        });
+    }
+}
@@ -0,0 +1,69 @@
+use libipld::{Block, Cid, Result};
+use libp2p_bitswap::BitswapStore;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Number of received blocks whose sizes are remembered.
+const CAPACITY: usize = 1024;
+
+/// Store of bitswap remembering the sizes of the most recently received blocks, so that
+/// syncs limiting the bytes don't need to read every received block back from the store.
+#[derive(Clone)]
+pub struct SizeRecorder<S> {
+    store: S,
+    sizes: Arc<Mutex<VecDeque<(Cid, usize)>>>,
+}
+
+impl<S: BitswapStore> SizeRecorder<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            sizes: Default::default(),
+        }
+    }
+
+    /// Returns the size of a received block, reading it from the store if it was
+    /// received a while ago.
+    pub fn size(&mut self, cid: &Cid) -> Result<usize> {
+        let size = {
+            let mut sizes = self.sizes.lock();
+            let pos = sizes.iter().rposition(|(c, _)| c == cid);
+            pos.and_then(|pos| sizes.remove(pos)).map(|(_, size)| size)
+        };
+        match size {
+            Some(size) => Ok(size),
+            None => Ok(self
+                .store
+                .get(cid)?
+                .map(|data| data.len())
+                .unwrap_or_default()),
+        }
+    }
+}
+
+impl<S: BitswapStore> BitswapStore for SizeRecorder<S> {
+    type Params = S::Params;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.store.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.store.get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        self.store.insert(block)?;
+        let mut sizes = self.sizes.lock();
+        if sizes.len() >= CAPACITY {
+            sizes.pop_front();
+        }
+        sizes.push_back((*block.cid(), block.data().len()));
+        Ok(())
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.store.missing_blocks(cid)
+    }
+}
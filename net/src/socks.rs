@@ -0,0 +1,210 @@
+use crate::TcpConfig;
+use futures::future::BoxFuture;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::{FutureExt, TryFutureExt};
+use ipfs_embed_rt::TcpStream;
+use libp2p::core::either::EitherOutput;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::transport::{Transport, TransportError};
+use libp2p::Multiaddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Socks5 proxy configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Socks5Config {
+    /// Address of the proxy, `127.0.0.1:9050` for a local tor daemon.
+    pub proxy: SocketAddr,
+    /// Username and password, if the proxy requires authentication.
+    pub auth: Option<(String, String)>,
+    /// Sends domain names to the proxy instead of resolving them locally, so that dns
+    /// queries don't leak outside of the proxy.
+    pub remote_dns: bool,
+}
+
+impl Socks5Config {
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self {
+            proxy,
+            auth: None,
+            remote_dns: true,
+        }
+    }
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("socks5: {}", msg))
+}
+
+enum Host {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+/// Parses `/ip4|ip6|dns|dns4|dns6/<host>/tcp/<port>` with an optional trailing `/p2p`.
+fn parse(addr: &Multiaddr) -> Option<(Host, u16)> {
+    let mut iter = addr.iter();
+    let host = match iter.next()? {
+        Protocol::Ip4(ip) => Host::Ip(ip.into()),
+        Protocol::Ip6(ip) => Host::Ip(ip.into()),
+        Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+            Host::Domain(name.into_owned())
+        }
+        _ => return None,
+    };
+    let port = match iter.next()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    match iter.next() {
+        None | Some(Protocol::P2p(_)) => Some((host, port)),
+        _ => None,
+    }
+}
+
+async fn read_u8(stream: &mut TcpStream) -> io::Result<u8> {
+    let mut buf = [0];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf[0])
+}
+
+/// Connects to `host:port` through the proxy.
+async fn connect(config: Socks5Config, host: Host, port: u16) -> io::Result<TcpStream> {
+    let host = match host {
+        Host::Domain(name) if !config.remote_dns => {
+            let resolved =
+                ipfs_embed_rt::spawn_blocking(move || (name.as_str(), port).to_socket_addrs())
+                    .await?
+                    .next()
+                    .ok_or_else(|| error("failed to resolve host"))?;
+            Host::Ip(resolved.ip())
+        }
+        host => host,
+    };
+    let mut stream = TcpStream::connect(config.proxy).await?;
+    stream.set_nodelay(true)?;
+
+    let method = if config.auth.is_some() {
+        USER_PASS
+    } else {
+        NO_AUTH
+    };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    if read_u8(&mut stream).await? != VERSION {
+        return Err(error("unsupported version"));
+    }
+    match (read_u8(&mut stream).await?, config.auth.as_ref()) {
+        (NO_AUTH, _) => {}
+        (USER_PASS, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(error("username or password too long"));
+            }
+            let mut msg = vec![1, user.len() as u8];
+            msg.extend_from_slice(user.as_bytes());
+            msg.push(pass.len() as u8);
+            msg.extend_from_slice(pass.as_bytes());
+            stream.write_all(&msg).await?;
+            let _version = read_u8(&mut stream).await?;
+            if read_u8(&mut stream).await? != 0 {
+                return Err(error("authentication failed"));
+            }
+        }
+        (NO_ACCEPTABLE_METHOD, _) => return Err(error("no acceptable authentication method")),
+        _ => return Err(error("unexpected authentication method")),
+    }
+
+    let mut msg = vec![VERSION, CONNECT, 0];
+    match host {
+        Host::Ip(IpAddr::V4(ip)) => {
+            msg.push(IPV4);
+            msg.extend_from_slice(&ip.octets());
+        }
+        Host::Ip(IpAddr::V6(ip)) => {
+            msg.push(IPV6);
+            msg.extend_from_slice(&ip.octets());
+        }
+        Host::Domain(name) => {
+            if name.len() > 255 {
+                return Err(error("domain name too long"));
+            }
+            msg.push(DOMAIN);
+            msg.push(name.len() as u8);
+            msg.extend_from_slice(name.as_bytes());
+        }
+    }
+    msg.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&msg).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(error("unsupported version"));
+    }
+    if reply[1] != 0 {
+        return Err(error(&format!("connect failed with code {}", reply[1])));
+    }
+    // the bound address is of no use to us, but needs to be consumed.
+    let len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => read_u8(&mut stream).await? as usize,
+        _ => return Err(error("invalid address type")),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+/// Tcp transport dialing through a socks5 proxy when configured. Listening is not
+/// affected by the proxy.
+#[derive(Clone)]
+pub struct Socks5Transport {
+    tcp: TcpConfig,
+    config: Option<Socks5Config>,
+}
+
+impl Socks5Transport {
+    pub fn new(tcp: TcpConfig, config: Option<Socks5Config>) -> Self {
+        Self { tcp, config }
+    }
+}
+
+impl Transport for Socks5Transport {
+    type Output = EitherOutput<<TcpConfig as Transport>::Output, TcpStream>;
+    type Error = io::Error;
+    type Listener = <TcpConfig as Transport>::Listener;
+    type ListenerUpgrade = <TcpConfig as Transport>::ListenerUpgrade;
+    type Dial = BoxFuture<'static, io::Result<Self::Output>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        self.tcp.listen_on(addr)
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let config = match self.config {
+            Some(config) => config,
+            None => return Ok(self.tcp.dial(addr)?.map_ok(EitherOutput::First).boxed()),
+        };
+        let (host, port) = match parse(&addr) {
+            Some(target) => target,
+            None => return Err(TransportError::MultiaddrNotSupported(addr)),
+        };
+        tracing::trace!("dialing {} through {}", addr, config.proxy);
+        Ok(connect(config, host, port)
+            .map_ok(EitherOutput::Second)
+            .boxed())
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.tcp.address_translation(listen, observed)
+    }
+}
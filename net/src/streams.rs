@@ -0,0 +1,372 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::{mpsc, oneshot};
+use futures::future;
+use futures::io::{AsyncRead, AsyncWrite};
+use libipld::Result;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::upgrade::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::core::ProtocolName;
+use libp2p::swarm::protocols_handler::{
+    KeepAlive, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr, SubstreamProtocol,
+};
+use libp2p::swarm::{
+    DialPeerCondition, NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction,
+    NotifyHandler, PollParameters,
+};
+use libp2p::{Multiaddr, PeerId};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("failed to open stream: {0}")]
+pub struct StreamFailure(pub String);
+
+#[derive(Debug, Error)]
+#[error("invalid protocol name {0:?}, protocol names start with a /")]
+pub struct InvalidProtocolName(pub String);
+
+/// Name of an application protocol.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct AppProtocol(Arc<str>);
+
+impl AppProtocol {
+    pub fn new(name: &str) -> Result<Self> {
+        if !name.starts_with('/') {
+            return Err(InvalidProtocolName(name.into()).into());
+        }
+        Ok(Self(name.into()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl ProtocolName for AppProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// A stream of an application protocol, multiplexed over a connection to a peer.
+pub struct AppStream {
+    protocol: AppProtocol,
+    inner: NegotiatedSubstream,
+    _live: LiveStream,
+}
+
+/// Counts the streams of a connection that are alive, keeping the connection open until
+/// they are dropped.
+struct LiveStream(Arc<AtomicUsize>);
+
+impl LiveStream {
+    fn new(live: &Arc<AtomicUsize>) -> Self {
+        live.fetch_add(1, Ordering::SeqCst);
+        Self(live.clone())
+    }
+}
+
+impl Drop for LiveStream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl AppStream {
+    /// The protocol negotiated on the stream.
+    pub fn protocol(&self) -> &str {
+        self.protocol.name()
+    }
+}
+
+impl std::fmt::Debug for AppStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AppStream")
+            .field("protocol", &self.protocol)
+            .finish()
+    }
+}
+
+impl AsyncRead for AppStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AppStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+pub type StreamChannel = oneshot::Receiver<Result<AppStream>>;
+type StreamSender = oneshot::Sender<Result<AppStream>>;
+type Listeners = Arc<Mutex<FnvHashMap<AppProtocol, mpsc::UnboundedSender<(PeerId, AppStream)>>>>;
+
+/// Negotiates one of the application protocols.
+#[derive(Clone, Debug)]
+pub struct AppUpgrade {
+    protocols: Vec<AppProtocol>,
+    live: Arc<AtomicUsize>,
+}
+
+impl UpgradeInfo for AppUpgrade {
+    type Info = AppProtocol;
+    type InfoIter = Vec<AppProtocol>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.protocols.clone()
+    }
+}
+
+impl InboundUpgrade<NegotiatedSubstream> for AppUpgrade {
+    type Output = AppStream;
+    type Error = void::Void;
+    type Future = future::Ready<std::result::Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, inner: NegotiatedSubstream, protocol: AppProtocol) -> Self::Future {
+        future::ok(AppStream {
+            protocol,
+            inner,
+            _live: LiveStream::new(&self.live),
+        })
+    }
+}
+
+impl OutboundUpgrade<NegotiatedSubstream> for AppUpgrade {
+    type Output = AppStream;
+    type Error = void::Void;
+    type Future = future::Ready<std::result::Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, inner: NegotiatedSubstream, protocol: AppProtocol) -> Self::Future {
+        future::ok(AppStream {
+            protocol,
+            inner,
+            _live: LiveStream::new(&self.live),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct OpenStream(AppProtocol, StreamSender);
+
+pub struct StreamHandler {
+    listeners: Listeners,
+    outbound: VecDeque<OpenStream>,
+    inbound: VecDeque<AppStream>,
+    opening: usize,
+    live: Arc<AtomicUsize>,
+}
+
+impl ProtocolsHandler for StreamHandler {
+    type InEvent = OpenStream;
+    type OutEvent = AppStream;
+    type Error = void::Void;
+    type InboundProtocol = AppUpgrade;
+    type OutboundProtocol = AppUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = StreamSender;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        // only protocols with a listener are negotiated, so that peers can tell that the
+        // protocol isn't supported.
+        let mut listeners = self.listeners.lock();
+        listeners.retain(|_, tx| !tx.is_closed());
+        let protocols = listeners.keys().cloned().collect();
+        let upgrade = AppUpgrade {
+            protocols,
+            live: self.live.clone(),
+        };
+        SubstreamProtocol::new(upgrade, ())
+    }
+
+    fn inject_fully_negotiated_inbound(&mut self, stream: AppStream, _: ()) {
+        self.inbound.push_back(stream);
+    }
+
+    fn inject_fully_negotiated_outbound(&mut self, stream: AppStream, tx: StreamSender) {
+        self.opening -= 1;
+        tx.send(Ok(stream)).ok();
+    }
+
+    fn inject_event(&mut self, event: OpenStream) {
+        self.outbound.push_back(event);
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        tx: StreamSender,
+        error: ProtocolsHandlerUpgrErr<void::Void>,
+    ) {
+        self.opening -= 1;
+        tx.send(Err(StreamFailure(error.to_string()).into())).ok();
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.outbound.is_empty() && self.opening == 0 && self.live.load(Ordering::SeqCst) == 0 {
+            KeepAlive::No
+        } else {
+            KeepAlive::Yes
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn poll(
+        &mut self,
+        _cx: &mut Context,
+    ) -> Poll<ProtocolsHandlerEvent<AppUpgrade, StreamSender, AppStream, void::Void>> {
+        if let Some(stream) = self.inbound.pop_front() {
+            return Poll::Ready(ProtocolsHandlerEvent::Custom(stream));
+        }
+        if let Some(OpenStream(protocol, tx)) = self.outbound.pop_front() {
+            self.opening += 1;
+            let upgrade = AppUpgrade {
+                protocols: vec![protocol],
+                live: self.live.clone(),
+            };
+            return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(upgrade, tx),
+            });
+        }
+        Poll::Pending
+    }
+}
+
+/// Behaviour opening and accepting streams of application protocols.
+#[derive(Default)]
+pub struct AppStreams {
+    listeners: Listeners,
+    connected: FnvHashSet<PeerId>,
+    dialing: FnvHashMap<PeerId, Vec<OpenStream>>,
+    actions: VecDeque<NetworkBehaviourAction<OpenStream, void::Void>>,
+}
+
+impl AppStreams {
+    /// Opens a stream to `peer`, dialing the peer if it isn't connected.
+    pub fn open_stream(&mut self, peer: PeerId, protocol: AppProtocol) -> StreamChannel {
+        let (tx, rx) = oneshot::channel();
+        let open = OpenStream(protocol, tx);
+        if self.connected.contains(&peer) {
+            self.actions
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: peer,
+                    handler: NotifyHandler::Any,
+                    event: open,
+                });
+        } else {
+            self.dialing.entry(peer).or_default().push(open);
+            self.actions.push_back(NetworkBehaviourAction::DialPeer {
+                peer_id: peer,
+                condition: DialPeerCondition::Disconnected,
+            });
+        }
+        rx
+    }
+
+    /// Accepts inbound streams of `protocol`. Only the most recently returned receiver
+    /// receives streams.
+    pub fn listen(
+        &mut self,
+        protocol: AppProtocol,
+    ) -> mpsc::UnboundedReceiver<(PeerId, AppStream)> {
+        let (tx, rx) = mpsc::unbounded();
+        self.listeners.lock().insert(protocol, tx);
+        rx
+    }
+}
+
+impl NetworkBehaviour for AppStreams {
+    type ProtocolsHandler = StreamHandler;
+    type OutEvent = void::Void;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        StreamHandler {
+            listeners: self.listeners.clone(),
+            outbound: Default::default(),
+            inbound: Default::default(),
+            opening: 0,
+            live: Default::default(),
+        }
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        vec![]
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.connected.insert(*peer_id);
+        for open in self.dialing.remove(peer_id).unwrap_or_default() {
+            self.actions
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: *peer_id,
+                    handler: NotifyHandler::Any,
+                    event: open,
+                });
+        }
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.connected.remove(peer_id);
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        for OpenStream(_, tx) in self.dialing.remove(peer_id).unwrap_or_default() {
+            tx.send(Err(StreamFailure("dial failure".into()).into()))
+                .ok();
+        }
+    }
+
+    fn inject_event(&mut self, peer_id: PeerId, _connection: ConnectionId, stream: AppStream) {
+        let mut listeners = self.listeners.lock();
+        let res = match listeners.get(&stream.protocol) {
+            Some(tx) => tx
+                .unbounded_send((peer_id, stream))
+                .map_err(|err| err.into_inner().1),
+            None => Err(stream),
+        };
+        // the listener stopped after the protocol was negotiated. dropping the stream
+        // resets it, so the peer sees the stream failing instead of waiting for a reply.
+        if let Err(stream) = res {
+            tracing::debug!(
+                "no listener for {} stream of {}, resetting it",
+                stream.protocol(),
+                peer_id
+            );
+            listeners.remove(&stream.protocol);
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<OpenStream, void::Void>> {
+        if let Some(action) = self.actions.pop_front() {
+            Poll::Ready(action)
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::peers::Event;
+use crate::NetworkService;
+use futures::stream::StreamExt;
+use libipld::store::StoreParams;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::Multiaddr;
+
+/// Maps a listen address to the address it is reachable at from outside, for example a
+/// port published by a container runtime or a kubernetes node port. Needed when the
+/// mapping happens outside of the gateway, so port mapping and identify can't see it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AddressTranslation {
+    /// The listen address. An unspecified ip matches listen addresses on any ip and a tcp
+    /// port of 0 matches any port.
+    pub internal: Multiaddr,
+    /// The externally visible address. A tcp port of 0 is replaced with the port of the
+    /// listen address.
+    pub external: Multiaddr,
+}
+
+impl AddressTranslation {
+    /// Creates a new `AddressTranslation`.
+    pub fn new(internal: Multiaddr, external: Multiaddr) -> Self {
+        Self { internal, external }
+    }
+
+    /// Returns the external address of `listen` if the translation applies to it.
+    pub fn translate(&self, listen: &Multiaddr) -> Option<Multiaddr> {
+        let mut internal = self.internal.iter();
+        let mut port = None;
+        for protocol in listen.iter() {
+            match (internal.next()?, protocol) {
+                (Protocol::Ip4(pattern), Protocol::Ip4(_)) if pattern.is_unspecified() => {}
+                (Protocol::Ip6(pattern), Protocol::Ip6(_)) if pattern.is_unspecified() => {}
+                (Protocol::Tcp(0), Protocol::Tcp(p)) => port = Some(p),
+                (Protocol::Tcp(pattern), Protocol::Tcp(p)) if pattern == p => port = Some(p),
+                (pattern, protocol) if pattern == protocol => {}
+                _ => return None,
+            }
+        }
+        if internal.next().is_some() {
+            return None;
+        }
+        Some(
+            self.external
+                .iter()
+                .map(|protocol| match (protocol, port) {
+                    (Protocol::Tcp(0), Some(port)) => Protocol::Tcp(port),
+                    (protocol, _) => protocol,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Returns the external addresses of `listen`.
+fn translate(translations: &[AddressTranslation], listen: &Multiaddr) -> Vec<Multiaddr> {
+    translations
+        .iter()
+        .filter_map(|translation| translation.translate(listen))
+        .collect()
+}
+
+/// Announces the external addresses of the listeners as they start and stop listening.
+pub(crate) async fn run<P: StoreParams>(
+    service: NetworkService<P>,
+    translations: Vec<AddressTranslation>,
+) {
+    let mut events = service.swarm_events();
+    for listen in service.listeners() {
+        for external in translate(&translations, &listen) {
+            service.add_translated_address(listen.clone(), external);
+        }
+    }
+    while let Some(event) = events.next().await {
+        match event {
+            Event::NewListenAddr(listen) => {
+                for external in translate(&translations, &listen) {
+                    service.add_translated_address(listen.clone(), external);
+                }
+            }
+            Event::ExpiredListenAddr(listen) => {
+                for external in translate(&translations, &listen) {
+                    service.remove_translated_address(&external);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use fnv::FnvHashMap;
+use ipfs_embed_rt::Instant;
+use libp2p::{Multiaddr, PeerId};
+use std::time::Duration;
+
+/// Time after which the vote of a peer for an address expires, unless the peer reports
+/// the address again.
+const VOTE_TTL: Duration = Duration::from_secs(60 * 60);
+/// Interval at which expired votes are dropped, so that addresses are removed while no
+/// peer reports addresses.
+pub(crate) const VOTE_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Maximum number of addresses votes are kept for. Peers can report arbitrary addresses,
+/// so the addresses with the fewest votes are dropped.
+const MAX_ADDRESSES: usize = 64;
+
+/// An address of the node as observed by its peers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ObservedAddress {
+    /// The observed address.
+    pub addr: Multiaddr,
+    /// Number of distinct peers that observed the address within the last hour.
+    pub votes: usize,
+    /// If enough peers observed the address for it to be announced.
+    pub confirmed: bool,
+}
+
+/// Change of the confirmed addresses, which the swarm applies to its external addresses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum AddressChange {
+    /// The address was confirmed by the number of peers.
+    Confirmed(Multiaddr, usize),
+    /// The votes for a confirmed address expired.
+    Expired(Multiaddr),
+}
+
+#[derive(Default)]
+struct Votes {
+    peers: FnvHashMap<PeerId, Instant>,
+    confirmed: bool,
+}
+
+/// Aggregates the addresses peers observe the node at, confirming an address once
+/// `threshold` distinct peers observed it.
+pub struct AddressVotes {
+    threshold: usize,
+    votes: FnvHashMap<Multiaddr, Votes>,
+    /// Changes that weren't taken yet.
+    changes: Vec<AddressChange>,
+}
+
+impl AddressVotes {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold: std::cmp::max(threshold, 1),
+            votes: Default::default(),
+            changes: Default::default(),
+        }
+    }
+
+    /// Takes the changes of the confirmed addresses since the last call.
+    pub fn take_changes(&mut self) -> Vec<AddressChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Drops the expired votes, recording the confirmed addresses that lost their
+    /// confirmation.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for votes in self.votes.values_mut() {
+            votes
+                .peers
+                .retain(|_, seen| now.saturating_duration_since(*seen) < VOTE_TTL);
+        }
+        let threshold = self.threshold;
+        let changes = &mut self.changes;
+        self.votes.retain(|addr, votes| {
+            if votes.confirmed && votes.peers.len() < threshold {
+                votes.confirmed = false;
+                changes.push(AddressChange::Expired(addr.clone()));
+            }
+            !votes.peers.is_empty()
+        });
+    }
+
+    /// Records that `peer` observed the node at `addr`. Returns the number of votes if
+    /// the address was confirmed by this vote.
+    pub fn vote(&mut self, peer: PeerId, addr: Multiaddr) -> Option<usize> {
+        self.expire();
+        let now = Instant::now();
+        if !self.votes.contains_key(&addr) && self.votes.len() >= MAX_ADDRESSES {
+            let weakest = self
+                .votes
+                .iter()
+                .filter(|(_, votes)| !votes.confirmed)
+                .min_by_key(|(_, votes)| votes.peers.len())
+                .map(|(addr, _)| addr.clone());
+            match weakest {
+                Some(weakest) => {
+                    self.votes.remove(&weakest);
+                }
+                None => return None,
+            }
+        }
+        let votes = self.votes.entry(addr.clone()).or_default();
+        votes.peers.insert(peer, now);
+        if !votes.confirmed && votes.peers.len() >= self.threshold {
+            votes.confirmed = true;
+            let len = votes.peers.len();
+            self.changes.push(AddressChange::Confirmed(addr, len));
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the observed addresses, the addresses with the most votes first.
+    pub fn addresses(&mut self) -> Vec<ObservedAddress> {
+        self.expire();
+        let mut addrs = self
+            .votes
+            .iter()
+            .map(|(addr, votes)| ObservedAddress {
+                addr: addr.clone(),
+                votes: votes.peers.len(),
+                confirmed: votes.confirmed,
+            })
+            .collect::<Vec<_>>();
+        addrs.sort_by(|a, b| b.votes.cmp(&a.votes));
+        addrs
+    }
+}
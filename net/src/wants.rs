@@ -0,0 +1,240 @@
+use crate::behaviour::QueryId;
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::Cid;
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+type BitswapQueryId = libp2p_bitswap::QueryId;
+
+/// Priority of a get or sync query.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    /// Bulk transfers like background replication. Background blocks are requested
+    /// while no interactive blocks are in flight, with a bounded concurrency.
+    Background,
+    /// Requests blocking the user. Interactive blocks are requested immediately.
+    Interactive,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::Interactive
+    }
+}
+
+struct Want<I> {
+    id: Option<I>,
+    priority: Priority,
+    waiters: FnvHashSet<QueryId>,
+}
+
+/// Table of blocks requested via bitswap. It is shared between all get and sync queries,
+/// so that every block is requested at most once, regardless of how many queries are
+/// waiting for it.
+///
+/// Background wants are queued until they are started by the scheduler. Generic over the
+/// id of the bitswap query, so that it can be tested without a swarm.
+pub struct WantTable<I = BitswapQueryId> {
+    wants: FnvHashMap<Cid, Want<I>>,
+    ids: FnvHashMap<I, Cid>,
+    queue: VecDeque<Cid>,
+    in_flight: FnvHashMap<Priority, usize>,
+}
+
+impl<I> Default for WantTable<I> {
+    fn default() -> Self {
+        Self {
+            wants: Default::default(),
+            ids: Default::default(),
+            queue: Default::default(),
+            in_flight: Default::default(),
+        }
+    }
+}
+
+impl<I: Copy + Eq + Hash> WantTable<I> {
+    /// Adds a waiter to an existing want, raising the priority of the want. Returns
+    /// `false` if the block isn't wanted yet.
+    pub fn add_waiter(&mut self, cid: &Cid, waiter: QueryId, priority: Priority) -> bool {
+        if let Some(want) = self.wants.get_mut(cid) {
+            want.waiters.insert(waiter);
+            if priority > want.priority {
+                let started = want.id.is_some();
+                let prev = std::mem::replace(&mut want.priority, priority);
+                if started {
+                    self.finished(prev);
+                    self.started(priority);
+                }
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Adds a new want for a block requested by the bitswap query `id`.
+    pub fn insert(&mut self, cid: Cid, id: I, waiter: QueryId, priority: Priority) {
+        self.insert_want(cid, Some(id), waiter, priority);
+        self.ids.insert(id, cid);
+        self.started(priority);
+    }
+
+    /// Adds a new want that is requested once it is started.
+    pub fn enqueue(&mut self, cid: Cid, waiter: QueryId, priority: Priority) {
+        self.insert_want(cid, None, waiter, priority);
+        self.queue.push_back(cid);
+    }
+
+    fn insert_want(&mut self, cid: Cid, id: Option<I>, waiter: QueryId, priority: Priority) {
+        let mut waiters = FnvHashSet::default();
+        waiters.insert(waiter);
+        self.wants.insert(
+            cid,
+            Want {
+                id,
+                priority,
+                waiters,
+            },
+        );
+    }
+
+    /// Returns `true` if the want wasn't started yet.
+    pub fn is_queued(&self, cid: &Cid) -> bool {
+        self.wants
+            .get(cid)
+            .map(|want| want.id.is_none())
+            .unwrap_or_default()
+    }
+
+    /// Marks a queued want as requested by the bitswap query `id`.
+    pub fn start(&mut self, cid: &Cid, id: I) {
+        if let Some(want) = self.wants.get_mut(cid) {
+            if want.id.is_some() {
+                return;
+            }
+            want.id = Some(id);
+            let priority = want.priority;
+            self.ids.insert(id, *cid);
+            self.started(priority);
+        }
+    }
+
+    fn started(&mut self, priority: Priority) {
+        *self.in_flight.entry(priority).or_default() += 1;
+    }
+
+    fn finished(&mut self, priority: Priority) {
+        let in_flight = self.in_flight.entry(priority).or_default();
+        *in_flight = in_flight.saturating_sub(1);
+    }
+
+    /// Returns the next queued want.
+    pub fn next_queued(&mut self) -> Option<(Cid, Priority)> {
+        while let Some(cid) = self.queue.pop_front() {
+            if let Some(want) = self.wants.get(&cid) {
+                if want.id.is_none() {
+                    return Some((cid, want.priority));
+                }
+            }
+        }
+        None
+    }
+
+    /// Number of requested blocks.
+    pub fn requested(&self) -> usize {
+        self.in_flight.values().sum()
+    }
+
+    /// Number of wants that weren't started yet.
+    pub fn queued(&self) -> usize {
+        self.wants.len().saturating_sub(self.requested())
+    }
+
+    /// Number of requested blocks with `priority`.
+    pub fn in_flight(&self, priority: Priority) -> usize {
+        self.in_flight.get(&priority).copied().unwrap_or_default()
+    }
+
+    /// Removes a waiter from a want. Returns the bitswap query to cancel if no waiters
+    /// are left.
+    pub fn remove_waiter(&mut self, cid: &Cid, waiter: &QueryId) -> Option<I> {
+        let want = self.wants.get_mut(cid)?;
+        want.waiters.remove(waiter);
+        if want.waiters.is_empty() {
+            let want = self.wants.remove(cid)?;
+            let id = want.id?;
+            self.ids.remove(&id);
+            self.finished(want.priority);
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Removes the want of a completed bitswap query, returning the block and its waiters.
+    pub fn complete(&mut self, id: &I) -> Option<(Cid, FnvHashSet<QueryId>)> {
+        let cid = self.ids.remove(id)?;
+        let want = self.wants.remove(&cid)?;
+        self.finished(want.priority);
+        Some((cid, want.waiters))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::multihash::Multihash;
+
+    fn cid(n: u8) -> Cid {
+        Cid::new_v1(0x55, Multihash::wrap(0x12, &[n; 32]).unwrap())
+    }
+
+    /// Wants `cid` for `waiter` like the behaviour does, returning the bitswap query
+    /// that was started for it, if any.
+    fn want(
+        table: &mut WantTable<u64>,
+        next_id: &mut u64,
+        cid: Cid,
+        waiter: QueryId,
+    ) -> Option<u64> {
+        if table.add_waiter(&cid, waiter, Priority::Interactive) {
+            return None;
+        }
+        *next_id += 1;
+        table.insert(cid, *next_id, waiter, Priority::Interactive);
+        Some(*next_id)
+    }
+
+    #[test]
+    fn test_concurrent_syncs_share_want() {
+        let mut table = WantTable::default();
+        let mut next_id = 0;
+        let (sync1, sync2) = (QueryId::want(1), QueryId::want(2));
+        let id = want(&mut table, &mut next_id, cid(0), sync1).unwrap();
+        assert_eq!(want(&mut table, &mut next_id, cid(0), sync2), None);
+        assert_eq!(table.requested(), 1);
+        assert_eq!(table.queued(), 0);
+
+        let (block, waiters) = table.complete(&id).unwrap();
+        assert_eq!(block, cid(0));
+        assert_eq!(waiters.len(), 2);
+        assert!(waiters.contains(&sync1) && waiters.contains(&sync2));
+        assert_eq!(table.requested(), 0);
+        assert!(table.complete(&id).is_none());
+    }
+
+    #[test]
+    fn test_cancelled_sync_keeps_shared_want() {
+        let mut table = WantTable::default();
+        let mut next_id = 0;
+        let (sync1, sync2) = (QueryId::want(1), QueryId::want(2));
+        let id = want(&mut table, &mut next_id, cid(0), sync1).unwrap();
+        want(&mut table, &mut next_id, cid(0), sync2);
+
+        // the want is only cancelled once no sync is waiting for it.
+        assert_eq!(table.remove_waiter(&cid(0), &sync1), None);
+        assert_eq!(table.requested(), 1);
+        let (_, waiters) = table.complete(&id).unwrap();
+        assert_eq!(waiters.into_iter().collect::<Vec<_>>(), vec![sync2]);
+    }
+}
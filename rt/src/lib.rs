@@ -0,0 +1,416 @@
+//! Runtime used by the ipfs-embed crates to spawn tasks and wait for timers.
+//!
+//! All crates go through this module instead of using an executor directly, so that the
+//! runtime is selected in a single place with a cargo feature:
+//!
+//! - `async-global` (default): async-global-executor and async-io timers, which work
+//!   with async-std.
+//! - `tokio`: the tokio runtime the caller is running on, or a runtime started on first
+//!   use when called outside of one.
+//! - `wasm`: the browser event loop with gloo timers. Only available when compiling for
+//!   `wasm32`, on other targets the feature only switches the `Instant` clock.
+//!
+//! If several features are enabled, `tokio` takes precedence over `wasm`, which takes
+//! precedence over `async-global`. The sockets `TcpStream` and `UdpSocket` use tokio with
+//! the `tokio` feature and async-io with the `async-global` feature. The browser has no
+//! sockets, so they are not available with only the `wasm` feature.
+//!
+//! `std::time::Instant::now` panics on `wasm32-unknown-unknown`, so the crates measure
+//! time with the `Instant` exported here, which reads the browser clock with the `wasm`
+//! feature.
+#[cfg(feature = "tokio")]
+extern crate tokio_crate as tokio;
+
+use futures::future::{BoxFuture, FutureExt, RemoteHandle};
+#[cfg(any(feature = "tokio", feature = "async-global"))]
+use futures::io::{AsyncRead, AsyncWrite};
+use std::future::Future;
+#[cfg(any(feature = "tokio", feature = "async-global"))]
+use std::io;
+#[cfg(any(feature = "tokio", feature = "async-global"))]
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Monotonic clock that also works in the browser. It is `std::time::Instant` on all
+/// targets but `wasm32`.
+pub use instant::Instant;
+
+/// Spawns tasks and creates timers.
+pub trait Runtime {
+    /// Runs `fut` in the background until it completes.
+    fn spawn<F>(fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static;
+
+    /// Runs `f` where blocking is allowed and returns its result.
+    fn spawn_blocking<F, T>(f: F) -> BoxFuture<'static, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+
+    /// Completes after `duration`.
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// async-global-executor with async-io timers.
+#[cfg(feature = "async-global")]
+pub struct AsyncGlobal;
+
+#[cfg(feature = "async-global")]
+impl Runtime for AsyncGlobal {
+    fn spawn<F>(fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        async_global_executor::spawn(fut).detach();
+    }
+
+    fn spawn_blocking<F, T>(f: F) -> BoxFuture<'static, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        async_global_executor::spawn_blocking(f).boxed()
+    }
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        async_io::Timer::after(duration).map(|_| ()).boxed()
+    }
+}
+
+/// The tokio runtime. Tasks are spawned on the runtime the caller is running on. Outside
+/// of a runtime, they are spawned on a runtime started on first use.
+#[cfg(feature = "tokio")]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Tokio {
+    fn handle() -> tokio::runtime::Handle {
+        lazy_static::lazy_static! {
+            static ref RUNTIME: tokio::runtime::Runtime =
+                tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .thread_name("ipfs-embed-rt")
+                    .build()
+                    .expect("failed to start the tokio runtime");
+        }
+        tokio::runtime::Handle::try_current().unwrap_or_else(|_| RUNTIME.handle().clone())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Runtime for Tokio {
+    fn spawn<F>(fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self::handle().spawn(fut);
+    }
+
+    fn spawn_blocking<F, T>(f: F) -> BoxFuture<'static, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::handle()
+            .spawn_blocking(f)
+            .map(|res| res.unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic())))
+            .boxed()
+    }
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        let handle = Self::handle();
+        let _guard = handle.enter();
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+/// The browser event loop. There are no threads, so blocking functions run on the event
+/// loop when their future is polled. They need to return quickly, loops wait on a `Timer`
+/// between calls.
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub struct Wasm;
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+impl Runtime for Wasm {
+    fn spawn<F>(fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        wasm_bindgen_futures::spawn_local(fut);
+    }
+
+    fn spawn_blocking<F, T>(f: F) -> BoxFuture<'static, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        async move { f() }.boxed()
+    }
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        let millis = std::cmp::min(duration.as_millis(), u32::MAX as u128) as u32;
+        AssertSend(gloo_timers::future::TimeoutFuture::new(millis)).boxed()
+    }
+}
+
+/// Wraps a future that isn't `Send` because it holds javascript values.
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+struct AssertSend<F>(F);
+
+// wasm32 has a single thread, so the future is never moved to another thread.
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+unsafe impl<F> Send for AssertSend<F> {}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+impl<F: Future + Unpin> Future for AssertSend<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+/// Runtime selected by the cargo features.
+#[cfg(feature = "tokio")]
+pub type DefaultRuntime = Tokio;
+/// Runtime selected by the cargo features.
+#[cfg(all(feature = "wasm", target_arch = "wasm32", not(feature = "tokio")))]
+pub type DefaultRuntime = Wasm;
+/// Runtime selected by the cargo features.
+#[cfg(all(
+    feature = "async-global",
+    not(feature = "tokio"),
+    not(all(feature = "wasm", target_arch = "wasm32"))
+))]
+pub type DefaultRuntime = AsyncGlobal;
+
+#[cfg(not(any(
+    feature = "async-global",
+    feature = "tokio",
+    all(feature = "wasm", target_arch = "wasm32")
+)))]
+compile_error!(
+    "one of the features `async-global` or `tokio` needs to be enabled, or `wasm` when \
+     compiling for wasm32"
+);
+
+/// A spawned task. The task is cancelled when the handle is dropped, unless it was
+/// detached.
+#[must_use = "the task is cancelled when dropped, use `detach` to run it in the background"]
+pub struct Task<T>(RemoteHandle<T>);
+
+impl<T> Task<T> {
+    /// Keeps the task running after the handle is dropped.
+    pub fn detach(self) {
+        self.0.forget()
+    }
+}
+
+impl<T: 'static> Future for Task<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+/// Spawns `fut` on the `DefaultRuntime`.
+pub fn spawn<F>(fut: F) -> Task<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (fut, handle) = fut.remote_handle();
+    DefaultRuntime::spawn(fut);
+    Task(handle)
+}
+
+/// Runs the blocking function `f` on the `DefaultRuntime`.
+pub fn spawn_blocking<F, T>(f: F) -> BoxFuture<'static, T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    DefaultRuntime::spawn_blocking(f)
+}
+
+/// A timer of the `DefaultRuntime`. Unlike `async_io::Timer` it completes with `()`.
+pub struct Timer(BoxFuture<'static, ()>);
+
+impl Timer {
+    /// Completes after `duration`.
+    pub fn after(duration: Duration) -> Self {
+        Self(DefaultRuntime::sleep(duration))
+    }
+
+    /// Completes at `instant`, or immediately if it passed.
+    pub fn at(instant: Instant) -> Self {
+        Self::after(instant.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// A tcp stream of the runtime, implementing the futures io traits.
+#[cfg(any(feature = "tokio", feature = "async-global"))]
+pub struct TcpStream(TcpInner);
+
+#[cfg(feature = "tokio")]
+type TcpInner = tokio::net::TcpStream;
+#[cfg(all(feature = "async-global", not(feature = "tokio")))]
+type TcpInner = async_io::Async<std::net::TcpStream>;
+
+#[cfg(feature = "tokio")]
+impl TcpStream {
+    /// Connects to `addr`.
+    pub async fn connect<A: Into<SocketAddr>>(addr: A) -> io::Result<Self> {
+        // connecting on the runtime registers the socket with its reactor, which keeps
+        // driving it when the stream is polled from another executor.
+        let stream = Tokio::handle()
+            .spawn(tokio::net::TcpStream::connect(addr.into()))
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
+        Ok(Self(stream))
+    }
+
+    /// Enables or disables `TCP_NODELAY`.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.0.set_nodelay(nodelay)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        futures::ready!(tokio::io::AsyncRead::poll_read(
+            Pin::new(&mut self.0),
+            cx,
+            &mut buf
+        ))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+    }
+}
+
+#[cfg(all(feature = "async-global", not(feature = "tokio")))]
+impl TcpStream {
+    /// Connects to `addr`.
+    pub async fn connect<A: Into<SocketAddr>>(addr: A) -> io::Result<Self> {
+        Ok(Self(
+            async_io::Async::<std::net::TcpStream>::connect(addr).await?,
+        ))
+    }
+
+    /// Enables or disables `TCP_NODELAY`.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.0.get_ref().set_nodelay(nodelay)
+    }
+}
+
+#[cfg(all(feature = "async-global", not(feature = "tokio")))]
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(all(feature = "async-global", not(feature = "tokio")))]
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+/// A udp socket of the runtime.
+#[cfg(any(feature = "tokio", feature = "async-global"))]
+pub struct UdpSocket(UdpInner);
+
+#[cfg(feature = "tokio")]
+type UdpInner = tokio::net::UdpSocket;
+#[cfg(all(feature = "async-global", not(feature = "tokio")))]
+type UdpInner = async_io::Async<std::net::UdpSocket>;
+
+#[cfg(any(feature = "tokio", feature = "async-global"))]
+impl UdpSocket {
+    /// Binds a socket to `addr`.
+    pub fn bind<A: Into<SocketAddr>>(addr: A) -> io::Result<Self> {
+        Self::from_std(std::net::UdpSocket::bind(addr.into())?)
+    }
+
+    /// Sends `buf` to `addr`.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.0.send_to(buf, addr).await
+    }
+
+    /// Receives a datagram into `buf`, returning its length and sender.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf).await
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl UdpSocket {
+    /// Uses a socket that was bound and configured by the caller.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        let handle = Tokio::handle();
+        let _guard = handle.enter();
+        Ok(Self(tokio::net::UdpSocket::from_std(socket)?))
+    }
+}
+
+#[cfg(all(feature = "async-global", not(feature = "tokio")))]
+impl UdpSocket {
+    /// Uses a socket that was bound and configured by the caller.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        Ok(Self(async_io::Async::new(socket)?))
+    }
+}
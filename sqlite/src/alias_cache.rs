@@ -0,0 +1,210 @@
+use fnv::FnvHashMap;
+use ipfs_embed_rt::Instant;
+use libipld::Cid;
+use parking_lot::Mutex;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Maximum number of entries of each map. Once full, the expired entries are dropped, or
+/// all of them if none expired.
+const MAX_ENTRIES: usize = 4096;
+
+struct Entries<K, V> {
+    map: FnvHashMap<K, (Instant, V)>,
+}
+
+impl<K: Eq + Hash, V: Clone> Entries<K, V> {
+    fn get(&self, key: &K, ttl: Duration) -> Option<V> {
+        match self.map.get(key) {
+            Some((cached, value)) if cached.elapsed() < ttl => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V, ttl: Duration) {
+        if self.map.len() >= MAX_ENTRIES {
+            self.map.retain(|_, (cached, _)| cached.elapsed() < ttl);
+            if self.map.len() >= MAX_ENTRIES {
+                self.map.clear();
+            }
+        }
+        self.map.insert(key, (Instant::now(), value));
+    }
+}
+
+impl<K, V> Default for Entries<K, V> {
+    fn default() -> Self {
+        Self {
+            map: Default::default(),
+        }
+    }
+}
+
+/// Coalesces concurrent misses of the same key.
+struct Misses<K> {
+    keys: Mutex<FnvHashMap<K, Arc<Mutex<()>>>>,
+}
+
+impl<K: Eq + Hash + Clone> Misses<K> {
+    /// Runs `miss` after the misses of `key` in flight completed.
+    fn coalesce<T>(&self, key: &K, miss: impl FnOnce() -> T) -> T {
+        let lock = self.keys.lock().entry(key.clone()).or_default().clone();
+        let res = {
+            let _miss = lock.lock();
+            miss()
+        };
+        let mut keys = self.keys.lock();
+        // references are only taken under the map lock, so no miss is waiting when the
+        // map and this miss hold the last ones.
+        if Arc::strong_count(&lock) == 2 {
+            keys.remove(key);
+        }
+        res
+    }
+}
+
+impl<K> Default for Misses<K> {
+    fn default() -> Self {
+        Self {
+            keys: Default::default(),
+        }
+    }
+}
+
+/// Caches the results of `resolve` and `reverse_alias`.
+///
+/// Concurrent misses of the same key are coalesced, the first miss queries the store
+/// while the others wait for it and then find its result cached.
+///
+/// Alias writes invalidate both. Inserted blocks invalidate the reverse aliases, since
+/// a block filling a hole in an aliased dag makes its descendants reachable from the
+/// alias. Deleted blocks are not reachable from an alias, so only their own entry is
+/// dropped. Each kind of entry has a generation that is bumped before the entries are
+/// dropped, a lookup only caches its result if the generation didn't change while it
+/// queried the store.
+pub(crate) struct AliasCache {
+    ttl: Option<Duration>,
+    aliases: AtomicU64,
+    blocks: AtomicU64,
+    resolve: Mutex<Entries<Vec<u8>, Option<Cid>>>,
+    reverse: Mutex<Entries<Cid, Option<Vec<Vec<u8>>>>>,
+    resolve_misses: Misses<Vec<u8>>,
+    reverse_misses: Misses<Cid>,
+}
+
+impl AliasCache {
+    /// Creates a cache whose entries expire after `ttl`, a ttl of `None` disables it.
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            aliases: Default::default(),
+            blocks: Default::default(),
+            resolve: Default::default(),
+            reverse: Default::default(),
+            resolve_misses: Default::default(),
+            reverse_misses: Default::default(),
+        }
+    }
+
+    /// Returns the cached root of `alias`, or queries it with `query`.
+    pub fn resolve<E>(
+        &self,
+        alias: &[u8],
+        query: impl FnOnce() -> Result<Option<Cid>, E>,
+    ) -> Result<Option<Cid>, E> {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return query(),
+        };
+        let alias = alias.to_vec();
+        if let Some(cid) = self.resolve.lock().get(&alias, ttl) {
+            return Ok(cid);
+        }
+        self.resolve_misses.coalesce(&alias, || {
+            if let Some(cid) = self.resolve.lock().get(&alias, ttl) {
+                return Ok(cid);
+            }
+            let generation = self.aliases.load(Ordering::SeqCst);
+            let cid = query()?;
+            let mut resolve = self.resolve.lock();
+            if self.aliases.load(Ordering::SeqCst) == generation {
+                resolve.insert(alias.clone(), cid, ttl);
+            }
+            Ok(cid)
+        })
+    }
+
+    /// Returns the cached aliases keeping `cid` alive, or queries them with `query`.
+    pub fn reverse_alias<E>(
+        &self,
+        cid: &Cid,
+        query: impl FnOnce() -> Result<Option<Vec<Vec<u8>>>, E>,
+    ) -> Result<Option<Vec<Vec<u8>>>, E> {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return query(),
+        };
+        if let Some(aliases) = self.reverse.lock().get(cid, ttl) {
+            return Ok(aliases);
+        }
+        self.reverse_misses.coalesce(cid, || {
+            if let Some(aliases) = self.reverse.lock().get(cid, ttl) {
+                return Ok(aliases);
+            }
+            let generation = self.generation();
+            let aliases = query()?;
+            let mut reverse = self.reverse.lock();
+            if self.generation() == generation {
+                reverse.insert(*cid, aliases.clone(), ttl);
+            }
+            Ok(aliases)
+        })
+    }
+
+    fn generation(&self) -> (u64, u64) {
+        (
+            self.aliases.load(Ordering::SeqCst),
+            self.blocks.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Drops the entries after an alias changed.
+    pub fn alias_changed(&self) {
+        if self.ttl.is_none() {
+            return;
+        }
+        self.aliases.fetch_add(1, Ordering::SeqCst);
+        self.resolve.lock().map.clear();
+        self.reverse.lock().map.clear();
+    }
+
+    /// Drops the reverse aliases after blocks were inserted.
+    pub fn blocks_inserted(&self) {
+        if self.ttl.is_none() {
+            return;
+        }
+        self.blocks.fetch_add(1, Ordering::SeqCst);
+        self.reverse.lock().map.clear();
+    }
+
+    /// Drops the reverse aliases of a deleted block.
+    pub fn block_deleted(&self, cid: &Cid) {
+        if self.ttl.is_none() {
+            return;
+        }
+        self.blocks.fetch_add(1, Ordering::SeqCst);
+        self.reverse.lock().map.remove(cid);
+    }
+}
+
+impl std::fmt::Debug for AliasCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AliasCache")
+            .field("ttl", &self.ttl)
+            .field("resolve", &self.resolve.lock().map.len())
+            .field("reverse", &self.reverse.lock().map.len())
+            .finish()
+    }
+}
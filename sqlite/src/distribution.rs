@@ -0,0 +1,99 @@
+use crate::BLOCK_SIZE;
+use libipld::Cid;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Number and size of the blocks of a codec and hash inserted and deleted.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlockCounts {
+    /// Number of inserted blocks that weren't stored already.
+    pub inserted: u64,
+    /// Size in bytes of the inserted blocks.
+    pub inserted_bytes: u64,
+    /// Number of blocks deleted by the garbage collector.
+    pub deleted: u64,
+    /// Size in bytes of the deleted blocks.
+    pub deleted_bytes: u64,
+}
+
+/// Blocks inserted in to and deleted from the store, by codec and multihash code. The
+/// counts are updated on insert and gc, the stored blocks are never scanned. They are
+/// saved to the store every `stats_interval` and on `flush`, so a crash loses the counts
+/// since the last save.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BlockDistribution {
+    /// Counts keyed by the codec and the multihash code of the blocks.
+    pub blocks: BTreeMap<(u64, u64), BlockCounts>,
+}
+
+impl BlockDistribution {
+    /// Returns the counts of the blocks of `codec`.
+    pub fn codec(&self, codec: u64) -> BlockCounts {
+        self.sum(|(c, _)| *c == codec)
+    }
+
+    /// Returns the counts of the blocks hashed with the multihash `code`.
+    pub fn hash(&self, code: u64) -> BlockCounts {
+        self.sum(|(_, h)| *h == code)
+    }
+
+    fn sum(&self, f: impl Fn(&(u64, u64)) -> bool) -> BlockCounts {
+        self.blocks.iter().filter(|(key, _)| f(key)).fold(
+            BlockCounts::default(),
+            |acc, (_, counts)| BlockCounts {
+                inserted: acc.inserted + counts.inserted,
+                inserted_bytes: acc.inserted_bytes + counts.inserted_bytes,
+                deleted: acc.deleted + counts.deleted,
+                deleted_bytes: acc.deleted_bytes + counts.deleted_bytes,
+            },
+        )
+    }
+}
+
+/// Records the inserted and deleted blocks for the metrics.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DistributionRecorder(Arc<Mutex<BlockDistribution>>);
+
+impl DistributionRecorder {
+    fn counts<F: FnOnce(&mut BlockCounts)>(&self, cid: &Cid, f: F) {
+        let key = (cid.codec(), cid.hash().code());
+        f(self.0.lock().blocks.entry(key).or_default());
+    }
+
+    pub fn inserted(&self, cid: &Cid, size: usize) {
+        BLOCK_SIZE
+            .with_label_values(&["insert"])
+            .observe(size as f64);
+        self.counts(cid, |counts| {
+            counts.inserted += 1;
+            counts.inserted_bytes += size as u64;
+        });
+    }
+
+    pub fn deleted(&self, cid: &Cid, size: usize) {
+        BLOCK_SIZE
+            .with_label_values(&["delete"])
+            .observe(size as f64);
+        self.counts(cid, |counts| {
+            counts.deleted += 1;
+            counts.deleted_bytes += size as u64;
+        });
+    }
+
+    pub fn distribution(&self) -> BlockDistribution {
+        self.0.lock().clone()
+    }
+
+    /// Adds the counts saved before the store was opened.
+    pub fn restore(&self, saved: BlockDistribution) {
+        let mut distribution = self.0.lock();
+        for (key, saved) in saved.blocks {
+            let counts = distribution.blocks.entry(key).or_default();
+            counts.inserted += saved.inserted;
+            counts.inserted_bytes += saved.inserted_bytes;
+            counts.deleted += saved.deleted;
+            counts.deleted_bytes += saved.deleted_bytes;
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use fnv::FnvHashMap;
+use futures::stream::Stream;
+use libipld::Cid;
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntGauge};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+/// Event of the block store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StorageEvent {
+    /// A block was removed by the garbage collector.
+    Remove(Cid),
+    /// The subscriber fell behind and missed the given number of events. Caches keyed
+    /// by cid need to be invalidated completely.
+    Lagged(u64),
+}
+
+#[derive(Debug)]
+struct Log {
+    /// Sequence number of the first buffered event.
+    first: u64,
+    events: VecDeque<Cid>,
+    capacity: usize,
+    next_id: u64,
+    /// Sequence number of the next event of each subscriber.
+    cursors: FnvHashMap<u64, u64>,
+    wakers: Vec<Waker>,
+}
+
+impl Log {
+    fn end(&self) -> u64 {
+        self.first + self.events.len() as u64
+    }
+}
+
+/// Buffer of the last `capacity` storage events shared by all subscribers.
+///
+/// Pushing never blocks the garbage collector. Once the buffer is full the oldest event
+/// is dropped, and subscribers that didn't receive it yet get a `Lagged` event instead.
+#[derive(Debug)]
+pub(crate) struct EventLog {
+    log: Mutex<Log>,
+    queued: IntGauge,
+    lagged: IntCounter,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize, queued: IntGauge, lagged: IntCounter) -> Self {
+        Self {
+            log: Mutex::new(Log {
+                first: 0,
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+                next_id: 0,
+                cursors: Default::default(),
+                wakers: vec![],
+            }),
+            queued,
+            lagged,
+        }
+    }
+
+    pub fn push(&self, cid: Cid) {
+        let mut log = self.log.lock();
+        if log.capacity == 0 {
+            return;
+        }
+        if log.events.len() >= log.capacity {
+            log.events.pop_front();
+            log.first += 1;
+        }
+        log.events.push_back(cid);
+        self.update_queued(&log);
+        for waker in log.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Returns the sequence number of the next event.
+    pub fn position(&self) -> u64 {
+        self.log.lock().end()
+    }
+
+    /// Sets the gauge to the number of events the slowest subscriber didn't receive.
+    fn update_queued(&self, log: &Log) {
+        let end = log.end();
+        let behind = log
+            .cursors
+            .values()
+            .map(|next| end.saturating_sub(*next))
+            .max()
+            .unwrap_or_default();
+        self.queued.set(behind as i64);
+    }
+}
+
+/// Subscription to the storage events.
+///
+/// Events are buffered by the block store, so a subscription can start at an earlier
+/// sequence number than the current position as long as the events weren't dropped yet.
+pub struct StorageEvents {
+    log: Arc<EventLog>,
+    id: u64,
+    next: u64,
+}
+
+impl StorageEvents {
+    pub(crate) fn new(log: Arc<EventLog>, next: u64) -> Self {
+        let id = {
+            let mut inner = log.log.lock();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.cursors.insert(id, next);
+            log.update_queued(&inner);
+            id
+        };
+        Self { log, id, next }
+    }
+
+    /// Returns the sequence number of the next event of the subscription.
+    pub fn position(&self) -> u64 {
+        self.next
+    }
+}
+
+impl Stream for StorageEvents {
+    type Item = StorageEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let mut log = this.log.log.lock();
+        let event = if this.next < log.first {
+            let missed = log.first - this.next;
+            this.log.lagged.inc_by(missed);
+            this.next = log.first;
+            StorageEvent::Lagged(missed)
+        } else if this.next < log.end() {
+            let cid = log.events[(this.next - log.first) as usize];
+            this.next += 1;
+            StorageEvent::Remove(cid)
+        } else {
+            if !log.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                log.wakers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        };
+        log.cursors.insert(this.id, this.next);
+        this.log.update_queued(&log);
+        Poll::Ready(Some(event))
+    }
+}
+
+impl Drop for StorageEvents {
+    fn drop(&mut self) {
+        let mut log = self.log.log.lock();
+        log.cursors.remove(&self.id);
+        self.log.update_queued(&log);
+    }
+}
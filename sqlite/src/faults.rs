@@ -0,0 +1,97 @@
+use libipld::Result;
+use parking_lot::Mutex;
+use rusqlite::ffi;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Transient error injected by the `FaultInjector`.
+#[derive(Debug, Error)]
+#[error("injected storage fault")]
+pub struct InjectedFault;
+
+#[derive(Debug, Default)]
+struct FaultState {
+    latency: Duration,
+    fail_next: usize,
+    fail_every: usize,
+    disk_full: bool,
+    ops: usize,
+    injected: usize,
+}
+
+/// Injects latency and errors into storage operations, to test how an application
+/// behaves on a degraded disk.
+///
+/// Every block and alias operation sleeps for the configured latency on the calling
+/// thread. Transient failures return `InjectedFault`, while a full disk fails writes with
+/// the error sqlite returns when it runs out of space. Reads keep working on a full disk.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    state: Mutex<FaultState>,
+}
+
+impl FaultInjector {
+    /// Delays every operation by `latency`.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().latency = latency;
+    }
+
+    /// Fails the next `n` operations.
+    pub fn fail_next(&self, n: usize) {
+        self.state.lock().fail_next = n;
+    }
+
+    /// Fails every `n`th operation. When set to 0 no operations fail.
+    pub fn fail_every(&self, n: usize) {
+        self.state.lock().fail_every = n;
+    }
+
+    /// Fails writes as if the disk was full.
+    pub fn set_disk_full(&self, full: bool) {
+        self.state.lock().disk_full = full;
+    }
+
+    /// Removes all faults.
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        let injected = state.injected;
+        *state = FaultState {
+            injected,
+            ..Default::default()
+        };
+    }
+
+    /// Returns the number of injected errors.
+    pub fn injected(&self) -> usize {
+        self.state.lock().injected
+    }
+
+    pub(crate) fn inject(&self, write: bool) -> Result<()> {
+        let (latency, res) = {
+            let mut state = self.state.lock();
+            state.ops += 1;
+            let res = if write && state.disk_full {
+                Err(rusqlite::Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_FULL),
+                    Some("database or disk is full".into()),
+                )
+                .into())
+            } else if state.fail_next > 0 {
+                state.fail_next -= 1;
+                Err(InjectedFault.into())
+            } else if state.fail_every > 0 && state.ops % state.fail_every == 0 {
+                Err(InjectedFault.into())
+            } else {
+                Ok(())
+            };
+            if res.is_err() {
+                state.injected += 1;
+            }
+            (state.latency, res)
+        };
+        if latency > Duration::from_secs(0) {
+            std::thread::sleep(latency);
+        }
+        res
+    }
+}
@@ -0,0 +1,151 @@
+use crate::meta::{checkpoint, MetaStore};
+use crate::shard::Shards;
+use ipfs_embed_rt::Instant;
+use ipfs_sqlite_block_store::BlockStore;
+use libipld::Result;
+use parking_lot::{Condvar, Mutex};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Error returned when pending writes don't finish before the freeze timeout.
+#[derive(Debug, Error)]
+#[error("failed to freeze writes within {0:?}")]
+pub struct FreezeTimeout(pub Duration);
+
+/// Keeps the store frozen until it is dropped.
+///
+/// While the guard is held the database files are not modified, so they can be copied
+/// by an external backup tool. Writes, including garbage collection, wait until the
+/// guard is dropped, while reads continue.
+pub struct FreezeGuard {
+    gate: Arc<WriteGate>,
+}
+
+impl Drop for FreezeGuard {
+    fn drop(&mut self) {
+        self.gate.open();
+    }
+}
+
+thread_local! {
+    /// Number of writes the current thread is in, so that nested writes don't wait for
+    /// a freeze that waits for the outer write.
+    static WRITING: Cell<usize> = Cell::new(0);
+}
+
+#[derive(Default)]
+struct GateState {
+    frozen: bool,
+    writers: usize,
+}
+
+/// Gate the writers of the store pass. Freezing closes the gate, which makes new writes
+/// wait without blocking the connections for reads, and waits for the writes in flight.
+#[derive(Default)]
+pub(crate) struct WriteGate {
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+/// Counts a write as in flight until it is dropped.
+pub(crate) struct Writing<'a> {
+    gate: &'a WriteGate,
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        WRITING.with(|writing| writing.set(writing.get() - 1));
+        let mut state = self.gate.state.lock();
+        state.writers -= 1;
+        if state.writers == 0 {
+            self.gate.changed.notify_all();
+        }
+    }
+}
+
+impl WriteGate {
+    /// Waits while the store is frozen and returns a guard counting the write as in
+    /// flight.
+    pub fn enter(&self) -> Writing<'_> {
+        let nested = WRITING.with(|writing| {
+            writing.set(writing.get() + 1);
+            writing.get() > 1
+        });
+        let mut state = self.state.lock();
+        while state.frozen && !nested {
+            self.changed.wait(&mut state);
+        }
+        state.writers += 1;
+        Writing { gate: self }
+    }
+
+    /// Closes the gate and waits for the writes in flight. Returns `false` and leaves the
+    /// gate open if the store is still frozen or writing at the `deadline`.
+    fn close(&self, deadline: Instant) -> bool {
+        let mut state = self.state.lock();
+        while state.frozen {
+            if self
+                .changed
+                .wait_for(&mut state, remaining(deadline))
+                .timed_out()
+            {
+                return false;
+            }
+        }
+        state.frozen = true;
+        while state.writers > 0 {
+            if self
+                .changed
+                .wait_for(&mut state, remaining(deadline))
+                .timed_out()
+            {
+                state.frozen = false;
+                self.changed.notify_all();
+                return false;
+            }
+        }
+        true
+    }
+
+    fn open(&self) {
+        self.state.lock().frozen = false;
+        self.changed.notify_all();
+    }
+}
+
+/// Returns the time left until `deadline`. Waits take a timeout instead of the deadline,
+/// since the `Instant` of the runtime isn't the one of the standard library on wasm32.
+fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+/// Closes the write gate, waiting up to `timeout` for the pending writes, and checkpoints
+/// the write ahead logs. The store stays frozen until the guard is dropped.
+pub(crate) async fn freeze(
+    gate: Arc<WriteGate>,
+    store: Arc<Mutex<BlockStore>>,
+    meta: Arc<Mutex<MetaStore>>,
+    shards: Option<Arc<Shards>>,
+    timeout: Duration,
+) -> Result<FreezeGuard> {
+    ipfs_embed_rt::spawn_blocking(move || {
+        let deadline = Instant::now() + timeout;
+        if !gate.close(deadline) {
+            return Err(FreezeTimeout(timeout).into());
+        }
+        // reopens the gate if checkpointing fails.
+        let guard = FreezeGuard { gate };
+        store.lock().flush()?;
+        meta.lock().checkpoint()?;
+        if let Some(shards) = shards.as_deref() {
+            let shards = shards.lock_until(deadline).ok_or(FreezeTimeout(timeout))?;
+            for shard in &shards {
+                checkpoint(shard)?;
+            }
+        }
+        Ok(guard)
+    })
+    .await
+}
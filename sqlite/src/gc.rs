@@ -0,0 +1,383 @@
+use crate::stats::StoreStats;
+use arc_swap::ArcSwap;
+use futures::channel::oneshot;
+use ipfs_embed_rt::{Instant, Timer};
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Interval at which a deferred gc pass checks if the syncs completed.
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counts the syncs in flight.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ActiveSyncs(Arc<AtomicUsize>);
+
+impl ActiveSyncs {
+    pub fn begin(&self) -> SyncGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        SyncGuard(self.0.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Marks a sync as in flight until it is dropped. The garbage collector defers its passes
+/// while syncs are in flight, see `GcConfig::sync_deferral`.
+#[derive(Debug)]
+pub struct SyncGuard(Arc<AtomicUsize>);
+
+impl Drop for SyncGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+struct Throttle {
+    /// Factor the gc interval is stretched by, 0 pauses the garbage collector.
+    factor: u32,
+    resumed: Vec<oneshot::Sender<()>>,
+}
+
+/// Stretches or pauses the gc loop, see `StorageService::throttle_gc`.
+#[derive(Clone, Default)]
+pub(crate) struct GcThrottle(Arc<Mutex<Throttle>>);
+
+impl GcThrottle {
+    pub fn new() -> Self {
+        let throttle = Self::default();
+        throttle.set(Some(1));
+        throttle
+    }
+
+    pub fn set(&self, factor: Option<u32>) {
+        let mut throttle = self.0.lock();
+        throttle.factor = factor.map(|factor| std::cmp::max(factor, 1)).unwrap_or(0);
+        if throttle.factor > 0 {
+            for tx in throttle.resumed.drain(..) {
+                tx.send(()).ok();
+            }
+        }
+    }
+
+    /// Returns `interval` stretched by the throttle factor, waiting while the garbage
+    /// collector is paused.
+    pub async fn stretch(&self, interval: Duration) -> Duration {
+        loop {
+            let resumed = {
+                let mut throttle = self.0.lock();
+                if throttle.factor > 0 {
+                    return interval
+                        .checked_mul(throttle.factor)
+                        .unwrap_or_else(|| Duration::from_secs(u64::MAX));
+                }
+                let (tx, rx) = oneshot::channel();
+                throttle.resumed.push(tx);
+                rx
+            };
+            resumed.await.ok();
+        }
+    }
+}
+
+/// Conditions that run the garbage collector before the `gc_interval` elapsed. Whichever
+/// trigger fires first starts a run.
+///
+/// A trigger that fired doesn't fire again until its measurement recovered by
+/// `hysteresis_percent` of its threshold, to avoid running the gc continuously when it
+/// can't free enough blocks.
+///
+/// The store size and block count are taken from the statistics sampled every
+/// `stats_interval`, so checking the triggers never touches the database.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GcTriggers {
+    /// Runs the gc when the store size exceeds this percentage of the `cache_size_bytes`.
+    pub size_percent: Option<u8>,
+    /// Runs the gc when the free space of the disk holding the store falls below this
+    /// number of bytes.
+    pub min_free_disk: Option<u64>,
+    /// Runs the gc when the number of blocks exceeds this threshold.
+    pub max_blocks: Option<u64>,
+    /// Percentage of a threshold a measurement needs to recover before the trigger is
+    /// armed again.
+    pub hysteresis_percent: u8,
+    /// Interval at which the triggers are checked. The store size and block count only
+    /// change every `stats_interval`.
+    pub check_interval: Duration,
+}
+
+impl GcTriggers {
+    /// No triggers, the gc only runs at the `gc_interval`.
+    pub fn none() -> Self {
+        Self {
+            size_percent: None,
+            min_free_disk: None,
+            max_blocks: None,
+            hysteresis_percent: 10,
+            check_interval: Duration::from_secs(1),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.size_percent.is_none() && self.min_free_disk.is_none() && self.max_blocks.is_none()
+    }
+}
+
+impl Default for GcTriggers {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+/// A trigger with hysteresis.
+#[derive(Clone, Copy)]
+struct Trigger {
+    armed: bool,
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Self { armed: true }
+    }
+}
+
+impl Trigger {
+    /// Fires when `fire` is true and the trigger is armed. The trigger is armed again once
+    /// `rearm` is true.
+    fn update(&mut self, fire: bool, rearm: bool) -> bool {
+        if self.armed && fire {
+            self.armed = false;
+            return true;
+        }
+        if !self.armed && rearm {
+            self.armed = true;
+        }
+        false
+    }
+}
+
+fn percent(n: u64, p: u8) -> u64 {
+    (n as u128 * p as u128 / 100) as u64
+}
+
+/// Evaluates the `GcTriggers` of the gc loop.
+pub(crate) struct GcTriggerState {
+    path: Option<PathBuf>,
+    stats: Arc<ArcSwap<StoreStats>>,
+    cache_size_bytes: u64,
+    size: Trigger,
+    disk: Trigger,
+    blocks: Trigger,
+}
+
+impl GcTriggerState {
+    pub fn new(
+        path: Option<&Path>,
+        cache_size_bytes: u64,
+        stats: Arc<ArcSwap<StoreStats>>,
+    ) -> Self {
+        Self {
+            path: path.map(|path| match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+                _ => PathBuf::from("."),
+            }),
+            stats,
+            cache_size_bytes,
+            size: Default::default(),
+            disk: Default::default(),
+            blocks: Default::default(),
+        }
+    }
+
+    /// Sleeps for `duration` or until one of the `triggers` fires.
+    pub async fn sleep(&mut self, duration: Duration, triggers: &GcTriggers) {
+        if triggers.is_empty() {
+            Timer::after(duration).await;
+            return;
+        }
+        let start = Instant::now();
+        while let Some(remaining) = duration.checked_sub(start.elapsed()) {
+            Timer::after(std::cmp::min(remaining, triggers.check_interval)).await;
+            if let Some(trigger) = self.check(triggers) {
+                tracing::debug!("gc triggered by {}", trigger);
+                return;
+            }
+        }
+    }
+
+    /// Waits until no `syncs` are in flight, but at most `max`. The wait ends early when
+    /// the store is under pressure, that is when one of the `triggers` fires or hasn't
+    /// recovered since it last fired. Returns `true` when no syncs are in flight.
+    pub async fn defer(
+        &mut self,
+        syncs: &ActiveSyncs,
+        max: Duration,
+        triggers: &GcTriggers,
+    ) -> bool {
+        if syncs.count() == 0 {
+            return true;
+        }
+        tracing::debug!("deferring gc while {} syncs are in flight", syncs.count());
+        let start = Instant::now();
+        loop {
+            if self.check(triggers).is_some() || self.under_pressure() {
+                tracing::debug!("not deferring gc under pressure");
+                return false;
+            }
+            if syncs.count() == 0 {
+                return true;
+            }
+            match max.checked_sub(start.elapsed()) {
+                Some(remaining) if remaining > Duration::default() => {
+                    Timer::after(std::cmp::min(remaining, SYNC_CHECK_INTERVAL)).await
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    /// Returns `true` while a trigger that fired hasn't recovered.
+    fn under_pressure(&self) -> bool {
+        !(self.size.armed && self.disk.armed && self.blocks.armed)
+    }
+
+    fn check(&mut self, triggers: &GcTriggers) -> Option<&'static str> {
+        let h = triggers.hysteresis_percent;
+        let mut fired = None;
+        if triggers.size_percent.is_some() || triggers.max_blocks.is_some() {
+            let stats = self.stats.load();
+            // the `cache_size_bytes` only accounts for the main database.
+            let (size, count) = (stats.size.saturating_sub(stats.sharded_size), stats.blocks);
+            if let Some(p) = triggers.size_percent {
+                let threshold = percent(self.cache_size_bytes, p);
+                let rearm = threshold.saturating_sub(percent(threshold, h));
+                if self.size.update(size > threshold, size < rearm) {
+                    fired = Some("store size");
+                }
+            }
+            if let Some(max) = triggers.max_blocks {
+                let rearm = max.saturating_sub(percent(max, h));
+                if self.blocks.update(count > max, count < rearm) {
+                    fired = fired.or(Some("block count"));
+                }
+            }
+        }
+        if let (Some(min), Some(path)) = (triggers.min_free_disk, self.path.as_deref()) {
+            match fs2::available_space(path) {
+                Ok(free) => {
+                    let rearm = min.saturating_add(percent(min, h));
+                    if self.disk.update(free < min, free > rearm) {
+                        fired = fired.or(Some("free disk space"));
+                    }
+                }
+                Err(err) => tracing::warn!("failed to read free disk space: {}", err),
+            }
+        }
+        fired
+    }
+}
+
+/// Report of the last run of the garbage collector.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    /// Number of runs completed since the store was opened.
+    pub runs: u64,
+    /// Time the last run completed.
+    pub last_run: Option<SystemTime>,
+    /// Time the last run spent collecting, excluding the pause between its passes.
+    pub last_duration: Duration,
+    /// Number of blocks in the store when the last run started, as of the last store
+    /// statistics.
+    pub blocks_scanned: u64,
+    /// Number of blocks deleted by the last run.
+    pub blocks_deleted: u64,
+    /// Size in bytes of the blocks deleted by the last run.
+    pub bytes_reclaimed: u64,
+    /// Number of stored blocks not reachable from an alias, as of the last store
+    /// statistics. Only available for stores on disk.
+    pub orphan_estimate: Option<u64>,
+}
+
+thread_local! {
+    /// Blocks and bytes deleted by the pass running on this thread. The store reports
+    /// deletions on the thread performing them, so a run only counts its own deletions
+    /// while an `evict` and the gc loop run concurrently.
+    static PASS: Cell<Option<(u64, u64)>> = Cell::new(None);
+}
+
+/// Records the runs of the garbage collector.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GcRecorder {
+    stats: Arc<Mutex<GcStats>>,
+}
+
+impl GcRecorder {
+    /// Records a deleted block, counting it towards the pass running on this thread.
+    pub fn deleted(&self, size: u64) {
+        PASS.with(|pass| {
+            if let Some((blocks, bytes)) = pass.get() {
+                pass.set(Some((blocks + 1, bytes + size)));
+            }
+        });
+    }
+
+    /// Starts a run of the garbage collector. The blocks scanned are taken from the
+    /// sampled store statistics, so starting a run doesn't count the blocks under the
+    /// store lock.
+    pub fn begin(&self, stats: &StoreStats) -> GcRun {
+        GcRun {
+            blocks: stats.blocks,
+            busy: Duration::default(),
+            deleted: 0,
+            reclaimed: 0,
+        }
+    }
+
+    /// Completes a run of the garbage collector.
+    pub fn finish(&self, run: GcRun) {
+        let mut stats = self.stats.lock();
+        stats.runs += 1;
+        stats.last_run = Some(SystemTime::now());
+        stats.last_duration = run.busy;
+        stats.blocks_scanned = run.blocks;
+        stats.blocks_deleted = run.deleted;
+        stats.bytes_reclaimed = run.reclaimed;
+    }
+
+    /// Returns the report of the last run, without the orphan estimate.
+    pub fn stats(&self) -> GcStats {
+        *self.stats.lock()
+    }
+}
+
+/// A run of the garbage collector, consisting of a gc pass and a pass deleting the
+/// orphaned blocks.
+pub(crate) struct GcRun {
+    blocks: u64,
+    busy: Duration,
+    deleted: u64,
+    reclaimed: u64,
+}
+
+impl GcRun {
+    /// Runs a pass of the run on this thread, adding its duration and deletions to the
+    /// run.
+    pub fn pass<T>(&mut self, pass: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let outer = PASS.with(|counter| counter.replace(Some((0, 0))));
+        let res = pass();
+        let (deleted, reclaimed) = PASS
+            .with(|counter| counter.replace(outer))
+            .unwrap_or_default();
+        self.busy += start.elapsed();
+        self.deleted += deleted;
+        self.reclaimed += reclaimed;
+        res
+    }
+}
@@ -0,0 +1,82 @@
+use fnv::FnvHasher;
+use libipld::Cid;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Number of counters per expected block, giving a false positive rate of about 1%.
+const COUNTERS_PER_BLOCK: usize = 10;
+/// Number of counters a cid maps to.
+const HASHES: u64 = 7;
+
+/// Counting bloom filter over the cids of the stored blocks, used to answer that a block
+/// is not in the store without querying the database.
+///
+/// The filter is updated when a block is inserted or deleted by the garbage collector.
+/// Counters that overflow stick at their maximum, so they can cause false positives but
+/// never false negatives.
+pub(crate) struct HaveFilter {
+    counters: Vec<AtomicU8>,
+}
+
+impl HaveFilter {
+    /// Creates a filter sized for `capacity` blocks. A filter with a capacity of 0 is
+    /// disabled and may contain every cid.
+    pub fn new(capacity: usize) -> Self {
+        let len = capacity.saturating_mul(COUNTERS_PER_BLOCK);
+        Self {
+            counters: (0..len).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    fn counters<'a>(&'a self, cid: &Cid) -> impl Iterator<Item = &'a AtomicU8> + 'a {
+        let mut hasher = FnvHasher::default();
+        cid.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = self.counters.len() as u64;
+        (0..HASHES).map(move |i| &self.counters[(h1.wrapping_add(i * h2) % len) as usize])
+    }
+
+    /// Records a stored block.
+    pub fn insert(&self, cid: &Cid) {
+        if self.counters.is_empty() {
+            return;
+        }
+        for counter in self.counters(cid) {
+            counter
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1))
+                .ok();
+        }
+    }
+
+    /// Records a deleted block.
+    pub fn remove(&self, cid: &Cid) {
+        if self.counters.is_empty() {
+            return;
+        }
+        for counter in self.counters(cid) {
+            counter
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| match n {
+                    0 | u8::MAX => None,
+                    n => Some(n - 1),
+                })
+                .ok();
+        }
+    }
+
+    /// Returns `false` if the block is definitely not in the store.
+    pub fn may_contain(&self, cid: &Cid) -> bool {
+        self.counters.is_empty()
+            || self
+                .counters(cid)
+                .all(|counter| counter.load(Ordering::Relaxed) > 0)
+    }
+}
+
+impl std::fmt::Debug for HaveFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("HaveFilter")
+            .field("counters", &self.counters.len())
+            .finish()
+    }
+}
@@ -0,0 +1,18 @@
+use libipld::{Cid, Ipld, Result};
+use rusqlite::{Connection, Transaction};
+
+/// Hook maintaining a secondary index of the blocks in the store.
+///
+/// The index is stored in the database of the block store. A block is only inserted if
+/// indexing it succeeds. The index transaction is committed before the block is written,
+/// so if writing the block fails the index can refer to a block that isn't stored.
+/// Lookups through the index should check that the block exists.
+pub trait IndexHook: Send + Sync + 'static {
+    /// Creates the tables of the index. Called when the hook is installed.
+    fn init(&self, _conn: &Connection) -> Result<()> {
+        Ok(())
+    }
+
+    /// Indexes an inserted block.
+    fn index(&self, tx: &Transaction, cid: &Cid, ipld: &Ipld) -> Result<()>;
+}
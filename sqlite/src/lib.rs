@@ -1,22 +1,91 @@
-use futures::channel::mpsc;
+use crate::alias_cache::AliasCache;
+use crate::distribution::DistributionRecorder;
+use crate::events::EventLog;
+use crate::gc::{ActiveSyncs, GcRecorder, GcThrottle, GcTriggerState};
+use crate::have::HaveFilter;
+use crate::lock::StoreLock;
+use crate::meta::MetaStore;
+use crate::shard::Shards;
+use arc_swap::ArcSwap;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::oneshot;
 pub use ipfs_sqlite_block_store::TempPin;
 use ipfs_sqlite_block_store::{
     cache::{BlockInfo, CacheTracker, SqliteCacheTracker},
     BlockStore, Config, SizeTargets, Synchronous,
 };
 use lazy_static::lazy_static;
-use libipld::codec::References;
+use libipld::codec::{Decode, References};
 use libipld::store::StoreParams;
 use libipld::{Block, Cid, Ipld, Result};
 use parking_lot::Mutex;
 use prometheus::core::{Collector, Desc};
 use prometheus::proto::MetricFamily;
-use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry};
+use prometheus::{
+    Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+};
+pub use rusqlite;
+use rusqlite::Transaction;
+use std::convert::TryFrom;
 use std::future::Future;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+mod alias_cache;
+mod distribution;
+mod events;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod freeze;
+mod gc;
+mod have;
+mod index;
+mod lock;
+mod meta;
+mod namespace;
+mod reader;
+mod recovery;
+mod shard;
+mod stats;
+
+pub use crate::distribution::{BlockCounts, BlockDistribution};
+pub use crate::events::{StorageEvent, StorageEvents};
+#[cfg(feature = "fault-injection")]
+pub use crate::faults::{FaultInjector, InjectedFault};
+use crate::freeze::WriteGate;
+pub use crate::freeze::{FreezeGuard, FreezeTimeout};
+pub use crate::gc::{GcStats, GcTriggers, SyncGuard};
+pub use crate::index::IndexHook;
+pub use crate::lock::StoreLocked;
+pub use crate::meta::{OutboxRecord, PeerStatsRecord, PublishedRecord};
+pub use crate::namespace::{
+    AliasChange, AliasCommitHook, AliasExists, AliasMetaTooLarge, MAX_ALIAS_META_SIZE,
+};
+pub use crate::reader::{SnapshotUnsupported, StoreReader, UnsupportedSchema};
+pub use crate::recovery::{RecoveryMode, RecoveryReport};
+pub use crate::shard::{ShardLayoutMismatch, ShardMissing};
+pub use crate::stats::StoreStats;
+
+/// How writes are persisted to disk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Durability {
+    /// Writes are synced at checkpoints of the write ahead log. A power loss can roll
+    /// back the most recent transactions, but never corrupts the database.
+    Normal,
+    /// Every transaction is synced before it completes and alias updates are flushed
+    /// immediately, at the cost of latency. Suitable for consensus-critical roots.
+    Strict,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
 
 /// Storage configuration.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -56,6 +125,73 @@ pub struct StorageConfig {
     /// This can not be guaranteed, since we guarantee to collect at least `gc_min_blocks`. But
     /// as soon as this duration is exceeded, the incremental gc will stop doing additional work.
     pub gc_target_duration: Duration,
+    /// Maximum time a garbage collector pass is deferred while syncs are in flight.
+    /// Defaults to `None`, which doesn't defer the passes.
+    pub gc_sync_deferral: Option<Duration>,
+    /// Conditions that run the garbage collector before the `gc_interval` elapsed.
+    pub gc_triggers: GcTriggers,
+    /// The number of unused pages freed in a single compaction step.
+    pub compact_pages: u64,
+    /// When set, `compact_pages` unused pages are released after a garbage collector run
+    /// once the number of unused pages exceeds this threshold. Databases created without
+    /// incremental vacuum support need to be compacted with `compact` first.
+    pub auto_compact_free_pages: Option<u64>,
+    /// How to handle a corrupted database.
+    pub recovery_mode: RecoveryMode,
+    /// Number of previous roots kept alive when an alias is re-pointed. When set to 0 no
+    /// alias history is recorded.
+    pub alias_history: usize,
+    /// Number of database files the data of blocks without links is sharded across. The
+    /// main database only keeps track of the sharded blocks, while the blocks linking to
+    /// them are stored in the main database. When set to 0 all blocks are stored in the
+    /// main database.
+    ///
+    /// The `cache_size_bytes` only accounts for the main database, and readers and
+    /// salvaging a corrupted store don't see the data of sharded blocks. The number of
+    /// shards can't be changed, opening the store with a different number of shards
+    /// fails with `ShardLayoutMismatch`.
+    pub shards: usize,
+    /// Interval at which the statistics returned by `stats` and reported by the
+    /// prometheus collector are sampled.
+    pub stats_interval: Duration,
+    /// Number of blocks the in-memory filter answering that a block is not in the store
+    /// is sized for. When more blocks are stored the filter still works, but more lookups
+    /// fall through to the database. When set to 0 the filter is disabled.
+    pub have_filter_capacity: usize,
+    /// Interval at which the node verifies that the dags of all aliases are complete and
+    /// fetches the missing blocks. Defaults to `None`, which only verifies the dags on
+    /// request.
+    pub verify_interval: Option<Duration>,
+    /// Time the ids of messages received with `subscribe_deduplicated` are remembered, so
+    /// that messages replayed after a restart are not delivered again.
+    pub gossip_dedup_ttl: Duration,
+    /// Maximum number of remembered message ids per topic. Once exceeded the oldest ids of
+    /// the topic are dropped.
+    pub gossip_dedup_capacity: usize,
+    /// Time messages published while no peer is connected are kept in the outbox. The
+    /// messages are published in order once a peer connects. When set to `None`
+    /// publishing fails while no peer is connected.
+    pub outbox_ttl: Option<Duration>,
+    /// Interval at which aliases set with `alias_with_ttl` are removed once their ttl
+    /// elapsed.
+    pub alias_expiry_interval: Duration,
+    /// Time the results of `resolve` and `reverse_alias` are cached. Local alias writes
+    /// and inserted or deleted blocks invalidate the cached results immediately. Every
+    /// inserted block drops all cached reverse aliases, so the cache only pays off for
+    /// stores that are mostly read. When set to `None` every lookup queries the store.
+    pub alias_cache_ttl: Option<Duration>,
+    /// Interval at which the statistics of peers are persisted, so that the node starts
+    /// with the rtt estimates and success rates learned before a restart. When set to
+    /// `None` the statistics are not persisted.
+    pub peer_stats_interval: Option<Duration>,
+    /// Time after which the persisted statistics of a peer that wasn't seen are removed.
+    pub peer_stats_retention: Duration,
+    /// Number of storage events buffered for subscribers. Subscribers that fall further
+    /// behind miss the oldest events and receive a `StorageEvent::Lagged` instead. When
+    /// set to 0 no events are delivered.
+    pub event_capacity: usize,
+    /// How writes are persisted to disk.
+    pub durability: Durability,
 }
 
 impl StorageConfig {
@@ -68,68 +204,431 @@ impl StorageConfig {
             gc_interval,
             gc_min_blocks: usize::MAX,
             gc_target_duration: Duration::new(u64::MAX, 1_000_000_000 - 1),
+            gc_sync_deferral: None,
+            gc_triggers: GcTriggers::none(),
+            compact_pages: 1000,
+            auto_compact_free_pages: None,
+            recovery_mode: RecoveryMode::Fail,
+            alias_history: 0,
+            shards: 0,
+            stats_interval: Duration::from_secs(10),
+            have_filter_capacity: 100_000,
+            verify_interval: None,
+            gossip_dedup_ttl: Duration::from_secs(60 * 10),
+            gossip_dedup_capacity: 10_000,
+            outbox_ttl: None,
+            alias_expiry_interval: Duration::from_secs(60),
+            alias_cache_ttl: None,
+            peer_stats_interval: Some(Duration::from_secs(60)),
+            peer_stats_retention: Duration::from_secs(60 * 60 * 24 * 30),
+            event_capacity: 4096,
+            durability: Durability::Normal,
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum StorageEvent {
-    Remove(Cid),
+/// Garbage collector configuration that can be changed at runtime.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GcConfig {
+    /// The interval at which the garbage collector is run.
+    pub interval: Duration,
+    /// The minimum number of blocks to collect in any case.
+    pub min_blocks: usize,
+    /// The target maximum gc duration of a single garbage collector run.
+    pub target_duration: Duration,
+    /// Conditions that run the garbage collector before the `interval` elapsed.
+    pub triggers: GcTriggers,
+    /// Maximum time a garbage collector pass is deferred while syncs are in flight, so
+    /// that it doesn't compete with the inserts of the syncs for the database. Passes are
+    /// not deferred while a gc trigger reports pressure on the store. When set to `None`
+    /// the garbage collector runs regardless of syncs.
+    pub sync_deferral: Option<Duration>,
 }
 
+/// Error returned when accessing a block on the deny list.
+#[derive(Debug, Error)]
+#[error("block {0} is on the deny list")]
+pub struct Denied(pub Cid);
+
+type Indexer<S> = Arc<dyn Fn(&Transaction, &Block<S>) -> Result<()> + Send + Sync>;
+type GcHeartbeat = Arc<Mutex<Option<Box<dyn Fn(Duration) + Send + Sync>>>>;
+
 #[derive(Clone)]
 pub struct StorageService<S: StoreParams> {
     _marker: PhantomData<S>,
     store: Arc<Mutex<BlockStore>>,
-    gc_target_duration: Duration,
-    gc_min_blocks: usize,
+    meta: Arc<Mutex<MetaStore>>,
+    gc_config: Arc<Mutex<GcConfig>>,
+    /// Factor the gc interval is stretched by, 0 pauses the garbage collector.
+    gc_throttle: GcThrottle,
+    gc_heartbeat: GcHeartbeat,
+    gc_recorder: GcRecorder,
+    distribution: DistributionRecorder,
+    compact_pages: u64,
+    recovery: Option<RecoveryReport>,
+    alias_history: usize,
+    gossip_dedup_ttl: Duration,
+    gossip_dedup_capacity: usize,
+    outbox_ttl: Option<Duration>,
+    /// Number of messages of every topic in the outbox.
+    outbox: Arc<Mutex<FnvHashMap<String, u64>>>,
+    peer_stats_retention: Duration,
+    durability: Durability,
+    alias_generation: Arc<AtomicU64>,
+    alias_lock: Arc<Mutex<()>>,
+    alias_cache: Arc<AliasCache>,
+    _lock: Option<Arc<StoreLock>>,
+    denylist: Arc<Mutex<FnvHashSet<Cid>>>,
+    index: Arc<Mutex<Option<Indexer<S>>>>,
+    alias_hook: Arc<Mutex<Option<Arc<dyn AliasCommitHook>>>>,
+    path: Option<PathBuf>,
+    shards: Option<Arc<Shards>>,
+    have: Arc<HaveFilter>,
+    syncs: ActiveSyncs,
+    stats: Arc<ArcSwap<StoreStats>>,
+    events: Arc<EventLog>,
+    events_queued: IntGauge,
+    events_lagged: IntCounter,
+    #[cfg(feature = "fault-injection")]
+    faults: Arc<FaultInjector>,
+    watchers: Arc<Mutex<FnvHashMap<Cid, Vec<oneshot::Sender<Block<S>>>>>>,
+    /// Writers pass the gate, which `freeze_writes` closes.
+    write_gate: Arc<WriteGate>,
 }
 
 impl<S: StoreParams> StorageService<S>
 where
     Ipld: References<S::Codecs>,
 {
-    pub fn open(config: StorageConfig, tx: mpsc::UnboundedSender<StorageEvent>) -> Result<Self> {
+    pub fn open(config: StorageConfig) -> Result<Self> {
         let size = SizeTargets::new(config.cache_size_blocks, config.cache_size_bytes);
-        let store_config = Config::default()
-            .with_size_targets(size)
-            .with_pragma_synchronous(Synchronous::Normal);
-        let store = if let Some(path) = config.path {
-            let tracker = SqliteCacheTracker::open(&path, |access, _| Some(access))?;
-            let tracker = IpfsCacheTracker { tracker, tx };
-            BlockStore::open(path, store_config.with_cache_tracker(tracker))?
+        let durability = config.durability;
+        let store_config = || {
+            let synchronous = match durability {
+                Durability::Normal => Synchronous::Normal,
+                Durability::Strict => Synchronous::Full,
+            };
+            Config::default()
+                .with_size_targets(size)
+                .with_pragma_synchronous(synchronous)
+        };
+        let shards = match (config.shards, config.path.as_deref()) {
+            (0, _) => None,
+            (n, Some(path)) => Some(Arc::new(Shards::open(path, n)?)),
+            (n, None) => Some(Arc::new(Shards::memory(n)?)),
+        };
+        if let (Some(shards), Durability::Strict) = (shards.as_ref(), config.durability) {
+            shards.set_synchronous_full()?;
+        }
+        let have = Arc::new(HaveFilter::new(config.have_filter_capacity));
+        let gc_recorder = GcRecorder::default();
+        let distribution = DistributionRecorder::default();
+        let alias_cache = Arc::new(AliasCache::new(config.alias_cache_ttl));
+        let events_queued = IntGauge::new(
+            "storage_events_queued",
+            "Number of storage events the slowest subscriber didn't receive yet.",
+        )?;
+        let events_lagged = IntCounter::new(
+            "storage_events_lagged",
+            "Number of storage events missed by subscribers that fell behind.",
+        )?;
+        let events = Arc::new(EventLog::new(
+            config.event_capacity,
+            events_queued.clone(),
+            events_lagged.clone(),
+        ));
+        let open = |path: &Path| -> Result<(BlockStore, MetaStore)> {
+            let tracker = SqliteCacheTracker::open(path, |access, _| Some(access))?;
+            let tracker = IpfsCacheTracker {
+                tracker,
+                events: events.clone(),
+                shards: shards.clone(),
+                have: have.clone(),
+                gc: gc_recorder.clone(),
+                distribution: distribution.clone(),
+                alias_cache: alias_cache.clone(),
+            };
+            let meta = MetaStore::open(path)?;
+            let store = BlockStore::open(path, store_config().with_cache_tracker(tracker))?;
+            meta.backfill_alias_names()?;
+            Ok((store, meta))
+        };
+        let mut recovery = None;
+        let lock = if let Some(path) = config.path.as_deref() {
+            Some(Arc::new(StoreLock::acquire(path)?))
+        } else {
+            None
+        };
+        let (store, meta) = if let Some(path) = config.path.as_deref() {
+            match open(path) {
+                Ok(res) => res,
+                Err(err) if !recovery::is_corrupted(path) => return Err(err),
+                Err(err) => {
+                    tracing::error!("database {} is corrupted: {}", path.display(), err);
+                    let (res, report) = match config.recovery_mode {
+                        RecoveryMode::Fail => return Err(err),
+                        RecoveryMode::TruncateWal => {
+                            let report = recovery::truncate_wal(path)?;
+                            (open(path)?, report)
+                        }
+                        RecoveryMode::Salvage => {
+                            let corrupted = recovery::move_aside(path)?;
+                            let (mut store, meta) = open(path)?;
+                            let report = recovery::salvage::<S>(corrupted, &mut store);
+                            meta.backfill_alias_names()?;
+                            ((store, meta), report)
+                        }
+                    };
+                    tracing::info!(
+                        "recovered {} blocks and {} aliases, lost {} blocks",
+                        report.recovered_blocks,
+                        report.recovered_aliases,
+                        report.lost_blocks
+                    );
+                    recovery = Some(report);
+                    res
+                }
+            }
         } else {
             let tracker = SqliteCacheTracker::memory(|access, _| Some(access))?;
-            let tracker = IpfsCacheTracker { tracker, tx };
-            BlockStore::memory(store_config.with_cache_tracker(tracker))?
+            let tracker = IpfsCacheTracker {
+                tracker,
+                events: events.clone(),
+                shards: shards.clone(),
+                have: have.clone(),
+                gc: gc_recorder.clone(),
+                distribution: distribution.clone(),
+                alias_cache: alias_cache.clone(),
+            };
+            let store = BlockStore::memory(store_config().with_cache_tracker(tracker))?;
+            (store, MetaStore::memory()?)
         };
+        if let Durability::Strict = durability {
+            meta.set_synchronous_full()?;
+        }
+        match (shards.as_deref(), meta.shard_layout()?) {
+            (Some(shards), layout) => {
+                shards.check_layout(layout)?;
+                meta.set_shard_layout(shards.count())?;
+            }
+            (None, Some(expected)) => {
+                return Err(ShardLayoutMismatch { expected, found: 0 }.into());
+            }
+            (None, None) => {}
+        }
+        distribution.restore(meta.block_distribution()?);
+        let denylist = meta
+            .denylist()?
+            .into_iter()
+            .filter_map(|cid| Cid::try_from(cid).ok())
+            .collect();
+        let meta = Arc::new(Mutex::new(meta));
         let store = Arc::new(Mutex::new(store));
+        if let Some(shards) = shards.as_deref() {
+            recover_shards(shards, &store, &meta)?;
+        }
+        if config.have_filter_capacity > 0 {
+            for cid in store.lock().get_block_cids::<Vec<Cid>>()? {
+                have.insert(&cid);
+            }
+        }
+        let gc_config = Arc::new(Mutex::new(GcConfig {
+            interval: config.gc_interval,
+            min_blocks: config.gc_min_blocks,
+            target_duration: config.gc_target_duration,
+            triggers: config.gc_triggers,
+            sync_deferral: config.gc_sync_deferral,
+        }));
+        let stats = StoreStats::read(&store, &meta, shards.as_deref()).unwrap_or_default();
+        let stats = Arc::new(ArcSwap::from_pointee(stats));
+        let syncs = ActiveSyncs::default();
+        let gc_syncs = syncs.clone();
+        let mut trigger_state = GcTriggerState::new(
+            config.path.as_deref(),
+            config.cache_size_bytes,
+            stats.clone(),
+        );
         let gc = store.clone();
-        let gc_interval = config.gc_interval;
-        let gc_min_blocks = config.gc_min_blocks;
-        let gc_target_duration = config.gc_target_duration;
-        async_global_executor::spawn(async_global_executor::spawn_blocking(move || {
-            std::thread::sleep(gc_interval / 2);
+        let gc_meta = meta.clone();
+        let gc_shards = shards.clone();
+        let gc_config2 = gc_config.clone();
+        let gc_throttle = GcThrottle::new();
+        let gc_throttle2 = gc_throttle.clone();
+        let gc_heartbeat = GcHeartbeat::default();
+        let gc_heartbeat2 = gc_heartbeat.clone();
+        let beat = move |next: Duration| {
+            if let Some(heartbeat) = gc_heartbeat2.lock().as_ref() {
+                heartbeat(next);
+            }
+        };
+        let gc_recorder2 = gc_recorder.clone();
+        let gc_stats = stats.clone();
+        let write_gate = Arc::new(WriteGate::default());
+        let gc_gate = write_gate.clone();
+        let compact_pages = config.compact_pages;
+        let auto_compact = config.auto_compact_free_pages;
+        // the loop waits on timers, while the passes block and run off the event loop.
+        ipfs_embed_rt::spawn(async move {
+            let GcConfig {
+                interval, triggers, ..
+            } = *gc_config2.lock();
+            trigger_state.sleep(interval / 2, &triggers).await;
             loop {
+                let GcConfig {
+                    interval,
+                    min_blocks,
+                    target_duration,
+                    triggers,
+                    sync_deferral,
+                } = *gc_config2.lock();
+                let interval = gc_throttle2.stretch(interval).await;
+                // a pass sleeps for the interval and defers twice for active syncs.
+                let deferral = sync_deferral.unwrap_or_default();
+                let next = deferral
+                    .checked_mul(2)
+                    .and_then(|deferral| interval.checked_add(deferral))
+                    .unwrap_or_else(|| Duration::from_secs(u64::MAX));
+                beat(next);
+                if let Some(max) = sync_deferral {
+                    trigger_state.defer(&gc_syncs, max, &triggers).await;
+                }
                 tracing::debug!("gc_loop running incremental gc");
-                gc.lock()
-                    .incremental_gc(gc_min_blocks, gc_target_duration)
-                    .ok();
-                std::thread::sleep(gc_interval / 2);
+                let (gc2, gate) = (gc.clone(), gc_gate.clone());
+                let mut run = gc_recorder2.begin(&gc_stats.load());
+                let mut run = ipfs_embed_rt::spawn_blocking(move || {
+                    run.pass(|| {
+                        let _write = gate.enter();
+                        gc2.lock().incremental_gc(min_blocks, target_duration).ok()
+                    });
+                    run
+                })
+                .await;
+                trigger_state.sleep(interval / 2, &triggers).await;
+                if let Some(max) = sync_deferral {
+                    trigger_state.defer(&gc_syncs, max, &triggers).await;
+                }
                 tracing::debug!("gc_loop running incremental delete orphaned");
-                gc.lock()
-                    .incremental_delete_orphaned(gc_min_blocks, gc_target_duration)
-                    .ok();
-                std::thread::sleep(gc_interval / 2);
+                let (gc2, gate, recorder) = (gc.clone(), gc_gate.clone(), gc_recorder2.clone());
+                let (meta, shards) = (gc_meta.clone(), gc_shards.clone());
+                ipfs_embed_rt::spawn_blocking(move || {
+                    run.pass(|| {
+                        let _write = gate.enter();
+                        gc2.lock()
+                            .incremental_delete_orphaned(min_blocks, target_duration)
+                            .ok()
+                    });
+                    recorder.finish(run);
+                    let _write = gate.enter();
+                    if let Some(shards) = shards.as_deref() {
+                        if let Err(err) = unmark_removed_shards(shards, &gc2, &meta) {
+                            tracing::warn!("failed to unmark removed shards: {}", err);
+                        }
+                    }
+                    if let Some(threshold) = auto_compact {
+                        if meta.lock().free_pages().unwrap_or_default() > threshold {
+                            // a single slice per pass, so that the meta store isn't locked
+                            // for long. the full vacuum needed to enable incremental vacuum
+                            // is only performed by an explicit `compact`.
+                            tracing::debug!("gc_loop running compaction");
+                            if let Err(err) = meta.lock().incremental_vacuum(compact_pages) {
+                                tracing::warn!("compaction failed: {}", err);
+                            }
+                        }
+                    }
+                })
+                .await;
+                trigger_state.sleep(interval / 2, &triggers).await;
             }
-        }))
+        })
+        .detach();
+        let stats2 = stats.clone();
+        let store2 = store.clone();
+        let meta2 = meta.clone();
+        let shards2 = shards.clone();
+        let distribution2 = distribution.clone();
+        let stats_interval = config.stats_interval;
+        ipfs_embed_rt::spawn(async move {
+            loop {
+                ipfs_embed_rt::Timer::after(stats_interval).await;
+                let store = store2.clone();
+                let meta = meta2.clone();
+                let shards = shards2.clone();
+                let distribution = distribution2.distribution();
+                let res = ipfs_embed_rt::spawn_blocking(move || {
+                    if let Err(err) = meta.lock().save_block_distribution(&distribution) {
+                        tracing::warn!("failed to save the block distribution: {}", err);
+                    }
+                    StoreStats::read(&store, &meta, shards.as_deref())
+                })
+                .await;
+                match res {
+                    Ok(res) => stats2.store(Arc::new(res)),
+                    Err(err) => tracing::warn!("failed to read store stats: {}", err),
+                }
+            }
+        })
         .detach();
-        Ok(Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let outbox = meta.lock().outbox_topics(now)?.into_iter().collect();
+        let service = Self {
             _marker: PhantomData,
-            gc_target_duration: config.gc_target_duration,
-            gc_min_blocks: config.gc_min_blocks,
             store,
-        })
+            meta,
+            gc_config,
+            gc_throttle,
+            gc_heartbeat,
+            gc_recorder,
+            distribution,
+            compact_pages: config.compact_pages,
+            recovery,
+            alias_history: config.alias_history,
+            gossip_dedup_ttl: config.gossip_dedup_ttl,
+            gossip_dedup_capacity: config.gossip_dedup_capacity,
+            outbox_ttl: config.outbox_ttl,
+            outbox: Arc::new(Mutex::new(outbox)),
+            peer_stats_retention: config.peer_stats_retention,
+            durability: config.durability,
+            alias_generation: Default::default(),
+            alias_lock: Default::default(),
+            alias_cache,
+            _lock: lock,
+            denylist: Arc::new(Mutex::new(denylist)),
+            index: Default::default(),
+            alias_hook: Default::default(),
+            path: config.path,
+            shards,
+            have,
+            syncs,
+            stats,
+            events,
+            events_queued,
+            events_lagged,
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+            watchers: Default::default(),
+            write_gate,
+        };
+        service.apply_renames()?;
+        service.restore_alias_history()?;
+        service.prune_alias_names()?;
+        Ok(service)
+    }
+
+    /// Returns the recovery performed when opening a corrupted store.
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.recovery.as_ref()
+    }
+
+    /// Returns a reader of a consistent snapshot of the store.
+    pub fn read_snapshot(&self) -> Result<StoreReader> {
+        if let Some(path) = self.path.as_deref() {
+            StoreReader::snapshot(path)
+        } else {
+            Err(SnapshotUnsupported.into())
+        }
     }
 
     pub fn create_temp_pin(&self) -> Result<TempPin> {
@@ -143,6 +642,7 @@ where
         temp: &TempPin,
         iter: impl IntoIterator<Item = Cid> + Send + 'static,
     ) -> Result<()> {
+        let _write = self.write_gate.enter();
         observe_query("temp_pin", || {
             self.store.lock().assign_temp_pin(&temp, iter)
         })
@@ -153,286 +653,2977 @@ where
         Ok(cids.into_iter())
     }
 
-    pub fn contains(&self, cid: &Cid) -> Result<bool> {
-        observe_query("contains", || self.store.lock().has_block(cid))
+    /// Returns up to `limit` cids of stored blocks in a stable order, starting after the
+    /// cid `after`. Passing the last returned cid pages through the store while only
+    /// keeping a page of cids.
+    pub fn iter_after(&self, after: Option<&Cid>, limit: usize) -> Result<Vec<Cid>> {
+        let after = after.map(|cid| cid.to_bytes());
+        let mut cids = self
+            .iter()?
+            .map(|cid| (cid.to_bytes(), cid))
+            .filter(|(bytes, _)| after.as_ref().map(|after| bytes > after).unwrap_or(true))
+            .collect::<Vec<_>>();
+        if cids.len() > limit {
+            cids.select_nth_unstable_by(limit, |a, b| a.0.cmp(&b.0));
+            cids.truncate(limit);
+        }
+        cids.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(cids.into_iter().map(|(_, cid)| cid).collect())
     }
 
-    pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        observe_query("get", || self.store.lock().get_block(cid))
+    /// Returns up to `limit` stored blocks and the cids they link to, in the order of
+    /// `iter_after`. The references are decoded from the blocks of the page, blocks on the
+    /// deny list are returned without references.
+    pub fn references_after(
+        &self,
+        after: Option<&Cid>,
+        limit: usize,
+    ) -> Result<Vec<(Cid, Vec<Cid>)>> {
+        self.iter_after(after, limit)?
+            .into_iter()
+            .map(|cid| {
+                let mut refs = FnvHashSet::default();
+                let data = if self.is_denied(&cid) {
+                    None
+                } else {
+                    self.get(&cid)?
+                };
+                if let Some(data) = data {
+                    Block::<S>::new_unchecked(cid, data).references(&mut refs)?;
+                }
+                Ok((cid, refs.into_iter().collect()))
+            })
+            .collect()
     }
 
-    pub fn insert(&self, block: &Block<S>) -> Result<()> {
-        observe_query("insert", || self.store.lock().put_block(block, None))
+    pub fn list_by_codec(&self, codec: impl Into<u64>) -> Result<impl Iterator<Item = Cid>> {
+        let codec = codec.into();
+        Ok(self.iter()?.filter(move |cid| cid.codec() == codec))
     }
 
-    pub async fn evict(&self) -> Result<()> {
-        let store = self.store.clone();
-        let gc_min_blocks = self.gc_min_blocks;
-        let gc_target_duration = self.gc_target_duration;
-        async_global_executor::spawn_blocking(move || {
-            while !store
-                .lock()
-                .incremental_gc(gc_min_blocks, gc_target_duration)?
-            {}
-            while !store
-                .lock()
-                .incremental_delete_orphaned(gc_min_blocks, gc_target_duration)?
-            {}
-            Ok(())
-        })
-        .await
+    pub fn list_by_hash(&self, code: impl Into<u64>) -> Result<impl Iterator<Item = Cid>> {
+        let code = code.into();
+        Ok(self.iter()?.filter(move |cid| cid.hash().code() == code))
     }
 
-    pub fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
-        observe_query("alias", || self.store.lock().alias(alias, cid))
+    /// Returns the fault injector of the store.
+    #[cfg(feature = "fault-injection")]
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
     }
 
-    pub fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
-        observe_query("resolve", || self.store.lock().resolve(alias))
+    #[cfg(feature = "fault-injection")]
+    fn inject(&self, write: bool) -> Result<()> {
+        self.faults.inject(write)
     }
 
-    pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
-        observe_query("reverse_alias", || self.store.lock().reverse_alias(cid))
+    #[cfg(not(feature = "fault-injection"))]
+    #[inline(always)]
+    fn inject(&self, _write: bool) -> Result<()> {
+        Ok(())
     }
 
-    pub fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
-        observe_query("missing_blocks", || {
-            self.store.lock().get_missing_blocks(cid)
-        })
+    pub fn contains(&self, cid: &Cid) -> Result<bool> {
+        if inline_data(cid).is_some() {
+            return Ok(true);
+        }
+        self.inject(false)?;
+        if !self.have.may_contain(cid) {
+            QUERIES_TOTAL.with_label_values(&["have_filter"]).inc();
+            return Ok(false);
+        }
+        observe_query("contains", || self.store.lock().has_block(cid))
     }
 
-    pub async fn flush(&self) -> Result<()> {
-        let store = self.store.clone();
-        let flush = async_global_executor::spawn_blocking(move || store.lock().flush());
-        observe_future("flush", flush).await
+    /// Returns for every cid whether the block is in the store. The cids that may be
+    /// stored are looked up in a single query.
+    pub fn contains_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        self.inject(false)?;
+        let lookup = cids
+            .iter()
+            .filter(|cid| inline_data(cid).is_none() && self.have.may_contain(cid))
+            .collect::<Vec<_>>();
+        let bytes = lookup.iter().map(|cid| cid.to_bytes()).collect::<Vec<_>>();
+        let stored = observe_query("contains_many", || self.meta.lock().stored_blocks(&bytes))?;
+        let stored = match stored {
+            Some(stored) => stored.into_iter().collect::<FnvHashSet<_>>(),
+            // in-memory stores keep the blocks in a separate database.
+            None => observe_query("contains_many", || {
+                let mut store = self.store.lock();
+                let mut stored = FnvHashSet::default();
+                for (cid, bytes) in lookup.iter().zip(bytes) {
+                    if store.has_block(cid)? {
+                        stored.insert(bytes);
+                    }
+                }
+                Ok::<_, ipfs_sqlite_block_store::BlockStoreError>(stored)
+            })?,
+        };
+        Ok(cids
+            .iter()
+            .map(|cid| inline_data(cid).is_some() || stored.contains(&cid.to_bytes()))
+            .collect())
     }
 
-    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
-        registry.register(Box::new(QUERIES_TOTAL.clone()))?;
-        registry.register(Box::new(QUERY_DURATION.clone()))?;
-        registry.register(Box::new(SqliteStoreCollector::new(self.store.clone())))?;
+    /// Returns a block. Fails with `Denied` if the block is on the deny list.
+    pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        if self.is_denied(cid) {
+            return Err(Denied(*cid).into());
+        }
+        if let Some(data) = inline_data(cid) {
+            return Ok(Some(data.to_vec()));
+        }
+        self.inject(false)?;
+        if !self.have.may_contain(cid) {
+            QUERIES_TOTAL.with_label_values(&["have_filter"]).inc();
+            return Ok(None);
+        }
+        if let Some(shards) = self.shards.as_ref() {
+            if let Some(data) = observe_query("get_shard", || shards.get(cid))? {
+                return Ok(Some(data));
+            }
+        }
+        let data = observe_query("get", || self.store.lock().get_block(cid))?;
+        // the main database stores a placeholder in place of the data of sharded blocks.
+        if self.shards.is_some()
+            && data.is_some()
+            && data.as_deref() == shard::placeholder(cid.codec())
+            && observe_query("is_sharded", || {
+                self.meta.lock().is_sharded(&cid.to_bytes())
+            })?
+        {
+            tracing::warn!("data of sharded block {} is missing", cid);
+            return Ok(None);
+        }
+        Ok(data)
+    }
+
+    /// Inserts a block. Inline blocks without links are not stored, since their data is
+    /// part of the cid. Fails with `Denied` if the block is on the deny list.
+    pub fn insert(&self, block: &Block<S>) -> Result<()> {
+        if self.is_denied(block.cid()) {
+            return Err(Denied(*block.cid()).into());
+        }
+        self.inject(true)?;
+        let _write = self.write_gate.enter();
+        let index = self.index.lock().clone();
+        if let Some(index) = index {
+            // the block store writes on its own connection to the same database, so the
+            // index transaction is committed first to release the write lock.
+            let mut meta = self.meta.lock();
+            let tx = meta.transaction()?;
+            index(&tx, block)?;
+            observe_query("commit_index", || tx.commit())?;
+        }
+        self.put_blocks(std::slice::from_ref(block))?;
+        self.inserted(std::slice::from_ref(block));
         Ok(())
     }
-}
 
-#[derive(Debug)]
-struct IpfsCacheTracker<T> {
-    tracker: T,
-    tx: mpsc::UnboundedSender<StorageEvent>,
-}
+    /// Inserts a batch of blocks. The data of sharded blocks is written to the shards in
+    /// parallel. Fails with `Denied` before inserting any block if one of the blocks is
+    /// on the deny list.
+    pub fn insert_batch(&self, blocks: &[Block<S>]) -> Result<()> {
+        if let Some(block) = blocks.iter().find(|block| self.is_denied(block.cid())) {
+            return Err(Denied(*block.cid()).into());
+        }
+        if self.index.lock().is_some() {
+            // every block is indexed in its own transaction.
+            for block in blocks {
+                self.insert(block)?;
+            }
+            return Ok(());
+        }
+        self.inject(true)?;
+        let _write = self.write_gate.enter();
+        self.put_blocks(blocks)?;
+        self.inserted(blocks);
+        Ok(())
+    }
 
-impl<T: CacheTracker> CacheTracker for IpfsCacheTracker<T> {
-    fn blocks_accessed(&self, blocks: Vec<BlockInfo>) {
-        self.tracker.blocks_accessed(blocks)
+    fn inserted(&self, blocks: &[Block<S>]) {
+        self.alias_cache.blocks_inserted();
+        let mut watchers = self.watchers.lock();
+        for block in blocks {
+            for tx in watchers.remove(block.cid()).unwrap_or_default() {
+                tx.send(block.clone()).ok();
+            }
+        }
     }
 
-    fn blocks_deleted(&self, blocks: Vec<BlockInfo>) {
-        for block in &blocks {
-            self.tx
-                .unbounded_send(StorageEvent::Remove(*block.cid()))
-                .ok();
+    fn put_blocks(&self, blocks: &[Block<S>]) -> Result<()> {
+        let mut stored = vec![];
+        for block in blocks {
+            stored_blocks(block, &mut stored)?;
         }
-        self.tracker.blocks_deleted(blocks)
+        // the data of leaves is written to the shards before the placeholders are written
+        // to the main database. the leaves are marked as sharded until then, so that the
+        // data of leaves that weren't written is removed when the store is opened.
+        let mut sharded = vec![];
+        if let Some(shards) = self.shards.as_ref() {
+            let leaves = stored
+                .iter()
+                .filter(|(block, leaf)| *leaf && shard::placeholder(block.cid().codec()).is_some())
+                .map(|(block, _)| (*block.cid(), block.data().to_vec()))
+                .collect::<Vec<_>>();
+            if !leaves.is_empty() {
+                sharded = leaves.iter().map(|(cid, _)| cid.to_bytes()).collect();
+                observe_query("mark_sharded", || self.meta.lock().mark_sharded(&sharded))?;
+                observe_query("insert_shard", || shards.insert_many(leaves))?;
+            }
+        }
+        for (block, leaf) in &stored {
+            let placeholder = match self.shards {
+                Some(_) if *leaf => shard::placeholder(block.cid().codec())
+                    .map(|data| Block::<S>::new_unchecked(*block.cid(), data.to_vec())),
+                _ => None,
+            };
+            let data = placeholder.as_ref().unwrap_or(block);
+            let inserted = observe_query("insert", || {
+                let mut store = self.store.lock();
+                // the have filter saves the query for most new blocks. it is checked and
+                // updated under the store lock, so that concurrent inserts of a block
+                // don't both count it.
+                let maybe_stored = self.have.may_contain(block.cid());
+                // recorded before the insert, so that a concurrent lookup never misses
+                // the block once it's stored.
+                self.have.insert(block.cid());
+                let stored = if maybe_stored {
+                    store.has_block(block.cid())
+                } else {
+                    Ok(false)
+                };
+                stored.and_then(|stored| store.put_block(data, None).map(|()| !stored))
+            });
+            match inserted {
+                Ok(true) => self.distribution.inserted(block.cid(), block.data().len()),
+                // a block is only counted once, so that deleting it clears the counters.
+                Ok(false) => self.have.remove(block.cid()),
+                Err(err) => {
+                    self.have.remove(block.cid());
+                    return Err(err);
+                }
+            }
+        }
+        if !sharded.is_empty() {
+            observe_query("commit_sharded", || {
+                self.meta.lock().commit_sharded(&sharded)
+            })?;
+        }
+        Ok(())
     }
 
-    fn retain_ids(&self, ids: &[i64]) {
-        self.tracker.retain_ids(ids)
+    /// Installs a hook that indexes the decoded blocks on insert. Blocks that can't be
+    /// decoded are not indexed.
+    pub fn set_index_hook<H: IndexHook>(&self, hook: H) -> Result<()>
+    where
+        Ipld: Decode<S::Codecs>,
+    {
+        hook.init(self.meta.lock().connection())?;
+        let indexer: Indexer<S> = Arc::new(move |tx, block| {
+            let ipld = match block.ipld() {
+                Ok(ipld) => ipld,
+                Err(err) => {
+                    tracing::trace!("not indexing {}: {}", block.cid(), err);
+                    return Ok(());
+                }
+            };
+            hook.index(tx, block.cid(), &ipld)
+        });
+        *self.index.lock() = Some(indexer);
+        Ok(())
     }
 
-    fn sort_ids(&self, ids: &mut [i64]) {
-        self.tracker.sort_ids(ids)
+    /// Returns a receiver that resolves once the block is inserted. The caller needs to
+    /// check if the block is already in the store after registering the watcher.
+    pub fn watch(&self, cid: &Cid) -> oneshot::Receiver<Block<S>> {
+        let (tx, rx) = oneshot::channel();
+        let mut watchers = self.watchers.lock();
+        let entry = watchers.entry(*cid).or_default();
+        entry.retain(|tx| !tx.is_canceled());
+        entry.push(tx);
+        rx
     }
-}
 
-lazy_static! {
-    pub static ref QUERIES_TOTAL: IntCounterVec = IntCounterVec::new(
-        Opts::new(
-            "block_store_queries_total",
-            "Number of block store requests labelled by type."
-        ),
-        &["type"],
-    )
-    .unwrap();
-    pub static ref QUERY_DURATION: HistogramVec = HistogramVec::new(
-        HistogramOpts::new(
-            "block_store_query_duration",
-            "Duration of store queries labelled by type.",
-        ),
-        &["type"],
-    )
-    .unwrap();
-}
+    pub async fn evict(&self) -> Result<()> {
+        let store = self.store.clone();
+        let meta = self.meta.clone();
+        let shards = self.shards.clone();
+        let recorder = self.gc_recorder.clone();
+        let mut run = recorder.begin(&self.stats.load());
+        let gate = self.write_gate.clone();
+        let GcConfig {
+            min_blocks,
+            target_duration,
+            ..
+        } = self.gc_config();
+        ipfs_embed_rt::spawn_blocking(move || {
+            let _write = gate.enter();
+            run.pass(|| -> Result<()> {
+                while !store.lock().incremental_gc(min_blocks, target_duration)? {}
+                while !store
+                    .lock()
+                    .incremental_delete_orphaned(min_blocks, target_duration)?
+                {}
+                Ok(())
+            })?;
+            recorder.finish(run);
+            if let Some(shards) = shards.as_deref() {
+                unmark_removed_shards(shards, &store, &meta)?;
+            }
+            Ok(())
+        })
+        .await
+    }
 
-fn observe_query<T, E, F>(name: &'static str, query: F) -> Result<T>
-where
-    E: std::error::Error + Send + Sync + 'static,
-    F: FnOnce() -> Result<T, E>,
-{
-    QUERIES_TOTAL.with_label_values(&[name]).inc();
-    let timer = QUERY_DURATION.with_label_values(&[name]).start_timer();
-    let res = query();
-    if res.is_ok() {
-        timer.observe_duration();
-    } else {
-        timer.stop_and_discard();
+    pub async fn compact(&self) -> Result<()> {
+        let meta = self.meta.clone();
+        let pages = self.compact_pages;
+        let gate = self.write_gate.clone();
+        let compact = ipfs_embed_rt::spawn_blocking(move || {
+            let _write = gate.enter();
+            if meta.lock().enable_incremental_vacuum()? {
+                return Ok(());
+            }
+            // the lock is released between slices, so that other queries can interleave.
+            while !meta.lock().incremental_vacuum(pages)? {}
+            Ok(())
+        });
+        observe_future::<_, rusqlite::Error, _>("compact", compact).await
     }
-    Ok(res?)
-}
 
-async fn observe_future<T, E, F>(name: &'static str, query: F) -> Result<T>
-where
-    E: std::error::Error + Send + Sync + 'static,
-    F: Future<Output = Result<T, E>>,
-{
-    QUERIES_TOTAL.with_label_values(&[name]).inc();
-    let timer = QUERY_DURATION.with_label_values(&[name]).start_timer();
-    let res = query.await;
-    if res.is_ok() {
-        timer.observe_duration();
-    } else {
-        timer.stop_and_discard();
+    pub fn free_pages(&self) -> Result<u64> {
+        observe_query("free_pages", || self.meta.lock().free_pages())
     }
-    Ok(res?)
-}
 
-struct SqliteStoreCollector {
-    desc: Desc,
-    store: Arc<Mutex<BlockStore>>,
-}
+    /// Marks a sync as in flight until the returned guard is dropped.
+    pub fn begin_sync(&self) -> SyncGuard {
+        self.syncs.begin()
+    }
 
-impl Collector for SqliteStoreCollector {
-    fn desc(&self) -> Vec<&Desc> {
-        vec![&self.desc]
+    /// Returns the number of syncs in flight.
+    pub fn active_syncs(&self) -> usize {
+        self.syncs.count()
     }
 
-    fn collect(&self) -> Vec<MetricFamily> {
-        let mut family = vec![];
+    pub fn gc_config(&self) -> GcConfig {
+        *self.gc_config.lock()
+    }
 
-        if let Ok(stats) = self.store.lock().get_store_stats() {
-            let store_block_count =
-                IntGauge::new("block_store_block_count", "Number of stored blocks").unwrap();
-            store_block_count.set(stats.count() as _);
-            family.push(store_block_count.collect()[0].clone());
+    pub fn set_gc_config(&self, config: GcConfig) {
+        tracing::debug!("setting gc config {:?}", config);
+        *self.gc_config.lock() = config;
+    }
 
-            let store_size =
-                IntGauge::new("block_store_size", "Size in bytes of stored blocks").unwrap();
-            store_size.set(stats.size() as _);
-            family.push(store_size.collect()[0].clone());
-        }
+    /// Returns the report of the last garbage collector run, with the orphan estimate of
+    /// the last store statistics.
+    pub fn gc_stats(&self) -> GcStats {
+        let mut stats = self.gc_recorder.stats();
+        stats.orphan_estimate = self.stats.load().orphaned_blocks;
+        stats
+    }
 
-        family
+    /// Returns the number and size of the blocks inserted and deleted since the store
+    /// was opened, by codec and hash.
+    pub fn block_distribution(&self) -> BlockDistribution {
+        self.distribution.distribution()
     }
-}
 
-impl SqliteStoreCollector {
-    pub fn new(store: Arc<Mutex<BlockStore>>) -> Self {
-        let desc = Desc::new(
-            "block_store_stats".into(),
-            ".".into(),
-            Default::default(),
-            Default::default(),
-        )
-        .unwrap();
-        Self { store, desc }
+    /// Stretches the gc interval by `factor`, or pauses the garbage collector if `None`.
+    /// Takes effect after the current pass, the configured `GcConfig` is unchanged.
+    pub fn throttle_gc(&self, factor: Option<u32>) {
+        self.gc_throttle.set(factor);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures::stream::StreamExt;
-    use libipld::cbor::DagCborCodec;
-    use libipld::multihash::Code;
-    use libipld::store::DefaultParams;
-    use libipld::{alias, ipld};
+    /// Calls `heartbeat` at the start of every pass of the garbage collector, with the
+    /// time until the next pass starts.
+    pub fn set_gc_heartbeat<F>(&self, heartbeat: F)
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        *self.gc_heartbeat.lock() = Some(Box::new(heartbeat));
+    }
 
-    fn create_block(ipld: &Ipld) -> Block<DefaultParams> {
-        Block::encode(DagCborCodec, Code::Blake3_256, ipld).unwrap()
+    /// Installs a hook running on every alias change, replacing the previous hook.
+    pub fn set_alias_hook<H: AliasCommitHook>(&self, hook: H) {
+        *self.alias_hook.lock() = Some(Arc::new(hook));
     }
 
-    macro_rules! assert_evicted {
-        ($store:expr, $block:expr) => {
-            assert_eq!($store.reverse_alias($block.cid()).unwrap(), None);
+    /// Runs `write` between the hooks of the changes returned by `changes`. Needs to be
+    /// called holding the alias lock, so that the old roots of the changes are the ones
+    /// the write replaces.
+    fn commit_aliases(
+        &self,
+        changes: impl FnOnce() -> Result<Vec<AliasChange>>,
+        write: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let hook = match self.alias_hook.lock().clone() {
+            Some(hook) => hook,
+            None => return write(),
         };
+        let changes = changes()?;
+        if changes.is_empty() {
+            return write();
+        }
+        hook.pre_commit(&changes)?;
+        write()?;
+        hook.post_commit(changes);
+        Ok(())
     }
 
-    macro_rules! assert_pinned {
-        ($store:expr, $block:expr) => {
-            assert_eq!(
-                $store
-                    .reverse_alias($block.cid())
-                    .unwrap()
-                    .map(|a| !a.is_empty()),
-                Some(true)
-            );
-        };
+    /// Returns the change of setting `alias` to `new`, if the root changes.
+    fn alias_change(&self, alias: &[u8], new: Option<&Cid>) -> Result<Vec<AliasChange>> {
+        let old = self.resolve(alias)?;
+        if old.as_ref() == new {
+            return Ok(vec![]);
+        }
+        Ok(vec![AliasChange {
+            alias: alias.to_vec(),
+            old,
+            new: new.copied(),
+        }])
     }
 
-    macro_rules! assert_unpinned {
-        ($store:expr, $block:expr) => {
-            assert_eq!(
-                $store
-                    .reverse_alias($block.cid())
-                    .unwrap()
-                    .map(|a| !a.is_empty()),
-                Some(false)
-            );
+    pub fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        let _guard = self.alias_lock.lock();
+        let _write = self.write_gate.enter();
+        self.commit_aliases(
+            || self.alias_change(alias, cid),
+            || self.alias_unlocked(alias, cid, None),
+        )
+    }
+
+    /// Sets an alias with a metadata blob, for example a json descriptor or a version
+    /// vector. Setting the alias without metadata removes the metadata.
+    pub fn alias_with_meta(&self, alias: &[u8], cid: &Cid, meta: &[u8]) -> Result<()> {
+        if meta.len() > MAX_ALIAS_META_SIZE {
+            return Err(AliasMetaTooLarge(meta.len()).into());
+        }
+        let _guard = self.alias_lock.lock();
+        let _write = self.write_gate.enter();
+        self.commit_aliases(
+            || self.alias_change(alias, Some(cid)),
+            || self.alias_unlocked(alias, Some(cid), Some(meta)),
+        )
+    }
+
+    /// Returns the root of an alias and the metadata set with `alias_with_meta`. The
+    /// metadata is only returned if it was set for the resolved root, so if setting an
+    /// alias was interrupted the previous root is returned without metadata.
+    pub fn resolve_with_meta(&self, alias: &[u8]) -> Result<Option<(Cid, Option<Vec<u8>>)>> {
+        let cid = match self.resolve(alias)? {
+            Some(cid) => cid,
+            None => return Ok(None),
         };
+        let meta = observe_query("alias_meta", || {
+            self.meta.lock().alias_meta(alias, &cid.to_bytes())
+        })?;
+        Ok(Some((cid, meta)))
     }
 
-    fn tracing_try_init() {
-        tracing_subscriber::fmt()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .try_init()
-            .ok();
+    /// Sets an alias that is removed once `ttl` elapsed, after which its dag can be
+    /// collected by the garbage collector. Setting the alias again without a ttl makes it
+    /// permanent. Renamed aliases don't keep their ttl.
+    pub fn alias_with_ttl(&self, alias: &[u8], cid: &Cid, ttl: Duration) -> Result<()> {
+        let _guard = self.alias_lock.lock();
+        let _write = self.write_gate.enter();
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .checked_add(ttl)
+            .map(|expires| expires.as_secs() + u64::from(expires.subsec_nanos() > 0))
+            .unwrap_or(i64::MAX as u64);
+        self.commit_aliases(
+            || self.alias_change(alias, Some(cid)),
+            || {
+                self.alias_unlocked(alias, Some(cid), None)?;
+                observe_query("set_alias_expiry", || {
+                    self.meta.lock().set_alias_expiry(alias, Some(expires))
+                })
+            },
+        )
     }
 
-    fn create_store() -> (
-        StorageService<DefaultParams>,
-        mpsc::UnboundedReceiver<StorageEvent>,
-    ) {
-        let (tx, rx) = mpsc::unbounded();
-        let config = StorageConfig::new(None, 2, Duration::from_secs(100));
-        (StorageService::open(config, tx).unwrap(), rx)
+    /// Returns the time at which an alias set with `alias_with_ttl` is removed.
+    pub fn alias_expiry(&self, alias: &[u8]) -> Result<Option<SystemTime>> {
+        let expires = observe_query("alias_expiry", || self.meta.lock().alias_expiry(alias))?;
+        Ok(expires.map(|expires| UNIX_EPOCH + Duration::from_secs(expires)))
     }
 
-    #[async_std::test]
-    async fn test_store_evict() {
-        tracing_try_init();
-        let (store, mut rx) = create_store();
-        let blocks = [
+    /// Removes the aliases whose ttl elapsed, returning the number of removed aliases. The
+    /// previous roots of expired aliases are not recorded in the alias history.
+    pub fn expire_aliases(&self) -> Result<usize> {
+        let _guard = self.alias_lock.lock();
+        let _write = self.write_gate.enter();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let expired = observe_query("expired_aliases", || self.meta.lock().expired_aliases(now))?;
+        let changes = || -> Result<Vec<AliasChange>> {
+            let mut changes = vec![];
+            for alias in &expired {
+                changes.extend(self.alias_change(alias, None)?);
+            }
+            Ok(changes)
+        };
+        self.commit_aliases(changes, || {
+            for alias in &expired {
+                self.set_alias(alias, None, None, None)?;
+            }
+            Ok(())
+        })?;
+        Ok(expired.len())
+    }
+
+    fn alias_unlocked(&self, alias: &[u8], cid: Option<&Cid>, meta: Option<&[u8]>) -> Result<()> {
+        self.inject(true)?;
+        let prev = if self.alias_history > 0 {
+            self.resolve(alias)?.filter(|prev| Some(prev) != cid)
+        } else {
+            None
+        };
+        self.set_alias(alias, cid, meta, prev.as_ref())?;
+        if cid.is_some() {
+            observe_query("set_alias_expiry", || {
+                self.meta.lock().set_alias_expiry(alias, None)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Sets an alias and its metadata, recording `prev` in the alias history.
+    ///
+    /// The history entry is written in the same transaction as the alias name, and the
+    /// previous root is pinned by a history alias before the alias is moved. If the node
+    /// stops before the history alias is written, it is restored when the store is opened.
+    fn set_alias(
+        &self,
+        alias: &[u8],
+        cid: Option<&Cid>,
+        meta: Option<&[u8]>,
+        prev: Option<&Cid>,
+    ) -> Result<()> {
+        let bytes = cid.map(|cid| cid.to_bytes());
+        let prev_bytes = prev.map(|prev| prev.to_bytes());
+        let seq = observe_query("index_alias", || {
+            let mut store = self.meta.lock();
+            let seq = store.index_alias(alias, bytes.as_deref(), meta, prev_bytes.as_deref())?;
+            if bytes.is_none() {
+                store.set_alias_expiry(alias, None)?;
+            }
+            Ok::<_, rusqlite::Error>(seq)
+        })?;
+        if let (Some(seq), Some(prev)) = (seq, prev) {
+            observe_query("alias", || {
+                self.store
+                    .lock()
+                    .alias(&history_alias(alias, seq), Some(prev))
+            })?;
+        }
+        observe_query("alias", || self.store.lock().alias(alias, cid))?;
+        if cid.is_none() {
+            observe_query("remove_alias_name", || {
+                self.meta.lock().remove_alias_name(alias)
+            })?;
+        }
+        if seq.is_some() {
+            self.trim_alias_history(alias)?;
+        }
+        self.alias_generation.fetch_add(1, Ordering::SeqCst);
+        self.alias_cache.alias_changed();
+        if self.durability == Durability::Strict {
+            observe_query("flush_alias", || self.store.lock().flush())?;
+        }
+        Ok(())
+    }
+
+    /// Returns all aliases and their roots ordered by name.
+    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.aliases_with_prefix(b"")
+    }
+
+    /// Returns the aliases starting with `prefix` and their roots ordered by name. Alias
+    /// names can be used as hierarchical paths like `app/users/42/avatar`, in which case
+    /// the aliases below `app/users` are listed with the prefix `app/users/`.
+    pub fn aliases_with_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.inject(false)?;
+        let names = observe_query("alias_names", || self.meta.lock().alias_names(prefix))?;
+        // the roots are resolved by the block store, which skips the names of aliases
+        // that were removed while the node stopped.
+        let mut aliases = Vec::with_capacity(names.len());
+        let mut store = self.store.lock();
+        for alias in names {
+            if let Some(cid) = observe_query("resolve", || store.resolve(&alias))? {
+                aliases.push((alias, cid));
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Returns at most `limit` aliases starting with `prefix` and their roots ordered by
+    /// name, skipping the aliases starting with any of the `exclude` prefixes.
+    pub fn aliases_with_prefix_limited(
+        &self,
+        prefix: &[u8],
+        exclude: &[&[u8]],
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.inject(false)?;
+        let names = observe_query("alias_names", || {
+            self.meta.lock().alias_names_limited(prefix, exclude, limit)
+        })?;
+        let mut aliases = Vec::with_capacity(names.len());
+        let mut store = self.store.lock();
+        for alias in names {
+            if let Some(cid) = observe_query("resolve", || store.resolve(&alias))? {
+                aliases.push((alias, cid));
+            }
+        }
+        Ok(aliases)
+    }
+
+    /// Removes the names of aliases that were removed from the block store before their
+    /// name was removed.
+    fn prune_alias_names(&self) -> Result<()> {
+        let _guard = self.alias_lock.lock();
+        let names = observe_query("alias_names", || self.meta.lock().alias_names(b""))?;
+        for alias in names {
+            if observe_query("resolve", || self.store.lock().resolve(&alias))?.is_none() {
+                observe_query("remove_alias_name", || {
+                    self.meta.lock().remove_alias_name(&alias)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all aliases starting with `prefix`, returning the number of removed
+    /// aliases.
+    pub fn remove_aliases(&self, prefix: &[u8]) -> Result<usize> {
+        let _guard = self.alias_lock.lock();
+        let _write = self.write_gate.enter();
+        let aliases = self.aliases_with_prefix(prefix)?;
+        let changes = || -> Result<Vec<AliasChange>> {
+            Ok(aliases
+                .iter()
+                .map(|(alias, cid)| AliasChange {
+                    alias: alias.clone(),
+                    old: Some(*cid),
+                    new: None,
+                })
+                .collect())
+        };
+        self.commit_aliases(changes, || {
+            for (alias, _) in &aliases {
+                self.alias_unlocked(alias, None, None)?;
+            }
+            Ok(())
+        })?;
+        Ok(aliases.len())
+    }
+
+    /// Replaces the prefix `from` of all aliases starting with `from` with `to`, returning
+    /// the number of renamed aliases. Fails with `AliasExists` without changing any alias
+    /// if a new name is already taken by an alias outside of the renamed ones. Renamed
+    /// aliases keep their metadata.
+    ///
+    /// The history and expiry of the renamed aliases move with them. Other alias
+    /// operations wait for the rename to complete, so they never observe a partial rename.
+    /// The rename is journaled before the aliases are moved, and an interrupted rename is
+    /// completed when the store is opened.
+    pub fn rename_aliases(&self, from: &[u8], to: &[u8]) -> Result<usize> {
+        let _guard = self.alias_lock.lock();
+        let _write = self.write_gate.enter();
+        self.inject(true)?;
+        let aliases = self.aliases_with_prefix(from)?;
+        let old = aliases
+            .iter()
+            .map(|(alias, _)| alias.as_slice())
+            .collect::<FnvHashSet<_>>();
+        let mut renames = Vec::with_capacity(aliases.len());
+        for (alias, cid) in &aliases {
+            let new = namespace::rename(alias, from, to);
+            if !old.contains(new.as_slice()) && self.resolve(&new)?.is_some() {
+                return Err(AliasExists(new).into());
+            }
+            let cid = cid.to_bytes();
+            let meta = observe_query("alias_meta", || self.meta.lock().alias_meta(alias, &cid))?;
+            renames.push((alias.clone(), new, cid, meta));
+        }
+        let changes = || Ok(rename_changes(&aliases, from, to));
+        self.commit_aliases(changes, || {
+            observe_query("begin_rename", || self.meta.lock().begin_rename(&renames))?;
+            self.apply_renames()
+        })?;
+        Ok(aliases.len())
+    }
+
+    /// Moves the block store aliases of a journaled rename, including the history aliases
+    /// pinning the previous roots. Moving an alias is idempotent, so an interrupted rename
+    /// is completed by applying it again.
+    fn apply_renames(&self) -> Result<()> {
+        let renames = observe_query("alias_renames", || self.meta.lock().alias_renames())?;
+        if renames.is_empty() {
+            return Ok(());
+        }
+        for (old, new, cid) in &renames {
+            let cid = Cid::try_from(cid.as_slice())?;
+            observe_query("alias", || self.store.lock().alias(new, Some(&cid)))?;
+            let history = observe_query("alias_history", || {
+                self.meta.lock().alias_history(new, usize::MAX)
+            })?;
+            for (seq, prev) in history {
+                let prev = Cid::try_from(prev)?;
+                observe_query("alias", || {
+                    let mut store = self.store.lock();
+                    store.alias(&history_alias(new, seq), Some(&prev))?;
+                    store.alias(&history_alias(old, seq), None)
+                })?;
+            }
+        }
+        let new = renames
+            .iter()
+            .map(|(_, new, _)| new.as_slice())
+            .collect::<FnvHashSet<_>>();
+        for (old, _, _) in &renames {
+            if !new.contains(old.as_slice()) {
+                observe_query("alias", || self.store.lock().alias(old, None))?;
+                observe_query("remove_alias_name", || {
+                    self.meta.lock().remove_alias_name(old)
+                })?;
+            }
+        }
+        observe_query("finish_rename", || self.meta.lock().finish_rename())?;
+        for alias in new {
+            self.trim_alias_history(alias)?;
+        }
+        self.alias_generation.fetch_add(1, Ordering::SeqCst);
+        self.alias_cache.alias_changed();
+        if self.durability == Durability::Strict {
+            observe_query("flush_alias", || self.store.lock().flush())?;
+        }
+        Ok(())
+    }
+
+    /// Returns a counter that is incremented whenever an alias changes.
+    pub fn alias_generation(&self) -> u64 {
+        self.alias_generation.load(Ordering::SeqCst)
+    }
+
+    /// Releases the roots that fell out of the history of `alias`. The history alias is
+    /// removed before the history entry, so an interrupted trim is completed by the next.
+    fn trim_alias_history(&self, alias: &[u8]) -> Result<()> {
+        let history = observe_query("alias_history", || {
+            self.meta.lock().alias_history(alias, usize::MAX)
+        })?;
+        for (seq, _) in history.into_iter().skip(self.alias_history) {
+            observe_query("alias", || {
+                self.store.lock().alias(&history_alias(alias, seq), None)
+            })?;
+            observe_query("remove_alias_history", || {
+                self.meta.lock().remove_alias_history(seq)
+            })?;
+            self.alias_cache.alias_changed();
+        }
+        Ok(())
+    }
+
+    /// Returns up to `n` previous roots of `alias`, most recent first.
+    pub fn alias_history(&self, alias: &[u8], n: usize) -> Result<Vec<Cid>> {
+        let history = observe_query("alias_history", || self.meta.lock().alias_history(alias, n))?;
+        history
+            .into_iter()
+            .map(|(_, cid)| Ok(Cid::try_from(cid)?))
+            .collect()
+    }
+
+    pub fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
+        self.inject(false)?;
+        self.alias_cache.resolve(alias, || {
+            observe_query("resolve", || self.store.lock().resolve(alias))
+        })
+    }
+
+    /// Returns the aliases pinning `cid`. The aliases pinning the alias history are not
+    /// returned.
+    pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
+        self.inject(false)?;
+        let aliases = self.alias_cache.reverse_alias(cid, || {
+            observe_query("reverse_alias", || self.store.lock().reverse_alias(cid))
+        })?;
+        Ok(aliases.map(|aliases| {
+            aliases
+                .into_iter()
+                .filter(|alias| !alias.starts_with(ALIAS_HISTORY_PREFIX))
+                .collect()
+        }))
+    }
+
+    /// Pins the roots of the alias history whose history alias wasn't written before the
+    /// node stopped.
+    fn restore_alias_history(&self) -> Result<()> {
+        let history = observe_query("all_alias_history", || self.meta.lock().all_alias_history())?;
+        for (seq, alias, cid) in history {
+            let name = history_alias(&alias, seq);
+            let cid = Cid::try_from(cid)?;
+            if self.store.lock().resolve(&name)?.as_ref() != Some(&cid) {
+                tracing::info!("restoring alias history {}", seq);
+                self.store.lock().alias(&name, Some(&cid))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the blocks of the dag rooted at `cid` that are in the store, using the
+    /// references recorded by the store instead of decoding the blocks. The dags of
+    /// inline blocks aren't included.
+    pub fn stored_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        observe_query("stored_blocks", || {
+            let mut store = self.store.lock();
+            let missing = store.get_missing_blocks::<FnvHashSet<Cid>>(cid)?;
+            let mut blocks = store.get_descendants::<Vec<Cid>>(cid)?;
+            blocks.retain(|cid| !missing.contains(cid));
+            Ok::<_, ipfs_sqlite_block_store::BlockStoreError>(blocks)
+        })
+    }
+
+    /// Returns the blocks of the dag rooted at `cid` that are not in the store. The links
+    /// of inline blocks are followed, but inline blocks are never missing.
+    pub fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.inject(false)?;
+        let get_missing = |cid: &Cid| {
+            observe_query("missing_blocks", || {
+                self.store.lock().get_missing_blocks::<Vec<Cid>>(cid)
+            })
+        };
+        // the stack is filled in reverse, so that the blocks are returned in the order of
+        // the links.
+        let mut stack = get_missing(cid)?;
+        stack.reverse();
+        let mut missing = vec![];
+        while let Some(cid) = stack.pop() {
+            if let Some(data) = inline_data(&cid) {
+                let block = Block::<S>::new_unchecked(cid, data.to_vec());
+                let mut refs = vec![];
+                block.references(&mut refs)?;
+                for cid in refs.iter().rev() {
+                    stack.extend(get_missing(cid)?.into_iter().rev());
+                }
+            } else {
+                missing.push(cid);
+            }
+        }
+        Ok(missing)
+    }
+
+    pub fn published(&self) -> Result<Vec<PublishedRecord>> {
+        observe_query("published", || self.meta.lock().published())
+    }
+
+    /// Returns the published records due for republishing at unix time `now`.
+    pub fn due_published(&self, now: u64) -> Result<Vec<PublishedRecord>> {
+        observe_query("due_published", || self.meta.lock().due_published(now))
+    }
+
+    pub fn publish(&self, record: &PublishedRecord) -> Result<()> {
+        let _write = self.write_gate.enter();
+        observe_query("publish", || self.meta.lock().publish(record))
+    }
+
+    /// Makes all published records due, so that they are republished by the next run of
+    /// the republisher.
+    pub fn reschedule_published(&self) -> Result<()> {
+        let _write = self.write_gate.enter();
+        observe_query("reschedule_published", || {
+            self.meta.lock().reschedule_published()
+        })
+    }
+
+    pub fn unpublish(&self, key: &[u8]) -> Result<()> {
+        let _write = self.write_gate.enter();
+        observe_query("unpublish", || self.meta.lock().unpublish(key))
+    }
+
+    /// Records the ids of gossip messages received on `topic`, returning `false` for the
+    /// messages seen within the last `StorageConfig::gossip_dedup_ttl`.
+    pub fn mark_seen(&self, topic: &str, ids: &[Vec<u8>]) -> Result<Vec<bool>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let expires = now.saturating_add(self.gossip_dedup_ttl.as_secs());
+        let _write = self.write_gate.enter();
+        observe_query("mark_seen", || {
+            self.meta
+                .lock()
+                .mark_seen(topic, ids, now, expires, self.gossip_dedup_capacity)
+        })
+    }
+
+    /// Returns the time messages are kept in the outbox, `None` if the outbox is
+    /// disabled.
+    pub fn outbox_ttl(&self) -> Option<Duration> {
+        self.outbox_ttl
+    }
+
+    /// Queues a message in the outbox until it expires after `StorageConfig::outbox_ttl`.
+    pub fn push_outbox(&self, topic: &str, msg: &[u8]) -> Result<()> {
+        self.queue_outbox(topic, msg, true)?;
+        Ok(())
+    }
+
+    /// Queues a message in the outbox if `offline` or if messages of `topic` are waiting
+    /// in the outbox, returning if the message was queued. Messages are queued atomically
+    /// with removing the messages of the topic, so that a message is never queued behind
+    /// a topic that was just flushed.
+    pub fn queue_outbox(&self, topic: &str, msg: &[u8], offline: bool) -> Result<bool> {
+        let mut outbox = self.outbox.lock();
+        if !offline && !outbox.contains_key(topic) {
+            return Ok(false);
+        }
+        let ttl = self.outbox_ttl.unwrap_or_default();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let expires = now.saturating_add(ttl.as_secs());
+        let _write = self.write_gate.enter();
+        observe_query("push_outbox", || {
+            self.meta.lock().push_outbox(topic, msg, expires)
+        })?;
+        *outbox.entry(topic.to_string()).or_default() += 1;
+        Ok(true)
+    }
+
+    /// Removes the expired messages from the outbox and returns the remaining messages in
+    /// the order they were queued.
+    pub fn outbox(&self) -> Result<Vec<OutboxRecord>> {
+        let mut outbox = self.outbox.lock();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // expired messages are removed.
+        let _write = self.write_gate.enter();
+        let records = observe_query("outbox", || self.meta.lock().outbox(now))?;
+        outbox.clear();
+        for record in &records {
+            *outbox.entry(record.topic.clone()).or_default() += 1;
+        }
+        Ok(records)
+    }
+
+    /// Returns the number of messages in the outbox that didn't expire.
+    pub fn outbox_len(&self) -> Result<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        observe_query("outbox_len", || self.meta.lock().outbox_len(now))
+    }
+
+    /// Removes the message `seq` of `topic` from the outbox once it was published.
+    pub fn remove_outbox(&self, seq: u64, topic: &str) -> Result<()> {
+        let mut outbox = self.outbox.lock();
+        let _write = self.write_gate.enter();
+        observe_query("remove_outbox", || self.meta.lock().remove_outbox(seq))?;
+        if let Some(len) = outbox.get_mut(topic) {
+            *len -= 1;
+            if *len == 0 {
+                outbox.remove(topic);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the persisted statistics of peers.
+    pub fn peer_stats(&self) -> Result<Vec<PeerStatsRecord>> {
+        observe_query("peer_stats", || self.meta.lock().peer_stats())
+    }
+
+    /// Persists the statistics of peers. Statistics of peers that weren't updated within
+    /// `StorageConfig::peer_stats_retention` are removed.
+    pub fn save_peer_stats(&self, records: &[PeerStatsRecord]) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let stale = now.saturating_sub(self.peer_stats_retention.as_secs());
+        let _write = self.write_gate.enter();
+        observe_query("save_peer_stats", || {
+            self.meta.lock().save_peer_stats(records, stale)
+        })
+    }
+
+    pub fn provenance(&self, cid: &Cid) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        observe_query("provenance", || {
+            self.meta.lock().provenance(&cid.to_bytes())
+        })
+    }
+
+    pub fn add_provenance(&self, cid: &Cid, public_key: &[u8], signature: &[u8]) -> Result<()> {
+        let _write = self.write_gate.enter();
+        observe_query("add_provenance", || {
+            self.meta
+                .lock()
+                .add_provenance(&cid.to_bytes(), public_key, signature)
+        })
+    }
+
+    pub fn remove_provenance(&self, cid: &Cid) -> Result<()> {
+        let _write = self.write_gate.enter();
+        observe_query("remove_provenance", || {
+            self.meta.lock().remove_provenance(&cid.to_bytes())
+        })
+    }
+
+    /// Returns `true` if the block is on the deny list.
+    pub fn is_denied(&self, cid: &Cid) -> bool {
+        self.denylist.lock().contains(cid)
+    }
+
+    /// Returns the blocks on the deny list.
+    pub fn denylist(&self) -> Vec<Cid> {
+        self.denylist.lock().iter().copied().collect()
+    }
+
+    /// Adds a block to the deny list. Blocks that are already stored are no longer
+    /// returned, but are only removed by the garbage collector once they are unpinned.
+    pub fn deny(&self, cid: &Cid) -> Result<()> {
+        let _write = self.write_gate.enter();
+        observe_query("deny", || self.meta.lock().deny(&cid.to_bytes()))?;
+        self.denylist.lock().insert(*cid);
+        Ok(())
+    }
+
+    /// Removes a block from the deny list.
+    pub fn allow(&self, cid: &Cid) -> Result<()> {
+        let _write = self.write_gate.enter();
+        observe_query("allow", || self.meta.lock().allow(&cid.to_bytes()))?;
+        self.denylist.lock().remove(cid);
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.inject(true)?;
+        let store = self.store.clone();
+        let gate = self.write_gate.clone();
+        let flush = ipfs_embed_rt::spawn_blocking(move || {
+            let _write = gate.enter();
+            store.lock().flush()
+        });
+        observe_future("flush", flush).await?;
+        let meta = self.meta.clone();
+        let distribution = self.distribution.distribution();
+        let save = ipfs_embed_rt::spawn_blocking(move || {
+            meta.lock().save_block_distribution(&distribution)
+        });
+        observe_future("save_block_distribution", save).await
+    }
+
+    /// Waits up to `timeout` for pending writes, flushes the write ahead logs and keeps
+    /// the database files unmodified until the returned guard is dropped. New writes wait
+    /// at the write gate, without holding the connections, so reads continue.
+    pub async fn freeze_writes(&self, timeout: Duration) -> Result<FreezeGuard> {
+        freeze::freeze(
+            self.write_gate.clone(),
+            self.store.clone(),
+            self.meta.clone(),
+            self.shards.clone(),
+            timeout,
+        )
+        .await
+    }
+
+    /// Flushes the block store to disk and returns the root of `alias`. Once it completes
+    /// the alias and its dag survive a crash or power loss.
+    pub async fn flush_alias(&self, alias: &[u8]) -> Result<Option<Cid>> {
+        let cid = self.resolve(alias)?;
+        self.flush().await?;
+        Ok(cid)
+    }
+
+    /// Returns the current statistics of the block store.
+    pub async fn store_stats(&self) -> Result<StoreStats> {
+        let store = self.store.clone();
+        let meta = self.meta.clone();
+        let shards = self.shards.clone();
+        let stats = ipfs_embed_rt::spawn_blocking(move || {
+            StoreStats::read(&store, &meta, shards.as_deref())
+        })
+        .await?;
+        self.stats.store(Arc::new(stats));
+        Ok(stats)
+    }
+
+    /// Returns the statistics sampled in the background every `stats_interval`. Unlike
+    /// `store_stats` this doesn't touch the database.
+    pub fn stats(&self) -> StoreStats {
+        **self.stats.load()
+    }
+
+    /// Returns the gauge counting the storage events the slowest subscriber didn't
+    /// receive yet.
+    pub fn events_queued(&self) -> IntGauge {
+        self.events_queued.clone()
+    }
+
+    /// Subscribes to the storage events starting with the next event.
+    pub fn subscribe_events(&self) -> StorageEvents {
+        StorageEvents::new(self.events.clone(), self.events.position())
+    }
+
+    /// Subscribes to the storage events starting at the sequence number `position`, for
+    /// example the `position` of a previous subscription. Events that were dropped from
+    /// the buffer already are reported with a single `StorageEvent::Lagged`.
+    pub fn subscribe_events_from(&self, position: u64) -> StorageEvents {
+        StorageEvents::new(self.events.clone(), position)
+    }
+
+    /// Registers the prometheus metrics of the store. Nothing is registered without
+    /// the `metrics` feature.
+    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
+        if cfg!(not(feature = "metrics")) {
+            return Ok(());
+        }
+        registry.register(Box::new(QUERIES_TOTAL.clone()))?;
+        registry.register(Box::new(QUERY_DURATION.clone()))?;
+        registry.register(Box::new(BLOCK_SIZE.clone()))?;
+        registry.register(Box::new(SqliteStoreCollector::new(
+            self.stats.clone(),
+            self.gc_recorder.clone(),
+            self.distribution.clone(),
+        )))?;
+        registry.register(Box::new(self.events_queued.clone()))?;
+        registry.register(Box::new(self.events_lagged.clone()))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct IpfsCacheTracker<T> {
+    tracker: T,
+    events: Arc<EventLog>,
+    shards: Option<Arc<Shards>>,
+    have: Arc<HaveFilter>,
+    gc: GcRecorder,
+    distribution: DistributionRecorder,
+    alias_cache: Arc<AliasCache>,
+}
+
+impl<T: CacheTracker> CacheTracker for IpfsCacheTracker<T> {
+    fn blocks_accessed(&self, blocks: Vec<BlockInfo>) {
+        self.tracker.blocks_accessed(blocks)
+    }
+
+    fn blocks_deleted(&self, blocks: Vec<BlockInfo>) {
+        for block in &blocks {
+            self.have.remove(block.cid());
+            self.alias_cache.block_deleted(block.cid());
+            // the main database only stores a placeholder of sharded blocks.
+            let mut len = block.block_len();
+            if let Some(shards) = self.shards.as_ref() {
+                match shards.remove(block.cid()) {
+                    Ok(Some(sharded)) => len = sharded,
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::warn!("failed to remove {} from shard: {}", block.cid(), err)
+                    }
+                }
+            }
+            self.gc.deleted(len as u64);
+            self.distribution.deleted(block.cid(), len);
+            self.events.push(*block.cid());
+        }
+        self.tracker.blocks_deleted(blocks)
+    }
+
+    fn retain_ids(&self, ids: &[i64]) {
+        self.tracker.retain_ids(ids)
+    }
+
+    fn sort_ids(&self, ids: &mut [i64]) {
+        self.tracker.sort_ids(ids)
+    }
+}
+
+lazy_static! {
+    pub static ref QUERIES_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "block_store_queries_total",
+            "Number of block store requests labelled by type."
+        ),
+        &["type"],
+    )
+    .unwrap();
+    pub static ref QUERY_DURATION: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "block_store_query_duration",
+            "Duration of store queries labelled by type.",
+        ),
+        &["type"],
+    )
+    .unwrap();
+    pub static ref BLOCK_SIZE: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "block_store_block_size_bytes",
+            "Size of inserted and deleted blocks labelled by operation.",
+        )
+        .buckets(prometheus::exponential_buckets(64.0, 4.0, 9).unwrap()),
+        &["op"],
+    )
+    .unwrap();
+}
+
+/// Multihash code of the identity hash.
+const IDENTITY: u64 = 0x00;
+
+/// Returns the data of an inline block. Inline blocks use the identity hash, so the
+/// block data is contained in the cid.
+pub fn inline_data(cid: &Cid) -> Option<&[u8]> {
+    if cid.hash().code() == IDENTITY {
+        Some(cid.hash().digest())
+    } else {
+        None
+    }
+}
+
+/// Prefix of the aliases pinning the roots of the alias history. The prefix is followed
+/// by the big endian sequence number of the entry and the alias.
+pub const ALIAS_HISTORY_PREFIX: &[u8] = b"/ipfs-embed/alias-history/";
+
+/// Collects the blocks stored when inserting `block` and whether they are leaves. Inline
+/// blocks are usually not stored, since their data is part of the cid of the block linking
+/// to them. The ones with links are stored, so that the garbage collector follows their
+/// links.
+fn stored_blocks<S: StoreParams>(block: &Block<S>, blocks: &mut Vec<(Block<S>, bool)>) -> Result<()>
+where
+    Ipld: References<S::Codecs>,
+{
+    let mut refs = vec![];
+    block.references(&mut refs)?;
+    for cid in &refs {
+        if let Some(data) = inline_data(cid) {
+            stored_blocks(&Block::<S>::new_unchecked(*cid, data.to_vec()), blocks)?;
+        }
+    }
+    if inline_data(block.cid()).is_none() || !refs.is_empty() {
+        blocks.push((block.clone(), refs.is_empty()));
+    }
+    Ok(())
+}
+
+/// Removes the data of sharded blocks that weren't written to the main database, because
+/// the store was closed while inserting them.
+fn recover_shards(
+    shards: &Shards,
+    store: &Mutex<BlockStore>,
+    meta: &Mutex<MetaStore>,
+) -> Result<()> {
+    let mut committed = vec![];
+    let mut lost = vec![];
+    for bytes in meta.lock().pending_sharded()? {
+        let cid = Cid::try_from(bytes.as_slice())?;
+        if store.lock().has_block(&cid)? {
+            committed.push(bytes);
+        } else {
+            shards.remove(&cid)?;
+            lost.push(bytes);
+        }
+    }
+    if !lost.is_empty() {
+        tracing::info!(
+            "removed {} sharded blocks that weren't inserted",
+            lost.len()
+        );
+    }
+    meta.lock().commit_sharded(&committed)?;
+    meta.lock().unmark_sharded(&lost)?;
+    Ok(())
+}
+
+/// Removes the marks of the sharded blocks deleted by the garbage collector.
+fn unmark_removed_shards(
+    shards: &Shards,
+    store: &Mutex<BlockStore>,
+    meta: &Mutex<MetaStore>,
+) -> Result<()> {
+    let mut cids = vec![];
+    for cid in shards.take_removed() {
+        // the block may have been inserted again since it was deleted.
+        if !store.lock().has_block(&cid)? {
+            cids.push(cid.to_bytes());
+        }
+    }
+    if !cids.is_empty() {
+        meta.lock().unmark_sharded(&cids)?;
+    }
+    Ok(())
+}
+
+/// Returns the alias changes of renaming the `aliases` starting with `from` below `to`.
+fn rename_changes(aliases: &[(Vec<u8>, Cid)], from: &[u8], to: &[u8]) -> Vec<AliasChange> {
+    let old = aliases.iter().cloned().collect::<FnvHashMap<_, _>>();
+    let mut changes = vec![];
+    let mut renamed = FnvHashSet::default();
+    for (alias, root) in aliases {
+        let new = namespace::rename(alias, from, to);
+        if old.get(&new) != Some(root) {
+            changes.push(AliasChange {
+                alias: new.clone(),
+                old: old.get(&new).copied(),
+                new: Some(*root),
+            });
+        }
+        renamed.insert(new);
+    }
+    for (alias, root) in aliases {
+        if !renamed.contains(alias) {
+            changes.push(AliasChange {
+                alias: alias.clone(),
+                old: Some(*root),
+                new: None,
+            });
+        }
+    }
+    changes
+}
+
+/// Name of the alias keeping the root with sequence number `seq` of the alias history
+/// of `alias` alive.
+fn history_alias(alias: &[u8], seq: i64) -> Vec<u8> {
+    let mut name = ALIAS_HISTORY_PREFIX.to_vec();
+    name.extend_from_slice(&seq.to_be_bytes());
+    name.extend_from_slice(alias);
+    name
+}
+
+fn observe_query<T, E, F>(name: &'static str, query: F) -> Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+    F: FnOnce() -> Result<T, E>,
+{
+    if cfg!(not(feature = "metrics")) {
+        return Ok(query()?);
+    }
+    QUERIES_TOTAL.with_label_values(&[name]).inc();
+    let timer = QUERY_DURATION.with_label_values(&[name]).start_timer();
+    let res = query();
+    if res.is_ok() {
+        timer.observe_duration();
+    } else {
+        timer.stop_and_discard();
+    }
+    Ok(res?)
+}
+
+async fn observe_future<T, E, F>(name: &'static str, query: F) -> Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+    F: Future<Output = Result<T, E>>,
+{
+    if cfg!(not(feature = "metrics")) {
+        return Ok(query.await?);
+    }
+    QUERIES_TOTAL.with_label_values(&[name]).inc();
+    let timer = QUERY_DURATION.with_label_values(&[name]).start_timer();
+    let res = query.await;
+    if res.is_ok() {
+        timer.observe_duration();
+    } else {
+        timer.stop_and_discard();
+    }
+    Ok(res?)
+}
+
+/// Reports the statistics of the block store. Reading the statistics takes the store
+/// lock, so the collector reports a snapshot refreshed in the background instead of
+/// blocking the caller.
+struct SqliteStoreCollector {
+    desc: Desc,
+    stats: Arc<ArcSwap<StoreStats>>,
+    gc: GcRecorder,
+    distribution: DistributionRecorder,
+}
+
+impl Collector for SqliteStoreCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut family = vec![];
+
+        let stats = self.stats.load();
+
+        let store_block_count =
+            IntGauge::new("block_store_block_count", "Number of stored blocks").unwrap();
+        store_block_count.set(stats.blocks as _);
+        family.push(store_block_count.collect()[0].clone());
+
+        let store_size =
+            IntGauge::new("block_store_size", "Size in bytes of stored blocks").unwrap();
+        store_size.set(stats.size as _);
+        family.push(store_size.collect()[0].clone());
+
+        if let Some(pinned) = stats.pinned_blocks {
+            let pinned_count = IntGauge::new(
+                "block_store_pinned_count",
+                "Number of stored blocks reachable from an alias",
+            )
+            .unwrap();
+            pinned_count.set(pinned as _);
+            family.push(pinned_count.collect()[0].clone());
+        }
+
+        if let Some(orphaned) = stats.orphaned_blocks {
+            let orphaned_count = IntGauge::new(
+                "block_store_orphaned_count",
+                "Number of stored blocks not reachable from an alias",
+            )
+            .unwrap();
+            orphaned_count.set(orphaned as _);
+            family.push(orphaned_count.collect()[0].clone());
+        }
+
+        let gc = self.gc.stats();
+
+        let gc_runs = IntGauge::new("block_store_gc_runs", "Number of completed gc runs").unwrap();
+        gc_runs.set(gc.runs as _);
+        family.push(gc_runs.collect()[0].clone());
+
+        if let Some(last_run) = gc.last_run {
+            let gc_last_run = IntGauge::new(
+                "block_store_gc_last_run_timestamp",
+                "Unix time in seconds the last gc run completed",
+            )
+            .unwrap();
+            let secs = last_run
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            gc_last_run.set(secs as _);
+            family.push(gc_last_run.collect()[0].clone());
+        }
+
+        let gc_duration = Gauge::new(
+            "block_store_gc_last_duration_seconds",
+            "Time the last gc run spent collecting",
+        )
+        .unwrap();
+        gc_duration.set(gc.last_duration.as_secs_f64());
+        family.push(gc_duration.collect()[0].clone());
+
+        let gc_scanned = IntGauge::new(
+            "block_store_gc_blocks_scanned",
+            "Number of blocks in the store when the last gc run started",
+        )
+        .unwrap();
+        gc_scanned.set(gc.blocks_scanned as _);
+        family.push(gc_scanned.collect()[0].clone());
+
+        let gc_deleted = IntGauge::new(
+            "block_store_gc_blocks_deleted",
+            "Number of blocks deleted by the last gc run",
+        )
+        .unwrap();
+        gc_deleted.set(gc.blocks_deleted as _);
+        family.push(gc_deleted.collect()[0].clone());
+
+        let gc_reclaimed = IntGauge::new(
+            "block_store_gc_bytes_reclaimed",
+            "Size in bytes of the blocks deleted by the last gc run",
+        )
+        .unwrap();
+        gc_reclaimed.set(gc.bytes_reclaimed as _);
+        family.push(gc_reclaimed.collect()[0].clone());
+
+        let counters = [
+            (
+                "block_store_blocks_inserted_total",
+                "Number of inserted blocks labelled by codec and hash",
+            ),
+            (
+                "block_store_bytes_inserted_total",
+                "Size in bytes of inserted blocks labelled by codec and hash",
+            ),
+            (
+                "block_store_blocks_deleted_total",
+                "Number of deleted blocks labelled by codec and hash",
+            ),
+            (
+                "block_store_bytes_deleted_total",
+                "Size in bytes of deleted blocks labelled by codec and hash",
+            ),
+        ];
+        let counters = counters
+            .iter()
+            .map(|(name, help)| IntCounterVec::new(Opts::new(*name, *help), &["codec", "hash"]))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        for ((codec, hash), counts) in self.distribution.distribution().blocks {
+            let labels = [format!("{:#x}", codec), format!("{:#x}", hash)];
+            let labels = [labels[0].as_str(), labels[1].as_str()];
+            let values = [
+                counts.inserted,
+                counts.inserted_bytes,
+                counts.deleted,
+                counts.deleted_bytes,
+            ];
+            for (counter, value) in counters.iter().zip(values.iter()) {
+                counter.with_label_values(&labels).inc_by(*value);
+            }
+        }
+        for counter in &counters {
+            // families without metrics can't be encoded.
+            family.extend(
+                counter
+                    .collect()
+                    .into_iter()
+                    .filter(|family| !family.get_metric().is_empty()),
+            );
+        }
+
+        family
+    }
+}
+
+impl SqliteStoreCollector {
+    pub fn new(
+        stats: Arc<ArcSwap<StoreStats>>,
+        gc: GcRecorder,
+        distribution: DistributionRecorder,
+    ) -> Self {
+        let desc = Desc::new(
+            "block_store_stats".into(),
+            ".".into(),
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+        Self {
+            stats,
+            gc,
+            distribution,
+            desc,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use libipld::cbor::DagCborCodec;
+    use libipld::multihash::Code;
+    use libipld::raw::RawCodec;
+    use libipld::store::DefaultParams;
+    use libipld::{alias, ipld};
+    use std::time::Instant;
+
+    fn create_block(ipld: &Ipld) -> Block<DefaultParams> {
+        Block::encode(DagCborCodec, Code::Blake3_256, ipld).unwrap()
+    }
+
+    macro_rules! assert_evicted {
+        ($store:expr, $block:expr) => {
+            assert_eq!($store.reverse_alias($block.cid()).unwrap(), None);
+        };
+    }
+
+    macro_rules! assert_pinned {
+        ($store:expr, $block:expr) => {
+            assert_eq!(
+                $store
+                    .reverse_alias($block.cid())
+                    .unwrap()
+                    .map(|a| !a.is_empty()),
+                Some(true)
+            );
+        };
+    }
+
+    macro_rules! assert_unpinned {
+        ($store:expr, $block:expr) => {
+            assert_eq!(
+                $store
+                    .reverse_alias($block.cid())
+                    .unwrap()
+                    .map(|a| !a.is_empty()),
+                Some(false)
+            );
+        };
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ipfs-embed-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Polls `f` until it returns true, failing the test after 10s.
+    async fn eventually(mut f: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            ipfs_embed_rt::Timer::after(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn tracing_try_init() {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .try_init()
+            .ok();
+    }
+
+    fn create_store() -> (StorageService<DefaultParams>, StorageEvents) {
+        let config = StorageConfig::new(None, 2, Duration::from_secs(100));
+        let store = StorageService::open(config).unwrap();
+        let events = store.subscribe_events();
+        (store, events)
+    }
+
+    #[test]
+    fn test_store_recovery() {
+        tracing_try_init();
+        let dir = temp_dir("recovery");
+        let path = dir.join("db");
+        std::fs::write(&path, vec![0xff; 4096]).unwrap();
+        let mut config = StorageConfig::new(Some(path), 2, Duration::from_secs(100));
+        assert!(StorageService::<DefaultParams>::open(config.clone()).is_err());
+
+        config.recovery_mode = RecoveryMode::Salvage;
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let report = store.recovery_report().unwrap();
+        assert_eq!(report.mode, RecoveryMode::Salvage);
+        assert_eq!(report.recovered_blocks, 0);
+        assert!(report.corrupted.as_ref().unwrap().exists());
+        let block = create_block(&ipld!(0));
+        store.insert(&block).unwrap();
+        assert!(store.contains(block.cid()).unwrap());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_store_lock() {
+        tracing_try_init();
+        let dir = temp_dir("lock");
+        let path = dir.join("db");
+        let config = StorageConfig::new(Some(path.clone()), 2, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config.clone()).unwrap();
+        let err = StorageService::<DefaultParams>::open(config).err().unwrap();
+        assert!(err.downcast_ref::<StoreLocked>().is_some());
+
+        let block = create_block(&ipld!(0));
+        store.insert(&block).unwrap();
+        store.alias(b"a", Some(block.cid())).unwrap();
+        let reader = StoreReader::open(&path).unwrap();
+        assert_eq!(
+            reader.get(block.cid()).unwrap(),
+            Some(block.data().to_vec())
+        );
+        assert_eq!(reader.resolve(b"a").unwrap(), Some(*block.cid()));
+        assert_eq!(
+            reader.iter().unwrap().collect::<Vec<_>>(),
+            vec![*block.cid()]
+        );
+        drop(store);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_store_contains_many() {
+        tracing_try_init();
+        let dir = temp_dir("contains-many");
+        let disk = StorageConfig::new(Some(dir.join("db")), 10, Duration::from_secs(100));
+        let memory = StorageConfig::new(None, 10, Duration::from_secs(100));
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        for config in vec![disk, memory] {
+            let store = StorageService::<DefaultParams>::open(config).unwrap();
+            store.insert(&a).unwrap();
+            assert_eq!(
+                store.contains_many(&[*a.cid(), *b.cid()]).unwrap(),
+                vec![true, false]
+            );
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_store_reader_schema() {
+        tracing_try_init();
+        let dir = temp_dir("reader-schema");
+        let path = dir.join("db");
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE cids (id INTEGER PRIMARY KEY, cid BLOB);")
+            .unwrap();
+        let err = StoreReader::open(&path).err().unwrap();
+        assert!(err.downcast_ref::<UnsupportedSchema>().is_some());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[async_std::test]
+    async fn test_store_evict() {
+        tracing_try_init();
+        let (store, mut rx) = create_store();
+        let blocks = [
+            create_block(&ipld!(0)),
+            create_block(&ipld!(1)),
+            create_block(&ipld!(2)),
+            create_block(&ipld!(3)),
+        ];
+        store.insert(&blocks[0]).unwrap();
+        store.insert(&blocks[1]).unwrap();
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_unpinned!(&store, &blocks[0]);
+        assert_unpinned!(&store, &blocks[1]);
+        store.insert(&blocks[2]).unwrap();
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_evicted!(&store, &blocks[0]);
+        assert_unpinned!(&store, &blocks[1]);
+        assert_unpinned!(&store, &blocks[2]);
+        store.get(blocks[1].cid()).unwrap();
+        store.insert(&blocks[3]).unwrap();
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_unpinned!(&store, &blocks[1]);
+        assert_evicted!(&store, &blocks[2]);
+        assert_unpinned!(&store, &blocks[3]);
+        assert_eq!(
+            rx.next().await,
+            Some(StorageEvent::Remove(*blocks[0].cid()))
+        );
+        assert_eq!(
+            rx.next().await,
+            Some(StorageEvent::Remove(*blocks[2].cid()))
+        );
+    }
+
+    #[test]
+    fn test_store_list_by_codec() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = Block::<DefaultParams>::encode(RawCodec, Code::Sha2_256, &b"b"[..]).unwrap();
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        let cbor = store
+            .list_by_codec(DagCborCodec)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(cbor, vec![*a.cid()]);
+        let raw = store.list_by_codec(RawCodec).unwrap().collect::<Vec<_>>();
+        assert_eq!(raw, vec![*b.cid()]);
+        let sha2 = store
+            .list_by_hash(Code::Sha2_256)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(sha2, vec![*b.cid()]);
+    }
+
+    #[test]
+    fn test_store_published() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let record = PublishedRecord {
+            key: b"key".to_vec(),
+            value: Some(b"value".to_vec()),
+            expires: None,
+            republish: 42,
+        };
+        store.publish(&record).unwrap();
+        assert_eq!(store.published().unwrap(), vec![record.clone()]);
+        assert!(store.due_published(41).unwrap().is_empty());
+        assert_eq!(store.due_published(42).unwrap(), vec![record.clone()]);
+        let record2 = PublishedRecord {
+            republish: 43,
+            ..record
+        };
+        store.publish(&record2).unwrap();
+        assert_eq!(store.published().unwrap(), vec![record2.clone()]);
+        store.reschedule_published().unwrap();
+        let record3 = PublishedRecord {
+            republish: 0,
+            ..record2
+        };
+        assert_eq!(store.published().unwrap(), vec![record3]);
+        store.unpublish(b"key").unwrap();
+        assert!(store.published().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_store_alias_history() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, 0, Duration::from_secs(100));
+        config.alias_history = 2;
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let blocks = [
+            create_block(&ipld!(0)),
+            create_block(&ipld!(1)),
+            create_block(&ipld!(2)),
+            create_block(&ipld!(3)),
+        ];
+        let x = alias!(x);
+        for block in &blocks {
+            store.insert(block).unwrap();
+            store.alias(x.as_bytes(), Some(block.cid())).unwrap();
+        }
+        assert_eq!(
+            store.alias_history(x.as_bytes(), 10).unwrap(),
+            vec![*blocks[2].cid(), *blocks[1].cid()]
+        );
+        assert_eq!(
+            store.alias_history(x.as_bytes(), 1).unwrap(),
+            vec![*blocks[2].cid()]
+        );
+        // the history keeps the previous roots alive without exposing its aliases.
+        store.evict().await.unwrap();
+        assert!(store.get(blocks[0].cid()).unwrap().is_none());
+        assert!(store.get(blocks[1].cid()).unwrap().is_some());
+        assert!(store.get(blocks[2].cid()).unwrap().is_some());
+        assert_unpinned!(&store, &blocks[1]);
+        assert_unpinned!(&store, &blocks[2]);
+        assert_pinned!(&store, &blocks[3]);
+    }
+
+    #[async_std::test]
+    async fn test_store_alias_history_restore() {
+        tracing_try_init();
+        let dir = temp_dir("alias-history-restore");
+        let mut config = StorageConfig::new(Some(dir.join("db")), 0, Duration::from_secs(100));
+        config.alias_history = 2;
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        let x = alias!(x);
+        {
+            let store = StorageService::<DefaultParams>::open(config.clone()).unwrap();
+            store.insert(&a).unwrap();
+            store.insert(&b).unwrap();
+            store.alias(x.as_bytes(), Some(a.cid())).unwrap();
+            store.alias(x.as_bytes(), Some(b.cid())).unwrap();
+            // simulates stopping before the history alias was written.
+            let seq = store.meta.lock().alias_history(x.as_bytes(), 1).unwrap()[0].0;
+            store
+                .store
+                .lock()
+                .alias(&history_alias(x.as_bytes(), seq), None)
+                .unwrap();
+        }
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        store.evict().await.unwrap();
+        assert!(store.get(a.cid()).unwrap().is_some());
+        assert_eq!(
+            store.alias_history(x.as_bytes(), 10).unwrap(),
+            vec![*a.cid()]
+        );
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rename_aliases_recovery() {
+        tracing_try_init();
+        let dir = temp_dir("rename-recovery");
+        let mut config = StorageConfig::new(Some(dir.join("db")), 0, Duration::from_secs(100));
+        config.alias_history = 2;
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        {
+            let store = StorageService::<DefaultParams>::open(config.clone()).unwrap();
+            store.insert(&a).unwrap();
+            store.insert(&b).unwrap();
+            store.alias(b"app/x", Some(a.cid())).unwrap();
+            store.alias(b"app/x", Some(b.cid())).unwrap();
+            store.alias(b"app/v1/x", Some(a.cid())).unwrap();
+            // simulates stopping after the rename was journaled.
+            let renames = vec![
+                (
+                    b"app/x".to_vec(),
+                    b"app/v1/x".to_vec(),
+                    b.cid().to_bytes(),
+                    None,
+                ),
+                (
+                    b"app/v1/x".to_vec(),
+                    b"app/v1/v1/x".to_vec(),
+                    a.cid().to_bytes(),
+                    None,
+                ),
+            ];
+            store.meta.lock().begin_rename(&renames).unwrap();
+        }
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        assert_eq!(store.resolve(b"app/x").unwrap(), None);
+        assert_eq!(store.resolve(b"app/v1/x").unwrap(), Some(*b.cid()));
+        assert_eq!(store.resolve(b"app/v1/v1/x").unwrap(), Some(*a.cid()));
+        assert_eq!(store.aliases().unwrap().len(), 2);
+        assert_eq!(
+            store.alias_history(b"app/v1/x", 10).unwrap(),
+            vec![*a.cid()]
+        );
+        let seq = store.meta.lock().alias_history(b"app/v1/x", 1).unwrap()[0].0;
+        let mut block_store = store.store.lock();
+        assert_eq!(
+            block_store.resolve(&history_alias(b"app/x", seq)).unwrap(),
+            None
+        );
+        assert_eq!(
+            block_store
+                .resolve(&history_alias(b"app/v1/x", seq))
+                .unwrap(),
+            Some(*a.cid())
+        );
+        drop(block_store);
+        assert!(store.meta.lock().alias_renames().unwrap().is_empty());
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_aliases_with_prefix_limited() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!(0));
+        store.insert(&a).unwrap();
+        for alias in &[&b"app/a"[..], b"app/b", b"app/c", b"/internal/a", b"other"] {
+            store.alias(alias, Some(a.cid())).unwrap();
+        }
+        let names = |prefix: &[u8], exclude: &[&[u8]], limit| {
+            store
+                .aliases_with_prefix_limited(prefix, exclude, limit)
+                .unwrap()
+                .into_iter()
+                .map(|(alias, _)| alias)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(b"app/", &[], 2),
+            vec![b"app/a".to_vec(), b"app/b".to_vec()]
+        );
+        assert_eq!(names(b"app/", &[b"app/a"], 1), vec![b"app/b".to_vec()]);
+        assert_eq!(
+            names(b"", &[b"/internal/", b"app/"], 10),
+            vec![b"other".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_store_alias_names_prune() {
+        tracing_try_init();
+        let dir = temp_dir("alias-names-prune");
+        let config = StorageConfig::new(Some(dir.join("db")), 0, Duration::from_secs(100));
+        let a = create_block(&ipld!(0));
+        {
+            let store = StorageService::<DefaultParams>::open(config.clone()).unwrap();
+            store.insert(&a).unwrap();
+            store.alias(b"a", Some(a.cid())).unwrap();
+            store.alias(b"b", Some(a.cid())).unwrap();
+            // simulates stopping after the alias was removed but before its name was.
+            store.store.lock().alias(b"a", None).unwrap();
+            assert_eq!(store.aliases().unwrap(), vec![(b"b".to_vec(), *a.cid())]);
+            assert_eq!(store.resolve_with_meta(b"a").unwrap(), None);
+        }
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        assert_eq!(
+            store.meta.lock().alias_names(b"").unwrap(),
+            vec![b"b".to_vec()]
+        );
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_store_denylist() {
+        tracing_try_init();
+        let dir = temp_dir("deny");
+        let config = StorageConfig::new(Some(dir.join("db")), 2, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config.clone()).unwrap();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        store.insert(&a).unwrap();
+        store.deny(a.cid()).unwrap();
+        store.deny(b.cid()).unwrap();
+        let err = store.get(a.cid()).err().unwrap();
+        assert!(err.downcast_ref::<Denied>().is_some());
+        let err = store.insert(&b).err().unwrap();
+        assert!(err.downcast_ref::<Denied>().is_some());
+        store.allow(a.cid()).unwrap();
+        assert_eq!(store.get(a.cid()).unwrap(), Some(a.data().to_vec()));
+        drop(store);
+
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        assert_eq!(store.denylist(), vec![*b.cid()]);
+        assert!(store.is_denied(b.cid()));
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_store_index_hook() {
+        struct Index;
+
+        impl IndexHook for Index {
+            fn init(&self, conn: &rusqlite::Connection) -> Result<()> {
+                conn.execute_batch("CREATE TABLE IF NOT EXISTS numbers (cid BLOB, n INTEGER)")?;
+                Ok(())
+            }
+
+            fn index(&self, tx: &Transaction, cid: &Cid, ipld: &Ipld) -> Result<()> {
+                match ipld {
+                    Ipld::Integer(n) => {
+                        tx.execute(
+                            "INSERT INTO numbers (cid, n) VALUES (?, ?)",
+                            rusqlite::params![cid.to_bytes(), *n as i64],
+                        )?;
+                        Ok(())
+                    }
+                    _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "nan").into()),
+                }
+            }
+        }
+
+        tracing_try_init();
+        let (store, _rx) = create_store();
+        store.set_index_hook(Index).unwrap();
+        let a = create_block(&ipld!(42));
+        let b = create_block(&ipld!("42"));
+        store.insert(&a).unwrap();
+        assert!(store.insert(&b).is_err());
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(!store.contains(b.cid()).unwrap());
+        let n: i64 = store
+            .meta
+            .lock()
+            .connection()
+            .query_row("SELECT n FROM numbers", rusqlite::params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(n, 42);
+    }
+
+    #[test]
+    fn test_store_index_hook_on_disk() {
+        struct Index;
+
+        impl IndexHook for Index {
+            fn init(&self, conn: &rusqlite::Connection) -> Result<()> {
+                conn.execute_batch("CREATE TABLE IF NOT EXISTS numbers (cid BLOB, n INTEGER)")?;
+                Ok(())
+            }
+
+            fn index(&self, tx: &Transaction, cid: &Cid, ipld: &Ipld) -> Result<()> {
+                if let Ipld::Integer(n) = ipld {
+                    tx.execute(
+                        "INSERT INTO numbers (cid, n) VALUES (?, ?)",
+                        rusqlite::params![cid.to_bytes(), *n as i64],
+                    )?;
+                }
+                Ok(())
+            }
+        }
+
+        tracing_try_init();
+        let dir = temp_dir("index-hook");
+        let config = StorageConfig::new(Some(dir.join("db")), 100, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        store.set_index_hook(Index).unwrap();
+        for n in 0..10 {
+            let block = create_block(&ipld!(n));
+            store.insert(&block).unwrap();
+            assert!(store.contains(block.cid()).unwrap());
+        }
+        let count: i64 = store
+            .meta
+            .lock()
+            .connection()
+            .query_row("SELECT COUNT(*) FROM numbers", rusqlite::params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 10);
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[async_std::test]
+    async fn test_store_snapshot() {
+        tracing_try_init();
+        let dir = temp_dir("snapshot");
+        let config = StorageConfig::new(Some(dir.join("db")), 0, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        store.insert(&a).unwrap();
+        store.alias(b"a", Some(a.cid())).unwrap();
+        store.flush().await.unwrap();
+
+        let snapshot = store.read_snapshot().unwrap();
+        store.alias(b"a", None).unwrap();
+        store.insert(&b).unwrap();
+        store.evict().await.unwrap();
+        assert!(!store.contains(a.cid()).unwrap());
+
+        assert_eq!(snapshot.get(a.cid()).unwrap(), Some(a.data().to_vec()));
+        assert!(!snapshot.contains(b.cid()).unwrap());
+        assert_eq!(snapshot.aliases().unwrap(), vec![(b"a".to_vec(), *a.cid())]);
+        drop(snapshot);
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+
+        let (store, _rx) = create_store();
+        let err = store.read_snapshot().err().unwrap();
+        assert!(err.downcast_ref::<SnapshotUnsupported>().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_store_shards() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, 0, Duration::from_secs(100));
+        config.shards = 4;
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let leaves = (0..8).map(|i| create_block(&ipld!(i))).collect::<Vec<_>>();
+        let root = create_block(&Ipld::List(
+            leaves
+                .iter()
+                .map(|block| Ipld::Link(*block.cid()))
+                .collect(),
+        ));
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            store.insert(block).unwrap();
+        }
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            assert_eq!(store.get(block.cid()).unwrap().unwrap(), block.data());
+        }
+        let stats = store.shards.as_ref().unwrap().stats().unwrap();
+        assert_eq!(stats.iter().map(|(count, _)| count).sum::<u64>(), 8);
+
+        store.alias(b"root", Some(root.cid())).unwrap();
+        store.evict().await.unwrap();
+        assert!(store.missing_blocks(root.cid()).unwrap().is_empty());
+        assert_eq!(
+            store.get(leaves[0].cid()).unwrap().unwrap(),
+            leaves[0].data()
+        );
+
+        store.alias(b"root", None).unwrap();
+        store.evict().await.unwrap();
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            assert_eq!(store.get(block.cid()).unwrap(), None);
+        }
+        // deleted sharded blocks are counted with their size instead of the placeholder's.
+        let counts = store.block_distribution().codec(0x71);
+        assert_eq!(counts.deleted, 9);
+        assert_eq!(counts.deleted_bytes, counts.inserted_bytes);
+    }
+
+    #[test]
+    fn test_store_shard_layout() {
+        tracing_try_init();
+        let dir = temp_dir("shard-layout");
+        let open = |shards| {
+            let mut config =
+                StorageConfig::new(Some(dir.join("db")), 100, Duration::from_secs(100));
+            config.shards = shards;
+            StorageService::<DefaultParams>::open(config)
+        };
+        let leaves = (0..8).map(|i| create_block(&ipld!(i))).collect::<Vec<_>>();
+        let store = open(2).unwrap();
+        store.insert_batch(&leaves).unwrap();
+        // the node stopped after writing the data of `lost` to its shard.
+        let lost = create_block(&ipld!("lost"));
+        store
+            .meta
+            .lock()
+            .mark_sharded(&[lost.cid().to_bytes()])
+            .unwrap();
+        store
+            .shards
+            .as_ref()
+            .unwrap()
+            .insert_many(vec![(*lost.cid(), lost.data().to_vec())])
+            .unwrap();
+        drop(store);
+
+        let err = open(3).err().unwrap();
+        assert!(err.downcast_ref::<ShardLayoutMismatch>().is_some());
+        let err = open(0).err().unwrap();
+        assert!(err.downcast_ref::<ShardLayoutMismatch>().is_some());
+
+        let store = open(2).unwrap();
+        for block in &leaves {
+            assert_eq!(store.get(block.cid()).unwrap().unwrap(), block.data());
+        }
+        let shards = store.shards.as_ref().unwrap();
+        assert_eq!(shards.get(lost.cid()).unwrap(), None);
+        assert!(!store
+            .meta
+            .lock()
+            .is_sharded(&lost.cid().to_bytes())
+            .unwrap());
+        drop(store);
+
+        std::fs::remove_file(dir.join("db.shard-1")).unwrap();
+        let err = open(2).err().unwrap();
+        assert!(err.downcast_ref::<ShardMissing>().is_some());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[async_std::test]
+    async fn test_store_gc_triggers() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, 1, Duration::from_secs(1000));
+        config.gc_triggers.max_blocks = Some(2);
+        config.gc_triggers.check_interval = Duration::from_millis(10);
+        config.stats_interval = Duration::from_millis(10);
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let mut rx = store.subscribe_events();
+        for i in 0..4 {
+            store.insert(&create_block(&ipld!(i))).unwrap();
+        }
+        store.flush().await.unwrap();
+        let event = async_std::future::timeout(Duration::from_secs(5), rx.next())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(StorageEvent::Remove(_))));
+    }
+
+    #[async_std::test]
+    async fn test_store_stats() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, 10, Duration::from_secs(100));
+        config.shards = 2;
+        config.stats_interval = Duration::from_millis(10);
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let leaf = create_block(&ipld!("leaf"));
+        let root = create_block(&ipld!([Ipld::Link(*leaf.cid())]));
+        store.insert(&leaf).unwrap();
+        store.insert(&root).unwrap();
+        let stats = store.store_stats().await.unwrap();
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.sharded_blocks, 1);
+        assert_eq!(stats.sharded_size, leaf.data().len() as u64);
+        assert!(stats.size >= (leaf.data().len() + root.data().len()) as u64);
+
+        let registry = Registry::new();
+        store.register_metrics(&registry).unwrap();
+        let metrics = registry.gather();
+        let count = metrics
+            .iter()
+            .find(|family| family.get_name() == "block_store_block_count")
+            .unwrap();
+        assert_eq!(count.get_metric()[0].get_gauge().get_value() as u64, 2);
+
+        eventually(|| store.stats().blocks == 2).await;
+        assert_eq!(store.stats().pinned_blocks, Some(0));
+    }
+
+    #[async_std::test]
+    async fn test_store_pinned_stats() {
+        tracing_try_init();
+        let dir = temp_dir("stats");
+        let config = StorageConfig::new(Some(dir.join("db")), 10, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!([Ipld::Link(*a.cid())]));
+        let c = create_block(&ipld!(1));
+        for block in &[&a, &b, &c] {
+            store.insert(block).unwrap();
+        }
+        store.alias(b"b", Some(b.cid())).unwrap();
+        let stats = store.store_stats().await.unwrap();
+        assert_eq!(stats.pinned_blocks, Some(2));
+        assert_eq!(stats.orphaned_blocks, Some(1));
+        assert_eq!(store.stats(), stats);
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[async_std::test]
+    async fn test_store_aliases() {
+        tracing_try_init();
+        let (store, _rx) = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        let generation = store.alias_generation();
+        store.alias(b"b", Some(b.cid())).unwrap();
+        store.alias(b"a", Some(a.cid())).unwrap();
+        assert_eq!(store.alias_generation(), generation + 2);
+        assert_eq!(
+            store.aliases().unwrap(),
+            vec![(b"a".to_vec(), *a.cid()), (b"b".to_vec(), *b.cid())]
+        );
+        store.alias(b"a", None).unwrap();
+        assert_eq!(store.aliases().unwrap(), vec![(b"b".to_vec(), *b.cid())]);
+    }
+
+    #[test]
+    fn test_have_filter() {
+        let filter = HaveFilter::new(100);
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        assert!(!filter.may_contain(a.cid()));
+        filter.insert(a.cid());
+        filter.insert(a.cid());
+        assert!(filter.may_contain(a.cid()));
+        assert!(!filter.may_contain(b.cid()));
+        filter.remove(a.cid());
+        assert!(filter.may_contain(a.cid()));
+        filter.remove(a.cid());
+        assert!(!filter.may_contain(a.cid()));
+        assert!(HaveFilter::new(0).may_contain(b.cid()));
+
+        let (store, _) = create_store();
+        store.insert(&a).unwrap();
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(!store.contains(b.cid()).unwrap());
+        assert_eq!(store.get(b.cid()).unwrap(), None);
+        // inserting a stored block again doesn't count it twice, so the counters are
+        // cleared when the garbage collector deletes it.
+        store.insert(&a).unwrap();
+        store.have.remove(a.cid());
+        assert!(!store.have.may_contain(a.cid()));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_fault_injection() {
+        let (store, _) = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        store.insert(&a).unwrap();
+
+        store.faults().fail_next(1);
+        let err = store.get(a.cid()).unwrap_err();
+        assert!(err.downcast_ref::<InjectedFault>().is_some());
+        assert!(store.get(a.cid()).unwrap().is_some());
+
+        store.faults().set_disk_full(true);
+        let err = store.insert(&b).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::DiskFull
+        ));
+        assert!(store.alias(b"a", Some(a.cid())).is_err());
+        assert!(store.contains(a.cid()).unwrap());
+
+        store.faults().reset();
+        store.faults().set_latency(Duration::from_millis(20));
+        let start = Instant::now();
+        store.insert(&b).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(store.faults().injected(), 3);
+    }
+
+    #[async_std::test]
+    async fn test_gc_throttle() {
+        let throttle = GcThrottle::new();
+        let second = Duration::from_secs(1);
+        assert_eq!(throttle.stretch(second).await, second);
+        throttle.set(Some(4));
+        assert_eq!(throttle.stretch(second).await, second * 4);
+        // stretching saturates instead of overflowing.
+        let max = Duration::from_secs(u64::MAX);
+        assert_eq!(throttle.stretch(max).await, max);
+        // the paused gc waits until it is resumed.
+        throttle.set(None);
+        let mut stretch = Box::pin(throttle.stretch(Duration::default()));
+        assert!(futures::poll!(&mut stretch).is_pending());
+        throttle.set(Some(1));
+        assert_eq!(stretch.await, Duration::default());
+    }
+
+    #[async_std::test]
+    async fn test_gc_sync_deferral() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let guard = store.begin_sync();
+        let guard2 = store.begin_sync();
+        assert_eq!(store.active_syncs(), 2);
+        drop(guard);
+        drop(guard2);
+        assert_eq!(store.active_syncs(), 0);
+
+        let syncs = ActiveSyncs::default();
+        let stats = Arc::new(ArcSwap::from_pointee(StoreStats {
+            blocks: 10,
+            ..Default::default()
+        }));
+        let mut state = GcTriggerState::new(None, 0, stats.clone());
+        let mut triggers = GcTriggers::none();
+        triggers.max_blocks = Some(100);
+        // nothing to wait for without syncs.
+        assert!(
+            state
+                .defer(&syncs, Duration::from_secs(100), &triggers)
+                .await
+        );
+        let _guard = syncs.begin();
+        // the deferral is bounded by `max`.
+        assert!(!state.defer(&syncs, Duration::default(), &triggers).await);
+        // the store is under pressure, so the gc doesn't wait for the sync.
+        stats.store(Arc::new(StoreStats {
+            blocks: 1000,
+            ..Default::default()
+        }));
+        assert!(
+            !state
+                .defer(&syncs, Duration::from_secs(100), &triggers)
+                .await
+        );
+        // until the trigger recovered.
+        assert!(
+            !state
+                .defer(&syncs, Duration::from_secs(100), &triggers)
+                .await
+        );
+    }
+
+    #[test]
+    fn test_alias_prefix() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(b"app/users/1", Some(a.cid())).unwrap();
+        store.alias(b"app/users/2", Some(b.cid())).unwrap();
+        store.alias(b"app/usersx", Some(a.cid())).unwrap();
+        store.alias(b"app/groups/1", Some(b.cid())).unwrap();
+        assert_eq!(
+            store.aliases_with_prefix(b"app/users/").unwrap(),
+            vec![
+                (b"app/users/1".to_vec(), *a.cid()),
+                (b"app/users/2".to_vec(), *b.cid()),
+            ]
+        );
+        assert_eq!(store.aliases_with_prefix(b"").unwrap().len(), 4);
+
+        assert!(store.rename_aliases(b"app/users/", b"app/groups/").is_err());
+        assert_eq!(store.resolve(b"app/users/1").unwrap(), Some(*a.cid()));
+
+        assert_eq!(
+            store.rename_aliases(b"app/users/", b"app/people/").unwrap(),
+            2
+        );
+        assert!(store.aliases_with_prefix(b"app/users/").unwrap().is_empty());
+        assert_eq!(store.resolve(b"app/people/1").unwrap(), Some(*a.cid()));
+        assert_eq!(store.resolve(b"app/people/2").unwrap(), Some(*b.cid()));
+
+        assert_eq!(store.rename_aliases(b"app/", b"app/v1/").unwrap(), 4);
+        assert_eq!(store.aliases_with_prefix(b"app/v1/").unwrap().len(), 4);
+        assert_eq!(store.resolve(b"app/usersx").unwrap(), None);
+
+        assert_eq!(store.remove_aliases(b"app/v1/people/").unwrap(), 2);
+        assert_eq!(store.aliases().unwrap().len(), 2);
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+    }
+
+    #[test]
+    fn test_store_gossip_seen() {
+        tracing_try_init();
+        let dir = temp_dir("seen");
+        let mut config = StorageConfig::new(Some(dir.join("db")), 2, Duration::from_secs(100));
+        config.gossip_dedup_capacity = 2;
+        let id = |id: &[u8]| vec![id.to_vec()];
+        let store = StorageService::<DefaultParams>::open(config.clone()).unwrap();
+        assert_eq!(store.mark_seen("a", &id(b"1")).unwrap(), vec![true]);
+        assert_eq!(store.mark_seen("a", &id(b"1")).unwrap(), vec![false]);
+        assert_eq!(store.mark_seen("b", &id(b"1")).unwrap(), vec![true]);
+        drop(store);
+
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        assert_eq!(store.mark_seen("a", &id(b"1")).unwrap(), vec![false]);
+        assert_eq!(
+            store
+                .mark_seen("a", &[b"2".to_vec(), b"3".to_vec(), b"2".to_vec()])
+                .unwrap(),
+            vec![true, true, false]
+        );
+        // the capacity of the topic is exceeded, so one of the oldest ids was dropped.
+        let seen = [b"1", b"2", b"3"]
+            .iter()
+            .filter(|i| !store.mark_seen("a", &id(&i[..])).unwrap()[0])
+            .count();
+        assert!(seen < 3);
+        // the capacity is per topic.
+        assert_eq!(store.mark_seen("b", &id(b"1")).unwrap(), vec![false]);
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_store_iter_after() {
+        tracing_try_init();
+        let dir = temp_dir("paging");
+        let memory = StorageConfig::new(None, 10, Duration::from_secs(100));
+        let persistent = StorageConfig::new(Some(dir.join("db")), 10, Duration::from_secs(100));
+        for config in vec![memory, persistent] {
+            let store = StorageService::<DefaultParams>::open(config).unwrap();
+            for i in 0..5 {
+                store.insert(&create_block(&ipld!(i))).unwrap();
+            }
+            let mut expected = store.iter().unwrap().collect::<Vec<_>>();
+            expected.sort_by_key(|cid| cid.to_bytes());
+            let mut cids = vec![];
+            loop {
+                let page = store.iter_after(cids.last(), 2).unwrap();
+                if page.is_empty() {
+                    break;
+                }
+                assert!(page.len() <= 2);
+                cids.extend(page);
+            }
+            assert_eq!(cids, expected);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_store_alias_ttl() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        let x = alias!(x);
+        let y = alias!(y);
+        store
+            .alias_with_ttl(x.as_bytes(), a.cid(), Duration::from_secs(0))
+            .unwrap();
+        store
+            .alias_with_ttl(y.as_bytes(), b.cid(), Duration::from_secs(0))
+            .unwrap();
+        assert!(store.alias_expiry(x.as_bytes()).unwrap().is_some());
+        store.alias(y.as_bytes(), Some(b.cid())).unwrap();
+        assert!(store.alias_expiry(y.as_bytes()).unwrap().is_none());
+        assert_eq!(store.expire_aliases().unwrap(), 1);
+        assert_eq!(store.resolve(x.as_bytes()).unwrap(), None);
+        assert!(store.alias_expiry(x.as_bytes()).unwrap().is_none());
+        assert_unpinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        assert_eq!(store.expire_aliases().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_store_peer_stats() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let fresh = PeerStatsRecord {
+            peer: b"a".to_vec(),
+            rtt_ewma: Some(1500),
+            dial_successes: 3,
+            dial_failures: 1,
+            bytes_sent: 1024,
+            bytes_received: 4096,
+            updated: now,
+            auth_failures: 1,
+        };
+        let stale = PeerStatsRecord {
+            peer: b"b".to_vec(),
+            rtt_ewma: None,
+            updated: 0,
+            ..fresh.clone()
+        };
+        store
+            .save_peer_stats(&[fresh.clone(), stale.clone()])
+            .unwrap();
+        assert_eq!(store.peer_stats().unwrap(), vec![fresh.clone()]);
+        let updated = PeerStatsRecord {
+            dial_failures: 2,
+            ..fresh
+        };
+        store.save_peer_stats(&[updated.clone()]).unwrap();
+        assert_eq!(store.peer_stats().unwrap(), vec![updated]);
+    }
+
+    #[test]
+    fn test_outbox() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, 2, Duration::from_secs(100));
+        config.outbox_ttl = Some(Duration::from_secs(60));
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        store.push_outbox("a", b"1").unwrap();
+        store.push_outbox("b", b"2").unwrap();
+        assert_eq!(store.outbox_len().unwrap(), 2);
+        let outbox = store.outbox().unwrap();
+        let msgs = outbox
+            .iter()
+            .map(|record| (record.topic.as_str(), record.msg.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(msgs, vec![("a", &b"1"[..]), ("b", &b"2"[..])]);
+        store.remove_outbox(outbox[0].seq, "a").unwrap();
+        assert_eq!(store.outbox_len().unwrap(), 1);
+        // online messages are only queued behind waiting messages of their topic.
+        assert!(!store.queue_outbox("a", b"3", false).unwrap());
+        assert!(store.queue_outbox("b", b"4", false).unwrap());
+        assert_eq!(store.outbox_len().unwrap(), 2);
+        // without a ttl messages expire right away.
+        let (store, _) = create_store();
+        store.push_outbox("a", b"1").unwrap();
+        assert!(store.outbox().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_gc_stats() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        assert_eq!(store.gc_stats(), GcStats::default());
+        let blocks = [
             create_block(&ipld!(0)),
             create_block(&ipld!(1)),
             create_block(&ipld!(2)),
-            create_block(&ipld!(3)),
         ];
-        store.insert(&blocks[0]).unwrap();
-        store.insert(&blocks[1]).unwrap();
-        store.flush().await.unwrap();
-        store.evict().await.unwrap();
-        assert_unpinned!(&store, &blocks[0]);
-        assert_unpinned!(&store, &blocks[1]);
-        store.insert(&blocks[2]).unwrap();
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
         store.flush().await.unwrap();
+        // the blocks scanned are taken from the sampled statistics.
+        store.store_stats().await.unwrap();
         store.evict().await.unwrap();
         assert_evicted!(&store, &blocks[0]);
-        assert_unpinned!(&store, &blocks[1]);
-        assert_unpinned!(&store, &blocks[2]);
-        store.get(blocks[1].cid()).unwrap();
-        store.insert(&blocks[3]).unwrap();
+        let stats = store.gc_stats();
+        assert_eq!(stats.runs, 1);
+        assert!(stats.last_run.is_some());
+        assert_eq!(stats.blocks_scanned, 3);
+        assert_eq!(stats.blocks_deleted, 1);
+        assert_eq!(stats.bytes_reclaimed, blocks[0].data().len() as u64);
+        assert_eq!(stats.orphan_estimate, None);
+    }
+
+    #[async_std::test]
+    async fn test_alias_cache() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, 2, Duration::from_secs(100));
+        config.alias_cache_ttl = Some(Duration::from_secs(60));
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let a = create_block(&ipld!("a"));
+        let b = create_block(&ipld!([a.cid()]));
+        store.insert(&b).unwrap();
+        store.alias(b"x", Some(b.cid())).unwrap();
+        assert_eq!(store.resolve(b"x").unwrap(), Some(*b.cid()));
+        assert_eq!(store.resolve(b"x").unwrap(), Some(*b.cid()));
+        assert_eq!(store.resolve(b"y").unwrap(), None);
+        store.insert(&a).unwrap();
+        assert_eq!(
+            store.reverse_alias(a.cid()).unwrap(),
+            Some(vec![b"x".to_vec()])
+        );
+        store.alias(b"y", Some(a.cid())).unwrap();
+        assert_eq!(store.resolve(b"y").unwrap(), Some(*a.cid()));
+        let mut aliases = store.reverse_alias(a.cid()).unwrap().unwrap();
+        aliases.sort();
+        assert_eq!(aliases, vec![b"x".to_vec(), b"y".to_vec()]);
+        store.alias(b"x", None).unwrap();
+        assert_eq!(store.resolve(b"x").unwrap(), None);
+        assert_eq!(
+            store.reverse_alias(a.cid()).unwrap(),
+            Some(vec![b"y".to_vec()])
+        );
+    }
+
+    #[async_std::test]
+    async fn test_block_distribution() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let blocks = [
+            create_block(&ipld!(0)),
+            create_block(&ipld!(1)),
+            create_block(&ipld!(2)),
+        ];
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
+        // inserting a stored block again isn't counted.
+        store.insert(&blocks[1]).unwrap();
+        let raw = Block::<DefaultParams>::encode(RawCodec, Code::Sha2_256, &b"raw"[..]).unwrap();
+        store.insert(&raw).unwrap();
         store.flush().await.unwrap();
         store.evict().await.unwrap();
-        assert_unpinned!(&store, &blocks[1]);
-        assert_evicted!(&store, &blocks[2]);
-        assert_unpinned!(&store, &blocks[3]);
+
+        let dist = store.block_distribution();
+        let cbor = dist.codec(0x71);
+        assert_eq!(cbor.inserted, 3);
         assert_eq!(
-            rx.next().await,
-            Some(StorageEvent::Remove(*blocks[0].cid()))
+            cbor.inserted_bytes,
+            blocks.iter().map(|b| b.data().len() as u64).sum::<u64>()
+        );
+        assert_eq!(dist.codec(0x55).inserted, 1);
+        assert_eq!(dist.hash(0x12).inserted, 1);
+        assert_eq!(dist.hash(0x1e).inserted, 3);
+        assert_eq!(dist.blocks.values().map(|c| c.deleted).sum::<u64>(), 2);
+
+        let registry = Registry::new();
+        store.register_metrics(&registry).unwrap();
+        let families = registry.gather();
+        assert!(families
+            .iter()
+            .any(|family| family.get_name() == "block_store_blocks_inserted_total"));
+    }
+
+    #[async_std::test]
+    async fn test_block_distribution_persisted() {
+        tracing_try_init();
+        let dir = temp_dir("block-distribution");
+        let config = StorageConfig::new(Some(dir.join("db")), 0, Duration::from_secs(100));
+        let blocks = [create_block(&ipld!(0)), create_block(&ipld!(1))];
+        {
+            let store = StorageService::<DefaultParams>::open(config.clone()).unwrap();
+            store.insert(&blocks[0]).unwrap();
+            store.flush().await.unwrap();
+        }
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        store.insert(&blocks[1]).unwrap();
+        let counts = store.block_distribution().codec(0x71);
+        assert_eq!(counts.inserted, 2);
+        assert_eq!(
+            counts.inserted_bytes,
+            blocks.iter().map(|b| b.data().len() as u64).sum::<u64>()
+        );
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_alias_meta() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias_with_meta(b"app/a", a.cid(), b"v1").unwrap();
+        assert_eq!(
+            store.resolve_with_meta(b"app/a").unwrap(),
+            Some((*a.cid(), Some(b"v1".to_vec())))
         );
+        assert_eq!(store.resolve(b"app/a").unwrap(), Some(*a.cid()));
+        assert_pinned!(&store, &a);
+
+        store.alias_with_meta(b"app/a", b.cid(), b"v2").unwrap();
         assert_eq!(
-            rx.next().await,
-            Some(StorageEvent::Remove(*blocks[2].cid()))
+            store.resolve_with_meta(b"app/a").unwrap(),
+            Some((*b.cid(), Some(b"v2".to_vec())))
+        );
+
+        assert_eq!(store.rename_aliases(b"app/", b"v2/").unwrap(), 1);
+        assert_eq!(
+            store.resolve_with_meta(b"v2/a").unwrap(),
+            Some((*b.cid(), Some(b"v2".to_vec())))
+        );
+        assert_eq!(store.resolve_with_meta(b"app/a").unwrap(), None);
+
+        store.alias(b"v2/a", Some(a.cid())).unwrap();
+        assert_eq!(
+            store.resolve_with_meta(b"v2/a").unwrap(),
+            Some((*a.cid(), None))
+        );
+
+        let meta = vec![0; MAX_ALIAS_META_SIZE + 1];
+        assert!(store.alias_with_meta(b"v2/a", b.cid(), &meta).is_err());
+        assert_eq!(store.resolve(b"v2/a").unwrap(), Some(*a.cid()));
+    }
+
+    #[test]
+    fn test_alias_meta_interrupted() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias_with_meta(b"app/a", a.cid(), b"v1").unwrap();
+        // the metadata of the new root is committed, but the block store alias isn't.
+        store
+            .meta
+            .lock()
+            .index_alias(b"app/a", Some(&b.cid().to_bytes()), Some(b"v2"), None)
+            .unwrap();
+        assert_eq!(
+            store.resolve_with_meta(b"app/a").unwrap(),
+            Some((*a.cid(), None))
+        );
+        store.alias_with_meta(b"app/a", b.cid(), b"v2").unwrap();
+        assert_eq!(
+            store.resolve_with_meta(b"app/a").unwrap(),
+            Some((*b.cid(), Some(b"v2".to_vec())))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_freeze_writes() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let block = create_block(&ipld!({ "freeze": 0 }));
+        let guard = store.freeze_writes(Duration::from_secs(1)).await.unwrap();
+        // reads don't wait for the freeze.
+        assert!(!store.contains(block.cid()).unwrap());
+        let err = store
+            .freeze_writes(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<FreezeTimeout>().is_some());
+
+        let store2 = store.clone();
+        let block2 = block.clone();
+        let mut insert = ipfs_embed_rt::spawn_blocking(move || store2.insert(&block2));
+        ipfs_embed_rt::Timer::after(Duration::from_millis(100)).await;
+        assert!(futures::poll!(&mut insert).is_pending());
+        drop(guard);
+        insert.await.unwrap();
+        assert!(store.contains(block.cid()).unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_storage_events_lagged() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, 0, Duration::from_secs(100));
+        config.event_capacity = 2;
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let mut early = store.subscribe_events();
+        let blocks = (0..4).map(|i| create_block(&ipld!(i))).collect::<Vec<_>>();
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
+        store.evict().await.unwrap();
+
+        let mut late = store.subscribe_events_from(early.position());
+        assert_eq!(late.next().await, Some(StorageEvent::Lagged(2)));
+        assert!(matches!(late.next().await, Some(StorageEvent::Remove(_))));
+        assert!(matches!(late.next().await, Some(StorageEvent::Remove(_))));
+        assert_eq!(late.position(), 4);
+        assert_eq!(early.next().await, Some(StorageEvent::Lagged(2)));
+        assert_eq!(store.subscribe_events().position(), 4);
+    }
+
+    #[async_std::test]
+    async fn test_strict_durability() {
+        tracing_try_init();
+        let dir = temp_dir("durability");
+        let path = dir.join("db");
+        let block = create_block(&ipld!({ "durable": 0 }));
+        {
+            let mut config = StorageConfig::new(Some(path.clone()), 2, Duration::from_secs(100));
+            config.durability = Durability::Strict;
+            let store = StorageService::<DefaultParams>::open(config).unwrap();
+            let synchronous: i64 = store
+                .meta
+                .lock()
+                .connection()
+                .query_row("PRAGMA synchronous", rusqlite::params![], |row| row.get(0))
+                .unwrap();
+            assert_eq!(synchronous, 2);
+            store.insert(&block).unwrap();
+            store.alias(b"root", Some(block.cid())).unwrap();
+            assert_eq!(
+                store.flush_alias(b"root").await.unwrap(),
+                Some(*block.cid())
+            );
+            assert_eq!(store.flush_alias(b"missing").await.unwrap(), None);
+        }
+        let config = StorageConfig::new(Some(path), 2, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        assert_eq!(store.resolve(b"root").unwrap(), Some(*block.cid()));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_store_reference_graph() {
+        tracing_try_init();
+        let dir = temp_dir("graph");
+        let memory = StorageConfig::new(None, 10, Duration::from_secs(100));
+        let persistent = StorageConfig::new(Some(dir.join("db")), 10, Duration::from_secs(100));
+        for config in vec![memory, persistent] {
+            let store = StorageService::<DefaultParams>::open(config).unwrap();
+            let a = create_block(&ipld!(0));
+            let b = create_block(&ipld!(1));
+            let c = create_block(&ipld!([a.cid(), b.cid()]));
+            for block in &[&a, &b, &c] {
+                store.insert(block).unwrap();
+            }
+            let mut graph = FnvHashMap::default();
+            let mut last = None;
+            loop {
+                let page = store.references_after(last.as_ref(), 2).unwrap();
+                if page.is_empty() {
+                    break;
+                }
+                assert!(page.len() <= 2);
+                last = page.last().map(|(cid, _)| *cid);
+                graph.extend(page);
+            }
+            assert_eq!(graph.len(), 3);
+            assert!(graph[a.cid()].is_empty());
+            assert!(graph[b.cid()].is_empty());
+            let mut children = graph[c.cid()].clone();
+            children.sort_by_key(|cid| cid.to_bytes());
+            let mut expected = vec![*a.cid(), *b.cid()];
+            expected.sort_by_key(|cid| cid.to_bytes());
+            assert_eq!(children, expected);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_store_inline() {
+        tracing_try_init();
+        let (store, _rx) = create_store();
+        let inline = |codec: u64, data: &[u8]| {
+            Cid::new_v1(
+                codec,
+                libipld::multihash::Multihash::wrap(0x00, data).unwrap(),
+            )
+        };
+        let leaf = inline(RawCodec.into(), b"inline");
+        assert!(store.contains(&leaf).unwrap());
+        assert_eq!(store.get(&leaf).unwrap(), Some(b"inline".to_vec()));
+
+        let missing = create_block(&ipld!(0));
+        let data = libipld::codec::Codec::encode(&DagCborCodec, &ipld!([missing.cid()])).unwrap();
+        let node = inline(DagCborCodec.into(), &data);
+        let root = create_block(&ipld!([leaf, node]));
+        store.insert(&root).unwrap();
+        assert_eq!(
+            store.missing_blocks(root.cid()).unwrap(),
+            vec![*missing.cid()]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_store_inline_gc() {
+        tracing_try_init();
+        let store = StorageService::<DefaultParams>::open(StorageConfig::new(
+            None,
+            0,
+            Duration::from_secs(100),
+        ))
+        .unwrap();
+        let child = create_block(&ipld!(0));
+        let data = libipld::codec::Codec::encode(&DagCborCodec, &ipld!([child.cid()])).unwrap();
+        let node = Cid::new_v1(
+            DagCborCodec.into(),
+            libipld::multihash::Multihash::wrap(0x00, &data).unwrap(),
         );
+        let root = create_block(&ipld!([node]));
+        store.insert(&child).unwrap();
+        store.insert(&root).unwrap();
+        store.alias(alias!(x).as_bytes(), Some(root.cid())).unwrap();
+        store.evict().await.unwrap();
+        assert!(store.contains(child.cid()).unwrap());
+        assert!(store.missing_blocks(root.cid()).unwrap().is_empty());
     }
 
     #[async_std::test]
@@ -491,4 +3682,88 @@ mod tests {
         assert_unpinned!(&store, &a);
         assert_unpinned!(&store, &b);
     }
+
+    /// Inserts `n` unpinned blocks of 4KiB and evicts them, leaving unused pages behind.
+    async fn fill_and_evict(store: &StorageService<DefaultParams>, n: u32) {
+        for i in 0..n {
+            let mut data = vec![0u8; 4096];
+            data[..4].copy_from_slice(&i.to_be_bytes());
+            let block = Block::encode(RawCodec, Code::Blake3_256, &data[..]).unwrap();
+            store.insert(&block).unwrap();
+        }
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        store.meta.lock().checkpoint().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_compact() {
+        tracing_try_init();
+        let dir = temp_dir("compact");
+        let path = dir.join("db");
+        let mut config = StorageConfig::new(Some(path.clone()), 0, Duration::from_secs(100));
+        config.compact_pages = 10;
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let file_size = || std::fs::metadata(&path).unwrap().len();
+
+        // the first compaction enables incremental vacuum with a full vacuum.
+        fill_and_evict(&store, 200).await;
+        assert!(store.free_pages().unwrap() > 0);
+        let size = file_size();
+        store.compact().await.unwrap();
+        store.meta.lock().checkpoint().unwrap();
+        assert_eq!(store.free_pages().unwrap(), 0);
+        assert!(file_size() < size);
+
+        // later compactions release the unused pages slice by slice.
+        fill_and_evict(&store, 200).await;
+        assert!(store.free_pages().unwrap() > 10);
+        let size = file_size();
+        store.compact().await.unwrap();
+        store.meta.lock().checkpoint().unwrap();
+        assert_eq!(store.free_pages().unwrap(), 0);
+        assert!(file_size() < size);
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[async_std::test]
+    async fn test_auto_compact() {
+        tracing_try_init();
+        let dir = temp_dir("auto-compact");
+        let mut config = StorageConfig::new(Some(dir.join("db")), 0, Duration::from_millis(100));
+        config.compact_pages = 10;
+        config.auto_compact_free_pages = Some(0);
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        store.compact().await.unwrap();
+
+        fill_and_evict(&store, 200).await;
+        let free = store.free_pages().unwrap();
+        assert!(free > 10);
+        // the gc loop releases a slice of the unused pages after its passes.
+        eventually(|| store.free_pages().unwrap() < free).await;
+        drop(store);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[async_std::test]
+    async fn test_set_gc_config() {
+        tracing_try_init();
+        let config = StorageConfig::new(None, 0, Duration::from_millis(100));
+        let store = StorageService::<DefaultParams>::open(config).unwrap();
+        let (tx, mut heartbeats) = futures::channel::mpsc::unbounded();
+        store.set_gc_heartbeat(move |next| {
+            tx.unbounded_send(next).ok();
+        });
+        let mut gc_config = store.gc_config();
+        gc_config.interval = Duration::from_millis(200);
+        store.set_gc_config(gc_config);
+        assert_eq!(store.gc_config().interval, Duration::from_millis(200));
+
+        // the running loop picks up the new interval at the start of its next pass.
+        while heartbeats.next().await != Some(Duration::from_millis(200)) {}
+        let a = create_block(&ipld!(0));
+        store.insert(&a).unwrap();
+        eventually(|| !store.contains(a.cid()).unwrap()).await;
+    }
 }
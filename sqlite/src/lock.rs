@@ -0,0 +1,39 @@
+use fs2::FileExt;
+use libipld::Result;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Error returned when opening a block store that is already opened by another process.
+#[derive(Debug, Error)]
+#[error("block store {} is opened by another process", .0.display())]
+pub struct StoreLocked(pub PathBuf);
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Exclusive lock of a block store that is released when dropped.
+#[derive(Debug)]
+pub(crate) struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(with_suffix(path, ".lock"))?;
+        if let Err(err) = file.try_lock_exclusive() {
+            if err.kind() == fs2::lock_contended_error().kind() {
+                return Err(StoreLocked(path.to_owned()).into());
+            }
+            return Err(err.into());
+        }
+        Ok(Self { _file: file })
+    }
+}
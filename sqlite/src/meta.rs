@@ -0,0 +1,910 @@
+use crate::distribution::{BlockCounts, BlockDistribution};
+use crate::namespace::prefix_end;
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension, Result, Transaction};
+use std::path::Path;
+use std::time::Duration;
+
+const INIT: &str = r#"
+CREATE TABLE IF NOT EXISTS published (
+    key BLOB PRIMARY KEY,
+    value BLOB,
+    expires INTEGER,
+    republish INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_published_republish ON published (republish);
+CREATE TABLE IF NOT EXISTS provenance (
+    cid BLOB NOT NULL,
+    public_key BLOB NOT NULL,
+    signature BLOB NOT NULL,
+    PRIMARY KEY (cid, public_key)
+);
+CREATE TABLE IF NOT EXISTS alias_history (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    alias BLOB NOT NULL,
+    cid BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_alias_history_alias ON alias_history (alias);
+CREATE TABLE IF NOT EXISTS denylist (
+    cid BLOB PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS alias_names (
+    name BLOB PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS alias_meta (
+    alias BLOB PRIMARY KEY,
+    meta BLOB NOT NULL,
+    cid BLOB
+);
+CREATE TABLE IF NOT EXISTS gossip_seen (
+    topic TEXT NOT NULL,
+    id BLOB NOT NULL,
+    expires INTEGER NOT NULL,
+    PRIMARY KEY (topic, id)
+);
+CREATE INDEX IF NOT EXISTS idx_gossip_seen_expires ON gossip_seen (expires);
+CREATE INDEX IF NOT EXISTS idx_gossip_seen_topic_expires ON gossip_seen (topic, expires);
+CREATE TABLE IF NOT EXISTS alias_expiry (
+    alias BLOB PRIMARY KEY,
+    expires INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_alias_expiry_expires ON alias_expiry (expires);
+CREATE TABLE IF NOT EXISTS alias_renames (
+    old BLOB NOT NULL,
+    new BLOB NOT NULL,
+    cid BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS peer_stats (
+    peer BLOB PRIMARY KEY,
+    rtt_ewma INTEGER,
+    dial_successes INTEGER NOT NULL,
+    dial_failures INTEGER NOT NULL,
+    bytes_sent INTEGER NOT NULL,
+    bytes_received INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    auth_failures INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS outbox (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    topic TEXT NOT NULL,
+    msg BLOB NOT NULL,
+    expires INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS shard_layout (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    shards INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sharded (
+    cid BLOB PRIMARY KEY,
+    pending INTEGER NOT NULL
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS idx_sharded_pending ON sharded (pending) WHERE pending = 1;
+CREATE TABLE IF NOT EXISTS block_distribution (
+    codec INTEGER NOT NULL,
+    hash INTEGER NOT NULL,
+    inserted INTEGER NOT NULL,
+    inserted_bytes INTEGER NOT NULL,
+    deleted INTEGER NOT NULL,
+    deleted_bytes INTEGER NOT NULL,
+    PRIMARY KEY (codec, hash)
+);
+"#;
+
+/// A record published to the dht that is periodically republished.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PublishedRecord {
+    /// The dht key. For provider records this is the `Cid` of the provided block.
+    pub key: Vec<u8>,
+    /// The value of the record or `None` for a provider record.
+    pub value: Option<Vec<u8>>,
+    /// Unix timestamp in seconds after which the record is no longer republished.
+    pub expires: Option<u64>,
+    /// Unix timestamp in seconds of the next republish.
+    pub republish: u64,
+}
+
+/// Rolling statistics of a peer persisted across restarts.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerStatsRecord {
+    /// The peer id.
+    pub peer: Vec<u8>,
+    /// Moving average of the rtt in microseconds.
+    pub rtt_ewma: Option<u64>,
+    /// Number of dials that established a connection.
+    pub dial_successes: u64,
+    /// Number of dials that failed.
+    pub dial_failures: u64,
+    /// Number of bytes sent to the peer.
+    pub bytes_sent: u64,
+    /// Number of bytes received from the peer.
+    pub bytes_received: u64,
+    /// Unix timestamp in seconds of the last update.
+    pub updated: u64,
+    /// Number of times the peer failed to authenticate.
+    pub auth_failures: u64,
+}
+
+/// A gossip message waiting in the outbox until the node is connected.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutboxRecord {
+    /// Position of the message in the outbox.
+    pub seq: u64,
+    /// Topic the message is published on.
+    pub topic: String,
+    /// The message.
+    pub msg: Vec<u8>,
+    /// Unix timestamp in seconds after which the message is dropped.
+    pub expires: u64,
+}
+
+/// Copies the write ahead log of `conn` into the database file and truncates it.
+pub(crate) fn checkpoint(conn: &Connection) -> Result<()> {
+    let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", params![], |row| {
+        row.get(0)
+    })?;
+    if busy != 0 {
+        tracing::warn!("wal checkpoint was blocked by a reader");
+    }
+    Ok(())
+}
+
+/// Auxiliary tables stored alongside the blocks.
+pub(crate) struct MetaStore {
+    conn: Connection,
+    persistent: bool,
+}
+
+impl MetaStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::init(Connection::open(path)?, true)
+    }
+
+    pub fn memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?, false)
+    }
+
+    fn init(conn: Connection, persistent: bool) -> Result<Self> {
+        conn.busy_timeout(Duration::from_secs(10))?;
+        conn.execute_batch(INIT)?;
+        Self::migrate(&conn)?;
+        Ok(Self { conn, persistent })
+    }
+
+    /// Adds the columns introduced after a table was first created.
+    fn migrate(conn: &Connection) -> Result<()> {
+        if !Self::has_column(conn, "peer_stats", "auth_failures")? {
+            conn.execute_batch(
+                "ALTER TABLE peer_stats ADD COLUMN auth_failures INTEGER NOT NULL DEFAULT 0",
+            )?;
+        }
+        // metadata written before the root was recorded matches any root.
+        if !Self::has_column(conn, "alias_meta", "cid")? {
+            conn.execute_batch("ALTER TABLE alias_meta ADD COLUMN cid BLOB")?;
+        }
+        Ok(())
+    }
+
+    fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+        Ok(conn
+            .prepare(&format!("PRAGMA table_info({})", table))?
+            .query_map(params![], |row| row.get::<_, String>(1))?
+            .collect::<Result<Vec<_>>>()?
+            .iter()
+            .any(|name| name == column))
+    }
+
+    /// Syncs every commit to disk, like the block store with `Durability::Strict`.
+    pub fn set_synchronous_full(&self) -> Result<()> {
+        self.conn.execute_batch("PRAGMA synchronous = FULL")
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    pub fn transaction(&mut self) -> Result<Transaction<'_>> {
+        self.conn.transaction()
+    }
+
+    /// Returns the number of unused pages in the database file.
+    pub fn free_pages(&self) -> Result<u64> {
+        if !self.persistent {
+            return Ok(0);
+        }
+        let pages: i64 = self
+            .conn
+            .query_row("PRAGMA freelist_count", params![], |row| row.get(0))?;
+        Ok(pages as u64)
+    }
+
+    /// Copies the write ahead log into the database file and truncates it.
+    pub fn checkpoint(&self) -> Result<()> {
+        if !self.persistent {
+            return Ok(());
+        }
+        checkpoint(&self.conn)
+    }
+
+    /// Enables incremental auto vacuum if the database wasn't created with it, which
+    /// requires a full vacuum. Returns `true` if the full vacuum was performed.
+    pub fn enable_incremental_vacuum(&self) -> Result<bool> {
+        if !self.persistent || self.incremental_vacuum_enabled()? {
+            return Ok(false);
+        }
+        tracing::info!("enabling incremental auto vacuum");
+        self.conn
+            .execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        Ok(true)
+    }
+
+    fn incremental_vacuum_enabled(&self) -> Result<bool> {
+        let mode: i64 = self
+            .conn
+            .query_row("PRAGMA auto_vacuum", params![], |row| row.get(0))?;
+        Ok(mode == 2)
+    }
+
+    /// Frees up to `pages` unused pages. Returns `true` if there are no unused pages left
+    /// or if incremental auto vacuum isn't enabled.
+    pub fn incremental_vacuum(&self, pages: u64) -> Result<bool> {
+        if !self.persistent || !self.incremental_vacuum_enabled()? {
+            return Ok(true);
+        }
+        self.conn
+            .execute_batch(&format!("PRAGMA incremental_vacuum({})", pages))?;
+        Ok(self.free_pages()? == 0)
+    }
+
+    pub fn published(&self) -> Result<Vec<PublishedRecord>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT key, value, expires, republish FROM published")?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(PublishedRecord {
+                key: row.get(0)?,
+                value: row.get(1)?,
+                expires: row.get::<_, Option<i64>>(2)?.map(|t| t as u64),
+                republish: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Returns the records due for republishing at unix time `now`.
+    pub fn due_published(&self, now: u64) -> Result<Vec<PublishedRecord>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT key, value, expires, republish FROM published WHERE republish <= ?",
+        )?;
+        let rows = stmt.query_map(params![now as i64], |row| {
+            Ok(PublishedRecord {
+                key: row.get(0)?,
+                value: row.get(1)?,
+                expires: row.get::<_, Option<i64>>(2)?.map(|t| t as u64),
+                republish: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn publish(&self, record: &PublishedRecord) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO published (key, value, expires, republish) VALUES (?, ?, ?, ?)",
+            params![
+                record.key,
+                record.value,
+                record.expires.map(|t| t as i64),
+                record.republish as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn reschedule_published(&self) -> Result<()> {
+        self.conn
+            .execute("UPDATE published SET republish = 0", params![])?;
+        Ok(())
+    }
+
+    pub fn unpublish(&self, key: &[u8]) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM published WHERE key = ?", params![key])?;
+        Ok(())
+    }
+
+    pub fn provenance(&self, cid: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT public_key, signature FROM provenance WHERE cid = ?")?;
+        let rows = stmt.query_map(params![cid], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    pub fn add_provenance(&self, cid: &[u8], public_key: &[u8], signature: &[u8]) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO provenance (cid, public_key, signature) VALUES (?, ?, ?)",
+            params![cid, public_key, signature],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_provenance(&self, cid: &[u8]) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM provenance WHERE cid = ?", params![cid])?;
+        Ok(())
+    }
+
+    /// Returns all entries of the alias history.
+    pub fn all_alias_history(&self) -> Result<Vec<(i64, Vec<u8>, Vec<u8>)>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT seq, alias, cid FROM alias_history")?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.collect()
+    }
+
+    /// Returns the `limit` most recent entries of the alias history.
+    pub fn alias_history(&self, alias: &[u8], limit: usize) -> Result<Vec<(i64, Vec<u8>)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT seq, cid FROM alias_history WHERE alias = ? ORDER BY seq DESC LIMIT ?",
+        )?;
+        let limit = std::cmp::min(limit, i64::MAX as usize) as i64;
+        let rows = stmt.query_map(params![alias, limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    pub fn remove_alias_history(&self, seq: i64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM alias_history WHERE seq = ?", params![seq])?;
+        Ok(())
+    }
+
+    fn has_table(&self, name: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+            params![name],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Fills the empty alias names from stores created before the names were recorded.
+    /// The block store can't list its aliases, so this reads its alias table once when
+    /// the store is migrated.
+    pub fn backfill_alias_names(&self) -> Result<()> {
+        if self.has_table("alias_index")? {
+            self.conn.execute_batch(
+                "INSERT OR IGNORE INTO alias_names (name) SELECT name FROM alias_index; \
+                 DROP TABLE alias_index;",
+            )?;
+        }
+        let names: i64 =
+            self.conn
+                .query_row("SELECT COUNT(*) FROM alias_names", params![], |row| {
+                    row.get(0)
+                })?;
+        if names == 0 && self.has_table("aliases")? {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO alias_names (name) SELECT name FROM aliases",
+                params![],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Records the name of `alias` and replaces its metadata in a single transaction. If
+    /// `prev` is set it is recorded in the alias history in the same transaction, returning
+    /// the sequence number of the history entry.
+    ///
+    /// The roots are only stored by the block store, the metadata records the root it was
+    /// set for so that it isn't returned for another root if the block store alias wasn't
+    /// committed. A name is recorded before the alias
+    /// is set and removed with `remove_alias_name` after the alias is removed, so the
+    /// names may list aliases that no longer resolve but never miss an alias.
+    pub fn index_alias(
+        &mut self,
+        alias: &[u8],
+        cid: Option<&[u8]>,
+        meta: Option<&[u8]>,
+        prev: Option<&[u8]>,
+    ) -> Result<Option<i64>> {
+        let txn = self.conn.transaction()?;
+        let seq = if let Some(prev) = prev {
+            txn.execute(
+                "INSERT INTO alias_history (alias, cid) VALUES (?, ?)",
+                params![alias, prev],
+            )?;
+            Some(txn.last_insert_rowid())
+        } else {
+            None
+        };
+        if cid.is_some() {
+            txn.execute(
+                "INSERT OR IGNORE INTO alias_names (name) VALUES (?)",
+                params![alias],
+            )?;
+        }
+        match (cid, meta) {
+            (Some(cid), Some(meta)) => {
+                txn.execute(
+                    "INSERT OR REPLACE INTO alias_meta (alias, meta, cid) VALUES (?, ?, ?)",
+                    params![alias, meta, cid],
+                )?;
+            }
+            _ => {
+                txn.execute("DELETE FROM alias_meta WHERE alias = ?", params![alias])?;
+            }
+        }
+        txn.commit()?;
+        Ok(seq)
+    }
+
+    /// Journals a rename and moves the names, metadata, expiry and history of the renamed
+    /// aliases to their new names in a single transaction. Each rename is the old name,
+    /// the new name, the root and the metadata of an alias. The block store aliases are
+    /// moved after the journal is committed, and the journal is cleared with
+    /// `finish_rename`.
+    pub fn begin_rename(
+        &mut self,
+        renames: &[(Vec<u8>, Vec<u8>, Vec<u8>, Option<Vec<u8>>)],
+    ) -> Result<()> {
+        let txn = self.conn.transaction()?;
+        // the old and new names can overlap, so everything is read before it is moved.
+        let mut history = vec![];
+        let mut expiry = vec![];
+        for (old, new, _, _) in renames {
+            let seqs = txn
+                .prepare_cached("SELECT seq FROM alias_history WHERE alias = ?")?
+                .query_map(params![old], |row| row.get::<_, i64>(0))?
+                .collect::<Result<Vec<_>>>()?;
+            history.extend(seqs.into_iter().map(|seq| (seq, new)));
+            let expires: Option<i64> = txn
+                .query_row(
+                    "SELECT expires FROM alias_expiry WHERE alias = ?",
+                    params![old],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(expires) = expires {
+                expiry.push((new, expires));
+            }
+        }
+        for (old, _, _, _) in renames {
+            txn.execute("DELETE FROM alias_meta WHERE alias = ?", params![old])?;
+            txn.execute("DELETE FROM alias_expiry WHERE alias = ?", params![old])?;
+        }
+        for (seq, new) in history {
+            txn.execute(
+                "UPDATE alias_history SET alias = ? WHERE seq = ?",
+                params![new, seq],
+            )?;
+        }
+        for (new, expires) in expiry {
+            txn.execute(
+                "INSERT OR REPLACE INTO alias_expiry (alias, expires) VALUES (?, ?)",
+                params![new, expires],
+            )?;
+        }
+        for (old, new, cid, meta) in renames {
+            txn.execute(
+                "INSERT INTO alias_renames (old, new, cid) VALUES (?, ?, ?)",
+                params![old, new, cid],
+            )?;
+            txn.execute(
+                "INSERT OR IGNORE INTO alias_names (name) VALUES (?)",
+                params![new],
+            )?;
+            if let Some(meta) = meta {
+                txn.execute(
+                    "INSERT OR REPLACE INTO alias_meta (alias, meta, cid) VALUES (?, ?, ?)",
+                    params![new, meta, cid],
+                )?;
+            }
+        }
+        txn.commit()
+    }
+
+    /// Returns the old name, the new name and the root of the journaled renames.
+    pub fn alias_renames(&self) -> Result<Vec<(Vec<u8>, Vec<u8>, Vec<u8>)>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT old, new, cid FROM alias_renames")?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.collect()
+    }
+
+    /// Clears the journal of a completed rename.
+    pub fn finish_rename(&self) -> Result<()> {
+        self.conn.execute("DELETE FROM alias_renames", params![])?;
+        Ok(())
+    }
+
+    pub fn remove_alias_name(&self, alias: &[u8]) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM alias_names WHERE name = ?", params![alias])?;
+        Ok(())
+    }
+
+    /// Returns the metadata of `alias` if it was set for the root `cid`.
+    pub fn alias_meta(&self, alias: &[u8], cid: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT meta FROM alias_meta WHERE alias = ? AND (cid IS NULL OR cid = ?)",
+        )?;
+        let mut rows = stmt.query_map(params![alias, cid], |row| row.get(0))?;
+        rows.next().transpose()
+    }
+
+    /// Sets the unix timestamp in seconds after which `alias` is removed, or makes the
+    /// alias permanent.
+    pub fn set_alias_expiry(&self, alias: &[u8], expires: Option<u64>) -> Result<()> {
+        if let Some(expires) = expires {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO alias_expiry (alias, expires) VALUES (?, ?)",
+                params![alias, expires as i64],
+            )?;
+        } else {
+            self.conn
+                .execute("DELETE FROM alias_expiry WHERE alias = ?", params![alias])?;
+        }
+        Ok(())
+    }
+
+    pub fn alias_expiry(&self, alias: &[u8]) -> Result<Option<u64>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT expires FROM alias_expiry WHERE alias = ?")?;
+        let mut rows = stmt.query_map(params![alias], |row| row.get::<_, i64>(0))?;
+        rows.next().transpose().map(|t| t.map(|t| t as u64))
+    }
+
+    /// Returns the aliases that expired at `now`.
+    pub fn expired_aliases(&self, now: u64) -> Result<Vec<Vec<u8>>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT alias FROM alias_expiry WHERE expires <= ?")?;
+        let rows = stmt.query_map(params![now as i64], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Returns the recorded alias names starting with `prefix` ordered by name.
+    pub fn alias_names(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let end = prefix_end(prefix);
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT name FROM alias_names WHERE name >= ?1 AND (?2 IS NULL OR name < ?2) \
+             ORDER BY name",
+        )?;
+        let rows = stmt.query_map(params![prefix, end], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Returns at most `limit` alias names starting with `prefix`, ordered by name and
+    /// skipping the names starting with any of the `exclude` prefixes.
+    pub fn alias_names_limited(
+        &self,
+        prefix: &[u8],
+        exclude: &[&[u8]],
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let end = prefix_end(prefix);
+        let limit = limit as i64;
+        let lens = exclude
+            .iter()
+            .map(|exclude| exclude.len() as i64)
+            .collect::<Vec<_>>();
+        let mut sql =
+            "SELECT name FROM alias_names WHERE name >= ? AND (? IS NULL OR name < ?)".to_string();
+        let mut values: Vec<&dyn ToSql> = vec![&prefix, &end, &end];
+        for (exclude, len) in exclude.iter().zip(&lens) {
+            sql.push_str(" AND substr(name, 1, ?) != ?");
+            values.push(len);
+            values.push(exclude);
+        }
+        sql.push_str(" ORDER BY name LIMIT ?");
+        values.push(&limit);
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let rows = stmt.query_map(&values[..], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Returns the cids of `cids` whose blocks are stored, reading the block store tables
+    /// in batches of one query. The blocks are stored in the same database file, so this
+    /// returns `None` for in-memory stores.
+    pub fn stored_blocks(&self, cids: &[Vec<u8>]) -> Result<Option<Vec<Vec<u8>>>> {
+        if !self.persistent {
+            return Ok(None);
+        }
+        let mut stored = vec![];
+        // stays below the default limit of 999 parameters of a statement.
+        for chunk in cids.chunks(512) {
+            let params = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "SELECT cids.cid FROM cids INNER JOIN blocks ON cids.id = blocks.block_id \
+                 WHERE cids.cid IN ({})",
+                params
+            ))?;
+            let rows = stmt.query_map(chunk, |row| row.get(0))?;
+            for cid in rows {
+                stored.push(cid?);
+            }
+        }
+        Ok(Some(stored))
+    }
+
+    pub fn denylist(&self) -> Result<Vec<Vec<u8>>> {
+        let mut stmt = self.conn.prepare_cached("SELECT cid FROM denylist")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Returns the persisted statistics of all peers.
+    pub fn peer_stats(&self) -> Result<Vec<PeerStatsRecord>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT peer, rtt_ewma, dial_successes, dial_failures, bytes_sent, \
+             bytes_received, updated, auth_failures FROM peer_stats",
+        )?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(PeerStatsRecord {
+                peer: row.get(0)?,
+                rtt_ewma: row.get::<_, Option<i64>>(1)?.map(|t| t as u64),
+                dial_successes: row.get::<_, i64>(2)? as u64,
+                dial_failures: row.get::<_, i64>(3)? as u64,
+                bytes_sent: row.get::<_, i64>(4)? as u64,
+                bytes_received: row.get::<_, i64>(5)? as u64,
+                updated: row.get::<_, i64>(6)? as u64,
+                auth_failures: row.get::<_, i64>(7)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Replaces the statistics of the `records` peers and removes the statistics that
+    /// weren't updated since `stale`.
+    pub fn save_peer_stats(&mut self, records: &[PeerStatsRecord], stale: u64) -> Result<()> {
+        let txn = self.conn.transaction()?;
+        for record in records {
+            txn.execute(
+                "INSERT OR REPLACE INTO peer_stats (peer, rtt_ewma, dial_successes, \
+                 dial_failures, bytes_sent, bytes_received, updated, auth_failures) \
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    record.peer,
+                    record.rtt_ewma.map(|t| t as i64),
+                    record.dial_successes as i64,
+                    record.dial_failures as i64,
+                    record.bytes_sent as i64,
+                    record.bytes_received as i64,
+                    record.updated as i64,
+                    record.auth_failures as i64,
+                ],
+            )?;
+        }
+        txn.execute(
+            "DELETE FROM peer_stats WHERE updated < ?",
+            params![stale as i64],
+        )?;
+        txn.commit()
+    }
+
+    pub fn block_distribution(&self) -> Result<BlockDistribution> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT codec, hash, inserted, inserted_bytes, deleted, deleted_bytes \
+             FROM block_distribution",
+        )?;
+        let blocks = stmt
+            .query_map(params![], |row| {
+                let key = (row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64);
+                let counts = BlockCounts {
+                    inserted: row.get::<_, i64>(2)? as u64,
+                    inserted_bytes: row.get::<_, i64>(3)? as u64,
+                    deleted: row.get::<_, i64>(4)? as u64,
+                    deleted_bytes: row.get::<_, i64>(5)? as u64,
+                };
+                Ok((key, counts))
+            })?
+            .collect::<Result<_>>()?;
+        Ok(BlockDistribution { blocks })
+    }
+
+    pub fn save_block_distribution(&mut self, distribution: &BlockDistribution) -> Result<()> {
+        let txn = self.conn.transaction()?;
+        for ((codec, hash), counts) in &distribution.blocks {
+            txn.execute(
+                "INSERT OR REPLACE INTO block_distribution (codec, hash, inserted, \
+                 inserted_bytes, deleted, deleted_bytes) VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    *codec as i64,
+                    *hash as i64,
+                    counts.inserted as i64,
+                    counts.inserted_bytes as i64,
+                    counts.deleted as i64,
+                    counts.deleted_bytes as i64,
+                ],
+            )?;
+        }
+        txn.commit()
+    }
+
+    pub fn push_outbox(&self, topic: &str, msg: &[u8], expires: u64) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO outbox (topic, msg, expires) VALUES (?, ?, ?)",
+            params![topic, msg, expires as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the expired messages and returns the remaining messages in the order they
+    /// were queued.
+    pub fn outbox(&self, now: u64) -> Result<Vec<OutboxRecord>> {
+        self.conn
+            .execute("DELETE FROM outbox WHERE expires <= ?", params![now as i64])?;
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT seq, topic, msg, expires FROM outbox ORDER BY seq")?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(OutboxRecord {
+                seq: row.get::<_, i64>(0)? as u64,
+                topic: row.get(1)?,
+                msg: row.get(2)?,
+                expires: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Returns the number of messages of every topic in the outbox that expire after
+    /// `now`.
+    pub fn outbox_topics(&self, now: u64) -> Result<Vec<(String, u64)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT topic, COUNT(*) FROM outbox WHERE expires > ? GROUP BY topic",
+        )?;
+        let rows = stmt.query_map(params![now as i64], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+        rows.collect()
+    }
+
+    pub fn outbox_len(&self, now: u64) -> Result<u64> {
+        let len: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM outbox WHERE expires > ?",
+            params![now as i64],
+            |row| row.get(0),
+        )?;
+        Ok(len as u64)
+    }
+
+    pub fn remove_outbox(&self, seq: u64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM outbox WHERE seq = ?", params![seq as i64])?;
+        Ok(())
+    }
+
+    /// Records the `ids` of messages received on `topic` in a single transaction,
+    /// returning for each id if it wasn't seen before. At most `capacity` ids are kept per
+    /// topic, dropping the oldest.
+    pub fn mark_seen(
+        &mut self,
+        topic: &str,
+        ids: &[Vec<u8>],
+        now: u64,
+        expires: u64,
+        capacity: usize,
+    ) -> Result<Vec<bool>> {
+        let txn = self.conn.transaction()?;
+        txn.execute(
+            "DELETE FROM gossip_seen WHERE expires <= ?",
+            params![now as i64],
+        )?;
+        let mut inserted = Vec::with_capacity(ids.len());
+        {
+            let mut stmt = txn.prepare_cached(
+                "INSERT OR IGNORE INTO gossip_seen (topic, id, expires) VALUES (?, ?, ?)",
+            )?;
+            for id in ids {
+                inserted.push(stmt.execute(params![topic, id, expires as i64])? > 0);
+            }
+        }
+        let count: i64 = txn.query_row(
+            "SELECT COUNT(*) FROM gossip_seen WHERE topic = ?",
+            params![topic],
+            |row| row.get(0),
+        )?;
+        let excess = count - std::cmp::min(capacity, i64::MAX as usize) as i64;
+        if excess > 0 {
+            txn.execute(
+                "DELETE FROM gossip_seen WHERE rowid IN \
+                 (SELECT rowid FROM gossip_seen WHERE topic = ? ORDER BY expires LIMIT ?)",
+                params![topic, excess],
+            )?;
+        }
+        txn.commit()?;
+        Ok(inserted)
+    }
+
+    /// Returns the number of shards the store was created with.
+    pub fn shard_layout(&self) -> Result<Option<usize>> {
+        let shards: Option<i64> = self
+            .conn
+            .query_row("SELECT shards FROM shard_layout", params![], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(shards.map(|n| n as usize))
+    }
+
+    pub fn set_shard_layout(&self, shards: usize) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO shard_layout (id, shards) VALUES (0, ?)",
+            params![shards as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Marks blocks as stored in a shard before their data is written. The marks are
+    /// pending until `commit_sharded` is called once the blocks are in the main database.
+    pub fn mark_sharded(&mut self, cids: &[Vec<u8>]) -> Result<()> {
+        let txn = self.conn.transaction()?;
+        {
+            let mut stmt =
+                txn.prepare_cached("INSERT OR REPLACE INTO sharded (cid, pending) VALUES (?, 1)")?;
+            for cid in cids {
+                stmt.execute(params![cid])?;
+            }
+        }
+        txn.commit()
+    }
+
+    pub fn commit_sharded(&mut self, cids: &[Vec<u8>]) -> Result<()> {
+        let txn = self.conn.transaction()?;
+        {
+            let mut stmt = txn.prepare_cached("UPDATE sharded SET pending = 0 WHERE cid = ?")?;
+            for cid in cids {
+                stmt.execute(params![cid])?;
+            }
+        }
+        txn.commit()
+    }
+
+    /// Returns the blocks that were marked as sharded, but maybe not written to the main
+    /// database.
+    pub fn pending_sharded(&self) -> Result<Vec<Vec<u8>>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT cid FROM sharded WHERE pending = 1")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    pub fn is_sharded(&self, cid: &[u8]) -> Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT 1 FROM sharded WHERE cid = ?")?;
+        Ok(stmt
+            .query_row(params![cid], |row| row.get::<_, i64>(0))
+            .optional()?
+            .is_some())
+    }
+
+    pub fn unmark_sharded(&mut self, cids: &[Vec<u8>]) -> Result<()> {
+        let txn = self.conn.transaction()?;
+        {
+            let mut stmt = txn.prepare_cached("DELETE FROM sharded WHERE cid = ?")?;
+            for cid in cids {
+                stmt.execute(params![cid])?;
+            }
+        }
+        txn.commit()
+    }
+
+    pub fn deny(&self, cid: &[u8]) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO denylist (cid) VALUES (?)",
+            params![cid],
+        )?;
+        Ok(())
+    }
+
+    pub fn allow(&self, cid: &[u8]) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM denylist WHERE cid = ?", params![cid])?;
+        Ok(())
+    }
+}
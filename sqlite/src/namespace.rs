@@ -0,0 +1,59 @@
+use libipld::{Cid, Result};
+use thiserror::Error;
+
+/// Error returned when renaming aliases onto names that are already taken.
+#[derive(Debug, Error)]
+#[error("alias {} exists", String::from_utf8_lossy(.0))]
+pub struct AliasExists(pub Vec<u8>);
+
+/// Maximum size of the metadata of an alias.
+pub const MAX_ALIAS_META_SIZE: usize = 64 * 1024;
+
+/// Error returned when the metadata of an alias exceeds `MAX_ALIAS_META_SIZE`.
+#[derive(Debug, Error)]
+#[error("alias metadata of {0} bytes exceeds {} bytes", MAX_ALIAS_META_SIZE)]
+pub struct AliasMetaTooLarge(pub usize);
+
+/// A change of an alias.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AliasChange {
+    /// The alias.
+    pub alias: Vec<u8>,
+    /// Root before the change, `None` if the alias is created.
+    pub old: Option<Cid>,
+    /// Root after the change, `None` if the alias is removed.
+    pub new: Option<Cid>,
+}
+
+/// Hook running on every alias change of the store, including removals of expired
+/// aliases and the aliases moved by a rename.
+///
+/// The hook runs while the aliases are locked, so no other change can slip in between
+/// the roots a hook sees and the write. A hook must not change aliases itself.
+pub trait AliasCommitHook: Send + Sync + 'static {
+    /// Runs before the changes are written. Returning an error aborts all of them.
+    fn pre_commit(&self, changes: &[AliasChange]) -> Result<()>;
+
+    /// Runs after the changes were written.
+    fn post_commit(&self, changes: Vec<AliasChange>);
+}
+
+/// Returns the smallest name that is greater than all names starting with `prefix`, or
+/// `None` if there is no such name.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Replaces the prefix `from` of `name` with `to`.
+pub(crate) fn rename(name: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut renamed = to.to_vec();
+    renamed.extend_from_slice(&name[from.len()..]);
+    renamed
+}
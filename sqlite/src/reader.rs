@@ -0,0 +1,136 @@
+use libipld::{Cid, Result};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Error returned when taking a snapshot of an in-memory block store.
+#[derive(Debug, Error)]
+#[error("in-memory block stores don't support snapshots")]
+pub struct SnapshotUnsupported;
+
+/// Error returned when the database isn't a block store of a supported version.
+#[derive(Debug, Error)]
+#[error("unsupported block store schema: missing {0}")]
+pub struct UnsupportedSchema(pub String);
+
+/// Tables and columns of the block store read by `StoreReader`. The block store is
+/// written by another process, so it can't be read through the block store api.
+const SCHEMA: &[(&str, &[&str])] = &[
+    ("cids", &["id", "cid"]),
+    ("blocks", &["block_id", "block"]),
+    ("aliases", &["name", "block_id"]),
+];
+
+/// Checks that the block store has the tables and columns read by `StoreReader`, so that
+/// a changed schema fails when attaching instead of on the first read.
+fn check_schema(conn: &Connection) -> Result<()> {
+    for (table, columns) in SCHEMA {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+        let names = stmt
+            .query_map(params![], |row| row.get::<_, String>(1))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for column in columns.iter() {
+            if !names.iter().any(|name| name == column) {
+                return Err(UnsupportedSchema(format!("{}.{}", table, column)).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read-only view of a block store that is opened by another process.
+///
+/// The store is read without taking the store lock, so it can be used while another
+/// process is writing to it. Blocks may be evicted by the writer at any time, unless
+/// the reader is a snapshot.
+pub struct StoreReader {
+    conn: Mutex<Connection>,
+}
+
+impl StoreReader {
+    /// Attaches to the block store at `path`. Fails with `UnsupportedSchema` if the
+    /// database isn't a block store of a supported version.
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(Duration::from_secs(10))?;
+        check_schema(&conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Attaches to the block store at `path` with a consistent view of the blocks and
+    /// aliases at the time the snapshot is taken. Writes and garbage collection after the
+    /// snapshot was taken are not visible.
+    ///
+    /// The snapshot is held in a read transaction until the reader is dropped, which
+    /// prevents the write ahead log from being checkpointed completely.
+    pub fn snapshot(path: &Path) -> Result<Self> {
+        let reader = Self::open(path)?;
+        {
+            let conn = reader.conn.lock();
+            conn.execute_batch("BEGIN DEFERRED")?;
+            // the snapshot is established by the first read of the transaction.
+            conn.query_row("SELECT COUNT(*) FROM sqlite_master", params![], |row| {
+                row.get::<_, i64>(0)
+            })?;
+        }
+        Ok(reader)
+    }
+
+    pub fn contains(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.get(cid)?.is_some())
+    }
+
+    pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(
+            "SELECT blocks.block FROM cids INNER JOIN blocks ON cids.id = blocks.block_id WHERE cids.cid = ?",
+        )?;
+        Ok(stmt
+            .query_row(params![cid.to_bytes()], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn iter(&self) -> Result<impl Iterator<Item = Cid>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(
+            "SELECT cids.cid FROM cids INNER JOIN blocks ON cids.id = blocks.block_id",
+        )?;
+        let cids = stmt
+            .query_map(params![], |row| row.get::<_, Vec<u8>>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(cids.into_iter().filter_map(|cid| Cid::try_from(cid).ok()))
+    }
+
+    /// Returns all aliases and their roots.
+    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(
+            "SELECT aliases.name, cids.cid FROM aliases INNER JOIN cids ON aliases.block_id = cids.id",
+        )?;
+        let aliases = stmt
+            .query_map(params![], |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        aliases
+            .into_iter()
+            .map(|(alias, cid)| Ok((alias, Cid::try_from(cid)?)))
+            .collect()
+    }
+
+    pub fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare_cached(
+            "SELECT cids.cid FROM aliases INNER JOIN cids ON aliases.block_id = cids.id WHERE aliases.name = ?",
+        )?;
+        let cid: Option<Vec<u8>> = stmt
+            .query_row(params![alias], |row| row.get(0))
+            .optional()?;
+        Ok(cid.map(Cid::try_from).transpose()?)
+    }
+}
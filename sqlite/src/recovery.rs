@@ -0,0 +1,170 @@
+use crate::lock::with_suffix;
+use ipfs_sqlite_block_store::BlockStore;
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use rusqlite::{params, Connection, OpenFlags};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+/// How to handle a corrupted database when opening the store.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecoveryMode {
+    /// Fail to open the store.
+    Fail,
+    /// Moves the corrupted database aside and copies all readable blocks and aliases
+    /// in to a fresh store.
+    Salvage,
+    /// Discards the write ahead log, losing the most recent transactions.
+    TruncateWal,
+}
+
+impl Default for RecoveryMode {
+    fn default() -> Self {
+        Self::Fail
+    }
+}
+
+/// Summary of a recovery performed when opening the store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecoveryReport {
+    /// The recovery mode that was applied.
+    pub mode: RecoveryMode,
+    /// Path the corrupted database was moved to when salvaging.
+    pub corrupted: Option<PathBuf>,
+    /// Number of blocks copied to the fresh store.
+    pub recovered_blocks: usize,
+    /// Number of unreadable or invalid blocks.
+    pub lost_blocks: usize,
+    /// Number of aliases copied to the fresh store.
+    pub recovered_aliases: usize,
+}
+
+impl RecoveryReport {
+    fn new(mode: RecoveryMode) -> Self {
+        Self {
+            mode,
+            corrupted: None,
+            recovered_blocks: 0,
+            lost_blocks: 0,
+            recovered_aliases: 0,
+        }
+    }
+}
+
+/// Returns `true` if sqlite reports the database at `path` as corrupted.
+pub(crate) fn is_corrupted(path: &Path) -> bool {
+    if !path.exists() {
+        return false;
+    }
+    let check = || -> rusqlite::Result<String> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.query_row("PRAGMA quick_check", params![], |row| row.get(0))
+    };
+    match check() {
+        Ok(res) => res != "ok",
+        Err(rusqlite::Error::SqliteFailure(err, _)) => matches!(
+            err.code,
+            rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Removes the write ahead log of the database at `path`.
+pub(crate) fn truncate_wal(path: &Path) -> Result<RecoveryReport> {
+    for suffix in &["-wal", "-shm"] {
+        let file = with_suffix(path, suffix);
+        if file.exists() {
+            std::fs::remove_file(file)?;
+        }
+    }
+    Ok(RecoveryReport::new(RecoveryMode::TruncateWal))
+}
+
+/// Moves the database at `path` aside, returning the new path.
+pub(crate) fn move_aside(path: &Path) -> Result<PathBuf> {
+    let corrupted = with_suffix(path, ".corrupted");
+    for suffix in &["", "-wal", "-shm"] {
+        let file = with_suffix(path, suffix);
+        if file.exists() {
+            std::fs::rename(file, with_suffix(&corrupted, suffix))?;
+        }
+    }
+    Ok(corrupted)
+}
+
+/// Copies all readable blocks and aliases of the corrupted database in to `store`.
+pub(crate) fn salvage<S: StoreParams>(corrupted: PathBuf, store: &mut BlockStore) -> RecoveryReport
+where
+    Ipld: References<S::Codecs>,
+{
+    let mut report = RecoveryReport::new(RecoveryMode::Salvage);
+    match Connection::open_with_flags(&corrupted, OpenFlags::SQLITE_OPEN_READ_ONLY) {
+        Ok(conn) => {
+            if let Err(err) = salvage_blocks::<S>(&conn, store, &mut report) {
+                tracing::warn!("failed to read blocks: {}", err);
+            }
+            if let Err(err) = salvage_aliases(&conn, store, &mut report) {
+                tracing::warn!("failed to read aliases: {}", err);
+            }
+        }
+        Err(err) => tracing::warn!("failed to open corrupted database: {}", err),
+    }
+    report.corrupted = Some(corrupted);
+    report
+}
+
+fn salvage_blocks<S: StoreParams>(
+    conn: &Connection,
+    store: &mut BlockStore,
+    report: &mut RecoveryReport,
+) -> rusqlite::Result<()>
+where
+    Ipld: References<S::Codecs>,
+{
+    let mut stmt = conn.prepare(
+        "SELECT cids.cid, blocks.block FROM cids INNER JOIN blocks ON cids.id = blocks.block_id",
+    )?;
+    let mut rows = stmt.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let block = row
+            .get::<_, Vec<u8>>(0)
+            .and_then(|cid| Ok((cid, row.get::<_, Vec<u8>>(1)?)));
+        let block: Result<Block<S>> = match block {
+            Ok((cid, data)) => Cid::try_from(cid)
+                .map_err(Into::into)
+                .and_then(|cid| Block::new(cid, data)),
+            Err(err) => Err(err.into()),
+        };
+        match block.and_then(|block| Ok(store.put_block(&block, None)?)) {
+            Ok(()) => report.recovered_blocks += 1,
+            Err(err) => {
+                tracing::debug!("lost block: {}", err);
+                report.lost_blocks += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn salvage_aliases(
+    conn: &Connection,
+    store: &mut BlockStore,
+    report: &mut RecoveryReport,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT aliases.name, cids.cid FROM aliases INNER JOIN cids ON aliases.block_id = cids.id",
+    )?;
+    let mut rows = stmt.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let name: Vec<u8> = row.get(0)?;
+        let cid: Vec<u8> = row.get(1)?;
+        if let Ok(cid) = Cid::try_from(cid) {
+            if store.alias(&name, Some(&cid)).is_ok() {
+                report.recovered_aliases += 1;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::lock::with_suffix;
+use fnv::FnvHashMap;
+use ipfs_embed_rt::Instant;
+use libipld::Cid;
+use parking_lot::{Mutex, MutexGuard};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+const INIT: &str = r#"
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+CREATE TABLE IF NOT EXISTS blocks (
+    cid BLOB PRIMARY KEY,
+    data BLOB NOT NULL
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS layout (
+    shard INTEGER NOT NULL,
+    shards INTEGER NOT NULL
+);
+"#;
+
+/// Error returned when the store is opened with a different number of shards than it
+/// was created with.
+#[derive(Debug, Error)]
+#[error("block store was created with {expected} shards, but opened with {found}")]
+pub struct ShardLayoutMismatch {
+    /// Number of shards the store was created with.
+    pub expected: usize,
+    /// Number of shards the store was opened with.
+    pub found: usize,
+}
+
+/// Error returned when a shard of the store is missing or belongs to another store.
+#[derive(Debug, Error)]
+#[error("shard {0} of the block store is missing")]
+pub struct ShardMissing(pub usize);
+
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+const DAG_CBOR: u64 = 0x71;
+const DAG_JSON: u64 = 0x0129;
+
+/// Returns the data stored in the main database in place of a sharded block. The data
+/// decodes without references using the codec of the block, so the main database
+/// tracks the block without storing its data. Returns `None` for unknown codecs.
+pub(crate) fn placeholder(codec: u64) -> Option<&'static [u8]> {
+    match codec {
+        RAW | DAG_PB => Some(b""),
+        DAG_CBOR => Some(&[0xf6]),
+        DAG_JSON => Some(b"null"),
+        _ => None,
+    }
+}
+
+/// Data of blocks without links, spread across several database files by the prefix of
+/// the multihash digest. Each shard is written independently of the others.
+pub(crate) struct Shards {
+    shards: Vec<Mutex<Connection>>,
+    removed: Mutex<Vec<Cid>>,
+}
+
+impl Shards {
+    pub fn open(path: &Path, n: usize) -> Result<Self> {
+        let shards = (0..n)
+            .map(|i| {
+                Self::init(Connection::open(with_suffix(
+                    path,
+                    &format!(".shard-{}", i),
+                ))?)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            shards,
+            removed: Default::default(),
+        })
+    }
+
+    pub fn memory(n: usize) -> Result<Self> {
+        let shards = (0..n)
+            .map(|_| Self::init(Connection::open_in_memory()?))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            shards,
+            removed: Default::default(),
+        })
+    }
+
+    fn init(conn: Connection) -> Result<Mutex<Connection>> {
+        conn.busy_timeout(Duration::from_secs(10))?;
+        conn.execute_batch(INIT)?;
+        Ok(Mutex::new(conn))
+    }
+
+    /// Checks that the shards match the `layout` persisted in the main database, and
+    /// records the layout in new shards.
+    pub fn check_layout(&self, layout: Option<usize>) -> libipld::Result<()> {
+        let n = self.shards.len();
+        if let Some(expected) = layout {
+            if expected != n {
+                return Err(ShardLayoutMismatch { expected, found: n }.into());
+            }
+        }
+        for (i, conn) in self.shards.iter().enumerate() {
+            let conn = conn.lock();
+            let row: Option<(i64, i64)> = conn
+                .query_row("SELECT shard, shards FROM layout", params![], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .optional()?;
+            match row {
+                Some((shard, shards)) if shard as usize == i && shards as usize == n => {}
+                Some(_) => return Err(ShardMissing(i).into()),
+                // a fresh shard of an existing layout means the shard file was lost.
+                None if layout.is_some() => return Err(ShardMissing(i).into()),
+                None => {
+                    conn.execute(
+                        "INSERT INTO layout (shard, shards) VALUES (?, ?)",
+                        params![i as i64, n as i64],
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn index(&self, cid: &Cid) -> usize {
+        let digest = cid.hash().digest();
+        let prefix = digest
+            .iter()
+            .take(2)
+            .fold(0usize, |n, b| n << 8 | *b as usize);
+        prefix % self.shards.len()
+    }
+
+    fn shard(&self, cid: &Cid) -> &Mutex<Connection> {
+        &self.shards[self.index(cid)]
+    }
+
+    pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let conn = self.shard(cid).lock();
+        let mut stmt = conn.prepare_cached("SELECT data FROM blocks WHERE cid = ?")?;
+        stmt.query_row(params![cid.to_bytes()], |row| row.get(0))
+            .optional()
+    }
+
+    /// Inserts a batch of blocks. Each shard is written in a single transaction, and the
+    /// shards are written in parallel.
+    pub fn insert_many(self: &Arc<Self>, blocks: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+        let mut groups = FnvHashMap::<usize, Vec<(Cid, Vec<u8>)>>::default();
+        for (cid, data) in blocks {
+            groups
+                .entry(self.index(&cid))
+                .or_default()
+                .push((cid, data));
+        }
+        let mut groups = groups.into_iter();
+        let first = match groups.next() {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+        let handles = groups
+            .map(|(i, blocks)| {
+                let shards = self.clone();
+                std::thread::spawn(move || shards.insert_group(i, &blocks))
+            })
+            .collect::<Vec<_>>();
+        let mut res = self.insert_group(first.0, &first.1);
+        for handle in handles {
+            let res2 = handle
+                .join()
+                .unwrap_or_else(|err| std::panic::resume_unwind(err));
+            res = res.and(res2);
+        }
+        res
+    }
+
+    fn insert_group(&self, shard: usize, blocks: &[(Cid, Vec<u8>)]) -> Result<()> {
+        let mut conn = self.shards[shard].lock();
+        let txn = conn.transaction()?;
+        {
+            let mut stmt =
+                txn.prepare_cached("INSERT OR REPLACE INTO blocks (cid, data) VALUES (?, ?)")?;
+            for (cid, data) in blocks {
+                stmt.execute(params![cid.to_bytes(), data])?;
+            }
+        }
+        txn.commit()
+    }
+
+    /// Removes the data of a block deleted from the main database and returns its size,
+    /// or `None` if the block wasn't sharded. The block stays marked as sharded until
+    /// `take_removed` is called, since the marks are stored in the main database, which
+    /// can't be written while the block is deleted.
+    pub fn remove(&self, cid: &Cid) -> Result<Option<usize>> {
+        self.removed.lock().push(*cid);
+        let conn = self.shard(cid).lock();
+        let bytes = cid.to_bytes();
+        let len = conn
+            .prepare_cached("SELECT LENGTH(data) FROM blocks WHERE cid = ?")?
+            .query_row(params![bytes], |row| row.get::<_, i64>(0))
+            .optional()?;
+        let mut stmt = conn.prepare_cached("DELETE FROM blocks WHERE cid = ?")?;
+        stmt.execute(params![bytes])?;
+        Ok(len.map(|len| len as usize))
+    }
+
+    /// Returns the blocks removed since the last call.
+    pub fn take_removed(&self) -> Vec<Cid> {
+        std::mem::take(&mut *self.removed.lock())
+    }
+
+    /// Syncs every transaction of the shards before it completes.
+    pub fn set_synchronous_full(&self) -> Result<()> {
+        for conn in &self.shards {
+            conn.lock().execute_batch("PRAGMA synchronous = FULL")?;
+        }
+        Ok(())
+    }
+
+    /// Locks all shards, giving up at `deadline`.
+    pub fn lock_until(&self, deadline: Instant) -> Option<Vec<MutexGuard<'_, Connection>>> {
+        self.shards
+            .iter()
+            .map(|conn| conn.try_lock_for(deadline.saturating_duration_since(Instant::now())))
+            .collect()
+    }
+
+    /// Returns the number of blocks and their size in bytes of every shard.
+    pub fn stats(&self) -> Result<Vec<(u64, u64)>> {
+        self.shards
+            .iter()
+            .map(|conn| {
+                conn.lock().query_row(
+                    "SELECT COUNT(*), COALESCE(SUM(LENGTH(data)), 0) FROM blocks",
+                    params![],
+                    |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+                )
+            })
+            .collect()
+    }
+}
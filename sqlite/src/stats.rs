@@ -0,0 +1,80 @@
+use crate::meta::MetaStore;
+use crate::shard::Shards;
+use fnv::FnvHashSet;
+use ipfs_sqlite_block_store::BlockStore;
+use libipld::{Cid, Result};
+use parking_lot::Mutex;
+use std::convert::TryFrom;
+
+/// Block store statistics.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StoreStats {
+    /// Number of stored blocks, including the sharded blocks.
+    pub blocks: u64,
+    /// Size in bytes of the stored blocks, including the data of the sharded blocks.
+    pub size: u64,
+    /// Number of blocks whose data is stored in a shard.
+    pub sharded_blocks: u64,
+    /// Size in bytes of the data stored in shards.
+    pub sharded_size: u64,
+    /// Number of stored blocks reachable from an alias.
+    pub pinned_blocks: Option<u64>,
+    /// Number of stored blocks not reachable from an alias, which the garbage collector
+    /// is free to evict unless they are temporarily pinned.
+    pub orphaned_blocks: Option<u64>,
+}
+
+impl StoreStats {
+    /// Reads the statistics from the database. This blocks until the store lock is
+    /// acquired.
+    pub(crate) fn read(
+        store: &Mutex<BlockStore>,
+        meta: &Mutex<MetaStore>,
+        shards: Option<&Shards>,
+    ) -> Result<Self> {
+        let stats = store.lock().get_store_stats()?;
+        let (sharded_blocks, sharded_size) = match shards {
+            Some(shards) => shards
+                .stats()?
+                .into_iter()
+                .fold((0, 0), |(n, s), (count, size)| (n + count, s + size)),
+            None => (0, 0),
+        };
+        let blocks = stats.count() as u64;
+        let pinned_blocks = pinned_blocks(store, meta)?;
+        Ok(Self {
+            blocks,
+            size: stats.size() as u64 + sharded_size,
+            sharded_blocks,
+            sharded_size,
+            pinned_blocks: Some(pinned_blocks),
+            orphaned_blocks: Some(blocks.saturating_sub(pinned_blocks)),
+        })
+    }
+}
+
+/// Counts the stored blocks reachable from an alias or from the alias history. The store
+/// lock is released between the dags of the roots, so that counting doesn't stall writers.
+fn pinned_blocks(store: &Mutex<BlockStore>, meta: &Mutex<MetaStore>) -> Result<u64> {
+    let names = meta.lock().alias_names(b"")?;
+    let history = meta.lock().all_alias_history()?;
+    let mut roots = Vec::with_capacity(names.len() + history.len());
+    for name in names {
+        roots.extend(store.lock().resolve(&name)?);
+    }
+    for (_, _, cid) in history {
+        roots.push(Cid::try_from(cid)?);
+    }
+    let mut reachable = FnvHashSet::default();
+    let mut missing = FnvHashSet::default();
+    for root in roots {
+        // the dag below a reached block was already counted.
+        if !reachable.insert(root) {
+            continue;
+        }
+        let mut store = store.lock();
+        reachable.extend(store.get_descendants::<Vec<Cid>>(&root)?);
+        missing.extend(store.get_missing_blocks::<Vec<Cid>>(&root)?);
+    }
+    Ok(reachable.difference(&missing).count() as u64)
+}
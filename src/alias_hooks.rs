@@ -0,0 +1,97 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use ipfs_embed_sqlite::{AliasChange, AliasCommitHook};
+use libipld::Result;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Error returned when a pre-commit hook rejects an alias change.
+#[derive(Debug, Error)]
+#[error("change of alias {} rejected: {1}", String::from_utf8_lossy(.0))]
+pub struct AliasRejected(pub Vec<u8>, pub String);
+
+/// Hook running on every alias change, for example to write an audit log, validate the
+/// new roots or replicate them right away. Expired aliases are reported as removals.
+#[async_trait]
+pub trait AliasHook: Send + Sync + 'static {
+    /// Runs before a change is committed. Returning an error rejects the change, and all
+    /// other changes made by the same call.
+    ///
+    /// Pre-commit hooks are synchronous, because they run on the thread changing the
+    /// alias while the aliases are locked. They need to complete quickly, must not block
+    /// on async work and must not change aliases themselves. Slow work belongs in
+    /// `post_commit`.
+    fn pre_commit(&self, _change: &AliasChange) -> Result<()> {
+        Ok(())
+    }
+
+    /// Runs after a change was committed.
+    async fn post_commit(&self, _change: &AliasChange) {}
+}
+
+type Hooks = Arc<RwLock<Vec<Arc<dyn AliasHook>>>>;
+
+/// The registered alias hooks. Post-commit hooks run in a background task, one change
+/// after the other in the order the changes were committed.
+#[derive(Clone)]
+pub(crate) struct AliasHooks {
+    hooks: Hooks,
+    tx: mpsc::UnboundedSender<AliasChange>,
+}
+
+impl AliasHooks {
+    pub fn new() -> Self {
+        let hooks: Hooks = Default::default();
+        let (tx, mut rx) = mpsc::unbounded::<AliasChange>();
+        let hooks2 = hooks.clone();
+        ipfs_embed_rt::spawn(async move {
+            while let Some(change) = rx.next().await {
+                let hooks = hooks2.read().clone();
+                for hook in hooks {
+                    hook.post_commit(&change).await;
+                }
+            }
+        })
+        .detach();
+        Self { hooks, tx }
+    }
+
+    pub fn add(&self, hook: Arc<dyn AliasHook>) {
+        self.hooks.write().push(hook);
+    }
+
+    /// Runs the pre-commit hooks, failing with `AliasRejected` if a hook rejects the
+    /// change.
+    pub fn pre_commit(&self, change: &AliasChange) -> Result<()> {
+        let hooks = self.hooks.read().clone();
+        for hook in hooks {
+            if let Err(err) = hook.pre_commit(change) {
+                tracing::debug!("alias change {:?} rejected: {}", change, err);
+                return Err(AliasRejected(change.alias.clone(), err.to_string()).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues committed changes for the post-commit hooks.
+    pub fn committed(&self, changes: impl IntoIterator<Item = AliasChange>) {
+        for change in changes {
+            self.tx.unbounded_send(change).ok();
+        }
+    }
+}
+
+impl AliasCommitHook for AliasHooks {
+    fn pre_commit(&self, changes: &[AliasChange]) -> Result<()> {
+        for change in changes {
+            AliasHooks::pre_commit(self, change)?;
+        }
+        Ok(())
+    }
+
+    fn post_commit(&self, changes: Vec<AliasChange>) {
+        self.committed(changes)
+    }
+}
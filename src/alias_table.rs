@@ -0,0 +1,97 @@
+use crate::checkpoint::CHECKPOINT_ALIAS;
+use crate::{encode_block, DAG_CBOR};
+use ipfs_embed_sqlite::{StorageService, TempPin, ALIAS_HISTORY_PREFIX};
+use libipld::codec::{Decode, Encode, References};
+use libipld::error::BlockNotFound;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Number of aliases stored in a single block of the alias table.
+const ENTRIES_PER_BLOCK: usize = 256;
+
+/// Error returned when importing a malformed alias table.
+#[derive(Debug, Error)]
+#[error("invalid alias table")]
+pub struct InvalidAliasTable;
+
+/// Encodes the aliases as a dag and returns its root. The blocks are added to `tmp`.
+///
+/// The root is a map with the `version` and the links to the `entries` blocks, each
+/// holding a list of up to 256 `[name, root]` pairs ordered by name. The table links to
+/// the roots of the aliases, so syncing it replicates the aliased dags as well.
+pub(crate) fn export<P: StoreParams>(storage: &StorageService<P>, tmp: &TempPin) -> Result<Cid>
+where
+    Ipld: References<P::Codecs> + Encode<P::Codecs>,
+{
+    let aliases = storage
+        .aliases()?
+        .into_iter()
+        .filter(|(alias, _)| !alias.starts_with(ALIAS_HISTORY_PREFIX) && alias != CHECKPOINT_ALIAS)
+        .collect::<Vec<_>>();
+    let mut entries = vec![];
+    for chunk in aliases.chunks(ENTRIES_PER_BLOCK) {
+        let ipld = Ipld::List(
+            chunk
+                .iter()
+                .map(|(alias, cid)| Ipld::List(vec![Ipld::Bytes(alias.clone()), Ipld::Link(*cid)]))
+                .collect(),
+        );
+        let block = encode_block::<P>(DAG_CBOR, &ipld)?;
+        storage.temp_pin(tmp, std::iter::once(*block.cid()))?;
+        storage.insert(&block)?;
+        entries.push(Ipld::Link(*block.cid()));
+    }
+    let mut root = BTreeMap::new();
+    root.insert("version".to_string(), Ipld::Integer(1));
+    root.insert("entries".to_string(), Ipld::List(entries));
+    let block = encode_block::<P>(DAG_CBOR, &Ipld::StringMap(root))?;
+    storage.temp_pin(tmp, std::iter::once(*block.cid()))?;
+    storage.insert(&block)?;
+    Ok(*block.cid())
+}
+
+fn get<P: StoreParams>(storage: &StorageService<P>, cid: &Cid) -> Result<Ipld>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    let data = storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+    Block::<P>::new_unchecked(*cid, data).ipld()
+}
+
+/// Sets the aliases of the table rooted at `root`, returning the number of aliases. The
+/// blocks of the table need to be in the block store, the aliased dags don't.
+pub(crate) fn import<P: StoreParams>(storage: &StorageService<P>, root: &Cid) -> Result<usize>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    let entries = match get(storage, root)?.get("entries") {
+        Ok(Ipld::List(entries)) => entries.clone(),
+        _ => return Err(InvalidAliasTable.into()),
+    };
+    let mut aliases = vec![];
+    for entry in entries {
+        let cid = match entry {
+            Ipld::Link(cid) => cid,
+            _ => return Err(InvalidAliasTable.into()),
+        };
+        let list = match get(storage, &cid)? {
+            Ipld::List(list) => list,
+            _ => return Err(InvalidAliasTable.into()),
+        };
+        for pair in list {
+            match pair {
+                Ipld::List(pair) => match pair.as_slice() {
+                    [Ipld::Bytes(alias), Ipld::Link(cid)] => aliases.push((alias.clone(), *cid)),
+                    _ => return Err(InvalidAliasTable.into()),
+                },
+                _ => return Err(InvalidAliasTable.into()),
+            }
+        }
+    }
+    for (alias, cid) in &aliases {
+        storage.alias(alias, Some(cid))?;
+    }
+    Ok(aliases.len())
+}
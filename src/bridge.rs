@@ -0,0 +1,294 @@
+use crate::Ipfs;
+use async_trait::async_trait;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::mpsc;
+use futures::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use futures::stream::{BoxStream, StreamExt};
+use ipfs_embed_rt::{Task, TcpStream};
+use libipld::codec::References;
+use libipld::multihash::{Code, MultihashDigest};
+use libipld::store::StoreParams;
+use libipld::{Ipld, Result};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Time a mirrored message is remembered, so that it isn't mirrored back when it returns
+/// through another bridge of the same topic.
+const ECHO_WINDOW: Duration = Duration::from_secs(60);
+/// Maximum number of mirrored messages remembered per topic.
+const MAX_MIRRORED: usize = 4096;
+/// Maximum size of a message received from the nats server, the default `max_payload`
+/// of nats.
+const MAX_PAYLOAD_SIZE: usize = 1024 * 1024;
+
+/// A message broker gossipsub topics are mirrored to.
+///
+/// Brokers should not deliver the messages published by the bridge to its own
+/// subscriptions. Echoes are dropped by the bridge, but they cost a round trip.
+#[async_trait]
+pub trait Broker: Send + Sync + 'static {
+    /// Publishes a message on a subject of the broker.
+    async fn publish(&self, subject: &str, payload: Vec<u8>) -> Result<()>;
+
+    /// Subscribes to a subject of the broker.
+    async fn subscribe(&self, subject: &str) -> Result<BoxStream<'static, Vec<u8>>>;
+}
+
+/// Direction messages are mirrored in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BridgeDirection {
+    /// Gossipsub messages are published on the broker.
+    ToBroker,
+    /// Broker messages are published on gossipsub.
+    FromBroker,
+    /// Messages are mirrored in both directions.
+    Both,
+}
+
+/// A gossipsub topic mirrored to a subject of the broker.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BridgedTopic {
+    /// The gossipsub topic.
+    pub topic: String,
+    /// The subject of the broker.
+    pub subject: String,
+    /// Direction messages are mirrored in.
+    pub direction: BridgeDirection,
+}
+
+impl BridgedTopic {
+    /// Mirrors `topic` to the broker subject `subject` in both directions.
+    pub fn new(topic: impl Into<String>, subject: impl Into<String>) -> Self {
+        Self {
+            topic: topic.into(),
+            subject: subject.into(),
+            direction: BridgeDirection::Both,
+        }
+    }
+}
+
+/// Handle of a bridge. Mirroring stops when it is dropped.
+pub struct Bridge {
+    _tasks: Vec<Task<()>>,
+}
+
+/// The messages of a topic mirrored recently in either direction.
+///
+/// When several nodes bridge the same topic, a message mirrored to the broker by one
+/// bridge is published on gossipsub by the others, from where it reaches the first
+/// bridge again. Such echoes are recognized by the digest of the message and dropped.
+#[derive(Default)]
+pub(crate) struct Mirrored {
+    order: VecDeque<(Instant, Vec<u8>)>,
+    digests: FnvHashSet<Vec<u8>>,
+}
+
+impl Mirrored {
+    /// Records a message that is about to be mirrored. Returns `false` if the message is
+    /// an echo of a message mirrored within the `ECHO_WINDOW`.
+    pub fn insert(&mut self, msg: &[u8], now: Instant) -> bool {
+        while let Some((at, _)) = self.order.front() {
+            if now.duration_since(*at) < ECHO_WINDOW && self.order.len() < MAX_MIRRORED {
+                break;
+            }
+            if let Some((_, digest)) = self.order.pop_front() {
+                self.digests.remove(&digest);
+            }
+        }
+        let digest = Code::Blake3_256.digest(msg).to_bytes();
+        if !self.digests.insert(digest.clone()) {
+            return false;
+        }
+        self.order.push_back((now, digest));
+        true
+    }
+}
+
+/// Mirrors the gossipsub `topics` of `ipfs` to `broker`. A message is mirrored at most
+/// once per minute, so that bridges of the same topic on several nodes don't mirror
+/// messages back and forth.
+pub async fn bridge<P: StoreParams, B: Broker>(
+    ipfs: &Ipfs<P>,
+    broker: B,
+    topics: Vec<BridgedTopic>,
+) -> Result<Bridge>
+where
+    Ipld: References<P::Codecs>,
+{
+    let broker = Arc::new(broker);
+    let mut tasks = vec![];
+    for bridged in topics {
+        let BridgedTopic {
+            topic,
+            subject,
+            direction,
+        } = bridged;
+        let mirrored = Arc::new(Mutex::new(Mirrored::default()));
+        if direction != BridgeDirection::FromBroker {
+            let mut messages = ipfs.subscribe(&topic)?;
+            let broker = broker.clone();
+            let subject = subject.clone();
+            let mirrored = mirrored.clone();
+            tasks.push(ipfs_embed_rt::spawn(async move {
+                while let Some(msg) = messages.next().await {
+                    if !mirrored.lock().insert(&msg, Instant::now()) {
+                        tracing::trace!("dropping echo on {}", subject);
+                        continue;
+                    }
+                    if let Err(err) = broker.publish(&subject, msg).await {
+                        tracing::warn!("failed to publish on {}: {}", subject, err);
+                    }
+                }
+            }));
+        }
+        if direction != BridgeDirection::ToBroker {
+            let mut messages = broker.subscribe(&subject).await?;
+            let ipfs = ipfs.clone();
+            tasks.push(ipfs_embed_rt::spawn(async move {
+                while let Some(msg) = messages.next().await {
+                    if !mirrored.lock().insert(&msg, Instant::now()) {
+                        tracing::trace!("dropping echo on {}", topic);
+                        continue;
+                    }
+                    if let Err(err) = ipfs.publish(&topic, msg) {
+                        tracing::debug!("failed to publish on {}: {}", topic, err);
+                    }
+                }
+            }));
+        }
+    }
+    Ok(Bridge { _tasks: tasks })
+}
+
+/// Error returned when the nats server sends an error or an unexpected message.
+#[derive(Debug, Error)]
+#[error("nats: {0}")]
+pub struct NatsError(pub String);
+
+pub(crate) type Subscriptions = Arc<Mutex<FnvHashMap<u64, mpsc::UnboundedSender<Vec<u8>>>>>;
+
+/// A minimal client of the nats core protocol. Subscriptions end when the connection to
+/// the server is lost; the client doesn't reconnect.
+pub struct NatsBroker {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    subscriptions: Subscriptions,
+    next_sid: AtomicU64,
+    _tasks: [Task<()>; 2],
+}
+
+impl NatsBroker {
+    /// Connects to the nats server listening on `addr`.
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        let (reader, mut writer) = socket.split();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        if !line.starts_with("INFO ") {
+            return Err(NatsError(line.trim_end().into()).into());
+        }
+        let (tx, mut rx) = mpsc::unbounded::<Vec<u8>>();
+        tx.unbounded_send(
+            b"CONNECT {\"verbose\":false,\"pedantic\":false,\"echo\":false}\r\n".to_vec(),
+        )?;
+        let write = ipfs_embed_rt::spawn(async move {
+            while let Some(frame) = rx.next().await {
+                if let Err(err) = writer.write_all(&frame).await {
+                    tracing::warn!("nats: {}", err);
+                    break;
+                }
+            }
+        });
+        let subscriptions = Subscriptions::default();
+        let subscriptions2 = subscriptions.clone();
+        let tx2 = tx.clone();
+        let read = ipfs_embed_rt::spawn(async move {
+            if let Err(err) = read_loop(reader, &subscriptions2, &tx2).await {
+                tracing::warn!("nats: {}", err);
+            }
+            subscriptions2.lock().clear();
+        });
+        Ok(Self {
+            tx,
+            subscriptions,
+            next_sid: AtomicU64::new(1),
+            _tasks: [write, read],
+        })
+    }
+}
+
+pub(crate) async fn read_loop<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    subscriptions: &Subscriptions,
+    tx: &mpsc::UnboundedSender<Vec<u8>>,
+) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("MSG") => {
+                let parts = parts.collect::<Vec<_>>();
+                let (sid, len) = match parts.as_slice() {
+                    [_, sid, len] | [_, sid, _, len] => {
+                        (sid.parse::<u64>()?, len.parse::<usize>()?)
+                    }
+                    _ => return Err(NatsError(line.trim_end().into()).into()),
+                };
+                if len > MAX_PAYLOAD_SIZE {
+                    return Err(NatsError(format!("payload of {} bytes is too large", len)).into());
+                }
+                let mut payload = vec![0; len + 2];
+                reader.read_exact(&mut payload).await?;
+                payload.truncate(len);
+                let mut subscriptions = subscriptions.lock();
+                if let Some(sub) = subscriptions.get(&sid) {
+                    if sub.unbounded_send(payload).is_err() {
+                        subscriptions.remove(&sid);
+                        tx.unbounded_send(format!("UNSUB {}\r\n", sid).into_bytes())?;
+                    }
+                }
+            }
+            Some("PING") => tx.unbounded_send(b"PONG\r\n".to_vec())?,
+            Some("-ERR") => return Err(NatsError(line.trim_end().into()).into()),
+            _ => {}
+        }
+    }
+}
+
+fn check_subject(subject: &str) -> Result<()> {
+    if subject.is_empty() || subject.contains(char::is_whitespace) {
+        return Err(NatsError(format!("invalid subject {:?}", subject)).into());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Broker for NatsBroker {
+    async fn publish(&self, subject: &str, payload: Vec<u8>) -> Result<()> {
+        check_subject(subject)?;
+        let mut frame = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(b"\r\n");
+        self.tx.unbounded_send(frame)?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, subject: &str) -> Result<BoxStream<'static, Vec<u8>>> {
+        check_subject(subject)?;
+        let sid = self.next_sid.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded();
+        self.subscriptions.lock().insert(sid, tx);
+        self.tx
+            .unbounded_send(format!("SUB {} {}\r\n", subject, sid).into_bytes())?;
+        Ok(rx.boxed())
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{encode_block, Ipfs, DAG_CBOR, RAW};
+use fnv::FnvHashMap;
+use futures::stream::{Stream, StreamExt};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, Decode, Encode, References};
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Error returned when a channel message or log block is malformed.
+#[derive(Debug, Error)]
+#[error("invalid channel message")]
+pub struct InvalidChannelMessage;
+
+/// Locks serializing the appends to the log of each channel, shared by all `Channel`s of
+/// a node with the same name.
+pub(crate) type ChannelLocks = Arc<Mutex<FnvHashMap<String, Arc<Mutex<()>>>>>;
+
+/// A message received on a channel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChannelMessage {
+    /// The message.
+    pub data: Vec<u8>,
+    /// Log of the sender after appending the message. Can be passed to `Channel::replay`
+    /// to fetch the messages the sender has seen.
+    pub head: Cid,
+}
+
+fn encode_message(head: &Cid, data: &[u8]) -> Result<Vec<u8>> {
+    let mut map = BTreeMap::new();
+    map.insert("head".to_string(), Ipld::Link(*head));
+    map.insert("data".to_string(), Ipld::Bytes(data.to_vec()));
+    DagCborCodec.encode(&Ipld::StringMap(map))
+}
+
+fn decode_message(bytes: &[u8]) -> Result<ChannelMessage> {
+    let ipld: Ipld = DagCborCodec.decode(bytes)?;
+    match (ipld.get("head"), ipld.get("data")) {
+        (Ok(Ipld::Link(head)), Ok(Ipld::Bytes(data))) => Ok(ChannelMessage {
+            data: data.clone(),
+            head: *head,
+        }),
+        _ => Err(InvalidChannelMessage.into()),
+    }
+}
+
+/// A named message log on top of gossipsub.
+///
+/// Every message is stored as a block, and the log of the last `capacity` messages is a
+/// block linking to them that is pinned by the alias `/ipfs-embed/channel/<name>`. Older
+/// messages are left to the garbage collector. Received messages are appended to the
+/// local log, so every subscriber can serve the history to peers joining later, who
+/// fetch it over bitswap using `replay`.
+#[derive(Clone)]
+pub struct Channel<P: StoreParams> {
+    ipfs: Ipfs<P>,
+    name: String,
+    capacity: usize,
+    lock: Arc<Mutex<()>>,
+}
+
+impl<P: StoreParams> Channel<P>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs> + Encode<P::Codecs>,
+{
+    pub(crate) fn new(ipfs: Ipfs<P>, name: &str, capacity: usize) -> Self {
+        let lock = ipfs
+            .channel_locks
+            .lock()
+            .entry(name.into())
+            .or_default()
+            .clone();
+        Self {
+            ipfs,
+            name: name.into(),
+            capacity,
+            lock,
+        }
+    }
+
+    /// Returns the name of the channel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the gossipsub topic of the channel.
+    pub fn topic(&self) -> String {
+        format!("/ipfs-embed/channel/{}", self.name)
+    }
+
+    /// Returns the root of the local log.
+    pub fn head(&self) -> Result<Option<Cid>> {
+        Ok(self.ipfs.resolve(self.topic())?)
+    }
+
+    /// Returns the messages of the local log, oldest first.
+    pub fn history(&self) -> Result<Vec<Vec<u8>>> {
+        match self.head()? {
+            Some(head) => self.messages(&head),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Publishes a message, appending it to the local log.
+    pub fn publish(&self, data: Vec<u8>) -> Result<Cid> {
+        let head = self.append(&data)?;
+        self.ipfs
+            .publish(&self.topic(), encode_message(&head, &data)?)?;
+        Ok(head)
+    }
+
+    /// Subscribes to the channel, returning a `Stream` of received messages. Received
+    /// messages are appended to the local log.
+    pub fn subscribe(&self) -> Result<impl Stream<Item = ChannelMessage>> {
+        let channel = self.clone();
+        let stream = self.ipfs.subscribe(&self.topic())?;
+        Ok(stream.filter_map(move |bytes| {
+            let channel = channel.clone();
+            async move {
+                let msg = match decode_message(&bytes) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        tracing::debug!("{}: {}", channel.name, err);
+                        return None;
+                    }
+                };
+                let name = channel.name.clone();
+                let data = msg.data.clone();
+                let res = ipfs_embed_rt::spawn_blocking(move || channel.append(&data)).await;
+                if let Err(err) = res {
+                    tracing::warn!("failed to append to channel {}: {}", name, err);
+                }
+                Some(msg)
+            }
+        }))
+    }
+
+    /// Fetches the log rooted at `head` from peers and returns its messages, oldest first.
+    pub async fn replay(&self, head: &Cid) -> Result<Vec<Vec<u8>>> {
+        let tmp = self.ipfs.create_temp_pin()?;
+        self.ipfs.temp_pin(&tmp, head)?;
+        self.ipfs.sync(head).await?;
+        self.messages(head)
+    }
+
+    fn messages(&self, head: &Cid) -> Result<Vec<Vec<u8>>> {
+        self.links(head)?
+            .iter()
+            .map(|cid| match self.ipfs.get(cid)?.ipld()? {
+                Ipld::Bytes(data) => Ok(data),
+                _ => Err(InvalidChannelMessage.into()),
+            })
+            .collect()
+    }
+
+    fn links(&self, head: &Cid) -> Result<Vec<Cid>> {
+        match self.ipfs.get(head)?.ipld()? {
+            Ipld::List(links) => links
+                .into_iter()
+                .map(|link| match link {
+                    Ipld::Link(cid) => Ok(cid),
+                    _ => Err(InvalidChannelMessage.into()),
+                })
+                .collect(),
+            _ => Err(InvalidChannelMessage.into()),
+        }
+    }
+
+    fn append(&self, data: &[u8]) -> Result<Cid> {
+        let _guard = self.lock.lock();
+        let msg = encode_block::<P>(RAW, &Ipld::Bytes(data.to_vec()))?;
+        let tmp = self.ipfs.create_temp_pin()?;
+        self.ipfs.temp_pin(&tmp, msg.cid())?;
+        let _ = self.ipfs.insert(&msg)?;
+        let mut links = match self.head()? {
+            Some(head) => self.links(&head)?,
+            None => vec![],
+        };
+        links.push(*msg.cid());
+        let skip = links.len().saturating_sub(self.capacity);
+        let log = Ipld::List(links.into_iter().skip(skip).map(Ipld::Link).collect());
+        let log = encode_block::<P>(DAG_CBOR, &log)?;
+        self.ipfs.temp_pin(&tmp, log.cid())?;
+        let _ = self.ipfs.insert(&log)?;
+        self.ipfs.alias(self.topic(), Some(log.cid()))?;
+        Ok(*log.cid())
+    }
+}
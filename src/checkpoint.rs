@@ -0,0 +1,105 @@
+use crate::alias_table;
+use crate::traversal;
+use crate::{encode_block, DAG_CBOR};
+use ipfs_embed_net::TraversalOrder;
+use ipfs_embed_sqlite::{StorageService, TempPin};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, Decode, Encode, References};
+use libipld::error::BlockNotFound;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+use thiserror::Error;
+
+/// Alias of the most recent checkpoint. It is excluded from the alias table, so that a
+/// checkpoint doesn't include the previous one.
+pub(crate) const CHECKPOINT_ALIAS: &[u8] = b"/ipfs-embed/checkpoint";
+
+/// Error returned when a checkpoint manifest is malformed.
+#[derive(Debug, Error)]
+#[error("invalid checkpoint manifest {0}")]
+pub struct InvalidCheckpoint(pub Cid);
+
+fn write_varint(writer: &mut impl Write, mut n: u64) -> Result<()> {
+    let mut buf = [0; 10];
+    let mut i = 0;
+    loop {
+        buf[i] = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            break;
+        }
+        buf[i] |= 0x80;
+        i += 1;
+    }
+    writer.write_all(&buf[..=i])?;
+    Ok(())
+}
+
+fn write_section(writer: &mut impl Write, parts: &[&[u8]]) -> Result<()> {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>();
+    write_varint(writer, len as u64)?;
+    for part in parts {
+        writer.write_all(part)?;
+    }
+    Ok(())
+}
+
+/// Encodes the manifest of a checkpoint linking to the alias table and pins it with the
+/// checkpoint alias. The blocks of the alias table are added to `tmp`.
+pub(crate) fn create<P: StoreParams>(storage: &StorageService<P>, tmp: &TempPin) -> Result<Cid>
+where
+    Ipld: References<P::Codecs> + Encode<P::Codecs>,
+{
+    let aliases = alias_table::export(storage, tmp)?;
+    let mut manifest = BTreeMap::new();
+    manifest.insert("version".to_string(), Ipld::Integer(1));
+    manifest.insert("aliases".to_string(), Ipld::Link(aliases));
+    let block = encode_block::<P>(DAG_CBOR, &Ipld::StringMap(manifest))?;
+    storage.temp_pin(tmp, std::iter::once(*block.cid()))?;
+    storage.insert(&block)?;
+    storage.alias(CHECKPOINT_ALIAS, Some(block.cid()))?;
+    Ok(*block.cid())
+}
+
+/// Writes the dag rooted at `root` as a CAR file with `root` as its only root.
+pub(crate) fn write_car<P: StoreParams>(
+    storage: &StorageService<P>,
+    root: &Cid,
+    order: TraversalOrder,
+    mut writer: impl Write,
+) -> Result<usize>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut header = BTreeMap::new();
+    header.insert("roots".to_string(), Ipld::List(vec![Ipld::Link(*root)]));
+    header.insert("version".to_string(), Ipld::Integer(1));
+    let header = DagCborCodec.encode(&Ipld::StringMap(header))?;
+    write_section(&mut writer, &[&header])?;
+    let mut written = 0;
+    traversal::walk(storage, root, order, |cid, data| {
+        let data = data.ok_or(BlockNotFound(*cid))?;
+        write_section(&mut writer, &[&cid.to_bytes(), data])?;
+        written += 1;
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Returns the root of the alias table of a checkpoint manifest.
+pub(crate) fn aliases<P: StoreParams>(storage: &StorageService<P>, manifest: &Cid) -> Result<Cid>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    let data = storage.get(manifest)?.ok_or(BlockNotFound(*manifest))?;
+    match Block::<P>::new_unchecked(*manifest, data)
+        .ipld()?
+        .get("aliases")
+    {
+        Ok(Ipld::Link(aliases)) => Ok(*aliases),
+        _ => Err(InvalidCheckpoint(*manifest).into()),
+    }
+}
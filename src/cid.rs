@@ -0,0 +1,71 @@
+use crate::{DAG_PB, SHA2_256};
+use libipld::cid::multibase::Base;
+use libipld::cid::Version;
+use libipld::{Cid, Result};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+/// Error returned when a cid can't be represented as a cid v0.
+#[derive(Debug, Error)]
+#[error("{0} is not a dag-pb sha2-256 cid")]
+pub struct NotCidV0(pub Cid);
+
+/// A value that can be used where a cid is expected. Strings are parsed, so cids
+/// received as text don't need to be parsed first.
+pub trait ToCid {
+    /// Returns the cid.
+    fn to_cid(&self) -> Result<Cid>;
+}
+
+impl ToCid for Cid {
+    fn to_cid(&self) -> Result<Cid> {
+        Ok(*self)
+    }
+}
+
+impl ToCid for str {
+    fn to_cid(&self) -> Result<Cid> {
+        Ok(Cid::try_from(self)?)
+    }
+}
+
+impl ToCid for String {
+    fn to_cid(&self) -> Result<Cid> {
+        self.as_str().to_cid()
+    }
+}
+
+impl<T: ToCid + ?Sized> ToCid for &T {
+    fn to_cid(&self) -> Result<Cid> {
+        (**self).to_cid()
+    }
+}
+
+/// Converts a cid to a cid v1. Cid v1s are returned unchanged, so comparing the results
+/// tells if two cids refer to the same block.
+pub fn cid_v1(cid: &Cid) -> Cid {
+    match cid.version() {
+        Version::V0 => Cid::new_v1(cid.codec(), *cid.hash()),
+        Version::V1 => *cid,
+    }
+}
+
+/// Converts a cid to a cid v0. Only dag-pb cids using sha2-256 have a v0 representation.
+pub fn cid_v0(cid: &Cid) -> Result<Cid> {
+    if cid.codec() != DAG_PB || cid.hash().code() != SHA2_256 {
+        return Err(NotCidV0(*cid).into());
+    }
+    Ok(Cid::new_v0(*cid.hash())?)
+}
+
+/// Encodes a cid with a multibase. Cid v0s can only be encoded with base58btc, so for
+/// other bases they are converted to a cid v1 first.
+pub fn format_cid(cid: &Cid, base: Base) -> String {
+    let cid = if base == Base::Base58Btc {
+        *cid
+    } else {
+        cid_v1(cid)
+    };
+    cid.to_string_of_base(base)
+        .unwrap_or_else(|_| cid.to_string())
+}
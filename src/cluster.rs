@@ -0,0 +1,442 @@
+use crate::follow::inbox_topic;
+use crate::provenance::{InvalidProvenance, Provenance};
+use crate::{encode_block, Ipfs, DAG_CBOR};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::future;
+use futures::stream::{self, StreamExt};
+use ipfs_embed_net::{Event, PeerId, PublicKey};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, Decode, Encode, References};
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Minimum time between two announcements of the same head in reply to outdated heads.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Error returned when a pinset feed or announcement is malformed.
+#[derive(Debug, Error)]
+#[error("invalid pinset")]
+pub struct InvalidPinset;
+
+/// Error returned when a peer that isn't a writer of the cluster changes the pinset.
+#[derive(Debug, Error)]
+#[error("{0} is not a writer of the cluster")]
+pub struct NotAWriter(pub PeerId);
+
+/// An operation of a pinset feed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PinOp {
+    /// The dag of the `Cid` is pinned by all nodes of the cluster.
+    Pin(Cid),
+    /// The dag of the `Cid` is no longer pinned.
+    Unpin(Cid),
+}
+
+struct Entry {
+    op: PinOp,
+    prev: Option<Cid>,
+}
+
+fn encode_entry(entry: &Entry) -> Ipld {
+    let (op, cid) = match entry.op {
+        PinOp::Pin(cid) => ("pin", cid),
+        PinOp::Unpin(cid) => ("unpin", cid),
+    };
+    let mut map = BTreeMap::new();
+    map.insert("op".to_string(), Ipld::String(op.into()));
+    // the cid is stored as bytes, so that syncing the feed doesn't fetch unpinned dags.
+    map.insert("cid".to_string(), Ipld::Bytes(cid.to_bytes()));
+    map.insert(
+        "prev".to_string(),
+        entry.prev.map(Ipld::Link).unwrap_or(Ipld::Null),
+    );
+    Ipld::StringMap(map)
+}
+
+fn decode_entry(ipld: &Ipld) -> Result<Entry> {
+    let cid = match ipld.get("cid") {
+        Ok(Ipld::Bytes(bytes)) => Cid::try_from(&bytes[..])?,
+        _ => return Err(InvalidPinset.into()),
+    };
+    let op = match ipld.get("op") {
+        Ok(Ipld::String(op)) if op == "pin" => PinOp::Pin(cid),
+        Ok(Ipld::String(op)) if op == "unpin" => PinOp::Unpin(cid),
+        _ => return Err(InvalidPinset.into()),
+    };
+    let prev = match ipld.get("prev") {
+        Ok(Ipld::Link(prev)) => Some(*prev),
+        Ok(Ipld::Null) => None,
+        _ => return Err(InvalidPinset.into()),
+    };
+    Ok(Entry { op, prev })
+}
+
+fn encode_announcement(head: &Cid, provenance: &Provenance) -> Result<Vec<u8>> {
+    let public_key = provenance.public_key().clone().into_protobuf_encoding();
+    let mut map = BTreeMap::new();
+    map.insert("head".to_string(), Ipld::Link(*head));
+    map.insert("public_key".to_string(), Ipld::Bytes(public_key));
+    map.insert(
+        "signature".to_string(),
+        Ipld::Bytes(provenance.signature().to_vec()),
+    );
+    DagCborCodec.encode(&Ipld::StringMap(map))
+}
+
+pub(crate) fn decode_announcement(bytes: &[u8]) -> Result<(Cid, Provenance)> {
+    let ipld: Ipld = DagCborCodec.decode(bytes)?;
+    match (
+        ipld.get("head"),
+        ipld.get("public_key"),
+        ipld.get("signature"),
+    ) {
+        (Ok(Ipld::Link(head)), Ok(Ipld::Bytes(public_key)), Ok(Ipld::Bytes(signature))) => {
+            let public_key = PublicKey::from_protobuf_encoding(public_key)?;
+            Ok((*head, Provenance::new(public_key, signature.clone())))
+        }
+        _ => Err(InvalidPinset.into()),
+    }
+}
+
+/// The pinset of a feed, kept up to date by applying the operations appended since.
+#[derive(Default)]
+struct Pinset {
+    /// Head of the feed the pinset was computed from.
+    head: Option<Cid>,
+    seq: u64,
+    /// The pinned roots in the order they were pinned.
+    order: BTreeMap<u64, Cid>,
+    pins: FnvHashMap<Cid, u64>,
+}
+
+impl Pinset {
+    fn apply(&mut self, op: PinOp) {
+        match op {
+            PinOp::Pin(cid) => {
+                if !self.pins.contains_key(&cid) {
+                    self.seq += 1;
+                    self.order.insert(self.seq, cid);
+                    self.pins.insert(cid, self.seq);
+                }
+            }
+            PinOp::Unpin(cid) => {
+                if let Some(seq) = self.pins.remove(&cid) {
+                    self.order.remove(&seq);
+                }
+            }
+        }
+    }
+}
+
+enum Input {
+    Announcement(Vec<u8>),
+    Subscribed(PeerId),
+}
+
+/// A pinset shared by a group of nodes.
+///
+/// The pinset is a feed of pin and unpin operations, where every operation links to the
+/// previous one. Writers append operations and announce the signed head of the feed on
+/// the topic `/ipfs-embed/cluster/<name>`. Nodes following the cluster fetch the feed,
+/// and pin the dags of the pinset with the aliases `/ipfs-embed/cluster/<name>/pins/<cid>`,
+/// so that all of them converge to storing the same content.
+///
+/// Only heads signed by one of the `writers` are accepted, and only if they extend the
+/// local feed. Concurrent writes of several writers are not merged, so the writers need
+/// to coordinate.
+#[derive(Clone)]
+pub struct Cluster<P: StoreParams> {
+    ipfs: Ipfs<P>,
+    name: String,
+    writers: Arc<FnvHashSet<PeerId>>,
+    lock: Arc<Mutex<()>>,
+    pinset: Arc<Mutex<Pinset>>,
+    announced: Arc<Mutex<Option<(Cid, Instant)>>>,
+}
+
+impl<P: StoreParams> Cluster<P>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs> + Encode<P::Codecs>,
+{
+    pub(crate) fn new(ipfs: Ipfs<P>, name: &str, writers: Vec<PeerId>) -> Self {
+        Self {
+            ipfs,
+            name: name.into(),
+            writers: Arc::new(writers.into_iter().collect()),
+            lock: Default::default(),
+            pinset: Default::default(),
+            announced: Default::default(),
+        }
+    }
+
+    /// Returns the name of the cluster.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the gossipsub topic of the cluster.
+    pub fn topic(&self) -> String {
+        format!("/ipfs-embed/cluster/{}", self.name)
+    }
+
+    fn pin_alias(&self, cid: &Cid) -> String {
+        format!("{}/pins/{}", self.topic(), cid)
+    }
+
+    /// Returns the head of the local feed.
+    pub fn head(&self) -> Result<Option<Cid>> {
+        Ok(self.ipfs.resolve(self.topic())?)
+    }
+
+    /// Returns the operations of the local feed, oldest first.
+    pub fn history(&self) -> Result<Vec<PinOp>> {
+        let mut ops = vec![];
+        let mut next = self.head()?;
+        while let Some(cid) = next {
+            let entry = decode_entry(&self.ipfs.get(&cid)?.ipld()?)?;
+            ops.push(entry.op);
+            next = entry.prev;
+        }
+        ops.reverse();
+        Ok(ops)
+    }
+
+    /// Returns the roots pinned by the cluster according to the local feed.
+    pub fn pinset(&self) -> Result<Vec<Cid>> {
+        self.update_pinset()?;
+        Ok(self.pinset.lock().order.values().copied().collect())
+    }
+
+    /// Returns the operations from `base` to `head`, oldest first, or `None` if `base`
+    /// isn't an ancestor of `head`.
+    fn ops_since(&self, head: Option<&Cid>, base: Option<&Cid>) -> Result<Option<Vec<PinOp>>> {
+        let mut ops = vec![];
+        let mut next = head.copied();
+        while let Some(cid) = next {
+            if Some(&cid) == base {
+                break;
+            }
+            let entry = decode_entry(&self.ipfs.get(&cid)?.ipld()?)?;
+            ops.push(entry.op);
+            next = entry.prev;
+        }
+        if next.is_none() && base.is_some() {
+            return Ok(None);
+        }
+        ops.reverse();
+        Ok(Some(ops))
+    }
+
+    /// Applies the operations appended to the local feed since the pinset was last
+    /// updated, returning them.
+    fn update_pinset(&self) -> Result<Vec<PinOp>> {
+        let head = self.head()?;
+        let mut pinset = self.pinset.lock();
+        if pinset.head == head {
+            return Ok(vec![]);
+        }
+        let ops = match self.ops_since(head.as_ref(), pinset.head.as_ref())? {
+            Some(ops) => ops,
+            None => {
+                *pinset = Pinset::default();
+                self.ops_since(head.as_ref(), None)?.unwrap_or_default()
+            }
+        };
+        for op in &ops {
+            pinset.apply(*op);
+        }
+        pinset.head = head;
+        Ok(ops)
+    }
+
+    /// Adds a root to the pinset and announces the new head. Fails with `NotAWriter` if
+    /// the local node isn't a writer.
+    pub async fn pin(&self, cid: &Cid) -> Result<Cid> {
+        self.append(PinOp::Pin(*cid)).await
+    }
+
+    /// Removes a root from the pinset and announces the new head. Fails with `NotAWriter`
+    /// if the local node isn't a writer.
+    pub async fn unpin(&self, cid: &Cid) -> Result<Cid> {
+        self.append(PinOp::Unpin(*cid)).await
+    }
+
+    /// Follows the cluster, applying the heads announced by writers until the
+    /// subscription ends. When a peer announces an outdated head the local head is
+    /// announced, so that it catches up.
+    ///
+    /// Followers also subscribe to their inbox of the cluster topic. When a peer
+    /// subscribes to its inbox, which happens when it connects or reconnects after a
+    /// partition, the local head is sent to the inbox of that peer only. The peer does the
+    /// same, so both converge without waiting for the next write.
+    pub async fn follow(&self) -> Result<()> {
+        let topic = self.topic();
+        let inbox = inbox_topic(&topic, &self.ipfs.local_peer_id());
+        let announcements =
+            stream::select(self.ipfs.subscribe(&topic)?, self.ipfs.subscribe(&inbox)?)
+                .map(Input::Announcement);
+        let subscribed = self.ipfs.swarm_events().filter_map(move |event| {
+            future::ready(match event {
+                Event::Subscribed(peer, t) if t == inbox_topic(&topic, &peer) => {
+                    Some(Input::Subscribed(peer))
+                }
+                _ => None,
+            })
+        });
+        let mut inputs = stream::select(announcements, subscribed);
+        self.announce(true)?;
+        while let Some(input) = inputs.next().await {
+            let bytes = match input {
+                Input::Announcement(bytes) => bytes,
+                Input::Subscribed(peer) => {
+                    tracing::debug!("cluster {}: sending head to {}", self.name, peer);
+                    self.send_head(&inbox_topic(&self.topic(), &peer))?;
+                    continue;
+                }
+            };
+            let (head, provenance) = match decode_announcement(&bytes) {
+                Ok(announcement) => announcement,
+                Err(err) => {
+                    tracing::debug!("cluster {}: {}", self.name, err);
+                    continue;
+                }
+            };
+            match self.update(&head, &provenance).await {
+                Ok(true) => {}
+                // forked writers keep announcing their heads, so the replies are limited.
+                Ok(false) => self.announce(false)?,
+                Err(err) => tracing::warn!("cluster {}: {}", self.name, err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a signed head of the feed, fetching the missing operations and the dags of
+    /// the pinset. Returns `false` if the head doesn't extend the local feed.
+    pub async fn update(&self, head: &Cid, provenance: &Provenance) -> Result<bool> {
+        if !provenance.verify(head) {
+            return Err(InvalidProvenance(*head).into());
+        }
+        let writer = provenance.peer_id();
+        if !self.writers.contains(&writer) {
+            return Err(NotAWriter(writer).into());
+        }
+        let tmp = self.ipfs.create_temp_pin()?;
+        self.ipfs.temp_pin(&tmp, head)?;
+        self.ipfs.sync(head).await?;
+        {
+            let _guard = self.lock.lock();
+            let local = self.head()?;
+            if local.as_ref() == Some(head) {
+                return Ok(true);
+            }
+            if self.ops_since(Some(head), local.as_ref())?.is_none() {
+                return Ok(false);
+            }
+            self.ipfs.add_provenance(head, provenance)?;
+            self.ipfs.alias(self.topic(), Some(head))?;
+        }
+        self.reconcile().await?;
+        Ok(true)
+    }
+
+    /// Pins the dags of the roots pinned since the last reconciliation and unpins the
+    /// roots that were removed.
+    async fn reconcile(&self) -> Result<()> {
+        let ops = self.update_pinset()?;
+        let changed = ops
+            .iter()
+            .map(|op| match op {
+                PinOp::Pin(cid) | PinOp::Unpin(cid) => *cid,
+            })
+            .collect::<FnvHashSet<_>>();
+        for cid in changed {
+            let pinned = self.pinset.lock().pins.contains_key(&cid);
+            if !pinned {
+                self.ipfs.alias(self.pin_alias(&cid), None)?;
+                continue;
+            }
+            self.ipfs.alias(self.pin_alias(&cid), Some(&cid))?;
+            if let Err(err) = self.ipfs.sync(&cid).await {
+                tracing::warn!("cluster {}: failed to sync {}: {}", self.name, cid, err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn append(&self, op: PinOp) -> Result<Cid> {
+        let local = self.ipfs.local_peer_id();
+        if !self.writers.contains(&local) {
+            return Err(NotAWriter(local).into());
+        }
+        let head = {
+            let _guard = self.lock.lock();
+            let entry = Entry {
+                op,
+                prev: self.head()?,
+            };
+            let block = encode_block::<P>(DAG_CBOR, &encode_entry(&entry))?;
+            let tmp = self.ipfs.create_temp_pin()?;
+            self.ipfs.temp_pin(&tmp, block.cid())?;
+            let _ = self.ipfs.insert_signed(&block)?;
+            self.ipfs.alias(self.topic(), Some(block.cid()))?;
+            *block.cid()
+        };
+        self.announce(true)?;
+        self.reconcile().await?;
+        Ok(head)
+    }
+
+    /// Publishes the signed head of the local feed. Unless `force` is set, a head
+    /// announced within the last `ANNOUNCE_INTERVAL` isn't announced again.
+    fn announce(&self, force: bool) -> Result<()> {
+        let head = match self.head()? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+        {
+            let mut announced = self.announced.lock();
+            if let Some((prev, at)) = *announced {
+                if !force && prev == head && at.elapsed() < ANNOUNCE_INTERVAL {
+                    return Ok(());
+                }
+            }
+            *announced = Some((head, Instant::now()));
+        }
+        self.publish_head(&self.topic(), &head)
+    }
+
+    /// Publishes the signed head of the local feed to the inbox topic of a peer.
+    fn send_head(&self, inbox: &str) -> Result<()> {
+        match self.head()? {
+            Some(head) => self.publish_head(inbox, &head),
+            None => Ok(()),
+        }
+    }
+
+    fn publish_head(&self, topic: &str, head: &Cid) -> Result<()> {
+        let provenance = self
+            .ipfs
+            .block_provenance(head)?
+            .into_iter()
+            .find(|provenance| self.writers.contains(&provenance.peer_id()));
+        if let Some(provenance) = provenance {
+            let msg = encode_announcement(head, &provenance)?;
+            if let Err(err) = self.ipfs.publish(topic, msg) {
+                tracing::debug!(
+                    "cluster {}: failed to announce {}: {}",
+                    self.name,
+                    head,
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use fnv::FnvHashMap;
+use libipld::codec::{Codec, Decode, Encode, References};
+use libipld::error::UnsupportedCodec;
+use libipld::multihash::Code;
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, IpldCodec, Result};
+use parking_lot::RwLock;
+use std::convert::TryFrom;
+use std::io::{Read, Seek, Write};
+use std::sync::Arc;
+use thiserror::Error;
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: RwLock<FnvHashMap<u64, Arc<dyn CustomCodec>>> = Default::default();
+}
+
+/// Error returned when registering a codec that is built in or already registered.
+#[derive(Debug, Error)]
+#[error("codec 0x{0:x} is already registered")]
+pub struct CodecExists(pub u64);
+
+/// Error returned when decoding or encoding with a custom codec that only extracts links.
+#[derive(Debug, Error)]
+#[error("codec 0x{0:x} only supports link extraction")]
+pub struct LinksOnly(pub u64);
+
+/// An ipld codec registered at runtime. Only link extraction is required, which is
+/// enough for the blocks to be stored, synced and garbage collected.
+pub trait CustomCodec: Send + Sync + 'static {
+    /// Returns the multicodec code of the codec.
+    fn code(&self) -> u64;
+
+    /// Adds the links of the block `data` to `links`.
+    fn links(&self, data: &[u8], links: &mut Vec<Cid>) -> Result<()>;
+
+    /// Decodes the block `data`.
+    fn decode(&self, _data: &[u8]) -> Result<Ipld> {
+        Err(LinksOnly(self.code()).into())
+    }
+
+    /// Encodes `ipld` in to block data.
+    fn encode(&self, _ipld: &Ipld) -> Result<Vec<u8>> {
+        Err(LinksOnly(self.code()).into())
+    }
+}
+
+/// Registers a custom codec for blocks of stores using `RegistryParams`. Codecs can't
+/// be unregistered, since blocks already stored would lose their links.
+pub fn register_codec<C: CustomCodec>(codec: C) -> Result<()> {
+    let code = codec.code();
+    let mut registry = REGISTRY.write();
+    if IpldCodec::try_from(code).is_ok() || registry.contains_key(&code) {
+        return Err(CodecExists(code).into());
+    }
+    registry.insert(code, Arc::new(codec));
+    Ok(())
+}
+
+fn custom_codec(code: u64) -> Option<Arc<dyn CustomCodec>> {
+    REGISTRY.read().get(&code).cloned()
+}
+
+/// The built in codecs and the codecs registered with `register_codec`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RegistryCodec(u64);
+
+impl RegistryCodec {
+    fn builtin(self) -> Option<IpldCodec> {
+        IpldCodec::try_from(self.0).ok()
+    }
+
+    fn custom(self) -> Result<Arc<dyn CustomCodec>> {
+        custom_codec(self.0).ok_or_else(|| UnsupportedCodec(self.0).into())
+    }
+}
+
+impl TryFrom<u64> for RegistryCodec {
+    type Error = UnsupportedCodec;
+
+    fn try_from(code: u64) -> std::result::Result<Self, Self::Error> {
+        if IpldCodec::try_from(code).is_ok() || custom_codec(code).is_some() {
+            Ok(Self(code))
+        } else {
+            Err(UnsupportedCodec(code))
+        }
+    }
+}
+
+impl From<IpldCodec> for RegistryCodec {
+    fn from(codec: IpldCodec) -> Self {
+        Self(codec.into())
+    }
+}
+
+impl From<RegistryCodec> for u64 {
+    fn from(codec: RegistryCodec) -> Self {
+        codec.0
+    }
+}
+
+impl Codec for RegistryCodec {}
+
+impl Encode<RegistryCodec> for Ipld {
+    fn encode<W: Write>(&self, c: RegistryCodec, w: &mut W) -> Result<()> {
+        if let Some(codec) = c.builtin() {
+            return Encode::encode(self, codec, w);
+        }
+        w.write_all(&c.custom()?.encode(self)?)?;
+        Ok(())
+    }
+}
+
+impl Decode<RegistryCodec> for Ipld {
+    fn decode<R: Read + Seek>(c: RegistryCodec, r: &mut R) -> Result<Self> {
+        if let Some(codec) = c.builtin() {
+            return Decode::decode(codec, r);
+        }
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        c.custom()?.decode(&data)
+    }
+}
+
+impl References<RegistryCodec> for Ipld {
+    fn references<R: Read + Seek, E: Extend<Cid>>(
+        c: RegistryCodec,
+        r: &mut R,
+        set: &mut E,
+    ) -> Result<()> {
+        if let Some(codec) = c.builtin() {
+            return <Ipld as References<IpldCodec>>::references(codec, r, set);
+        }
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        let mut links = vec![];
+        c.custom()?.links(&data, &mut links)?;
+        set.extend(links);
+        Ok(())
+    }
+}
+
+/// Store params supporting the codecs registered at runtime with `register_codec` in
+/// addition to the built in codecs, so that applications with custom codecs don't need
+/// to define their own `StoreParams`.
+#[derive(Clone, Debug, Default)]
+pub struct RegistryParams;
+
+impl StoreParams for RegistryParams {
+    const MAX_BLOCK_SIZE: usize = 1_048_576;
+    type Codecs = RegistryCodec;
+    type Hashes = Code;
+}
@@ -0,0 +1,476 @@
+use crate::{Ipfs, DAG_CBOR, RAW, SHA2_256};
+use libipld::codec::{Decode, Encode, References};
+use libipld::error::UnsupportedMultihash;
+use libipld::multihash::MultihashDigest;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use thiserror::Error;
+
+/// Number of entries a hamt bucket holds before it is split into a node.
+const BUCKET_SIZE: usize = 3;
+
+/// Random values the gear hash of the content defined chunker adds per byte.
+const GEAR: [u64; 256] = gear_table();
+
+/// Fills the gear table using splitmix64, so that it doesn't need to be spelled out.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// How byte streams are split into chunks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Chunker {
+    /// Chunks of exactly `chunk_size` bytes. Inserting a byte changes all following
+    /// chunks.
+    Fixed,
+    /// Content defined chunks using FastCDC, averaging `chunk_size` bytes and ranging
+    /// from a quarter to four times `chunk_size`, which needs to fit in a block. Chunk
+    /// boundaries depend on the bytes around them, so an edited stream shares all but the
+    /// chunks near the edit with the original.
+    FastCdc,
+}
+
+/// Configuration of the dag builders. Building the same leaves or entries with the same
+/// configuration always results in the same root.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DagBuilderConfig {
+    /// Maximum number of children of a node. Hamts need a power of two between 8 and 256.
+    pub fanout: usize,
+    /// Size of the raw blocks byte streams are split into.
+    pub chunk_size: usize,
+    /// How byte streams are split into raw blocks.
+    pub chunker: Chunker,
+    /// Codec of the nodes.
+    pub codec: u64,
+    /// Multihash code of the blocks. Hamts hash their keys with it as well.
+    pub hash: u64,
+}
+
+impl Default for DagBuilderConfig {
+    fn default() -> Self {
+        Self {
+            fanout: 256,
+            chunk_size: 256 * 1024,
+            chunker: Chunker::Fixed,
+            codec: DAG_CBOR,
+            hash: SHA2_256,
+        }
+    }
+}
+
+impl DagBuilderConfig {
+    fn check(&self, hamt: bool) -> Result<()> {
+        if self.fanout < 2 {
+            return Err(InvalidDagBuilderConfig("the fanout needs to be at least 2").into());
+        }
+        if self.chunk_size == 0 {
+            return Err(InvalidDagBuilderConfig("the chunk size can't be 0").into());
+        }
+        if self.chunker == Chunker::FastCdc && self.chunk_size < 64 {
+            return Err(InvalidDagBuilderConfig(
+                "content defined chunks need to average at least 64 bytes",
+            )
+            .into());
+        }
+        if hamt && (!self.fanout.is_power_of_two() || self.fanout < 8 || self.fanout > 256) {
+            return Err(InvalidDagBuilderConfig(
+                "the hamt fanout needs to be a power of two between 8 and 256",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn hasher<P: StoreParams>(&self) -> Result<P::Hashes> {
+        Ok(P::Hashes::try_from(self.hash).map_err(|_| UnsupportedMultihash(self.hash))?)
+    }
+
+    fn encode<P: StoreParams>(&self, codec: u64, ipld: &Ipld) -> Result<Block<P>>
+    where
+        Ipld: Encode<P::Codecs>,
+    {
+        Block::encode(P::Codecs::try_from(codec)?, self.hasher::<P>()?, ipld)
+    }
+
+    /// Returns the maximum size of a chunk.
+    fn max_chunk_size(&self) -> usize {
+        match self.chunker {
+            Chunker::Fixed => self.chunk_size,
+            Chunker::FastCdc => self.chunk_size.saturating_mul(4),
+        }
+    }
+
+    /// Returns the length of the first chunk of `data`. Unless `data` is the end of the
+    /// stream, it needs to contain at least `max_chunk_size` bytes.
+    fn cut(&self, data: &[u8]) -> usize {
+        match self.chunker {
+            Chunker::Fixed => std::cmp::min(data.len(), self.chunk_size),
+            Chunker::FastCdc => fastcdc_cut(data, self.chunk_size),
+        }
+    }
+}
+
+/// Finds a chunk boundary with normalized chunking: below the average size a harder mask
+/// with one more bit is used, above it an easier one with one bit less, which narrows
+/// the distribution of the chunk sizes around the average.
+fn fastcdc_cut(data: &[u8], avg: usize) -> usize {
+    let min = avg / 4;
+    let max = avg.saturating_mul(4);
+    if data.len() <= min {
+        return data.len();
+    }
+    let end = std::cmp::min(data.len(), max);
+    let normal = std::cmp::min(avg, end);
+    let bits = 63 - (avg as u64).leading_zeros();
+    // the gear hash shifts older bytes out to the left, so the high bits depend on the
+    // last 64 bytes.
+    let mask_small = !0u64 << (64 - (bits + 1));
+    let mask_large = !0u64 << (64 - (bits - 1));
+    let mut hash = 0u64;
+    for (i, byte) in data.iter().enumerate().take(end).skip(min) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { mask_small } else { mask_large };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Error returned when a `DagBuilderConfig` can't be used.
+#[derive(Debug, Error)]
+#[error("invalid dag builder config: {0}")]
+pub struct InvalidDagBuilderConfig(pub &'static str);
+
+/// Error returned when a hamt node is malformed.
+#[derive(Debug, Error)]
+#[error("invalid hamt node {0}")]
+pub struct InvalidHamt(pub Cid);
+
+/// Builds a balanced tree from a stream of leaves.
+///
+/// Every node is a map with its `height`, the `count` of leaves below it and its
+/// `children`: the leaves for nodes of height 0 and links to the nodes one level below
+/// otherwise. All nodes but the last of each level are full, so the node holding the
+/// `n`th leaf can be found using the fanout.
+///
+/// Completed nodes are returned as soon as possible, so large trees can be built without
+/// holding them in memory. Inserting the returned blocks is up to the caller.
+pub struct TreeBuilder<P: StoreParams> {
+    _marker: PhantomData<P>,
+    config: DagBuilderConfig,
+    /// Children and leaf count of the incomplete node of each level.
+    levels: Vec<(Vec<Ipld>, u64)>,
+    chunk: Vec<u8>,
+}
+
+impl<P: StoreParams> TreeBuilder<P>
+where
+    Ipld: Encode<P::Codecs>,
+{
+    /// Creates a new `TreeBuilder`.
+    pub fn new(config: DagBuilderConfig) -> Result<Self> {
+        config.check(false)?;
+        if config.max_chunk_size() > P::MAX_BLOCK_SIZE {
+            return Err(InvalidDagBuilderConfig("chunks can exceed the maximum block size").into());
+        }
+        Ok(Self {
+            _marker: PhantomData,
+            config,
+            levels: vec![Default::default()],
+            chunk: vec![],
+        })
+    }
+
+    /// Appends a leaf, returning the blocks of the nodes it completed.
+    pub fn push(&mut self, leaf: Ipld) -> Result<Vec<Block<P>>> {
+        let mut blocks = vec![];
+        self.push_child(0, leaf, 1, &mut blocks)?;
+        Ok(blocks)
+    }
+
+    /// Splits `bytes` into raw blocks using the `chunker` and appends links to them as
+    /// leaves. The bytes of an incomplete chunk are kept until more bytes are pushed or
+    /// the tree is finished.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<Vec<Block<P>>> {
+        let mut blocks = vec![];
+        self.chunk.extend_from_slice(bytes);
+        while self.chunk.len() >= self.config.max_chunk_size() {
+            self.cut_chunk(&mut blocks)?;
+        }
+        Ok(blocks)
+    }
+
+    fn cut_chunk(&mut self, blocks: &mut Vec<Block<P>>) -> Result<()> {
+        let rest = self.chunk.split_off(self.config.cut(&self.chunk));
+        let chunk = std::mem::replace(&mut self.chunk, rest);
+        self.push_chunk(chunk, blocks)
+    }
+
+    /// Completes the tree, returning its root and the remaining blocks. A tree without
+    /// leaves consists of a single empty node.
+    pub fn finish(mut self) -> Result<(Cid, Vec<Block<P>>)> {
+        let mut blocks = vec![];
+        while !self.chunk.is_empty() {
+            self.cut_chunk(&mut blocks)?;
+        }
+        let mut height = 0;
+        loop {
+            let top = height + 1 == self.levels.len();
+            let (children, count) = std::mem::take(&mut self.levels[height]);
+            if top && height > 0 {
+                if let [Ipld::Link(root)] = children.as_slice() {
+                    return Ok((*root, blocks));
+                }
+            }
+            if top || !children.is_empty() {
+                let block = self.node(height, children, count)?;
+                let cid = *block.cid();
+                blocks.push(block);
+                if top {
+                    return Ok((cid, blocks));
+                }
+                self.push_child(height + 1, Ipld::Link(cid), count, &mut blocks)?;
+            }
+            height += 1;
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: Vec<u8>, blocks: &mut Vec<Block<P>>) -> Result<()> {
+        let block = self.config.encode::<P>(RAW, &Ipld::Bytes(chunk))?;
+        let cid = *block.cid();
+        blocks.push(block);
+        self.push_child(0, Ipld::Link(cid), 1, blocks)
+    }
+
+    fn push_child(
+        &mut self,
+        height: usize,
+        child: Ipld,
+        count: u64,
+        blocks: &mut Vec<Block<P>>,
+    ) -> Result<()> {
+        if self.levels.len() == height {
+            self.levels.push(Default::default());
+        }
+        let level = &mut self.levels[height];
+        level.0.push(child);
+        level.1 += count;
+        if level.0.len() == self.config.fanout {
+            let (children, count) = std::mem::take(level);
+            let block = self.node(height, children, count)?;
+            let cid = *block.cid();
+            blocks.push(block);
+            self.push_child(height + 1, Ipld::Link(cid), count, blocks)?;
+        }
+        Ok(())
+    }
+
+    fn node(&self, height: usize, children: Vec<Ipld>, count: u64) -> Result<Block<P>> {
+        let mut node = BTreeMap::new();
+        node.insert("height".to_string(), Ipld::Integer(height as i128));
+        node.insert("count".to_string(), Ipld::Integer(count as i128));
+        node.insert("children".to_string(), Ipld::List(children));
+        self.config
+            .encode(self.config.codec, &Ipld::StringMap(node))
+    }
+}
+
+struct HamtEntry {
+    digest: Vec<u8>,
+    key: Vec<u8>,
+    value: Ipld,
+}
+
+/// Returns the slot of a digest at `depth` or `None` if the digest is exhausted. The bits
+/// of the digest are consumed starting with the most significant bit of its first byte.
+fn slot_index(digest: &[u8], depth: usize, bits: usize) -> Option<usize> {
+    let start = depth * bits;
+    if start + bits > digest.len() * 8 {
+        return None;
+    }
+    let mut index = 0;
+    for bit in start..start + bits {
+        let set = digest[bit / 8] & (0x80 >> (bit % 8)) != 0;
+        index = index << 1 | set as usize;
+    }
+    Some(index)
+}
+
+/// Bitmaps are big endian bitfields: slot 0 is the least significant bit of the last
+/// byte.
+fn set_bit(bitmap: &mut [u8], index: usize) {
+    let byte = bitmap.len() - 1 - index / 8;
+    bitmap[byte] |= 1 << (index % 8);
+}
+
+fn is_set(bitmap: &[u8], index: usize) -> bool {
+    bitmap
+        .len()
+        .checked_sub(1 + index / 8)
+        .map(|byte| bitmap[byte] & (1 << (index % 8)) != 0)
+        .unwrap_or_default()
+}
+
+/// Encodes the node holding `entries` at `depth`, handing the blocks of its child nodes
+/// to `emit`. The entries need to be sorted by digest, so that the entries of a slot are
+/// adjacent.
+fn hamt_node<P: StoreParams>(
+    config: &DagBuilderConfig,
+    entries: Vec<HamtEntry>,
+    depth: usize,
+    emit: &mut impl FnMut(Block<P>) -> Result<()>,
+) -> Result<Ipld>
+where
+    Ipld: Encode<P::Codecs>,
+{
+    let bits = config.fanout.trailing_zeros() as usize;
+    let mut bitmap = vec![0u8; config.fanout / 8];
+    let mut data = vec![];
+    let mut entries = entries.into_iter().peekable();
+    while let Some(entry) = entries.next() {
+        let index = slot_index(&entry.digest, depth, bits).unwrap_or_default();
+        let mut slot = vec![entry];
+        while let Some(next) = entries.peek() {
+            if slot_index(&next.digest, depth, bits).unwrap_or_default() != index {
+                break;
+            }
+            slot.extend(entries.next());
+        }
+        set_bit(&mut bitmap, index);
+        let exhausted = slot_index(&slot[0].digest, depth + 1, bits).is_none();
+        if slot.len() <= BUCKET_SIZE || exhausted {
+            slot.sort_by(|a, b| a.key.cmp(&b.key));
+            let bucket = slot
+                .into_iter()
+                .map(|entry| Ipld::List(vec![Ipld::Bytes(entry.key), entry.value]))
+                .collect();
+            data.push(Ipld::List(bucket));
+        } else {
+            let node = hamt_node(config, slot, depth + 1, emit)?;
+            let block = config.encode::<P>(config.codec, &node)?;
+            data.push(Ipld::Link(*block.cid()));
+            emit(block)?;
+        }
+    }
+    Ok(Ipld::List(vec![Ipld::Bytes(bitmap), Ipld::List(data)]))
+}
+
+/// Builds a hamt mapping keys to values following the IPLD HashMap spec, returning its
+/// root. When a key occurs more than once the last value is kept.
+///
+/// The root is a map with the multihash code `hashAlg` the keys are hashed with, the
+/// `bucketSize` and the root node `hamt`. Each level consumes `log2(fanout)` bits of the
+/// digest. Nodes are `[bitmap, data]` tuples, where `data` holds for every occupied slot
+/// either a link to a node one level below or a bucket of up to 3 `[key, value]` pairs
+/// ordered by key.
+///
+/// The entries are sorted by digest, so that every node is complete when it is encoded.
+/// Blocks are handed to `emit` as soon as they are encoded instead of being collected,
+/// the root block last.
+pub fn build_hamt<P: StoreParams>(
+    config: &DagBuilderConfig,
+    entries: impl IntoIterator<Item = (Vec<u8>, Ipld)>,
+    mut emit: impl FnMut(Block<P>) -> Result<()>,
+) -> Result<Cid>
+where
+    Ipld: Encode<P::Codecs>,
+{
+    config.check(true)?;
+    let hasher = config.hasher::<P>()?;
+    let mut digests = BTreeMap::new();
+    for (key, value) in entries {
+        let digest = hasher.digest(&key).digest().to_vec();
+        digests.insert((digest, key), value);
+    }
+    let entries = digests
+        .into_iter()
+        .map(|((digest, key), value)| HamtEntry { digest, key, value })
+        .collect();
+    let node = hamt_node(config, entries, 0, &mut emit)?;
+    let mut root = BTreeMap::new();
+    root.insert("hashAlg".to_string(), Ipld::Integer(config.hash as i128));
+    root.insert("bucketSize".to_string(), Ipld::Integer(BUCKET_SIZE as i128));
+    root.insert("hamt".to_string(), node);
+    let block = config.encode::<P>(config.codec, &Ipld::StringMap(root))?;
+    let cid = *block.cid();
+    emit(block)?;
+    Ok(cid)
+}
+
+/// Looks up `key` in a hamt following the IPLD HashMap spec, like the ones built by
+/// `build_hamt`. The keys are hashed with the `hashAlg` of the root and the fanout is
+/// derived from the size of the bitmaps. The nodes on the path to the key need to be in
+/// the block store.
+pub fn hamt_get<P: StoreParams>(ipfs: &Ipfs<P>, root: &Cid, key: &[u8]) -> Result<Option<Ipld>>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    let (hash, mut node) = match ipfs.get(root)?.ipld()? {
+        Ipld::StringMap(mut map) => match (map.remove("hashAlg"), map.remove("hamt")) {
+            (Some(Ipld::Integer(hash)), Some(node)) => (hash, node),
+            _ => return Err(InvalidHamt(*root).into()),
+        },
+        _ => return Err(InvalidHamt(*root).into()),
+    };
+    let hasher = u64::try_from(hash)
+        .ok()
+        .and_then(|hash| P::Hashes::try_from(hash).ok())
+        .ok_or(InvalidHamt(*root))?;
+    let digest = hasher.digest(key).digest().to_vec();
+    let mut cid = *root;
+    let mut depth = 0;
+    loop {
+        let (bitmap, data) = match node {
+            Ipld::List(mut tuple) if tuple.len() == 2 => match (tuple.remove(0), tuple.remove(0)) {
+                (Ipld::Bytes(bitmap), Ipld::List(data)) => (bitmap, data),
+                _ => return Err(InvalidHamt(cid).into()),
+            },
+            _ => return Err(InvalidHamt(cid).into()),
+        };
+        let fanout = bitmap.len() * 8;
+        if !fanout.is_power_of_two() || fanout < 8 {
+            return Err(InvalidHamt(cid).into());
+        }
+        let bits = fanout.trailing_zeros() as usize;
+        let index = slot_index(&digest, depth, bits).ok_or(InvalidHamt(cid))?;
+        if !is_set(&bitmap, index) {
+            return Ok(None);
+        }
+        let position = (0..index).filter(|i| is_set(&bitmap, *i)).count();
+        match data.into_iter().nth(position) {
+            Some(Ipld::Link(child)) => {
+                cid = child;
+                node = ipfs.get(&cid)?.ipld()?;
+            }
+            Some(Ipld::List(bucket)) => {
+                for entry in bucket {
+                    if let Ipld::List(mut pair) = entry {
+                        if pair.len() == 2
+                            && matches!(&pair[0], Ipld::Bytes(k) if k.as_slice() == key)
+                        {
+                            return Ok(Some(pair.remove(1)));
+                        }
+                    }
+                }
+                return Ok(None);
+            }
+            _ => return Err(InvalidHamt(cid).into()),
+        }
+        depth += 1;
+    }
+}
@@ -0,0 +1,72 @@
+use fnv::FnvHashMap;
+use libipld::{Cid, Ipld};
+use std::collections::BTreeMap;
+
+/// Cache of decoded blocks and their sizes that evicts the least recently used block
+/// once it holds more than `capacity` blocks. A capacity of 0 disables the cache.
+#[derive(Default)]
+pub(crate) struct DecodedCache {
+    capacity: usize,
+    tick: u64,
+    entries: FnvHashMap<Cid, (u64, Ipld, usize)>,
+    order: BTreeMap<u64, Cid>,
+}
+
+impl DecodedCache {
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn get(&mut self, cid: &Cid) -> Option<(Ipld, usize)> {
+        let tick = self.next_tick();
+        let (used, ipld, len) = self.entries.get_mut(cid)?;
+        self.order.remove(used);
+        self.order.insert(tick, *cid);
+        *used = tick;
+        Some((ipld.clone(), *len))
+    }
+
+    pub fn insert(&mut self, cid: Cid, ipld: Ipld, len: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let tick = self.next_tick();
+        if let Some((used, _, _)) = self.entries.insert(cid, (tick, ipld, len)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(tick, cid);
+        self.evict();
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub fn remove(&mut self, cid: &Cid) {
+        if let Some((used, _, _)) = self.entries.remove(cid) {
+            self.order.remove(&used);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let (used, cid) = match self.order.iter().next() {
+                Some((used, cid)) => (*used, *cid),
+                None => break,
+            };
+            self.order.remove(&used);
+            self.entries.remove(&cid);
+        }
+    }
+}
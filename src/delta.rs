@@ -0,0 +1,313 @@
+use crate::{traversal, Ipfs};
+use fnv::{FnvHashSet, FnvHasher};
+use futures::future::{self, Either, Future};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::stream::{Stream, StreamExt};
+use ipfs_embed_net::{AppStream, LimitExceeded, PeerId, TraversalOrder};
+use ipfs_embed_rt::Timer;
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Application protocol exchanging the blocks of a dag the requester is missing.
+pub const DELTA_SYNC_PROTOCOL: &str = "/ipfs-embed/delta/1.0.0";
+
+/// Number of filter bits per block, giving a false positive rate of about 1%.
+const BITS_PER_BLOCK: usize = 10;
+/// Number of bits a cid maps to.
+const HASHES: u64 = 7;
+/// Maximum size of the filter. Larger dags share the bits, which only increases the
+/// false positive rate.
+const MAX_FILTER_SIZE: usize = 1024 * 1024;
+const MAX_REQUEST_SIZE: usize = MAX_FILTER_SIZE + 4096;
+/// Maximum number of blocks sent in response to a single request. Blocks that aren't
+/// sent are fetched by the regular sync.
+const MAX_RESPONSE_BLOCKS: usize = 100_000;
+/// Maximum number of bytes sent in response to a single request.
+const MAX_RESPONSE_SIZE: u64 = 256 * 1024 * 1024;
+/// Maximum number of blocks walked to answer a single request, including the blocks the
+/// requester already has.
+const MAX_WALK_BLOCKS: usize = 1_000_000;
+/// Maximum number of received blocks that weren't expected before the requester stops
+/// reading. They are fetched by the regular sync.
+const MAX_SKIPPED_BLOCKS: usize = 1024;
+/// Time after which a request or a response frame that wasn't transferred fails.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Error returned when a delta sync message is malformed.
+#[derive(Debug, Error)]
+#[error("invalid delta sync message")]
+pub struct InvalidDeltaMessage;
+
+/// Error returned when a delta sync frame wasn't transferred in time.
+#[derive(Debug, Error)]
+#[error("delta sync timed out after {0:?}")]
+pub struct DeltaTimeout(pub Duration);
+
+/// Error returned when a peer isn't authenticated or requests a dag that isn't shared.
+#[derive(Debug, Error)]
+#[error("peer {0} isn't authorized to delta sync the dag")]
+pub struct DeltaNotAuthorized(pub PeerId);
+
+/// Bloom filter over the cids of the blocks of a dag the requester already has. The
+/// cids are hashed from their binary representation, so the filter is the same on
+/// every node.
+pub(crate) struct HaveSet {
+    bits: Vec<u8>,
+}
+
+impl HaveSet {
+    fn new(blocks: usize) -> Self {
+        let len = blocks
+            .saturating_mul(BITS_PER_BLOCK)
+            .max(64)
+            .min(MAX_FILTER_SIZE * 8);
+        Self {
+            bits: vec![0; (len + 7) / 8],
+        }
+    }
+
+    fn bits(&self, cid: &Cid) -> impl Iterator<Item = usize> {
+        let mut hasher = FnvHasher::default();
+        hasher.write(&cid.to_bytes());
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let len = self.bits.len() as u64 * 8;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i * h2) % len) as usize)
+    }
+
+    fn insert(&mut self, cid: &Cid) {
+        for bit in self.bits(cid).collect::<Vec<_>>() {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns `false` if the requester definitely doesn't have the block.
+    fn contains(&self, cid: &Cid) -> bool {
+        self.bits(cid)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Returns a filter of the blocks of the dag rooted at `root` in the block store and the
+/// missing blocks whose parents are in the block store. The blocks are looked up in the
+/// references of the block store, without decoding the dag.
+pub(crate) fn have_set<P: StoreParams>(
+    ipfs: &Ipfs<P>,
+    root: &Cid,
+) -> Result<(HaveSet, FnvHashSet<Cid>)>
+where
+    Ipld: References<P::Codecs>,
+{
+    let have = ipfs.storage.stored_blocks(root)?;
+    let missing = ipfs.storage.missing_blocks(root)?.into_iter().collect();
+    let mut filter = HaveSet::new(have.len());
+    for cid in &have {
+        filter.insert(cid);
+    }
+    Ok((filter, missing))
+}
+
+/// Fails with `DeltaTimeout` if `fut` doesn't complete within `FRAME_TIMEOUT`.
+async fn timeout<T>(fut: impl Future<Output = Result<T>>) -> Result<T> {
+    futures::pin_mut!(fut);
+    match future::select(fut, Timer::after(FRAME_TIMEOUT)).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(DeltaTimeout(FRAME_TIMEOUT).into()),
+    }
+}
+
+async fn read_frame(stream: &mut AppStream, limit: usize) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > limit {
+        return Err(InvalidDeltaMessage.into());
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+async fn close(stream: &mut AppStream) -> Result<()> {
+    stream.close().await?;
+    Ok(())
+}
+
+async fn write_frame(stream: &mut AppStream, msg: &Ipld) -> Result<()> {
+    let buf = DagCborCodec.encode(msg)?;
+    stream.write_all(&(buf.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+fn encode_request(root: &Cid, filter: &HaveSet) -> Ipld {
+    let mut map = BTreeMap::new();
+    map.insert("root".to_string(), Ipld::Link(*root));
+    map.insert("filter".to_string(), Ipld::Bytes(filter.bits.clone()));
+    Ipld::StringMap(map)
+}
+
+fn decode_request(ipld: Ipld) -> Result<(Cid, HaveSet)> {
+    match (ipld.get("root"), ipld.get("filter")) {
+        (Ok(Ipld::Link(root)), Ok(Ipld::Bytes(bits)))
+            if !bits.is_empty() && bits.len() <= MAX_FILTER_SIZE =>
+        {
+            Ok((*root, HaveSet { bits: bits.clone() }))
+        }
+        _ => Err(InvalidDeltaMessage.into()),
+    }
+}
+
+fn decode_block<P: StoreParams>(ipld: Ipld) -> Result<Block<P>> {
+    match ipld {
+        Ipld::List(entry) => match entry.as_slice() {
+            [Ipld::Link(cid), Ipld::Bytes(data)] => Block::new(*cid, data.clone()),
+            _ => Err(InvalidDeltaMessage.into()),
+        },
+        _ => Err(InvalidDeltaMessage.into()),
+    }
+}
+
+/// Returns the blocks of the dag rooted at `root` that aren't in `filter` and that the
+/// local node shares with peers. The walk stops after `MAX_WALK_BLOCKS` blocks or when
+/// the response is full.
+fn delta<P: StoreParams>(ipfs: &Ipfs<P>, root: &Cid, filter: &HaveSet) -> Result<Vec<Cid>>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut blocks = vec![];
+    let mut size = 0;
+    let mut walked = 0;
+    let res = traversal::walk(
+        &ipfs.storage,
+        root,
+        TraversalOrder::BreadthFirst,
+        |cid, data| {
+            walked += 1;
+            if walked > MAX_WALK_BLOCKS {
+                return Err(LimitExceeded::Blocks(MAX_WALK_BLOCKS).into());
+            }
+            let data = match data {
+                Some(data) => data,
+                None => return Ok(()),
+            };
+            if blocks.len() >= MAX_RESPONSE_BLOCKS {
+                return Err(LimitExceeded::Blocks(MAX_RESPONSE_BLOCKS).into());
+            }
+            if size + data.len() as u64 > MAX_RESPONSE_SIZE
+                || filter.contains(cid)
+                || ipfs.storage.is_denied(cid)
+            {
+                return Ok(());
+            }
+            size += data.len() as u64;
+            blocks.push(*cid);
+            Ok(())
+        },
+    );
+    match res {
+        Err(err) if err.downcast_ref::<LimitExceeded>().is_none() => Err(err),
+        _ => Ok(blocks),
+    }
+}
+
+/// Answers a single delta sync request of `peer`. Peers need to be authenticated, and
+/// the dag needs to be reachable from an alias that isn't private to a tenant, which
+/// makes all of its blocks public.
+async fn answer<P: StoreParams>(ipfs: &Ipfs<P>, peer: PeerId, mut stream: AppStream) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+{
+    if !ipfs.network.is_authenticated(&peer) {
+        return Err(DeltaNotAuthorized(peer).into());
+    }
+    let req = match timeout(read_frame(&mut stream, MAX_REQUEST_SIZE)).await? {
+        Some(req) => DagCborCodec.decode(&req)?,
+        None => return Err(InvalidDeltaMessage.into()),
+    };
+    let (root, filter) = decode_request(req)?;
+    if ipfs.storage.is_denied(&root) || ipfs.tenants.lock().is_private(&ipfs.storage, &root)? {
+        return Err(DeltaNotAuthorized(peer).into());
+    }
+    let ipfs2 = ipfs.clone();
+    let blocks = ipfs_embed_rt::spawn_blocking(move || delta(&ipfs2, &root, &filter)).await?;
+    for cid in blocks {
+        // the block may have been collected since the walk.
+        if let Some(data) = ipfs.storage.get(&cid)? {
+            let msg = Ipld::List(vec![Ipld::Link(cid), Ipld::Bytes(data)]);
+            timeout(write_frame(&mut stream, &msg)).await?;
+        }
+    }
+    timeout(close(&mut stream)).await?;
+    Ok(())
+}
+
+/// Answers the delta sync requests received on `streams`.
+pub(crate) async fn serve<P: StoreParams>(
+    ipfs: Ipfs<P>,
+    streams: impl Stream<Item = (PeerId, AppStream)>,
+) where
+    Ipld: References<P::Codecs>,
+{
+    futures::pin_mut!(streams);
+    while let Some((peer, stream)) = streams.next().await {
+        let ipfs = ipfs.clone();
+        ipfs_embed_rt::spawn(async move {
+            if let Err(err) = answer(&ipfs, peer, stream).await {
+                tracing::debug!("failed to answer delta sync of {}: {}", peer, err);
+            }
+        })
+        .detach();
+    }
+}
+
+/// Sends the filter of the blocks under `root` to `peer` and inserts the blocks received
+/// in return. Only blocks linked from a block in the block store or from a previously
+/// received block are accepted, and reading stops after `MAX_SKIPPED_BLOCKS` other
+/// blocks. Returns the number of inserted blocks.
+pub(crate) async fn request<P: StoreParams>(
+    ipfs: &Ipfs<P>,
+    peer: PeerId,
+    mut stream: AppStream,
+    root: &Cid,
+    filter: HaveSet,
+    mut expected: FnvHashSet<Cid>,
+) -> Result<usize>
+where
+    Ipld: References<P::Codecs>,
+{
+    let _guard = ipfs.storage.begin_sync();
+    timeout(write_frame(&mut stream, &encode_request(root, &filter))).await?;
+    timeout(close(&mut stream)).await?;
+    let mut inserted = 0;
+    let mut skipped = 0;
+    while let Some(frame) = timeout(read_frame(&mut stream, P::MAX_BLOCK_SIZE + 1024)).await? {
+        let block = decode_block::<P>(DagCborCodec.decode(&frame)?)?;
+        if !expected.remove(block.cid()) {
+            // the parent is missing, because the filter gave a false positive.
+            skipped += 1;
+            if skipped > MAX_SKIPPED_BLOCKS {
+                tracing::debug!("delta sync of {}: too many unexpected blocks", peer);
+                break;
+            }
+            continue;
+        }
+        ipfs.validators.validate(Some(peer), &block)?;
+        let mut links = vec![];
+        block.references(&mut links)?;
+        ipfs.storage.insert(&block)?;
+        expected.extend(links);
+        inserted += 1;
+    }
+    Ok(inserted)
+}
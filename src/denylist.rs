@@ -0,0 +1,45 @@
+use libipld::{Cid, Result};
+use std::convert::TryFrom;
+use std::io::BufRead;
+use thiserror::Error;
+
+/// An update of the deny list.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DenylistEntry {
+    /// Adds a block to the deny list.
+    Deny(Cid),
+    /// Removes a block from the deny list.
+    Allow(Cid),
+}
+
+/// Error returned when a deny list line can't be parsed.
+#[derive(Debug, Error)]
+#[error("invalid deny list entry on line {0}: {1}")]
+pub struct InvalidDenylistEntry(pub usize, pub String);
+
+/// Parses a deny list.
+///
+/// Every line contains a cid that is denied, or a cid prefixed with `!` that is allowed
+/// again. Empty lines and lines starting with `#` are ignored.
+pub fn parse_denylist(reader: impl BufRead) -> Result<Vec<DenylistEntry>> {
+    let mut entries = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (allow, cid) = if let Some(cid) = line.strip_prefix('!') {
+            (true, cid.trim())
+        } else {
+            (false, line)
+        };
+        let cid = Cid::try_from(cid).map_err(|err| InvalidDenylistEntry(i + 1, err.to_string()))?;
+        entries.push(if allow {
+            DenylistEntry::Allow(cid)
+        } else {
+            DenylistEntry::Deny(cid)
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,232 @@
+use async_trait::async_trait;
+use futures::future::{self, Either};
+use futures::stream::StreamExt;
+use ipfs_embed_net::{Event, Multiaddr, NetworkService, PeerId};
+use ipfs_embed_rt::Timer;
+use libipld::store::StoreParams;
+use libipld::Result;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+/// Source of a reference time used to detect clock skew.
+#[async_trait]
+pub trait TimeSource: Send + Sync {
+    /// Returns the current reference time.
+    async fn now(&self) -> Result<SystemTime>;
+}
+
+/// Configures the checks performed by `Ipfs::diagnose`.
+#[derive(Clone)]
+pub struct DiagnoseConfig {
+    /// Boot nodes that are dialed and used to bootstrap the dht.
+    pub boot_nodes: Vec<(PeerId, Multiaddr)>,
+    /// Peer that a bitswap request is sent to. The peer is dialed if it isn't connected.
+    pub echo_peer: Option<(PeerId, Option<Multiaddr>)>,
+    /// Reference time used to measure the clock skew.
+    pub time_source: Option<Arc<dyn TimeSource>>,
+    /// Timeout of every network check.
+    pub timeout: Duration,
+}
+
+impl Default for DiagnoseConfig {
+    fn default() -> Self {
+        Self {
+            boot_nodes: vec![],
+            echo_peer: None,
+            time_source: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl std::fmt::Debug for DiagnoseConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DiagnoseConfig")
+            .field("boot_nodes", &self.boot_nodes)
+            .field("echo_peer", &self.echo_peer)
+            .field("time_source", &self.time_source.is_some())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// Result of a single check. Contains the time the check took or the reason it failed.
+pub type Check = std::result::Result<Duration, String>;
+
+/// Reachability of the node inferred from its external addresses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NatStatus {
+    /// An external address is one of the listen addresses.
+    Public,
+    /// None of the external addresses are listen addresses.
+    BehindNat,
+    /// No external addresses were observed yet.
+    Unknown,
+}
+
+/// Report returned by `Ipfs::diagnose`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiagnoseReport {
+    /// Bound listen addresses.
+    pub listeners: Vec<Multiaddr>,
+    /// External addresses.
+    pub external_addresses: Vec<Multiaddr>,
+    /// Nat status.
+    pub nat: NatStatus,
+    /// Time it took to connect to every boot node.
+    pub boot_nodes: Vec<(PeerId, Check)>,
+    /// Result of bootstrapping the dht. `None` if no boot nodes were configured.
+    pub dht_bootstrap: Option<Check>,
+    /// Round trip of a bitswap request. `None` if no echo peer was configured.
+    pub bitswap_echo: Option<(PeerId, Check)>,
+    /// Clock skew in milliseconds relative to the time source. Positive if the local
+    /// clock is ahead. `None` if no time source was configured.
+    pub clock_skew: Option<std::result::Result<i64, String>>,
+}
+
+impl DiagnoseReport {
+    /// Returns `true` if there are bound listeners and none of the checks failed.
+    pub fn is_healthy(&self) -> bool {
+        !self.listeners.is_empty()
+            && self.boot_nodes.iter().all(|(_, check)| check.is_ok())
+            && self
+                .dht_bootstrap
+                .as_ref()
+                .map(|check| check.is_ok())
+                .unwrap_or(true)
+            && self
+                .bitswap_echo
+                .as_ref()
+                .map(|(_, check)| check.is_ok())
+                .unwrap_or(true)
+            && self
+                .clock_skew
+                .as_ref()
+                .map(|skew| skew.is_ok())
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("timed out")]
+struct TimedOut;
+
+#[derive(Debug, Error)]
+#[error("not connected")]
+struct NotConnected;
+
+pub(crate) async fn timeout<T, F: Future<Output = Result<T>>>(
+    duration: Duration,
+    fut: F,
+) -> Result<T> {
+    futures::pin_mut!(fut);
+    match future::select(fut, Timer::after(duration)).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(TimedOut.into()),
+    }
+}
+
+pub(crate) async fn check<F: Future<Output = Result<()>>>(duration: Duration, fut: F) -> Check {
+    let start = Instant::now();
+    timeout(duration, fut)
+        .await
+        .map(|()| start.elapsed())
+        .map_err(|err| err.to_string())
+}
+
+async fn connect<P: StoreParams>(
+    network: &NetworkService<P>,
+    peer: &PeerId,
+    addr: Option<&Multiaddr>,
+) -> Result<()> {
+    let mut events = network.swarm_events();
+    if network.connections().iter().any(|(p, _)| p == peer) {
+        return Ok(());
+    }
+    if let Some(addr) = addr {
+        network.add_address(peer, addr.clone());
+    }
+    network.dial(peer)?;
+    while let Some(event) = events.next().await {
+        if event == Event::Connected(*peer) {
+            return Ok(());
+        }
+    }
+    Err(NotConnected.into())
+}
+
+fn nat_status(listeners: &[Multiaddr], external: &[Multiaddr]) -> NatStatus {
+    if external.is_empty() {
+        NatStatus::Unknown
+    } else if external.iter().any(|addr| listeners.contains(addr)) {
+        NatStatus::Public
+    } else {
+        NatStatus::BehindNat
+    }
+}
+
+async fn clock_skew(source: &dyn TimeSource) -> Result<i64> {
+    let start = SystemTime::now();
+    let reference = source.now().await?;
+    let end = SystemTime::now();
+    let local = start + end.duration_since(start).unwrap_or_default() / 2;
+    let skew = match local.duration_since(reference) {
+        Ok(ahead) => ahead.as_millis() as i64,
+        Err(behind) => -(behind.duration().as_millis() as i64),
+    };
+    Ok(skew)
+}
+
+pub(crate) async fn diagnose<P: StoreParams>(
+    network: &NetworkService<P>,
+    config: &DiagnoseConfig,
+) -> DiagnoseReport {
+    let listeners = network.listeners();
+    let external_addresses: Vec<_> = network
+        .external_addresses()
+        .into_iter()
+        .map(|record| record.addr)
+        .collect();
+    let nat = nat_status(&listeners, &external_addresses);
+
+    let boot_nodes = future::join_all(config.boot_nodes.iter().map(|(peer, addr)| async move {
+        let res = check(config.timeout, connect(network, peer, Some(addr))).await;
+        (*peer, res)
+    }))
+    .await;
+
+    let dht_bootstrap = if config.boot_nodes.is_empty() {
+        None
+    } else {
+        Some(check(config.timeout, network.bootstrap(&[])).await)
+    };
+
+    let bitswap_echo = if let Some((peer, addr)) = &config.echo_peer {
+        let echo = async {
+            connect(network, peer, addr.as_ref()).await?;
+            network.bitswap_echo(peer).await
+        };
+        let res = timeout(config.timeout, echo).await;
+        Some((*peer, res.map_err(|err| err.to_string())))
+    } else {
+        None
+    };
+
+    let clock_skew = if let Some(source) = &config.time_source {
+        Some(clock_skew(&**source).await.map_err(|err| err.to_string()))
+    } else {
+        None
+    };
+
+    DiagnoseReport {
+        listeners,
+        external_addresses,
+        nat,
+        boot_nodes,
+        dht_bootstrap,
+        bitswap_echo,
+        clock_skew,
+    }
+}
@@ -0,0 +1,171 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{Cid, Ipld, Result};
+
+/// A change of a value at an ipld path.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathChange {
+    /// The value was added.
+    Added(Ipld),
+    /// The value was removed.
+    Removed(Ipld),
+    /// The value was replaced.
+    Changed(Ipld, Ipld),
+}
+
+/// A difference between two dags at an ipld path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathDiff {
+    /// Path of the value relative to the root. Path segments are separated by `/`.
+    pub path: String,
+    /// The change.
+    pub change: PathChange,
+}
+
+/// Difference between two dags.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DagDiff {
+    /// Blocks only reachable from the new root.
+    pub added: Vec<Cid>,
+    /// Blocks only reachable from the old root.
+    pub removed: Vec<Cid>,
+    /// Pairs of old and new blocks found at the same path.
+    pub changed: Vec<(Cid, Cid)>,
+    /// Differences of values inside of blocks found at the same path.
+    pub paths: Vec<PathDiff>,
+}
+
+/// Returns the children of all blocks reachable from `root`, skipping blocks for which
+/// `descend` returns `false`.
+fn reachable<F>(
+    get: &F,
+    root: Cid,
+    descend: impl Fn(&Cid) -> bool,
+) -> Result<FnvHashMap<Cid, Vec<Cid>>>
+where
+    F: Fn(&Cid) -> Result<Ipld>,
+{
+    let mut visited = FnvHashMap::default();
+    let mut stack = vec![root];
+    while let Some(cid) = stack.pop() {
+        if visited.contains_key(&cid) || !descend(&cid) {
+            continue;
+        }
+        let mut refs = vec![];
+        get(&cid)?.references(&mut refs);
+        stack.extend(refs.iter().copied());
+        visited.insert(cid, refs);
+    }
+    Ok(visited)
+}
+
+/// Computes the difference between the dags rooted at `old` and `new`. Subdags shared by
+/// both dags are only traversed once.
+pub(crate) fn dag_diff<F>(get: F, old: &Cid, new: &Cid) -> Result<DagDiff>
+where
+    F: Fn(&Cid) -> Result<Ipld>,
+{
+    let old_refs = reachable(&get, *old, |_| true)?;
+    let new_refs = reachable(&get, *new, |cid| !old_refs.contains_key(cid))?;
+
+    let mut stack: Vec<Cid> = new_refs
+        .values()
+        .flatten()
+        .chain(std::iter::once(new))
+        .filter(|cid| old_refs.contains_key(cid))
+        .copied()
+        .collect();
+    let mut shared = FnvHashSet::default();
+    while let Some(cid) = stack.pop() {
+        if shared.insert(cid) {
+            stack.extend(old_refs[&cid].iter().copied());
+        }
+    }
+
+    let mut diff = DagDiff {
+        added: new_refs.keys().copied().collect(),
+        removed: old_refs
+            .keys()
+            .filter(|cid| !shared.contains(cid))
+            .copied()
+            .collect(),
+        ..Default::default()
+    };
+    let mut walker = Walker {
+        get: &get,
+        diff: &mut diff,
+        path: vec![],
+    };
+    walker.walk_link(old, new)?;
+    Ok(diff)
+}
+
+/// Walks two dags in parallel, descending only in to values that differ.
+struct Walker<'a, F> {
+    get: &'a F,
+    diff: &'a mut DagDiff,
+    path: Vec<String>,
+}
+
+impl<'a, F> Walker<'a, F>
+where
+    F: Fn(&Cid) -> Result<Ipld>,
+{
+    fn push_change(&mut self, change: PathChange) {
+        self.diff.paths.push(PathDiff {
+            path: self.path.join("/"),
+            change,
+        });
+    }
+
+    fn walk_link(&mut self, old: &Cid, new: &Cid) -> Result<()> {
+        if old == new {
+            return Ok(());
+        }
+        self.diff.changed.push((*old, *new));
+        let old = (self.get)(old)?;
+        let new = (self.get)(new)?;
+        self.walk(&old, &new)
+    }
+
+    fn walk(&mut self, old: &Ipld, new: &Ipld) -> Result<()> {
+        match (old, new) {
+            (Ipld::Link(old), Ipld::Link(new)) => self.walk_link(old, new)?,
+            (Ipld::StringMap(old), Ipld::StringMap(new)) => {
+                for (key, old) in old {
+                    self.path.push(key.clone());
+                    if let Some(new) = new.get(key) {
+                        self.walk(old, new)?;
+                    } else {
+                        self.push_change(PathChange::Removed(old.clone()));
+                    }
+                    self.path.pop();
+                }
+                for (key, new) in new {
+                    if !old.contains_key(key) {
+                        self.path.push(key.clone());
+                        self.push_change(PathChange::Added(new.clone()));
+                        self.path.pop();
+                    }
+                }
+            }
+            (Ipld::List(old), Ipld::List(new)) => {
+                for i in 0..std::cmp::max(old.len(), new.len()) {
+                    self.path.push(i.to_string());
+                    match (old.get(i), new.get(i)) {
+                        (Some(old), Some(new)) => self.walk(old, new)?,
+                        (Some(old), None) => self.push_change(PathChange::Removed(old.clone())),
+                        (None, Some(new)) => self.push_change(PathChange::Added(new.clone())),
+                        (None, None) => {}
+                    }
+                    self.path.pop();
+                }
+            }
+            (old, new) => {
+                if old != new {
+                    self.push_change(PathChange::Changed(old.clone(), new.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use ipfs_embed_net::DnsResolver;
+use libipld::{Cid, Result};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+/// Error returned when a domain has no dnslink record pointing to an ipfs path.
+#[derive(Debug, Error)]
+#[error("no dnslink record found for {0}")]
+pub struct DnsLinkNotFound(pub String);
+
+/// Target of a dnslink record.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsLink {
+    /// Root of the dag.
+    pub cid: Cid,
+    /// Path within the dag, without the leading `/`. Empty if the record links to the
+    /// root.
+    pub path: String,
+}
+
+/// Parses a `dnslink=/ipfs/<cid>[/<path>]` TXT record. Returns `None` for other records,
+/// including `/ipns/` links.
+pub(crate) fn parse_dnslink(txt: &str) -> Option<Result<DnsLink>> {
+    let value = txt.trim().strip_prefix("dnslink=")?;
+    let value = value.trim().strip_prefix("/ipfs/")?;
+    let (cid, path) = match value.find('/') {
+        Some(i) => (&value[..i], value[i + 1..].trim_end_matches('/')),
+        None => (value, ""),
+    };
+    let res = Cid::try_from(cid)
+        .map(|cid| DnsLink {
+            cid,
+            path: path.to_string(),
+        })
+        .map_err(Into::into);
+    Some(res)
+}
+
+/// Looks up the TXT records of `_dnslink.<domain>` using `resolver` and returns the first
+/// valid dnslink.
+pub(crate) async fn resolve(resolver: &dyn DnsResolver, domain: &str) -> Result<DnsLink> {
+    let domain = domain.trim_end_matches('.');
+    let records = resolver
+        .lookup_txt(&format!("_dnslink.{}.", domain))
+        .await?;
+    for txt in records {
+        match parse_dnslink(&txt) {
+            Some(Ok(link)) => return Ok(link),
+            Some(Err(err)) => tracing::debug!("{}: invalid dnslink {}: {}", domain, txt, err),
+            None => {}
+        }
+    }
+    Err(DnsLinkNotFound(domain.to_string()).into())
+}
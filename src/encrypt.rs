@@ -0,0 +1,297 @@
+use crate::{encode_block, rewrite_links, DAG_CBOR, RAW};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use fnv::FnvHashMap;
+use ipfs_embed_net::{Keypair, PublicKey};
+use ipfs_embed_sqlite::{StorageService, TempPin};
+use libipld::codec::{Decode, Encode, References};
+use libipld::error::BlockNotFound;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use thiserror::Error;
+
+const DOMAIN: &[u8] = b"/ipfs-embed/encrypted-dag/1.0.0/";
+const NONCE_SIZE: usize = 24;
+/// Version of the envelope. Version 1 only encrypted the blocks without links.
+const VERSION: i128 = 2;
+
+/// Error returned when a key isn't an ed25519 key.
+#[derive(Debug, Error)]
+#[error("only ed25519 keys can encrypt dags")]
+pub struct UnsupportedKey;
+
+/// Error returned when the content key of an encrypted dag isn't wrapped for a keypair.
+#[derive(Debug, Error)]
+#[error("not a recipient of the encrypted dag {0}")]
+pub struct NotARecipient(pub Cid);
+
+/// Error returned when an envelope or an encrypted block is malformed or doesn't
+/// decrypt with the content key.
+#[derive(Debug, Error)]
+#[error("invalid encrypted block {0}")]
+pub struct InvalidEncryptedDag(pub Cid);
+
+/// Returns the x25519 public key of an ed25519 public key.
+fn x25519_public(key: &PublicKey) -> Result<MontgomeryPoint> {
+    match key {
+        PublicKey::Ed25519(key) => CompressedEdwardsY(key.encode())
+            .decompress()
+            .map(|point| point.to_montgomery())
+            .ok_or_else(|| UnsupportedKey.into()),
+        _ => Err(UnsupportedKey.into()),
+    }
+}
+
+/// Returns the x25519 secret key of an ed25519 keypair.
+fn x25519_secret(keypair: &Keypair) -> Result<Scalar> {
+    match keypair {
+        Keypair::Ed25519(keypair) => {
+            let hash = Sha512::digest(keypair.secret().as_ref());
+            let mut bytes = [0; 32];
+            bytes.copy_from_slice(&hash[..32]);
+            Ok(clamp(bytes))
+        }
+        _ => Err(UnsupportedKey.into()),
+    }
+}
+
+fn clamp(mut bytes: [u8; 32]) -> Scalar {
+    bytes[0] &= 248;
+    bytes[31] &= 127;
+    bytes[31] |= 64;
+    Scalar::from_bits(bytes)
+}
+
+/// Derives the key wrapping the content key for a recipient.
+fn wrapping_key(shared: &MontgomeryPoint, ephemeral: &[u8], recipient: &[u8]) -> ChaCha20Poly1305 {
+    let mut hasher = Sha256::new();
+    hasher.update(DOMAIN);
+    hasher.update(shared.as_bytes());
+    hasher.update(ephemeral);
+    hasher.update(recipient);
+    ChaCha20Poly1305::new(&hasher.finalize())
+}
+
+/// Encrypts the cid and the data of a block, prefixed with a random nonce.
+fn seal<P: StoreParams>(cipher: &XChaCha20Poly1305, block: &Block<P>) -> Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut plaintext = block.cid().to_bytes();
+    plaintext.extend_from_slice(block.data());
+    let ciphertext = cipher
+        .encrypt(&nonce.into(), plaintext.as_slice())
+        .map_err(|_| InvalidEncryptedDag(*block.cid()))?;
+    let mut data = nonce.to_vec();
+    data.extend(ciphertext);
+    Ok(data)
+}
+
+/// Encrypts a block whose links were encrypted to the blocks in `mapped`. Blocks without
+/// links become raw blocks of the sealed block. Other blocks become dag-cbor blocks of
+/// the sealed block and the links to the encrypted blocks, so that the encrypted dag can
+/// be pinned and synced without the content key. Only the shape of the dag is revealed.
+fn encrypt_block<P: StoreParams>(
+    cipher: &XChaCha20Poly1305,
+    block: &Block<P>,
+    mapped: &FnvHashMap<Cid, Cid>,
+) -> Result<Block<P>>
+where
+    Ipld: References<P::Codecs> + Encode<P::Codecs>,
+{
+    let data = seal(cipher, block)?;
+    let hash = P::Hashes::try_from(block.cid().hash().code())
+        .map_err(|_| libipld::error::UnsupportedMultihash(block.cid().hash().code()))?;
+    let mut refs = vec![];
+    block.references(&mut refs)?;
+    if refs.is_empty() {
+        return Block::<P>::encode(P::Codecs::try_from(RAW)?, hash, &Ipld::Bytes(data));
+    }
+    let links = refs
+        .iter()
+        .map(|cid| Ipld::Link(mapped.get(cid).copied().unwrap_or(*cid)))
+        .collect();
+    let mut map = BTreeMap::new();
+    map.insert("data".to_string(), Ipld::Bytes(data));
+    map.insert("links".to_string(), Ipld::List(links));
+    Block::<P>::encode(P::Codecs::try_from(DAG_CBOR)?, hash, &Ipld::StringMap(map))
+}
+
+/// Decrypts a block created by `encrypt_block`, returning the original block.
+fn decrypt_block<P: StoreParams>(cipher: &XChaCha20Poly1305, block: &Block<P>) -> Result<Block<P>>
+where
+    Ipld: Decode<P::Codecs>,
+{
+    let invalid = || InvalidEncryptedDag(*block.cid());
+    let sealed = match block.cid().codec() {
+        RAW => block.data().to_vec(),
+        DAG_CBOR => match block.ipld()?.get("data") {
+            Ok(Ipld::Bytes(data)) => data.clone(),
+            _ => return Err(invalid().into()),
+        },
+        _ => return Err(invalid().into()),
+    };
+    if sealed.len() < NONCE_SIZE {
+        return Err(invalid().into());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let plaintext = cipher
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| invalid())?;
+    let mut reader = std::io::Cursor::new(plaintext.as_slice());
+    let cid = Cid::read_bytes(&mut reader).map_err(|_| invalid())?;
+    let data = plaintext[reader.position() as usize..].to_vec();
+    Block::new(cid, data)
+}
+
+/// Rebuilds the dag rooted at `root` bottom up, replacing every block with the result of
+/// `map`, which is passed the new blocks of the links of the block. The new blocks are
+/// added to `tmp`.
+fn map_dag<P: StoreParams>(
+    storage: &StorageService<P>,
+    tmp: &TempPin,
+    root: &Cid,
+    map: impl Fn(&Block<P>, &FnvHashMap<Cid, Cid>) -> Result<Block<P>>,
+) -> Result<Cid>
+where
+    Ipld: References<P::Codecs>,
+{
+    enum Visit<P: StoreParams> {
+        Enter(Cid),
+        Exit(Block<P>),
+    }
+    let mut mapped = FnvHashMap::default();
+    let mut stack = vec![Visit::<P>::Enter(*root)];
+    while let Some(visit) = stack.pop() {
+        match visit {
+            Visit::Enter(cid) => {
+                if mapped.contains_key(&cid) {
+                    continue;
+                }
+                let data = storage.get(&cid)?.ok_or(BlockNotFound(cid))?;
+                let block = Block::<P>::new_unchecked(cid, data);
+                let mut refs = vec![];
+                block.references(&mut refs)?;
+                // the links are mapped before the block.
+                stack.push(Visit::Exit(block));
+                stack.extend(refs.into_iter().map(Visit::Enter));
+            }
+            Visit::Exit(block) => {
+                if mapped.contains_key(block.cid()) {
+                    continue;
+                }
+                let new = map(&block, &mapped)?;
+                storage.temp_pin(tmp, std::iter::once(*new.cid()))?;
+                storage.insert(&new)?;
+                mapped.insert(*block.cid(), *new.cid());
+            }
+        }
+    }
+    Ok(mapped[root])
+}
+
+/// Encrypts every block of the dag rooted at `root` and returns the envelope wrapping
+/// the content key for each of the `recipients`.
+pub(crate) fn encrypt_dag<P: StoreParams>(
+    storage: &StorageService<P>,
+    tmp: &TempPin,
+    root: &Cid,
+    recipients: &[PublicKey],
+) -> Result<Cid>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs> + Encode<P::Codecs>,
+{
+    let mut content_key = [0; 32];
+    rand::thread_rng().fill_bytes(&mut content_key);
+    let cipher = XChaCha20Poly1305::new(&content_key.into());
+    let encrypted = map_dag(storage, tmp, root, |block, mapped| {
+        encrypt_block(&cipher, block, mapped)
+    })?;
+
+    let mut secret = [0; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = clamp(secret);
+    let ephemeral = (X25519_BASEPOINT * secret).to_bytes();
+    let mut keys = vec![];
+    for recipient in recipients {
+        let public = x25519_public(recipient)?;
+        let peer = recipient.clone().into_peer_id().to_bytes();
+        let wrapped = wrapping_key(&(public * secret), &ephemeral, public.as_bytes())
+            .encrypt(&[0; 12].into(), &content_key[..])
+            .map_err(|_| InvalidEncryptedDag(*root))?;
+        let mut key = BTreeMap::new();
+        key.insert("peer".to_string(), Ipld::Bytes(peer));
+        key.insert("key".to_string(), Ipld::Bytes(wrapped));
+        keys.push(Ipld::StringMap(key));
+    }
+    let mut envelope = BTreeMap::new();
+    envelope.insert("version".to_string(), Ipld::Integer(VERSION));
+    envelope.insert("root".to_string(), Ipld::Link(encrypted));
+    envelope.insert("ephemeral".to_string(), Ipld::Bytes(ephemeral.to_vec()));
+    envelope.insert("keys".to_string(), Ipld::List(keys));
+    let block = encode_block::<P>(DAG_CBOR, &Ipld::StringMap(envelope))?;
+    storage.temp_pin(tmp, std::iter::once(*block.cid()))?;
+    storage.insert(&block)?;
+    Ok(*block.cid())
+}
+
+/// Unwraps the content key of the envelope with `keypair` and decrypts the dag, returning
+/// the root of the decrypted dag.
+pub(crate) fn decrypt_dag<P: StoreParams>(
+    storage: &StorageService<P>,
+    tmp: &TempPin,
+    envelope: &Cid,
+    keypair: &Keypair,
+) -> Result<Cid>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs> + Encode<P::Codecs>,
+{
+    let invalid = || InvalidEncryptedDag(*envelope);
+    let data = storage.get(envelope)?.ok_or(BlockNotFound(*envelope))?;
+    let ipld = Block::<P>::new_unchecked(*envelope, data).ipld()?;
+    if ipld.get("version").ok() != Some(&Ipld::Integer(VERSION)) {
+        return Err(invalid().into());
+    }
+    let (root, ephemeral, keys) = match (ipld.get("root"), ipld.get("ephemeral"), ipld.get("keys"))
+    {
+        (Ok(Ipld::Link(root)), Ok(Ipld::Bytes(ephemeral)), Ok(Ipld::List(keys)))
+            if ephemeral.len() == 32 =>
+        {
+            (*root, ephemeral, keys)
+        }
+        _ => return Err(invalid().into()),
+    };
+    let peer = keypair.public().into_peer_id().to_bytes();
+    let wrapped = keys
+        .iter()
+        .find_map(|key| match (key.get("peer"), key.get("key")) {
+            (Ok(Ipld::Bytes(p)), Ok(Ipld::Bytes(wrapped))) if *p == peer => Some(wrapped),
+            _ => None,
+        })
+        .ok_or(NotARecipient(*envelope))?;
+    let secret = x25519_secret(keypair)?;
+    let public = X25519_BASEPOINT * secret;
+    let mut point = [0; 32];
+    point.copy_from_slice(ephemeral);
+    let content_key = wrapping_key(
+        &(MontgomeryPoint(point) * secret),
+        ephemeral,
+        public.as_bytes(),
+    )
+    .decrypt(&[0; 12].into(), wrapped.as_slice())
+    .map_err(|_| invalid())?;
+    if content_key.len() != 32 {
+        return Err(invalid().into());
+    }
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&content_key));
+    map_dag(storage, tmp, &root, |block, _| {
+        decrypt_block(&cipher, block)
+    })
+}
@@ -0,0 +1,85 @@
+use ipfs_embed_net::{ErrorClass, SwarmStopped};
+use libipld::error::{BlockTooLarge, InvalidMultihash, UnsupportedCodec, UnsupportedMultihash};
+use thiserror::Error;
+
+/// The error of a failed operation.
+type Source = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Error returned by `Ipfs`.
+///
+/// The error of the failed operation is kept as the source, so `downcast_ref` still
+/// returns specific errors like `Denied` or `BlockNotFound`.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The block store failed.
+    #[error("store: {0}")]
+    Store(#[source] Source),
+    /// A network request failed.
+    #[error("network: {0}")]
+    Network(#[source] Source),
+    /// A network request timed out.
+    #[error("timeout: {0}")]
+    Timeout(#[source] Source),
+    /// A cid is malformed or uses an unsupported codec or hash.
+    #[error("invalid cid: {0}")]
+    InvalidCid(#[source] Source),
+    /// A block exceeds the maximum block size.
+    #[error("block size {0} exceeds the maximum")]
+    BlockTooLarge(usize),
+    /// The node shut down.
+    #[error("the node shut down")]
+    Shutdown,
+}
+
+impl Error {
+    /// Classifies an error of the block store.
+    pub(crate) fn store(err: libipld::error::Error) -> Self {
+        Self::classify(err, Self::Store)
+    }
+
+    /// Classifies an error of the network.
+    pub(crate) fn network(err: libipld::error::Error) -> Self {
+        if ErrorClass::of(&err) == ErrorClass::Timeout {
+            return Self::Timeout(err.into());
+        }
+        Self::classify(err, Self::Network)
+    }
+
+    fn classify(err: libipld::error::Error, default: fn(Source) -> Self) -> Self {
+        let err = match err.downcast::<Self>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        if let Some(BlockTooLarge(size)) = err.downcast_ref() {
+            return Self::BlockTooLarge(*size);
+        }
+        if err.downcast_ref::<SwarmStopped>().is_some() {
+            return Self::Shutdown;
+        }
+        if err.downcast_ref::<libipld::cid::Error>().is_some()
+            || err.downcast_ref::<UnsupportedCodec>().is_some()
+            || err.downcast_ref::<UnsupportedMultihash>().is_some()
+            || err.downcast_ref::<InvalidMultihash>().is_some()
+        {
+            return Self::InvalidCid(err.into());
+        }
+        default(err.into())
+    }
+
+    /// Returns the underlying error if it is of type `E`.
+    pub fn downcast_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        match self {
+            Self::Store(err) | Self::Network(err) | Self::Timeout(err) | Self::InvalidCid(err) => {
+                err.downcast_ref()
+            }
+            Self::BlockTooLarge(_) | Self::Shutdown => None,
+        }
+    }
+}
+
+/// Errors of the local operations of the node are block store errors.
+impl From<libipld::error::Error> for Error {
+    fn from(err: libipld::error::Error) -> Self {
+        Self::store(err)
+    }
+}
@@ -0,0 +1,177 @@
+use futures::channel::mpsc;
+use futures::stream::Stream;
+use ipfs_embed_net::{Event, PeerId};
+use libipld::Cid;
+use parking_lot::Mutex;
+use std::ops::BitOr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Number of events buffered per subscriber before events are dropped.
+const EVENT_BUFFER: usize = 256;
+
+/// Event emitted by an `Ipfs` node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NodeEvent {
+    /// A swarm event.
+    Swarm(Event),
+    /// A block was evicted by the garbage collector.
+    Evicted(Cid),
+    /// The pin verification found blocks missing from an aliased dag. Contains the root
+    /// and the number of missing blocks.
+    MissingBlocks(Cid, usize),
+    /// The missing blocks of an aliased dag were fetched.
+    Repaired(Cid),
+    /// The missing blocks of an aliased dag couldn't be fetched.
+    RepairFailed(Cid, String),
+    /// A block received from the network was rejected by a `BlockValidator`. Contains the
+    /// sender if it is known, the block and the reason.
+    InvalidBlock(Option<PeerId>, Cid, String),
+}
+
+/// Selects the events a subscriber is interested in. Filters can be combined with `|`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EventFilter(u8);
+
+#[allow(non_upper_case_globals)]
+impl EventFilter {
+    /// Listener events.
+    pub const Listeners: Self = Self(1);
+    /// Peer connection and subscription events.
+    pub const Connections: Self = Self(2);
+    /// Garbage collector events.
+    pub const Gc: Self = Self(4);
+    /// Peer rtt measurements.
+    pub const Latency: Self = Self(8);
+    /// Pin verification events.
+    pub const Repair: Self = Self(16);
+    /// Blocks rejected by validators.
+    pub const Validation: Self = Self(32);
+    /// Port mappings on the gateway and their failures.
+    pub const PortMapping: Self = Self(64);
+    /// Failed attempts to rebind a closed listener.
+    pub const Rebind: Self = Self(128);
+    /// All events.
+    pub const All: Self = Self(255);
+
+    /// Returns `true` if the filter matches the event.
+    pub fn matches(self, event: &NodeEvent) -> bool {
+        let kind = match event {
+            NodeEvent::Swarm(Event::Connected(_))
+            | NodeEvent::Swarm(Event::Disconnected(_))
+            | NodeEvent::Swarm(Event::Subscribed(_, _)) => Self::Connections,
+            NodeEvent::Swarm(Event::Rtt(_, _)) => Self::Latency,
+            NodeEvent::Swarm(Event::NewListener(_, _))
+            | NodeEvent::Swarm(Event::NewListenAddr(_))
+            | NodeEvent::Swarm(Event::ExpiredListenAddr(_))
+            | NodeEvent::Swarm(Event::ListenerError(_, _))
+            | NodeEvent::Swarm(Event::ListenerClosed(_, _, _)) => Self::Listeners,
+            NodeEvent::Swarm(Event::PortMapped(_, _))
+            | NodeEvent::Swarm(Event::PortMappingFailed(_, _)) => Self::PortMapping,
+            NodeEvent::Swarm(Event::RebindFailed(_, _)) => Self::Rebind,
+            NodeEvent::Evicted(_) => Self::Gc,
+            NodeEvent::MissingBlocks(_, _)
+            | NodeEvent::Repaired(_)
+            | NodeEvent::RepairFailed(_, _) => Self::Repair,
+            NodeEvent::InvalidBlock(_, _, _) => Self::Validation,
+        };
+        self.0 & kind.0 != 0
+    }
+}
+
+impl BitOr for EventFilter {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+struct Subscriber {
+    filter: EventFilter,
+    tx: mpsc::Sender<NodeEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Distributes events to subscribers without ever blocking the publisher. Events are
+/// dropped for subscribers that don't keep up.
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self, filter: EventFilter) -> EventSubscription {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.subscribers.lock().push(Subscriber {
+            filter,
+            tx,
+            dropped: dropped.clone(),
+        });
+        EventSubscription { rx, dropped }
+    }
+
+    pub fn publish(&self, event: NodeEvent) {
+        let mut subscribers = self.subscribers.lock();
+        for sub in subscribers.iter_mut() {
+            if !sub.filter.matches(&event) {
+                continue;
+            }
+            if let Err(err) = sub.tx.try_send(event.clone()) {
+                if err.is_full() {
+                    sub.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        subscribers.retain(|sub| !sub.tx.is_closed());
+    }
+}
+
+/// Stream of events matching an `EventFilter`.
+pub struct EventSubscription {
+    rx: mpsc::Receiver<NodeEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSubscription {
+    /// Returns the number of events dropped because the subscriber didn't keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for EventSubscription {
+    type Item = NodeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_swarm_events() {
+        let addr: ipfs_embed_net::Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let listener = NodeEvent::Swarm(Event::NewListenAddr(addr.clone()));
+        let mapped = NodeEvent::Swarm(Event::PortMapped(addr.clone(), addr.clone()));
+        let mapping_failed = NodeEvent::Swarm(Event::PortMappingFailed(addr.clone(), "".into()));
+        let rebind_failed = NodeEvent::Swarm(Event::RebindFailed(addr, "".into()));
+
+        assert!(EventFilter::Listeners.matches(&listener));
+        for event in &[&mapped, &mapping_failed, &rebind_failed] {
+            assert!(!EventFilter::Listeners.matches(event));
+            assert!(EventFilter::All.matches(event));
+        }
+        assert!(EventFilter::PortMapping.matches(&mapped));
+        assert!(EventFilter::PortMapping.matches(&mapping_failed));
+        assert!(!EventFilter::PortMapping.matches(&rebind_failed));
+        assert!(EventFilter::Rebind.matches(&rebind_failed));
+        assert!((EventFilter::Listeners | EventFilter::Rebind).matches(&rebind_failed));
+    }
+}
@@ -0,0 +1,91 @@
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{BoxStream, StreamExt};
+use ipfs_embed_net::{BlockExchange, NetworkService, Priority, RetryPolicy, SyncEvent, SyncQuery};
+use libipld::store::StoreParams;
+use libipld::{Cid, Result};
+use std::sync::Arc;
+
+/// Object safe `BlockExchange`, so that a node can hold an exchange of any type.
+pub(crate) trait DynExchange<P: StoreParams>: Send + Sync {
+    fn want(&self, cid: Cid, priority: Priority) -> BoxFuture<'static, Result<()>>;
+
+    fn sync(&self, missing: Vec<Cid>, priority: Priority) -> BoxStream<'static, SyncEvent>;
+
+    fn provide(&self, cid: Cid) -> BoxFuture<'static, Result<()>>;
+
+    fn unprovide(&self, cid: Cid);
+}
+
+impl<P, E> DynExchange<P> for E
+where
+    P: StoreParams,
+    E: BlockExchange<P>,
+    E::Want: 'static,
+    E::Sync: 'static,
+    E::Provide: 'static,
+{
+    fn want(&self, cid: Cid, priority: Priority) -> BoxFuture<'static, Result<()>> {
+        BlockExchange::want(self, cid, priority).boxed()
+    }
+
+    fn sync(&self, missing: Vec<Cid>, priority: Priority) -> BoxStream<'static, SyncEvent> {
+        // the sync is a stream and a future, only its events are needed.
+        StreamExt::boxed(BlockExchange::sync(self, missing, priority))
+    }
+
+    fn provide(&self, cid: Cid) -> BoxFuture<'static, Result<()>> {
+        BlockExchange::provide(self, cid).boxed()
+    }
+
+    fn unprovide(&self, cid: Cid) {
+        BlockExchange::unprovide(self, cid)
+    }
+}
+
+/// The exchange a node requests and provides blocks through. Either the bitswap exchange
+/// of its network or an exchange passed to `Ipfs::with_exchange`.
+#[derive(Clone)]
+pub(crate) enum Exchange<P: StoreParams> {
+    Network(NetworkService<P>),
+    Custom {
+        exchange: Arc<dyn DynExchange<P>>,
+        /// Wraps the syncs of the exchange in sync queries.
+        network: NetworkService<P>,
+        retry: RetryPolicy,
+    },
+}
+
+impl<P: StoreParams> Exchange<P> {
+    /// Requests the block `cid`, retrying failed requests according to the retry policy.
+    pub async fn fetch(&self, cid: Cid, priority: Priority) -> Result<()> {
+        match self {
+            Self::Network(network) => network.fetch(cid, priority).await,
+            Self::Custom {
+                exchange, retry, ..
+            } => retry.run(|| exchange.want(cid, priority)).await,
+        }
+    }
+
+    pub fn sync(&self, missing: Vec<Cid>, priority: Priority) -> SyncQuery<P> {
+        match self {
+            Self::Network(network) => BlockExchange::sync(network, missing, priority),
+            Self::Custom {
+                exchange, network, ..
+            } => network.sync_with(exchange.sync(missing, priority)),
+        }
+    }
+
+    pub fn provide(&self, cid: Cid) -> BoxFuture<'static, Result<()>> {
+        match self {
+            Self::Network(network) => BlockExchange::provide(network, cid),
+            Self::Custom { exchange, .. } => exchange.provide(cid),
+        }
+    }
+
+    pub fn unprovide(&self, cid: Cid) {
+        match self {
+            Self::Network(network) => BlockExchange::unprovide(network, cid),
+            Self::Custom { exchange, .. } => exchange.unprovide(cid),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use ipfs_embed_net::SyncStats;
+use libipld::Cid;
+use std::time::Duration;
+use thiserror::Error;
+
+/// How `Ipfs::get_or_fetch` retrieves blocks missing from the block store.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FetchPolicy {
+    /// Only reads the block store.
+    Offline,
+    /// Requests the block once from the connected peers, without looking up providers in
+    /// the dht, giving up after the timeout.
+    Fast(Duration),
+    /// Looks up the providers in the dht and retries according to the
+    /// `NetworkConfig::retry_policy`, like `Ipfs::fetch`.
+    Thorough,
+}
+
+/// Outcome of `Ipfs::sync_with_deadline`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartialSync {
+    /// Whether the whole dag is in the store.
+    pub complete: bool,
+    /// Bytes downloaded until the sync completed or the deadline passed.
+    pub stats: SyncStats,
+    /// Missing blocks linked from blocks in the store. The blocks they link to are
+    /// unknown until they are received.
+    pub missing: Vec<Cid>,
+    /// Reason the sync failed before the deadline, like a block no peer provided.
+    pub error: Option<String>,
+}
+
+/// Error returned when a block wasn't retrieved within the timeout of `FetchPolicy::Fast`.
+#[derive(Debug, Error)]
+#[error("fetching {0} timed out")]
+pub struct FetchTimeout(pub Cid);
@@ -0,0 +1,240 @@
+use crate::Ipfs;
+use fnv::FnvHashMap;
+use futures::future::{self, Either, FutureExt};
+use futures::stream::{Stream, StreamExt};
+use ipfs_embed_net::{Event, Key, Keypair, NetworkService, PeerId, PublicKey, Quorum};
+use ipfs_embed_rt::Task;
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+const DOMAIN: &[u8] = b"/ipfs-embed/root/1.0.0/";
+
+/// Interval between lookups of the dht record of a followed peer, in case gossip
+/// messages were missed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Error returned when a published root is malformed or isn't signed by the publisher.
+#[derive(Debug, Error)]
+#[error("invalid root published by {0}")]
+pub struct InvalidRoot(pub PeerId);
+
+/// The follow tasks by followed peer and topic. Dropping a task stops following.
+pub(crate) type Follows = Arc<Mutex<FnvHashMap<(PeerId, String), Task<()>>>>;
+
+/// Returns the gossipsub topic and dht key the roots of `peer` are published under.
+pub(crate) fn root_topic(peer: &PeerId, topic: &str) -> String {
+    format!("/ipfs-embed/root/{}/{}", peer, topic)
+}
+
+/// Returns the topic `peer` subscribes to for the messages of `topic` sent to it directly,
+/// like the latest root when it (re)connects.
+pub(crate) fn inbox_topic(topic: &str, peer: &PeerId) -> String {
+    format!("{}/inbox/{}", topic, peer)
+}
+
+/// Returns the alias pinning the root of `peer` that was received last.
+pub(crate) fn follow_alias(peer: &PeerId, topic: &str) -> String {
+    format!("/ipfs-embed/follow/{}/{}", peer, topic)
+}
+
+fn message(topic: &str, seq: u64, root: &Cid) -> Vec<u8> {
+    let mut msg = DOMAIN.to_vec();
+    msg.extend_from_slice(&(topic.len() as u64).to_be_bytes());
+    msg.extend_from_slice(topic.as_bytes());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend(root.to_bytes());
+    msg
+}
+
+/// Encodes a root signed with the node key. Roots with a higher `seq` replace the
+/// roots with a lower one.
+pub(crate) fn encode_root(keypair: &Keypair, topic: &str, seq: u64, root: &Cid) -> Result<Vec<u8>> {
+    let signature = keypair.sign(&message(topic, seq, root))?;
+    let mut map = BTreeMap::new();
+    map.insert("root".to_string(), Ipld::Link(*root));
+    map.insert("seq".to_string(), Ipld::Integer(seq as i128));
+    map.insert(
+        "public_key".to_string(),
+        Ipld::Bytes(keypair.public().into_protobuf_encoding()),
+    );
+    map.insert("signature".to_string(), Ipld::Bytes(signature));
+    DagCborCodec.encode(&Ipld::StringMap(map))
+}
+
+/// Decodes a root published by `peer`, returning its sequence number and the root.
+pub(crate) fn decode_root(bytes: &[u8], peer: &PeerId, topic: &str) -> Result<(u64, Cid)> {
+    let ipld: Ipld = DagCborCodec.decode(bytes)?;
+    let (root, seq, public_key, signature) = match (
+        ipld.get("root"),
+        ipld.get("seq"),
+        ipld.get("public_key"),
+        ipld.get("signature"),
+    ) {
+        (
+            Ok(Ipld::Link(root)),
+            Ok(Ipld::Integer(seq)),
+            Ok(Ipld::Bytes(public_key)),
+            Ok(Ipld::Bytes(signature)),
+        ) if *seq >= 0 && *seq <= u64::MAX as i128 => (*root, *seq as u64, public_key, signature),
+        _ => return Err(InvalidRoot(*peer).into()),
+    };
+    let public_key = PublicKey::from_protobuf_encoding(public_key)?;
+    if public_key.clone().into_peer_id() != *peer
+        || !public_key.verify(&message(topic, seq, &root), signature)
+    {
+        return Err(InvalidRoot(*peer).into());
+    }
+    Ok((seq, root))
+}
+
+/// The latest roots published by the local node since it started, by root topic, and the
+/// task sending them to followers.
+#[derive(Clone, Default)]
+pub(crate) struct PublishedRoots {
+    roots: Arc<Mutex<FnvHashMap<String, Vec<u8>>>>,
+    task: Arc<Mutex<Option<Task<()>>>>,
+}
+
+impl PublishedRoots {
+    /// Records the latest root published under the root topic `key`, starting the task
+    /// sending the roots to followers on the first call.
+    pub fn insert<P: StoreParams>(&self, network: &NetworkService<P>, key: String, msg: Vec<u8>) {
+        self.roots.lock().insert(key, msg);
+        let mut task = self.task.lock();
+        if task.is_none() {
+            let reply = reply(network.clone(), self.roots.clone());
+            *task = Some(ipfs_embed_rt::spawn(reply));
+        }
+    }
+}
+
+/// Sends the latest root of a root topic to a follower when it subscribes to its inbox,
+/// which it does when following the root topic or after reconnecting. Followers catch up
+/// this way without waiting for the next root or dht lookup, and without the root being
+/// announced to all followers.
+async fn reply<P: StoreParams>(
+    network: NetworkService<P>,
+    roots: Arc<Mutex<FnvHashMap<String, Vec<u8>>>>,
+) {
+    let mut events = network.swarm_events();
+    while let Some(event) = events.next().await {
+        let (peer, inbox) = match event {
+            Event::Subscribed(peer, inbox) => (peer, inbox),
+            _ => continue,
+        };
+        let msg = inbox
+            .strip_suffix(&*inbox_topic("", &peer))
+            .and_then(|key| roots.lock().get(key).cloned());
+        if let Some(msg) = msg {
+            if let Err(err) = network.publish(&inbox, msg) {
+                tracing::debug!("failed to send root to {}: {}", peer, err);
+            }
+        }
+    }
+}
+
+/// Fetches the dag of a root received from `peer` and pins it with the follow alias. The
+/// sequence number is stored as the metadata of the alias, so that older roots are still
+/// rejected after a restart.
+async fn apply<P: StoreParams>(
+    ipfs: &Ipfs<P>,
+    peer: &PeerId,
+    topic: &str,
+    seq: u64,
+    root: &Cid,
+) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+{
+    let tmp = ipfs.create_temp_pin()?;
+    ipfs.temp_pin(&tmp, root)?;
+    ipfs.sync(root).await?;
+    ipfs.alias_with_meta(follow_alias(peer, topic), root, &seq.to_be_bytes())?;
+    Ok(())
+}
+
+/// Returns the sequence number of the root last received from `peer`.
+pub(crate) fn last_seq<P: StoreParams>(
+    ipfs: &Ipfs<P>,
+    peer: &PeerId,
+    topic: &str,
+) -> Result<Option<u64>>
+where
+    Ipld: References<P::Codecs>,
+{
+    let meta = match ipfs.resolve_with_meta(follow_alias(peer, topic))? {
+        Some((_, Some(meta))) => meta,
+        _ => return Ok(None),
+    };
+    Ok(meta.as_slice().try_into().ok().map(u64::from_be_bytes))
+}
+
+/// Keeps the follow alias of `peer` up to date with the roots it publishes on gossipsub
+/// and in the dht. `roots` are the subscriptions to the root topic of `peer` and to the
+/// inbox of the local node for the root topic.
+pub(crate) async fn run<P: StoreParams>(
+    ipfs: Ipfs<P>,
+    peer: PeerId,
+    topic: String,
+    roots: impl Stream<Item = Vec<u8>>,
+) where
+    Ipld: References<P::Codecs>,
+{
+    let key = root_topic(&peer, &topic);
+    futures::pin_mut!(roots);
+    let mut last = match last_seq(&ipfs, &peer, &topic) {
+        Ok(last) => last,
+        Err(err) => {
+            tracing::warn!("failed to read the last root of {}: {}", peer, err);
+            return;
+        }
+    };
+    let activity = ipfs.network.activity();
+    let mut timer = activity.sleep(Duration::from_secs(0)).boxed();
+    loop {
+        let msg = match future::select(roots.next(), &mut timer).await {
+            Either::Left((Some(bytes), _)) => Some(bytes),
+            Either::Left((None, _)) => break,
+            Either::Right(_) => None,
+        };
+        let records = match msg {
+            Some(bytes) => vec![bytes],
+            None => {
+                timer = activity.sleep(REFRESH_INTERVAL).boxed();
+                match ipfs.get_record(&Key::new(&key), Quorum::One).await {
+                    Ok(records) => records.into_iter().map(|r| r.record.value).collect(),
+                    Err(err) => {
+                        tracing::debug!("failed to look up root of {}: {}", peer, err);
+                        continue;
+                    }
+                }
+            }
+        };
+        let latest = records
+            .iter()
+            .filter_map(|bytes| match decode_root(bytes, &peer, &topic) {
+                Ok(root) => Some(root),
+                Err(err) => {
+                    tracing::debug!("{}", err);
+                    None
+                }
+            })
+            .max_by_key(|(seq, _)| *seq);
+        let (seq, root) = match latest {
+            Some((seq, root)) if last.map(|last| seq > last).unwrap_or(true) => (seq, root),
+            _ => continue,
+        };
+        match apply(&ipfs, &peer, &topic, seq, &root).await {
+            Ok(()) => last = Some(seq),
+            Err(err) => tracing::warn!("failed to sync root {} of {}: {}", root, peer, err),
+        }
+    }
+}
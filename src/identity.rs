@@ -0,0 +1,146 @@
+use ipfs_embed_net::{Keypair, PeerId, PublicKey};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::Codec;
+use libipld::{Ipld, Result};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+const DOMAIN: &[u8] = b"/ipfs-embed/identity/1.0.0/";
+
+/// Returns the gossipsub topic and dht key the identity link of `peer` is published
+/// under.
+pub(crate) fn identity_topic(peer: &PeerId) -> String {
+    format!("/ipfs-embed/identity/{}", peer)
+}
+
+/// Error returned when an identity link is malformed or isn't signed by both keys.
+#[derive(Debug, Error)]
+#[error("invalid identity link")]
+pub struct InvalidIdentityLink;
+
+fn message(old: &PublicKey, new: &PublicKey, timestamp: u64) -> Vec<u8> {
+    let old = old.clone().into_protobuf_encoding();
+    let new = new.clone().into_protobuf_encoding();
+    let mut msg = DOMAIN.to_vec();
+    msg.extend_from_slice(&(old.len() as u64).to_be_bytes());
+    msg.extend(old);
+    msg.extend_from_slice(&(new.len() as u64).to_be_bytes());
+    msg.extend(new);
+    msg.extend_from_slice(&timestamp.to_be_bytes());
+    msg
+}
+
+/// A statement that a node replaced its identity `old` with `new`, signed by both keys.
+/// The signature of the old key authorizes the change, the signature of the new key
+/// proves that the node holds it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IdentityLink {
+    old: PublicKey,
+    new: PublicKey,
+    timestamp: u64,
+    old_signature: Vec<u8>,
+    new_signature: Vec<u8>,
+}
+
+impl IdentityLink {
+    /// Signs a link from the identity `old` to `new` at the unix time `timestamp`.
+    pub fn sign(old: &Keypair, new: &Keypair, timestamp: u64) -> Result<Self> {
+        let msg = message(&old.public(), &new.public(), timestamp);
+        Ok(Self {
+            old: old.public(),
+            new: new.public(),
+            timestamp,
+            old_signature: old.sign(&msg)?,
+            new_signature: new.sign(&msg)?,
+        })
+    }
+
+    /// Returns the public key of the old identity.
+    pub fn old_key(&self) -> &PublicKey {
+        &self.old
+    }
+
+    /// Returns the public key of the new identity.
+    pub fn new_key(&self) -> &PublicKey {
+        &self.new
+    }
+
+    /// Returns the `PeerId` of the old identity.
+    pub fn old_peer_id(&self) -> PeerId {
+        self.old.clone().into_peer_id()
+    }
+
+    /// Returns the `PeerId` of the new identity.
+    pub fn new_peer_id(&self) -> PeerId {
+        self.new.clone().into_peer_id()
+    }
+
+    /// Returns the unix time in seconds the identity was rotated at.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Verifies the signatures of both keys.
+    pub fn verify(&self) -> bool {
+        let msg = message(&self.old, &self.new, self.timestamp);
+        self.old.verify(&msg, &self.old_signature) && self.new.verify(&msg, &self.new_signature)
+    }
+
+    /// Encodes the link as dag-cbor.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut map = BTreeMap::new();
+        map.insert(
+            "old".to_string(),
+            Ipld::Bytes(self.old.clone().into_protobuf_encoding()),
+        );
+        map.insert(
+            "new".to_string(),
+            Ipld::Bytes(self.new.clone().into_protobuf_encoding()),
+        );
+        map.insert(
+            "timestamp".to_string(),
+            Ipld::Integer(self.timestamp as i128),
+        );
+        map.insert(
+            "old_signature".to_string(),
+            Ipld::Bytes(self.old_signature.clone()),
+        );
+        map.insert(
+            "new_signature".to_string(),
+            Ipld::Bytes(self.new_signature.clone()),
+        );
+        DagCborCodec.encode(&Ipld::StringMap(map))
+    }
+
+    /// Decodes a link, failing with `InvalidIdentityLink` unless it is signed by both
+    /// keys.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let ipld: Ipld = DagCborCodec.decode(bytes)?;
+        let link = match (
+            ipld.get("old"),
+            ipld.get("new"),
+            ipld.get("timestamp"),
+            ipld.get("old_signature"),
+            ipld.get("new_signature"),
+        ) {
+            (
+                Ok(Ipld::Bytes(old)),
+                Ok(Ipld::Bytes(new)),
+                Ok(Ipld::Integer(timestamp)),
+                Ok(Ipld::Bytes(old_signature)),
+                Ok(Ipld::Bytes(new_signature)),
+            ) if *timestamp >= 0 && *timestamp <= u64::MAX as i128 => Self {
+                old: PublicKey::from_protobuf_encoding(old)?,
+                new: PublicKey::from_protobuf_encoding(new)?,
+                timestamp: *timestamp as u64,
+                old_signature: old_signature.clone(),
+                new_signature: new_signature.clone(),
+            },
+            _ => return Err(InvalidIdentityLink.into()),
+        };
+        if !link.verify() {
+            return Err(InvalidIdentityLink.into());
+        }
+        Ok(link)
+    }
+}
@@ -0,0 +1,480 @@
+use crate::{DAG_CBOR, RAW, SHA2_256};
+use fnv::{FnvHashMap, FnvHashSet};
+use ipfs_embed_sqlite::{StorageService, TempPin};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::error::{BlockNotFound, UnsupportedMultihash};
+use libipld::multihash::{Multihash, MultihashDigest};
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+const JOURNAL_PREFIX: &str = "/ipfs-embed/import-journal/";
+/// Time after which the journal of an import that made no progress is removed, so that
+/// abandoned imports don't keep their blocks pinned forever.
+pub(crate) const JOURNAL_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Alias pinning the blocks a flatfs import stored as raw blocks.
+const RAW_ALIAS: &[u8] = b"/go-ipfs/raw";
+
+/// Number of blocks checked for duplicates and inserted at once.
+const BATCH_SIZE: usize = 256;
+
+/// Error returned when a CAR file is malformed.
+#[derive(Debug, Error)]
+#[error("invalid car file: {0}")]
+pub struct InvalidCar(pub String);
+
+/// Summary of an import.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImportReport {
+    /// Number of imported blocks, including the blocks that were already in the store.
+    pub blocks: usize,
+    /// Number of imported blocks that were already in the store.
+    pub duplicate_blocks: usize,
+    /// Size in bytes of the blocks that were added to the store.
+    pub new_bytes: u64,
+    /// Size in bytes of the blocks that were already in the store.
+    pub duplicate_bytes: u64,
+    /// Number of blocks that didn't match their hash.
+    pub invalid_blocks: usize,
+    /// Number of blocks linked from a pin that are not in the repo.
+    pub missing_blocks: usize,
+    /// Number of blocks a flatfs import stored as raw blocks, because they are not
+    /// reachable from a pin or their codec is not supported.
+    pub raw_blocks: usize,
+    /// The pinned roots.
+    pub pins: Vec<Cid>,
+    /// Offset in bytes a journaled import resumed from.
+    pub resumed_from: u64,
+}
+
+/// Returns the alias an imported pin is stored under.
+pub fn import_alias(cid: &Cid) -> Vec<u8> {
+    format!("/go-ipfs/pin/{}", cid).into_bytes()
+}
+
+/// Returns the alias of the journal of the import `id`.
+pub(crate) fn journal_alias(id: &str) -> Vec<u8> {
+    format!("{}{}", JOURNAL_PREFIX, id).into_bytes()
+}
+
+/// Returns the ids of the unfinished journaled imports.
+pub(crate) fn journal_ids<P: StoreParams>(storage: &StorageService<P>) -> Result<Vec<String>> {
+    Ok(storage
+        .aliases_with_prefix(JOURNAL_PREFIX.as_bytes())?
+        .into_iter()
+        .filter_map(|(alias, _)| {
+            let id = alias.get(JOURNAL_PREFIX.len()..)?;
+            String::from_utf8(id.to_vec()).ok()
+        })
+        .collect())
+}
+
+/// Returns the offset in bytes up to which the journaled import `id` was committed.
+pub(crate) fn journal_offset<P: StoreParams>(
+    storage: &StorageService<P>,
+    id: &str,
+) -> Result<Option<u64>>
+where
+    Ipld: References<P::Codecs>,
+{
+    let cid = match storage.resolve(&journal_alias(id))? {
+        Some(cid) => cid,
+        None => return Ok(None),
+    };
+    let data = storage.get(&cid)?.ok_or(BlockNotFound(cid))?;
+    let node: Ipld = DagCborCodec.decode(&data)?;
+    match node.get("offset") {
+        Ok(Ipld::Integer(offset)) if *offset >= 0 => Ok(Some(*offset as u64)),
+        _ => Err(InvalidCar(format!("invalid journal {}", cid)).into()),
+    }
+}
+
+/// Journal of an import. Every committed batch is recorded in a block linking to the
+/// blocks of the batch and to the previous record, and the latest record is aliased, so
+/// the committed blocks stay pinned across restarts until the import completes.
+struct Journal {
+    alias: Vec<u8>,
+    prev: Option<Cid>,
+    offset: u64,
+}
+
+/// Reader counting the bytes read, so that an import knows where it can resume.
+struct Counting<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads an unsigned varint, returning `None` at the end of the stream.
+fn read_varint(reader: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut n = 0u64;
+    for i in 0..10 {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        n |= ((byte[0] & 0x7f) as u64) << (i * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint overflow",
+    ))
+}
+
+fn read_section(reader: &mut impl Read, max: usize) -> Result<Option<Vec<u8>>> {
+    let len = match read_varint(reader)? {
+        Some(len) => len as usize,
+        None => return Ok(None),
+    };
+    if len > max {
+        return Err(InvalidCar(format!("section of {} bytes exceeds the limit", len)).into());
+    }
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+/// Decodes an unpadded upper case RFC4648 base32 string as used by flatfs keys.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buf = buf << 5 | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+pub(crate) struct Importer<'a, P: StoreParams> {
+    storage: &'a StorageService<P>,
+    tmp: TempPin,
+    pending: Vec<Block<P>>,
+    report: ImportReport,
+    journal: Option<Journal>,
+}
+
+impl<'a, P: StoreParams> Importer<'a, P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub fn new(storage: &'a StorageService<P>) -> Result<Self> {
+        Ok(Self {
+            storage,
+            tmp: storage.create_temp_pin()?,
+            pending: Vec::with_capacity(BATCH_SIZE),
+            report: Default::default(),
+            journal: None,
+        })
+    }
+
+    /// Records the committed batches in the journal of the import `id`, resuming from
+    /// the last committed record.
+    fn with_journal(mut self, id: &str) -> Result<Self> {
+        let alias = journal_alias(id);
+        let prev = self.storage.resolve(&alias)?;
+        let offset = journal_offset(self.storage, id)?.unwrap_or_default();
+        self.report.resumed_from = offset;
+        self.journal = Some(Journal {
+            alias,
+            prev,
+            offset,
+        });
+        Ok(self)
+    }
+
+    /// Queues a block for insertion, returning `None` if it doesn't match its hash.
+    /// Imported blocks are temporarily pinned until the import completes.
+    fn insert(&mut self, cid: Cid, data: Vec<u8>) -> Result<Option<Block<P>>> {
+        match Block::<P>::new(cid, data) {
+            Ok(block) => {
+                self.push(block.clone())?;
+                Ok(Some(block))
+            }
+            Err(err) => {
+                tracing::debug!("invalid block {}: {}", cid, err);
+                self.report.invalid_blocks += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Queues a block for insertion.
+    pub fn push(&mut self, block: Block<P>) -> Result<()> {
+        self.pending.push(block);
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Inserts the queued blocks that are not in the store yet.
+    fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let cids = self
+            .pending
+            .iter()
+            .map(|block| *block.cid())
+            .collect::<Vec<_>>();
+        self.storage.temp_pin(&self.tmp, cids.iter().copied())?;
+        let present = self.storage.contains_many(&cids)?;
+        let mut blocks = Vec::with_capacity(self.pending.len());
+        for (block, present) in self.pending.drain(..).zip(present) {
+            let size = block.data().len() as u64;
+            self.report.blocks += 1;
+            if present {
+                self.report.duplicate_blocks += 1;
+                self.report.duplicate_bytes += size;
+            } else {
+                self.report.new_bytes += size;
+                blocks.push(block);
+            }
+        }
+        self.storage.insert_batch(&blocks)?;
+        if let Some(journal) = self.journal.as_mut() {
+            let mut record = BTreeMap::new();
+            record.insert("offset".to_string(), Ipld::Integer(journal.offset as i128));
+            record.insert(
+                "batch".to_string(),
+                Ipld::List(cids.into_iter().map(Ipld::Link).collect()),
+            );
+            if let Some(prev) = journal.prev {
+                record.insert("prev".to_string(), Ipld::Link(prev));
+            }
+            let cid = insert_record(self.storage, &self.tmp, record)?;
+            self.storage
+                .alias_with_ttl(&journal.alias, &cid, JOURNAL_TTL)?;
+            journal.prev = Some(cid);
+        }
+        Ok(())
+    }
+
+    /// Pins the raw blocks `cids` with the `RAW_ALIAS`, in records of `BATCH_SIZE` blocks
+    /// linking to the previous record. The raw blocks of earlier imports stay pinned.
+    fn pin_raw(&mut self, cids: Vec<Cid>) -> Result<()> {
+        self.flush()?;
+        let mut prev = self.storage.resolve(RAW_ALIAS)?;
+        for batch in cids.chunks(BATCH_SIZE) {
+            let mut record = BTreeMap::new();
+            record.insert(
+                "blocks".to_string(),
+                Ipld::List(batch.iter().copied().map(Ipld::Link).collect()),
+            );
+            if let Some(prev) = prev {
+                record.insert("prev".to_string(), Ipld::Link(prev));
+            }
+            prev = Some(insert_record(self.storage, &self.tmp, record)?);
+        }
+        self.storage.alias(RAW_ALIAS, prev.as_ref())
+    }
+
+    /// Completes the import, pinning the `roots` that are in the store. The journal is
+    /// removed, so the imported blocks not reachable from a root can be collected.
+    pub fn pin(mut self, roots: Vec<Cid>) -> Result<ImportReport> {
+        self.flush()?;
+        for root in roots {
+            if self.storage.contains(&root)? {
+                self.storage.alias(&import_alias(&root), Some(&root))?;
+                self.report.pins.push(root);
+            }
+        }
+        if let Some(journal) = self.journal.as_ref() {
+            self.storage.alias(&journal.alias, None)?;
+        }
+        Ok(self.report)
+    }
+}
+
+/// Inserts a dag-cbor `record`, temporarily pinning it with `tmp`.
+fn insert_record<P: StoreParams>(
+    storage: &StorageService<P>,
+    tmp: &TempPin,
+    record: BTreeMap<String, Ipld>,
+) -> Result<Cid>
+where
+    Ipld: References<P::Codecs>,
+{
+    let data = DagCborCodec.encode(&Ipld::StringMap(record))?;
+    let hash = P::Hashes::try_from(SHA2_256)
+        .map_err(|_| UnsupportedMultihash(SHA2_256))?
+        .digest(&data);
+    let block = Block::<P>::new_unchecked(Cid::new_v1(DAG_CBOR, hash), data);
+    storage.temp_pin(tmp, std::iter::once(*block.cid()))?;
+    storage.insert(&block)?;
+    Ok(*block.cid())
+}
+
+/// Imports the blocks of a CAR file and pins its roots.
+///
+/// When a journal `id` is given, the progress is recorded after every batch, and an
+/// import with the same `id` skips the part of the file that was committed already.
+pub(crate) fn import_car<P: StoreParams>(
+    storage: &StorageService<P>,
+    reader: impl Read,
+    journal: Option<&str>,
+) -> Result<ImportReport>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut reader = Counting {
+        inner: reader,
+        position: 0,
+    };
+    let max = P::MAX_BLOCK_SIZE + 128;
+    let header = read_section(&mut reader, max)?.ok_or_else(|| InvalidCar("empty".into()))?;
+    let header: Ipld = DagCborCodec.decode(&header)?;
+    let roots = match header.get("roots") {
+        Ok(Ipld::List(roots)) => roots
+            .iter()
+            .map(|root| match root {
+                Ipld::Link(cid) => Ok(*cid),
+                _ => Err(InvalidCar("root is not a link".into())),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+        _ => return Err(InvalidCar("missing roots".into()).into()),
+    };
+    let mut importer = Importer::new(storage)?;
+    if let Some(id) = journal {
+        importer = importer.with_journal(id)?;
+        let skip = importer.report.resumed_from.saturating_sub(reader.position);
+        let skipped = io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+        if skipped < skip {
+            return Err(InvalidCar("shorter than the committed part of the import".into()).into());
+        }
+    }
+    while let Some(section) = read_section(&mut reader, max)? {
+        let mut cursor = io::Cursor::new(section);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        let start = cursor.position() as usize;
+        let data = cursor.into_inner().split_off(start);
+        if let Some(journal) = importer.journal.as_mut() {
+            journal.offset = reader.position;
+        }
+        importer.insert(cid, data)?;
+    }
+    importer.pin(roots)
+}
+
+/// Lists the blocks of a flatfs datastore by multihash.
+fn flatfs_blocks(repo: &Path) -> Result<FnvHashMap<Multihash, PathBuf>> {
+    let mut blocks = FnvHashMap::default();
+    for shard in std::fs::read_dir(repo.join("blocks"))? {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(shard.path())? {
+            let path = file?.path();
+            let key = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => match name.strip_suffix(".data") {
+                    Some(key) => key.to_string(),
+                    None => continue,
+                },
+                None => continue,
+            };
+            // older repos are keyed by cid, newer ones by multihash.
+            let hash = base32_decode(&key).and_then(|bytes| match Cid::try_from(&bytes[..]) {
+                Ok(cid) => Some(*cid.hash()),
+                Err(_) => Multihash::from_bytes(&bytes).ok(),
+            });
+            match hash {
+                Some(hash) => {
+                    blocks.insert(hash, path);
+                }
+                None => tracing::debug!("skipping {}", path.display()),
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+/// Imports the blocks of a go-ipfs flatfs datastore and pins `pins`.
+///
+/// Flatfs only stores the multihash of a block, so the codecs are recovered from the links
+/// of the pinned dags. Blocks that are not reachable from a pin, and blocks whose codec is
+/// not supported by the store params, are imported as raw blocks and pinned with the
+/// alias `/go-ipfs/raw`. The links of blocks with an unsupported codec can't be followed,
+/// so a pin with an unsupported codec is only imported as a raw block.
+pub(crate) fn import_flatfs<P: StoreParams>(
+    storage: &StorageService<P>,
+    repo: &Path,
+    pins: &[Cid],
+) -> Result<ImportReport>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut blocks = flatfs_blocks(repo)?;
+    let mut importer = Importer::new(storage)?;
+    let mut visited = FnvHashSet::default();
+    let mut stack = pins.to_vec();
+    let mut raw = Vec::new();
+    while let Some(cid) = stack.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let path = match blocks.remove(cid.hash()) {
+            Some(path) => path,
+            None => {
+                if !storage.contains(&cid)? {
+                    importer.report.missing_blocks += 1;
+                }
+                continue;
+            }
+        };
+        let data = std::fs::read(path)?;
+        if P::Codecs::try_from(cid.codec()).is_err() {
+            tracing::debug!(
+                "importing {} as a raw block, its codec is not supported",
+                cid
+            );
+            if let Some(block) = importer.insert(Cid::new_v1(RAW, *cid.hash()), data)? {
+                raw.push(*block.cid());
+            }
+            continue;
+        }
+        if let Some(block) = importer.insert(cid, data)? {
+            if let Err(err) = block.references(&mut stack) {
+                tracing::debug!("failed to read the links of {}: {}", cid, err);
+            }
+        }
+    }
+    for (hash, path) in blocks {
+        if let Some(block) = importer.insert(Cid::new_v1(RAW, hash), std::fs::read(path)?)? {
+            raw.push(*block.cid());
+        }
+    }
+    importer.report.raw_blocks = raw.len();
+    importer.pin_raw(raw)?;
+    importer.pin(pins.to_vec())
+}
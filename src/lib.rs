@@ -10,26 +10,176 @@
 //! ipfs.listen_on("/ip4/0.0.0.0/tcp/0".parse()?).await?;
 //! # Ok(()) }
 //! ```
+use crate::alias_hooks::AliasHooks;
+pub use crate::alias_hooks::{AliasHook, AliasRejected};
+pub use crate::alias_table::InvalidAliasTable;
+#[cfg(feature = "bridge")]
+pub use crate::bridge::{
+    bridge, Bridge, BridgeDirection, BridgedTopic, Broker, NatsBroker, NatsError,
+};
+use crate::channel::ChannelLocks;
+pub use crate::channel::{Channel, ChannelMessage, InvalidChannelMessage};
+pub use crate::checkpoint::InvalidCheckpoint;
+pub use crate::cid::{cid_v0, cid_v1, format_cid, NotCidV0, ToCid};
+pub use crate::cluster::{Cluster, InvalidPinset, NotAWriter, PinOp};
+pub use crate::codecs::{
+    register_codec, CodecExists, CustomCodec, LinksOnly, RegistryCodec, RegistryParams,
+};
+pub use crate::dagbuilder::{
+    build_hamt, hamt_get, Chunker, DagBuilderConfig, InvalidDagBuilderConfig, InvalidHamt,
+    TreeBuilder,
+};
+use crate::decoded::DecodedCache;
+pub use crate::delta::{
+    DeltaNotAuthorized, DeltaTimeout, InvalidDeltaMessage, DELTA_SYNC_PROTOCOL,
+};
+pub use crate::denylist::{parse_denylist, DenylistEntry, InvalidDenylistEntry};
+pub use crate::diagnose::{Check, DiagnoseConfig, DiagnoseReport, NatStatus, TimeSource};
+pub use crate::diff::{DagDiff, PathChange, PathDiff};
+pub use crate::dnslink::{DnsLink, DnsLinkNotFound};
+#[cfg(feature = "encryption")]
+pub use crate::encrypt::{InvalidEncryptedDag, NotARecipient, UnsupportedKey};
+pub use crate::error::Error;
+use crate::events::EventBus;
+pub use crate::events::{EventFilter, EventSubscription, NodeEvent};
+use crate::exchange::{DynExchange, Exchange};
+pub use crate::fetch::{FetchPolicy, FetchTimeout, PartialSync};
+pub use crate::follow::InvalidRoot;
+use crate::follow::{Follows, PublishedRoots};
+pub use crate::identity::{IdentityLink, InvalidIdentityLink};
+pub use crate::import::{import_alias, ImportReport, InvalidCar};
+#[cfg(feature = "otlp")]
+pub use crate::otlp::{otlp_metrics, otlp_tracer, OtlpMetrics};
+pub use crate::path::PathNotFound;
+use crate::presence::Rooms;
+pub use crate::presence::{AlreadyJoined, InvalidBeacon, PresenceConfig, PresenceEvent, Room};
+pub use crate::provenance::{InvalidProvenance, Provenance};
+pub use crate::ready::{ReadyConfig, ReadyReport};
+pub use crate::repair::VerifyReport;
+pub use crate::report::{
+    InvalidReport, MisbehaviourReport, NotAMember, Offence, ReportConfig, Reports,
+};
+use crate::republish::Republisher;
+pub use crate::sample::PeerFilter;
+pub use crate::search::{
+    AliasSearchConfig, InvalidSearchMessage, SearchRejected, ALIAS_SEARCH_PROTOCOL,
+};
+pub use crate::stage::{NoUniqueRoot, StagedDag};
+use crate::tenant::Tenants;
+pub use crate::tenant::{
+    InvalidTenantMessage, InvalidTenantName, ReservedAlias, Tenant, TenantBlockRejected,
+    TENANT_BLOCKS_PROTOCOL,
+};
+pub use crate::traversal::DagStat;
+use crate::validate::Validators;
+pub use crate::validate::{BlockValidator, InvalidBlock};
 use async_trait::async_trait;
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::channel::mpsc;
 use futures::stream::{Stream, StreamExt};
-pub use ipfs_embed_net::SyncEvent;
+#[cfg(feature = "dns-over-https")]
+pub use ipfs_embed_net::DohResolver;
+use ipfs_embed_net::NetworkService;
 pub use ipfs_embed_net::{
-    AddressRecord, AddressSource, Key, Multiaddr, NetworkConfig, PeerId, PeerInfo, PeerRecord,
-    Quorum, Record, SyncQuery,
+    peer_topic, ActivityLevel, AddressRecord, AddressSource, AddressTranslation, AppStream,
+    AuditConfig, AuditKind, AuthTimeout, Authenticator, Backoff, BandwidthLimits, BeaconConfig,
+    BitswapStore, BlockExchange, BlockPolicy, BlockReceiver, BlockRejected, Boxed, CachingResolver,
+    CaptureConfig, CaptureReader, CapturedFrame, DhtDisabled, DhtMode, DnsResolver, ErrorClass,
+    Event, GossipDisabled, Health, Heartbeat, InvalidCapture, Key, Keypair, LimitExceeded,
+    ListenerId, Multiaddr, NatStatus, NetworkConfig, ObservedAddress, PeerId, PeerIdentity,
+    PeerInfo, PeerRecord, PeerStats, PortMapConfig, Priority, PublicKey, Quorum, Record,
+    RendezvousFailure, RendezvousRejected, RetryPolicy, RotationUnsupported, Socks5Config,
+    StreamMuxerBox, SwarmStopped, SyncQuery, SystemResolver, TraversalLimits, TraversalOrder,
+    UnsupportedOrder,
+};
+#[cfg(feature = "trust-dns")]
+pub use ipfs_embed_net::{ResolverConfig, ResolverOpts, TrustDnsResolver};
+pub use ipfs_embed_net::{SyncEvent, SyncStalled, SyncStats};
+use ipfs_embed_rt::Timer;
+use ipfs_embed_sqlite::StorageService;
+pub use ipfs_embed_sqlite::{
+    inline_data, rusqlite, AliasChange, AliasExists, AliasMetaTooLarge, BlockCounts,
+    BlockDistribution, Denied, Durability, FreezeGuard, FreezeTimeout, GcConfig, GcStats,
+    GcTriggers, IndexHook, RecoveryMode, RecoveryReport, SnapshotUnsupported, StorageConfig,
+    StorageEvent, StorageEvents, StoreLocked, StoreReader, StoreStats, SyncGuard, TempPin,
+    UnsupportedSchema, MAX_ALIAS_META_SIZE,
 };
-use ipfs_embed_net::{BitswapStore, NetworkService};
-pub use ipfs_embed_sqlite::{StorageConfig, TempPin};
-use ipfs_embed_sqlite::{StorageEvent, StorageService};
-use libipld::codec::References;
-use libipld::error::BlockNotFound;
+#[cfg(feature = "fault-injection")]
+pub use ipfs_embed_sqlite::{FaultInjector, InjectedFault};
+pub use libipld::cid::multibase::Base;
+use libipld::codec::{Decode, Encode, References};
+use libipld::error::{BlockNotFound, UnsupportedMultihash};
 pub use libipld::store::DefaultParams;
 use libipld::store::{Store, StoreParams};
 use libipld::{Block, Cid, Ipld, Result};
+use parking_lot::Mutex;
 use prometheus::{Encoder, Registry};
+use std::convert::TryFrom;
 use std::future::Future;
+use std::io::{BufRead, Read, Write};
+#[cfg(feature = "telemetry")]
 use std::net::SocketAddr;
+use std::num::NonZeroU16;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod alias_hooks;
+mod alias_table;
+#[cfg(feature = "bridge")]
+mod bridge;
+mod channel;
+mod checkpoint;
+mod cid;
+mod cluster;
+mod codecs;
+mod dagbuilder;
+mod decoded;
+mod delta;
+mod denylist;
+mod diagnose;
+mod diff;
+mod dnslink;
+#[cfg(feature = "encryption")]
+mod encrypt;
+mod error;
+mod events;
+mod exchange;
+mod fetch;
+mod follow;
+mod identity;
+mod import;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod outbox;
+mod path;
+mod peer_stats;
+mod presence;
+mod provenance;
+mod ready;
+mod repair;
+mod report;
+mod republish;
+mod sample;
+mod search;
+mod stage;
+mod tenant;
+mod traversal;
+mod validate;
+
+/// Multicodec code of raw blocks.
+const RAW: u64 = 0x55;
+/// Multicodec code of dag-pb blocks. Their named links are path segments, and cid v0
+/// blocks use it.
+const DAG_PB: u64 = 0x70;
+/// Multicodec code of dag-cbor blocks.
+const DAG_CBOR: u64 = 0x71;
+/// Multihash code of sha2-256.
+const SHA2_256: u64 = 0x12;
+
+/// Maximum number of gossip message ids recorded in a single transaction.
+const DEDUP_BATCH_SIZE: usize = 64;
 
 /// Ipfs configuration.
 #[derive(Clone, Debug)]
@@ -49,6 +199,83 @@ impl Config {
         let network = NetworkConfig::new();
         Self { storage, network }
     }
+
+    /// Configuration of a node serving blocks to many clients. The store is bounded by
+    /// `cache_size_bytes` and collected eagerly, wants are answered with many concurrent
+    /// reads and a high rate per peer, up to 4096 clients can connect and fetches are
+    /// retried to cover slow providers.
+    pub fn gateway(path: Option<std::path::PathBuf>, cache_size_bytes: u64) -> Self {
+        let mut config = Self::new(path, u64::MAX);
+        config.storage.cache_size_bytes = cache_size_bytes;
+        config.storage.gc_interval = Duration::from_secs(60);
+        config.storage.gc_min_blocks = 10_000;
+        config.storage.gc_target_duration = Duration::from_millis(500);
+        config.storage.gc_triggers.size_percent = Some(90);
+        config.storage.auto_compact_free_pages = Some(10_000);
+        config.network.enable_mdns = false;
+        config.network.bitswap_request_timeout = Duration::from_secs(30);
+        config.network.bitswap_receive_limit = NonZeroU16::new(200).expect("200 > 0");
+        config.network.bitswap_background_wants = 128;
+        config.network.bitswap_serve_reads = 64;
+        config.network.bitswap_serve_peer_rate = 256;
+        config.network.max_incoming_connections = Some(4096);
+        config.network.retry_policy = RetryPolicy::exponential(3);
+        config
+    }
+
+    /// Configuration of a node keeping every block it receives. The gc only runs to
+    /// delete orphaned blocks, a corrupted store is salvaged instead of failing and the
+    /// stored blocks are reprovided twice as often as the default. Wants are answered
+    /// with more concurrent reads than the default, from up to 1024 peers.
+    pub fn archival(path: Option<std::path::PathBuf>) -> Self {
+        let mut config = Self::new(path, u64::MAX);
+        config.storage.gc_interval = Duration::from_secs(60 * 60);
+        config.storage.recovery_mode = RecoveryMode::Salvage;
+        config.network.enable_mdns = false;
+        config.network.bitswap_request_timeout = Duration::from_secs(60);
+        config.network.bitswap_background_wants = 256;
+        config.network.bitswap_serve_reads = 32;
+        config.network.bitswap_serve_peer_rate = 128;
+        config.network.max_incoming_connections = Some(1024);
+        config.network.retry_policy = RetryPolicy::exponential(5);
+        config.network.republish_interval = Duration::from_secs(60 * 60 * 6);
+        config
+    }
+
+    /// Configuration of a small node close to its users. The store is bounded by
+    /// `cache_size_bytes` and the gc runs whenever the store or the free disk space runs
+    /// low. Requests time out quickly, and the cached blocks are reprovided rarely since
+    /// they come and go. Wants are answered with few concurrent reads, from up to 256
+    /// peers.
+    pub fn edge_cache(path: Option<std::path::PathBuf>, cache_size_bytes: u64) -> Self {
+        let mut config = Self::new(path, u64::MAX);
+        config.storage.cache_size_bytes = cache_size_bytes;
+        config.storage.gc_interval = Duration::from_secs(30);
+        config.storage.gc_min_blocks = 1000;
+        config.storage.gc_target_duration = Duration::from_millis(100);
+        config.storage.gc_triggers.size_percent = Some(90);
+        config.storage.gc_triggers.min_free_disk = Some(1024 * 1024 * 512);
+        config.storage.auto_compact_free_pages = Some(1000);
+        config.network.bitswap_request_timeout = Duration::from_secs(5);
+        config.network.bitswap_receive_limit = NonZeroU16::new(50).expect("50 > 0");
+        config.network.bitswap_serve_reads = 4;
+        config.network.bitswap_serve_peer_rate = 32;
+        config.network.max_incoming_connections = Some(256);
+        config.network.retry_policy = RetryPolicy::exponential(2);
+        config.network.republish_interval = Duration::from_secs(60 * 60 * 24);
+        config
+    }
+}
+
+/// Blocks pushed by a peer. The blocks are kept in the block store until the temporary
+/// pin is dropped, giving the application a chance to accept them by aliasing them.
+pub struct PushedBlocks {
+    /// The peer that pushed the blocks.
+    pub peer: PeerId,
+    /// The pushed blocks.
+    pub blocks: Vec<Cid>,
+    /// Temporary pin keeping the blocks alive.
+    pub tmp: TempPin,
 }
 
 /// Ipfs node.
@@ -56,9 +283,37 @@ impl Config {
 pub struct Ipfs<P: StoreParams> {
     storage: StorageService<P>,
     network: NetworkService<P>,
+    exchange: Exchange<P>,
+    republisher: Republisher<P>,
+    pushed: Arc<Mutex<Option<mpsc::UnboundedSender<PushedBlocks>>>>,
+    events: EventBus,
+    validators: Validators<P>,
+    tenants: Tenants,
+    alias_table: Arc<Mutex<Option<(u64, Cid, TempPin)>>>,
+    decoded: Arc<Mutex<DecodedCache>>,
+    rooms: Rooms,
+    follows: Follows,
+    published_roots: PublishedRoots,
+    channel_locks: ChannelLocks,
+    alias_hooks: AliasHooks,
+    /// Time the first successful bootstrap of `ready` took.
+    bootstrapped: Arc<Mutex<Option<Duration>>>,
 }
 
-struct BitswapStorage<P: StoreParams>(StorageService<P>);
+/// The block store of a node as a `BitswapStore`, for exchanging its blocks with a custom
+/// network. Denied and tenant blocks aren't served, and received blocks are validated.
+#[derive(Clone)]
+pub struct BitswapStorage<P: StoreParams>(StorageService<P>, Validators<P>, Tenants);
+
+impl<P: StoreParams> BitswapStorage<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    /// Returns `true` if the block is only reachable from tenant aliases.
+    fn is_private(&self, cid: &Cid) -> Result<bool> {
+        self.2.lock().is_private(&self.0, cid)
+    }
+}
 
 impl<P: StoreParams> BitswapStore for BitswapStorage<P>
 where
@@ -67,14 +322,25 @@ where
     type Params = P;
 
     fn contains(&mut self, cid: &Cid) -> Result<bool> {
-        self.0.contains(cid)
+        // missing blocks are answered by the have filter without a query.
+        if self.0.is_denied(cid) || !self.0.contains(cid)? {
+            return Ok(false);
+        }
+        Ok(!self.is_private(cid)?)
     }
 
     fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        self.0.get(cid)
+        if self.0.is_denied(cid) {
+            return Ok(None);
+        }
+        match self.0.get(cid)? {
+            Some(data) if !self.is_private(cid)? => Ok(Some(data)),
+            _ => Ok(None),
+        }
     }
 
     fn insert(&mut self, block: &Block<P>) -> Result<()> {
+        self.1.validate(ipfs_embed_net::bitswap_sender(), block)?;
         self.0.insert(block)
     }
 
@@ -89,21 +355,242 @@ where
 {
     /// Creates a new `Ipfs` from a `Config`.
     ///
-    /// This starts three background tasks. The swarm, garbage collector and the dht cleanup
-    /// tasks run in the background.
-    pub async fn new(config: Config) -> Result<Self> {
-        let (tx, mut storage_events) = mpsc::unbounded();
-        let storage = StorageService::open(config.storage, tx)?;
-        let bitswap = BitswapStorage(storage.clone());
-        let network = NetworkService::new(config.network, bitswap).await?;
+    /// This starts four background tasks. The swarm, garbage collector, dht cleanup and
+    /// republish tasks run in the background. Without any of the `dht`, `gossip` and
+    /// `mdns` features the swarm can't listen or dial, so the node only serves its block
+    /// store.
+    pub async fn new(config: Config) -> Result<Self, Error> {
+        Self::build(config, None, None).await
+    }
+
+    /// Creates a new `Ipfs` running the swarm over a custom `transport`, for example to
+    /// connect peers over bluetooth or a serial link. The transport needs to authenticate
+    /// and multiplex connections itself, and the `psk` and `bandwidth_limits` of the
+    /// network config are not applied to it.
+    pub async fn with_transport(
+        config: Config,
+        transport: Boxed<(PeerId, StreamMuxerBox)>,
+    ) -> Result<Self, Error> {
+        Self::build(config, Some(transport), None).await
+    }
+
+    /// Creates a new `Ipfs` that requests and provides blocks through `exchange` instead
+    /// of bitswap, for example to exchange blocks over custom networking. The swarm still
+    /// runs for the other protocols and still answers the bitswap wants of peers. Failed
+    /// fetches are retried according to `NetworkConfig::retry_policy`.
+    pub async fn with_exchange<E>(config: Config, exchange: E) -> Result<Self, Error>
+    where
+        E: BlockExchange<P>,
+        E::Want: 'static,
+        E::Sync: 'static,
+        E::Provide: 'static,
+    {
+        Self::build(config, None, Some(Arc::new(exchange))).await
+    }
+
+    async fn build(
+        config: Config,
+        transport: Option<Boxed<(PeerId, StreamMuxerBox)>>,
+        exchange: Option<Arc<dyn DynExchange<P>>>,
+    ) -> Result<Self, Error> {
+        let verify_interval = config.storage.verify_interval;
+        let alias_expiry_interval = config.storage.alias_expiry_interval;
+        let peer_stats_interval = config.storage.peer_stats_interval;
+        let storage = StorageService::open(config.storage)?;
+        // starts at the first event, so that blocks removed before the task below is
+        // spawned are cleaned up as well.
+        let mut storage_events = storage.subscribe_events_from(0);
+        let events = EventBus::default();
+        let (bans, mut banned) = mpsc::unbounded();
+        let validators = Validators::new(events.clone(), bans);
+        let tenants = Tenants::default();
+        let bitswap = BitswapStorage(storage.clone(), validators.clone(), tenants.clone());
+        let republish_interval = config.network.republish_interval;
+        let republish_jitter = config.network.republish_jitter;
+        let retry = config.network.retry_policy.clone();
+        let network = match transport {
+            Some(transport) => NetworkService::with_transport(config.network, transport, bitswap)
+                .await
+                .map_err(Error::network)?,
+            #[cfg(any(feature = "dht", feature = "gossip", feature = "mdns"))]
+            None => NetworkService::new(config.network, bitswap)
+                .await
+                .map_err(Error::network)?,
+            #[cfg(not(any(feature = "dht", feature = "gossip", feature = "mdns")))]
+            None => NetworkService::offline(config.network, bitswap)
+                .await
+                .map_err(Error::network)?,
+        };
+        let exchange = match exchange {
+            Some(exchange) => Exchange::Custom {
+                exchange,
+                network: network.clone(),
+                retry,
+            },
+            None => Exchange::Network(network.clone()),
+        };
+        let republisher = Republisher::new(
+            storage.clone(),
+            network.clone(),
+            exchange.clone(),
+            republish_interval,
+            republish_jitter,
+        );
+        let events2 = events.clone();
+        let mut swarm_events = network.swarm_events();
+        ipfs_embed_rt::spawn(async move {
+            while let Some(event) = swarm_events.next().await {
+                events2.publish(NodeEvent::Swarm(event));
+            }
+        })
+        .detach();
         let network2 = network.clone();
-        async_global_executor::spawn(async move {
-            while let Some(StorageEvent::Remove(cid)) = storage_events.next().await {
+        let storage2 = storage.clone();
+        let republisher2 = republisher.clone();
+        let events2 = events.clone();
+        let decoded = Arc::new(Mutex::new(DecodedCache::default()));
+        let decoded2 = decoded.clone();
+        ipfs_embed_rt::spawn(async move {
+            while let Some(event) = storage_events.next().await {
+                let cid = match event {
+                    StorageEvent::Remove(cid) => cid,
+                    StorageEvent::Lagged(missed) => {
+                        // the missed removals are recovered from the store, so that provider
+                        // records of evicted blocks aren't republished.
+                        tracing::warn!("missed {} storage events", missed);
+                        decoded2.lock().clear();
+                        if let Err(err) = republisher2.reconcile() {
+                            tracing::warn!("failed to reconcile provider records: {}", err);
+                        }
+                        continue;
+                    }
+                };
+                decoded2.lock().remove(&cid);
+                events2.publish(NodeEvent::Evicted(cid));
                 network2.unprovide(cid);
+                if let Err(err) = republisher2.remove(&cid.to_bytes()) {
+                    tracing::warn!("failed to remove provider record: {}", err);
+                }
+                if let Err(err) = storage2.remove_provenance(&cid) {
+                    tracing::warn!("failed to remove provenance: {}", err);
+                }
+            }
+        })
+        .detach();
+        ipfs_embed_rt::spawn(republisher.clone().run()).detach();
+        if let Some(interval) = verify_interval {
+            let task = repair::run(storage.clone(), network.clone(), events.clone(), interval);
+            ipfs_embed_rt::spawn(task).detach();
+        }
+        let network2 = network.clone();
+        ipfs_embed_rt::spawn(async move {
+            while let Some(peer) = banned.next().await {
+                network2.ban(peer);
+            }
+        })
+        .detach();
+        let pushed = Arc::new(Mutex::new(None));
+        let pushed2 = pushed.clone();
+        let storage3 = storage.clone();
+        let validators2 = validators.clone();
+        let mut pushes = network.pushed();
+        ipfs_embed_rt::spawn(async move {
+            while let Some((peer, blocks)) = pushes.next().await {
+                match store_pushed(&storage3, &validators2, peer, &blocks) {
+                    Ok(pushed) => {
+                        let mut tx = pushed2.lock();
+                        if let Some(ch) = tx.as_ref() {
+                            if ch.unbounded_send(pushed).is_err() {
+                                *tx = None;
+                            }
+                        }
+                    }
+                    Err(err) => tracing::warn!("failed to store pushed blocks: {}", err),
+                }
+            }
+        })
+        .detach();
+        let heartbeat = network
+            .health()
+            .paced_heartbeat("gc", storage.gc_config().interval);
+        storage.set_gc_heartbeat(move |next| {
+            heartbeat.set_interval(next);
+            heartbeat.beat();
+        });
+        let storage4 = storage.clone();
+        let heartbeat = network
+            .health()
+            .paced_heartbeat("alias_expiry", alias_expiry_interval);
+        let activity = network.activity();
+        ipfs_embed_rt::spawn(async move {
+            loop {
+                activity.sleep(alias_expiry_interval).await;
+                heartbeat.beat();
+                match storage4.expire_aliases() {
+                    Ok(0) => {}
+                    Ok(n) => tracing::debug!("removed {} expired aliases", n),
+                    Err(err) => tracing::warn!("failed to remove expired aliases: {}", err),
+                }
             }
         })
         .detach();
-        Ok(Self { storage, network })
+        if storage.outbox_ttl().is_some() {
+            let task = outbox::run(storage.clone(), network.clone(), network.swarm_events());
+            ipfs_embed_rt::spawn(task).detach();
+        }
+        if let Some(interval) = peer_stats_interval {
+            let saved = peer_stats::restore(&storage, &network)?;
+            let heartbeat = network.health().paced_heartbeat("peer_stats", interval);
+            let task =
+                peer_stats::run(storage.clone(), network.clone(), saved, interval, heartbeat);
+            ipfs_embed_rt::spawn(task).detach();
+        }
+        Ok(Self {
+            storage,
+            network,
+            exchange,
+            republisher,
+            pushed,
+            events,
+            validators,
+            tenants,
+            alias_table: Default::default(),
+            decoded,
+            rooms: Default::default(),
+            follows: Default::default(),
+            published_roots: Default::default(),
+            channel_locks: Default::default(),
+            alias_hooks: AliasHooks::new(),
+            bootstrapped: Default::default(),
+        })
+    }
+
+    /// Changes the activity level of the node, for example when the host application is
+    /// moved to the background. In the background the periodic work of the node, like
+    /// reproviding, dht lookups of followed roots, presence and lan beacons, rendezvous
+    /// registrations, gossipsub heartbeats, kademlia jobs and garbage collection, runs
+    /// four times less often. While suspended it is paused, and it resumes when the node
+    /// is activated. Messages and requests of peers are still handled right away, and
+    /// the paused tasks aren't reported as stale by the `Health` of the node.
+    pub fn set_activity_level(&self, level: ActivityLevel) {
+        self.network.set_activity_level(level);
+        self.storage.throttle_gc(level.factor());
+    }
+
+    /// Returns the activity level of the node.
+    pub fn activity_level(&self) -> ActivityLevel {
+        self.network.activity().level()
+    }
+
+    /// Sets the upload and download limits of all connections.
+    pub fn set_bandwidth_limits(&self, limits: BandwidthLimits) {
+        self.network.set_bandwidth_limits(limits)
+    }
+
+    /// Sets the upload and download limits of connections to `peer`. `None` removes the
+    /// limits.
+    pub fn set_peer_bandwidth_limits(&self, peer: PeerId, limits: Option<BandwidthLimits>) {
+        self.network.set_peer_bandwidth_limits(peer, limits)
     }
 
     /// Returns the local `PeerId`.
@@ -112,8 +599,54 @@ where
     }
 
     /// Listens on a new `Multiaddr`.
-    pub async fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr> {
-        self.network.listen_on(addr).await
+    pub async fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr, Error> {
+        self.network.listen_on(addr).await.map_err(Error::network)
+    }
+
+    /// Starts listening on a new `Multiaddr` without waiting for the address to be bound.
+    /// If the listener closes with an error, it is rebound with exponential backoff.
+    pub fn add_listener(&self, addr: Multiaddr) -> Result<ListenerId, Error> {
+        self.network.add_listener(addr).map_err(Error::network)
+    }
+
+    /// Removes a listener. Returns `false` if the listener doesn't exist.
+    pub fn remove_listener(&self, id: ListenerId) -> bool {
+        self.network.remove_listener(id)
+    }
+
+    /// Returns a `Stream` of swarm events.
+    pub fn swarm_events(&self) -> impl Stream<Item = Event> {
+        self.network.swarm_events()
+    }
+
+    /// Subscribes to the events matching `filter`. Every subscriber has a bounded buffer,
+    /// when it fills up further events are dropped and counted by the subscription.
+    pub fn subscribe_events(&self, filter: EventFilter) -> EventSubscription {
+        self.events.subscribe(filter)
+    }
+
+    /// Registers a validator that runs before blocks received from peers are inserted.
+    /// Blocks rejected by any validator are not stored, and a `NodeEvent::InvalidBlock`
+    /// is emitted. Peers sending too many invalid blocks are banned, see
+    /// `set_invalid_block_threshold`. Blocks inserted locally are not validated.
+    pub fn add_block_validator<F>(&self, validator: F)
+    where
+        F: Fn(&Block<P>) -> Result<()> + Send + Sync + 'static,
+    {
+        self.validators.add(Arc::new(validator))
+    }
+
+    /// Returns the number of blocks sent by `peer` that were rejected by a validator.
+    /// Blocks bitswap inserts outside of handling the message of a peer are not
+    /// attributed to a peer.
+    pub fn invalid_blocks(&self, peer: &PeerId) -> u64 {
+        self.validators.invalid_blocks(peer)
+    }
+
+    /// Bans peers once `threshold` blocks they sent were rejected by a validator. Defaults
+    /// to 3, `None` never bans peers.
+    pub fn set_invalid_block_threshold(&self, threshold: Option<u64>) {
+        self.validators.set_ban_threshold(threshold)
     }
 
     /// Returns the currently active listener addresses.
@@ -121,16 +654,61 @@ where
         self.network.listeners()
     }
 
+    /// Resolves when a listener starts listening on a new address. Listeners added with
+    /// `add_listener` are bound in the background, so call this before adding the listener
+    /// to get its address once the socket is bound.
+    pub fn next_listen_addr(&self) -> impl Future<Output = Result<Multiaddr, Error>> {
+        let addr = self.network.next_listen_addr();
+        async move { addr.await.map_err(Error::network) }
+    }
+
+    /// Resolves once the node listens on at least `n` addresses, returning the listen
+    /// addresses. A listener on an unspecified address like `0.0.0.0` listens on an
+    /// address per network interface.
+    pub fn wait_for_listeners(
+        &self,
+        n: usize,
+    ) -> impl Future<Output = Result<Vec<Multiaddr>, Error>> {
+        let listeners = self.network.wait_for_listeners(n);
+        async move { listeners.await.map_err(Error::network) }
+    }
+
     /// Adds an external address.
     pub fn add_external_address(&self, addr: Multiaddr) {
         self.network.add_external_address(addr)
     }
 
-    /// Returns the currently used external addresses.
+    /// Returns the currently used external addresses and their scores. Addresses observed
+    /// by `NetworkConfig::external_address_confidence` peers are added with the number of
+    /// peers as their score, and removed once their votes expire.
     pub fn external_addresses(&self) -> Vec<AddressRecord> {
         self.network.external_addresses()
     }
 
+    /// Removes the ports mapped with `NetworkConfig::port_mapping` from the gateway. Call
+    /// this before stopping the node.
+    pub async fn remove_port_mappings(&self) {
+        self.network.remove_port_mappings().await
+    }
+
+    /// Returns the addresses peers observed the node at and their votes, for example to
+    /// pick the address to put in an invite.
+    pub fn observed_addresses(&self) -> Vec<ObservedAddress> {
+        self.network.observed_addresses()
+    }
+
+    /// Returns the mode the dht is currently operating in, `None` if kad is disabled. In
+    /// `DhtMode::Auto` the node starts as a server and is demoted to a client when peers
+    /// fail to dial it back.
+    pub fn dht_mode(&self) -> Option<DhtMode> {
+        self.network.dht_mode()
+    }
+
+    /// Returns whether peers can dial the node, `None` if kad is disabled.
+    pub fn nat_status(&self) -> Option<NatStatus> {
+        self.network.nat_status()
+    }
+
     /// Adds a known `Multiaddr` for a `PeerId`.
     pub fn add_address(&self, peer: &PeerId, addr: Multiaddr) {
         self.network.add_address(peer, addr)
@@ -142,14 +720,14 @@ where
     }
 
     /// Dials a `PeerId` using a known address.
-    pub fn dial(&self, peer: &PeerId) -> Result<()> {
-        self.network.dial(peer)
+    pub fn dial(&self, peer: &PeerId) -> Result<(), Error> {
+        self.network.dial(peer).map_err(Error::network)
     }
 
     /// Dials a `PeerId` using `Multiaddr`.
-    pub fn dial_address(&self, peer: &PeerId, addr: Multiaddr) -> Result<()> {
+    pub fn dial_address(&self, peer: &PeerId, addr: Multiaddr) -> Result<(), Error> {
         self.network.add_address(peer, addr);
-        self.network.dial(peer)
+        self.network.dial(peer).map_err(Error::network)
     }
 
     /// Bans a `PeerId` from the swarm, dropping all existing connections and
@@ -163,6 +741,18 @@ where
         self.network.unban(peer)
     }
 
+    /// Installs an authenticator that decides which peers may stay connected once they
+    /// identified themselves. Replaces the previously installed authenticator.
+    pub fn set_authenticator<A: Authenticator>(&self, authenticator: A) {
+        self.network
+            .set_authenticator(Some(Arc::new(authenticator)))
+    }
+
+    /// Removes the authenticator, accepting all peers again.
+    pub fn remove_authenticator(&self) {
+        self.network.set_authenticator(None)
+    }
+
     /// Returns the known peers.
     pub fn peers(&self) -> Vec<PeerId> {
         self.network.peers()
@@ -178,269 +768,4289 @@ where
         self.network.peer_info(peer)
     }
 
+    /// Returns the moving average of the rtt measured by pinging the peer.
+    pub fn peer_rtt(&self, peer: &PeerId) -> Option<Duration> {
+        self.network.peer_rtt(peer)
+    }
+
+    /// Returns the statistics of the known peers. When `StorageConfig::peer_stats_interval`
+    /// is set they include the statistics learned before the node was restarted.
+    pub fn peer_stats(&self) -> Vec<(PeerId, PeerStats)> {
+        self.network.peer_stats()
+    }
+
+    /// Returns up to `n` peers matching the `filter`, chosen uniformly at random, for
+    /// example to pick the partners of a round of an epidemic protocol.
+    pub fn sample_peers(&self, n: usize, filter: &PeerFilter) -> Vec<PeerId> {
+        let candidates = if filter.connected {
+            self.connections()
+                .into_iter()
+                .map(|(peer, _)| peer)
+                .collect()
+        } else {
+            self.peers()
+        };
+        let stats = self.peer_stats().into_iter().collect();
+        sample::sample(candidates, n, filter, |peer| self.peer_info(peer), &stats)
+    }
+
     /// Bootstraps the dht using a set of bootstrap nodes. After bootstrap completes it
     /// provides all blocks in the block store.
-    pub async fn bootstrap(&self, nodes: &[(PeerId, Multiaddr)]) -> Result<()> {
-        self.network.bootstrap(nodes).await?;
+    pub async fn bootstrap(&self, nodes: &[(PeerId, Multiaddr)]) -> Result<(), Error> {
+        self.network
+            .bootstrap(nodes)
+            .await
+            .map_err(Error::network)?;
         for cid in self.storage.iter()? {
             let _ = self.network.provide(cid);
         }
         Ok(())
     }
 
+    /// Resolves the dnslink of `domain` by looking up the TXT records of
+    /// `_dnslink.<domain>` with the `NetworkConfig::dns_resolver`. Only
+    /// `dnslink=/ipfs/<cid>` records are supported.
+    pub async fn resolve_dnslink(&self, domain: &str) -> Result<DnsLink, Error> {
+        let resolver = self.network.dns_resolver();
+        dnslink::resolve(&*resolver, domain)
+            .await
+            .map_err(Error::network)
+    }
+
+    /// Resolves the dnslink of `domain`, points `alias` at the block its path points to
+    /// and syncs the dag below it. The blocks on the path are fetched, but the rest of the
+    /// dag of the root isn't synced.
+    pub async fn sync_dnslink<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        domain: &str,
+        alias: T,
+    ) -> Result<DnsLink, Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        let link = self.resolve_dnslink(domain).await?;
+        let target = self.fetch_path(&link.cid, &link.path).await?;
+        self.alias(alias, Some(&target))?;
+        self.sync(&target).await.map_err(Error::network)?;
+        Ok(link)
+    }
+
+    /// Returns the block `path` points to starting at `root`, fetching the blocks on the
+    /// path that are missing from the block store.
+    async fn fetch_path(&self, root: &Cid, path: &str) -> Result<Cid, Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        let limits = TraversalLimits::unlimited();
+        loop {
+            let missing = std::cell::Cell::new(None);
+            let get = |cid: &Cid| {
+                let res = self.path_node(cid);
+                if let Err(err) = &res {
+                    if err.downcast_ref::<BlockNotFound>().is_some() {
+                        missing.set(Some(*cid));
+                    }
+                }
+                Ok(res?)
+            };
+            match path::resolve_link(get, root, path, &limits) {
+                Ok(cid) => return Ok(cid),
+                Err(err) => match missing.take() {
+                    Some(cid) => {
+                        self.fetch(&cid).await?;
+                    }
+                    None => return Err(err.into()),
+                },
+            }
+        }
+    }
+
+    /// Resolves once the store answers queries, the node listens on
+    /// `ReadyConfig::listeners` addresses and the dht is bootstrapped from the
+    /// `ReadyConfig::boot_nodes`. Steps that fail or time out are recorded in the report
+    /// instead of failing the future, so it can back a readiness endpoint directly.
+    ///
+    /// The dht is only bootstrapped until a bootstrap succeeds, later calls report the
+    /// time the successful bootstrap took.
+    pub async fn ready(&self, config: &ReadyConfig) -> ReadyReport {
+        ready::ready(self, config).await
+    }
+
+    /// Runs a set of network self tests and returns a report for troubleshooting. Checks
+    /// that listeners are bound, infers the nat status, connects to the boot nodes,
+    /// bootstraps the dht, sends a bitswap request to the echo peer and measures the
+    /// clock skew.
+    pub async fn diagnose(&self, config: &DiagnoseConfig) -> DiagnoseReport {
+        diagnose::diagnose(&self.network, config).await
+    }
+
     /// Gets a record from the dht.
-    pub async fn get_record(&self, key: &Key, quorum: Quorum) -> Result<Vec<PeerRecord>> {
-        self.network.get_record(key, quorum).await
+    pub async fn get_record(&self, key: &Key, quorum: Quorum) -> Result<Vec<PeerRecord>, Error> {
+        self.network
+            .get_record(key, quorum)
+            .await
+            .map_err(Error::network)
     }
 
-    /// Puts a new record in the dht.
-    pub async fn put_record(&self, record: Record, quorum: Quorum) -> Result<()> {
-        self.network.put_record(record, quorum).await
+    /// Puts a new record in the dht. The record is republished until it expires or is
+    /// removed.
+    pub async fn put_record(&self, record: Record, quorum: Quorum) -> Result<(), Error> {
+        self.network
+            .put_record(record.clone(), quorum)
+            .await
+            .map_err(Error::network)?;
+        self.republisher.put(&record).map_err(Error::store)
     }
 
     /// Removes a record from the dht.
     pub fn remove_record(&self, key: &Key) {
-        self.network.remove_record(key)
+        self.network.remove_record(key);
+        if let Err(err) = self.republisher.remove(&key.to_vec()) {
+            tracing::warn!("failed to remove record: {}", err);
+        }
+    }
+
+    /// Registers the node under `namespace` at the rendezvous `point`. Returns the ttl
+    /// granted by the rendezvous point.
+    pub async fn rendezvous_register(
+        &self,
+        point: &PeerId,
+        namespace: &str,
+        ttl: Duration,
+    ) -> Result<Duration, Error> {
+        self.network
+            .rendezvous_register(point, namespace, ttl)
+            .await
+            .map_err(Error::network)
+    }
+
+    /// Removes the registration under `namespace` at the rendezvous `point`.
+    pub async fn rendezvous_unregister(
+        &self,
+        point: &PeerId,
+        namespace: &str,
+    ) -> Result<(), Error> {
+        self.network
+            .rendezvous_unregister(point, namespace)
+            .await
+            .map_err(Error::network)
+    }
+
+    /// Discovers the peers registered under `namespace` at the rendezvous `point` and adds
+    /// their addresses to the address book.
+    pub async fn rendezvous_discover(
+        &self,
+        point: &PeerId,
+        namespace: &str,
+    ) -> Result<Vec<(PeerId, Vec<Multiaddr>)>, Error> {
+        self.network
+            .rendezvous_discover(point, namespace)
+            .await
+            .map_err(Error::network)
+    }
+
+    /// Opens a stream of an application `protocol` to a peer, dialing the peer if it isn't
+    /// connected. The stream is multiplexed over the connection used by the node.
+    ///
+    /// This is experimental and may change in future releases.
+    pub async fn open_stream(&self, peer: &PeerId, protocol: &str) -> Result<AppStream, Error> {
+        self.network
+            .open_stream(peer, protocol)
+            .await
+            .map_err(Error::network)
+    }
+
+    /// Returns a `Stream` of inbound streams of an application `protocol`. Only the most
+    /// recently returned `Stream` receives new streams.
+    ///
+    /// This is experimental and may change in future releases.
+    pub fn listen_streams(
+        &self,
+        protocol: &str,
+    ) -> Result<impl Stream<Item = (PeerId, AppStream)>, Error> {
+        self.network
+            .listen_streams(protocol)
+            .map_err(Error::network)
+    }
+
+    /// Answers the alias searches of the peers in `config` with the aliases starting with
+    /// `config.prefix`, using the application protocol `ALIAS_SEARCH_PROTOCOL`.
+    pub fn serve_alias_search(&self, config: AliasSearchConfig) -> Result<(), Error> {
+        let streams = self.listen_streams(ALIAS_SEARCH_PROTOCOL)?;
+        ipfs_embed_rt::spawn(search::serve(self.clone(), config, streams)).detach();
+        Ok(())
+    }
+
+    /// Serves the blocks of the tenant dags to the members of the tenants, using the
+    /// application protocol `TENANT_BLOCKS_PROTOCOL`. See `Tenant::fetch`.
+    pub fn serve_tenant_blocks(&self) -> Result<(), Error> {
+        let streams = self.listen_streams(TENANT_BLOCKS_PROTOCOL)?;
+        ipfs_embed_rt::spawn(tenant::serve(self.clone(), streams)).detach();
+        Ok(())
+    }
+
+    /// Asks `peer` for its aliases starting with `prefix` and their roots. Fails with
+    /// `SearchRejected` if the peer doesn't share them with the local node.
+    pub async fn search_aliases<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        peer: &PeerId,
+        prefix: T,
+    ) -> Result<Vec<(Vec<u8>, Cid)>, Error> {
+        let stream = self.open_stream(peer, ALIAS_SEARCH_PROTOCOL).await?;
+        search::search(*peer, stream, prefix.as_ref())
+            .await
+            .map_err(Error::network)
+    }
+
+    /// Searches the aliases starting with `prefix` of all connected peers. Peers that
+    /// fail or reject the search are skipped.
+    pub async fn search_swarm<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        prefix: T,
+    ) -> Vec<(PeerId, Vec<(Vec<u8>, Cid)>)> {
+        let prefix = prefix.as_ref();
+        let searches = self.peers().into_iter().map(|peer| async move {
+            match self.search_aliases(&peer, prefix).await {
+                Ok(aliases) => Some((peer, aliases)),
+                Err(err) => {
+                    tracing::debug!("alias search of {} failed: {}", peer, err);
+                    None
+                }
+            }
+        });
+        futures::future::join_all(searches)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Answers the delta syncs of peers using the application protocol
+    /// `DELTA_SYNC_PROTOCOL`. Blocks are shared under the same rules as with bitswap:
+    /// peers need to be authenticated, and dags private to a tenant aren't served.
+    pub fn serve_delta_sync(&self) -> Result<(), Error> {
+        let streams = self.listen_streams(DELTA_SYNC_PROTOCOL)?;
+        ipfs_embed_rt::spawn(delta::serve(self.clone(), streams)).detach();
+        Ok(())
+    }
+
+    /// Syncs the dag rooted at `root` from `peer`, which needs to serve delta syncs.
+    /// Instead of walking the dag block by block, a bloom filter of the blocks already
+    /// in the block store is sent to `peer`, which answers with the blocks missing from
+    /// the filter. Blocks skipped because of false positives are synced afterwards.
+    /// Returns the number of blocks received in the exchange.
+    pub async fn sync_delta(&self, peer: &PeerId, root: impl ToCid) -> Result<usize, Error> {
+        let root = &root.to_cid()?;
+        let (filter, missing) = delta::have_set(self, root).map_err(Error::store)?;
+        if missing.is_empty() {
+            return Ok(0);
+        }
+        let stream = self.open_stream(peer, DELTA_SYNC_PROTOCOL).await?;
+        let received = delta::request(self, *peer, stream, root, filter, missing)
+            .await
+            .map_err(Error::network)?;
+        self.sync(root).await.map_err(Error::network)?;
+        Ok(received)
     }
 
     /// Subscribes to a `topic` returning a `Stream` of messages. If all `Stream`s for
     /// a topic are dropped it unsubscribes from the `topic`.
-    pub fn subscribe(&self, topic: &str) -> Result<impl Stream<Item = Vec<u8>>> {
-        self.network.subscribe(topic)
+    pub fn subscribe(&self, topic: &str) -> Result<impl Stream<Item = Vec<u8>>, Error> {
+        self.network.subscribe(topic).map_err(Error::network)
+    }
+
+    /// Like `subscribe`, but remembers the ids of received messages in the store for
+    /// `StorageConfig::gossip_dedup_ttl`. Messages replayed by peers after a restart are
+    /// not delivered again, which matters for topics driving replication.
+    pub fn subscribe_deduplicated(
+        &self,
+        topic: &str,
+    ) -> Result<impl Stream<Item = Vec<u8>>, Error> {
+        let messages = self
+            .network
+            .subscribe_with_ids(topic)
+            .map_err(Error::network)?;
+        let storage = self.storage.clone();
+        let topic = topic.to_string();
+        Ok(messages
+            .ready_chunks(DEDUP_BATCH_SIZE)
+            .then(move |batch| {
+                let storage = storage.clone();
+                let topic = topic.clone();
+                async move {
+                    // the ids of the messages received meanwhile are recorded in a single
+                    // transaction off the executor.
+                    let ids = batch.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+                    let res =
+                        ipfs_embed_rt::spawn_blocking(move || storage.mark_seen(&topic, &ids))
+                            .await;
+                    let fresh = res.unwrap_or_else(|err| {
+                        tracing::warn!("failed to record gossip messages: {}", err);
+                        vec![true; batch.len()]
+                    });
+                    let messages = batch
+                        .into_iter()
+                        .zip(fresh)
+                        .filter(|(_, fresh)| *fresh)
+                        .map(|((_, data), _)| data);
+                    futures::stream::iter(messages)
+                }
+            })
+            .flatten())
     }
 
     /// Publishes a new message in a `topic`, sending the message to all subscribed peers.
-    pub fn publish(&self, topic: &str, msg: Vec<u8>) -> Result<()> {
-        self.network.publish(topic, msg)
+    ///
+    /// If `StorageConfig::outbox_ttl` is set, messages published while no peer is
+    /// connected are queued in a persistent outbox, and published in order once a peer
+    /// connects unless they expired. Messages published while messages of the same topic
+    /// wait in the outbox are queued behind them.
+    pub fn publish(&self, topic: &str, msg: Vec<u8>) -> Result<(), Error> {
+        if self.storage.outbox_ttl().is_some() {
+            let offline = self.network.connections().is_empty();
+            if self
+                .storage
+                .queue_outbox(topic, &msg, offline)
+                .map_err(Error::store)?
+            {
+                return Ok(());
+            }
+        }
+        self.network.publish(topic, msg).map_err(Error::network)
+    }
+
+    /// Returns the number of messages waiting in the outbox.
+    pub fn outbox_len(&self) -> Result<u64, Error> {
+        self.storage.outbox_len().map_err(Error::store)
+    }
+
+    /// Returns a `Stream` of direct messages and their senders. Every node subscribes to
+    /// the topic returned by `peer_topic` for its own peer id on startup.
+    pub fn direct_messages(&self) -> impl Stream<Item = (PeerId, Vec<u8>)> {
+        self.network.direct_messages()
+    }
+
+    /// Sends a direct message to `peer` by publishing it to the topic of the peer.
+    pub fn send_direct(&self, peer: &PeerId, msg: Vec<u8>) -> Result<(), Error> {
+        self.network.send_direct(peer, msg).map_err(Error::network)
+    }
+
+    /// Returns the channel `name` keeping the last `capacity` messages.
+    pub fn channel(&self, name: &str, capacity: usize) -> Channel<P>
+    where
+        Ipld: Decode<P::Codecs> + Encode<P::Codecs>,
+    {
+        Channel::new(self.clone(), name, capacity)
+    }
+
+    /// Returns the cluster `name` whose pinset can be changed by the `writers`. See
+    /// `Cluster` for how the nodes of a cluster converge.
+    pub fn cluster(&self, name: &str, writers: Vec<PeerId>) -> Cluster<P>
+    where
+        Ipld: Decode<P::Codecs> + Encode<P::Codecs>,
+    {
+        Cluster::new(self.clone(), name, writers)
+    }
+
+    /// Returns the misbehaviour reports `name` of a permissioned swarm. See `Reports` for
+    /// how reported peers are banned. Reports are only applied after calling
+    /// `Reports::follow`.
+    pub fn reports(&self, name: &str, config: ReportConfig) -> Reports<P> {
+        Reports::new(self.clone(), name, config)
+    }
+
+    /// Publishes `root` as the latest root of the local node under `topic`, signed with the
+    /// node key. The root is announced on gossipsub and stored in the dht, so that peers
+    /// following the node with `follow_peer` pick it up. Followers that (re)connect later
+    /// are sent the latest root directly. The dag needs to be pinned by the caller. Fails
+    /// only if the root could be published neither on gossipsub nor in the dht.
+    pub async fn publish_root(&self, topic: &str, root: impl ToCid) -> Result<(), Error> {
+        let root = &root.to_cid()?;
+        let seq = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let msg = follow::encode_root(&self.network.node_key(), topic, seq, root)?;
+        let key = follow::root_topic(&self.local_peer_id(), topic);
+        self.published_roots
+            .insert(&self.network, key.clone(), msg.clone());
+        let gossip = self.publish(&key, msg.clone());
+        let record = Record::new(Key::new(&key), msg);
+        let dht = self.put_record(record, Quorum::One).await;
+        match (gossip, dht) {
+            (Err(err), Err(_)) => Err(err),
+            (gossip, dht) => {
+                if let Err(err) = gossip {
+                    tracing::debug!("failed to announce root {}: {}", root, err);
+                }
+                if let Err(err) = dht {
+                    tracing::debug!("failed to put root {} in the dht: {}", root, err);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Follows the roots `peer` publishes under `topic` with `publish_root`. Every newer
+    /// root is synced and pinned with the alias `/ipfs-embed/follow/<peer>/<topic>`,
+    /// replacing the previous one. Roots are received on gossipsub and looked up in the
+    /// dht periodically, in case announcements were missed. When the nodes (re)connect,
+    /// `peer` sends its latest root directly. Following a peer that is already followed
+    /// does nothing.
+    pub fn follow_peer(&self, peer: PeerId, topic: &str) -> Result<(), Error> {
+        let mut follows = self.follows.lock();
+        let key = (peer, topic.to_string());
+        if follows.contains_key(&key) {
+            return Ok(());
+        }
+        let key = follow::root_topic(&peer, topic);
+        let roots = self.subscribe(&key)?;
+        let inbox = self.subscribe(&follow::inbox_topic(&key, &self.local_peer_id()))?;
+        let roots = futures::stream::select(roots, inbox);
+        let task = follow::run(self.clone(), peer, topic.to_string(), roots);
+        follows.insert(key, ipfs_embed_rt::spawn(task));
+        Ok(())
+    }
+
+    /// Stops following the roots `peer` publishes under `topic`. The latest root stays
+    /// pinned by its follow alias until the alias is removed.
+    pub fn unfollow_peer(&self, peer: &PeerId, topic: &str) {
+        self.follows.lock().remove(&(*peer, topic.to_string()));
+    }
+
+    /// Returns the latest root received from `peer` under `topic`.
+    pub fn followed_root(&self, peer: &PeerId, topic: &str) -> Result<Option<Cid>, Error> {
+        self.resolve(follow::follow_alias(peer, topic))
+    }
+
+    /// Replaces the node key with `new_key`, or a newly generated ed25519 key, while the
+    /// node is running and returns the new key together with a link from the previous
+    /// identity to it.
+    ///
+    /// The network is rebuilt with the new key as described by
+    /// `NetworkService::rotate_key`, which also updates the identify info. Afterwards all
+    /// provider and dht records are made due, so that the republisher re-announces them
+    /// under the new `PeerId` right away. If `announce` is set, the link is published on
+    /// gossipsub and stored in the dht before the rotation, where peers find it with
+    /// `resolve_identity` and `follow_identity`. Announcing fails only if the link could
+    /// be published neither on gossipsub nor in the dht. Set `NetworkConfig::node_key` to
+    /// the returned key to keep the identity across restarts.
+    pub async fn rotate_identity(
+        &self,
+        new_key: Option<Keypair>,
+        announce: bool,
+    ) -> Result<(Keypair, IdentityLink), Error> {
+        let new_key = new_key.unwrap_or_else(Keypair::generate_ed25519);
+        let old_key = self.network.node_key();
+        let link = IdentityLink::sign(&old_key, &new_key, republish::unix_now())?;
+        if announce {
+            let msg = link.encode()?;
+            let key = identity::identity_topic(&self.local_peer_id());
+            let gossip = self.publish(&key, msg.clone());
+            let record = Record::new(Key::new(&key), msg);
+            let dht = self
+                .network
+                .put_record(record.clone(), Quorum::One)
+                .await
+                .map_err(Error::network);
+            // tracked even if the put failed, the new identity keeps publishing it.
+            self.republisher.put(&record).map_err(Error::store)?;
+            match (gossip, dht) {
+                (Err(err), Err(_)) => return Err(err),
+                (gossip, dht) => {
+                    if let Err(err) = gossip {
+                        tracing::debug!("failed to announce identity link: {}", err);
+                    }
+                    if let Err(err) = dht {
+                        tracing::debug!("failed to put identity link in the dht: {}", err);
+                    }
+                }
+            }
+        }
+        self.network
+            .rotate_key(new_key.clone())
+            .await
+            .map_err(Error::network)?;
+        self.storage.reschedule_published().map_err(Error::store)?;
+        Ok((new_key, link))
+    }
+
+    /// Looks up the link `peer` published when rotating its identity in the dht. Returns
+    /// the most recent valid link, `None` if `peer` didn't rotate its identity.
+    pub async fn resolve_identity(&self, peer: &PeerId) -> Result<Option<IdentityLink>, Error> {
+        let key = Key::new(&identity::identity_topic(peer));
+        let records = match self.get_record(&key, Quorum::One).await {
+            Ok(records) => records,
+            Err(err) => {
+                tracing::debug!("failed to look up identity link of {}: {}", peer, err);
+                return Ok(None);
+            }
+        };
+        Ok(records
+            .into_iter()
+            .filter_map(|record| IdentityLink::decode(&record.record.value).ok())
+            .filter(|link| link.old_peer_id() == *peer)
+            .max_by_key(|link| link.timestamp()))
+    }
+
+    /// Returns a `Stream` of the valid links `peer` announces when rotating its identity.
+    pub fn follow_identity(&self, peer: PeerId) -> Result<impl Stream<Item = IdentityLink>, Error> {
+        let links = self.subscribe(&identity::identity_topic(&peer))?;
+        Ok(links.filter_map(move |msg| {
+            let link = match IdentityLink::decode(&msg) {
+                Ok(link) if link.old_peer_id() == peer => Some(link),
+                Ok(_) => None,
+                Err(err) => {
+                    tracing::debug!("{}", err);
+                    None
+                }
+            };
+            futures::future::ready(link)
+        }))
+    }
+
+    /// Joins the presence room `room`, publishing signed beacons until the returned `Room`
+    /// is dropped. The room is a stream of the peers joining and leaving it. Fails with
+    /// `AlreadyJoined` if the room was joined already.
+    pub fn join_room(&self, room: &str, config: PresenceConfig) -> Result<Room<P>, Error> {
+        Room::join(self.network.clone(), self.rooms.clone(), room, config).map_err(Error::network)
+    }
+
+    /// Returns the members of the joined room `room`, not including the local node.
+    pub fn room_members(&self, room: &str) -> Vec<PeerId> {
+        self.rooms
+            .lock()
+            .get(room)
+            .map(|members| members.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the tenant `name`, creating it if it doesn't exist. See `Tenant` for the
+    /// isolation it provides.
+    pub fn tenant(&self, name: &str) -> Result<Tenant<P>, Error> {
+        Ok(Tenant::new(self.clone(), name)?)
     }
 
     /// Creates a temporary pin in the block store. A temporary pin is not persisted to disk
     /// and is released once it is dropped.
-    pub fn create_temp_pin(&self) -> Result<TempPin> {
-        self.storage.create_temp_pin()
+    pub fn create_temp_pin(&self) -> Result<TempPin, Error> {
+        self.storage.create_temp_pin().map_err(Error::store)
     }
 
     /// Adds a new root to a temporary pin.
-    pub fn temp_pin(&self, tmp: &TempPin, cid: &Cid) -> Result<()> {
-        self.storage.temp_pin(tmp, std::iter::once(*cid))
+    pub fn temp_pin(&self, tmp: &TempPin, cid: impl ToCid) -> Result<(), Error> {
+        let cid = &cid.to_cid()?;
+        self.storage
+            .temp_pin(tmp, std::iter::once(*cid))
+            .map_err(Error::store)
     }
 
     /// Returns an `Iterator` of `Cid`s stored in the block store.
-    pub fn iter(&self) -> Result<impl Iterator<Item = Cid>> {
-        self.storage.iter()
+    pub fn iter(&self) -> Result<impl Iterator<Item = Cid>, Error> {
+        self.storage.iter().map_err(Error::store)
+    }
+
+    /// Returns up to `limit` `Cid`s stored in the block store, starting after `after`.
+    /// The `Cid`s are returned in a stable order, so passing the last `Cid` of a page
+    /// returns the next page.
+    pub fn iter_after(&self, after: Option<&Cid>, limit: usize) -> Result<Vec<Cid>, Error> {
+        self.storage.iter_after(after, limit).map_err(Error::store)
+    }
+
+    /// Returns an `Iterator` over the `Cid`s stored in the block store that loads
+    /// `page_size` `Cid`s at a time. Blocks inserted or evicted while iterating may or may
+    /// not be returned.
+    pub fn iter_paged(&self, page_size: usize) -> impl Iterator<Item = Result<Cid, Error>> {
+        let storage = self.storage.clone();
+        let mut page = Vec::new().into_iter();
+        let mut last = None;
+        let mut done = page_size == 0;
+        std::iter::from_fn(move || loop {
+            if let Some(cid) = page.next() {
+                last = Some(cid);
+                return Some(Ok(cid));
+            }
+            if done {
+                return None;
+            }
+            match storage.iter_after(last.as_ref(), page_size) {
+                Ok(next) => {
+                    done = next.len() < page_size;
+                    page = next.into_iter();
+                }
+                Err(err) => {
+                    done = true;
+                    return Some(Err(Error::store(err)));
+                }
+            }
+        })
+    }
+
+    /// Returns an `Iterator` over the reference graph of the block store, yielding each
+    /// stored block with the `Cid`s it links to, so large stores can be exported for
+    /// analysis. Loads `page_size` blocks at a time.
+    pub fn reference_graph(
+        &self,
+        page_size: usize,
+    ) -> impl Iterator<Item = Result<(Cid, Vec<Cid>), Error>> {
+        let storage = self.storage.clone();
+        let mut page = Vec::new().into_iter();
+        let mut last = None;
+        let mut done = page_size == 0;
+        std::iter::from_fn(move || loop {
+            if let Some((cid, refs)) = page.next() {
+                last = Some(cid);
+                return Some(Ok((cid, refs)));
+            }
+            if done {
+                return None;
+            }
+            match storage.references_after(last.as_ref(), page_size) {
+                Ok(next) => {
+                    done = next.len() < page_size;
+                    page = next.into_iter();
+                }
+                Err(err) => {
+                    done = true;
+                    return Some(Err(Error::store(err)));
+                }
+            }
+        })
+    }
+
+    /// Returns an `Iterator` of `Cid`s stored in the block store that use `codec`.
+    pub fn list_by_codec(&self, codec: impl Into<u64>) -> Result<impl Iterator<Item = Cid>, Error> {
+        self.storage.list_by_codec(codec).map_err(Error::store)
+    }
+
+    /// Returns an `Iterator` of `Cid`s stored in the block store that were hashed with the
+    /// multihash `code`.
+    pub fn list_by_hash(&self, code: impl Into<u64>) -> Result<impl Iterator<Item = Cid>, Error> {
+        self.storage.list_by_hash(code).map_err(Error::store)
     }
 
     /// Checks if the block is in the block store.
-    pub fn contains(&self, cid: &Cid) -> Result<bool> {
-        self.storage.contains(cid)
+    pub fn contains(&self, cid: impl ToCid) -> Result<bool, Error> {
+        let cid = &cid.to_cid()?;
+        self.storage.contains(cid).map_err(Error::store)
     }
 
     /// Returns a block from the block store.
-    pub fn get(&self, cid: &Cid) -> Result<Block<P>> {
+    pub fn get(&self, cid: impl ToCid) -> Result<Block<P>, Error> {
+        let cid = &cid.to_cid()?;
         if let Some(data) = self.storage.get(cid)? {
             let block = Block::new_unchecked(*cid, data);
             Ok(block)
         } else {
-            Err(BlockNotFound(*cid).into())
+            Err(Error::Store(BlockNotFound(*cid).into()))
+        }
+    }
+
+    /// Returns the data of a block from the block store without wrapping it in a `Block`.
+    /// The data can be cloned and sliced without copying it.
+    pub fn get_raw(&self, cid: impl ToCid) -> Result<Bytes, Error> {
+        let cid = &cid.to_cid()?;
+        self.storage
+            .get(cid)?
+            .map(Bytes::from)
+            .ok_or_else(|| Error::Store(BlockNotFound(*cid).into()))
+    }
+
+    /// Reads a block from the block store and decodes it on the blocking thread pool, so
+    /// that decoding large blocks doesn't stall the executor.
+    pub async fn get_decoded<T>(&self, cid: impl ToCid) -> Result<T, Error>
+    where
+        T: Decode<P::Codecs> + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        let cid = cid.to_cid()?;
+        ipfs_embed_rt::spawn_blocking(move || {
+            let data = storage
+                .get(&cid)?
+                .ok_or_else(|| Error::Store(BlockNotFound(cid).into()))?;
+            Ok(Block::<P>::new_unchecked(cid, data).decode::<P::Codecs, T>()?)
+        })
+        .await
+    }
+
+    /// Sets the number of decoded blocks cached for path resolution. The least recently
+    /// used blocks are evicted first. A capacity of 0, the default, disables the cache.
+    pub fn set_decoded_cache_size(&self, capacity: usize) {
+        self.decoded.lock().set_capacity(capacity);
+    }
+
+    /// Returns a block from the block store decoded, using the cache of decoded blocks.
+    fn get_ipld(&self, cid: &Cid) -> Result<(Ipld, usize), Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        if let Some(cached) = self.decoded.lock().get(cid) {
+            return Ok(cached);
         }
+        let block = self.get(cid)?;
+        let ipld = block.ipld()?;
+        let mut cache = self.decoded.lock();
+        if cache.capacity() > 0 {
+            cache.insert(*cid, ipld.clone(), block.data().len());
+        }
+        Ok((ipld, block.data().len()))
     }
 
     /// Either returns a block if it's in the block store or tries to retrieve it from
     /// a peer.
-    pub async fn fetch(&self, cid: &Cid) -> Result<Block<P>> {
+    pub async fn fetch(&self, cid: impl ToCid) -> Result<Block<P>, Error> {
+        self.fetch_with_priority(cid, Priority::Interactive).await
+    }
+
+    /// Like `fetch`, but requests the block with `priority`. Background fetches don't
+    /// compete with interactive fetches for bandwidth.
+    ///
+    /// Failed requests are retried according to the `NetworkConfig::retry_policy`.
+    pub async fn fetch_with_priority(
+        &self,
+        cid: impl ToCid,
+        priority: Priority,
+    ) -> Result<Block<P>, Error> {
+        let cid = &cid.to_cid()?;
         if let Some(data) = self.storage.get(cid)? {
             let block = Block::new_unchecked(*cid, data);
             return Ok(block);
         }
-        self.network.get(*cid).await?;
+        self.exchange
+            .fetch(*cid, priority)
+            .await
+            .map_err(Error::network)?;
         if let Some(data) = self.storage.get(cid)? {
             let block = Block::new_unchecked(*cid, data);
             return Ok(block);
         }
         tracing::error!("block evicted too soon. use a temp pin to keep the block around.");
-        Err(BlockNotFound(*cid).into())
+        Err(Error::Store(BlockNotFound(*cid).into()))
     }
 
-    /// Inserts a block in to the block store and announces it to peers.
-    pub fn insert(&self, block: &Block<P>) -> Result<impl Future<Output = Result<()>> + '_> {
-        let cid = *block.cid();
-        self.storage.insert(block)?;
-        Ok(self.network.provide(cid))
+    /// Returns a block from the block store, retrieving it from the network according to
+    /// `policy` if it is missing.
+    pub async fn get_or_fetch(
+        &self,
+        cid: impl ToCid,
+        policy: FetchPolicy,
+    ) -> Result<Block<P>, Error> {
+        let cid = cid.to_cid()?;
+        let timeout = match policy {
+            FetchPolicy::Offline => return self.get(&cid),
+            FetchPolicy::Fast(timeout) => timeout,
+            FetchPolicy::Thorough => return self.fetch(&cid).await,
+        };
+        if let Some(data) = self.storage.get(&cid)? {
+            return Ok(Block::new_unchecked(cid, data));
+        }
+        // keeps the block from being evicted before it is read.
+        let tmp = self.storage.create_temp_pin()?;
+        self.storage.temp_pin(&tmp, std::iter::once(cid))?;
+        let query = self.network.get_connected(cid);
+        match futures::future::select(query, Timer::after(timeout)).await {
+            futures::future::Either::Left((res, _)) => res.map_err(Error::network)?,
+            futures::future::Either::Right(_) => {
+                return Err(Error::Timeout(FetchTimeout(cid).into()))
+            }
+        }
+        self.get(&cid)
     }
 
-    /// Manually runs garbage collection to completion. This is mainly useful for testing and
-    /// administrative interfaces. During normal operation, the garbage collector automatically
+    /// Pushes blocks to a peer. The peer needs to have push enabled.
+    pub async fn push(&self, peer: &PeerId, blocks: Vec<Block<P>>) -> Result<(), Error> {
+        self.network
+            .push(peer, blocks)
+            .await
+            .map_err(Error::network)
+    }
+
+    /// Returns a stream of blocks pushed by peers. Only the most recently returned stream
+    /// receives pushed blocks. Blocks pushed while there is no stream are evicted by the
+    /// garbage collector.
+    pub fn pushed(&self) -> impl Stream<Item = PushedBlocks> {
+        let (tx, rx) = mpsc::unbounded();
+        *self.pushed.lock() = Some(tx);
+        rx
+    }
+
+    /// Returns a future that resolves once the block is available locally. The block can
+    /// arrive by any means, be it a local insert, a sync or a fetch. Unlike `fetch` this
+    /// doesn't request the block from peers.
+    pub fn want(&self, cid: impl ToCid) -> Result<impl Future<Output = Block<P>> + '_, Error> {
+        let cid = cid.to_cid()?;
+        let rx = self.storage.watch(&cid);
+        Ok(async move {
+            match self.storage.get(&cid) {
+                Ok(Some(data)) => return Block::new_unchecked(cid, data),
+                Ok(None) => {}
+                Err(err) => tracing::warn!("failed to get block: {}", err),
+            }
+            match rx.await {
+                Ok(block) => block,
+                Err(_) => futures::future::pending().await,
+            }
+        })
+    }
+
+    /// Inserts a block in to the block store and announces it to peers. Once announced
+    /// the provider record is republished until the block is removed from the store.
+    ///
+    /// Inline blocks using the identity hash are neither stored nor announced.
+    pub fn insert(
+        &self,
+        block: &Block<P>,
+    ) -> Result<impl Future<Output = Result<(), Error>> + '_, Error> {
+        if block.data().len() > P::MAX_BLOCK_SIZE {
+            return Err(Error::BlockTooLarge(block.data().len()));
+        }
+        let cid = *block.cid();
+        self.storage.insert(block).map_err(Error::store)?;
+        Ok(async move {
+            if inline_data(&cid).is_some() {
+                return Ok(());
+            }
+            self.exchange.provide(cid).await.map_err(Error::network)?;
+            self.republisher.provided(&cid).map_err(Error::store)
+        })
+    }
+
+    /// Inserts the blocks of a dag under a temporary pin without aliasing or announcing
+    /// them, so that the application can validate the dag before it is committed. The
+    /// blocks need to have a single root and pass the block validators.
+    pub fn stage(&self, blocks: impl IntoIterator<Item = Block<P>>) -> Result<StagedDag<P>, Error> {
+        Ok(StagedDag::new(self, blocks)?)
+    }
+
+    /// Re-encodes the dag rooted at `root` and returns the new root. Every block is hashed
+    /// using `hash`, blocks encoded with the codec `from` are transcoded to the codec `to`
+    /// and links are rewritten to point to the re-encoded blocks. The new blocks are added
+    /// to the temporary pin `tmp`. All blocks of the dag need to be in the block store.
+    pub fn reencode_dag(
+        &self,
+        tmp: &TempPin,
+        root: impl ToCid,
+        from: P::Codecs,
+        to: P::Codecs,
+        hash: P::Hashes,
+    ) -> Result<Cid, Error>
+    where
+        Ipld: Decode<P::Codecs> + Encode<P::Codecs>,
+    {
+        let root = &root.to_cid()?;
+        enum Visit {
+            Enter(Cid),
+            Exit(Cid, Ipld),
+        }
+        let from: u64 = from.into();
+        let mut reencoded = FnvHashMap::default();
+        let mut stack = vec![Visit::Enter(*root)];
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(cid) => {
+                    if reencoded.contains_key(&cid) {
+                        continue;
+                    }
+                    let ipld = self.get(&cid)?.ipld()?;
+                    let mut refs = vec![];
+                    ipld.references(&mut refs);
+                    stack.push(Visit::Exit(cid, ipld));
+                    stack.extend(refs.into_iter().map(Visit::Enter));
+                }
+                Visit::Exit(cid, mut ipld) => {
+                    if reencoded.contains_key(&cid) {
+                        continue;
+                    }
+                    rewrite_links(&mut ipld, &reencoded);
+                    let codec = if cid.codec() == from {
+                        to
+                    } else {
+                        P::Codecs::try_from(cid.codec())
+                            .map_err(|err| Error::InvalidCid(err.into()))?
+                    };
+                    let block = Block::<P>::encode(codec, hash, &ipld)?;
+                    self.storage.temp_pin(tmp, std::iter::once(*block.cid()))?;
+                    self.storage.insert(&block)?;
+                    reencoded.insert(cid, *block.cid());
+                }
+            }
+        }
+        Ok(reencoded[root])
+    }
+
+    /// Encrypts every block of the dag rooted at `root` with a random content key and
+    /// returns the root of an envelope linking to the encrypted dag. The content key is
+    /// wrapped for each of the ed25519 `recipients`, so only they can decrypt the dag with
+    /// `decrypt_dag`. Encrypted blocks with links keep the links to the encrypted blocks
+    /// in the clear, so that the encrypted dag can be pinned and synced, which reveals
+    /// the shape of the dag but not its data. The new blocks are added to the temporary
+    /// pin `tmp`. All blocks of the dag need to be in the block store.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt_dag(
+        &self,
+        tmp: &TempPin,
+        root: impl ToCid,
+        recipients: &[PublicKey],
+    ) -> Result<Cid, Error>
+    where
+        Ipld: Decode<P::Codecs> + Encode<P::Codecs>,
+    {
+        let root = &root.to_cid()?;
+        Ok(encrypt::encrypt_dag(&self.storage, tmp, root, recipients)?)
+    }
+
+    /// Decrypts the dag of the envelope `root` created by `encrypt_dag` with `keypair`
+    /// and returns the root of the decrypted dag, which is the root of the dag that was
+    /// encrypted. The decrypted blocks are added to the temporary pin `tmp`. Fails with
+    /// `NotARecipient` if the content key isn't wrapped for `keypair`. All blocks of the
+    /// envelope need to be in the block store, they can be synced like any other dag.
+    #[cfg(feature = "encryption")]
+    pub fn decrypt_dag(
+        &self,
+        tmp: &TempPin,
+        root: impl ToCid,
+        keypair: &Keypair,
+    ) -> Result<Cid, Error>
+    where
+        Ipld: Decode<P::Codecs> + Encode<P::Codecs>,
+    {
+        let root = &root.to_cid()?;
+        Ok(encrypt::decrypt_dag(&self.storage, tmp, root, keypair)?)
+    }
+
+    /// Computes the blocks added and removed between the dags rooted at `old` and `new`,
+    /// the blocks replaced at the same path and the differences of values inside of them.
+    /// All blocks of both dags need to be in the block store.
+    pub fn dag_diff(&self, old: impl ToCid, new: impl ToCid) -> Result<DagDiff, Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        let (old, new) = (&old.to_cid()?, &new.to_cid()?);
+        Ok(diff::dag_diff(|cid| self.get(cid)?.ipld(), old, new)?)
+    }
+
+    /// Resolves a `/` separated `path` starting at `root`, following links across blocks.
+    /// Dags can mix all codecs of `P::Codecs`; the `dag-pb` and `dag-json` features add
+    /// them to the codecs of `DefaultParams`. Segments of dag-pb nodes are link names,
+    /// and blocks of codecs registered with `register_codec` that only extract links are
+    /// lists of their links, so their segments are link indices. All blocks on the path
+    /// need to be in the block store. Fails with `LimitExceeded` if resolving the path
+    /// exceeds the `limits`.
+    pub fn resolve_path(
+        &self,
+        root: impl ToCid,
+        path: &str,
+        limits: &TraversalLimits,
+    ) -> Result<Ipld, Error>
+    where
+        Ipld: Decode<P::Codecs> + References<P::Codecs>,
+    {
+        let root = &root.to_cid()?;
+        let get = |cid: &Cid| Ok(self.path_node(cid)?);
+        Ok(path::resolve_path(get, root, path, limits)?)
+    }
+
+    /// Returns a block on a path decoded. Blocks of codecs that only extract links are
+    /// lists of their links.
+    fn path_node(&self, cid: &Cid) -> Result<(Ipld, usize), Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        match self.get_ipld(cid) {
+            Err(err) if err.downcast_ref::<LinksOnly>().is_some() => {
+                let block = self.get(cid)?;
+                let mut links = vec![];
+                block.references(&mut links)?;
+                let links = links.into_iter().map(Ipld::Link).collect();
+                Ok((Ipld::List(links), block.data().len()))
+            }
+            res => res,
+        }
+    }
+
+    /// Returns a read handle of a consistent snapshot of the blocks and aliases. Blocks
+    /// evicted by the garbage collector or aliases changed after the snapshot was taken
+    /// remain visible to the handle, which makes it suitable for long-running exports.
+    /// In-memory block stores don't support snapshots.
+    pub fn read_snapshot(&self) -> Result<StoreReader, Error> {
+        self.storage.read_snapshot().map_err(Error::store)
+    }
+
+    /// Returns the recovery performed when the block store was opened, if the database
+    /// was corrupted.
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.storage.recovery_report()
+    }
+
+    /// Compacts the block store by releasing unused pages of the database file in slices
+    /// of `StorageConfig::compact_pages`. The first compaction of a database created
+    /// without incremental vacuum support performs a full vacuum.
+    pub async fn compact(&self) -> Result<(), Error> {
+        self.storage.compact().await.map_err(Error::store)
+    }
+
+    /// Returns the number of unused pages in the database file.
+    pub fn free_pages(&self) -> Result<u64, Error> {
+        self.storage.free_pages().map_err(Error::store)
+    }
+
+    /// Returns the current garbage collector configuration.
+    pub fn gc_config(&self) -> GcConfig {
+        self.storage.gc_config()
+    }
+
+    /// Changes the garbage collector configuration. The new configuration takes effect
+    /// the next time the garbage collector runs.
+    pub fn set_gc_config(&self, config: GcConfig) {
+        self.storage.set_gc_config(config)
+    }
+
+    /// Returns when the garbage collector last ran, how long it took, how many blocks it
+    /// deleted and how many bytes it reclaimed. The same values are exported by
+    /// `register_metrics`.
+    pub fn gc_stats(&self) -> GcStats {
+        self.storage.gc_stats()
+    }
+
+    /// Returns the block store as a `BitswapStore`, to exchange blocks over a network
+    /// other than the one of the node.
+    pub fn bitswap_store(&self) -> BitswapStorage<P> {
+        BitswapStorage(
+            self.storage.clone(),
+            self.validators.clone(),
+            self.tenants.clone(),
+        )
+    }
+
+    /// Returns the number and size of the blocks inserted and deleted since the store was
+    /// opened, by codec and hash. The same values and a histogram of the block sizes are
+    /// exported by `register_metrics`.
+    pub fn block_distribution(&self) -> BlockDistribution {
+        self.storage.block_distribution()
+    }
+
+    /// Inserts a block in to the block store, signs it with the node key and announces it
+    /// to peers.
+    pub fn insert_signed(
+        &self,
+        block: &Block<P>,
+    ) -> Result<impl Future<Output = Result<(), Error>> + '_, Error> {
+        let provenance = Provenance::sign(&self.network.node_key(), block.cid())?;
+        let provide = self.insert(block)?;
+        self.add_provenance(block.cid(), &provenance)?;
+        Ok(provide)
+    }
+
+    /// Adds a `Provenance` record for a block. Returns an `InvalidProvenance` error if the
+    /// signature is invalid.
+    pub fn add_provenance(&self, cid: impl ToCid, provenance: &Provenance) -> Result<(), Error> {
+        let cid = &cid.to_cid()?;
+        if !provenance.verify(cid) {
+            return Err(Error::Store(InvalidProvenance(*cid).into()));
+        }
+        let public_key = provenance.public_key().clone().into_protobuf_encoding();
+        self.storage
+            .add_provenance(cid, &public_key, provenance.signature())
+            .map_err(Error::store)
+    }
+
+    /// Returns the `Provenance` records of a block.
+    pub fn block_provenance(&self, cid: impl ToCid) -> Result<Vec<Provenance>, Error> {
+        let cid = &cid.to_cid()?;
+        let mut records = vec![];
+        for (public_key, signature) in self.storage.provenance(cid)? {
+            match PublicKey::from_protobuf_encoding(&public_key) {
+                Ok(public_key) => records.push(Provenance::new(public_key, signature)),
+                Err(err) => tracing::warn!("invalid provenance public key: {}", err),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Manually runs garbage collection to completion. This is mainly useful for testing and
+    /// administrative interfaces. During normal operation, the garbage collector automatically
     /// runs in the background.
-    pub async fn evict(&self) -> Result<()> {
-        self.storage.evict().await
+    pub async fn evict(&self) -> Result<(), Error> {
+        self.storage.evict().await.map_err(Error::store)
+    }
+
+    /// Returns the number of syncs in flight. The garbage collector defers its passes while
+    /// syncs are in flight, up to `GcConfig::sync_deferral`.
+    pub fn active_syncs(&self) -> usize {
+        self.storage.active_syncs()
+    }
+
+    /// Syncs the dag rooted at `cid`. The query fails right away if `cid` can't be
+    /// parsed.
+    pub fn sync(&self, cid: impl ToCid) -> SyncQuery<P> {
+        self.sync_with_priority(cid, Priority::Interactive)
+    }
+
+    /// Like `sync`, but requests the blocks with `priority`. Use `Priority::Background`
+    /// for bulk replication, so that it doesn't delay interactive fetches.
+    pub fn sync_with_priority(&self, cid: impl ToCid, priority: Priority) -> SyncQuery<P> {
+        let cid = match cid.to_cid() {
+            Ok(cid) => cid,
+            Err(err) => return self.network.sync_failed(err),
+        };
+        let missing = self.storage.missing_blocks(&cid).ok().unwrap_or_default();
+        self.exchange
+            .sync(missing, priority)
+            .hold(self.storage.begin_sync())
+    }
+
+    /// Syncs the dags rooted at `roots` as a single operation. The missing blocks of all
+    /// dags are requested together, so blocks shared between the dags are fetched once.
+    pub fn sync_many(&self, roots: Vec<Cid>) -> SyncQuery<P> {
+        self.sync_many_with_priority(roots, Priority::Interactive)
+    }
+
+    /// Like `sync_many`, but requests the blocks with `priority`.
+    pub fn sync_many_with_priority(&self, roots: Vec<Cid>, priority: Priority) -> SyncQuery<P> {
+        let mut seen = FnvHashSet::default();
+        let mut missing = vec![];
+        for root in roots {
+            for cid in self.storage.missing_blocks(&root).ok().unwrap_or_default() {
+                if seen.insert(cid) {
+                    missing.push(cid);
+                }
+            }
+        }
+        self.exchange
+            .sync(missing, priority)
+            .hold(self.storage.begin_sync())
+    }
+
+    /// Like `sync_with_priority`, but fails with `LimitExceeded` once the synced blocks
+    /// exceed the `limits` instead of the configured `sync_limits`. The depth is counted
+    /// from the blocks missing when the sync starts.
+    pub fn sync_with_limits(
+        &self,
+        cid: impl ToCid,
+        priority: Priority,
+        limits: TraversalLimits,
+    ) -> SyncQuery<P> {
+        let cid = match cid.to_cid() {
+            Ok(cid) => cid,
+            Err(err) => return self.network.sync_failed(err),
+        };
+        let missing = self.storage.missing_blocks(&cid).ok().unwrap_or_default();
+        self.network
+            .sync_with_limits(cid, missing.into_iter(), priority, Some(limits))
+            .hold(self.storage.begin_sync())
+    }
+
+    /// Like `sync_with_priority`, but requests the blocks in traversal `order`. A depth
+    /// first sync only requests a few blocks at a time and the links of a block before
+    /// its siblings, so that it receives a file roughly in the order of its bytes and it
+    /// can be read while it is synced. Fails with `UnsupportedOrder` for `LargestFirst`.
+    pub fn sync_with_order(
+        &self,
+        cid: impl ToCid,
+        priority: Priority,
+        order: TraversalOrder,
+    ) -> SyncQuery<P> {
+        let cid = match cid.to_cid() {
+            Ok(cid) => cid,
+            Err(err) => return self.network.sync_failed(err),
+        };
+        let missing = self.storage.missing_blocks(&cid).ok().unwrap_or_default();
+        self.network
+            .sync_missing_with_order(missing.into_iter(), priority, None, order)
+            .hold(self.storage.begin_sync())
+    }
+
+    /// Syncs the dag rooted at `cid` until it is complete or the `deadline` passed, for
+    /// interactive flows where partial data now beats complete data later. Returns the
+    /// blocks that are still missing instead of failing at the deadline. A sync that
+    /// fails before the deadline is reported the same way, with the reason in
+    /// `PartialSync::error`.
+    ///
+    /// The root is pinned to `tmp`, which keeps the received blocks in the store, so that
+    /// a later call with the same temp pin resumes the sync.
+    pub async fn sync_with_deadline(
+        &self,
+        tmp: &TempPin,
+        cid: impl ToCid,
+        deadline: Instant,
+    ) -> Result<PartialSync, Error> {
+        let cid = &cid.to_cid()?;
+        self.temp_pin(tmp, cid)?;
+        let mut query = self.sync(cid);
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let error = match futures::future::select(&mut query, Timer::after(timeout)).await {
+            futures::future::Either::Left((res, _)) => res.err().map(|err| err.to_string()),
+            futures::future::Either::Right(_) => None,
+        };
+        let stats = query.stats();
+        drop(query);
+        let missing = self.storage.missing_blocks(cid).map_err(Error::store)?;
+        Ok(PartialSync {
+            complete: missing.is_empty(),
+            stats,
+            missing,
+            error,
+        })
+    }
+
+    /// Creates, updates or removes an alias with a new root `Cid`. Aliases starting with
+    /// `/tenant/` are reserved for `Tenant`s, setting them fails with `ReservedAlias`.
+    pub fn alias<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: Option<&Cid>,
+    ) -> Result<(), Error> {
+        let alias = alias.as_ref();
+        tenant::check_alias(alias)?;
+        self.set_alias(alias, cid)
+    }
+
+    /// Creates or updates an alias like `alias`, with a root that is parsed if it is a
+    /// string.
+    pub fn alias_to<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: impl ToCid,
+    ) -> Result<(), Error> {
+        self.alias(alias, Some(&cid.to_cid()?))
     }
 
-    pub fn sync(&self, cid: &Cid) -> SyncQuery<P> {
-        let missing = self.storage.missing_blocks(cid).ok().unwrap_or_default();
-        self.network.sync(*cid, missing.into_iter())
+    /// Like `alias`, but allows the reserved prefixes.
+    pub(crate) fn set_alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<(), Error> {
+        self.storage.alias(alias, cid).map_err(Error::store)
     }
 
-    /// Creates, updates or removes an alias with a new root `Cid`.
-    pub fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
-        self.storage.alias(alias.as_ref(), cid)
+    /// Registers a hook running on every alias change of the node, including the
+    /// removal of expired aliases. Changes fail with `AliasRejected` if a pre-commit hook
+    /// rejects them, post-commit hooks run in the background.
+    pub fn add_alias_hook<H: AliasHook>(&self, hook: H) {
+        self.alias_hooks.add(Arc::new(hook));
+        self.storage.set_alias_hook(self.alias_hooks.clone());
+    }
+
+    /// Creates or updates an alias with a metadata blob of at most `MAX_ALIAS_META_SIZE`
+    /// bytes, like a json descriptor or a version vector, so that applications don't need
+    /// a second store for per root metadata. Setting the alias with `alias` removes the
+    /// metadata.
+    pub fn alias_with_meta<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: impl ToCid,
+        meta: &[u8],
+    ) -> Result<(), Error> {
+        let cid = &cid.to_cid()?;
+        let alias = alias.as_ref();
+        tenant::check_alias(alias)?;
+        self.storage
+            .alias_with_meta(alias, cid, meta)
+            .map_err(Error::store)
+    }
+
+    /// Returns the root of an alias and its metadata. The metadata records the root it was
+    /// set for and is only returned with that root, so it always describes the returned
+    /// root.
+    pub fn resolve_with_meta<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+    ) -> Result<Option<(Cid, Option<Vec<u8>>)>, Error> {
+        self.storage
+            .resolve_with_meta(alias.as_ref())
+            .map_err(Error::store)
+    }
+
+    /// Creates or updates an alias that is removed once `ttl` elapsed, after which its dag
+    /// can be garbage collected. Expired aliases are removed every
+    /// `StorageConfig::alias_expiry_interval`. Setting the alias with `alias` makes it
+    /// permanent again.
+    pub fn alias_with_ttl<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: impl ToCid,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let cid = &cid.to_cid()?;
+        let alias = alias.as_ref();
+        tenant::check_alias(alias)?;
+        self.storage
+            .alias_with_ttl(alias, cid, ttl)
+            .map_err(Error::store)
+    }
+
+    /// Returns the time at which an alias set with `alias_with_ttl` expires.
+    pub fn alias_expiry<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+    ) -> Result<Option<SystemTime>, Error> {
+        self.storage
+            .alias_expiry(alias.as_ref())
+            .map_err(Error::store)
     }
 
     /// Returns the root of an alias.
-    pub fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
-        self.storage.resolve(alias.as_ref())
+    pub fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>, Error> {
+        self.storage.resolve(alias.as_ref()).map_err(Error::store)
     }
 
     /// Returns a list of aliases preventing a `Cid` from being garbage collected.
-    pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
-        self.storage.reverse_alias(cid)
+    pub fn reverse_alias(&self, cid: impl ToCid) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        let cid = &cid.to_cid()?;
+        self.storage.reverse_alias(cid).map_err(Error::store)
+    }
+
+    /// Returns the aliases starting with `prefix` and their roots ordered by name. Aliases
+    /// can be structured as paths like `app/users/42/avatar`, listing the aliases below
+    /// `app/users` with the prefix `app/users/`.
+    pub fn aliases_with_prefix<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        prefix: T,
+    ) -> Result<Vec<(Vec<u8>, Cid)>, Error> {
+        self.storage
+            .aliases_with_prefix(prefix.as_ref())
+            .map_err(Error::store)
+    }
+
+    /// Removes all aliases starting with `prefix`, returning the number of removed
+    /// aliases.
+    pub fn remove_aliases<T: AsRef<[u8]> + Send + Sync>(&self, prefix: T) -> Result<usize, Error> {
+        self.storage
+            .remove_aliases(prefix.as_ref())
+            .map_err(Error::store)
+    }
+
+    /// Moves all aliases starting with `from` below `to`, returning the number of renamed
+    /// aliases. Fails with `AliasExists` if a new name is already taken. Other alias
+    /// operations never observe a partially renamed prefix.
+    pub fn rename_aliases<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        from: T,
+        to: T,
+    ) -> Result<usize, Error> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        tenant::check_alias(from)?;
+        tenant::check_alias(to)?;
+        self.storage.rename_aliases(from, to).map_err(Error::store)
+    }
+
+    /// Returns the root of a dag holding all aliases and their roots. The dag is
+    /// re-encoded when an alias changed since the last call, and the blocks of the latest
+    /// dag are kept alive by the node. See `import_aliases` for restoring it.
+    pub fn aliases_root(&self) -> Result<Cid, Error>
+    where
+        Ipld: Encode<P::Codecs>,
+    {
+        let mut cached = self.alias_table.lock();
+        let generation = self.storage.alias_generation();
+        if let Some((gen, root, _)) = cached.as_ref() {
+            if *gen == generation {
+                return Ok(*root);
+            }
+        }
+        let tmp = self.storage.create_temp_pin()?;
+        let root = alias_table::export(&self.storage, &tmp)?;
+        *cached = Some((generation, root, tmp));
+        Ok(root)
+    }
+
+    /// Sets the aliases of the alias table rooted at `root`, returning the number of
+    /// imported aliases. The blocks of the table need to be available locally, for example
+    /// by syncing the `root`, while the aliased dags can be synced afterwards.
+    pub fn import_aliases(&self, root: impl ToCid) -> Result<usize, Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        let root = &root.to_cid()?;
+        Ok(alias_table::import(&self.storage, root)?)
+    }
+
+    /// Checkpoints the state of the node, returning the `Cid` of a manifest linking to the
+    /// alias table. The manifest covers all aliased dags, so the same state always has the
+    /// same `Cid`. The latest checkpoint is kept alive by the node, so that peers can
+    /// `restore` it. Use `export_car` to write it to a file.
+    pub fn checkpoint(&self) -> Result<Cid, Error>
+    where
+        Ipld: Encode<P::Codecs>,
+    {
+        let tmp = self.storage.create_temp_pin()?;
+        Ok(checkpoint::create(&self.storage, &tmp)?)
+    }
+
+    /// Restores the checkpoint with the manifest `Cid`, syncing it from the network and
+    /// setting its aliases. Returns the number of restored aliases. Aliases that are not
+    /// part of the checkpoint are kept. To restore from a file, import it with
+    /// `import_car` first.
+    pub async fn restore(&self, manifest: impl ToCid) -> Result<usize, Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        let manifest = &manifest.to_cid()?;
+        let tmp = self.storage.create_temp_pin()?;
+        self.storage.temp_pin(&tmp, std::iter::once(*manifest))?;
+        self.sync(manifest).await.map_err(Error::network)?;
+        let aliases = checkpoint::aliases(&self.storage, manifest)?;
+        let n = alias_table::import(&self.storage, &aliases)?;
+        self.storage
+            .alias(checkpoint::CHECKPOINT_ALIAS, Some(manifest))?;
+        Ok(n)
+    }
+
+    /// Writes the dag rooted at `root` to a CAR file, returning the number of written
+    /// blocks. All blocks of the dag need to be in the block store. The blocks are written
+    /// depth first.
+    pub fn export_car(&self, root: impl ToCid, writer: impl Write) -> Result<usize, Error> {
+        self.export_car_with_order(root, TraversalOrder::DepthFirst, writer)
+    }
+
+    /// Like `export_car`, but writes the blocks in traversal `order`.
+    pub fn export_car_with_order(
+        &self,
+        root: impl ToCid,
+        order: TraversalOrder,
+        writer: impl Write,
+    ) -> Result<usize, Error> {
+        let root = &root.to_cid()?;
+        Ok(checkpoint::write_car(&self.storage, root, order, writer)?)
+    }
+
+    /// Returns the number and size of the blocks of the dag rooted at `root` in the block
+    /// store, and the missing blocks in traversal `order`. With `DepthFirst` the first
+    /// missing block is the next one a sequential reader of the dag needs.
+    pub fn dag_stat(&self, root: impl ToCid, order: TraversalOrder) -> Result<DagStat, Error> {
+        let root = &root.to_cid()?;
+        Ok(traversal::dag_stat(&self.storage, root, order)?)
+    }
+
+    /// Returns up to `n` previous roots of `alias`, most recent first. The history is only
+    /// recorded when `StorageConfig::alias_history` is set, in which case the configured
+    /// number of previous roots are kept alive by the garbage collector.
+    pub fn alias_history<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        n: usize,
+    ) -> Result<Vec<Cid>, Error> {
+        self.storage
+            .alias_history(alias.as_ref(), n)
+            .map_err(Error::store)
+    }
+
+    /// Installs a hook maintaining a secondary index of the inserted blocks in the
+    /// database of the block store. Replaces the previously installed hook.
+    pub fn set_index_hook<H: IndexHook>(&self, hook: H) -> Result<(), Error>
+    where
+        Ipld: Decode<P::Codecs>,
+    {
+        self.storage.set_index_hook(hook).map_err(Error::store)
+    }
+
+    /// Adds a block to the deny list. Denied blocks are neither inserted, returned nor
+    /// served to peers, and are no longer provided.
+    pub fn deny(&self, cid: impl ToCid) -> Result<(), Error> {
+        let cid = &cid.to_cid()?;
+        self.storage.deny(cid)?;
+        self.exchange.unprovide(*cid);
+        self.republisher
+            .remove(&cid.to_bytes())
+            .map_err(Error::store)
+    }
+
+    /// Removes a block from the deny list.
+    pub fn allow(&self, cid: impl ToCid) -> Result<(), Error> {
+        let cid = &cid.to_cid()?;
+        self.storage.allow(cid).map_err(Error::store)
+    }
+
+    /// Returns the blocks on the deny list.
+    pub fn denylist(&self) -> Vec<Cid> {
+        self.storage.denylist()
+    }
+
+    /// Applies a deny list in the format described in `parse_denylist`. Returns the number
+    /// of applied entries.
+    pub fn apply_denylist(&self, reader: impl BufRead) -> Result<usize, Error> {
+        let entries = parse_denylist(reader)?;
+        for entry in &entries {
+            match entry {
+                DenylistEntry::Deny(cid) => self.deny(cid)?,
+                DenylistEntry::Allow(cid) => self.allow(cid)?,
+            }
+        }
+        Ok(entries.len())
+    }
+
+    /// Applies the deny list updates published on a gossipsub `topic` by one of the
+    /// `publishers`, for example a content moderation feed. Gossipsub messages are signed
+    /// by their source, so updates published or forged by other peers are ignored.
+    pub fn follow_denylist(&self, topic: &str, publishers: &[PeerId]) -> Result<(), Error> {
+        let updates = self
+            .network
+            .subscribe_messages(topic)
+            .map_err(Error::network)?;
+        let publishers = publishers.iter().copied().collect::<FnvHashSet<_>>();
+        let ipfs = self.clone();
+        ipfs_embed_rt::spawn(async move {
+            futures::pin_mut!(updates);
+            while let Some(update) = updates.next().await {
+                match update.source {
+                    Some(source) if publishers.contains(&source) => {}
+                    source => {
+                        tracing::warn!("ignoring deny list update from {:?}", source);
+                        continue;
+                    }
+                }
+                if let Err(err) = ipfs.apply_denylist(&update.data[..]) {
+                    tracing::warn!("failed to apply deny list update: {}", err);
+                }
+            }
+        })
+        .detach();
+        Ok(())
+    }
+
+    /// Imports the blocks of a CAR file, for example exported from go-ipfs with
+    /// `ipfs dag export`. The roots are pinned with the alias returned by `import_alias`.
+    /// Imported blocks are not announced to peers.
+    pub fn import_car(&self, reader: impl Read) -> Result<ImportReport, Error> {
+        Ok(import::import_car(&self.storage, reader, None)?)
+    }
+
+    /// Like `import_car`, but records the progress in the journal `id` after every batch
+    /// of blocks. If the node crashes mid-import, importing the same file with the same
+    /// `id` resumes after the last committed batch. The committed blocks stay pinned by
+    /// the journal until the import completes or is aborted with `abort_import`. Journals
+    /// of imports that made no progress for 7 days are removed like expired aliases, so
+    /// abandoned imports can't pin their blocks forever. Blocks of an uncommitted batch
+    /// are collected by the garbage collector.
+    pub fn import_car_journaled(&self, id: &str, reader: impl Read) -> Result<ImportReport, Error> {
+        Ok(import::import_car(&self.storage, reader, Some(id))?)
+    }
+
+    /// Returns the offset in bytes up to which the journaled import `id` was committed, or
+    /// `None` if there is no unfinished import `id`.
+    pub fn import_journal(&self, id: &str) -> Result<Option<u64>, Error> {
+        Ok(import::journal_offset(&self.storage, id)?)
+    }
+
+    /// Returns the ids of the unfinished journaled imports, for example to abort the
+    /// imports that won't be resumed after a restart.
+    pub fn import_journals(&self) -> Result<Vec<String>, Error> {
+        Ok(import::journal_ids(&self.storage)?)
+    }
+
+    /// Removes the journal of the unfinished import `id`, so that the blocks it imported
+    /// can be collected.
+    pub fn abort_import(&self, id: &str) -> Result<(), Error> {
+        self.storage
+            .alias(&import::journal_alias(id), None)
+            .map_err(Error::store)
+    }
+
+    /// Inserts a batch of blocks and pins the `roots` with the alias returned by
+    /// `import_alias`. Blocks already in the store are skipped and reported as duplicates.
+    /// Imported blocks are not announced to peers.
+    pub fn import_blocks(
+        &self,
+        blocks: impl IntoIterator<Item = Block<P>>,
+        roots: Vec<Cid>,
+    ) -> Result<ImportReport, Error> {
+        let mut importer = import::Importer::new(&self.storage)?;
+        for block in blocks {
+            importer.push(block)?;
+        }
+        Ok(importer.pin(roots)?)
+    }
+
+    /// Imports the blocks of the flatfs datastore of the go-ipfs `repo`. go-ipfs keeps its
+    /// pins in a separate datastore, so the recursive `pins` need to be passed in, they can
+    /// be listed with `ipfs pin ls --type=recursive -q`. The pins are stored with the alias
+    /// returned by `import_alias`. Blocks not reachable from a pin, or whose codec isn't
+    /// enabled, are imported as raw blocks pinned with the alias `/go-ipfs/raw`. Imported
+    /// blocks are not announced to peers.
+    ///
+    /// Badger datastores can't be read, they need to be exported to a CAR file first.
+    pub fn import_flatfs(&self, repo: &Path, pins: &[Cid]) -> Result<ImportReport, Error> {
+        Ok(import::import_flatfs(&self.storage, repo, pins)?)
     }
 
     /// Flushes the block store. After `flush` completes successfully it is guaranteed that
     /// all writes have been persisted to disk.
-    pub async fn flush(&self) -> Result<()> {
-        self.storage.flush().await
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.storage.flush().await.map_err(Error::store)
+    }
+
+    /// Flushes the block store and returns the root of `alias`. Once it completes the
+    /// alias and its dag survive a crash or power loss. With `Durability::Strict` every
+    /// alias update is flushed immediately.
+    pub async fn flush_alias<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+    ) -> Result<Option<Cid>, Error> {
+        self.storage
+            .flush_alias(alias.as_ref())
+            .await
+            .map_err(Error::store)
+    }
+
+    /// Quiesces writes and garbage collection so that an external backup tool can copy the
+    /// database files. Waits up to `timeout` for pending writes and flushes the write ahead
+    /// log. Reads continue while the store is frozen, writes wait until the returned guard
+    /// is dropped.
+    pub async fn freeze_writes(&self, timeout: Duration) -> Result<FreezeGuard, Error> {
+        self.storage.freeze_writes(timeout).await.map_err(|err| {
+            if err.downcast_ref::<FreezeTimeout>().is_some() {
+                Error::Timeout(err.into())
+            } else {
+                Error::store(err)
+            }
+        })
+    }
+
+    /// Returns the number and size of the stored blocks.
+    pub async fn store_stats(&self) -> Result<StoreStats, Error> {
+        self.storage.store_stats().await.map_err(Error::store)
+    }
+
+    /// Returns the store statistics sampled in the background every
+    /// `StorageConfig::stats_interval`. This never blocks on the database.
+    pub fn stats(&self) -> StoreStats {
+        self.storage.stats()
+    }
+
+    /// Verifies that the dags of all aliases are complete and fetches the missing blocks.
+    /// When `StorageConfig::verify_interval` is set this also runs in the background,
+    /// emitting `EventFilter::Repair` events.
+    pub async fn verify_pins(&self) -> Result<VerifyReport, Error> {
+        Ok(repair::verify(&self.storage, &self.network, &self.events).await?)
+    }
+
+    /// Returns the fault injector of the block store, to test the application on a slow
+    /// or failing disk.
+    #[cfg(feature = "fault-injection")]
+    pub fn faults(&self) -> &FaultInjector {
+        self.storage.faults()
+    }
+
+    /// Subscribes to the blocks removed by the garbage collector, starting with the next
+    /// removal. Subscribers that fall more than `event_capacity` events behind receive a
+    /// `StorageEvent::Lagged` and need to invalidate their caches completely.
+    pub fn subscribe_storage_events(&self) -> StorageEvents {
+        self.storage.subscribe_events()
+    }
+
+    /// Subscribes to the storage events starting at `position`, so that a subscriber
+    /// attaching late doesn't miss the removals since the `position` of an earlier
+    /// subscription.
+    pub fn subscribe_storage_events_from(&self, position: u64) -> StorageEvents {
+        self.storage.subscribe_events_from(position)
+    }
+
+    /// Returns the heartbeats of the background tasks and the lengths of the internal
+    /// queues. The number of storage events the slowest subscriber didn't receive is
+    /// exported as the metric `storage_events_queued`, and the number of events missed
+    /// by lagging subscribers as `storage_events_lagged`.
+    pub fn health(&self) -> Health {
+        self.network.health()
     }
 
-    /// Registers prometheus metrics in a registry.
-    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
+    /// Registers prometheus metrics in a registry. Nothing is registered without
+    /// the `metrics` feature.
+    pub fn register_metrics(&self, registry: &Registry) -> Result<(), Error> {
         self.storage.register_metrics(registry)?;
-        self.network.register_metrics(registry)?;
+        self.network
+            .register_metrics(registry)
+            .map_err(Error::network)?;
         Ok(())
     }
 }
 
+/// Encodes a block with the codec `codec` hashing it with sha2-256.
+pub(crate) fn encode_block<P: StoreParams>(codec: u64, ipld: &Ipld) -> Result<Block<P>>
+where
+    Ipld: Encode<P::Codecs>,
+{
+    let hash = P::Hashes::try_from(SHA2_256).map_err(|_| UnsupportedMultihash(SHA2_256))?;
+    Block::<P>::encode(P::Codecs::try_from(codec)?, hash, ipld)
+}
+
+/// Replaces all links in `ipld` with their re-encoded counterparts.
+fn rewrite_links(ipld: &mut Ipld, reencoded: &FnvHashMap<Cid, Cid>) {
+    match ipld {
+        Ipld::Link(cid) => {
+            if let Some(new) = reencoded.get(cid) {
+                *cid = *new;
+            }
+        }
+        Ipld::List(list) => {
+            for ipld in list {
+                rewrite_links(ipld, reencoded);
+            }
+        }
+        Ipld::StringMap(map) => {
+            for ipld in map.values_mut() {
+                rewrite_links(ipld, reencoded);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn store_pushed<P: StoreParams>(
+    storage: &StorageService<P>,
+    validators: &Validators<P>,
+    peer: PeerId,
+    blocks: &[Block<P>],
+) -> Result<PushedBlocks>
+where
+    Ipld: References<P::Codecs>,
+{
+    for block in blocks {
+        validators.validate(Some(peer), block)?;
+    }
+    let cids: Vec<Cid> = blocks.iter().map(|block| *block.cid()).collect();
+    let tmp = storage.create_temp_pin()?;
+    storage.temp_pin(&tmp, cids.clone())?;
+    for block in blocks {
+        storage.insert(block)?;
+    }
+    Ok(PushedBlocks {
+        peer,
+        blocks: cids,
+        tmp,
+    })
+}
+
 /// Telemetry server
+#[cfg(feature = "telemetry")]
 pub fn telemetry<P: StoreParams>(addr: SocketAddr, ipfs: &Ipfs<P>) -> Result<()>
 where
     Ipld: References<P::Codecs>,
 {
     let registry = prometheus::default_registry();
     ipfs.register_metrics(registry)?;
-    let mut s = tide::new();
-    s.at("/metrics").get(get_metric);
-    async_global_executor::spawn(async move { s.listen(addr).await }).detach();
+    let s = telemetry_server(ipfs);
+    ipfs_embed_rt::spawn(async move { s.listen(addr).await }).detach();
     Ok(())
 }
 
+#[cfg(feature = "telemetry")]
+fn telemetry_server<P: StoreParams>(ipfs: &Ipfs<P>) -> tide::Server<Ipfs<P>>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut s = tide::with_state(ipfs.clone());
+    s.at("/metrics").get(get_metric);
+    s.at("/ipfs/:cid").get(get_block);
+    s
+}
+
+/// Serves the raw blocks of the local store. Blocks on the deny list are answered with
+/// `451 Unavailable For Legal Reasons`.
+#[cfg(feature = "telemetry")]
+async fn get_block<P: StoreParams>(req: tide::Request<Ipfs<P>>) -> tide::Result
+where
+    Ipld: References<P::Codecs>,
+{
+    let cid = match Cid::try_from(req.param("cid")?) {
+        Ok(cid) => cid,
+        Err(_) => return Ok(tide::Response::new(400)),
+    };
+    let response = match req.state().storage.get(&cid) {
+        Ok(Some(data)) => tide::Response::builder(200)
+            .content_type("application/vnd.ipld.raw")
+            .body(tide::Body::from(data))
+            .build(),
+        Ok(None) => tide::Response::new(404),
+        Err(err) if err.downcast_ref::<Denied>().is_some() => tide::Response::new(451),
+        Err(err) => return Err(tide::Error::new(500, err)),
+    };
+    Ok(response)
+}
+
 /// Return metrics to prometheus
-async fn get_metric(_: tide::Request<()>) -> tide::Result {
+#[cfg(feature = "telemetry")]
+async fn get_metric<S>(_: tide::Request<S>) -> tide::Result {
     let encoder = prometheus::TextEncoder::new();
     let metric_families = prometheus::gather();
     let mut buffer = vec![];
 
-    encoder.encode(&metric_families, &mut buffer).unwrap();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+
+    let response = tide::Response::builder(200)
+        .content_type("text/plain; version=0.0.4")
+        .body(tide::Body::from(buffer))
+        .build();
+
+    Ok(response)
+}
+
+#[async_trait]
+impl<P: StoreParams> Store for Ipfs<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    type Params = P;
+    type TempPin = Arc<TempPin>;
+
+    fn create_temp_pin(&self) -> Result<Self::TempPin> {
+        Ok(Arc::new(Ipfs::create_temp_pin(self)?))
+    }
+
+    fn temp_pin(&self, tmp: &Self::TempPin, cid: &Cid) -> Result<()> {
+        Ok(Ipfs::temp_pin(self, tmp, cid)?)
+    }
+
+    fn contains(&self, cid: &Cid) -> Result<bool> {
+        Ok(Ipfs::contains(self, cid)?)
+    }
+
+    fn get(&self, cid: &Cid) -> Result<Block<P>> {
+        Ok(Ipfs::get(self, cid)?)
+    }
+
+    fn insert(&self, block: &Block<P>) -> Result<()> {
+        let _ = Ipfs::insert(self, block)?;
+        Ok(())
+    }
+
+    fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
+        Ok(Ipfs::alias(self, alias, cid)?)
+    }
+
+    fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
+        Ok(Ipfs::resolve(self, alias)?)
+    }
+
+    fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
+        Ok(Ipfs::reverse_alias(self, cid)?)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(Ipfs::flush(self).await?)
+    }
+
+    async fn fetch(&self, cid: &Cid) -> Result<Block<Self::Params>> {
+        Ok(Ipfs::fetch(self, cid).await?)
+    }
+
+    async fn sync(&self, cid: &Cid) -> Result<()> {
+        Ipfs::sync(self, cid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::join;
+    use libipld::cbor::DagCborCodec;
+    use libipld::multihash::{Code, MultihashDigest};
+    use libipld::raw::RawCodec;
+    use libipld::store::DefaultParams;
+    use libipld::{alias, ipld, IpldCodec};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn tracing_try_init() {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .try_init()
+            .ok();
+    }
+
+    async fn create_store(enable_mdns: bool) -> Result<Ipfs<DefaultParams>> {
+        let sweep_interval = Duration::from_millis(10000);
+        let storage = StorageConfig::new(None, 10, sweep_interval);
+
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = enable_mdns;
+        network.allow_non_globals_in_dht = true;
+
+        let ipfs = Ipfs::new(Config { storage, network }).await?;
+        ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        Ok(ipfs)
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ipfs-embed-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Polls `f` until it returns true, failing the test after 10s.
+    async fn eventually(mut f: impl FnMut() -> bool) {
+        let start = std::time::Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            Timer::after(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn create_block(bytes: &[u8]) -> Result<Block<DefaultParams>> {
+        Block::encode(RawCodec, Code::Blake3_256, bytes)
+    }
+
+    #[async_std::test]
+    async fn test_local_store() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let block = create_block(b"test_local_store")?;
+        let tmp = store.create_temp_pin()?;
+        store.temp_pin(&tmp, block.cid())?;
+        let _ = store.insert(&block)?;
+        let block2 = store.get(block.cid())?;
+        assert_eq!(block.data(), block2.data());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_want() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let block = create_block(b"test_want")?;
+        let want = store.want(block.cid())?;
+        let tmp = store.create_temp_pin()?;
+        store.temp_pin(&tmp, block.cid())?;
+        let _ = store.insert(&block)?;
+        assert_eq!(want.await.data(), block.data());
+        assert_eq!(store.want(block.cid())?.await.data(), block.data());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_listener() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let mut events = store.swarm_events();
+        let id = store.add_listener("/ip4/127.0.0.1/tcp/0".parse()?)?;
+        assert_eq!(
+            events.next().await,
+            Some(Event::NewListener(id, "/ip4/127.0.0.1/tcp/0".parse()?))
+        );
+        let addr = loop {
+            if let Some(Event::NewListenAddr(addr)) = events.next().await {
+                break addr;
+            }
+        };
+        assert!(store.listeners().contains(&addr));
+        assert!(store.remove_listener(id));
+        assert!(!store.remove_listener(id));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_subscribe_events() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let mut connections = store1.subscribe_events(EventFilter::Connections);
+        let mut listeners = store1.subscribe_events(EventFilter::Listeners | EventFilter::Gc);
+        store1.add_address(&store2.local_peer_id(), store2.listeners()[0].clone());
+        store1.dial(&store2.local_peer_id())?;
+        assert_eq!(
+            connections.next().await,
+            Some(NodeEvent::Swarm(Event::Connected(store2.local_peer_id())))
+        );
+        store1.add_listener("/ip4/127.0.0.1/tcp/0".parse()?)?;
+        loop {
+            match listeners.next().await {
+                Some(NodeEvent::Swarm(Event::NewListener(_, _))) => break,
+                Some(NodeEvent::Swarm(Event::NewListenAddr(_))) => {}
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(connections.dropped(), 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_peer_rtt() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let mut events = store1.subscribe_events(EventFilter::Latency);
+        store1.add_address(&store2.local_peer_id(), store2.listeners()[0].clone());
+        store1.dial(&store2.local_peer_id())?;
+        match events.next().await {
+            Some(NodeEvent::Swarm(Event::Rtt(peer, rtt))) => {
+                assert_eq!(peer, store2.local_peer_id());
+                assert_eq!(store1.peer_rtt(&peer), Some(rtt));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_diagnose() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let config = DiagnoseConfig {
+            echo_peer: Some((store2.local_peer_id(), Some(store2.listeners()[0].clone()))),
+            ..Default::default()
+        };
+        let report = store1.diagnose(&config).await;
+        assert!(!report.listeners.is_empty());
+        assert!(report.dht_bootstrap.is_none());
+        let (peer, echo) = report.bitswap_echo.clone().unwrap();
+        assert_eq!(peer, store2.local_peer_id());
+        assert!(echo.is_ok());
+        assert!(report.is_healthy());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_priority() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        store2.dial_address(&store1.local_peer_id(), store1.listeners()[0].clone())?;
+        let mut root = create_ipld_block(&ipld!(null))?;
+        let _ = store1.insert(&root)?;
+        for i in 0..5 {
+            root = create_ipld_block(&ipld!({ "i": i, "prev": root.cid() }))?;
+            let _ = store1.insert(&root)?;
+        }
+        let block = create_block(b"test_sync_priority")?;
+        let _ = store1.insert(&block)?;
+        store1.alias(alias!(x), Some(root.cid()))?;
+        store1.flush().await?;
+
+        store2.alias(alias!(x), Some(root.cid()))?;
+        let tmp = store2.create_temp_pin()?;
+        store2.temp_pin(&tmp, block.cid())?;
+        let order = Mutex::new(vec![]);
+        let sync = async {
+            let res = store2
+                .sync_with_priority(root.cid(), Priority::Background)
+                .await;
+            order.lock().push(Priority::Background);
+            res
+        };
+        let fetch = async {
+            let res = store2
+                .fetch_with_priority(block.cid(), Priority::Interactive)
+                .await;
+            order.lock().push(Priority::Interactive);
+            res
+        };
+        let (synced, fetched) = futures::future::join(sync, fetch).await;
+        synced?;
+        assert_eq!(fetched?.data(), block.data());
+        assert!(store2.contains(root.cid())?);
+        assert_eq!(
+            *order.lock(),
+            vec![Priority::Interactive, Priority::Background]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_denylist() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_block(b"test_denylist_a")?;
+        let b = create_block(b"test_denylist_b")?;
+        let _ = store.insert(&a)?;
+        let list = format!(
+            "# moderation feed\n{}\n\n{}\n!{}\n",
+            a.cid(),
+            b.cid(),
+            b.cid()
+        );
+        assert_eq!(store.apply_denylist(list.as_bytes())?, 3);
+        assert_eq!(store.denylist(), vec![*a.cid()]);
+        let err = store.get(a.cid()).err().unwrap();
+        assert!(err.downcast_ref::<Denied>().is_some());
+        assert!(store.apply_denylist(&b"not a cid"[..]).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "telemetry")]
+    #[async_std::test]
+    async fn test_gateway_denylist() -> Result<()> {
+        use tide::http::{Method, Request, Response, Url};
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_block(b"test_gateway_denylist")?;
+        let _ = store.insert(&a)?;
+        let server = telemetry_server(&store);
+        let get = |cid: &Cid| {
+            let url = Url::parse(&format!("http://localhost/ipfs/{}", cid)).unwrap();
+            server.respond::<_, Response>(Request::new(Method::Get, url))
+        };
+        let mut res = get(a.cid()).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_bytes().await.unwrap(), a.data());
+        store.deny(a.cid())?;
+        assert_eq!(get(a.cid()).await.unwrap().status(), 451);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_app_streams() -> Result<()> {
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let mut incoming = store2.listen_streams("/ipfs-embed/test/1.0.0")?;
+        store1.add_address(&store2.local_peer_id(), store2.listeners()[0].clone());
+        let mut stream = store1
+            .open_stream(&store2.local_peer_id(), "/ipfs-embed/test/1.0.0")
+            .await?;
+        stream.write_all(b"ping").await?;
+        stream.close().await?;
+        let (peer, mut stream2) = incoming.next().await.unwrap();
+        assert_eq!(peer, store1.local_peer_id());
+        assert_eq!(stream2.protocol(), "/ipfs-embed/test/1.0.0");
+        let mut buf = vec![];
+        stream2.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"ping");
+        assert!(store1.open_stream(&peer, "no-slash").await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_rendezvous() -> Result<()> {
+        tracing_try_init();
+        let sweep_interval = Duration::from_millis(10000);
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.enable_kad = false;
+        network.enable_rendezvous_server = true;
+        let point = Ipfs::<DefaultParams>::new(Config {
+            storage: StorageConfig::new(None, 10, sweep_interval),
+            network,
+        })
+        .await?;
+        let point_addr = point.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        let point_id = point.local_peer_id();
+
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        for store in &[&store1, &store2] {
+            store.add_address(&point_id, point_addr.clone());
+        }
+        let err = store1
+            .rendezvous_register(&point_id, "test", Duration::from_secs(60))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<RendezvousRejected>().is_some());
+        let ttl = Duration::from_secs(60 * 60);
+        assert_eq!(
+            store1.rendezvous_register(&point_id, "test", ttl).await?,
+            ttl
+        );
+        let peers = store2.rendezvous_discover(&point_id, "test").await?;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].0, store1.local_peer_id());
+        assert!(peers[0].1.contains(&store1.listeners()[0]));
+        let info = store2.peer_info(&store1.local_peer_id()).unwrap();
+        assert!(info
+            .addresses()
+            .all(|(_, source)| source == AddressSource::Rendezvous));
+        assert!(store1
+            .rendezvous_discover(&point_id, "other")
+            .await?
+            .is_empty());
+
+        store1.rendezvous_unregister(&point_id, "test").await?;
+        assert!(store2
+            .rendezvous_discover(&point_id, "test")
+            .await?
+            .is_empty());
+
+        let err = store1
+            .rendezvous_discover(&store2.local_peer_id(), "test")
+            .await
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<RendezvousRejected>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_resolve_path() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_ipld_block(&ipld!({ "name": "a" }))?;
+        let b = create_ipld_block(&ipld!({ "children": [a.cid()] }))?;
+        let c = create_ipld_block(&ipld!({ "b": b.cid() }))?;
+        for block in &[&a, &b, &c] {
+            let _ = store.insert(block)?;
+        }
+        let unlimited = TraversalLimits::unlimited();
+        let name = store.resolve_path(c.cid(), "/b/children/0/name", &unlimited)?;
+        assert_eq!(name, ipld!("a"));
+        let err = store
+            .resolve_path(c.cid(), "b/children/1", &unlimited)
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<PathNotFound>().is_some());
+        // 4 path segments across 3 blocks.
+        let limits = TraversalLimits {
+            max_depth: Some(4),
+            max_blocks: Some(3),
+            ..Default::default()
+        };
+        let name = store.resolve_path(c.cid(), "b/children/0/name", &limits)?;
+        assert_eq!(name, ipld!("a"));
+        let limits = TraversalLimits {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let err = store
+            .resolve_path(c.cid(), "b/children/0/name", &limits)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::Depth(2))
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_limits() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        store2.dial_address(&store1.local_peer_id(), store1.listeners()[0].clone())?;
+        let mut root = create_ipld_block(&ipld!(null))?;
+        let _ = store1.insert(&root)?;
+        for i in 0..5 {
+            root = create_ipld_block(&ipld!({ "i": i, "prev": root.cid() }))?;
+            let _ = store1.insert(&root)?;
+        }
+        store1.alias(alias!(x), Some(root.cid()))?;
+        store1.flush().await?;
+
+        store2.alias(alias!(x), Some(root.cid()))?;
+        let limits = TraversalLimits {
+            max_blocks: Some(2),
+            ..Default::default()
+        };
+        let err = store2
+            .sync_with_limits(root.cid(), Priority::Interactive, limits)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::Blocks(2))
+        );
+        Ok(())
+    }
+
+    fn write_varint(buf: &mut Vec<u8>, mut n: usize) {
+        while n >= 0x80 {
+            buf.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        buf.push(n as u8);
+    }
+
+    fn base32_encode(bytes: &[u8]) -> String {
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+        let mut out = String::new();
+        let (mut buf, mut bits) = (0u32, 0);
+        for byte in bytes {
+            buf = buf << 8 | *byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(alphabet[(buf >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            out.push(alphabet[(buf << (5 - bits)) as usize & 31] as char);
+        }
+        out
+    }
+
+    #[async_std::test]
+    async fn test_import() -> Result<()> {
+        use libipld::codec::Codec;
+        tracing_try_init();
+        let a = create_block(b"a")?;
+        let b = create_ipld_block(&ipld!({ "a": a.cid() }))?;
+        let c = create_block(b"c")?;
+
+        let mut car = vec![];
+        let header = DagCborCodec.encode(&ipld!({ "roots": [b.cid()], "version": 1 }))?;
+        write_varint(&mut car, header.len());
+        car.extend_from_slice(&header);
+        for block in &[&a, &b, &c] {
+            let cid = block.cid().to_bytes();
+            write_varint(&mut car, cid.len() + block.data().len());
+            car.extend_from_slice(&cid);
+            car.extend_from_slice(block.data());
+        }
+        let store = create_store(false).await?;
+        let report = store.import_car(&car[..])?;
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.pins, vec![*b.cid()]);
+        assert_eq!(store.resolve(import_alias(b.cid()))?, Some(*b.cid()));
+        assert_eq!(store.get(c.cid())?.data(), c.data());
+        assert_eq!(report.duplicate_blocks, 0);
+        let size = (a.data().len() + b.data().len() + c.data().len()) as u64;
+        assert_eq!(report.new_bytes, size);
+
+        let report = store.import_car(&car[..])?;
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.duplicate_blocks, 3);
+        assert_eq!(report.new_bytes, 0);
+        assert_eq!(report.duplicate_bytes, size);
+
+        let d = create_block(b"d")?;
+        let report = store.import_blocks(vec![a.clone(), d.clone()], vec![*d.cid()])?;
+        assert_eq!(report.blocks, 2);
+        assert_eq!(report.duplicate_blocks, 1);
+        assert_eq!(report.new_bytes, d.data().len() as u64);
+        assert_eq!(report.pins, vec![*d.cid()]);
+
+        let repo = temp_dir("import");
+        for block in &[&a, &b, &c] {
+            let key = base32_encode(&block.cid().hash().to_bytes());
+            let shard = repo.join("blocks").join(&key[key.len() - 3..key.len() - 1]);
+            std::fs::create_dir_all(&shard)?;
+            std::fs::write(shard.join(format!("{}.data", key)), block.data())?;
+        }
+        let store = create_store(false).await?;
+        let report = store.import_flatfs(&repo, &[*b.cid()])?;
+        std::fs::remove_dir_all(&repo).ok();
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.missing_blocks, 0);
+        assert_eq!(store.resolve(import_alias(b.cid()))?, Some(*b.cid()));
+        assert_eq!(store.get(a.cid())?.data(), a.data());
+        let raw = Cid::new_v1(RAW, *c.cid().hash());
+        assert_eq!(store.get(&raw)?.data(), c.data());
+        assert_eq!(report.raw_blocks, 1);
+        let aliases = store.reverse_alias(&raw)?.unwrap();
+        assert!(aliases.contains(&b"/go-ipfs/raw".to_vec()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_audit_log() -> Result<()> {
+        tracing_try_init();
+        let dir = temp_dir("audit");
+        let path = dir.join("audit.log");
+        let sweep_interval = Duration::from_millis(10000);
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        let mut audit = AuditConfig::new(path.clone());
+        audit.max_file_size = 1;
+        network.audit_log = Some(audit);
+        let store = Ipfs::<DefaultParams>::new(Config {
+            storage: StorageConfig::new(None, 10, sweep_interval),
+            network,
+        })
+        .await?;
+        store.publish("test_audit_log", b"a".to_vec()).ok();
+        store.publish("test_audit_log", b"b".to_vec()).ok();
+        // the lines are written in the background.
+        eventually(|| {
+            let len = |path: &Path| std::fs::metadata(path).map(|meta| meta.len());
+            len(&dir.join("audit.log.1")).unwrap_or_default() > 0
+                && len(&path).unwrap_or_default() > 0
+        })
+        .await;
+        let rotated = std::fs::read_to_string(dir.join("audit.log.1"))?;
+        let current = std::fs::read_to_string(&path)?;
+        std::fs::remove_dir_all(&dir).ok();
+        for log in &[rotated, current] {
+            let fields = log.trim_end().split('\t').collect::<Vec<_>>();
+            assert_eq!(fields[1], AuditKind::GossipPublish.to_string());
+            assert_eq!(fields[2], "test_audit_log");
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_tenant() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        assert!(store.tenant("a/b").is_err());
+        let tenant = store.tenant("acme")?;
+        assert_eq!(tenant.topic("chat"), "/tenant/acme/chat");
+        let block = create_block(b"test_tenant")?;
+        store.insert(&block)?;
+        tenant.alias("root", Some(block.cid()))?;
+        assert_eq!(tenant.resolve("root")?, Some(*block.cid()));
+        assert_eq!(store.resolve("root")?, None);
+        assert_eq!(store.tenant("other")?.resolve("root")?, None);
+
+        let err = store
+            .alias("/tenant/acme/root", Some(block.cid()))
+            .unwrap_err();
+        assert!(err.downcast_ref::<ReservedAlias>().is_some());
+
+        let mut bitswap = store.bitswap_store();
+        assert_eq!(bitswap.get(block.cid())?, None);
+
+        // members fetch the blocks of the tenant, other peers are rejected.
+        store.serve_tenant_blocks()?;
+        let member = create_store(false).await?;
+        let other = create_store(false).await?;
+        for node in &[&member, &other] {
+            node.add_address(&store.local_peer_id(), store.listeners()[0].clone());
+        }
+        tenant.add_member(member.local_peer_id());
+        assert!(tenant.is_member(&member.local_peer_id()));
+        assert_eq!(tenant.members(), vec![member.local_peer_id()]);
+        let fetched = member
+            .tenant("acme")?
+            .fetch(&store.local_peer_id(), block.cid())
+            .await?;
+        assert_eq!(fetched.data(), block.data());
+        let err = other
+            .tenant("acme")?
+            .fetch(&store.local_peer_id(), block.cid())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<TenantBlockRejected>().is_some());
+        tenant.remove_member(&member.local_peer_id());
+        assert!(!tenant.is_member(&member.local_peer_id()));
+
+        store.alias("public", Some(block.cid()))?;
+        assert!(bitswap.get(block.cid())?.is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_retry_policy() -> Result<()> {
+        tracing_try_init();
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(300),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(300));
+        assert_eq!(backoff.delay(40), Duration::from_millis(300));
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Backoff::Constant(Duration::from_millis(0)),
+            retry_on: vec![ErrorClass::NotFound],
+        };
+        let cid = *create_block(b"test_retry_policy")?.cid();
+        let attempts = std::cell::Cell::new(0);
+        let err = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err::<(), _>(BlockNotFound(cid).into()) }
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(ErrorClass::of(&err), ErrorClass::NotFound);
+        assert_eq!(attempts.get(), 3);
+        attempts.set(0);
+        policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err::<(), _>(anyhow::anyhow!("other")) }
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(attempts.get(), 1);
+
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.retry_policy = policy;
+        let store = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let err = store.fetch(&cid).await.unwrap_err();
+        assert!(matches!(err, Error::Network(_)));
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_channel() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(true).await?;
+        let store2 = create_store(true).await?;
+        let channel1 = store1.channel("test_channel", 2);
+        for msg in &[b"a", b"b", b"c"] {
+            channel1.publish(msg.to_vec())?;
+        }
+        assert_eq!(channel1.history()?, vec![b"b".to_vec(), b"c".to_vec()]);
+
+        let channel2 = store2.channel("test_channel", 2);
+        assert!(channel2.history()?.is_empty());
+        let head = channel1.head()?.unwrap();
+        assert_eq!(
+            channel2.replay(&head).await?,
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_presets() -> Result<()> {
+        tracing_try_init();
+        let gateway = Config::gateway(None, 1024 * 1024);
+        assert_eq!(gateway.storage.cache_size_bytes, 1024 * 1024);
+        // the target duration only bounds a gc run if the minimum isn't unbounded.
+        assert!(gateway.storage.gc_min_blocks < usize::MAX);
+        let archival = Config::archival(None);
+        assert_eq!(archival.storage.cache_size_bytes, u64::MAX);
+        let edge = Config::edge_cache(None, 1024 * 1024);
+        assert!(edge.storage.gc_triggers.min_free_disk.is_some());
+        assert!(edge.storage.gc_min_blocks < usize::MAX);
+        for config in &[&gateway, &archival, &edge] {
+            assert!(config.network.max_incoming_connections.is_some());
+        }
+        assert!(gateway.network.bitswap_serve_reads > edge.network.bitswap_serve_reads);
+        assert!(gateway.network.bitswap_serve_peer_rate > edge.network.bitswap_serve_peer_rate);
+        for config in vec![gateway, archival, edge] {
+            let store = Ipfs::<DefaultParams>::new(config).await?;
+            let block = create_block(b"test_presets")?;
+            let _ = store.insert(&block)?;
+            assert!(store.contains(block.cid())?);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_aliases_root() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(true).await?;
+        let store2 = create_store(true).await?;
+        let a = create_block(b"test_aliases_root_a")?;
+        let b = create_block(b"test_aliases_root_b")?;
+        let _ = store1.insert(&a)?;
+        let _ = store1.insert(&b)?;
+        store1.alias("a", Some(a.cid()))?;
+        let root1 = store1.aliases_root()?;
+        assert_eq!(store1.aliases_root()?, root1);
+        store1.alias("b", Some(b.cid()))?;
+        let root2 = store1.aliases_root()?;
+        assert_ne!(root1, root2);
+
+        let tmp = store2.create_temp_pin()?;
+        store2.temp_pin(&tmp, &root2)?;
+        store2.sync(&root2).await?;
+        assert_eq!(store2.import_aliases(&root2)?, 2);
+        assert_eq!(store2.resolve("a")?, Some(*a.cid()));
+        assert_eq!(store2.resolve("b")?, Some(*b.cid()));
+        assert!(store2.contains(b.cid())?);
+        Ok(())
+    }
+
+    /// Starts a socks5 proxy without authentication that only supports ipv4 targets,
+    /// returning its address and the number of proxied connections.
+    async fn socks5_proxy() -> Result<(SocketAddr, Arc<std::sync::atomic::AtomicUsize>)> {
+        use async_std::net::{TcpListener, TcpStream};
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+        use std::sync::atomic::Ordering;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count2 = count.clone();
+        async_std::task::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                count2.fetch_add(1, Ordering::SeqCst);
+                async_std::task::spawn(async move {
+                    let mut buf = [0; 255];
+                    client.read_exact(&mut buf[..2]).await?;
+                    client.read_exact(&mut buf[..buf[1] as usize]).await?;
+                    client.write_all(&[5, 0]).await?;
+                    client.read_exact(&mut buf[..4]).await?;
+                    let mut ip = [0; 4];
+                    client.read_exact(&mut ip).await?;
+                    let mut port = [0; 2];
+                    client.read_exact(&mut port).await?;
+                    let server = TcpStream::connect((ip, u16::from_be_bytes(port))).await?;
+                    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                    let (mut client2, mut server2) = (client.clone(), server.clone());
+                    futures::future::try_join(
+                        futures::io::copy(&mut client, &mut server2),
+                        futures::io::copy(&mut server, &mut client2),
+                    )
+                    .await?;
+                    Ok::<_, std::io::Error>(())
+                });
+            }
+        });
+        Ok((addr, count))
+    }
+
+    #[async_std::test]
+    async fn test_socks5() -> Result<()> {
+        tracing_try_init();
+        let (proxy, count) = socks5_proxy().await?;
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.socks5 = Some(Socks5Config::new(proxy));
+        let store1 = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let store2 = create_store(false).await?;
+        let _incoming = store2.listen_streams("/ipfs-embed/test/1.0.0")?;
+        store1.add_address(&store2.local_peer_id(), store2.listeners()[0].clone());
+        store1
+            .open_stream(&store2.local_peer_id(), "/ipfs-embed/test/1.0.0")
+            .await?;
+        assert!(count.load(std::sync::atomic::Ordering::SeqCst) > 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dht_mode() -> Result<()> {
+        tracing_try_init();
+        let sweep_interval = Duration::from_millis(10000);
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.dht_mode = DhtMode::Client;
+        let client = Ipfs::<DefaultParams>::new(Config {
+            storage: StorageConfig::new(None, 10, sweep_interval),
+            network,
+        })
+        .await?;
+        assert_eq!(client.dht_mode(), Some(DhtMode::Client));
+
+        let store = create_store(false).await?;
+        assert_eq!(store.dht_mode(), Some(DhtMode::Server));
+        assert_eq!(store.nat_status(), Some(NatStatus::Unknown));
+        // the peer dials the store back at its listen address.
+        let peer = create_store(false).await?;
+        peer.dial_address(&store.local_peer_id(), store.listeners()[0].clone())?;
+        eventually(|| store.nat_status() == Some(NatStatus::Public)).await;
+        assert_eq!(store.dht_mode(), Some(DhtMode::Server));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_verify_pins() -> Result<()> {
+        tracing_try_init();
+        let local1 = create_store(true).await?;
+        let local2 = create_store(true).await?;
+        let a = create_ipld_block(&ipld!({ "a": 0 }))?;
+        let b = create_ipld_block(&ipld!({ "b": 0 }))?;
+        let c = create_ipld_block(&ipld!({ "c": [a.cid(), b.cid()] }))?;
+        let x = alias!(x);
+
+        let _ = local1.insert(&a)?;
+        let _ = local1.insert(&c)?;
+        local1.alias(x, Some(c.cid()))?;
+        let _ = local2.insert(&b)?;
+        local2.alias(x, Some(b.cid()))?;
+        local2.flush().await?;
+
+        let mut events = local1.subscribe_events(EventFilter::Repair);
+        let report = local1.verify_pins().await?;
+        assert_eq!(report.roots, 1);
+        assert_eq!(report.missing_blocks, 1);
+        assert_eq!(report.repaired, vec![*c.cid()]);
+        assert!(report.failed.is_empty());
+        assert_eq!(
+            events.next().await,
+            Some(NodeEvent::MissingBlocks(*c.cid(), 1))
+        );
+        assert_eq!(events.next().await, Some(NodeEvent::Repaired(*c.cid())));
+        assert_eq!(local1.get(b.cid())?.data(), b.data());
+
+        let report = local1.verify_pins().await?;
+        assert_eq!(report.missing_blocks, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_listen_addr_futures() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        assert_eq!(store.wait_for_listeners(1).await?, store.listeners());
+        let listeners = store.wait_for_listeners(2);
+        let next = store.next_listen_addr();
+        store.add_listener("/ip4/127.0.0.1/tcp/0".parse()?)?;
+        let addr = next.await?;
+        let listeners = listeners.await?;
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners.contains(&addr));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_many() -> Result<()> {
+        tracing_try_init();
+        let local1 = create_store(true).await?;
+        let local2 = create_store(true).await?;
+        let a = create_ipld_block(&ipld!({ "a": 0 }))?;
+        let b = create_ipld_block(&ipld!({ "b": [a.cid()] }))?;
+        let c = create_ipld_block(&ipld!({ "c": [a.cid()] }))?;
+        for block in &[&a, &b, &c] {
+            let _ = local1.insert(block)?;
+        }
+        local1.alias("b", Some(b.cid()))?;
+        local1.alias("c", Some(c.cid()))?;
+        local1.flush().await?;
+
+        local2.alias("b", Some(b.cid()))?;
+        local2.alias("c", Some(c.cid()))?;
+        local2.sync_many(vec![*b.cid(), *c.cid()]).await?;
+        for block in &[&a, &b, &c] {
+            assert_eq!(local2.get(block.cid())?.data(), block.data());
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_direct_messages() -> Result<()> {
+        tracing_try_init();
+        let a = create_store(false).await?;
+        let b = create_store(false).await?;
+        let mut messages = b.direct_messages();
+        a.dial_address(&b.local_peer_id(), b.listeners()[0].clone())?;
+        eventually(|| a.peers().contains(&b.local_peer_id())).await;
+
+        a.send_direct(&b.local_peer_id(), b"hello".to_vec())?;
+        let (peer, msg) = messages.next().await.unwrap();
+        assert_eq!(peer, a.local_peer_id());
+        assert_eq!(msg, b"hello".to_vec());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_error() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let block = create_block(b"test_error")?;
+        let err = store.get(block.cid()).unwrap_err();
+        assert!(matches!(err, Error::Store(_)));
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+        let err = store.fetch(block.cid()).await.unwrap_err();
+        assert!(matches!(err, Error::Network(_) | Error::Timeout(_)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_cluster() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(true).await?;
+        let store2 = create_store(true).await?;
+        let writers = vec![store1.local_peer_id()];
+        let cluster1 = store1.cluster("test_cluster", writers.clone());
+        let cluster2 = store2.cluster("test_cluster", writers);
+        let a = create_block(b"test_cluster_a")?;
+        let b = create_block(b"test_cluster_b")?;
+        let _ = store1.insert(&a)?;
+        let _ = store1.insert(&b)?;
+        cluster1.pin(a.cid()).await?;
+        let head = cluster1.pin(b.cid()).await?;
+        cluster1.unpin(a.cid()).await?;
+        assert_eq!(cluster1.pinset()?, vec![*b.cid()]);
+
+        let err = cluster2.pin(a.cid()).await.unwrap_err();
+        assert!(err.downcast_ref::<NotAWriter>().is_some());
+        let provenance = store1.block_provenance(&head)?.remove(0);
+        assert!(cluster2.update(&head, &provenance).await?);
+        assert_eq!(cluster2.pinset()?, vec![*a.cid(), *b.cid()]);
+        assert!(store2.contains(a.cid())?);
+        assert!(store2.contains(b.cid())?);
+
+        let head = cluster1.head()?.unwrap();
+        let provenance = store1.block_provenance(&head)?.remove(0);
+        assert!(cluster2.update(&head, &provenance).await?);
+        assert_eq!(cluster2.pinset()?, vec![*b.cid()]);
+        assert_eq!(
+            store2.aliases_with_prefix("/ipfs-embed/cluster/test_cluster/pins/")?,
+            vec![(
+                format!("/ipfs-embed/cluster/test_cluster/pins/{}", b.cid()).into_bytes(),
+                *b.cid()
+            )]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_block_validator() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.enable_push = true;
+        let store2 = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let addr = store2.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        store1.add_address(&store2.local_peer_id(), addr);
+        store2.add_block_validator(|block: &Block<DefaultParams>| {
+            if block.data() == b"invalid" {
+                return Err(anyhow::anyhow!("invalid data"));
+            }
+            Ok(())
+        });
+
+        let mut events = store2.subscribe_events(EventFilter::Validation);
+        let block = create_block(b"invalid")?;
+        store1
+            .push(&store2.local_peer_id(), vec![block.clone()])
+            .await?;
+        let event = events.next().await.unwrap();
+        let peer = store1.local_peer_id();
+        assert_eq!(
+            event,
+            NodeEvent::InvalidBlock(Some(peer), *block.cid(), "invalid data".into())
+        );
+        assert_eq!(store2.invalid_blocks(&peer), 1);
+        assert!(!store2.contains(block.cid())?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_block_validator_ban() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.enable_push = true;
+        let store2 = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let addr = store2.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        store1.add_address(&store2.local_peer_id(), addr);
+        store2.set_invalid_block_threshold(Some(1));
+        store2.add_block_validator(|_: &Block<DefaultParams>| Err(anyhow::anyhow!("invalid")));
+
+        let block = create_block(b"test_block_validator_ban")?;
+        store1.push(&store2.local_peer_id(), vec![block]).await?;
+        let peer = store1.local_peer_id();
+        eventually(|| store2.invalid_blocks(&peer) == 1).await;
+        eventually(|| store2.connections().iter().all(|(p, _)| *p != peer)).await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_checkpoint() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(true).await?;
+        let store2 = create_store(true).await?;
+        let store3 = create_store(false).await?;
+        let a = create_block(b"test_checkpoint_a")?;
+        let b = create_block(b"test_checkpoint_b")?;
+        let _ = store1.insert(&a)?;
+        let _ = store1.insert(&b)?;
+        store1.alias("a", Some(a.cid()))?;
+        store1.alias("b", Some(b.cid()))?;
+        let manifest = store1.checkpoint()?;
+        assert_eq!(store1.checkpoint()?, manifest);
+
+        assert_eq!(store2.restore(&manifest).await?, 2);
+        assert_eq!(store2.resolve("a")?, Some(*a.cid()));
+        assert_eq!(store2.get(b.cid())?.data(), b.data());
+
+        let mut car = vec![];
+        assert_eq!(store1.export_car(&manifest, &mut car)?, 5);
+        store3.import_car(&car[..])?;
+        assert_eq!(store3.restore(&manifest).await?, 2);
+        assert_eq!(store3.resolve("b")?, Some(*b.cid()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_health() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let health = store.health();
+        let tasks = health.tasks();
+        let (_, elapsed) = tasks.iter().find(|(task, _)| task == "swarm").unwrap();
+        assert!(*elapsed < Duration::from_secs(5));
+        assert!(tasks.iter().any(|(task, _)| task == "gc"));
+        assert!(health.stale_tasks().is_empty());
+        assert!(health
+            .queues()
+            .iter()
+            .any(|(queue, len)| queue == "bitswap_serve_reads" && *len == 0));
+        let registry = Registry::new();
+        store.register_metrics(&registry)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_root() -> Result<()> {
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().into_peer_id();
+        let root = *create_block(b"test_follow_root")?.cid();
+        let bytes = follow::encode_root(&keypair, "topic", 1, &root)?;
+        assert_eq!(follow::decode_root(&bytes, &peer, "topic")?, (1, root));
+        let err = follow::decode_root(&bytes, &peer, "other").unwrap_err();
+        assert!(err.downcast_ref::<InvalidRoot>().is_some());
+        let other = Keypair::generate_ed25519().public().into_peer_id();
+        let err = follow::decode_root(&bytes, &other, "topic").unwrap_err();
+        assert!(err.downcast_ref::<InvalidRoot>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_follow_peer() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let peer = Keypair::generate_ed25519().public().into_peer_id();
+        let root = create_block(b"test_follow_peer")?;
+        let _ = store.insert(&root)?;
+        assert_eq!(follow::last_seq(&store, &peer, "topic")?, None);
+        let alias = follow::follow_alias(&peer, "topic");
+        store.alias_with_meta(&alias, root.cid(), &42u64.to_be_bytes())?;
+        assert_eq!(follow::last_seq(&store, &peer, "topic")?, Some(42));
+
+        store.follow_peer(peer, "topic")?;
+        store.follow_peer(peer, "topic")?;
+        assert_eq!(store.follows.lock().len(), 1);
+        store.unfollow_peer(&peer, "topic");
+        assert!(store.follows.lock().is_empty());
+        assert_eq!(store.followed_root(&peer, "topic")?, Some(*root.cid()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_alias_search() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let a = create_block(b"test_alias_search_a")?;
+        let b = create_block(b"test_alias_search_b")?;
+        let _ = store2.insert(&a)?;
+        let _ = store2.insert(&b)?;
+        store2.alias("shared/a", Some(a.cid()))?;
+        store2.alias("private/b", Some(b.cid()))?;
+        let config = AliasSearchConfig::new(vec![store1.local_peer_id()], "shared/");
+        store2.serve_alias_search(config)?;
+        let peer = store2.local_peer_id();
+        store1.add_address(&peer, store2.listeners()[0].clone());
+        let aliases = store1.search_aliases(&peer, "shared/").await?;
+        assert_eq!(aliases, vec![(b"shared/a".to_vec(), *a.cid())]);
+        let err = store1.search_aliases(&peer, "private/").await.unwrap_err();
+        assert!(err.downcast_ref::<SearchRejected>().is_some());
+        let err = store2
+            .search_aliases(&store1.local_peer_id(), "shared/")
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<SearchRejected>().is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_get_decoded() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_ipld_block(&ipld!({ "name": "a" }))?;
+        let b = create_ipld_block(&ipld!({ "a": a.cid() }))?;
+        let _ = store.insert(&a)?;
+        let _ = store.insert(&b)?;
+        assert_eq!(store.get_raw(a.cid())?, a.data());
+        let ipld: Ipld = store.get_decoded(a.cid()).await?;
+        assert_eq!(ipld, ipld!({ "name": "a" }));
+        store.set_decoded_cache_size(1);
+        let unlimited = TraversalLimits::unlimited();
+        for _ in 0..2 {
+            let name = store.resolve_path(b.cid(), "a/name", &unlimited)?;
+            assert_eq!(name, ipld!("a"));
+        }
+        assert_eq!(
+            store.decoded.lock().get(a.cid()),
+            Some((ipld, a.data().len()))
+        );
+        assert!(store.decoded.lock().get(b.cid()).is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_cid_strings() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let block = create_block(b"test_cid_strings")?;
+        let tmp = store.create_temp_pin()?;
+        store.temp_pin(&tmp, block.cid().to_string())?;
+        store.insert(&block)?;
+        let encoded = format_cid(block.cid(), Base::Base32Lower);
+        assert_eq!(store.get(encoded.as_str())?.data(), block.data());
+        assert!(matches!(store.get("not a cid"), Err(Error::InvalidCid(_))));
+        store.alias_to(b"root", encoded.as_str())?;
+        assert_eq!(store.resolve(b"root")?, Some(*block.cid()));
+        store.sync(encoded.as_str()).await?;
+        assert!(store.sync("not a cid").await.is_err());
+        assert!(matches!(
+            store.alias_to(b"root", "not a cid"),
+            Err(Error::InvalidCid(_))
+        ));
+
+        let hash = Code::Sha2_256.digest(b"test_cid_strings");
+        let v0 = Cid::new_v0(hash)?;
+        let v1 = cid_v1(&v0);
+        assert_eq!(v1.version(), libipld::cid::Version::V1);
+        assert_eq!(cid_v0(&v1)?, v0);
+        assert!(cid_v0(block.cid()).is_err());
+        assert_eq!(format_cid(&v0, Base::Base58Btc), v0.to_string());
+        assert_eq!(format_cid(&v0, Base::Base32Lower), v1.to_string());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_get_or_fetch() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let block = create_block(b"test_get_or_fetch")?;
+        let err = store
+            .get_or_fetch(block.cid(), FetchPolicy::Offline)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+        let err = store
+            .get_or_fetch(block.cid(), FetchPolicy::Fast(Duration::from_millis(100)))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Network(_) | Error::Timeout(_)));
+        let tmp = store.create_temp_pin()?;
+        store.temp_pin(&tmp, block.cid())?;
+        store.insert(&block)?;
+        for policy in [
+            FetchPolicy::Offline,
+            FetchPolicy::Fast(Duration::from_millis(100)),
+            FetchPolicy::Thorough,
+        ]
+        .iter()
+        {
+            let fetched = store.get_or_fetch(block.cid(), *policy).await?;
+            assert_eq!(fetched.data(), block.data());
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dagbuilder() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let tmp = store.create_temp_pin()?;
+        let config = DagBuilderConfig {
+            fanout: 4,
+            chunk_size: 3,
+            ..Default::default()
+        };
+
+        let build = |n: i128| -> Result<(Cid, Vec<Block<DefaultParams>>)> {
+            let mut tree = TreeBuilder::new(config)?;
+            let mut blocks = vec![];
+            for i in 0..n {
+                blocks.extend(tree.push(ipld!(i))?);
+            }
+            let (root, rest) = tree.finish()?;
+            blocks.extend(rest);
+            Ok((root, blocks))
+        };
+        let (root, blocks) = build(10)?;
+        assert_eq!(build(10)?.0, root);
+        // 3 leaf nodes, 1 root.
+        assert_eq!(blocks.len(), 4);
+        for block in &blocks {
+            store.temp_pin(&tmp, block.cid())?;
+            store.insert(block)?;
+        }
+        let node = store.get(&root)?.ipld()?;
+        assert_eq!(node.get("count")?, &ipld!(10));
+        assert_eq!(node.get("height")?, &ipld!(1));
+        let (root, blocks) = build(4)?;
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].cid(), &root);
+
+        let mut tree = TreeBuilder::<DefaultParams>::new(config)?;
+        let mut blocks = tree.push_bytes(b"abcdefg")?;
+        assert_eq!(blocks.len(), 2);
+        let (_, rest) = tree.finish()?;
+        blocks.extend(rest);
+        // 3 chunks, 1 node.
+        assert_eq!(blocks.len(), 4);
+
+        let entries = (0..100)
+            .map(|i: i128| (i.to_string().into_bytes(), ipld!(i)))
+            .collect::<Vec<_>>();
+        let config = DagBuilderConfig {
+            fanout: 8,
+            ..config
+        };
+        let mut blocks = vec![];
+        let root = build_hamt::<DefaultParams>(&config, entries.clone(), |block| {
+            blocks.push(block);
+            Ok(())
+        })?;
+        let root2 = build_hamt::<DefaultParams>(&config, entries.into_iter().rev(), |_| Ok(()))?;
+        assert_eq!(root, root2);
+        assert_eq!(blocks.last().unwrap().cid(), &root);
+        let node = blocks.last().unwrap().ipld()?;
+        assert_eq!(node.get("hashAlg")?, &ipld!(0x12));
+        assert_eq!(node.get("bucketSize")?, &ipld!(3));
+        for block in &blocks {
+            store.temp_pin(&tmp, block.cid())?;
+            store.insert(block)?;
+        }
+        for i in 0..100i128 {
+            let value = hamt_get(&store, &root, i.to_string().as_bytes())?;
+            assert_eq!(value, Some(ipld!(i)));
+        }
+        assert_eq!(hamt_get(&store, &root, b"100")?, None);
+        assert!(build_hamt::<DefaultParams>(
+            &DagBuilderConfig {
+                fanout: 4,
+                ..config
+            },
+            vec![],
+            |_| Ok(())
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fastcdc_chunker() -> Result<()> {
+        let config = DagBuilderConfig {
+            chunk_size: 1024,
+            chunker: Chunker::FastCdc,
+            ..Default::default()
+        };
+        let chunks = |bytes: &[u8]| -> Result<Vec<Cid>> {
+            let mut tree = TreeBuilder::<DefaultParams>::new(config)?;
+            let mut blocks = vec![];
+            // push in uneven pieces, chunk boundaries must not depend on them.
+            for piece in bytes.chunks(1000) {
+                blocks.extend(tree.push_bytes(piece)?);
+            }
+            blocks.extend(tree.finish()?.1);
+            Ok(blocks
+                .into_iter()
+                .filter(|block| block.cid().codec() == RAW)
+                .map(|block| {
+                    assert!(block.data().len() <= 4096);
+                    *block.cid()
+                })
+                .collect())
+        };
+        let mut state = 1u64;
+        let data = (0..64 * 1024)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect::<Vec<_>>();
+        let mut edited = data.clone();
+        edited.splice(32 * 1024..32 * 1024, b"inserted".iter().copied());
+
+        let original = chunks(&data)?;
+        let changed = chunks(&edited)?;
+        assert!(original.len() > 8);
+        let shared = changed.iter().filter(|cid| original.contains(cid)).count();
+        assert!(shared + 3 >= original.len());
+
+        let config = DagBuilderConfig {
+            chunk_size: 16,
+            chunker: Chunker::FastCdc,
+            ..Default::default()
+        };
+        assert!(TreeBuilder::<DefaultParams>::new(config).is_err());
+        // the largest chunks don't fit in a block.
+        let config = DagBuilderConfig {
+            chunk_size: DefaultParams::MAX_BLOCK_SIZE / 2,
+            chunker: Chunker::FastCdc,
+            ..Default::default()
+        };
+        assert!(TreeBuilder::<DefaultParams>::new(config).is_err());
+        let config = DagBuilderConfig {
+            chunk_size: DefaultParams::MAX_BLOCK_SIZE / 4,
+            chunker: Chunker::FastCdc,
+            ..Default::default()
+        };
+        assert!(TreeBuilder::<DefaultParams>::new(config).is_ok());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_import_car_journaled() -> Result<()> {
+        tracing_try_init();
+        let blocks = (0..300)
+            .map(|i| create_block(format!("test_import_car_journaled {}", i).as_bytes()))
+            .collect::<Result<Vec<_>>>()?;
+        let root = create_ipld_block(&Ipld::List(
+            blocks
+                .iter()
+                .map(|block| Ipld::Link(*block.cid()))
+                .collect(),
+        ))?;
+        let mut car = vec![];
+        let header = DagCborCodec.encode(&ipld!({ "roots": [root.cid()], "version": 1 }))?;
+        write_varint(&mut car, header.len());
+        car.extend_from_slice(&header);
+        for block in blocks.iter().chain(std::iter::once(&root)) {
+            let cid = block.cid().to_bytes();
+            write_varint(&mut car, cid.len() + block.data().len());
+            car.extend_from_slice(&cid);
+            car.extend_from_slice(block.data());
+        }
+        let store = create_store(false).await?;
+
+        // the import fails after the first batch was committed.
+        let truncated = &car[..car.len() - 100];
+        assert!(store.import_car_journaled("test", truncated).is_err());
+        let offset = store.import_journal("test")?.unwrap();
+        assert!(offset > 0);
+        assert_eq!(store.import_journals()?, vec!["test".to_string()]);
+        let expiry = store
+            .storage
+            .alias_expiry(&import::journal_alias("test"))?
+            .unwrap();
+        assert!(expiry > SystemTime::now() + import::JOURNAL_TTL - Duration::from_secs(60));
+
+        let report = store.import_car_journaled("test", &car[..])?;
+        assert_eq!(report.resumed_from, offset);
+        assert_eq!(report.blocks, 301 - 256);
+        assert_eq!(report.pins, vec![*root.cid()]);
+        assert_eq!(store.import_journal("test")?, None);
+        assert!(store.import_journals()?.is_empty());
+        for block in &blocks {
+            assert!(store.contains(block.cid())?);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_address_translation() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.address_translations = vec![AddressTranslation::new(
+            "/ip4/0.0.0.0/tcp/0".parse()?,
+            "/ip4/203.0.113.5/tcp/0".parse()?,
+        )];
+        let store = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let listen = store.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        let listen = listen.to_string();
+        let port = listen.rsplit('/').next().unwrap();
+        let external: Multiaddr = format!("/ip4/203.0.113.5/tcp/{}", port).parse()?;
+        for _ in 0..50 {
+            if store
+                .external_addresses()
+                .iter()
+                .any(|record| record.addr == external)
+            {
+                return Ok(());
+            }
+            Timer::after(Duration::from_millis(100)).await;
+        }
+        panic!("{} was not announced", external);
+    }
+
+    #[async_std::test]
+    async fn test_sync_bandwidth() -> Result<()> {
+        tracing_try_init();
+        let local1 = create_store(true).await?;
+        let local2 = create_store(true).await?;
+        let a = create_ipld_block(&ipld!({ "a": vec![0u8; 4096] }))?;
+        let b = create_ipld_block(&ipld!({ "b": [a.cid()] }))?;
+        let _ = local1.insert(&a)?;
+        let _ = local1.insert(&b)?;
+        local1.alias("b", Some(b.cid()))?;
+        local1.flush().await?;
+
+        local2.alias("b", Some(b.cid()))?;
+        let mut query = local2.sync(b.cid());
+        let mut stats = None;
+        while let Some(event) = query.next().await {
+            match event {
+                SyncEvent::Bandwidth(s) => stats = Some(s),
+                SyncEvent::Complete(result) => {
+                    result?;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let stats = stats.unwrap();
+        assert!(stats.bytes() >= 4096);
+        assert_eq!(stats.peers[0].0, local1.local_peer_id());
+        assert!(stats.throughput() > 0.0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_presence() -> Result<()> {
+        tracing_try_init();
+        let a = create_store(true).await?;
+        let b = create_store(true).await?;
+        let config = PresenceConfig {
+            interval: Duration::from_millis(200),
+            timeout: Duration::from_secs(5),
+        };
+        let mut room_a = a.join_room("lobby", config)?;
+        let room_b = b.join_room("lobby", config)?;
+        assert!(a.join_room("lobby", config).is_err());
+
+        let joined = async_std::future::timeout(Duration::from_secs(10), room_a.next()).await?;
+        assert_eq!(joined, Some(PresenceEvent::Joined(b.local_peer_id())));
+        assert_eq!(a.room_members("lobby"), vec![b.local_peer_id()]);
+
+        drop(room_b);
+        let left = async_std::future::timeout(Duration::from_secs(10), room_a.next()).await?;
+        assert_eq!(left, Some(PresenceEvent::Left(b.local_peer_id())));
+        assert!(a.room_members("lobby").is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_activity_level() -> Result<()> {
+        tracing_try_init();
+        let a = create_store(true).await?;
+        let b = create_store(true).await?;
+        assert_eq!(b.activity_level(), ActivityLevel::Active);
+        b.set_activity_level(ActivityLevel::Suspended);
+        assert_eq!(b.activity_level(), ActivityLevel::Suspended);
+        let config = PresenceConfig {
+            interval: Duration::from_millis(200),
+            timeout: Duration::from_secs(5),
+        };
+        let mut room_a = a.join_room("lobby", config)?;
+        let _room_b = b.join_room("lobby", config)?;
+
+        let joined = async_std::future::timeout(Duration::from_secs(2), room_a.next()).await;
+        assert!(joined.is_err());
+
+        b.set_activity_level(ActivityLevel::Active);
+        let joined = async_std::future::timeout(Duration::from_secs(10), room_a.next()).await?;
+        assert_eq!(joined, Some(PresenceEvent::Joined(b.local_peer_id())));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sample_peers() -> Result<()> {
+        tracing_try_init();
+        let a = create_store(false).await?;
+        let b = create_store(false).await?;
+        let c = create_store(false).await?;
+        a.dial_address(&b.local_peer_id(), b.listeners()[0].clone())?;
+        a.dial_address(&c.local_peer_id(), c.listeners()[0].clone())?;
+        eventually(|| a.sample_peers(5, &PeerFilter::connected()).len() == 2).await;
+
+        let peers = a.sample_peers(5, &PeerFilter::connected());
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains(&b.local_peer_id()));
+        assert!(peers.contains(&c.local_peer_id()));
+        assert_eq!(a.sample_peers(1, &PeerFilter::connected()).len(), 1);
+        let filter = PeerFilter::connected().with_protocol("/unsupported/1.0.0");
+        assert!(a.sample_peers(5, &filter).is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_resume() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.allow_non_globals_in_dht = true;
+        network.retry_policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Backoff::Constant(Duration::from_millis(0)),
+            retry_on: vec![ErrorClass::NotFound],
+        };
+        let local1 = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let local2 = create_store(false).await?;
+        let block = create_block(b"test_sync_resume")?;
+        let _ = local2.insert(&block)?;
+        local2.flush().await?;
+
+        local1.alias("block", Some(block.cid()))?;
+        let mut query = local1.sync(block.cid());
+        match query.next().await {
+            Some(SyncEvent::Stalled(cid)) => assert_eq!(cid, *block.cid()),
+            _ => panic!("expected the sync to stall"),
+        }
+        let addr = local2.listeners()[0].clone();
+        local1.dial_address(&local2.local_peer_id(), addr)?;
+        let mut resumed = false;
+        while let Some(event) = query.next().await {
+            match event {
+                SyncEvent::Resumed(peer) => {
+                    assert_eq!(peer, local2.local_peer_id());
+                    resumed = true;
+                }
+                SyncEvent::Complete(result) => {
+                    result?;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert!(resumed);
+        assert!(local1.contains(block.cid())?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_stall_timeout() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.retry_policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Backoff::Constant(Duration::from_millis(100)),
+            retry_on: vec![ErrorClass::NotFound],
+        };
+        network.sync_stall_timeout = Some(Duration::from_millis(200));
+        let local = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let block = create_block(b"test_sync_stall_timeout")?;
+        local.alias("block", Some(block.cid()))?;
+        let mut query = local.sync(block.cid());
+        match query.next().await {
+            Some(SyncEvent::Stalled(cid)) => assert_eq!(cid, *block.cid()),
+            _ => panic!("expected the sync to stall"),
+        }
+        match query.next().await {
+            Some(SyncEvent::Complete(Err(err))) => {
+                assert!(err.downcast_ref::<SyncStalled>().is_some())
+            }
+            _ => panic!("expected the sync to time out"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_dnslink() -> Result<()> {
+        let block = create_block(b"test_parse_dnslink")?;
+        let link = dnslink::parse_dnslink(&format!("dnslink=/ipfs/{}", block.cid())).unwrap()?;
+        assert_eq!(link.cid, *block.cid());
+        assert_eq!(link.path, "");
+        let link =
+            dnslink::parse_dnslink(&format!("dnslink=/ipfs/{}/a/b/", block.cid())).unwrap()?;
+        assert_eq!(link.cid, *block.cid());
+        assert_eq!(link.path, "a/b");
+        assert!(dnslink::parse_dnslink("dnslink=/ipns/example.com").is_none());
+        assert!(dnslink::parse_dnslink("v=spf1 -all").is_none());
+        assert!(dnslink::parse_dnslink("dnslink=/ipfs/notacid")
+            .unwrap()
+            .is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_misbehaviour_reports() -> Result<()> {
+        tracing_try_init();
+        let a = create_store(true).await?;
+        let b = create_store(true).await?;
+        let c = create_store(false).await?;
+        let offender = c.local_peer_id();
+        let config = ReportConfig::new(vec![a.local_peer_id(), b.local_peer_id()]);
+        let reports_a = a.reports("fleet", config.clone());
+        let reports_b = b.reports("fleet", config.clone());
+        let reports_c = c.reports("fleet", config);
+        let follow = reports_a.clone();
+        async_std::task::spawn(async move { follow.follow().await });
+
+        let block = create_block(b"test_misbehaviour_reports")?;
+        let offence = Offence::InvalidBlock(*block.cid());
+        assert!(reports_c.report(&offender, offence.clone()).is_err());
+        reports_a.report(&offender, offence.clone())?;
+        assert_eq!(reports_a.score(&offender), 1);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut report = MisbehaviourReport {
+            reporter: b.local_peer_id(),
+            offender,
+            offence,
+            time: now - 2 * 3600,
+        };
+        assert!(reports_a.receive(&reports_b.sign(&report)?).is_err());
+        report.time = now;
+        reports_a.receive(&reports_b.sign(&report)?)?;
+        assert_eq!(reports_a.score(&offender), 2);
+        let reporters = reports_a
+            .reports(&offender)
+            .into_iter()
+            .map(|report| report.reporter)
+            .collect::<Vec<_>>();
+        assert!(reporters.contains(&a.local_peer_id()));
+        assert!(reporters.contains(&b.local_peer_id()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dag_stat_order() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let x = create_block(b"x")?;
+        let y = create_block(b"y")?;
+        let a = create_ipld_block(&ipld!({ "x": x.cid() }))?;
+        let root = create_ipld_block(&ipld!([a.cid(), y.cid()]))?;
+        let small = create_ipld_block(&ipld!([x.cid()]))?;
+        let big = create_ipld_block(&ipld!({ "padding": vec![0u8; 256], "y": y.cid() }))?;
+        let root2 = create_ipld_block(&ipld!([small.cid(), big.cid()]))?;
+        let tmp = store.create_temp_pin()?;
+        for block in &[&a, &root, &small, &big, &root2] {
+            store.temp_pin(&tmp, block.cid())?;
+            let _ = store.insert(block)?;
+        }
+
+        let stat = store.dag_stat(root.cid(), TraversalOrder::DepthFirst)?;
+        assert_eq!(stat.blocks, 2);
+        assert_eq!(stat.size, (a.data().len() + root.data().len()) as u64);
+        assert_eq!(stat.missing, vec![*x.cid(), *y.cid()]);
+        let stat = store.dag_stat(root.cid(), TraversalOrder::BreadthFirst)?;
+        assert_eq!(stat.missing, vec![*y.cid(), *x.cid()]);
+
+        let stat = store.dag_stat(root2.cid(), TraversalOrder::DepthFirst)?;
+        assert_eq!(stat.missing, vec![*x.cid(), *y.cid()]);
+        let stat = store.dag_stat(root2.cid(), TraversalOrder::LargestFirst)?;
+        assert_eq!(stat.blocks, 3);
+        assert_eq!(stat.missing, vec![*y.cid(), *x.cid()]);
+
+        for block in &[&x, &y] {
+            store.temp_pin(&tmp, block.cid())?;
+            let _ = store.insert(block)?;
+        }
+        let mut car = vec![];
+        assert_eq!(
+            store.export_car_with_order(root.cid(), TraversalOrder::BreadthFirst, &mut car)?,
+            4
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_authenticator() -> Result<()> {
+        struct AllowList(Vec<PeerId>);
+
+        #[async_trait]
+        impl Authenticator for AllowList {
+            async fn authenticate(&self, identity: &PeerIdentity) -> Result<()> {
+                if self.0.contains(&identity.public_key.clone().into_peer_id()) {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("{} isn't signed in", identity.peer_id))
+                }
+            }
+        }
+
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let store3 = create_store(false).await?;
+        store1.set_authenticator(AllowList(vec![store2.local_peer_id()]));
+
+        store2.dial_address(&store1.local_peer_id(), store1.listeners()[0].clone())?;
+        store3.dial_address(&store1.local_peer_id(), store1.listeners()[0].clone())?;
+        let peer3 = store3.local_peer_id();
+        eventually(|| {
+            store1
+                .peer_stats()
+                .into_iter()
+                .any(|(peer, stats)| peer == peer3 && stats.auth_failures > 0)
+        })
+        .await;
+        eventually(|| !store1.peers().contains(&peer3)).await;
+        let connected = store1
+            .connections()
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect::<Vec<_>>();
+        assert!(connected.contains(&store2.local_peer_id()));
+        assert!(!connected.contains(&peer3));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_alias_hooks() -> Result<()> {
+        struct Recorder(Cid, Arc<Mutex<Vec<AliasChange>>>);
+
+        #[async_trait]
+        impl AliasHook for Recorder {
+            fn pre_commit(&self, change: &AliasChange) -> Result<()> {
+                if change.new == Some(self.0) {
+                    return Err(anyhow::anyhow!("{} is blocked", self.0));
+                }
+                Ok(())
+            }
+
+            async fn post_commit(&self, change: &AliasChange) {
+                self.1.lock().push(change.clone());
+            }
+        }
+
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_block(b"a")?;
+        let b = create_block(b"b")?;
+        store.insert(&a)?;
+        store.insert(&b)?;
+        let changes = Arc::new(Mutex::new(vec![]));
+        store.add_alias_hook(Recorder(*b.cid(), changes.clone()));
+
+        store.alias(b"root", Some(a.cid()))?;
+        assert!(store.alias(b"root", Some(b.cid())).is_err());
+        assert!(store
+            .alias_with_ttl(b"other", b.cid(), Duration::from_secs(60))
+            .is_err());
+        assert_eq!(store.resolve(b"root")?, Some(*a.cid()));
+        assert_eq!(store.resolve(b"other")?, None);
+        store.rename_aliases(b"ro", b"ne")?;
+        store.alias(b"newt", None)?;
+        store.alias(b"neot", None)?;
+        store.alias_with_ttl(b"tmp", a.cid(), Duration::default())?;
+        Timer::after(Duration::from_secs(1)).await;
+        store.storage.expire_aliases()?;
+
+        let expected = vec![
+            AliasChange {
+                alias: b"root".to_vec(),
+                old: None,
+                new: Some(*a.cid()),
+            },
+            AliasChange {
+                alias: b"neot".to_vec(),
+                old: None,
+                new: Some(*a.cid()),
+            },
+            AliasChange {
+                alias: b"root".to_vec(),
+                old: Some(*a.cid()),
+                new: None,
+            },
+            AliasChange {
+                alias: b"neot".to_vec(),
+                old: Some(*a.cid()),
+                new: None,
+            },
+            AliasChange {
+                alias: b"tmp".to_vec(),
+                old: None,
+                new: Some(*a.cid()),
+            },
+            AliasChange {
+                alias: b"tmp".to_vec(),
+                old: Some(*a.cid()),
+                new: None,
+            },
+        ];
+        eventually(|| changes.lock().len() >= expected.len()).await;
+        assert_eq!(*changes.lock(), expected);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_capture() -> Result<()> {
+        tracing_try_init();
+        let dir = temp_dir("capture");
+        let path = dir.join("capture");
+        let store1 = create_store(false).await?;
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.allow_non_globals_in_dht = true;
+        network.capture = Some(CaptureConfig::new(path.clone()));
+        let store2 = Ipfs::<DefaultParams>::new(Config {
+            storage: StorageConfig::new(None, 10, Duration::from_millis(10000)),
+            network,
+        })
+        .await?;
+        let nodes = [(store1.local_peer_id(), store1.listeners()[0].clone())];
+        store2.bootstrap(&nodes).await?;
+
+        const KAD: &str = "/ipfs/kad/1.0.0";
+        // the records are written in the background.
+        let mut frames = vec![];
+        for _ in 0..50 {
+            // the last record may be partially written.
+            frames = CaptureReader::open(&path)?
+                .take_while(|frame| frame.is_ok())
+                .collect::<Result<Vec<_>>>()?;
+            if frames.iter().any(|frame| {
+                frame.outbound && !frame.sent && frame.protocol.as_deref() == Some(KAD)
+            }) {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(frames
+            .iter()
+            .all(|frame| frame.peer == store1.local_peer_id()));
+        assert!(frames.iter().any(|frame| frame.outbound
+            && !frame.sent
+            && frame.message
+            && frame.protocol.as_deref() == Some(KAD)));
+        assert!(frames
+            .iter()
+            .filter(|frame| frame.message)
+            .all(|frame| frame.data.len() == frame.len));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_outbox() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let mut storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        storage.outbox_ttl = Some(Duration::from_secs(60));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        let store2 = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let mut msgs = store1.subscribe("test_outbox")?;
+
+        store2.publish("test_outbox", b"a".to_vec())?;
+        store2.publish("test_outbox", b"b".to_vec())?;
+        assert_eq!(store2.outbox_len()?, 2);
+
+        store2.dial_address(&store1.local_peer_id(), store1.listeners()[0].clone())?;
+        let a = async_std::future::timeout(Duration::from_secs(10), msgs.next()).await?;
+        let b = async_std::future::timeout(Duration::from_secs(10), msgs.next()).await?;
+        assert_eq!(a, Some(b"a".to_vec()));
+        assert_eq!(b, Some(b"b".to_vec()));
+        assert_eq!(store2.outbox_len()?, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_custom_codec() -> Result<()> {
+        /// Links prefixed with their length.
+        struct LinkList;
+
+        impl CustomCodec for LinkList {
+            fn code(&self) -> u64 {
+                0x30_0001
+            }
+
+            fn links(&self, mut data: &[u8], links: &mut Vec<Cid>) -> Result<()> {
+                while let Some((len, rest)) = data.split_first() {
+                    let (cid, rest) = rest.split_at(*len as usize);
+                    links.push(Cid::try_from(cid)?);
+                    data = rest;
+                }
+                Ok(())
+            }
+        }
+
+        tracing_try_init();
+        register_codec(LinkList)?;
+        assert!(register_codec(LinkList).is_err());
+        let mut config = Config::new(None, 0);
+        config.network.enable_mdns = false;
+        let store = Ipfs::<RegistryParams>::new(config).await?;
+        let block = |codec: u64, data: Vec<u8>| {
+            let cid = Cid::new_v1(codec, Code::Blake3_256.digest(&data));
+            Block::<RegistryParams>::new(cid, data)
+        };
+        let a = block(RAW, b"a".to_vec())?;
+        let b = block(RAW, b"b".to_vec())?;
+        let mut data = vec![a.cid().to_bytes().len() as u8];
+        data.extend(a.cid().to_bytes());
+        let root = block(0x30_0001, data)?;
+        store.insert(&a)?;
+        store.insert(&b)?;
+        store.insert(&root)?;
+        store.alias(b"root", Some(root.cid()))?;
+        store.evict().await?;
+        assert!(store.contains(root.cid())?);
+        assert!(store.contains(a.cid())?);
+        assert!(!store.contains(b.cid())?);
+        let unlimited = TraversalLimits::unlimited();
+        let leaf = store.resolve_path(root.cid(), "0", &unlimited)?;
+        assert_eq!(leaf, Ipld::Bytes(b"a".to_vec()));
+        assert!(store.resolve_path(root.cid(), "1", &unlimited).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_observed_addresses() -> Result<()> {
+        tracing_try_init();
+        let mut config = Config::new(None, 10);
+        config.network.enable_mdns = false;
+        config.network.external_address_confidence = 2;
+        let store1 = Ipfs::<DefaultParams>::new(config).await?;
+        let addr = store1.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        let store2 = create_store(false).await?;
+        let store3 = create_store(false).await?;
+        store2.dial_address(&store1.local_peer_id(), addr.clone())?;
+        store3.dial_address(&store1.local_peer_id(), addr.clone())?;
+
+        eventually(|| {
+            store1
+                .observed_addresses()
+                .iter()
+                .any(|a| a.addr == addr && a.confirmed)
+        })
+        .await;
+        assert_eq!(store1.observed_addresses()[0].votes, 2);
+        eventually(|| {
+            store1
+                .external_addresses()
+                .iter()
+                .any(|record| record.addr == addr)
+        })
+        .await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_delta() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let leaves = (0..20u8)
+            .map(|i| create_block(&[b't', b'e', b's', b't', i]))
+            .collect::<Result<Vec<_>>>()?;
+        let links = leaves.iter().map(|b| Ipld::Link(*b.cid())).collect();
+        let root = create_ipld_block(&Ipld::List(links))?;
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            let _ = store1.insert(block)?;
+        }
+        store1.alias("test_sync_delta", Some(root.cid()))?;
+        let _ = store2.insert(&root)?;
+        for block in &leaves[..15] {
+            let _ = store2.insert(block)?;
+        }
+        store2.alias("test_sync_delta", Some(root.cid()))?;
+        store1.serve_delta_sync()?;
+        let peer = store1.local_peer_id();
+        store2.add_address(&peer, store1.listeners()[0].clone());
+        let received = store2.sync_delta(&peer, root.cid()).await?;
+        assert!(received > 0 && received <= 5);
+        let stat = store2.dag_stat(root.cid(), TraversalOrder::BreadthFirst)?;
+        assert!(stat.missing.is_empty());
+        assert_eq!(stat.blocks, 21);
+        assert_eq!(store2.sync_delta(&peer, root.cid()).await?, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_with_order() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let tmp = store1.create_temp_pin()?;
+        let mut nodes = vec![];
+        for i in 0..4u8 {
+            let leaves = (0..10u8)
+                .map(|j| create_block(&[b't', i, j]))
+                .collect::<Result<Vec<_>>>()?;
+            let links = leaves.iter().map(|b| Ipld::Link(*b.cid())).collect();
+            let node = create_ipld_block(&Ipld::List(links))?;
+            for block in leaves.iter().chain(std::iter::once(&node)) {
+                store1.temp_pin(&tmp, block.cid())?;
+                let _ = store1.insert(block)?;
+            }
+            nodes.push(Ipld::Link(*node.cid()));
+        }
+        let root = create_ipld_block(&Ipld::List(nodes))?;
+        store1.temp_pin(&tmp, root.cid())?;
+        let _ = store1.insert(&root)?;
+
+        let peer = store1.local_peer_id();
+        store2.add_address(&peer, store1.listeners()[0].clone());
+        store2.dial(&peer)?;
+        let tmp2 = store2.create_temp_pin()?;
+        store2.temp_pin(&tmp2, root.cid())?;
+        let err = store2
+            .sync_with_order(
+                root.cid(),
+                Priority::Interactive,
+                TraversalOrder::LargestFirst,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedOrder>().is_some());
+        // more blocks than a depth first sync requests at a time.
+        store2
+            .sync_with_order(
+                root.cid(),
+                Priority::Interactive,
+                TraversalOrder::DepthFirst,
+            )
+            .await?;
+        let stat = store2.dag_stat(root.cid(), TraversalOrder::DepthFirst)?;
+        assert!(stat.missing.is_empty());
+        assert_eq!(stat.blocks, 45);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_stage() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_block(b"test_stage_a")?;
+        let b = create_ipld_block(&Ipld::List(vec![Ipld::Link(*a.cid())]))?;
+        let staged = store.stage(vec![a.clone(), b.clone()])?;
+        assert_eq!(staged.root(), b.cid());
+        assert_eq!(staged.blocks(), &[*a.cid(), *b.cid()][..]);
+        store.evict().await?;
+        assert!(store.contains(a.cid())?);
+        assert_eq!(staged.commit("test_stage").await?, *b.cid());
+        assert_eq!(store.resolve("test_stage")?, Some(*b.cid()));
+        store.evict().await?;
+        assert!(store.contains(a.cid())?);
+
+        let c = create_block(b"test_stage_c")?;
+        store.stage(vec![c.clone()])?.abort();
+        store.evict().await?;
+        assert!(!store.contains(c.cid())?);
+
+        let d = create_block(b"test_stage_d")?;
+        let err = store.stage(vec![c, d]).err().unwrap();
+        assert!(err.downcast_ref::<NoUniqueRoot>().is_some());
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[async_std::test]
+    async fn test_encrypt_dag() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let keypair = Keypair::generate_ed25519();
+        let a = create_block(b"test_encrypt_dag_a")?;
+        let b = create_block(b"test_encrypt_dag_b")?;
+        let root = create_ipld_block(&ipld!({ "a": a.cid(), "b": [b.cid(), a.cid()] }))?;
+        let tmp1 = store1.create_temp_pin()?;
+        for block in &[&a, &b, &root] {
+            store1.temp_pin(&tmp1, block.cid())?;
+            let _ = store1.insert(block)?;
+        }
+        let envelope = store1.encrypt_dag(&tmp1, root.cid(), &[keypair.public()])?;
+        store1.alias("test_encrypt_dag", Some(&envelope))?;
+
+        let peer = store1.local_peer_id();
+        store2.add_address(&peer, store1.listeners()[0].clone());
+        store2.dial(&peer)?;
+        let tmp2 = store2.create_temp_pin()?;
+        store2.temp_pin(&tmp2, &envelope)?;
+        store2.sync(&envelope).await?;
+        assert!(!store2.contains(a.cid())?);
+        assert!(!store2.contains(root.cid())?);
+        assert_eq!(store2.decrypt_dag(&tmp2, &envelope, &keypair)?, *root.cid());
+        assert_eq!(store2.get(a.cid())?.data(), a.data());
+        assert_eq!(store2.get(b.cid())?.data(), b.data());
+
+        let other = Keypair::generate_ed25519();
+        let err = store2.decrypt_dag(&tmp2, &envelope, &other).unwrap_err();
+        assert!(err.downcast_ref::<NotARecipient>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_rotate_identity() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let other = create_store(false).await?;
+        store
+            .bootstrap(&[(other.local_peer_id(), other.listeners()[0].clone())])
+            .await?;
+        other
+            .bootstrap(&[(store.local_peer_id(), store.listeners()[0].clone())])
+            .await?;
+        let old = store.local_peer_id();
+        let listeners = store.listeners();
+        let mut events = store.swarm_events();
+        let mut links = other.follow_identity(old)?;
+        let topic = identity::identity_topic(&old);
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Subscribed(peer, t) if peer == other.local_peer_id() && t == topic => break,
+                _ => {}
+            }
+        }
+
+        let (key, link) = store.rotate_identity(None, true).await?;
+        assert!(link.verify());
+        assert_eq!(link.old_peer_id(), old);
+        assert_eq!(link.new_peer_id(), key.public().into_peer_id());
+        assert_ne!(link.new_peer_id(), old);
+        assert_eq!(link.new_key(), &key.public());
+        // the node runs under the new identity and keeps its port.
+        assert_eq!(store.local_peer_id(), link.new_peer_id());
+        eventually(|| store.listeners() == listeners).await;
+        assert_eq!(links.next().await, Some(link.clone()));
+        assert_eq!(other.resolve_identity(&old).await?, Some(link.clone()));
+        let unknown = Keypair::generate_ed25519().public().into_peer_id();
+        assert_eq!(other.resolve_identity(&unknown).await?, None);
+
+        let bytes = link.encode()?;
+        assert_eq!(IdentityLink::decode(&bytes)?, link);
+        let mut ipld: Ipld = DagCborCodec.decode(&bytes)?;
+        if let Ipld::StringMap(map) = &mut ipld {
+            map.insert("timestamp".to_string(), Ipld::Integer(0));
+        }
+        let err = IdentityLink::decode(&DagCborCodec.encode(&ipld)?).unwrap_err();
+        assert!(err.downcast_ref::<InvalidIdentityLink>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dns_resolver() -> Result<()> {
+        use std::net::IpAddr;
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-    let response = tide::Response::builder(200)
-        .content_type("text/plain; version=0.0.4")
-        .body(tide::Body::from(buffer))
-        .build();
+        struct StaticResolver(String, Arc<AtomicUsize>);
 
-    Ok(response)
-}
+        #[async_trait]
+        impl DnsResolver for StaticResolver {
+            async fn lookup_ip(&self, _name: &str) -> Result<Vec<IpAddr>> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                Ok(vec![[127, 0, 0, 1].into()])
+            }
 
-#[async_trait]
-impl<P: StoreParams> Store for Ipfs<P>
-where
-    Ipld: References<P::Codecs>,
-{
-    type Params = P;
-    type TempPin = Arc<TempPin>;
+            async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(name, "_dnslink.example.com.");
+                Ok(vec!["v=spf1 -all".into(), self.0.clone()])
+            }
+        }
 
-    fn create_temp_pin(&self) -> Result<Self::TempPin> {
-        Ok(Arc::new(Ipfs::create_temp_pin(self)?))
+        let block = create_block(b"test_dns_resolver")?;
+        let txt = format!("dnslink=/ipfs/{}/a", block.cid());
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = CachingResolver::new(
+            StaticResolver(txt, lookups.clone()),
+            Duration::from_secs(60),
+        );
+        for _ in 0..2 {
+            let link = dnslink::resolve(&resolver, "example.com.").await?;
+            assert_eq!(link.cid, *block.cid());
+            assert_eq!(link.path, "a");
+            let ips = resolver.lookup_ip("example.com").await?;
+            assert_eq!(ips, vec![IpAddr::from([127, 0, 0, 1])]);
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+        Ok(())
     }
 
-    fn temp_pin(&self, tmp: &Self::TempPin, cid: &Cid) -> Result<()> {
-        Ipfs::temp_pin(self, tmp, cid)
-    }
+    #[async_std::test]
+    async fn test_ready() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let report = store.ready(&ReadyConfig::default()).await;
+        assert!(report.is_ready());
+        assert_eq!(report.listen_addrs, store.listeners());
+        assert!(report.bootstrap.is_none());
+        assert!(report.recovery.is_none());
 
-    fn contains(&self, cid: &Cid) -> Result<bool> {
-        Ipfs::contains(self, cid)
-    }
+        let other = create_store(false).await?;
+        let config = ReadyConfig {
+            boot_nodes: vec![(other.local_peer_id(), other.listeners()[0].clone())],
+            ..Default::default()
+        };
+        let report = store.ready(&config).await;
+        assert!(report.is_ready());
+        let bootstrap = report.bootstrap.unwrap();
+        assert!(bootstrap.is_ok());
+        // probes don't bootstrap again, even if the boot nodes are gone.
+        drop(other);
+        let report = store.ready(&config).await;
+        assert_eq!(report.bootstrap, Some(bootstrap));
 
-    fn get(&self, cid: &Cid) -> Result<Block<P>> {
-        Ipfs::get(self, cid)
+        let config = ReadyConfig {
+            listeners: 2,
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let report = store.ready(&config).await;
+        assert!(report.storage.is_ok());
+        assert!(report.listeners.is_err());
+        assert!(!report.is_ready());
+        Ok(())
     }
 
-    fn insert(&self, block: &Block<P>) -> Result<()> {
-        let _ = Ipfs::insert(self, block)?;
+    #[async_std::test]
+    async fn test_cluster_reconnect() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let writers = vec![store1.local_peer_id()];
+        let cluster1 = store1.cluster("test_cluster_reconnect", writers.clone());
+        let cluster2 = store2.cluster("test_cluster_reconnect", writers);
+        let a = create_block(b"test_cluster_reconnect")?;
+        let _ = store1.insert(&a)?;
+        let head = cluster1.pin(a.cid()).await?;
+        let follower1 = cluster1.clone();
+        async_std::task::spawn(async move { follower1.follow().await });
+
+        // the head was announced before the peers were connected, so it is sent to the
+        // inbox of the follower once it subscribes.
+        let inbox = follow::inbox_topic(&cluster2.topic(), &store2.local_peer_id());
+        let mut inbox = store2.subscribe(&inbox)?;
+        let addr = store1.listeners()[0].clone();
+        store2.dial_address(&store1.local_peer_id(), addr)?;
+        let msg = async_std::future::timeout(Duration::from_secs(10), inbox.next())
+            .await?
+            .unwrap();
+        let (received, provenance) = cluster::decode_announcement(&msg)?;
+        assert_eq!(received, head);
+        assert!(cluster2.update(&received, &provenance).await?);
+        assert_eq!(cluster2.head()?, Some(head));
+        assert_eq!(cluster2.pinset()?, vec![*a.cid()]);
         Ok(())
     }
 
-    fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
-        Ipfs::alias(self, alias, cid)
-    }
+    #[async_std::test]
+    async fn test_follow_peer_reconnect() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let root = create_block(b"test_follow_peer_reconnect")?;
+        let _ = store1.insert(&root)?;
+        // published before the nodes are connected, so no follower receives it.
+        let _ = store1.publish_root("topic", root.cid()).await;
 
-    fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
-        Ipfs::resolve(self, alias)
+        let peer = store1.local_peer_id();
+        let key = follow::root_topic(&peer, "topic");
+        let mut inbox = store2.subscribe(&follow::inbox_topic(&key, &store2.local_peer_id()))?;
+        store2.dial_address(&peer, store1.listeners()[0].clone())?;
+        let msg = async_std::future::timeout(Duration::from_secs(10), inbox.next())
+            .await?
+            .unwrap();
+        let (_, received) = follow::decode_root(&msg, &peer, "topic")?;
+        assert_eq!(received, *root.cid());
+        Ok(())
     }
 
-    fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
-        Ipfs::reverse_alias(self, cid)
-    }
+    #[async_std::test]
+    async fn test_block_exchange() -> Result<()> {
+        struct ChannelReceiver(Mutex<mpsc::UnboundedSender<(Cid, usize)>>);
 
-    async fn flush(&self) -> Result<()> {
-        Ipfs::flush(self).await
-    }
+        impl BlockReceiver for ChannelReceiver {
+            fn received(&self, cid: &Cid, size: usize) {
+                self.0.lock().unbounded_send((*cid, size)).ok();
+            }
+        }
 
-    async fn fetch(&self, cid: &Cid) -> Result<Block<Self::Params>> {
-        Ipfs::fetch(self, cid).await
-    }
+        async fn want<E: BlockExchange<DefaultParams>>(exchange: &E, cid: Cid) -> Result<()> {
+            exchange.want(cid, Priority::Interactive).await
+        }
 
-    async fn sync(&self, cid: &Cid) -> Result<()> {
-        Ipfs::sync(self, cid).await
-    }
-}
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let block = create_block(b"test_block_exchange")?;
+        let tmp1 = store1.create_temp_pin()?;
+        store1.temp_pin(&tmp1, block.cid())?;
+        store1.insert(&block)?;
+        store1.flush().await?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures::join;
-    use libipld::cbor::DagCborCodec;
-    use libipld::multihash::Code;
-    use libipld::raw::RawCodec;
-    use libipld::store::DefaultParams;
-    use libipld::{alias, ipld};
-    use std::time::Duration;
+        // the networking of a node on top of the store of another
+        let (tx, mut received) = mpsc::unbounded();
+        let mut config = NetworkConfig::new();
+        config.enable_mdns = false;
+        config.block_receiver = Some(Arc::new(ChannelReceiver(Mutex::new(tx))));
+        let network = NetworkService::new(config, store2.bitswap_store()).await?;
+        network.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        network.add_address(&store1.local_peer_id(), store1.listeners()[0].clone());
 
-    fn tracing_try_init() {
-        tracing_subscriber::fmt()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .try_init()
-            .ok();
+        let tmp2 = store2.create_temp_pin()?;
+        store2.temp_pin(&tmp2, block.cid())?;
+        want(&network, *block.cid()).await?;
+        assert_eq!(network.wants(), (0, 0));
+        assert_eq!(store2.get(block.cid())?.data(), block.data());
+        // receipts are delivered by a task, after the block was inserted.
+        assert_eq!(
+            received.next().await,
+            Some((*block.cid(), block.data().len()))
+        );
+        Ok(())
     }
 
-    async fn create_store(enable_mdns: bool) -> Result<Ipfs<DefaultParams>> {
-        let sweep_interval = Duration::from_millis(10000);
-        let storage = StorageConfig::new(None, 10, sweep_interval);
+    #[async_std::test]
+    async fn test_offline_network() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let mut config = NetworkConfig::new();
+        config.enable_mdns = false;
+        let network = NetworkService::offline(config, store.bitswap_store()).await?;
+        let addr = "/ip4/127.0.0.1/tcp/0".parse()?;
+        assert!(network.listen_on(addr).await.is_err());
+        Ok(())
+    }
 
-        let mut network = NetworkConfig::new();
-        network.enable_mdns = enable_mdns;
-        network.allow_non_globals_in_dht = true;
+    #[async_std::test]
+    async fn test_sync_with_deadline() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let a = create_ipld_block(&ipld!({ "name": "a" }))?;
+        let b = create_ipld_block(&ipld!({ "a": a.cid() }))?;
+        let tmp1 = store1.create_temp_pin()?;
+        store1.temp_pin(&tmp1, b.cid())?;
+        store1.insert(&a)?;
+        store1.insert(&b)?;
+        store1.flush().await?;
+        store2.add_address(&store1.local_peer_id(), store1.listeners()[0].clone());
 
-        let ipfs = Ipfs::new(Config { storage, network }).await?;
-        ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
-        Ok(ipfs)
-    }
+        let tmp2 = store2.create_temp_pin()?;
+        let partial = store2
+            .sync_with_deadline(&tmp2, b.cid(), std::time::Instant::now())
+            .await?;
+        assert!(!partial.complete);
+        assert_eq!(partial.missing, vec![*b.cid()]);
 
-    fn create_block(bytes: &[u8]) -> Result<Block<DefaultParams>> {
-        Block::encode(RawCodec, Code::Blake3_256, bytes)
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        let partial = store2.sync_with_deadline(&tmp2, b.cid(), deadline).await?;
+        assert!(partial.complete);
+        assert!(partial.missing.is_empty());
+        assert!(partial.error.is_none());
+        assert!(store2.contains(a.cid())?);
+
+        // a failed sync still reports what is missing.
+        let store3 = create_store(false).await?;
+        let tmp3 = store3.create_temp_pin()?;
+        let partial = store3.sync_with_deadline(&tmp3, b.cid(), deadline).await?;
+        assert!(!partial.complete);
+        assert_eq!(partial.missing, vec![*b.cid()]);
+        assert!(partial.error.is_some());
+        Ok(())
     }
 
     #[async_std::test]
-    async fn test_local_store() -> Result<()> {
+    async fn test_alias_meta() -> Result<()> {
         tracing_try_init();
         let store = create_store(false).await?;
-        let block = create_block(b"test_local_store")?;
-        let tmp = store.create_temp_pin()?;
-        store.temp_pin(&tmp, block.cid())?;
-        let _ = store.insert(&block)?;
-        let block2 = store.get(block.cid())?;
-        assert_eq!(block.data(), block2.data());
+        let block = create_block(b"test_alias_meta")?;
+        store.insert(&block)?;
+        store.alias_with_meta("root", block.cid(), br#"{"version":1}"#)?;
+        assert_eq!(
+            store.resolve_with_meta("root")?,
+            Some((*block.cid(), Some(br#"{"version":1}"#.to_vec())))
+        );
+        store.alias("root", Some(block.cid()))?;
+        assert_eq!(store.resolve_with_meta("root")?, Some((*block.cid(), None)));
+        assert_eq!(store.resolve_with_meta("other")?, None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dial_back() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.allow_non_globals_in_dht = true;
+        network.dial_back = true;
+        let store1 = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let addr1 = store1.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        let store2 = create_store(false).await?;
+        let addr2 = store2.listeners()[0].clone();
+        store2.dial_address(&store1.local_peer_id(), addr1)?;
+
+        let peer2 = store2.local_peer_id();
+        let mut verified = false;
+        for _ in 0..100 {
+            if let Some(info) = store1.peer_info(&peer2) {
+                verified = info
+                    .addresses()
+                    .any(|(addr, source)| addr == &addr2 && source == AddressSource::DialBack);
+                if verified {
+                    break;
+                }
+            }
+            Timer::after(Duration::from_millis(100)).await;
+        }
+        assert!(verified);
         Ok(())
     }
 
     #[async_std::test]
+    #[cfg(feature = "mdns")]
     #[cfg(not(target_os = "macos"))] // mdns doesn't work on macos in github actions
     async fn test_exchange_mdns() -> Result<()> {
         tracing_try_init();
@@ -458,6 +5068,50 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_block_policy() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.block_policy = BlockPolicy::allow_all().with_hashes(vec![Code::Blake3_256]);
+        let store = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let block = Block::<DefaultParams>::encode(RawCodec, Code::Sha2_256, b"test_block_policy")?;
+        let err = store.fetch(block.cid()).await.unwrap_err();
+        assert!(err.downcast_ref::<BlockRejected>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_push() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        network.enable_push = true;
+        let store2 = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let addr = store2.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        store1.add_address(&store2.local_peer_id(), addr);
+
+        let mut pushed = store2.pushed();
+        let block = create_block(b"test_push")?;
+        store1
+            .push(&store2.local_peer_id(), vec![block.clone()])
+            .await?;
+        let pushed = pushed.next().await.unwrap();
+        assert_eq!(pushed.peer, store1.local_peer_id());
+        assert_eq!(pushed.blocks, vec![*block.cid()]);
+        assert_eq!(store2.get(block.cid())?.data(), block.data());
+
+        let err = store2
+            .push(&store1.local_peer_id(), vec![block])
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<PushRejected>().is_some());
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_exchange_kad() -> Result<()> {
         tracing_try_init();
@@ -533,6 +5187,42 @@ mod tests {
         Block::encode(DagCborCodec, Code::Blake3_256, ipld)
     }
 
+    #[async_std::test]
+    async fn test_resolve_path_mixed_codecs() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let leaf = Block::encode(
+            IpldCodec::Raw,
+            Code::Blake3_256,
+            &Ipld::Bytes(b"hello".to_vec()),
+        )?;
+        let dir = Block::encode(
+            IpldCodec::DagPb,
+            Code::Blake3_256,
+            &ipld!({
+                "Links": [{ "Hash": leaf.cid(), "Name": "hello.txt", "Tsize": 5 }],
+                "Data": Ipld::Bytes(vec![8, 1]),
+            }),
+        )?;
+        let root = create_ipld_block(&ipld!({ "dir": dir.cid() }))?;
+        for block in &[&leaf, &dir, &root] {
+            let _ = store.insert(block)?;
+        }
+        let unlimited = TraversalLimits::unlimited();
+        let data = store.resolve_path(root.cid(), "dir/hello.txt", &unlimited)?;
+        assert_eq!(data, Ipld::Bytes(b"hello".to_vec()));
+        let err = store
+            .resolve_path(root.cid(), "dir/missing.txt", &unlimited)
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<PathNotFound>().is_some());
+
+        store.alias("root", Some(root.cid()))?;
+        store.flush().await?;
+        assert_pinned!(&store, &leaf);
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_sync() -> Result<()> {
         tracing_try_init();
@@ -598,6 +5288,90 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_block_provenance() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let block = create_block(b"test_block_provenance")?;
+        let _ = store.insert_signed(&block)?;
+        let records = store.block_provenance(block.cid())?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer_id(), store.local_peer_id());
+        assert!(records[0].verify(block.cid()));
+        let other = create_block(b"other")?;
+        assert!(store.add_provenance(other.cid(), &records[0]).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_reencode_dag() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_ipld_block(&ipld!({ "a": 0 }))?;
+        let b = create_ipld_block(&ipld!({ "b": [a.cid()] }))?;
+        let _ = store.insert(&a)?;
+        let _ = store.insert(&b)?;
+        let tmp = store.create_temp_pin()?;
+        let root = store.reencode_dag(
+            &tmp,
+            b.cid(),
+            IpldCodec::DagCbor,
+            IpldCodec::DagCbor,
+            Code::Sha2_256,
+        )?;
+        assert_eq!(root.hash().code(), u64::from(Code::Sha2_256));
+        let a2 = Block::<DefaultParams>::encode(DagCborCodec, Code::Sha2_256, &ipld!({ "a": 0 }))?;
+        let b2 = store.get(&root)?;
+        assert_eq!(b2.ipld()?, ipld!({ "b": [a2.cid()] }));
+        assert!(store.contains(a2.cid())?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dag_diff() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a1 = create_ipld_block(&ipld!({ "a": 0 }))?;
+        let b1 = create_ipld_block(&ipld!({ "b": 0 }))?;
+        let c1 = create_ipld_block(&ipld!({ "c": [a1.cid(), b1.cid()] }))?;
+        let b2 = create_ipld_block(&ipld!({ "b": 1 }))?;
+        let c2 = create_ipld_block(&ipld!({ "c": [a1.cid(), b2.cid()], "d": true }))?;
+        let tmp = store.create_temp_pin()?;
+        for block in &[&a1, &b1, &c1, &b2, &c2] {
+            store.temp_pin(&tmp, block.cid())?;
+            let _ = store.insert(block)?;
+        }
+
+        let mut diff = store.dag_diff(c1.cid(), c2.cid())?;
+        diff.added.sort();
+        diff.removed.sort();
+        let mut added = vec![*b2.cid(), *c2.cid()];
+        added.sort();
+        let mut removed = vec![*b1.cid(), *c1.cid()];
+        removed.sort();
+        assert_eq!(diff.added, added);
+        assert_eq!(diff.removed, removed);
+        assert_eq!(
+            diff.changed,
+            vec![(*c1.cid(), *c2.cid()), (*b1.cid(), *b2.cid())]
+        );
+        assert_eq!(
+            diff.paths,
+            vec![
+                PathDiff {
+                    path: "c/1/b".into(),
+                    change: PathChange::Changed(ipld!(0), ipld!(1)),
+                },
+                PathDiff {
+                    path: "d".into(),
+                    change: PathChange::Added(ipld!(true)),
+                },
+            ]
+        );
+        assert_eq!(store.dag_diff(c1.cid(), c1.cid())?, DagDiff::default());
+        Ok(())
+    }
+
     #[async_std::test]
     #[allow(clippy::eval_order_dependence)]
     async fn test_dht_record() -> Result<()> {
@@ -656,4 +5430,217 @@ mod tests {
         }
         Ok(())
     }
+
+    #[cfg(feature = "bridge")]
+    #[test]
+    fn test_bridge_drops_echoes() {
+        let mut mirrored = bridge::Mirrored::default();
+        let now = std::time::Instant::now();
+        assert!(mirrored.insert(b"a", now));
+        assert!(mirrored.insert(b"b", now));
+        assert!(!mirrored.insert(b"a", now + Duration::from_secs(1)));
+        assert!(mirrored.insert(b"a", now + Duration::from_secs(61)));
+    }
+
+    #[cfg(feature = "bridge")]
+    #[async_std::test]
+    async fn test_nats_read_loop() -> Result<()> {
+        let subscriptions = bridge::Subscriptions::default();
+        let (sub, mut messages) = mpsc::unbounded();
+        subscriptions.lock().insert(1, sub);
+        let (tx, mut frames) = mpsc::unbounded();
+        let input = &b"MSG subject 1 5\r\nhello\r\nPING\r\n"[..];
+        bridge::read_loop(futures::io::BufReader::new(input), &subscriptions, &tx).await?;
+        assert_eq!(messages.next().await, Some(b"hello".to_vec()));
+        assert_eq!(frames.next().await, Some(b"PONG\r\n".to_vec()));
+
+        let input = &b"MSG subject 1 18446744073709551615\r\n"[..];
+        let err = bridge::read_loop(futures::io::BufReader::new(input), &subscriptions, &tx)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<NatsError>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_lagged_removals_reconcile_provider_records() -> Result<()> {
+        tracing_try_init();
+        let mut storage = StorageConfig::new(None, 0, Duration::from_secs(100));
+        storage.event_capacity = 1;
+        let ipfs = Ipfs::<DefaultParams>::new(Config {
+            storage,
+            network: NetworkConfig::new(),
+        })
+        .await?;
+        let blocks = (0..4u8)
+            .map(|i| create_block(&[i]))
+            .collect::<Result<Vec<_>>>()?;
+        let cids = blocks.iter().map(|block| *block.cid()).collect::<Vec<_>>();
+        for block in &blocks {
+            ipfs.storage.insert(block)?;
+        }
+        ipfs.republisher.schedule(&cids)?;
+        assert_eq!(ipfs.storage.published()?.len(), 4);
+
+        // the removals overflow the event buffer, so the core task lags and recovers the
+        // missed removals from the store.
+        ipfs.evict().await?;
+        eventually(|| ipfs.storage.published().unwrap().is_empty()).await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_custom_exchange() -> Result<()> {
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        /// Sync that completes right away.
+        struct Done(Option<Result<()>>);
+
+        impl Stream for Done {
+            type Item = SyncEvent;
+
+            fn poll_next(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<Option<SyncEvent>> {
+                Poll::Ready(self.0.take().map(SyncEvent::Complete))
+            }
+        }
+
+        impl Future for Done {
+            type Output = Result<()>;
+
+            fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+                Poll::Ready(self.0.take().unwrap_or(Ok(())))
+            }
+        }
+
+        /// Exchange inserting the blocks of a map in to the store of the node.
+        #[derive(Clone, Default)]
+        struct MockExchange {
+            blocks: Arc<Mutex<FnvHashMap<Cid, Block<DefaultParams>>>>,
+            store: Arc<Mutex<Option<BitswapStorage<DefaultParams>>>>,
+            provided: Arc<Mutex<FnvHashSet<Cid>>>,
+        }
+
+        impl MockExchange {
+            fn insert(&self, cid: &Cid) -> Result<()> {
+                let block = self
+                    .blocks
+                    .lock()
+                    .get(cid)
+                    .cloned()
+                    .ok_or(BlockNotFound(*cid))?;
+                self.store.lock().as_mut().unwrap().insert(&block)
+            }
+        }
+
+        impl BlockExchange<DefaultParams> for MockExchange {
+            type Want = futures::future::Ready<Result<()>>;
+            type Sync = Done;
+            type Provide = futures::future::Ready<Result<()>>;
+
+            fn want(&self, cid: Cid, _priority: Priority) -> Self::Want {
+                futures::future::ready(self.insert(&cid))
+            }
+
+            fn sync(&self, missing: Vec<Cid>, _priority: Priority) -> Self::Sync {
+                let res = missing.iter().try_for_each(|cid| self.insert(cid));
+                Done(Some(res))
+            }
+
+            fn wants(&self) -> (usize, usize) {
+                (0, 0)
+            }
+
+            fn provide(&self, cid: Cid) -> Self::Provide {
+                self.provided.lock().insert(cid);
+                futures::future::ready(Ok(()))
+            }
+
+            fn unprovide(&self, cid: Cid) {
+                self.provided.lock().remove(&cid);
+            }
+        }
+
+        tracing_try_init();
+        let exchange = MockExchange::default();
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        let ipfs = Ipfs::<DefaultParams>::with_exchange(
+            Config {
+                storage: StorageConfig::new(None, 10, Duration::from_secs(100)),
+                network,
+            },
+            exchange.clone(),
+        )
+        .await?;
+        *exchange.store.lock() = Some(ipfs.bitswap_store());
+        let tmp = ipfs.create_temp_pin()?;
+
+        let fetched = create_block(b"fetched")?;
+        exchange
+            .blocks
+            .lock()
+            .insert(*fetched.cid(), fetched.clone());
+        ipfs.temp_pin(&tmp, fetched.cid())?;
+        assert_eq!(ipfs.fetch(fetched.cid()).await?.data(), fetched.data());
+
+        let leaf = create_block(b"leaf")?;
+        let root = create_ipld_block(&ipld!([leaf.cid()]))?;
+        exchange.blocks.lock().insert(*leaf.cid(), leaf.clone());
+        ipfs.temp_pin(&tmp, root.cid())?;
+        ipfs.insert(&root)?.await?;
+        ipfs.sync(root.cid()).await?;
+        assert!(ipfs.contains(leaf.cid())?);
+
+        assert!(exchange.provided.lock().contains(root.cid()));
+        ipfs.deny(root.cid())?;
+        assert!(!exchange.provided.lock().contains(root.cid()));
+        Ok(())
+    }
+
+    /// Creates a node whose swarm connects over in-memory channels.
+    async fn create_memory_store() -> Result<Ipfs<DefaultParams>> {
+        use libp2p::core::transport::{MemoryTransport, Transport};
+        use libp2p::core::upgrade::Version;
+        use libp2p::mplex::MplexConfig;
+        use libp2p::noise::{self, NoiseConfig, X25519Spec};
+
+        let storage = StorageConfig::new(None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new();
+        network.enable_mdns = false;
+        let dh_key = noise::Keypair::<X25519Spec>::new().into_authentic(&network.node_key)?;
+        let transport = MemoryTransport::default()
+            .upgrade(Version::V1)
+            .authenticate(NoiseConfig::xx(dh_key).into_authenticated())
+            .multiplex(MplexConfig::new())
+            .boxed();
+        let ipfs = Ipfs::with_transport(Config { storage, network }, transport).await?;
+        ipfs.listen_on("/memory/0".parse()?).await?;
+        Ok(ipfs)
+    }
+
+    #[async_std::test]
+    async fn test_with_transport() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_memory_store().await?;
+        let store2 = create_memory_store().await?;
+        let addr = store1.listeners()[0].clone();
+        assert!(addr.to_string().starts_with("/memory/"));
+
+        let block = create_block(b"test_with_transport")?;
+        let tmp1 = store1.create_temp_pin()?;
+        store1.temp_pin(&tmp1, block.cid())?;
+        let _ = store1.insert(&block)?;
+        store1.flush().await?;
+
+        store2.dial_address(&store1.local_peer_id(), addr)?;
+        let peer1 = store1.local_peer_id();
+        eventually(|| store2.peers().contains(&peer1)).await;
+
+        let tmp2 = store2.create_temp_pin()?;
+        store2.temp_pin(&tmp2, block.cid())?;
+        let block2 = store2.fetch(block.cid()).await?;
+        assert_eq!(block.data(), block2.data());
+        Ok(())
+    }
 }
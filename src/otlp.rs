@@ -0,0 +1,150 @@
+use futures::stream::{self, Stream};
+use ipfs_embed_rt::Timer;
+use libipld::Result;
+use opentelemetry::metrics::ObserverResult;
+use opentelemetry::sdk::metrics::{selectors, PushController};
+use opentelemetry::sdk::trace::Tracer;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::ExporterConfig;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::Registry;
+use std::time::Duration;
+
+/// Handle of the otlp metrics exporter. Exporting stops when it is dropped.
+pub struct OtlpMetrics {
+    _controller: PushController,
+}
+
+/// Exports the metrics registered in `registry` to the otlp collector listening on
+/// `endpoint` every `interval`.
+///
+/// Register the metrics of the node with `Ipfs::register_metrics` before exporting them.
+/// Counters and gauges are exported as value observers with the prometheus labels as
+/// attributes, histograms and summaries as their sample sum and count.
+pub fn otlp_metrics(endpoint: &str, interval: Duration, registry: Registry) -> Result<OtlpMetrics> {
+    let controller = opentelemetry_otlp::new_metrics_pipeline(spawn, interval_stream)
+        .with_export_config(ExporterConfig {
+            endpoint: endpoint.into(),
+            ..Default::default()
+        })
+        .with_aggregator_selector(selectors::simple::Selector::Exact)
+        .with_period(interval)
+        .build()?;
+    let meter = global::meter("ipfs-embed");
+    for family in registry.gather() {
+        let name = family.get_name().to_string();
+        let registry = registry.clone();
+        meter
+            .f64_value_observer(family.get_name(), move |result| {
+                for family in registry.gather() {
+                    if family.get_name() == name {
+                        observe(&family, &result);
+                    }
+                }
+            })
+            .with_description(family.get_help())
+            .init();
+    }
+    Ok(OtlpMetrics {
+        _controller: controller,
+    })
+}
+
+/// Installs a tracer exporting spans to the otlp collector listening on `endpoint`.
+///
+/// The storage and network subsystems are instrumented using `tracing`, the tracer can
+/// be added to a subscriber with `tracing_opentelemetry::layer().with_tracer(tracer)`.
+pub fn otlp_tracer(endpoint: &str) -> Result<Tracer> {
+    let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_service_name("ipfs-embed")
+        .install()?;
+    // the tracer provider is kept installed for the lifetime of the process.
+    std::mem::forget(uninstall);
+    Ok(tracer)
+}
+
+fn observe(family: &MetricFamily, result: &ObserverResult<f64>) {
+    for metric in family.get_metric() {
+        let labels = labels(metric);
+        match family.get_field_type() {
+            MetricType::COUNTER => result.observe(metric.get_counter().get_value(), &labels),
+            MetricType::GAUGE => result.observe(metric.get_gauge().get_value(), &labels),
+            MetricType::UNTYPED => result.observe(metric.get_untyped().get_value(), &labels),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                observe_summary(
+                    result,
+                    &labels,
+                    histogram.get_sample_sum(),
+                    histogram.get_sample_count(),
+                );
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                observe_summary(
+                    result,
+                    &labels,
+                    summary.get_sample_sum(),
+                    summary.get_sample_count(),
+                );
+            }
+        }
+    }
+}
+
+fn observe_summary(result: &ObserverResult<f64>, labels: &[KeyValue], sum: f64, count: u64) {
+    let with_stat = |stat: &'static str, value: f64| {
+        let mut labels = labels.to_vec();
+        labels.push(KeyValue::new("stat", stat));
+        result.observe(value, &labels);
+    };
+    with_stat("sum", sum);
+    with_stat("count", count as f64);
+}
+
+fn labels(metric: &Metric) -> Vec<KeyValue> {
+    metric
+        .get_label()
+        .iter()
+        .map(|label| KeyValue::new(label.get_name().to_string(), label.get_value().to_string()))
+        .collect()
+}
+
+fn spawn<T: std::future::Future<Output = ()> + Send + 'static>(fut: T) {
+    ipfs_embed_rt::spawn(fut).detach();
+}
+
+fn interval_stream(interval: Duration) -> impl Stream<Item = ()> {
+    stream::unfold((), move |()| async move {
+        Timer::after(interval).await;
+        Some(((), ()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{IntCounterVec, Opts};
+
+    // nothing listens on the endpoint, the exporters connect lazily when exporting.
+    const ENDPOINT: &str = "http://127.0.0.1:4317";
+
+    #[test]
+    fn test_otlp_without_collector() {
+        // the grpc channels of the exporters are driven by tokio.
+        let rt = tokio_crate::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = rt.enter();
+
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("blocks", "blocks"), &["kind"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["raw"]).inc();
+        let metrics = otlp_metrics(ENDPOINT, Duration::from_millis(100), registry).unwrap();
+        let _tracer = otlp_tracer(ENDPOINT).unwrap();
+        drop(metrics);
+    }
+}
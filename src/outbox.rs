@@ -0,0 +1,75 @@
+use fnv::FnvHashSet;
+use futures::stream::{Stream, StreamExt};
+use ipfs_embed_net::{Event, NetworkService};
+use ipfs_embed_rt::Timer;
+use ipfs_embed_sqlite::StorageService;
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Ipld, Result};
+use std::time::Duration;
+
+/// Initial delay before flushing the outbox after a peer connected, giving gossipsub a
+/// chance to learn the subscriptions of the peer.
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay between attempts to flush the outbox while peers are connected.
+const MAX_FLUSH_DELAY: Duration = Duration::from_secs(60);
+
+/// Publishes the queued messages of every topic in order, skipping the rest of a topic
+/// after a message that can't be published yet, until the outbox is empty. Returns
+/// `false` if messages of some topics remain.
+fn flush<P: StoreParams>(storage: &StorageService<P>, network: &NetworkService<P>) -> Result<bool>
+where
+    Ipld: References<P::Codecs>,
+{
+    loop {
+        let records = storage.outbox()?;
+        if records.is_empty() {
+            return Ok(true);
+        }
+        let mut blocked = FnvHashSet::default();
+        for record in records {
+            if blocked.contains(&record.topic) {
+                continue;
+            }
+            if let Err(err) = network.publish(&record.topic, record.msg) {
+                tracing::debug!("outbox: failed to publish on {}: {}", record.topic, err);
+                blocked.insert(record.topic);
+                continue;
+            }
+            storage.remove_outbox(record.seq, &record.topic)?;
+        }
+        if !blocked.is_empty() {
+            return Ok(false);
+        }
+    }
+}
+
+/// Flushes the outbox whenever a peer connects, retrying with exponential backoff while
+/// peers are connected and messages remain.
+pub(crate) async fn run<P: StoreParams>(
+    storage: StorageService<P>,
+    network: NetworkService<P>,
+    events: impl Stream<Item = Event>,
+) where
+    Ipld: References<P::Codecs>,
+{
+    futures::pin_mut!(events);
+    while let Some(event) = events.next().await {
+        if !matches!(event, Event::Connected(_)) {
+            continue;
+        }
+        let mut delay = FLUSH_DELAY;
+        loop {
+            Timer::after(delay).await;
+            match flush(&storage, &network) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(err) => tracing::warn!("outbox: {}", err),
+            }
+            if network.connections().is_empty() {
+                break;
+            }
+            delay = std::cmp::min(delay * 2, MAX_FLUSH_DELAY);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::DAG_PB;
+use ipfs_embed_net::TraversalLimits;
+use libipld::{Cid, Ipld, Result};
+use thiserror::Error;
+
+/// Error returned when a path doesn't exist.
+#[derive(Debug, Error)]
+#[error("path {0} not found")]
+pub struct PathNotFound(pub String);
+
+struct Resolver<'a, F> {
+    get: F,
+    limits: &'a TraversalLimits,
+    depth: usize,
+    blocks: usize,
+    bytes: u64,
+    /// Codec of the last loaded block.
+    codec: u64,
+}
+
+impl<'a, F> Resolver<'a, F>
+where
+    F: Fn(&Cid) -> Result<(Ipld, usize)>,
+{
+    fn load(&mut self, cid: &Cid) -> Result<Ipld> {
+        self.blocks += 1;
+        self.limits.check_blocks(self.blocks)?;
+        self.codec = cid.codec();
+        let (ipld, len) = (self.get)(cid)?;
+        self.bytes += len as u64;
+        self.limits.check_bytes(self.bytes)?;
+        Ok(ipld)
+    }
+
+    /// Follows links until reaching a value that isn't a link.
+    fn follow(&mut self, mut ipld: Ipld) -> Result<Ipld> {
+        while let Ipld::Link(cid) = ipld {
+            ipld = self.load(&cid)?;
+        }
+        Ok(ipld)
+    }
+}
+
+/// Returns the target of the link named `name` of a dag-pb node.
+fn named_link(node: &Ipld, name: &str) -> Option<Ipld> {
+    match node.get("Links") {
+        Ok(Ipld::List(links)) => links.iter().find_map(|link| match link.get("Name") {
+            Ok(Ipld::String(n)) if n == name => link.get("Hash").ok().cloned(),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+/// Resolves a `/` separated `path` starting at the block `root`, following links across
+/// blocks. List elements are addressed by their index. Segments of dag-pb nodes are the
+/// names of their links, so unixfs directories can be traversed by file name.
+pub(crate) fn resolve_path<F>(
+    get: F,
+    root: &Cid,
+    path: &str,
+    limits: &TraversalLimits,
+) -> Result<Ipld>
+where
+    F: Fn(&Cid) -> Result<(Ipld, usize)>,
+{
+    let (mut resolver, ipld) = walk(get, root, path, limits)?;
+    resolver.follow(ipld)
+}
+
+/// Resolves `path` like `resolve_path`, but returns the block the path points to instead
+/// of its value. Fails if the path ends at a value that isn't a link.
+pub(crate) fn resolve_link<F>(
+    get: F,
+    root: &Cid,
+    path: &str,
+    limits: &TraversalLimits,
+) -> Result<Cid>
+where
+    F: Fn(&Cid) -> Result<(Ipld, usize)>,
+{
+    if path.split('/').all(|segment| segment.is_empty()) {
+        return Ok(*root);
+    }
+    match walk(get, root, path, limits)?.1 {
+        Ipld::Link(cid) => Ok(cid),
+        _ => Err(PathNotFound(path.to_string()).into()),
+    }
+}
+
+/// Walks the segments of `path`, returning the value of the last segment without
+/// following it if it is a link.
+fn walk<'a, F>(
+    get: F,
+    root: &Cid,
+    path: &str,
+    limits: &'a TraversalLimits,
+) -> Result<(Resolver<'a, F>, Ipld)>
+where
+    F: Fn(&Cid) -> Result<(Ipld, usize)>,
+{
+    let mut resolver = Resolver {
+        get,
+        limits,
+        depth: 0,
+        blocks: 0,
+        bytes: 0,
+        codec: root.codec(),
+    };
+    let mut ipld = resolver.load(root)?;
+    let mut resolved = String::new();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        // the depth counts the path segments, while following links to links only adds
+        // to the blocks.
+        resolver.depth += 1;
+        resolver.limits.check_depth(resolver.depth)?;
+        resolved.push('/');
+        resolved.push_str(segment);
+        let node = resolver.follow(ipld)?;
+        let link = if resolver.codec == DAG_PB {
+            named_link(&node, segment)
+        } else {
+            None
+        };
+        let value = link.or_else(|| match node {
+            Ipld::StringMap(mut map) => map.remove(segment),
+            Ipld::List(mut list) => segment
+                .parse::<usize>()
+                .ok()
+                .filter(|i| *i < list.len())
+                .map(|i| list.swap_remove(i)),
+            _ => None,
+        });
+        ipld = value.ok_or_else(|| PathNotFound(resolved.clone()))?;
+    }
+    Ok((resolver, ipld))
+}
@@ -0,0 +1,106 @@
+use fnv::FnvHashMap;
+use ipfs_embed_net::{Heartbeat, NetworkService, PeerId, PeerStats};
+use ipfs_embed_sqlite::{PeerStatsRecord, StorageService};
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Ipld, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn to_record(peer: &PeerId, stats: &PeerStats, updated: u64) -> PeerStatsRecord {
+    PeerStatsRecord {
+        peer: peer.to_bytes(),
+        rtt_ewma: stats.rtt_ewma.map(|rtt| rtt.as_micros() as u64),
+        dial_successes: stats.dial_successes,
+        dial_failures: stats.dial_failures,
+        bytes_sent: stats.bytes_sent,
+        bytes_received: stats.bytes_received,
+        updated,
+        auth_failures: stats.auth_failures,
+    }
+}
+
+fn from_record(record: &PeerStatsRecord) -> Result<(PeerId, PeerStats)> {
+    let peer = PeerId::from_bytes(&record.peer)?;
+    let stats = PeerStats {
+        rtt_ewma: record.rtt_ewma.map(Duration::from_micros),
+        dial_successes: record.dial_successes,
+        dial_failures: record.dial_failures,
+        bytes_sent: record.bytes_sent,
+        bytes_received: record.bytes_received,
+        auth_failures: record.auth_failures,
+    };
+    Ok((peer, stats))
+}
+
+/// Seeds the network with the persisted peer statistics. Returns the restored statistics.
+pub(crate) fn restore<P: StoreParams>(
+    storage: &StorageService<P>,
+    network: &NetworkService<P>,
+) -> Result<FnvHashMap<PeerId, PeerStats>>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut restored = FnvHashMap::default();
+    for record in storage.peer_stats()? {
+        match from_record(&record) {
+            Ok((peer, stats)) => {
+                network.restore_peer_stats(&peer, stats);
+                restored.insert(peer, stats);
+            }
+            Err(err) => tracing::debug!("skipping invalid peer stats: {}", err),
+        }
+    }
+    Ok(restored)
+}
+
+/// Persists the statistics of the peers that changed since they were last saved.
+fn save<P: StoreParams>(
+    storage: &StorageService<P>,
+    network: &NetworkService<P>,
+    saved: &mut FnvHashMap<PeerId, PeerStats>,
+) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+{
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let current = network
+        .peer_stats()
+        .into_iter()
+        .collect::<FnvHashMap<_, _>>();
+    // the network drops the statistics of peers it forgot about.
+    saved.retain(|peer, _| current.contains_key(peer));
+    let changed = current
+        .into_iter()
+        .filter(|(peer, stats)| saved.get(peer) != Some(stats))
+        .collect::<Vec<_>>();
+    let records = changed
+        .iter()
+        .map(|(peer, stats)| to_record(peer, stats, now))
+        .collect::<Vec<_>>();
+    storage.save_peer_stats(&records)?;
+    saved.extend(changed);
+    Ok(())
+}
+
+/// Periodically persists the peer statistics.
+pub(crate) async fn run<P: StoreParams>(
+    storage: StorageService<P>,
+    network: NetworkService<P>,
+    mut saved: FnvHashMap<PeerId, PeerStats>,
+    interval: Duration,
+    heartbeat: Heartbeat,
+) where
+    Ipld: References<P::Codecs>,
+{
+    let activity = network.activity();
+    loop {
+        activity.sleep(interval).await;
+        heartbeat.beat();
+        if let Err(err) = save(&storage, &network, &mut saved) {
+            tracing::warn!("failed to save peer stats: {}", err);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use fnv::FnvHashMap;
+use futures::channel::mpsc;
+use futures::future::{self, Either, FutureExt};
+use futures::stream::{Stream, StreamExt};
+use ipfs_embed_net::{Keypair, NetworkService, PeerId, PublicKey};
+use ipfs_embed_rt::Task;
+use libipld::cbor::DagCborCodec;
+use libipld::codec::Codec;
+use libipld::store::StoreParams;
+use libipld::{Ipld, Result};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const DOMAIN: &[u8] = b"/ipfs-embed/presence/1.0.0/";
+
+/// Members of the joined rooms and when their last beacon was received.
+pub(crate) type Rooms = Arc<Mutex<FnvHashMap<String, FnvHashMap<PeerId, Instant>>>>;
+
+/// Configuration of a presence room.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PresenceConfig {
+    /// Interval between beacons.
+    pub interval: Duration,
+    /// Members that didn't send a beacon for `timeout` leave the room. Beacons with a
+    /// timestamp further than `timeout` from the local clock are ignored, and within the
+    /// timeout beacons need to increase the sequence number of their sender, so that
+    /// recorded beacons can't be replayed.
+    pub timeout: Duration,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Event of a presence room.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PresenceEvent {
+    /// A peer joined the room.
+    Joined(PeerId),
+    /// A peer left the room or timed out.
+    Left(PeerId),
+}
+
+/// Error returned when joining a room that was already joined.
+#[derive(Debug, Error)]
+#[error("room {0} was already joined")]
+pub struct AlreadyJoined(pub String);
+
+/// Error returned when a beacon is malformed or isn't signed by its sender.
+#[derive(Debug, Error)]
+#[error("invalid presence beacon")]
+pub struct InvalidBeacon;
+
+/// Returns the gossipsub topic of the room.
+fn room_topic(room: &str) -> String {
+    format!("/ipfs-embed/presence/{}", room)
+}
+
+/// Returns the unix time in milliseconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Returns the sequence number of the next beacon: the time in milliseconds, unless
+/// beacons were sent faster, so that it increases across restarts.
+fn next_seq(last: &AtomicU64) -> u64 {
+    let now = now();
+    let mut current = last.load(Ordering::SeqCst);
+    loop {
+        let seq = std::cmp::max(now, current + 1);
+        match last.compare_exchange(current, seq, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return seq,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+fn message(room: &str, seq: u64, leave: bool) -> Vec<u8> {
+    let mut msg = DOMAIN.to_vec();
+    msg.extend_from_slice(&(room.len() as u64).to_be_bytes());
+    msg.extend_from_slice(room.as_bytes());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.push(leave as u8);
+    msg
+}
+
+/// Encodes a beacon signed with the node key. A `leave` beacon announces that the node
+/// left the room.
+fn encode_beacon(keypair: &Keypair, room: &str, seq: u64, leave: bool) -> Result<Vec<u8>> {
+    let signature = keypair.sign(&message(room, seq, leave))?;
+    let mut map = BTreeMap::new();
+    map.insert("seq".to_string(), Ipld::Integer(seq as i128));
+    map.insert("leave".to_string(), Ipld::Bool(leave));
+    map.insert(
+        "public_key".to_string(),
+        Ipld::Bytes(keypair.public().into_protobuf_encoding()),
+    );
+    map.insert("signature".to_string(), Ipld::Bytes(signature));
+    DagCborCodec.encode(&Ipld::StringMap(map))
+}
+
+/// Decodes a beacon, returning its sender, its sequence number and if it left the room.
+fn decode_beacon(bytes: &[u8], room: &str, max_age: Duration) -> Result<(PeerId, u64, bool)> {
+    let ipld: Ipld = DagCborCodec.decode(bytes)?;
+    let (seq, leave, public_key, signature) = match (
+        ipld.get("seq"),
+        ipld.get("leave"),
+        ipld.get("public_key"),
+        ipld.get("signature"),
+    ) {
+        (
+            Ok(Ipld::Integer(time)),
+            Ok(Ipld::Bool(leave)),
+            Ok(Ipld::Bytes(public_key)),
+            Ok(Ipld::Bytes(signature)),
+        ) if *seq >= 0 && *seq <= u64::MAX as i128 => (*seq as u64, *leave, public_key, signature),
+        _ => return Err(InvalidBeacon.into()),
+    };
+    let now = now();
+    let age = if seq > now { seq - now } else { now - seq };
+    let public_key = PublicKey::from_protobuf_encoding(public_key)?;
+    if u128::from(age) > max_age.as_millis()
+        || !public_key.verify(&message(room, seq, leave), signature)
+    {
+        return Err(InvalidBeacon.into());
+    }
+    Ok((public_key.into_peer_id(), seq, leave))
+}
+
+/// A joined presence room. The node leaves the room when it is dropped.
+///
+/// Members of a room periodically publish beacons signed with their node key on the topic
+/// `/ipfs-embed/presence/<room>`. Peers are members until they announce that they left
+/// or their beacons time out. The room is a stream of the members joining and leaving.
+pub struct Room<P: StoreParams> {
+    network: NetworkService<P>,
+    name: String,
+    rooms: Rooms,
+    /// Sequence number of the last beacon sent.
+    seq: Arc<AtomicU64>,
+    events: mpsc::UnboundedReceiver<PresenceEvent>,
+    _task: Task<()>,
+}
+
+impl<P: StoreParams> Room<P> {
+    pub(crate) fn join(
+        network: NetworkService<P>,
+        rooms: Rooms,
+        name: &str,
+        config: PresenceConfig,
+    ) -> Result<Self> {
+        {
+            let mut rooms = rooms.lock();
+            if rooms.contains_key(name) {
+                return Err(AlreadyJoined(name.into()).into());
+            }
+            rooms.insert(name.into(), Default::default());
+        }
+        let beacons = match network.subscribe(&room_topic(name)) {
+            Ok(beacons) => beacons,
+            Err(err) => {
+                rooms.lock().remove(name);
+                return Err(err);
+            }
+        };
+        let (tx, events) = mpsc::unbounded();
+        let seq = Arc::new(AtomicU64::new(0));
+        let task = run(
+            network.clone(),
+            rooms.clone(),
+            seq.clone(),
+            name.to_string(),
+            config,
+            beacons,
+            tx,
+        );
+        Ok(Self {
+            network,
+            name: name.into(),
+            rooms,
+            seq,
+            events,
+            _task: ipfs_embed_rt::spawn(task),
+        })
+    }
+
+    /// Returns the name of the room.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<P: StoreParams> Stream for Room<P> {
+    type Item = PresenceEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+impl<P: StoreParams> Drop for Room<P> {
+    fn drop(&mut self) {
+        self.rooms.lock().remove(&self.name);
+        let keypair = self.network.node_key();
+        let res = encode_beacon(&keypair, &self.name, next_seq(&self.seq), true)
+            .and_then(|msg| self.network.publish(&room_topic(&self.name), msg));
+        if let Err(err) = res {
+            tracing::debug!("room {}: failed to announce leaving: {}", self.name, err);
+        }
+    }
+}
+
+/// Publishes beacons every `interval` and tracks the members of the room until the room
+/// is left.
+async fn run<P: StoreParams>(
+    network: NetworkService<P>,
+    rooms: Rooms,
+    seq: Arc<AtomicU64>,
+    name: String,
+    config: PresenceConfig,
+    beacons: impl Stream<Item = Vec<u8>>,
+    tx: mpsc::UnboundedSender<PresenceEvent>,
+) {
+    futures::pin_mut!(beacons);
+    // sequence number of the last beacon of each peer. kept for twice the timeout, after
+    // which replayed beacons are rejected for their age.
+    let mut last_seq = FnvHashMap::<PeerId, (u64, Instant)>::default();
+    let activity = network.activity();
+    let mut timer = activity.sleep(Duration::from_secs(0)).boxed();
+    loop {
+        let msg = match future::select(beacons.next(), &mut timer).await {
+            Either::Left((Some(bytes), _)) => Some(bytes),
+            Either::Left((None, _)) => break,
+            Either::Right(_) => None,
+        };
+        let bytes = match msg {
+            Some(bytes) => bytes,
+            None => {
+                // beacons are sent less often in the background and not at all while the
+                // node is suspended, in which case the members time out the node.
+                timer = activity.sleep(config.interval).boxed();
+                let res = encode_beacon(&network.node_key(), &name, next_seq(&seq), false)
+                    .and_then(|msg| network.publish(&room_topic(&name), msg));
+                if let Err(err) = res {
+                    tracing::debug!("room {}: failed to publish beacon: {}", name, err);
+                }
+                let mut rooms = rooms.lock();
+                let members = match rooms.get_mut(&name) {
+                    Some(members) => members,
+                    None => break,
+                };
+                members.retain(|peer, seen| {
+                    let alive = seen.elapsed() < config.timeout;
+                    if !alive {
+                        tx.unbounded_send(PresenceEvent::Left(*peer)).ok();
+                    }
+                    alive
+                });
+                last_seq.retain(|_, (_, seen)| seen.elapsed() < config.timeout * 2);
+                continue;
+            }
+        };
+        let (peer, beacon_seq, leave) = match decode_beacon(&bytes, &name, config.timeout) {
+            Ok(beacon) => beacon,
+            Err(err) => {
+                tracing::debug!("room {}: {}", name, err);
+                continue;
+            }
+        };
+        match last_seq.get(&peer) {
+            Some((last, _)) if *last >= beacon_seq => {
+                tracing::debug!("room {}: replayed beacon of {}", name, peer);
+                continue;
+            }
+            _ => {
+                last_seq.insert(peer, (beacon_seq, Instant::now()));
+            }
+        }
+        let mut rooms = rooms.lock();
+        let members = match rooms.get_mut(&name) {
+            Some(members) => members,
+            None => break,
+        };
+        if leave {
+            if members.remove(&peer).is_some() {
+                tx.unbounded_send(PresenceEvent::Left(peer)).ok();
+            }
+        } else if members.insert(peer, Instant::now()).is_none() {
+            tx.unbounded_send(PresenceEvent::Joined(peer)).ok();
+        }
+    }
+}
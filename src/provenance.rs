@@ -0,0 +1,59 @@
+use ipfs_embed_net::{Keypair, PeerId, PublicKey};
+use libipld::{Cid, Result};
+use thiserror::Error;
+
+const DOMAIN: &[u8] = b"/ipfs-embed/provenance/1.0.0/";
+
+fn message(cid: &Cid) -> Vec<u8> {
+    let mut msg = DOMAIN.to_vec();
+    msg.extend(cid.to_bytes());
+    msg
+}
+
+/// A signed statement by a peer that it authored a block.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Provenance {
+    public_key: PublicKey,
+    signature: Vec<u8>,
+}
+
+impl Provenance {
+    /// Creates a new `Provenance` from a public key and a signature.
+    pub fn new(public_key: PublicKey, signature: Vec<u8>) -> Self {
+        Self {
+            public_key,
+            signature,
+        }
+    }
+
+    /// Signs a `Cid` with a keypair.
+    pub fn sign(keypair: &Keypair, cid: &Cid) -> Result<Self> {
+        let signature = keypair.sign(&message(cid))?;
+        Ok(Self::new(keypair.public(), signature))
+    }
+
+    /// Returns the public key of the author.
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// Returns the `PeerId` of the author.
+    pub fn peer_id(&self) -> PeerId {
+        self.public_key.clone().into_peer_id()
+    }
+
+    /// Returns the signature.
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+
+    /// Verifies the signature for a `Cid`.
+    pub fn verify(&self, cid: &Cid) -> bool {
+        self.public_key.verify(&message(cid), &self.signature)
+    }
+}
+
+/// Error returned when a `Provenance` has an invalid signature.
+#[derive(Debug, Error)]
+#[error("invalid provenance signature for block {0}")]
+pub struct InvalidProvenance(pub Cid);
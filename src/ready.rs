@@ -0,0 +1,99 @@
+use crate::diagnose::{check, Check};
+use crate::Ipfs;
+use ipfs_embed_net::{Multiaddr, PeerId};
+use ipfs_embed_sqlite::RecoveryReport;
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::Ipld;
+use std::time::Duration;
+
+/// Configures what `Ipfs::ready` waits for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReadyConfig {
+    /// Number of addresses the node needs to listen on. Listeners added with
+    /// `add_listener` are bound in the background. Defaults to none, since nodes that
+    /// only dial out don't listen.
+    pub listeners: usize,
+    /// Boot nodes the dht is bootstrapped from. The bootstrap is skipped if empty.
+    pub boot_nodes: Vec<(PeerId, Multiaddr)>,
+    /// Timeout of every step.
+    pub timeout: Duration,
+}
+
+impl Default for ReadyConfig {
+    fn default() -> Self {
+        Self {
+            listeners: 0,
+            boot_nodes: vec![],
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Report returned by `Ipfs::ready`, suitable for readiness probes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReadyReport {
+    /// Time a query of the store took.
+    pub storage: Check,
+    /// The recovery performed when the store was opened.
+    pub recovery: Option<RecoveryReport>,
+    /// Time it took until the node listened on `ReadyConfig::listeners` addresses.
+    pub listeners: Check,
+    /// Bound listen addresses.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Time it took to bootstrap the dht, or the reason the last attempt failed. `None`
+    /// if no boot nodes were configured.
+    pub bootstrap: Option<Check>,
+}
+
+impl ReadyReport {
+    /// Returns `true` if none of the steps failed.
+    pub fn is_ready(&self) -> bool {
+        self.storage.is_ok()
+            && self.listeners.is_ok()
+            && self
+                .bootstrap
+                .as_ref()
+                .map(|check| check.is_ok())
+                .unwrap_or(true)
+    }
+}
+
+pub(crate) async fn ready<P: StoreParams>(ipfs: &Ipfs<P>, config: &ReadyConfig) -> ReadyReport
+where
+    Ipld: References<P::Codecs>,
+{
+    let storage = check(config.timeout, async {
+        ipfs.storage.free_pages()?;
+        Ok(())
+    })
+    .await;
+    let listeners = check(config.timeout, async {
+        ipfs.network.wait_for_listeners(config.listeners).await?;
+        Ok(())
+    })
+    .await;
+    let bootstrapped = *ipfs.bootstrapped.lock();
+    let bootstrap = if config.boot_nodes.is_empty() {
+        None
+    } else if let Some(elapsed) = bootstrapped {
+        Some(Ok(elapsed))
+    } else {
+        let bootstrap = check(config.timeout, async {
+            ipfs.bootstrap(&config.boot_nodes).await?;
+            Ok(())
+        })
+        .await;
+        if let Ok(elapsed) = bootstrap {
+            ipfs.bootstrapped.lock().get_or_insert(elapsed);
+        }
+        Some(bootstrap)
+    };
+    ReadyReport {
+        storage,
+        recovery: ipfs.storage.recovery_report().cloned(),
+        listeners,
+        listen_addrs: ipfs.network.listeners(),
+        bootstrap,
+    }
+}
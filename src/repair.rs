@@ -0,0 +1,108 @@
+use crate::events::{EventBus, NodeEvent};
+use fnv::FnvHashSet;
+use ipfs_embed_net::{NetworkService, Priority};
+use ipfs_embed_sqlite::StorageService;
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use std::time::Duration;
+
+/// Summary of a pin verification.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// Number of verified roots.
+    pub roots: usize,
+    /// Number of blocks that were missing.
+    pub missing_blocks: usize,
+    /// Roots whose missing blocks were fetched.
+    pub repaired: Vec<Cid>,
+    /// Roots that still miss blocks.
+    pub failed: Vec<Cid>,
+}
+
+/// Verifies that the dags of all aliases are complete and fetches the missing blocks
+/// from the network.
+pub(crate) async fn verify<P: StoreParams>(
+    storage: &StorageService<P>,
+    network: &NetworkService<P>,
+    events: &EventBus,
+) -> Result<VerifyReport>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut report = VerifyReport::default();
+    let mut roots = FnvHashSet::default();
+    // walking the dags blocks on sqlite, so it runs off the executor threads.
+    let storage2 = storage.clone();
+    let aliases = ipfs_embed_rt::spawn_blocking(move || storage2.aliases()).await?;
+    for (_, root) in aliases {
+        if !roots.insert(root) {
+            continue;
+        }
+        report.roots += 1;
+        let storage2 = storage.clone();
+        let (_tmp, missing) = ipfs_embed_rt::spawn_blocking(move || -> Result<_> {
+            // the tmp pin keeps fetched blocks alive in case the alias is removed meanwhile.
+            let tmp = storage2.create_temp_pin()?;
+            storage2.temp_pin(&tmp, std::iter::once(root))?;
+            let missing = storage2.missing_blocks(&root)?;
+            Ok((tmp, missing))
+        })
+        .await?;
+        if missing.is_empty() {
+            continue;
+        }
+        tracing::warn!("{} blocks of {} are missing", missing.len(), root);
+        report.missing_blocks += missing.len();
+        events.publish(NodeEvent::MissingBlocks(root, missing.len()));
+        let res = network
+            .sync_with_priority(root, missing.into_iter(), Priority::Background)
+            .hold(storage.begin_sync())
+            .await;
+        let res = match res {
+            Ok(_) => {
+                let storage2 = storage.clone();
+                ipfs_embed_rt::spawn_blocking(move || storage2.missing_blocks(&root)).await
+            }
+            Err(err) => Err(err),
+        };
+        match res {
+            Ok(missing) if missing.is_empty() => {
+                events.publish(NodeEvent::Repaired(root));
+                report.repaired.push(root);
+            }
+            Ok(missing) => {
+                let err = format!("{} blocks are missing", missing.len());
+                events.publish(NodeEvent::RepairFailed(root, err));
+                report.failed.push(root);
+            }
+            Err(err) => {
+                tracing::warn!("failed to repair {}: {}", root, err);
+                events.publish(NodeEvent::RepairFailed(root, err.to_string()));
+                report.failed.push(root);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Verifies the pins every `interval`.
+pub(crate) async fn run<P: StoreParams>(
+    storage: StorageService<P>,
+    network: NetworkService<P>,
+    events: EventBus,
+    interval: Duration,
+) where
+    Ipld: References<P::Codecs>,
+{
+    let heartbeat = network.health().paced_heartbeat("repair", interval);
+    let activity = network.activity();
+    loop {
+        activity.sleep(interval).await;
+        heartbeat.beat();
+        match verify(&storage, &network, &events).await {
+            Ok(report) => tracing::debug!("verified pins: {:?}", report),
+            Err(err) => tracing::warn!("failed to verify pins: {}", err),
+        }
+    }
+}
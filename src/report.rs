@@ -0,0 +1,316 @@
+use crate::Ipfs;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::stream::StreamExt;
+use ipfs_embed_net::{PeerId, PublicKey};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const DOMAIN: &[u8] = b"/ipfs-embed/report/1.0.0/";
+
+/// Error returned when a misbehaviour report is malformed, expired or isn't signed by
+/// its reporter.
+#[derive(Debug, Error)]
+#[error("invalid misbehaviour report")]
+pub struct InvalidReport;
+
+/// Error returned when a peer that isn't a member of the swarm files a report.
+#[derive(Debug, Error)]
+#[error("{0} is not a member of the swarm")]
+pub struct NotAMember(pub PeerId);
+
+/// Misbehaviour of a peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Offence {
+    /// The peer served a block that failed validation.
+    InvalidBlock(Cid),
+    /// The peer abused a protocol, for example by flooding requests.
+    ProtocolAbuse(String),
+}
+
+/// A signed report of a peer misbehaving.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MisbehaviourReport {
+    /// Member that filed the report.
+    pub reporter: PeerId,
+    /// Peer that misbehaved.
+    pub offender: PeerId,
+    /// What the peer did.
+    pub offence: Offence,
+    /// Seconds since the unix epoch when the report was filed.
+    pub time: u64,
+}
+
+/// Configuration of the misbehaviour reports of a permissioned swarm.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReportConfig {
+    /// Members whose reports are accepted.
+    pub members: Vec<PeerId>,
+    /// Number of members that need to report a peer before it is banned.
+    pub ban_threshold: usize,
+    /// Reports older than `max_age` are ignored, so that recorded reports can't be
+    /// replayed, and expire once they reach this age, so that peers that were reported
+    /// once aren't banned by reports filed much later.
+    pub max_age: Duration,
+}
+
+impl ReportConfig {
+    /// Bans peers reported by two members, accepting reports up to an hour old.
+    pub fn new(members: Vec<PeerId>) -> Self {
+        Self {
+            members,
+            ban_threshold: 2,
+            max_age: Duration::from_secs(3600),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn encode_payload(name: &str, report: &MisbehaviourReport) -> Result<Vec<u8>> {
+    let (kind, detail) = match &report.offence {
+        Offence::InvalidBlock(cid) => ("invalid_block", Ipld::Bytes(cid.to_bytes())),
+        Offence::ProtocolAbuse(reason) => ("protocol_abuse", Ipld::String(reason.clone())),
+    };
+    let mut map = BTreeMap::new();
+    map.insert("swarm".to_string(), Ipld::String(name.into()));
+    map.insert(
+        "offender".to_string(),
+        Ipld::Bytes(report.offender.to_bytes()),
+    );
+    map.insert("offence".to_string(), Ipld::String(kind.into()));
+    map.insert("detail".to_string(), detail);
+    map.insert("time".to_string(), Ipld::Integer(report.time as i128));
+    DagCborCodec.encode(&Ipld::StringMap(map))
+}
+
+fn decode_payload(name: &str, reporter: PeerId, bytes: &[u8]) -> Result<MisbehaviourReport> {
+    let ipld: Ipld = DagCborCodec.decode(bytes)?;
+    let (swarm, offender, offence, detail, time) = match (
+        ipld.get("swarm"),
+        ipld.get("offender"),
+        ipld.get("offence"),
+        ipld.get("detail"),
+        ipld.get("time"),
+    ) {
+        (
+            Ok(Ipld::String(swarm)),
+            Ok(Ipld::Bytes(offender)),
+            Ok(Ipld::String(offence)),
+            Ok(detail),
+            Ok(Ipld::Integer(time)),
+        ) if *time >= 0 && *time <= u64::MAX as i128 => {
+            (swarm, offender, offence, detail, *time as u64)
+        }
+        _ => return Err(InvalidReport.into()),
+    };
+    if swarm != name {
+        return Err(InvalidReport.into());
+    }
+    let offence = match (offence.as_str(), detail) {
+        ("invalid_block", Ipld::Bytes(cid)) => Offence::InvalidBlock(Cid::try_from(&cid[..])?),
+        ("protocol_abuse", Ipld::String(reason)) => Offence::ProtocolAbuse(reason.clone()),
+        _ => return Err(InvalidReport.into()),
+    };
+    Ok(MisbehaviourReport {
+        reporter,
+        offender: PeerId::from_bytes(offender).map_err(|_| InvalidReport)?,
+        offence,
+        time,
+    })
+}
+
+/// Misbehaviour reports shared by the members of a permissioned swarm.
+///
+/// Members file reports signed with their node key on the topic
+/// `/ipfs-embed/reports/<name>`. A peer reported by `ban_threshold` different members is
+/// banned by every node following the reports, so that the swarm collectively isolates
+/// a misbehaving node. Reports of peers that aren't members are ignored, and a member
+/// can't be banned by its own reports.
+#[derive(Clone)]
+pub struct Reports<P: StoreParams> {
+    ipfs: Ipfs<P>,
+    name: String,
+    members: Arc<FnvHashSet<PeerId>>,
+    ban_threshold: usize,
+    max_age: Duration,
+    reports: Arc<Mutex<FnvHashMap<PeerId, FnvHashMap<PeerId, MisbehaviourReport>>>>,
+}
+
+impl<P: StoreParams> Reports<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub(crate) fn new(ipfs: Ipfs<P>, name: &str, config: ReportConfig) -> Self {
+        Self {
+            ipfs,
+            name: name.into(),
+            members: Arc::new(config.members.into_iter().collect()),
+            ban_threshold: std::cmp::max(config.ban_threshold, 1),
+            max_age: config.max_age,
+            reports: Default::default(),
+        }
+    }
+
+    /// Returns the gossipsub topic of the reports.
+    pub fn topic(&self) -> String {
+        format!("/ipfs-embed/reports/{}", self.name)
+    }
+
+    /// Files a report about `offender`, applying it locally and publishing it to the
+    /// members. Fails with `NotAMember` if the local node isn't a member.
+    pub fn report(&self, offender: &PeerId, offence: Offence) -> Result<()> {
+        let report = MisbehaviourReport {
+            reporter: self.ipfs.local_peer_id(),
+            offender: *offender,
+            offence,
+            time: now(),
+        };
+        if !self.members.contains(&report.reporter) {
+            return Err(NotAMember(report.reporter).into());
+        }
+        let bytes = self.sign(&report)?;
+        self.apply(report);
+        if let Err(err) = self.ipfs.publish(&self.topic(), bytes) {
+            // the report is still applied locally if no member is subscribed yet.
+            tracing::debug!("reports {}: failed to publish report: {}", self.name, err);
+        }
+        Ok(())
+    }
+
+    /// Encodes a report signed with the node key.
+    pub(crate) fn sign(&self, report: &MisbehaviourReport) -> Result<Vec<u8>> {
+        let payload = encode_payload(&self.name, report)?;
+        let mut msg = DOMAIN.to_vec();
+        msg.extend_from_slice(&payload);
+        let node_key = self.ipfs.network.node_key();
+        let signature = node_key.sign(&msg)?;
+        let mut map = BTreeMap::new();
+        map.insert("report".to_string(), Ipld::Bytes(payload));
+        map.insert(
+            "public_key".to_string(),
+            Ipld::Bytes(node_key.public().into_protobuf_encoding()),
+        );
+        map.insert("signature".to_string(), Ipld::Bytes(signature));
+        DagCborCodec.encode(&Ipld::StringMap(map))
+    }
+
+    /// Verifies and applies a report received from the swarm.
+    pub fn receive(&self, bytes: &[u8]) -> Result<MisbehaviourReport> {
+        let ipld: Ipld = DagCborCodec.decode(bytes)?;
+        let (payload, public_key, signature) = match (
+            ipld.get("report"),
+            ipld.get("public_key"),
+            ipld.get("signature"),
+        ) {
+            (Ok(Ipld::Bytes(payload)), Ok(Ipld::Bytes(public_key)), Ok(Ipld::Bytes(signature))) => {
+                (payload, public_key, signature)
+            }
+            _ => return Err(InvalidReport.into()),
+        };
+        let public_key = PublicKey::from_protobuf_encoding(public_key)?;
+        let mut msg = DOMAIN.to_vec();
+        msg.extend_from_slice(payload);
+        if !public_key.verify(&msg, signature) {
+            return Err(InvalidReport.into());
+        }
+        let reporter = public_key.into_peer_id();
+        if !self.members.contains(&reporter) {
+            return Err(NotAMember(reporter).into());
+        }
+        let report = decode_payload(&self.name, reporter, payload)?;
+        let now = now();
+        let age = if report.time > now {
+            report.time - now
+        } else {
+            now - report.time
+        };
+        if age > self.max_age.as_secs() {
+            return Err(InvalidReport.into());
+        }
+        self.apply(report.clone());
+        Ok(report)
+    }
+
+    /// Returns if a report reached the `max_age`.
+    fn expired(&self, report: &MisbehaviourReport, now: u64) -> bool {
+        now.saturating_sub(report.time) > self.max_age.as_secs()
+    }
+
+    /// Removes the expired reports.
+    fn prune(&self, reports: &mut FnvHashMap<PeerId, FnvHashMap<PeerId, MisbehaviourReport>>) {
+        let now = now();
+        for reports in reports.values_mut() {
+            reports.retain(|_, report| !self.expired(report, now));
+        }
+        reports.retain(|_, reports| !reports.is_empty());
+    }
+
+    /// Records a report, banning the offender once enough members reported it. An older
+    /// report of a member doesn't replace its newer one.
+    fn apply(&self, report: MisbehaviourReport) {
+        if report.reporter == report.offender {
+            return;
+        }
+        let offender = report.offender;
+        let score = {
+            let mut reports = self.reports.lock();
+            self.prune(&mut reports);
+            let reports = reports.entry(offender).or_default();
+            match reports.get(&report.reporter) {
+                Some(newer) if newer.time > report.time => {}
+                _ => {
+                    reports.insert(report.reporter, report);
+                }
+            }
+            reports.len()
+        };
+        if score >= self.ban_threshold && offender != self.ipfs.local_peer_id() {
+            tracing::info!("reports {}: banning {}", self.name, offender);
+            self.ipfs.ban(offender);
+        }
+    }
+
+    /// Returns the number of members whose reports about `peer` didn't expire yet.
+    pub fn score(&self, peer: &PeerId) -> usize {
+        let mut reports = self.reports.lock();
+        self.prune(&mut reports);
+        reports
+            .get(peer)
+            .map(|reports| reports.len())
+            .unwrap_or_default()
+    }
+
+    /// Returns the latest report of every member about `peer` that didn't expire yet.
+    pub fn reports(&self, peer: &PeerId) -> Vec<MisbehaviourReport> {
+        let mut reports = self.reports.lock();
+        self.prune(&mut reports);
+        reports
+            .get(peer)
+            .map(|reports| reports.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Applies the reports published by the members until the subscription ends.
+    pub async fn follow(&self) -> Result<()> {
+        let mut reports = self.ipfs.subscribe(&self.topic())?;
+        while let Some(bytes) = reports.next().await {
+            if let Err(err) = self.receive(&bytes) {
+                tracing::debug!("reports {}: {}", self.name, err);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use crate::exchange::Exchange;
+use fnv::FnvHasher;
+use ipfs_embed_net::{Key, NetworkService, Quorum, Record};
+use ipfs_embed_sqlite::{PublishedRecord, StorageService};
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use std::convert::TryFrom;
+use std::hash::Hasher;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Interval at which the scheduler checks for records that are due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Returns the current unix time in seconds.
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Converts an `Instant` in to a unix timestamp in seconds.
+pub(crate) fn unix_time(instant: Instant) -> u64 {
+    unix_now() + instant.saturating_duration_since(Instant::now()).as_secs()
+}
+
+/// Persistent scheduler that republishes provider and dht records.
+#[derive(Clone)]
+pub(crate) struct Republisher<P: StoreParams> {
+    storage: StorageService<P>,
+    network: NetworkService<P>,
+    exchange: Exchange<P>,
+    interval: Duration,
+    jitter: Duration,
+}
+
+impl<P: StoreParams> Republisher<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub fn new(
+        storage: StorageService<P>,
+        network: NetworkService<P>,
+        exchange: Exchange<P>,
+        interval: Duration,
+        jitter: Duration,
+    ) -> Self {
+        Self {
+            storage,
+            network,
+            exchange,
+            interval,
+            jitter,
+        }
+    }
+
+    fn next_republish(&self, key: &[u8]) -> u64 {
+        let mut hasher = FnvHasher::default();
+        hasher.write(key);
+        let jitter = hasher.finish() % std::cmp::max(self.jitter.as_secs(), 1);
+        unix_now() + self.interval.as_secs() + jitter
+    }
+
+    /// Tracks a provider record.
+    pub fn provided(&self, cid: &Cid) -> Result<()> {
+        let key = cid.to_bytes();
+        self.storage.publish(&PublishedRecord {
+            republish: self.next_republish(&key),
+            key,
+            value: None,
+            expires: None,
+        })
+    }
+
+    /// Tracks provider records that are due right away, so that the scheduler provides
+    /// them unless `provide` did.
+    pub fn schedule(&self, cids: &[Cid]) -> Result<()> {
+        let republish = unix_now();
+        for cid in cids {
+            self.storage.publish(&PublishedRecord {
+                key: cid.to_bytes(),
+                value: None,
+                expires: None,
+                republish,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Provides scheduled records in the background. Records that fail to be provided
+    /// are retried by the scheduler.
+    pub fn provide(&self, cids: Vec<Cid>) {
+        let republisher = self.clone();
+        ipfs_embed_rt::spawn(async move {
+            for cid in cids {
+                if let Err(err) = republisher.exchange.provide(cid).await {
+                    tracing::debug!("providing {} failed: {}", cid, err);
+                    continue;
+                }
+                if let Err(err) = republisher.provided(&cid) {
+                    tracing::warn!("failed to track provider record: {}", err);
+                }
+            }
+        })
+        .detach();
+    }
+
+    /// Tracks a dht record.
+    pub fn put(&self, record: &Record) -> Result<()> {
+        let key = record.key.to_vec();
+        self.storage.publish(&PublishedRecord {
+            republish: self.next_republish(&key),
+            key,
+            value: Some(record.value.clone()),
+            expires: record.expires.map(unix_time),
+        })
+    }
+
+    /// Stops tracking a record.
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        self.storage.unpublish(key)
+    }
+
+    /// Stops providing the tracked provider records of blocks that aren't stored anymore,
+    /// for when removals of the garbage collector were missed.
+    pub fn reconcile(&self) -> Result<()> {
+        let cids = self
+            .storage
+            .published()?
+            .into_iter()
+            .filter(|record| record.value.is_none())
+            .filter_map(|record| Cid::try_from(record.key.as_slice()).ok())
+            .collect::<Vec<_>>();
+        let present = self.storage.contains_many(&cids)?;
+        for (cid, present) in cids.into_iter().zip(present) {
+            if !present {
+                self.exchange.unprovide(cid);
+                self.remove(&cid.to_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn republish(&self, record: PublishedRecord) -> Result<()> {
+        if let Some(value) = record.value {
+            let key = Key::from(record.key.clone());
+            let mut dht_record = Record::new(key, value);
+            if let Some(expires) = record.expires {
+                let ttl = Duration::from_secs(expires.saturating_sub(unix_now()));
+                dht_record.expires = Some(Instant::now() + ttl);
+            }
+            self.network.put_record(dht_record, Quorum::One).await
+        } else {
+            let cid = Cid::try_from(record.key.as_slice())?;
+            self.exchange.provide(cid).await
+        }
+    }
+
+    /// Republishes all records that are due.
+    pub async fn tick(&self) -> Result<()> {
+        let now = unix_now();
+        for mut record in self.storage.due_published(now)? {
+            if record
+                .expires
+                .map(|expires| expires <= now)
+                .unwrap_or_default()
+            {
+                tracing::debug!("record expired");
+                self.storage.unpublish(&record.key)?;
+                continue;
+            }
+            if let Err(err) = self.republish(record.clone()).await {
+                tracing::debug!("republishing record failed: {}", err);
+            }
+            record.republish = self.next_republish(&record.key);
+            self.storage.publish(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the scheduler.
+    pub async fn run(self) {
+        let heartbeat = self
+            .network
+            .health()
+            .paced_heartbeat("republish", CHECK_INTERVAL);
+        let activity = self.network.activity();
+        loop {
+            heartbeat.beat();
+            if let Err(err) = self.tick().await {
+                tracing::warn!("republish failed: {}", err);
+            }
+            activity.sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
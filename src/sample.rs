@@ -0,0 +1,83 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use ipfs_embed_net::{PeerId, PeerInfo, PeerStats};
+use rand::seq::SliceRandom;
+use std::time::Duration;
+
+/// Criteria of the peers returned by `sample_peers`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerFilter {
+    /// Only connected peers are sampled. Otherwise all known peers are.
+    pub connected: bool,
+    /// Protocols the peers need to support, as reported by identify.
+    pub protocols: Vec<String>,
+    /// Minimum share of successful dials. Peers that were never dialed pass.
+    pub min_success_rate: Option<f64>,
+    /// Maximum moving average of the rtt. Peers that were never pinged pass.
+    pub max_rtt: Option<Duration>,
+}
+
+impl PeerFilter {
+    /// Samples the connected peers.
+    pub fn connected() -> Self {
+        Self {
+            connected: true,
+            ..Default::default()
+        }
+    }
+
+    /// Only samples peers supporting `protocol`.
+    pub fn with_protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.protocols.push(protocol.into());
+        self
+    }
+
+    fn matches(&self, info: Option<&PeerInfo>, stats: Option<&PeerStats>) -> bool {
+        if !self.protocols.is_empty() {
+            let info = match info {
+                Some(info) => info,
+                None => return false,
+            };
+            let supported = info.protocols().collect::<FnvHashSet<_>>();
+            if !self
+                .protocols
+                .iter()
+                .all(|protocol| supported.contains(protocol.as_str()))
+            {
+                return false;
+            }
+        }
+        if let (Some(min), Some(rate)) = (
+            self.min_success_rate,
+            stats.and_then(|stats| stats.success_rate()),
+        ) {
+            if rate < min {
+                return false;
+            }
+        }
+        if let (Some(max), Some(rtt)) = (self.max_rtt, stats.and_then(|stats| stats.rtt_ewma)) {
+            if rtt > max {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Returns up to `n` of the `candidates` matching the `filter`, chosen uniformly at
+/// random.
+pub(crate) fn sample(
+    candidates: Vec<PeerId>,
+    n: usize,
+    filter: &PeerFilter,
+    info: impl Fn(&PeerId) -> Option<PeerInfo>,
+    stats: &FnvHashMap<PeerId, PeerStats>,
+) -> Vec<PeerId> {
+    let mut peers = candidates
+        .into_iter()
+        .collect::<FnvHashSet<_>>()
+        .into_iter()
+        .filter(|peer| filter.matches(info(peer).as_ref(), stats.get(peer)))
+        .collect::<Vec<_>>();
+    let (sample, _) = peers.partial_shuffle(&mut rand::thread_rng(), n);
+    sample.to_vec()
+}
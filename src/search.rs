@@ -0,0 +1,172 @@
+use crate::diagnose::timeout;
+use crate::tenant::TENANT_PREFIX;
+use crate::Ipfs;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::stream::{Stream, StreamExt};
+use ipfs_embed_net::{AppStream, PeerId};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Application protocol answering which aliases a node has below a prefix.
+pub const ALIAS_SEARCH_PROTOCOL: &str = "/ipfs-embed/alias-search/1.0.0";
+
+const MAX_REQUEST_SIZE: u64 = 4096;
+const MAX_RESPONSE_SIZE: u64 = 4 * 1024 * 1024;
+/// Timeout of a search, covering the request and the response.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Aliases used internally by the node, which are never visible to peers.
+const INTERNAL_PREFIXES: &[&[u8]] = &[b"/ipfs-embed/", TENANT_PREFIX];
+
+/// Configuration of the alias search protocol.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AliasSearchConfig {
+    /// Peers allowed to search the aliases.
+    pub peers: Vec<PeerId>,
+    /// Only aliases starting with `prefix` are visible to peers. Aliases used internally,
+    /// like the alias history and tenant aliases, are never visible.
+    pub prefix: Vec<u8>,
+    /// Maximum number of aliases returned for a search.
+    pub max_results: usize,
+}
+
+impl AliasSearchConfig {
+    /// Creates a new `AliasSearchConfig` making the aliases starting with `prefix`
+    /// visible to `peers`.
+    pub fn new(peers: Vec<PeerId>, prefix: impl Into<Vec<u8>>) -> Self {
+        Self {
+            peers,
+            prefix: prefix.into(),
+            max_results: 1000,
+        }
+    }
+}
+
+/// Error returned when a peer rejects an alias search.
+#[derive(Debug, Error)]
+#[error("{0} rejected the alias search: {1}")]
+pub struct SearchRejected(pub PeerId, pub String);
+
+/// Error returned when a search request or response is malformed.
+#[derive(Debug, Error)]
+#[error("invalid alias search message")]
+pub struct InvalidSearchMessage;
+
+async fn read_message(stream: &mut AppStream, limit: u64) -> Result<Ipld> {
+    let mut buf = vec![];
+    stream.take(limit).read_to_end(&mut buf).await?;
+    DagCborCodec.decode(&buf)
+}
+
+async fn write_message(stream: &mut AppStream, msg: &Ipld) -> Result<()> {
+    stream.write_all(&DagCborCodec.encode(msg)?).await?;
+    stream.close().await?;
+    Ok(())
+}
+
+fn encode_response(res: Result<Vec<(Vec<u8>, Cid)>, String>) -> Ipld {
+    let mut map = BTreeMap::new();
+    match res {
+        Ok(aliases) => {
+            let aliases = aliases
+                .into_iter()
+                .map(|(alias, cid)| Ipld::List(vec![Ipld::Bytes(alias), Ipld::Link(cid)]))
+                .collect();
+            map.insert("aliases".to_string(), Ipld::List(aliases));
+        }
+        Err(reason) => {
+            map.insert("error".to_string(), Ipld::String(reason));
+        }
+    }
+    Ipld::StringMap(map)
+}
+
+fn decode_response(peer: PeerId, ipld: Ipld) -> Result<Vec<(Vec<u8>, Cid)>> {
+    if let Ok(Ipld::String(reason)) = ipld.get("error") {
+        return Err(SearchRejected(peer, reason.clone()).into());
+    }
+    match ipld.get("aliases") {
+        Ok(Ipld::List(aliases)) => aliases
+            .iter()
+            .map(|entry| match entry {
+                Ipld::List(entry) => match entry.as_slice() {
+                    [Ipld::Bytes(alias), Ipld::Link(cid)] => Ok((alias.clone(), *cid)),
+                    _ => Err(InvalidSearchMessage.into()),
+                },
+                _ => Err(InvalidSearchMessage.into()),
+            })
+            .collect(),
+        _ => Err(InvalidSearchMessage.into()),
+    }
+}
+
+/// Answers a single search request of `peer`.
+async fn answer<P: StoreParams>(
+    ipfs: &Ipfs<P>,
+    config: &AliasSearchConfig,
+    peer: PeerId,
+    mut stream: AppStream,
+) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+{
+    let req = read_message(&mut stream, MAX_REQUEST_SIZE).await?;
+    let prefix = match req.get("prefix") {
+        Ok(Ipld::Bytes(prefix)) => prefix.clone(),
+        _ => return Err(InvalidSearchMessage.into()),
+    };
+    let res = if !config.peers.contains(&peer) {
+        Err("unauthorized".to_string())
+    } else if !prefix.starts_with(&config.prefix) {
+        Err("prefix not shared".to_string())
+    } else {
+        Ok(ipfs.storage.aliases_with_prefix_limited(
+            &prefix,
+            INTERNAL_PREFIXES,
+            config.max_results,
+        )?)
+    };
+    write_message(&mut stream, &encode_response(res)).await
+}
+
+/// Answers the search requests received on `streams`.
+pub(crate) async fn serve<P: StoreParams>(
+    ipfs: Ipfs<P>,
+    config: AliasSearchConfig,
+    streams: impl Stream<Item = (PeerId, AppStream)>,
+) where
+    Ipld: References<P::Codecs>,
+{
+    futures::pin_mut!(streams);
+    while let Some((peer, stream)) = streams.next().await {
+        let ipfs = ipfs.clone();
+        let config = config.clone();
+        ipfs_embed_rt::spawn(async move {
+            let res = timeout(SEARCH_TIMEOUT, answer(&ipfs, &config, peer, stream)).await;
+            if let Err(err) = res {
+                tracing::debug!("failed to answer alias search of {}: {}", peer, err);
+            }
+        })
+        .detach();
+    }
+}
+
+/// Asks `peer` for its aliases starting with `prefix`.
+pub(crate) async fn search(
+    peer: PeerId,
+    mut stream: AppStream,
+    prefix: &[u8],
+) -> Result<Vec<(Vec<u8>, Cid)>> {
+    let mut req = BTreeMap::new();
+    req.insert("prefix".to_string(), Ipld::Bytes(prefix.to_vec()));
+    let res = timeout(SEARCH_TIMEOUT, async {
+        write_message(&mut stream, &Ipld::StringMap(req)).await?;
+        read_message(&mut stream, MAX_RESPONSE_SIZE).await
+    })
+    .await?;
+    decode_response(peer, res)
+}
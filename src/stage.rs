@@ -0,0 +1,100 @@
+use crate::{Error, Ipfs};
+use fnv::FnvHashSet;
+use ipfs_embed_sqlite::{inline_data, TempPin};
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+
+/// Error returned when the staged blocks don't form a dag with a single root.
+#[derive(Debug, thiserror::Error)]
+#[error("staged blocks have {} roots instead of one", .0.len())]
+pub struct NoUniqueRoot(pub Vec<Cid>);
+
+/// A dag inserted in to the block store but not made durable yet. The blocks are kept
+/// alive by a temporary pin and are neither aliased nor announced to peers until the dag
+/// is committed. Dropping the `StagedDag` aborts it.
+pub struct StagedDag<P: StoreParams> {
+    ipfs: Ipfs<P>,
+    root: Cid,
+    blocks: Vec<Cid>,
+    tmp: TempPin,
+}
+
+impl<P: StoreParams> StagedDag<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub(crate) fn new(ipfs: &Ipfs<P>, blocks: impl IntoIterator<Item = Block<P>>) -> Result<Self> {
+        let tmp = ipfs.storage.create_temp_pin()?;
+        let mut cids = vec![];
+        let mut linked = FnvHashSet::default();
+        for block in blocks {
+            if block.data().len() > P::MAX_BLOCK_SIZE {
+                return Err(Error::BlockTooLarge(block.data().len()).into());
+            }
+            ipfs.validators.validate(None, &block)?;
+            let mut links = vec![];
+            block.references(&mut links)?;
+            linked.extend(links);
+            // pin before inserting, so that the garbage collector can't remove the block.
+            ipfs.storage.temp_pin(&tmp, std::iter::once(*block.cid()))?;
+            ipfs.storage.insert(&block)?;
+            cids.push(*block.cid());
+        }
+        let mut seen = FnvHashSet::default();
+        let roots = cids
+            .iter()
+            .filter(|cid| !linked.contains(*cid) && seen.insert(**cid))
+            .copied()
+            .collect::<Vec<_>>();
+        if roots.len() != 1 {
+            return Err(NoUniqueRoot(roots).into());
+        }
+        Ok(Self {
+            ipfs: ipfs.clone(),
+            root: roots[0],
+            blocks: cids,
+            tmp,
+        })
+    }
+
+    /// Returns the root of the staged dag, the only block not linked from another
+    /// staged block.
+    pub fn root(&self) -> &Cid {
+        &self.root
+    }
+
+    /// Returns the staged blocks in the order they were staged.
+    pub fn blocks(&self) -> &[Cid] {
+        &self.blocks
+    }
+
+    /// Returns a staged block, or any other block in the block store.
+    pub fn get(&self, cid: &Cid) -> Result<Block<P>, Error> {
+        self.ipfs.get(cid)
+    }
+
+    /// Makes the staged dag durable by setting `alias` to its root, and announces the
+    /// staged blocks to peers in the background. The provider records are scheduled
+    /// before the alias is set, so that announcements failing or interrupted by a restart
+    /// are retried by the republisher. Returns the root.
+    pub async fn commit<T: AsRef<[u8]> + Send + Sync>(self, alias: T) -> Result<Cid, Error> {
+        let blocks = self
+            .blocks
+            .iter()
+            .filter(|cid| inline_data(cid).is_none())
+            .copied()
+            .collect::<Vec<_>>();
+        self.ipfs
+            .republisher
+            .schedule(&blocks)
+            .map_err(Error::store)?;
+        self.ipfs.alias(alias, Some(&self.root))?;
+        self.ipfs.republisher.provide(blocks);
+        Ok(self.root)
+    }
+
+    /// Discards the staged dag. Staged blocks that aren't referenced otherwise are
+    /// removed by the next garbage collection.
+    pub fn abort(self) {}
+}
@@ -0,0 +1,462 @@
+use crate::{Error, Ipfs, ToCid};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::stream::{Stream, StreamExt};
+use ipfs_embed_net::{AppStream, PeerId};
+use ipfs_embed_sqlite::{StorageService, ALIAS_HISTORY_PREFIX};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Application protocol serving the blocks of a tenant to its members.
+pub const TENANT_BLOCKS_PROTOCOL: &str = "/ipfs-embed/tenant-blocks/1.0.0";
+
+pub(crate) const TENANT_PREFIX: &[u8] = b"/tenant/";
+
+const MAX_REQUEST_SIZE: u64 = 4096;
+
+/// Interval at which the index is rebuilt while a tenant dag is incomplete.
+const INDEX_REFRESH: Duration = Duration::from_secs(30);
+
+/// Number of blocks sent in a single push by `Tenant::share`.
+const SHARE_BATCH: usize = 64;
+
+/// Error returned when a tenant name is empty or contains a `/`.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid tenant name {0:?}")]
+pub struct InvalidTenantName(pub String);
+
+/// Error returned when a tenant block request is malformed.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid tenant block message")]
+pub struct InvalidTenantMessage;
+
+/// Error returned when a peer doesn't serve a tenant block.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} rejected the tenant block request: {1}")]
+pub struct TenantBlockRejected(pub PeerId, pub String);
+
+/// Error returned when an alias starts with a prefix reserved for tenants or the alias
+/// history.
+#[derive(Debug, thiserror::Error)]
+#[error("alias {:?} uses a reserved prefix", String::from_utf8_lossy(.0))]
+pub struct ReservedAlias(pub Vec<u8>);
+
+/// Fails with `ReservedAlias` if `alias` can only be set through a `Tenant` or by the
+/// alias history.
+pub(crate) fn check_alias(alias: &[u8]) -> Result<(), Error> {
+    if alias.starts_with(TENANT_PREFIX) || alias.starts_with(ALIAS_HISTORY_PREFIX) {
+        return Err(Error::store(ReservedAlias(alias.to_vec()).into()));
+    }
+    Ok(())
+}
+
+/// Returns the name of the tenant `alias` is scoped to, including the aliases keeping the
+/// alias history of a tenant alias alive.
+fn tenant_name(alias: &[u8]) -> Option<&[u8]> {
+    let alias = match alias.strip_prefix(ALIAS_HISTORY_PREFIX) {
+        Some(rest) if rest.len() >= 8 => &rest[8..],
+        _ => alias,
+    };
+    let rest = alias.strip_prefix(TENANT_PREFIX)?;
+    rest.split(|b| *b == b'/').next()
+}
+
+/// Members of each tenant and an index of the blocks of the tenant dags.
+#[derive(Default)]
+pub(crate) struct TenantState {
+    members: FnvHashMap<String, FnvHashSet<PeerId>>,
+    index: TenantIndex,
+}
+
+pub(crate) type Tenants = Arc<Mutex<TenantState>>;
+
+/// Index of the blocks reachable from tenant aliases and their alias history, so that
+/// serving a block doesn't need to query the aliases pinning it.
+///
+/// The index is rebuilt when an alias changed. While a tenant dag is incomplete, the
+/// blocks it is missing can arrive any time, so blocks that are not indexed fall back to
+/// querying their aliases, and the index is rebuilt every `INDEX_REFRESH`.
+#[derive(Default)]
+struct TenantIndex {
+    generation: Option<u64>,
+    built: Option<Instant>,
+    complete: bool,
+    blocks: FnvHashMap<Cid, FnvHashSet<String>>,
+    /// Whether an indexed block is only reachable from tenant aliases.
+    private: FnvHashMap<Cid, bool>,
+}
+
+impl TenantIndex {
+    fn refresh<P: StoreParams>(&mut self, storage: &StorageService<P>) -> Result<()>
+    where
+        Ipld: References<P::Codecs>,
+    {
+        let generation = storage.alias_generation();
+        let stale = match (self.generation, self.built) {
+            (Some(g), Some(built)) => {
+                g != generation || (!self.complete && built.elapsed() > INDEX_REFRESH)
+            }
+            _ => true,
+        };
+        if !stale {
+            return Ok(());
+        }
+        let mut roots = vec![];
+        for (alias, root) in storage.aliases_with_prefix(TENANT_PREFIX)? {
+            roots.push((alias.clone(), root));
+            for root in storage.alias_history(&alias, usize::MAX)? {
+                roots.push((alias.clone(), root));
+            }
+        }
+        let mut blocks = FnvHashMap::<Cid, FnvHashSet<String>>::default();
+        let mut complete = true;
+        for (alias, root) in roots {
+            let name = match tenant_name(&alias) {
+                Some(name) => String::from_utf8_lossy(name).into_owned(),
+                None => continue,
+            };
+            let mut stack = vec![root];
+            while let Some(cid) = stack.pop() {
+                if !blocks.entry(cid).or_default().insert(name.clone()) {
+                    continue;
+                }
+                match storage.get(&cid)? {
+                    Some(data) => {
+                        let block = Block::<P>::new_unchecked(cid, data);
+                        if let Err(err) = block.references(&mut stack) {
+                            tracing::debug!("failed to index the links of {}: {}", cid, err);
+                        }
+                    }
+                    None => complete = false,
+                }
+            }
+        }
+        self.generation = Some(generation);
+        self.built = Some(Instant::now());
+        self.complete = complete;
+        self.blocks = blocks;
+        self.private.clear();
+        Ok(())
+    }
+
+    /// Returns the tenants whose dags contain `cid`.
+    fn tenants<P: StoreParams>(
+        &mut self,
+        storage: &StorageService<P>,
+        cid: &Cid,
+    ) -> Result<FnvHashSet<String>>
+    where
+        Ipld: References<P::Codecs>,
+    {
+        self.refresh(storage)?;
+        if let Some(tenants) = self.blocks.get(cid) {
+            return Ok(tenants.clone());
+        }
+        if self.complete {
+            return Ok(Default::default());
+        }
+        Ok(storage
+            .reverse_alias(cid)?
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|alias| tenant_name(alias))
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect())
+    }
+
+    /// Returns `true` if the block is only kept alive by tenant aliases. Such blocks are
+    /// not served over bitswap.
+    fn is_private<P: StoreParams>(&mut self, storage: &StorageService<P>, cid: &Cid) -> Result<bool>
+    where
+        Ipld: References<P::Codecs>,
+    {
+        if self.tenants(storage, cid)?.is_empty() {
+            return Ok(false);
+        }
+        if let Some(private) = self.private.get(cid) {
+            return Ok(*private);
+        }
+        // history aliases are not returned, so a block only pinned by the history of a
+        // tenant alias has no aliases.
+        let private = match storage.reverse_alias(cid)? {
+            Some(aliases) => aliases.iter().all(|alias| tenant_name(alias).is_some()),
+            None => return Ok(false),
+        };
+        if self.blocks.contains_key(cid) {
+            self.private.insert(*cid, private);
+        }
+        Ok(private)
+    }
+}
+
+impl TenantState {
+    /// Returns `true` if the block is only kept alive by tenant aliases.
+    pub(crate) fn is_private<P: StoreParams>(
+        &mut self,
+        storage: &StorageService<P>,
+        cid: &Cid,
+    ) -> Result<bool>
+    where
+        Ipld: References<P::Codecs>,
+    {
+        self.index.is_private(storage, cid)
+    }
+
+    /// Returns `true` if `peer` is a member of the tenant `name` and the block `cid`
+    /// belongs to the dag of the tenant.
+    fn may_serve<P: StoreParams>(
+        &mut self,
+        storage: &StorageService<P>,
+        name: &str,
+        peer: &PeerId,
+        cid: &Cid,
+    ) -> Result<bool>
+    where
+        Ipld: References<P::Codecs>,
+    {
+        let member = self
+            .members
+            .get(name)
+            .map(|members| members.contains(peer))
+            .unwrap_or_default();
+        Ok(member && self.index.tenants(storage, cid)?.contains(name))
+    }
+}
+
+/// A workspace sharing a node with other workspaces.
+///
+/// Gossip topics are prefixed and aliases are scoped with `/tenant/<name>/`, so tenants
+/// neither see each others messages nor roots. Blocks that are only pinned by tenant
+/// aliases are not served over bitswap, since bitswap requests can't be attributed to a
+/// tenant. Instead they are shared with the members of the tenant using `share`, which
+/// requires the members to enable push, or fetched by the members with `fetch` from nodes
+/// serving the `TENANT_BLOCKS_PROTOCOL`.
+///
+/// Membership is kept in memory and needs to be restored by the application after a
+/// restart.
+#[derive(Clone)]
+pub struct Tenant<P: StoreParams> {
+    ipfs: Ipfs<P>,
+    name: String,
+}
+
+impl<P: StoreParams> Tenant<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub(crate) fn new(ipfs: Ipfs<P>, name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains('/') {
+            return Err(InvalidTenantName(name.into()).into());
+        }
+        ipfs.tenants.lock().members.entry(name.into()).or_default();
+        Ok(Self {
+            ipfs,
+            name: name.into(),
+        })
+    }
+
+    /// Returns the name of the tenant.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix(&self) -> String {
+        format!("/tenant/{}/", self.name)
+    }
+
+    /// Returns the gossip topic `topic` is mapped to.
+    pub fn topic(&self, topic: &str) -> String {
+        format!("{}{}", self.prefix(), topic)
+    }
+
+    /// Returns the alias `alias` is mapped to.
+    pub fn scoped_alias(&self, alias: &[u8]) -> Vec<u8> {
+        let mut scoped = self.prefix().into_bytes();
+        scoped.extend_from_slice(alias);
+        scoped
+    }
+
+    /// Subscribes to a `topic` of the tenant.
+    pub fn subscribe(&self, topic: &str) -> Result<impl Stream<Item = Vec<u8>>, Error> {
+        self.ipfs.subscribe(&self.topic(topic))
+    }
+
+    /// Publishes a message in a `topic` of the tenant.
+    pub fn publish(&self, topic: &str, msg: Vec<u8>) -> Result<(), Error> {
+        self.ipfs.publish(&self.topic(topic), msg)
+    }
+
+    /// Creates, updates or removes an alias of the tenant.
+    pub fn alias<T: AsRef<[u8]>>(&self, alias: T, cid: Option<&Cid>) -> Result<(), Error> {
+        self.ipfs.set_alias(&self.scoped_alias(alias.as_ref()), cid)
+    }
+
+    /// Creates or updates an alias of the tenant, with a root that is parsed if it is a
+    /// string.
+    pub fn alias_to<T: AsRef<[u8]>>(&self, alias: T, cid: impl ToCid) -> Result<(), Error> {
+        self.alias(alias, Some(&cid.to_cid()?))
+    }
+
+    /// Returns the root of an alias of the tenant.
+    pub fn resolve<T: AsRef<[u8]>>(&self, alias: T) -> Result<Option<Cid>, Error> {
+        self.ipfs.resolve(self.scoped_alias(alias.as_ref()))
+    }
+
+    /// Adds a member to the tenant.
+    pub fn add_member(&self, peer: PeerId) {
+        self.ipfs
+            .tenants
+            .lock()
+            .members
+            .entry(self.name.clone())
+            .or_default()
+            .insert(peer);
+    }
+
+    /// Removes a member from the tenant.
+    pub fn remove_member(&self, peer: &PeerId) {
+        if let Some(members) = self.ipfs.tenants.lock().members.get_mut(&self.name) {
+            members.remove(peer);
+        }
+    }
+
+    /// Returns `true` if `peer` is a member of the tenant. Can be used to decide if
+    /// pushed blocks are accepted into the tenant.
+    pub fn is_member(&self, peer: &PeerId) -> bool {
+        self.ipfs
+            .tenants
+            .lock()
+            .members
+            .get(&self.name)
+            .map(|members| members.contains(peer))
+            .unwrap_or_default()
+    }
+
+    /// Returns the members of the tenant.
+    pub fn members(&self) -> Vec<PeerId> {
+        self.ipfs
+            .tenants
+            .lock()
+            .members
+            .get(&self.name)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Pushes the locally available blocks of the dag rooted at `cid` to every member
+    /// of the tenant. Returns the members the push failed for.
+    pub async fn share(&self, cid: &Cid) -> Result<Vec<PeerId>> {
+        let mut blocks = vec![];
+        let mut visited = FnvHashSet::default();
+        let mut stack = vec![*cid];
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            if let Some(data) = self.ipfs.storage.get(&cid)? {
+                let block = Block::<P>::new_unchecked(cid, data);
+                block.references(&mut stack)?;
+                blocks.push(block);
+            }
+        }
+        let mut failed = vec![];
+        for peer in self.members() {
+            for chunk in blocks.chunks(SHARE_BATCH) {
+                if let Err(err) = self.ipfs.push(&peer, chunk.to_vec()).await {
+                    tracing::debug!("failed to share {} with {}: {}", cid, peer, err);
+                    failed.push(peer);
+                    break;
+                }
+            }
+        }
+        Ok(failed)
+    }
+    /// Fetches the block `cid` of the tenant from `peer`, which needs to serve the
+    /// `TENANT_BLOCKS_PROTOCOL` and have the local node as a member of the tenant. The
+    /// block is inserted into the store, but not provided.
+    pub async fn fetch(&self, peer: &PeerId, cid: &Cid) -> Result<Block<P>> {
+        let mut stream = self.ipfs.open_stream(peer, TENANT_BLOCKS_PROTOCOL).await?;
+        let mut req = BTreeMap::new();
+        req.insert("tenant".to_string(), Ipld::String(self.name.clone()));
+        req.insert("cid".to_string(), Ipld::Link(*cid));
+        stream
+            .write_all(&DagCborCodec.encode(&Ipld::StringMap(req))?)
+            .await?;
+        stream.close().await?;
+        let mut buf = vec![];
+        let limit = P::MAX_BLOCK_SIZE as u64 + MAX_REQUEST_SIZE;
+        (&mut stream).take(limit).read_to_end(&mut buf).await?;
+        let res: Ipld = DagCborCodec.decode(&buf)?;
+        if let Ok(Ipld::String(reason)) = res.get("error") {
+            return Err(TenantBlockRejected(*peer, reason.clone()).into());
+        }
+        let data = match res.get("data") {
+            Ok(Ipld::Bytes(data)) => data.clone(),
+            _ => return Err(InvalidTenantMessage.into()),
+        };
+        let block = Block::<P>::new(*cid, data)?;
+        self.ipfs.storage.insert(&block)?;
+        Ok(block)
+    }
+}
+
+/// Answers a single tenant block request of `peer`.
+async fn answer<P: StoreParams>(ipfs: &Ipfs<P>, peer: PeerId, mut stream: AppStream) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut buf = vec![];
+    (&mut stream)
+        .take(MAX_REQUEST_SIZE)
+        .read_to_end(&mut buf)
+        .await?;
+    let req: Ipld = DagCborCodec.decode(&buf)?;
+    let (name, cid) = match (req.get("tenant"), req.get("cid")) {
+        (Ok(Ipld::String(name)), Ok(Ipld::Link(cid))) => (name.clone(), *cid),
+        _ => return Err(InvalidTenantMessage.into()),
+    };
+    let storage = ipfs.storage.clone();
+    let tenants = ipfs.tenants.clone();
+    let data = ipfs_embed_rt::spawn_blocking(move || {
+        if !tenants.lock().may_serve(&storage, &name, &peer, &cid)? {
+            return Ok(None);
+        }
+        storage.get(&cid)
+    })
+    .await?;
+    let mut res = BTreeMap::new();
+    match data {
+        Some(data) => res.insert("data".to_string(), Ipld::Bytes(data)),
+        None => res.insert("error".to_string(), Ipld::String("not found".into())),
+    };
+    stream
+        .write_all(&DagCborCodec.encode(&Ipld::StringMap(res))?)
+        .await?;
+    stream.close().await?;
+    Ok(())
+}
+
+/// Serves the tenant blocks requested on `streams` to the members of the tenants.
+pub(crate) async fn serve<P: StoreParams>(
+    ipfs: Ipfs<P>,
+    streams: impl Stream<Item = (PeerId, AppStream)>,
+) where
+    Ipld: References<P::Codecs>,
+{
+    futures::pin_mut!(streams);
+    while let Some((peer, stream)) = streams.next().await {
+        let ipfs = ipfs.clone();
+        ipfs_embed_rt::spawn(async move {
+            if let Err(err) = answer(&ipfs, peer, stream).await {
+                tracing::debug!("failed to answer tenant block request of {}: {}", peer, err);
+            }
+        })
+        .detach();
+    }
+}
@@ -0,0 +1,138 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use ipfs_embed_net::TraversalOrder;
+use ipfs_embed_sqlite::StorageService;
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+
+/// Size and completeness of a dag.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DagStat {
+    /// Number of blocks of the dag in the block store.
+    pub blocks: usize,
+    /// Total size of the blocks in the block store.
+    pub size: u64,
+    /// Blocks of the dag missing from the block store, in traversal order. The blocks
+    /// they link to are unknown, so they aren't counted.
+    pub missing: Vec<Cid>,
+}
+
+/// Blocks discovered but not visited yet.
+struct Frontier {
+    order: TraversalOrder,
+    stack: Vec<Cid>,
+    queue: VecDeque<Cid>,
+    /// Blocks by size, ties are broken by the order they were discovered in.
+    heap: BinaryHeap<(usize, Reverse<u64>, Cid)>,
+    discovered: u64,
+}
+
+impl Frontier {
+    fn new(order: TraversalOrder) -> Self {
+        Self {
+            order,
+            stack: vec![],
+            queue: Default::default(),
+            heap: Default::default(),
+            discovered: 0,
+        }
+    }
+
+    fn push(&mut self, cid: Cid, size: usize) {
+        match self.order {
+            TraversalOrder::DepthFirst => self.stack.push(cid),
+            TraversalOrder::BreadthFirst => self.queue.push_back(cid),
+            TraversalOrder::LargestFirst => {
+                self.heap.push((size, Reverse(self.discovered), cid));
+                self.discovered += 1;
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Cid> {
+        match self.order {
+            TraversalOrder::DepthFirst => self.stack.pop(),
+            TraversalOrder::BreadthFirst => self.queue.pop_front(),
+            TraversalOrder::LargestFirst => self.heap.pop().map(|(_, _, cid)| cid),
+        }
+    }
+}
+
+/// Visits every block of the dag rooted at `root` once in traversal `order`, passing the
+/// data of the block or `None` if it is missing from the block store.
+pub(crate) fn walk<P: StoreParams>(
+    storage: &StorageService<P>,
+    root: &Cid,
+    order: TraversalOrder,
+    mut visit: impl FnMut(&Cid, Option<&[u8]>) -> Result<()>,
+) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut visited = FnvHashSet::default();
+    // blocks read to order the frontier by size, so that they are only read once.
+    let mut loaded = FnvHashMap::default();
+    let mut frontier = Frontier::new(order);
+    frontier.push(*root, 0);
+    while let Some(cid) = frontier.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let data = match loaded.remove(&cid) {
+            Some(data) => data,
+            None => storage.get(&cid)?,
+        };
+        let data = match data {
+            Some(data) => data,
+            None => {
+                visit(&cid, None)?;
+                continue;
+            }
+        };
+        visit(&cid, Some(&data))?;
+        let mut links = vec![];
+        Block::<P>::new_unchecked(cid, data).references(&mut links)?;
+        links.retain(|cid| !visited.contains(cid));
+        if order == TraversalOrder::DepthFirst {
+            // the stack pops the last link first.
+            links.reverse();
+        }
+        for link in links {
+            if order != TraversalOrder::LargestFirst {
+                frontier.push(link, 0);
+            } else if !loaded.contains_key(&link) {
+                let data = storage.get(&link)?;
+                let size = data.as_ref().map(Vec::len).unwrap_or_default();
+                loaded.insert(link, data);
+                frontier.push(link, size);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Counts the blocks of the dag rooted at `root`, listing the missing blocks in
+/// traversal `order`.
+pub(crate) fn dag_stat<P: StoreParams>(
+    storage: &StorageService<P>,
+    root: &Cid,
+    order: TraversalOrder,
+) -> Result<DagStat>
+where
+    Ipld: References<P::Codecs>,
+{
+    let mut stat = DagStat::default();
+    walk(storage, root, order, |cid, data| {
+        match data {
+            Some(data) => {
+                stat.blocks += 1;
+                stat.size += data.len() as u64;
+            }
+            None => stat.missing.push(*cid),
+        }
+        Ok(())
+    })?;
+    Ok(stat)
+}
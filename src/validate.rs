@@ -0,0 +1,97 @@
+use crate::events::{EventBus, NodeEvent};
+use fnv::FnvHashMap;
+use futures::channel::mpsc;
+use ipfs_embed_net::PeerId;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Result};
+use parking_lot::{Mutex, RwLock};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Validates blocks received from the network before they are inserted.
+pub type BlockValidator<P> = Arc<dyn Fn(&Block<P>) -> Result<()> + Send + Sync>;
+
+/// Error returned when a block is rejected by a `BlockValidator`.
+#[derive(Debug, Error)]
+#[error("block {0} is invalid: {1}")]
+pub struct InvalidBlock(pub Cid, pub String);
+
+/// Number of invalid blocks after which a peer is banned by default.
+const DEFAULT_BAN_THRESHOLD: u64 = 3;
+
+/// The registered validators and the number of invalid blocks sent by each peer.
+pub(crate) struct Validators<P: StoreParams> {
+    validators: Arc<RwLock<Vec<BlockValidator<P>>>>,
+    invalid: Arc<Mutex<FnvHashMap<PeerId, u64>>>,
+    ban_threshold: Arc<Mutex<Option<u64>>>,
+    bans: mpsc::UnboundedSender<PeerId>,
+    events: EventBus,
+}
+
+impl<P: StoreParams> Clone for Validators<P> {
+    fn clone(&self) -> Self {
+        Self {
+            validators: self.validators.clone(),
+            invalid: self.invalid.clone(),
+            ban_threshold: self.ban_threshold.clone(),
+            bans: self.bans.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl<P: StoreParams> Validators<P> {
+    /// Creates the validators. Peers exceeding the ban threshold are sent to `bans`.
+    pub fn new(events: EventBus, bans: mpsc::UnboundedSender<PeerId>) -> Self {
+        Self {
+            validators: Default::default(),
+            invalid: Default::default(),
+            ban_threshold: Arc::new(Mutex::new(Some(DEFAULT_BAN_THRESHOLD))),
+            bans,
+            events,
+        }
+    }
+
+    pub fn set_ban_threshold(&self, threshold: Option<u64>) {
+        *self.ban_threshold.lock() = threshold;
+    }
+
+    pub fn add(&self, validator: BlockValidator<P>) {
+        self.validators.write().push(validator);
+    }
+
+    /// Runs all validators on a block received from `peer`, or from an unknown peer over
+    /// bitswap.
+    pub fn validate(&self, peer: Option<PeerId>, block: &Block<P>) -> Result<(), InvalidBlock> {
+        for validator in self.validators.read().iter() {
+            if let Err(err) = validator(block) {
+                let reason = err.to_string();
+                tracing::debug!("rejecting block {}: {}", block.cid(), reason);
+                if let Some(peer) = peer {
+                    self.count_invalid(peer);
+                }
+                self.events
+                    .publish(NodeEvent::InvalidBlock(peer, *block.cid(), reason.clone()));
+                return Err(InvalidBlock(*block.cid(), reason));
+            }
+        }
+        Ok(())
+    }
+
+    fn count_invalid(&self, peer: PeerId) {
+        let invalid = {
+            let mut invalid = self.invalid.lock();
+            let count = invalid.entry(peer).or_default();
+            *count += 1;
+            *count
+        };
+        if *self.ban_threshold.lock() == Some(invalid) {
+            tracing::info!("banning {} after {} invalid blocks", peer, invalid);
+            self.bans.unbounded_send(peer).ok();
+        }
+    }
+
+    pub fn invalid_blocks(&self, peer: &PeerId) -> u64 {
+        self.invalid.lock().get(peer).copied().unwrap_or_default()
+    }
+}